@@ -0,0 +1,196 @@
+/// Buffer pool and disk manager benchmarks, all driven by `bench::Workload` so every configuration in a group replays the same
+/// accesses:
+///
+/// - `replacer`: LRU-K, Clock, 2Q and ARC on a skewed workload over ten times more pages than the pool has frames. ARC's
+///   lists and target size are printed once its run is done
+/// - `reads`: latched against optimistic reads of pages that stay resident, as reader threads are added
+/// - `pool`: a single buffer pool against a `ParallelBufferPool` with as many frames in total, under an even read/write mix, as threads are added
+/// - `disk`: the disk manager on its own, uniform reads and writes
+/// - `backend`: update-in-place against log-structured storage (with compaction running in the background) under a
+///   write-heavy mix, as threads are added
+///
+/// Run them with `cargo bench`, or one group with `cargo bench -- replacer`. Files go to `tests/bench` under the working
+/// directory and are removed at the end.
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use symmetric_concurrent_v4::bench::{Distribution, ReadMode, Workload};
+use symmetric_concurrent_v4::shared::{cwd, BUFFER_POOL_SIZE};
+use symmetric_concurrent_v4::storage::buffer::arc::{ArcReplacer, ArcReplacerApi as _};
+use symmetric_concurrent_v4::storage::buffer::bufmgr::{BufApi, BufferPool, REPLACER_K};
+use symmetric_concurrent_v4::storage::buffer::clock::{ClockReplacer, ClockReplacerApi as _};
+use symmetric_concurrent_v4::storage::buffer::diskmgr::{DiskApi, DiskMgr};
+use symmetric_concurrent_v4::storage::buffer::logstruct::{
+    LogStructuredApi as _, LogStructuredDiskMgr,
+};
+use symmetric_concurrent_v4::storage::buffer::lruk::{LRUKReplacer, LRUKReplacerApi as _};
+use symmetric_concurrent_v4::storage::buffer::parallel::{ParallelBufferPool, DEFAULT_INSTANCES};
+use symmetric_concurrent_v4::storage::buffer::replacer::BoxedReplacer;
+use symmetric_concurrent_v4::storage::buffer::twoq::{TwoQReplacer, TwoQReplacerApi as _};
+use symmetric_concurrent_v4::storage::config::StorageConfig;
+
+fn path(name: &str) -> String {
+    let dir = cwd() + "/tests/bench";
+    std::fs::create_dir_all(&dir).unwrap();
+    format!("{}/{}.bin", dir, name)
+}
+
+/// Benchmark `workload` against `pool` under `id`, then remove the pool's file. If the pool's replacer adapts, where it ended
+/// up is printed
+fn bench_pool<P: BufApi + Sync>(
+    c: &mut Criterion,
+    group: &str,
+    id: BenchmarkId,
+    pool: P,
+    file: String,
+    workload: &Workload,
+) {
+    let page_ids = workload.populate(&pool).unwrap();
+    c.benchmark_group(group)
+        .bench_function(id, |b| b.iter(|| workload.run(&pool, &page_ids).unwrap()));
+    if let Some(replacer) = pool.stats().replacer {
+        println!("{}: {:?}", group, replacer);
+    }
+    drop(pool);
+    std::fs::remove_file(file).unwrap();
+}
+
+fn create_replacer(name: &str) -> BoxedReplacer {
+    match name {
+        "lru-k" => Box::new(LRUKReplacer::create(BUFFER_POOL_SIZE, REPLACER_K)),
+        "clock" => Box::new(ClockReplacer::create(BUFFER_POOL_SIZE)),
+        "arc" => Box::new(ArcReplacer::create(BUFFER_POOL_SIZE)),
+        _ => Box::new(TwoQReplacer::create(BUFFER_POOL_SIZE)),
+    }
+}
+
+fn replacer(c: &mut Criterion) {
+    let workload = Workload::new(BUFFER_POOL_SIZE * 10)
+        .with_ops(2_000)
+        .with_distribution(Distribution::Zipfian(0.99))
+        .with_threads(4);
+    for name in ["lru-k", "clock", "2q", "arc"] {
+        let file = path(&format!("replacer_{}", name));
+        let pool = BufferPool::create_with_replacer(&file, create_replacer(name)).unwrap();
+        bench_pool(
+            c,
+            "replacer",
+            BenchmarkId::from_parameter(name),
+            pool,
+            file,
+            &workload,
+        );
+    }
+}
+
+fn reads(c: &mut Criterion) {
+    for threads in [1, 4, 8] {
+        for (name, read_mode) in [
+            ("latched", ReadMode::Latched),
+            ("optimistic", ReadMode::Optimistic),
+        ] {
+            let workload = Workload::new(BUFFER_POOL_SIZE / 2)
+                .with_ops(5_000)
+                .with_read_ratio(1.0)
+                .with_threads(threads)
+                .with_read_mode(read_mode);
+            let file = path(&format!("reads_{}_{}", name, threads));
+            let pool = BufferPool::create(&file).unwrap();
+            let id = BenchmarkId::new(name, threads);
+            bench_pool(c, "reads", id, pool, file, &workload);
+        }
+    }
+}
+
+fn pool(c: &mut Criterion) {
+    for threads in [1, 4, 8] {
+        let workload = Workload::new(BUFFER_POOL_SIZE * 2)
+            .with_ops(2_000)
+            .with_read_ratio(0.5)
+            .with_distribution(Distribution::Zipfian(0.99))
+            .with_threads(threads);
+        let file = path(&format!("pool_single_{}", threads));
+        let single = BufferPool::create(&file).unwrap();
+        bench_pool(
+            c,
+            "pool",
+            BenchmarkId::new("single", threads),
+            single,
+            file,
+            &workload,
+        );
+        let file = path(&format!("pool_parallel_{}", threads));
+        // split the same number of frames over the instances
+        let config =
+            StorageConfig::for_path(&file).with_pool_size(BUFFER_POOL_SIZE / DEFAULT_INSTANCES);
+        let parallel = ParallelBufferPool::create_with_config(&config).unwrap();
+        bench_pool(
+            c,
+            "pool",
+            BenchmarkId::new("parallel", threads),
+            parallel,
+            file,
+            &workload,
+        );
+    }
+}
+
+fn disk(c: &mut Criterion) {
+    let workload = Workload::new(BUFFER_POOL_SIZE * 10)
+        .with_ops(1_000)
+        .with_read_ratio(0.5);
+    let file = path("disk");
+    let disk = DiskMgr::create(&file).unwrap();
+    let page_ids = workload.populate_disk(&disk).unwrap();
+    c.bench_function("disk", |b| {
+        b.iter(|| workload.run_disk(&disk, &page_ids).unwrap())
+    });
+    drop(disk);
+    std::fs::remove_file(file).unwrap();
+}
+
+/// Benchmark `workload` straight against `disk` under `id`
+fn bench_disk<D: DiskApi + Sync>(
+    c: &mut Criterion,
+    id: BenchmarkId,
+    disk: &D,
+    workload: &Workload,
+) {
+    let page_ids = workload.populate_disk(disk).unwrap();
+    c.benchmark_group("backend").bench_function(id, |b| {
+        b.iter(|| workload.run_disk(disk, &page_ids).unwrap())
+    });
+}
+
+fn backend(c: &mut Criterion) {
+    for threads in [1, 4, 8] {
+        let workload = Workload::new(BUFFER_POOL_SIZE * 10)
+            .with_ops(1_000)
+            .with_read_ratio(0.2)
+            .with_threads(threads);
+        let file = path(&format!("backend_in_place_{}", threads));
+        let disk = DiskMgr::create(&file).unwrap();
+        bench_disk(c, BenchmarkId::new("in-place", threads), &disk, &workload);
+        drop(disk);
+        std::fs::remove_file(file).unwrap();
+
+        // the segments and their index go in a directory of their own
+        let dir = path(&format!("backend_log_{}", threads));
+        std::fs::create_dir_all(&dir).unwrap();
+        let disk = LogStructuredDiskMgr::create(&(dir.clone() + "/data.bin")).unwrap();
+        let compaction = disk.start_compaction(Duration::from_millis(10));
+        bench_disk(
+            c,
+            BenchmarkId::new("log-structured", threads),
+            &disk,
+            &workload,
+        );
+        compaction.stop();
+        drop(disk);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+criterion_group!(benches, replacer, reads, pool, disk, backend);
+criterion_main!(benches);
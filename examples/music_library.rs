@@ -0,0 +1,385 @@
+/// A music library built on the storage engine, from an empty directory to crash recovery. The program registers a `songs`
+/// table and its primary key index in the catalog, bulk loads `music_library.csv` into them and checkpoints. Then listeners
+/// and curators run concurrently, in transactions under two-phase locking:
+///
+/// - listeners look songs up through the index, read them from the table heap and check their play counts
+/// - curators record plays: each transaction locks a few songs, logs the new play counts and updates them in place, and
+///   commits through group commit
+///
+/// Halfway through a last transaction the program crashes: it drops the buffer pool without writing anything back, after
+/// that transaction's update has reached the log but before it commits. Recovery then redoes the committed plays from the
+/// checkpoint on and rolls the interrupted transaction back, and every play count has to come back to exactly what the
+/// committed transactions recorded.
+///
+/// Run it with `cargo run --example music_library [directory]`. It also runs as a test under `cargo test`.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use symmetric_concurrent_v4::catalog::{Catalog, CatalogApi as _, StorageOptions};
+use symmetric_concurrent_v4::shared::{cwd, Lsn, PageId, TxnId, INVALID_LSN};
+use symmetric_concurrent_v4::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+use symmetric_concurrent_v4::storage::buffer::diskmgr::{DiskApi as _, DiskMgr};
+use symmetric_concurrent_v4::storage::buffer::io;
+use symmetric_concurrent_v4::storage::buffer::page::{self, PAGE_HEADER_SIZE};
+use symmetric_concurrent_v4::storage::index::blink::{self, BLinkTree, MAX_ENTRIES};
+use symmetric_concurrent_v4::storage::log::checkpoint::{self, CheckpointManager};
+use symmetric_concurrent_v4::storage::log::group::{GroupCommit, MAX_GROUP_DELAY};
+use symmetric_concurrent_v4::storage::log::logmgr::{LogApi as _, LogManager};
+use symmetric_concurrent_v4::storage::log::record::LogBody;
+use symmetric_concurrent_v4::storage::log::recovery::RecoveryManager;
+use symmetric_concurrent_v4::storage::table::heap::{Rid, TableHeap};
+use symmetric_concurrent_v4::txn::lockmgr::{LockApi as _, LockManager, LockPolicy, LockTarget};
+use symmetric_concurrent_v4::txn::session::CancellationToken;
+use symmetric_concurrent_v4::txn::tracker::{TxnTracker, TxnTrackerApi as _};
+
+const SONGS_CSV: &str = include_str!("music_library.csv");
+const LISTENERS: usize = 4;
+const CURATORS: usize = 4;
+const TXNS_PER_CURATOR: usize = 50;
+const SONGS_PER_TXN: usize = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct Song {
+    id: u64,
+    title: String,
+    artist: String,
+}
+
+/// Parse the `id,title,artist` rows of a CSV file with a header line
+fn parse_csv(csv: &str) -> Vec<Song> {
+    csv.lines()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut fields = line.splitn(3, ',');
+            let (Some(id), Some(title), Some(artist)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                panic!("malformed row {:?}", line);
+            };
+            Song {
+                id: id.parse().expect("song ids are integers"),
+                title: title.to_string(),
+                artist: artist.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Index values are page ids, so a rid is packed into one: the page id in the high bits, the slot in the low 16
+fn pack(rid: Rid) -> PageId {
+    (rid.page_id << 16) | rid.slot as PageId
+}
+
+fn unpack(value: PageId) -> Rid {
+    Rid::new(value >> 16, (value & 0xffff) as u16)
+}
+
+/// Where a song's play count lives: an 8 byte counter per song, after the header of the plays page
+fn plays_offset(id: u64) -> usize {
+    PAGE_HEADER_SIZE + id as usize * 8
+}
+
+/// Everything the library needs to run. A real server would keep the page ids of its relations in a root page; the example
+/// carries them across the crash in `Layout` instead
+struct Library {
+    pool: BufferPool,
+    log: LogManager,
+    tracker: TxnTracker,
+    locks: LockManager,
+    songs: TableHeap,
+    by_id: BLinkTree,
+    plays: PageId,
+    next_txn_id: AtomicU64,
+}
+
+#[derive(Clone, Copy)]
+struct Layout {
+    songs: PageId,
+    by_id: PageId,
+    plays: PageId,
+}
+
+impl Library {
+    /// Look a song up by id through the index, read lock it, and read it and its play count
+    fn lookup(&self, txn_id: TxnId, id: u64) -> Option<(Song, u64)> {
+        let rid = unpack(self.by_id.search(&blink::key(id)).ok()??);
+        self.locks.lock_shared(txn_id, LockTarget::Row(rid));
+        let song = io::decode(self.songs.get_tuple(rid)?).ok()?;
+        let guard = self.pool.fetch_page_read(self.plays).ok()?;
+        let offset = plays_offset(id);
+        let plays = u64::from_le_bytes(guard[offset..offset + 8].try_into().unwrap());
+        Some((song, plays))
+    }
+
+    /// Add one play to song `id` on behalf of `txn_id`, which must hold its row lock. Returns the LSN of the update
+    fn record_play(&self, txn_id: TxnId, prev_lsn: Lsn, id: u64) -> Lsn {
+        let mut guard = self.pool.fetch_page_write(self.plays).unwrap();
+        let offset = plays_offset(id);
+        let before = guard[offset..offset + 8].to_vec();
+        let plays = u64::from_le_bytes(before.as_slice().try_into().unwrap()) + 1;
+        let body = LogBody::Update {
+            page_id: self.plays,
+            offset: offset as u16,
+            before,
+            after: plays.to_le_bytes().to_vec(),
+        };
+        let lsn = self.log.append(txn_id, prev_lsn, body).unwrap();
+        self.tracker.logged(txn_id, lsn);
+        guard[offset..offset + 8].copy_from_slice(&plays.to_le_bytes());
+        page::set_lsn(&mut guard, lsn);
+        lsn
+    }
+
+    /// Start a transaction. It isn't logged until it first changes something, so read-only transactions never reach the log
+    fn begin(&self) -> TxnId {
+        let txn_id = self.next_txn_id.fetch_add(1, Ordering::SeqCst);
+        self.tracker.begin(txn_id);
+        txn_id
+    }
+
+    fn finish(&self, txn_id: TxnId) {
+        self.tracker.end(txn_id);
+        self.locks.unlock_all(txn_id);
+    }
+}
+
+/// Create the library in `dir`, bulk load the songs and checkpoint
+fn load(dir: &str, catalog: &Catalog, songs: &[Song]) -> (Library, Layout) {
+    let pool = BufferPool::create(&(dir.to_string() + "/library.db")).unwrap();
+    let log = LogManager::create(&(dir.to_string() + "/library.wal")).unwrap();
+    pool.set_log_manager(log.clone());
+
+    let table = catalog
+        .create_table("songs", StorageOptions::default())
+        .unwrap();
+    catalog
+        .create_index("songs_by_id", table, StorageOptions::default())
+        .unwrap();
+    let heap = TableHeap::create(pool.clone()).unwrap();
+    let by_id = BLinkTree::create(pool.clone()).unwrap();
+    let plays = pool.alloc_page().unwrap();
+
+    // the load isn't logged: it becomes durable with the checkpoint that follows it
+    let entries: Vec<_> = songs
+        .iter()
+        .map(|song| {
+            let rid = heap
+                .insert_tuple(&io::encode(song.clone()).unwrap())
+                .unwrap();
+            (blink::key(song.id), pack(rid))
+        })
+        .collect();
+    let indexed = by_id.build(entries, &CancellationToken::default()).unwrap();
+    assert!(indexed == songs.len());
+    catalog.record_insert(table, songs.len() as u64);
+
+    let tracker = TxnTracker::create();
+    let checkpoints = CheckpointManager::new(
+        pool.clone(),
+        log.clone(),
+        tracker.clone(),
+        &checkpoint::master_path(&(dir.to_string() + "/library.wal")),
+    );
+    let checkpoint = checkpoints.checkpoint().unwrap();
+    println!(
+        "loaded {} songs, checkpoint at lsn {}",
+        songs.len(),
+        checkpoint.begin_lsn
+    );
+
+    let layout = Layout {
+        songs: heap.first_page_id(),
+        by_id: by_id.meta_page_id(),
+        plays,
+    };
+    let library = Library {
+        pool,
+        log,
+        tracker,
+        locks: LockManager::create(LockPolicy::Fifo),
+        songs: heap,
+        by_id,
+        plays,
+        next_txn_id: AtomicU64::new(1),
+    };
+    (library, layout)
+}
+
+/// Run listeners and curators against the library at the same time. Returns the play counts the committed transactions
+/// recorded, by song id
+fn serve(library: &Arc<Library>, songs: &[Song]) -> HashMap<u64, u64> {
+    let group = Arc::new(GroupCommit::start(library.log.clone(), MAX_GROUP_DELAY));
+    let ids: Arc<Vec<u64>> = Arc::new(songs.iter().map(|song| song.id).collect());
+    let expected: Arc<Vec<AtomicU64>> = Arc::new(songs.iter().map(|_| AtomicU64::new(0)).collect());
+
+    let curators: Vec<_> = (0..CURATORS)
+        .map(|_| {
+            let (library, group, ids, expected) = (
+                library.clone(),
+                group.clone(),
+                ids.clone(),
+                expected.clone(),
+            );
+            std::thread::spawn(move || {
+                let mut rng = rand::thread_rng();
+                for _ in 0..TXNS_PER_CURATOR {
+                    // locking in id order rules out deadlocks between curators
+                    let mut picked: Vec<u64> = ids
+                        .choose_multiple(&mut rng, SONGS_PER_TXN)
+                        .copied()
+                        .collect();
+                    picked.sort();
+                    let txn_id = library.begin();
+                    let mut lsn = INVALID_LSN;
+                    for id in &picked {
+                        let rid = unpack(library.by_id.search(&blink::key(*id)).unwrap().unwrap());
+                        assert!(library.locks.lock_exclusive(txn_id, LockTarget::Row(rid)));
+                        lsn = library.record_play(txn_id, lsn, *id);
+                    }
+                    let commit = library.log.append(txn_id, lsn, LogBody::Commit).unwrap();
+                    group.commit(commit).unwrap();
+                    for id in &picked {
+                        expected[*id as usize].fetch_add(1, Ordering::SeqCst);
+                    }
+                    library.finish(txn_id);
+                }
+            })
+        })
+        .collect();
+
+    let listeners: Vec<_> = (0..LISTENERS)
+        .map(|_| {
+            let (library, ids) = (library.clone(), ids.clone());
+            std::thread::spawn(move || {
+                let mut rng = rand::thread_rng();
+                let mut reads = 0;
+                for _ in 0..TXNS_PER_CURATOR * 2 {
+                    let txn_id = library.begin();
+                    let id = *ids.choose(&mut rng).unwrap();
+                    let (song, plays) = library.lookup(txn_id, id).unwrap();
+                    assert!(song.id == id);
+                    // a committed play count never exceeds what every curator could have recorded
+                    assert!(plays <= (CURATORS * TXNS_PER_CURATOR) as u64);
+                    library.finish(txn_id);
+                    reads += 1;
+                    if rng.gen_ratio(1, 10) {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                }
+                reads
+            })
+        })
+        .collect();
+
+    for curator in curators {
+        curator.join().unwrap();
+    }
+    let reads: usize = listeners
+        .into_iter()
+        .map(|listener| listener.join().unwrap())
+        .sum();
+    println!(
+        "{} listens, {} plays recorded in {} transactions over {} log syncs",
+        reads,
+        CURATORS * TXNS_PER_CURATOR * SONGS_PER_TXN,
+        CURATORS * TXNS_PER_CURATOR,
+        group.syncs()
+    );
+    ids.iter()
+        .map(|id| (*id, expected[*id as usize].load(Ordering::SeqCst)))
+        .collect()
+}
+
+/// Start a transaction that records a play and make its update durable in the log, then go down without committing it or
+/// writing any page back. Returns the id of the interrupted transaction
+fn crash(library: Library, id: u64) -> TxnId {
+    let txn_id = library.begin();
+    let rid = unpack(library.by_id.search(&blink::key(id)).unwrap().unwrap());
+    assert!(library.locks.lock_exclusive(txn_id, LockTarget::Row(rid)));
+    library.record_play(txn_id, INVALID_LSN, id);
+    library.log.flush().unwrap();
+    println!("crashing in the middle of transaction {}", txn_id);
+    drop(library);
+    txn_id
+}
+
+/// Recover the library in `dir` and reopen it
+fn recover(dir: &str, layout: Layout) -> (BufferPool, TableHeap, BLinkTree, Vec<TxnId>) {
+    let log_path = dir.to_string() + "/library.wal";
+    let log = LogManager::open(&log_path).unwrap();
+    let disk = DiskMgr::open(&(dir.to_string() + "/library.db")).unwrap();
+    let mut recovery = RecoveryManager::new(log, disk);
+    recovery.set_master_record(&checkpoint::master_path(&log_path));
+    let stats = recovery.recover().unwrap();
+    println!(
+        "recovered: {} updates redone, {} undone",
+        stats.redone, stats.undone
+    );
+
+    let pool = BufferPool::open(&(dir.to_string() + "/library.db")).unwrap();
+    let heap = TableHeap::open(pool.clone(), layout.songs);
+    let by_id = BLinkTree::open(pool.clone(), layout.by_id, MAX_ENTRIES);
+    (pool, heap, by_id, stats.losers)
+}
+
+fn run(dir: &str) {
+    std::fs::create_dir_all(dir).unwrap();
+    let songs = parse_csv(SONGS_CSV);
+    let catalog = Catalog::create();
+    let (library, layout) = load(dir, &catalog, &songs);
+    let library = Arc::new(library);
+    let expected = serve(&library, &songs);
+    let library = Arc::into_inner(library).unwrap();
+    let interrupted = crash(library, songs[0].id);
+
+    let (pool, heap, by_id, losers) = recover(dir, layout);
+    assert!(losers == vec![interrupted]);
+    let table = catalog.lookup("songs").unwrap();
+    assert!(catalog.estimate_rows(table.oid) == Some(songs.len() as u64));
+    assert!(by_id.iter().count() == songs.len());
+    let plays = pool.fetch_page_read(layout.plays).unwrap();
+    for song in &songs {
+        let rid = unpack(by_id.search(&blink::key(song.id)).unwrap().unwrap());
+        let stored: Song = io::decode(heap.get_tuple(rid).unwrap()).unwrap();
+        assert!(stored == *song);
+        let offset = plays_offset(song.id);
+        let count = u64::from_le_bytes(plays[offset..offset + 8].try_into().unwrap());
+        assert!(count == expected[&song.id]);
+    }
+    let (title, count) = songs
+        .iter()
+        .map(|song| (&song.title, expected[&song.id]))
+        .max_by_key(|(_, count)| *count)
+        .unwrap();
+    println!(
+        "every play count survived the crash; most played: {} ({} plays)",
+        title, count
+    );
+}
+
+fn main() {
+    let dir = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| cwd() + "/tests/music_library");
+    run(&dir);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_music_library() {
+        let dir = cwd() + "/tests/music_library_tests";
+        run(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,366 @@
+/// This file implements the workload generator behind the benchmarks in `benches/`. A `Workload` describes a stream of page
+/// reads and writes (how many pages, the read/write mix, how accesses are spread over the pages, how many threads issue
+/// them) and generates each thread's operations from a seed, so the same workload replays exactly against every
+/// configuration being compared: different replacement policies, a single pool against a `ParallelBufferPool`, latched
+/// against optimistic reads, or disk managers on their own (update-in-place against log-structured).
+///
+/// Zipfian access ranks pages by id: page 0 is the hottest. Runs report what they did along with the pool's hit rate, so a
+/// replacement policy can be judged on both speed and the IO it saves.
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::shared::{PageId, PAGE_SIZE};
+use crate::storage::buffer::bufmgr::BufApi;
+use crate::storage::buffer::diskmgr::DiskApi;
+use crate::storage::buffer::page;
+use crate::storage::error::StorageError;
+
+/// Byte of each page the workload reads and bumps. Past the page header, so checksums and LSNs are left alone
+const TOUCHED_BYTE: usize = PAGE_SIZE / 2;
+
+/// How a workload spreads its accesses over its pages
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Distribution {
+    /// Every page is equally likely
+    Uniform,
+    /// The page of rank `i` is picked with probability proportional to `1 / (i + 1)^theta`. A theta of 0.99 is the usual
+    /// YCSB skew; 0 is uniform
+    Zipfian(f64),
+}
+
+/// How the buffer pool is asked for a page that's only read
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadMode {
+    /// `fetch_page_read`: pin the page and take its frame's shared latch
+    Latched,
+    /// `read_page_optimistic`, falling back to `fetch_page_read` when the page isn't resident or is being written
+    Optimistic,
+}
+
+/// One operation of a workload
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Read(usize),
+    Write(usize),
+}
+
+/// Picks page indexes following a `Distribution`
+enum Sampler {
+    Uniform(usize),
+    // cumulative weights, one per page
+    Zipfian(Vec<f64>),
+}
+
+impl Sampler {
+    fn new(pages: usize, distribution: Distribution) -> Self {
+        match distribution {
+            Distribution::Uniform => Sampler::Uniform(pages),
+            Distribution::Zipfian(theta) => {
+                let mut total = 0.0;
+                let cumulative = (0..pages)
+                    .map(|rank| {
+                        total += 1.0 / ((rank + 1) as f64).powf(theta);
+                        total
+                    })
+                    .collect();
+                Sampler::Zipfian(cumulative)
+            }
+        }
+    }
+
+    fn sample(&self, rng: &mut StdRng) -> usize {
+        match self {
+            Sampler::Uniform(pages) => rng.gen_range(0..*pages),
+            Sampler::Zipfian(cumulative) => {
+                let target = rng.gen::<f64>() * cumulative[cumulative.len() - 1];
+                cumulative
+                    .partition_point(|weight| *weight <= target)
+                    .min(cumulative.len() - 1)
+            }
+        }
+    }
+}
+
+/// A reproducible stream of page accesses. Build one with `Workload::new` and the `with_*` methods
+#[derive(Clone, Debug)]
+pub struct Workload {
+    pages: usize,
+    ops_per_thread: usize,
+    read_ratio: f64,
+    distribution: Distribution,
+    threads: usize,
+    read_mode: ReadMode,
+    seed: u64,
+}
+
+impl Workload {
+    /// A workload over `pages` pages: 10,000 operations on one thread, 80% reads, uniform access, latched reads
+    pub fn new(pages: usize) -> Self {
+        assert!(pages > 0);
+        Workload {
+            pages,
+            ops_per_thread: 10_000,
+            read_ratio: 0.8,
+            distribution: Distribution::Uniform,
+            threads: 1,
+            read_mode: ReadMode::Latched,
+            seed: 0,
+        }
+    }
+
+    /// Operations each thread issues
+    pub fn with_ops(mut self, ops_per_thread: usize) -> Self {
+        self.ops_per_thread = ops_per_thread;
+        self
+    }
+
+    /// Fraction of operations that are reads, between 0 and 1
+    pub fn with_read_ratio(mut self, read_ratio: f64) -> Self {
+        assert!((0.0..=1.0).contains(&read_ratio));
+        self.read_ratio = read_ratio;
+        self
+    }
+
+    pub fn with_distribution(mut self, distribution: Distribution) -> Self {
+        self.distribution = distribution;
+        self
+    }
+
+    /// Number of threads issuing operations at once. Must be at least 1
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        assert!(threads > 0);
+        self.threads = threads;
+        self
+    }
+
+    pub fn with_read_mode(mut self, read_mode: ReadMode) -> Self {
+        self.read_mode = read_mode;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// The operations thread `thread` issues, as indexes into the workload's pages. The same workload always generates the
+    /// same operations for a thread
+    pub fn ops(&self, thread: usize) -> Vec<Op> {
+        let mut rng = StdRng::seed_from_u64(self.seed.wrapping_add(thread as u64));
+        let sampler = Sampler::new(self.pages, self.distribution);
+        (0..self.ops_per_thread)
+            .map(|_| {
+                let page = sampler.sample(&mut rng);
+                if rng.gen::<f64>() < self.read_ratio {
+                    Op::Read(page)
+                } else {
+                    Op::Write(page)
+                }
+            })
+            .collect()
+    }
+
+    /// Allocate the workload's pages in `pool`, returning their ids in rank order
+    pub fn populate<P: BufApi>(&self, pool: &P) -> Result<Vec<PageId>, StorageError> {
+        (0..self.pages).map(|_| pool.alloc_page()).collect()
+    }
+
+    /// Allocate the workload's pages straight through `disk`, returning their ids in rank order
+    pub fn populate_disk<D: DiskApi>(&self, disk: &D) -> Result<Vec<PageId>, StorageError> {
+        let buf = page::empty();
+        (0..self.pages).map(|_| disk.allocate_page(&buf)).collect()
+    }
+
+    /// Run the workload against `pool`, over the pages `populate` returned. Every thread runs its operations to the end;
+    /// the first error any of them hits is returned
+    pub fn run<P: BufApi + Sync>(
+        &self,
+        pool: &P,
+        page_ids: &[PageId],
+    ) -> Result<WorkloadReport, StorageError> {
+        assert!(page_ids.len() == self.pages);
+        let before = pool.stats();
+        let ops: Vec<Vec<Op>> = (0..self.threads).map(|thread| self.ops(thread)).collect();
+        let start = Instant::now();
+        let results: Vec<Result<(), StorageError>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = ops
+                .iter()
+                .map(|ops| scope.spawn(move || self.run_thread(pool, page_ids, ops)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        let elapsed = start.elapsed();
+        results.into_iter().collect::<Result<(), StorageError>>()?;
+        let after = pool.stats();
+        let mut report = WorkloadReport::count(&ops, elapsed);
+        report.hits = after.hits - before.hits;
+        report.misses = after.misses - before.misses;
+        Ok(report)
+    }
+
+    fn run_thread<P: BufApi>(
+        &self,
+        pool: &P,
+        page_ids: &[PageId],
+        ops: &[Op],
+    ) -> Result<(), StorageError> {
+        for op in ops {
+            match *op {
+                Op::Read(page) => {
+                    let page_id = page_ids[page];
+                    let optimistic = match self.read_mode {
+                        ReadMode::Optimistic => pool.read_page_optimistic(page_id),
+                        ReadMode::Latched => None,
+                    };
+                    match optimistic {
+                        Some(copy) => std::hint::black_box(copy[TOUCHED_BYTE]),
+                        None => std::hint::black_box(pool.fetch_page_read(page_id)?[TOUCHED_BYTE]),
+                    };
+                }
+                Op::Write(page) => {
+                    let mut guard = pool.fetch_page_write(page_ids[page])?;
+                    guard[TOUCHED_BYTE] = guard[TOUCHED_BYTE].wrapping_add(1);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the workload straight against `disk`, over the pages `populate_disk` returned: every operation is a page read
+    /// or write
+    pub fn run_disk<D: DiskApi + Sync>(
+        &self,
+        disk: &D,
+        page_ids: &[PageId],
+    ) -> Result<WorkloadReport, StorageError> {
+        assert!(page_ids.len() == self.pages);
+        let ops: Vec<Vec<Op>> = (0..self.threads).map(|thread| self.ops(thread)).collect();
+        let start = Instant::now();
+        let results: Vec<Result<(), StorageError>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = ops
+                .iter()
+                .map(|ops| {
+                    scope.spawn(move || {
+                        let mut buf = page::empty();
+                        for op in ops {
+                            match *op {
+                                Op::Read(page) => {
+                                    disk.read_page(&mut buf, page_ids[page] as u64)?;
+                                }
+                                Op::Write(page) => {
+                                    buf[TOUCHED_BYTE] = buf[TOUCHED_BYTE].wrapping_add(1);
+                                    disk.write_page(&buf, page_ids[page] as u64)?;
+                                }
+                            }
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        let elapsed = start.elapsed();
+        results.into_iter().collect::<Result<(), StorageError>>()?;
+        Ok(WorkloadReport::count(&ops, elapsed))
+    }
+}
+
+/// What a run of a workload did
+#[derive(Clone, Debug, Default)]
+pub struct WorkloadReport {
+    pub reads: usize,
+    pub writes: usize,
+    /// Buffer pool hits and misses during the run; both 0 for runs against the disk manager
+    pub hits: usize,
+    pub misses: usize,
+    pub elapsed: Duration,
+}
+
+impl WorkloadReport {
+    fn count(ops: &[Vec<Op>], elapsed: Duration) -> Self {
+        let reads = ops
+            .iter()
+            .flatten()
+            .filter(|op| matches!(op, Op::Read(_)))
+            .count();
+        let total: usize = ops.iter().map(Vec::len).sum();
+        WorkloadReport {
+            reads,
+            writes: total - reads,
+            elapsed,
+            ..Default::default()
+        }
+    }
+
+    /// Fraction of page requests the buffer pool served without reading from disk
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+
+    /// Operations per second
+    pub fn throughput(&self) -> f64 {
+        (self.reads + self.writes) as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::buffer::bufmgr::BufferPool;
+
+    #[test]
+    fn test_workload() {
+        let workload = Workload::new(100)
+            .with_ops(5000)
+            .with_read_ratio(0.5)
+            .with_distribution(Distribution::Zipfian(0.99))
+            .with_threads(4)
+            .with_seed(7);
+        assert!(workload.ops(1) == workload.ops(1));
+        assert!(workload.ops(1) != workload.ops(2));
+        let ops = workload.ops(0);
+        let hottest = ops
+            .iter()
+            .filter(|op| matches!(op, Op::Read(0) | Op::Write(0)))
+            .count();
+        let coldest = ops
+            .iter()
+            .filter(|op| matches!(op, Op::Read(99) | Op::Write(99)))
+            .count();
+        assert!(hottest > 10 * coldest);
+
+        let dir = cwd() + "/tests/bench_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir + "/test_workload.bin";
+        let pool = BufferPool::create(&path).unwrap();
+        let page_ids = workload.populate(&pool).unwrap();
+        let report = workload.run(&pool, &page_ids).unwrap();
+        assert!(report.reads + report.writes == 20000);
+        assert!(report.hits + report.misses == 20000);
+        assert!(report.hit_rate() > 0.5);
+
+        let optimistic = workload.clone().with_read_mode(ReadMode::Optimistic);
+        assert!(optimistic.run(&pool, &page_ids).unwrap().reads == report.reads);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,322 @@
+/// Inspect the on-disk state of a database without running it. Files are read directly, never through the disk manager,
+/// buffer pool or log manager, so pages that fail verification can still be looked at and nothing is ever written:
+///
+/// - `hex <data file> <page id>`: dump a page in hex
+/// - `page <data file> <page id>`: decode a page's header, and its slot directory, B-link node, overflow page or Bloom
+///   filter directory
+/// - `tree <data file> <meta page id>`: walk a B-link tree level by level, from the root down to the leaves
+/// - `log <log file> [from lsn]`: list the log records with their LSNs
+///
+/// Data files written through a compressing disk manager store extents instead of pages and can't be read this way.
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use symmetric_concurrent_v4::shared::{Lsn, PageId, INVALID_PAGE_ID, PAGE_SIZE};
+use symmetric_concurrent_v4::storage::buffer::page::{
+    self, Page, PageHeader, PageType, SlottedPage,
+};
+use symmetric_concurrent_v4::storage::index::blink::{self, Key, Node};
+use symmetric_concurrent_v4::storage::index::bloom;
+use symmetric_concurrent_v4::storage::log::logmgr::LOG_MAGIC;
+use symmetric_concurrent_v4::storage::log::record;
+use symmetric_concurrent_v4::storage::table::overflow::{
+    Stub, NEXT_OVERFLOW_PAGE_OFFSET, OVERFLOW_DATA_SIZE, OVERFLOW_LEN_OFFSET,
+};
+
+const USAGE: &str = "usage: inspect hex <data file> <page id>
+       inspect page <data file> <page id>
+       inspect tree <data file> <meta page id>
+       inspect log <log file> [from lsn]";
+
+fn fail(message: impl std::fmt::Display) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+fn read_page(path: &str, page_id: PageId) -> Page {
+    let mut file =
+        File::open(path).unwrap_or_else(|err| fail(format!("failed to open {}: {}", path, err)));
+    let mut buf = page::empty();
+    let read = file
+        .seek(SeekFrom::Start(page_id as u64 * PAGE_SIZE as u64))
+        .and_then(|_| file.read_exact(&mut buf));
+    if let Err(err) = read {
+        fail(format!(
+            "failed to read page {} of {}: {}",
+            page_id, path, err
+        ));
+    }
+    buf
+}
+
+/// A key in hex, followed by the integer it holds if it looks like one made by `blink::key`
+fn format_key(key: &Key) -> String {
+    let hex: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+    let (high, low) = key.split_at(key.len() - 8);
+    if high.iter().all(|b| *b == 0) {
+        return format!("{} ({})", hex, u64::from_be_bytes(low.try_into().unwrap()));
+    }
+    hex
+}
+
+/// 16 bytes a line, with runs of repeated lines collapsed into a `*` like hexdump does
+fn hex(page: &Page) {
+    let mut previous: Option<&[u8]> = None;
+    let mut collapsed = false;
+    for (i, line) in page.chunks(16).enumerate() {
+        if previous == Some(line) {
+            if !collapsed {
+                println!("*");
+                collapsed = true;
+            }
+            continue;
+        }
+        previous = Some(line);
+        collapsed = false;
+        let bytes: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let text: String = line
+            .iter()
+            .map(|b| {
+                if b.is_ascii_graphic() || *b == b' ' {
+                    *b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        println!("{:04x}  {}  |{}|", i * 16, bytes.join(" "), text);
+    }
+    println!("{:04x}", PAGE_SIZE);
+}
+
+fn print_node(page_id: PageId, node: &Node) {
+    let kind = if node.leaf { "leaf" } else { "internal" };
+    let deleted = if node.deleted { ", deleted" } else { "" };
+    println!(
+        "node {}: {} at level {}{}, {} keys",
+        page_id,
+        kind,
+        node.level,
+        deleted,
+        node.keys.len()
+    );
+    println!("  right link: {}", node.right);
+    if node.outlink != INVALID_PAGE_ID {
+        println!("  outlink: {}", node.outlink);
+    }
+    match &node.high_key {
+        Some(high_key) => println!("  high key: {}", format_key(high_key)),
+        None => println!("  high key: none (rightmost)"),
+    }
+}
+
+fn describe(page_id: PageId, page: &Page) {
+    let header = PageHeader::read(page);
+    let checksum = if page::verify_checksum(page) {
+        "ok"
+    } else {
+        "MISMATCH"
+    };
+    match header.page_type {
+        Some(page_type) => println!("type: {:?}", page_type),
+        None => println!("type: unknown ({})", page[page::PAGE_TYPE_OFFSET]),
+    }
+    println!("lsn: {}", header.lsn);
+    println!("checksum: {:08x} ({})", header.checksum, checksum);
+    match header.checksum_algorithm {
+        Some(algorithm) => println!("checksum algorithm: {:?}", algorithm),
+        None => println!(
+            "checksum algorithm: unknown ({})",
+            page[page::PAGE_CHECKSUM_ALGORITHM_OFFSET]
+        ),
+    }
+    println!("page id: {}", header.page_id);
+    if header.page_id != page_id {
+        println!("  (read from slot {} of the file)", page_id);
+    }
+    println!("free space offset: {}", header.free_space_offset);
+    match header.page_type {
+        Some(PageType::Slotted) => {
+            let slotted = SlottedPage::new(page);
+            println!("slots: {}", slotted.slot_count());
+            println!("prev page: {}", slotted.prev_page_id());
+            println!("next page: {}", slotted.next_page_id());
+            println!(
+                "free space: {} ({} after compaction)",
+                slotted.free_space(),
+                slotted.reclaimable_space()
+            );
+            for slot in 0..slotted.slot_count() {
+                match slotted.slot(slot) {
+                    (0, _) => println!("  slot {}: empty", slot),
+                    (offset, len) => match Stub::decode(slotted.get(slot).unwrap()) {
+                        Some(stub) if slotted.is_overflow(slot) => println!(
+                            "  slot {}: {} bytes at {}, overflow stub for {} bytes from page {}",
+                            slot, len, offset, stub.len, stub.first_page_id
+                        ),
+                        _ => println!("  slot {}: {} bytes at {}", slot, len, offset),
+                    },
+                }
+            }
+        }
+        Some(PageType::BLinkMeta) => {
+            println!("root: {}", blink::root_page_id(page).unwrap());
+            match blink::filter_page_id(page) {
+                Some(filter) => println!("filter: {}", filter),
+                None => println!("filter: none"),
+            }
+        }
+        Some(PageType::BLinkNode) => {
+            let node = Node::decode(page).unwrap();
+            print_node(page_id, &node);
+            if node.leaf {
+                for (key, value) in node.keys.iter().zip(node.values.iter()) {
+                    println!("  {} -> {}", format_key(key), value);
+                }
+            } else {
+                println!("  child {}", node.values[0]);
+                for (key, child) in node.keys.iter().zip(node.values[1..].iter()) {
+                    println!("  {} | child {}", format_key(key), child);
+                }
+            }
+        }
+        Some(PageType::Overflow) => {
+            let next = &page[NEXT_OVERFLOW_PAGE_OFFSET..NEXT_OVERFLOW_PAGE_OFFSET + 8];
+            let len = &page[OVERFLOW_LEN_OFFSET..OVERFLOW_LEN_OFFSET + 2];
+            println!(
+                "next page: {}",
+                i64::from_le_bytes(next.try_into().unwrap())
+            );
+            println!(
+                "data: {} of {} bytes",
+                u16::from_le_bytes(len.try_into().unwrap()),
+                OVERFLOW_DATA_SIZE
+            );
+        }
+        Some(PageType::BloomFilter) => match bloom::directory(page) {
+            Some((hashes, blocks)) => {
+                println!("hashes: {}", hashes);
+                println!("blocks: {:?}", blocks);
+            }
+            None => println!("invalid filter directory"),
+        },
+        Some(PageType::BloomBlock) => {
+            let set: u32 = page[page::PAGE_HEADER_SIZE..]
+                .iter()
+                .map(|byte| byte.count_ones())
+                .sum();
+            println!("bits set: {} of {}", set, bloom::BLOCK_BITS);
+        }
+        _ => {}
+    }
+}
+
+/// Print every level of the tree, each by following the right links from its leftmost node
+fn tree(path: &str, meta_page_id: PageId) {
+    let meta = read_page(path, meta_page_id);
+    let Some(root) = blink::root_page_id(&meta) else {
+        fail(format!(
+            "page {} isn't a B-link tree meta page",
+            meta_page_id
+        ));
+    };
+    println!("tree {}: root {}", meta_page_id, root);
+    let mut leftmost = root;
+    let mut visited = HashSet::new();
+    loop {
+        let mut page_id = leftmost;
+        let mut next_level = None;
+        while page_id != INVALID_PAGE_ID {
+            if !visited.insert(page_id) {
+                fail(format!(
+                    "page {} is reached twice: the tree has a cycle",
+                    page_id
+                ));
+            }
+            let Some(node) = Node::decode(&read_page(path, page_id)) else {
+                fail(format!("page {} isn't a B-link tree node", page_id));
+            };
+            if page_id == leftmost {
+                println!("level {}:", node.level);
+                next_level = (!node.leaf).then(|| node.values[0]);
+            }
+            let range = match (node.keys.first(), node.keys.last()) {
+                (Some(first), Some(last)) => {
+                    format!(", {} .. {}", format_key(first), format_key(last))
+                }
+                _ => String::new(),
+            };
+            let deleted = if node.deleted { " (deleted)" } else { "" };
+            println!(
+                "  node {}{}: {} keys{}",
+                page_id,
+                deleted,
+                node.keys.len(),
+                range
+            );
+            page_id = node.right;
+        }
+        match next_level {
+            Some(child) => leftmost = child,
+            None => return,
+        }
+    }
+}
+
+fn log(path: &str, from: Lsn) {
+    let bytes =
+        std::fs::read(path).unwrap_or_else(|err| fail(format!("failed to read {}: {}", path, err)));
+    if bytes.len() < LOG_MAGIC.len() || &bytes[..LOG_MAGIC.len()] != LOG_MAGIC {
+        fail(format!("{} isn't a log file", path));
+    }
+    let mut offset = LOG_MAGIC.len();
+    let mut count = 0;
+    while let Some((record, size)) = record::decode(&bytes[offset..]) {
+        if record.lsn >= from {
+            println!(
+                "{:>10}  txn {:<6} prev {:<10} {:?}",
+                record.lsn, record.txn_id, record.prev_lsn, record.body
+            );
+            count += 1;
+        }
+        offset += size;
+    }
+    println!("{} records", count);
+    if offset < bytes.len() {
+        println!(
+            "torn or corrupt record at {}: {} bytes at the tail don't decode",
+            offset,
+            bytes.len() - offset
+        );
+    }
+}
+
+fn parse<T: std::str::FromStr>(arg: Option<&String>, what: &str) -> T {
+    let Some(arg) = arg else {
+        fail(USAGE);
+    };
+    arg.parse()
+        .unwrap_or_else(|_| fail(format!("invalid {}: {}", what, arg)))
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (Some(command), Some(path)) = (args.get(1), args.get(2)) else {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    };
+    match command.as_str() {
+        "hex" => hex(&read_page(path, parse(args.get(3), "page id"))),
+        "page" => {
+            let page_id = parse(args.get(3), "page id");
+            describe(page_id, &read_page(path, page_id));
+        }
+        "tree" => tree(path, parse(args.get(3), "page id")),
+        "log" => log(path, args.get(3).map_or(0, |_| parse(args.get(3), "lsn"))),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
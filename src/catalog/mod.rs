@@ -0,0 +1,467 @@
+/// This file implements the system catalog: the registry of tables and indexes along with the storage parameters each one was
+/// created with. Registering a relation doesn't allocate its storage: whoever creates it does that through its options
+/// (`StorageOptions::create_pool`, `create_heap`, `build_index`), which is where compression and the fill factor take
+/// effect. The catalog serializes into a single page so it can be persisted through the disk manager.
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{AuditApi as _, AuditEvent, AuditLog};
+use crate::shared::{Oid, PageId};
+use crate::storage::buffer::bufmgr::{self, BufApi as _, BufferPool};
+use crate::storage::buffer::compress::ZeroRunCodec;
+use crate::storage::buffer::io;
+use crate::storage::buffer::page::Page;
+use crate::storage::config::StorageConfig;
+use crate::storage::error::StorageError;
+use crate::storage::index::blink::{BLinkTree, Key, MAX_ENTRIES};
+use crate::storage::table::heap::TableHeap;
+use crate::sync::{RwLatch as _, RwSynchronized};
+
+/// Version of the serialized catalog. Bump it whenever the byte layout of a catalog entry changes
+pub const CATALOG_FORMAT_VERSION: u32 = 1;
+pub const DEFAULT_FILL_FACTOR: u8 = 100;
+pub const MIN_FILL_FACTOR: u8 = 10;
+
+/// Per-relation storage parameters, validated and persisted with the relation when it's created. Compression and the fill
+/// factor are applied by the constructors below; ring-buffer scans are only recorded, since the pool has no scan ring yet.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StorageOptions {
+    /// Whether pages of this relation are compressed when they're written out. A compressed relation needs a data file, and so a
+    /// pool, of its own
+    pub compression: bool,
+    /// Percentage of each page to fill on insert/bulk load, leaving the rest for later updates
+    pub fill_factor: u8,
+    /// Whether sequential scans of this relation should run through a small ring of frames instead of the whole pool
+    pub ring_buffer_scans: bool,
+}
+
+impl Default for StorageOptions {
+    fn default() -> Self {
+        StorageOptions {
+            compression: false,
+            fill_factor: DEFAULT_FILL_FACTOR,
+            ring_buffer_scans: false,
+        }
+    }
+}
+
+impl StorageOptions {
+    pub fn is_valid(&self) -> bool {
+        (MIN_FILL_FACTOR..=100).contains(&self.fill_factor)
+    }
+
+    /// A buffer pool over a new data file for the relation, as `config` says. Its pages are compressed with `ZeroRunCodec`
+    /// if `compression` is set
+    pub fn create_pool(&self, config: &StorageConfig) -> Result<BufferPool, StorageError> {
+        if self.compression {
+            return bufmgr::create_compressed(config, Box::new(ZeroRunCodec));
+        }
+        BufferPool::create_with_config(config)
+    }
+
+    /// Like `create_pool`, but keeping the pages already in the data file
+    pub fn open_pool(&self, config: &StorageConfig) -> Result<BufferPool, StorageError> {
+        if self.compression {
+            return bufmgr::open_compressed(config, Box::new(ZeroRunCodec));
+        }
+        BufferPool::open_with_config(config)
+    }
+
+    /// An empty heap in `pool` whose inserts fill pages to `fill_factor`
+    pub fn create_heap(&self, pool: BufferPool) -> Option<TableHeap> {
+        Some(TableHeap::create(pool)?.with_fill_factor(self.fill_factor))
+    }
+
+    /// A heap created earlier with `create_heap` (see `TableHeap::open_with_fsm`)
+    pub fn open_heap(
+        &self,
+        pool: BufferPool,
+        first_page_id: PageId,
+        fsm_page_id: PageId,
+    ) -> TableHeap {
+        TableHeap::open_with_fsm(pool, first_page_id, fsm_page_id)
+            .with_fill_factor(self.fill_factor)
+    }
+
+    /// An index over `entries`, which must be sorted by key with no duplicates, bulk loaded into `pool` with its nodes
+    /// filled to `fill_factor` (see `BLinkTree::bulk_load_with`)
+    pub fn build_index<I: IntoIterator<Item = (Key, PageId)>>(
+        &self,
+        pool: BufferPool,
+        entries: I,
+    ) -> Option<BLinkTree> {
+        let fill_factor = self.fill_factor as f64 / 100.0;
+        BLinkTree::bulk_load_with(pool, MAX_ENTRIES, fill_factor, entries)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelationKind {
+    Table,
+    /// An index on the table with the given oid
+    Index(Oid),
+}
+
+/// Approximate size of a relation. Maintained incrementally on insert/delete so callers can get an estimate without a full
+/// scan; vacuum/analyze overwrite it with exact figures to correct any drift.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TableStats {
+    pub rows: u64,
+    pub pages: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TableInfo {
+    pub oid: Oid,
+    pub name: String,
+    pub kind: RelationKind,
+    pub options: StorageOptions,
+    pub stats: TableStats,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct CatalogInternal {
+    next_oid: Oid,
+    tables: HashMap<Oid, TableInfo>,
+    #[serde(skip)]
+    audit: Option<AuditLog>,
+}
+
+pub type Catalog = RwSynchronized<CatalogInternal>;
+
+pub trait CatalogApi {
+    fn create() -> Self;
+    fn create_table(&self, name: &str, options: StorageOptions) -> Option<Oid>;
+    fn create_index(&self, name: &str, table: Oid, options: StorageOptions) -> Option<Oid>;
+    fn drop_table(&self, oid: Oid) -> bool;
+    fn table(&self, oid: Oid) -> Option<TableInfo>;
+    fn lookup(&self, name: &str) -> Option<TableInfo>;
+    fn options(&self, oid: Oid) -> Option<StorageOptions>;
+    fn record_insert(&self, oid: Oid, rows: u64) -> bool;
+    fn record_delete(&self, oid: Oid, rows: u64) -> bool;
+    fn record_pages(&self, oid: Oid, pages: i64) -> bool;
+    fn set_stats(&self, oid: Oid, stats: TableStats) -> bool;
+    fn estimate_rows(&self, oid: Oid) -> Option<u64>;
+    fn estimate_pages(&self, oid: Oid) -> Option<u64>;
+    fn to_page(&self) -> Option<Page>;
+    fn from_page(page: &Page) -> Option<Self>
+    where
+        Self: Sized;
+    fn set_audit_log(&self, audit: AuditLog);
+}
+
+impl CatalogInternal {
+    fn update_stats(&mut self, oid: Oid, f: impl FnOnce(&mut TableStats)) -> bool {
+        match self.tables.get_mut(&oid) {
+            Some(info) => {
+                f(&mut info.stats);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Audit records are best effort: a failed audit write doesn't undo the catalog change
+    fn audit(&self, event: AuditEvent) {
+        if let Some(audit) = &self.audit {
+            let _ = audit.record("catalog", event);
+        }
+    }
+
+    fn register(&mut self, name: &str, kind: RelationKind, options: StorageOptions) -> Option<Oid> {
+        if !options.is_valid() || self.tables.values().any(|t| t.name == name) {
+            return None;
+        }
+        // oid 0 is never handed out so it can be used as a sentinel
+        self.next_oid = self.next_oid.checked_add(1)?;
+        let oid = self.next_oid;
+        self.tables.insert(
+            oid,
+            TableInfo {
+                oid,
+                name: name.to_string(),
+                kind,
+                options,
+                stats: TableStats::default(),
+            },
+        );
+        Some(oid)
+    }
+}
+
+impl CatalogApi for Catalog {
+    fn create() -> Self {
+        RwSynchronized::init(CatalogInternal::default())
+    }
+
+    /// Register a new table. Returns `None` if the name is taken or the options are out of range
+    fn create_table(&self, name: &str, options: StorageOptions) -> Option<Oid> {
+        let mut inner = self.write();
+        let oid = inner.register(name, RelationKind::Table, options)?;
+        inner.audit(AuditEvent::CreateTable {
+            oid,
+            name: name.to_string(),
+        });
+        Some(oid)
+    }
+
+    /// Register a new index on `table`. Returns `None` if the table doesn't exist, the name is taken, or the options are out of
+    /// range
+    fn create_index(&self, name: &str, table: Oid, options: StorageOptions) -> Option<Oid> {
+        let mut inner = self.write();
+        match inner.tables.get(&table) {
+            Some(info) if info.kind == RelationKind::Table => {}
+            _ => return None,
+        }
+        let oid = inner.register(name, RelationKind::Index(table), options)?;
+        inner.audit(AuditEvent::CreateIndex {
+            oid,
+            name: name.to_string(),
+            table,
+        });
+        Some(oid)
+    }
+
+    /// Remove a table (and every index on it) or a single index from the catalog
+    fn drop_table(&self, oid: Oid) -> bool {
+        let mut inner = self.write();
+        let Some(info) = inner.tables.remove(&oid) else {
+            return false;
+        };
+        inner
+            .tables
+            .retain(|_, t| t.kind != RelationKind::Index(oid));
+        inner.audit(AuditEvent::DropTable {
+            oid,
+            name: info.name,
+        });
+        true
+    }
+
+    fn table(&self, oid: Oid) -> Option<TableInfo> {
+        let inner = self.read();
+        inner.tables.get(&oid).cloned()
+    }
+
+    fn lookup(&self, name: &str) -> Option<TableInfo> {
+        let inner = self.read();
+        inner.tables.values().find(|t| t.name == name).cloned()
+    }
+
+    fn options(&self, oid: Oid) -> Option<StorageOptions> {
+        let inner = self.read();
+        inner.tables.get(&oid).map(|t| t.options)
+    }
+
+    /// Count `rows` newly inserted rows towards the estimate
+    fn record_insert(&self, oid: Oid, rows: u64) -> bool {
+        let mut inner = self.write();
+        inner.update_stats(oid, |s| s.rows = s.rows.saturating_add(rows))
+    }
+
+    /// Count `rows` deleted rows towards the estimate
+    fn record_delete(&self, oid: Oid, rows: u64) -> bool {
+        let mut inner = self.write();
+        inner.update_stats(oid, |s| s.rows = s.rows.saturating_sub(rows))
+    }
+
+    /// Adjust the page count by `pages` (negative when pages are freed)
+    fn record_pages(&self, oid: Oid, pages: i64) -> bool {
+        let mut inner = self.write();
+        inner.update_stats(oid, |s| {
+            s.pages = s.pages.saturating_add_signed(pages);
+        })
+    }
+
+    /// Replace the estimate with exact figures (used by vacuum/analyze)
+    fn set_stats(&self, oid: Oid, stats: TableStats) -> bool {
+        let mut inner = self.write();
+        inner.update_stats(oid, |s| *s = stats)
+    }
+
+    fn estimate_rows(&self, oid: Oid) -> Option<u64> {
+        let inner = self.read();
+        inner.tables.get(&oid).map(|t| t.stats.rows)
+    }
+
+    fn estimate_pages(&self, oid: Oid) -> Option<u64> {
+        let inner = self.read();
+        inner.tables.get(&oid).map(|t| t.stats.pages)
+    }
+
+    /// Serialize the catalog into a page so it can be written through the disk manager
+    fn to_page(&self) -> Option<Page> {
+        let inner = self.read();
+        io::to_buffer(&*inner).ok()
+    }
+
+    fn from_page(page: &Page) -> Option<Self> {
+        let inner: CatalogInternal = io::from_buffer(page).ok()?;
+        Some(RwSynchronized::init(inner))
+    }
+
+    /// Record DDL performed through this catalog in `audit`
+    fn set_audit_log(&self, audit: AuditLog) {
+        self.write().audit = Some(audit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::buffer::compress;
+    use crate::storage::index::blink;
+    use crate::storage::table::fsm::FreeSpaceMapApi as _;
+    use crate::storage::table::heap::Rid;
+    use crate::testing::Song;
+
+    #[test]
+    fn test_create_table_with_options() {
+        let catalog = Catalog::create();
+        let options = StorageOptions {
+            compression: true,
+            fill_factor: 70,
+            ring_buffer_scans: true,
+        };
+        let songs = catalog.create_table("songs", options).unwrap();
+        let artists = catalog
+            .create_table("artists", StorageOptions::default())
+            .unwrap();
+        assert!(songs != artists);
+        assert!(catalog.create_table("songs", options).is_none());
+
+        let bad = StorageOptions {
+            fill_factor: 5,
+            ..StorageOptions::default()
+        };
+        assert!(catalog.create_table("albums", bad).is_none());
+
+        let idx = catalog
+            .create_index("songs_by_id", songs, StorageOptions::default())
+            .unwrap();
+        assert!(catalog.create_index("bad_idx", idx, options).is_none());
+        assert!(catalog.table(idx).unwrap().kind == RelationKind::Index(songs));
+        assert!(catalog.options(songs).unwrap() == options);
+        assert!(catalog.lookup("artists").unwrap().oid == artists);
+
+        assert!(catalog.drop_table(songs));
+        assert!(catalog.table(idx).is_none());
+        assert!(!catalog.drop_table(songs));
+    }
+
+    #[test]
+    fn test_catalog_page_roundtrip() {
+        let catalog = Catalog::create();
+        let options = StorageOptions {
+            compression: true,
+            fill_factor: 90,
+            ring_buffer_scans: false,
+        };
+        let songs = catalog.create_table("songs", options).unwrap();
+        let page = catalog.to_page().unwrap();
+
+        let restored = Catalog::from_page(&page).unwrap();
+        let info = restored.table(songs).unwrap();
+        assert!(info.name == "songs");
+        assert!(info.options == options);
+        // oids keep counting from where the persisted catalog left off
+        let next = restored
+            .create_table("artists", StorageOptions::default())
+            .unwrap();
+        assert!(next > songs);
+    }
+
+    #[test]
+    fn test_estimates() {
+        let catalog = Catalog::create();
+        let songs = catalog
+            .create_table("songs", StorageOptions::default())
+            .unwrap();
+        assert!(catalog.estimate_rows(songs) == Some(0));
+
+        assert!(catalog.record_insert(songs, 15));
+        assert!(catalog.record_pages(songs, 2));
+        assert!(catalog.record_delete(songs, 5));
+        assert!(catalog.record_pages(songs, -1));
+        assert!(catalog.estimate_rows(songs) == Some(10));
+        assert!(catalog.estimate_pages(songs) == Some(1));
+
+        // deleting more than the estimate clamps instead of wrapping
+        assert!(catalog.record_delete(songs, 100));
+        assert!(catalog.estimate_rows(songs) == Some(0));
+
+        assert!(catalog.set_stats(songs, TableStats { rows: 7, pages: 3 }));
+        assert!(catalog.estimate_rows(songs) == Some(7));
+        assert!(catalog.estimate_pages(songs) == Some(3));
+
+        assert!(!catalog.record_insert(songs + 1, 1));
+        assert!(catalog.estimate_rows(songs + 1).is_none());
+    }
+
+    #[test]
+    fn test_options_applied() {
+        let dir = cwd() + "/tests/catalog_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/test_options_applied.bin", dir);
+        let config = StorageConfig::for_path(&path);
+        let catalog = Catalog::create();
+        let options = StorageOptions {
+            compression: true,
+            fill_factor: 50,
+            ring_buffer_scans: false,
+        };
+        let songs = catalog.create_table("songs", options).unwrap();
+
+        let options = catalog.options(songs).unwrap();
+        let pool = options.create_pool(&config).unwrap();
+        let heap = options.create_heap(pool.clone()).unwrap();
+        let tuples: Vec<Vec<u8>> = (0..200)
+            .map(|i| io::encode(Song::new(i, "Afraid", "The Neighbourhood")).unwrap())
+            .collect();
+        let rids: Vec<Rid> = tuples
+            .iter()
+            .map(|tuple| heap.insert_tuple(tuple).unwrap())
+            .collect();
+        let pages: HashSet<PageId> = rids.iter().map(|rid| rid.page_id).collect();
+        pool.flush_all().unwrap();
+        // the pages went through the compressing disk manager, which keeps a page table next to the data file
+        assert!(Path::new(&compress::table_path(&path)).exists());
+        let (first_page_id, fsm_page_id) = (heap.first_page_id(), heap.fsm().root());
+        drop(heap);
+        pool.close().unwrap();
+        drop(pool);
+
+        let pool = options.open_pool(&config).unwrap();
+        let heap = options.open_heap(pool.clone(), first_page_id, fsm_page_id);
+        for (rid, tuple) in rids.iter().zip(&tuples) {
+            assert!(heap.get_tuple(*rid).unwrap() == *tuple);
+        }
+        // the same tuples packed into full pages take about half as many
+        let full = StorageOptions::default().create_heap(pool.clone()).unwrap();
+        let full_pages: HashSet<PageId> = tuples
+            .iter()
+            .map(|tuple| full.insert_tuple(tuple).unwrap().page_id)
+            .collect();
+        assert!(pages.len() >= full_pages.len() * 3 / 2);
+
+        let entries = |n: u64| (0..n).map(|i| (blink::key(i), i as PageId));
+        let before = pool.read().disk().num_pages().unwrap();
+        let index = options.build_index(pool.clone(), entries(2000)).unwrap();
+        let half_filled = pool.read().disk().num_pages().unwrap() - before;
+        assert!(index.search(&blink::key(1234)).unwrap() == Some(1234));
+        let before = pool.read().disk().num_pages().unwrap();
+        StorageOptions::default()
+            .build_index(pool.clone(), entries(2000))
+            .unwrap();
+        let packed = pool.read().disk().num_pages().unwrap() - before;
+        assert!(half_filled >= packed * 3 / 2);
+        // the disk manager saves its page table when the last handle to it goes
+        drop((heap, full, index, pool));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
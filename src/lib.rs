@@ -0,0 +1,11 @@
+pub mod audit;
+pub mod bench;
+pub mod catalog;
+pub mod shared;
+pub mod sim;
+pub mod storage;
+pub mod sync;
+pub mod telemetry;
+#[cfg(test)]
+mod testing;
+pub mod txn;
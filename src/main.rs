@@ -1,7 +1,28 @@
-mod shared;
-mod storage;
-mod sync;
+use symmetric_concurrent_v4::audit;
 
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        // audit <file> [origin]: print the audit log, optionally only the records from one origin
+        Some("audit") => {
+            let Some(path) = args.get(2) else {
+                eprintln!("usage: {} audit <file> [origin]", args[0]);
+                std::process::exit(2);
+            };
+            let records = match audit::read_records(path) {
+                Ok(records) => records,
+                Err(err) => {
+                    eprintln!("failed to read {}: {}", path, err);
+                    std::process::exit(1);
+                }
+            };
+            for record in records
+                .iter()
+                .filter(|r| args.get(3).is_none_or(|origin| r.origin == *origin))
+            {
+                println!("{}", record);
+            }
+        }
+        _ => println!("Hello, world!"),
+    }
 }
@@ -0,0 +1,162 @@
+/// This file implements deterministic simulation support. A `Simulation` owns a seeded random number generator and a logical
+/// clock; components that would otherwise depend on thread timing or wall-clock time (the buffer pool's choice of free frame,
+/// the interleaving of background tasks) draw from it instead, so a run is fully determined by its seed. A failing test prints
+/// its seed and can be replayed bit-for-bit by setting `SIM_SEED`.
+///
+/// The `Scheduler` runs a set of tasks on the calling thread, picking which one takes the next step from the simulation's RNG.
+/// Tasks written as small steps (one insert, one compression pass, one flush) get a reproducible interleaving without any real
+/// concurrency.
+use std::ops::Range;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::sync::{Latch as _, Synchronized};
+
+/// Environment variable holding the seed to replay
+pub const SEED_VAR: &str = "SIM_SEED";
+
+pub struct SimulationInternal {
+    seed: u64,
+    rng: StdRng,
+    // logical time, advanced by `tick`
+    clock: u64,
+}
+
+pub type Simulation = Synchronized<SimulationInternal>;
+
+pub trait SimApi {
+    fn create(seed: u64) -> Self;
+    fn from_env() -> Self;
+    fn seed(&self) -> u64;
+    fn now(&self) -> u64;
+    fn tick(&self) -> u64;
+    fn next_u64(&self) -> u64;
+    fn gen_range(&self, range: Range<usize>) -> usize;
+}
+
+impl SimApi for Simulation {
+    fn create(seed: u64) -> Self {
+        Synchronized::init(SimulationInternal {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            clock: 0,
+        })
+    }
+
+    /// Use the seed in `SIM_SEED` if set, otherwise pick one at random. The seed is printed so a failing run can be replayed
+    fn from_env() -> Self {
+        let seed = std::env::var(SEED_VAR)
+            .ok()
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or_else(rand::random);
+        println!("{}={}", SEED_VAR, seed);
+        Simulation::create(seed)
+    }
+
+    fn seed(&self) -> u64 {
+        self.lock().seed
+    }
+
+    /// The current logical time
+    fn now(&self) -> u64 {
+        self.lock().clock
+    }
+
+    /// Advance the logical clock by one, returning the new time
+    fn tick(&self) -> u64 {
+        let mut inner = self.lock();
+        inner.clock += 1;
+        inner.clock
+    }
+
+    fn next_u64(&self) -> u64 {
+        self.lock().rng.gen()
+    }
+
+    /// A number drawn uniformly from `range`, which must not be empty
+    fn gen_range(&self, range: Range<usize>) -> usize {
+        self.lock().rng.gen_range(range)
+    }
+}
+
+/// A step of work. Returns false once the task has finished
+pub type Task = Box<dyn FnMut() -> bool + Send>;
+
+/// Runs tasks one step at a time on the calling thread, in an order chosen by the simulation
+pub struct Scheduler {
+    sim: Simulation,
+    tasks: Vec<Task>,
+}
+
+impl Scheduler {
+    pub fn new(sim: Simulation) -> Self {
+        Scheduler {
+            sim,
+            tasks: Vec::new(),
+        }
+    }
+
+    pub fn spawn<F: FnMut() -> bool + Send + 'static>(&mut self, task: F) {
+        self.tasks.push(Box::new(task));
+    }
+
+    /// Advance the clock and run one step of a randomly chosen task. Returns false if there are no tasks left
+    pub fn step(&mut self) -> bool {
+        if self.tasks.is_empty() {
+            return false;
+        }
+        self.sim.tick();
+        let i = self.sim.gen_range(0..self.tasks.len());
+        if !(self.tasks[i])() {
+            // keep the remaining tasks in a fixed order so later choices don't depend on how removal shuffles them
+            let _ = self.tasks.remove(i);
+        }
+        true
+    }
+
+    /// Run until every task has finished, returning the number of steps taken
+    pub fn run(&mut self) -> usize {
+        let mut steps = 0;
+        while self.step() {
+            steps += 1;
+        }
+        steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+
+    use super::*;
+
+    fn trace(seed: u64) -> Vec<(usize, u64)> {
+        let sim = Simulation::create(seed);
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let mut scheduler = Scheduler::new(sim.clone());
+        for id in 0..4 {
+            let trace = trace.clone();
+            let sim = sim.clone();
+            let mut remaining = 10;
+            scheduler.spawn(move || {
+                trace.lock().push((id, sim.now()));
+                remaining -= 1;
+                remaining > 0
+            });
+        }
+        assert!(scheduler.run() == 40);
+        let trace = trace.lock().clone();
+        trace
+    }
+
+    #[test]
+    fn test_replay() {
+        assert!(trace(7) == trace(7));
+        assert!(trace(7) != trace(8));
+        let times: Vec<u64> = trace(7).iter().map(|(_, time)| *time).collect();
+        assert!(times == (1..=40).collect::<Vec<u64>>());
+    }
+}
@@ -0,0 +1,191 @@
+/// This file implements the backup manifest: a record, stored alongside a backup, of every file the backup contains with its
+/// size and CRC32C checksum, plus the checkpoint LSN the backup is consistent as of. `verify_backup` re-checksums a backup
+/// directory against its manifest so a damaged or incomplete backup is caught before anyone restores from it.
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::buffer::io;
+
+pub const MANIFEST_FILE: &str = "backup_manifest";
+pub const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Path relative to the backup directory, with `/` separators
+    pub path: String,
+    pub size: u64,
+    pub checksum: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BackupManifest {
+    pub version: u32,
+    /// LSN of the checkpoint the backup is consistent as of (0 if taken without one)
+    pub checkpoint_lsn: u64,
+    pub files: Vec<ManifestEntry>,
+}
+
+/// Something `verify_backup` found wrong with a backup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackupProblem {
+    UnsupportedVersion(u32),
+    Missing(String),
+    SizeMismatch {
+        path: String,
+        expected: u64,
+        actual: u64,
+    },
+    ChecksumMismatch(String),
+}
+
+/// CRC32C of a file's contents along with its length
+pub fn checksum_file(path: &Path) -> std::io::Result<(u64, u32)> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut checksum = 0u32;
+    let mut size = 0u64;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        checksum = crc32c::crc32c_append(checksum, &buf[..n]);
+        size += n as u64;
+    }
+    Ok((size, checksum))
+}
+
+fn collect(root: &Path, dir: &Path, files: &mut Vec<ManifestEntry>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect(root, &path, files)?;
+            continue;
+        }
+        let relative = path
+            .strip_prefix(root)
+            .unwrap()
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        if relative == MANIFEST_FILE {
+            continue;
+        }
+        let (size, checksum) = checksum_file(&path)?;
+        files.push(ManifestEntry {
+            path: relative,
+            size,
+            checksum,
+        });
+    }
+    Ok(())
+}
+
+impl BackupManifest {
+    /// Build a manifest describing every file under `dir` (recursively), excluding any existing manifest
+    pub fn build(dir: &Path, checkpoint_lsn: u64) -> std::io::Result<BackupManifest> {
+        let mut files = Vec::new();
+        collect(dir, dir, &mut files)?;
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(BackupManifest {
+            version: MANIFEST_VERSION,
+            checkpoint_lsn,
+            files,
+        })
+    }
+
+    /// Write the manifest into `dir` and sync it
+    pub fn write(&self, dir: &Path) -> std::io::Result<()> {
+        use std::io::Write;
+        let encoded = io::encode(self)?;
+        let mut file = File::create(dir.join(MANIFEST_FILE))?;
+        file.write_all(&encoded)?;
+        file.sync_all()
+    }
+
+    /// Read the manifest stored in `dir`
+    pub fn read(dir: &Path) -> std::io::Result<BackupManifest> {
+        let bytes = std::fs::read(dir.join(MANIFEST_FILE))?;
+        io::decode(bytes).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "corrupt backup manifest")
+        })
+    }
+}
+
+/// Check every file listed in the manifest of the backup in `dir` for presence, size, and checksum. Returns the problems found;
+/// an empty list means the backup is safe to restore from. Fails outright only if the manifest itself can't be read.
+pub fn verify_backup(dir: &Path) -> std::io::Result<Vec<BackupProblem>> {
+    let manifest = BackupManifest::read(dir)?;
+    if manifest.version != MANIFEST_VERSION {
+        return Ok(vec![BackupProblem::UnsupportedVersion(manifest.version)]);
+    }
+    let mut problems = Vec::new();
+    for entry in manifest.files.iter() {
+        let path = dir.join(&entry.path);
+        if !path.is_file() {
+            problems.push(BackupProblem::Missing(entry.path.clone()));
+            continue;
+        }
+        let (size, checksum) = checksum_file(&path)?;
+        if size != entry.size {
+            problems.push(BackupProblem::SizeMismatch {
+                path: entry.path.clone(),
+                expected: entry.size,
+                actual: size,
+            });
+        } else if checksum != entry.checksum {
+            problems.push(BackupProblem::ChecksumMismatch(entry.path.clone()));
+        }
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::cwd;
+
+    #[test]
+    fn test_build_and_verify() {
+        let dir = cwd() + "/tests/manifest_tests";
+        let root = Path::new(&dir);
+        std::fs::create_dir_all(root.join("log")).unwrap();
+        std::fs::write(root.join("data.bin"), vec![7u8; 8192]).unwrap();
+        std::fs::write(root.join("log/wal.bin"), b"log records").unwrap();
+        std::fs::write(root.join("empty.bin"), b"").unwrap();
+
+        let manifest = BackupManifest::build(root, 42).unwrap();
+        assert!(manifest.files.len() == 3);
+        assert!(manifest.files[0].path == "data.bin");
+        assert!(manifest.files[2].path == "log/wal.bin");
+        manifest.write(root).unwrap();
+
+        let read = BackupManifest::read(root).unwrap();
+        assert!(read == manifest);
+        assert!(read.checkpoint_lsn == 42);
+        assert!(verify_backup(root).unwrap().is_empty());
+
+        // a rebuilt manifest doesn't include itself
+        assert!(BackupManifest::build(root, 42).unwrap() == manifest);
+
+        let mut corrupt = vec![7u8; 8192];
+        corrupt[100] = 8;
+        std::fs::write(root.join("data.bin"), corrupt).unwrap();
+        std::fs::write(root.join("log/wal.bin"), b"log").unwrap();
+        std::fs::remove_file(root.join("empty.bin")).unwrap();
+        let problems = verify_backup(root).unwrap();
+        assert!(problems.contains(&BackupProblem::ChecksumMismatch("data.bin".to_string())));
+        assert!(problems.contains(&BackupProblem::Missing("empty.bin".to_string())));
+        assert!(problems.contains(&BackupProblem::SizeMismatch {
+            path: "log/wal.bin".to_string(),
+            expected: 11,
+            actual: 3,
+        }));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
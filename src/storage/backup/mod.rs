@@ -0,0 +1,3 @@
+pub mod manifest;
+pub mod restore;
+pub mod snapshot;
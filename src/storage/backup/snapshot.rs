@@ -0,0 +1,169 @@
+/// This file implements online snapshots: a consistent copy of a running database, taken while transactions keep reading and
+/// writing it. A snapshot starts with a checkpoint, then copies the data file page by page straight from disk, then the log.
+/// Pages written back during the copy may or may not make it in, so the copied data file on its own is fuzzy, but every change
+/// to a copied page was logged, and the log is copied last: running recovery over the snapshot from its checkpoint (the master
+/// record is copied too) repeats whatever the data file missed and rolls back the transactions that were still open. The
+/// snapshot ends with a backup manifest, so it can be verified, uploaded and restored like any other backup.
+use std::path::Path;
+
+use crate::shared::PageId;
+use crate::storage::backup::manifest::BackupManifest;
+use crate::storage::buffer::diskmgr::{
+    DiskApi as _, DiskMgr, DurabilityMode, SharedDisk, SyncPolicy,
+};
+use crate::storage::buffer::page::{self, Page};
+use crate::storage::error::StorageError;
+use crate::storage::log::checkpoint::{self, CheckpointManager};
+use crate::storage::log::logmgr::LogApi as _;
+
+/// Name of the data file in a snapshot; its free page map sits next to it
+pub const SNAPSHOT_DATA_FILE: &str = "data";
+/// Name of the log in a snapshot; its master record sits next to it
+pub const SNAPSHOT_LOG_FILE: &str = "wal";
+/// How many times a page that fails its checksum is read again before the snapshot gives up on it
+const READ_ATTEMPTS: usize = 8;
+
+/// Read `page_id` from disk. A page being written back while it's read can come out torn and fail its checksum, so a failed
+/// read is retried a few times before it's taken for real corruption
+fn read_page(disk: &SharedDisk, buf: &mut Page, page_id: PageId) -> Result<(), StorageError> {
+    let mut attempts = 0;
+    loop {
+        match disk.read_page(buf, page_id as u64) {
+            Err(StorageError::Corruption(_)) if attempts + 1 < READ_ATTEMPTS => {
+                attempts += 1;
+                std::thread::yield_now();
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Take a snapshot of the database checkpointed by `checkpoints` into the directory `dest`, creating it if needed. Writers
+/// only wait while the checkpoint is taken and while the log is copied. Returns the snapshot's manifest, whose checkpoint
+/// LSN is where recovery of the snapshot starts
+pub fn snapshot(checkpoints: &CheckpointManager, dest: &Path) -> std::io::Result<BackupManifest> {
+    std::fs::create_dir_all(dest)?;
+    let checkpoint = checkpoints.checkpoint()?;
+
+    let disk = checkpoints.pool().read().disk();
+    let data_path = dest.join(SNAPSHOT_DATA_FILE);
+    let copy = DiskMgr::create_with_sync_policy(
+        &data_path.to_string_lossy(),
+        DurabilityMode::default(),
+        SyncPolicy::Never,
+    )?;
+    // pages appended from here on are past the end of the copy, which recovery reads as empty pages
+    let num_pages = disk.num_pages()?;
+    let mut buf = page::empty();
+    for page_id in 0..num_pages as PageId {
+        read_page(&disk, &mut buf, page_id)?;
+        copy.write_page(&buf, page_id as u64)?;
+    }
+    for page_id in disk.free_pages() {
+        copy.deallocate_page(page_id)?;
+    }
+    copy.sync()?;
+
+    let log_path = dest.join(SNAPSHOT_LOG_FILE).to_string_lossy().to_string();
+    checkpoints.log().copy_to(&log_path)?;
+    checkpoint::write_master(&checkpoint::master_path(&log_path), checkpoint.begin_lsn)?;
+
+    let manifest = BackupManifest::build(dest, checkpoint.begin_lsn)?;
+    manifest.write(dest)?;
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::shared::{cwd, INVALID_LSN};
+    use crate::storage::backup::manifest::verify_backup;
+    use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+    use crate::storage::log::logmgr::LogManager;
+    use crate::storage::log::record::LogBody;
+    use crate::storage::log::recovery::RecoveryManager;
+    use crate::txn::tracker::{TxnTracker, TxnTrackerApi as _};
+
+    #[test]
+    fn test_snapshot_while_writing() {
+        let dir = cwd() + "/tests/snapshot_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let data_path = dir.clone() + "/data.bin";
+        let log_path = dir.clone() + "/wal.log";
+        let pool = BufferPool::create(&data_path).unwrap();
+        let log = LogManager::create(&log_path).unwrap();
+        pool.set_log_manager(log.clone());
+        let tracker = TxnTracker::create();
+        let page_ids: Vec<PageId> = (0..8).map(|_| pool.alloc_page().unwrap()).collect();
+        let freed = pool.alloc_page().unwrap();
+        pool.delete_page(freed).unwrap();
+        let checkpoints = CheckpointManager::new(
+            pool.clone(),
+            log.clone(),
+            tracker.clone(),
+            &checkpoint::master_path(&log_path),
+        );
+
+        // each committed transaction bumps byte 100 of every page, so a consistent copy has the same value on all of them. The
+        // begin record goes into the log horizon before any page is touched, so a checkpoint taken in the middle of an update
+        // never starts redo after it
+        let update = |txn_id: u64, value: u8| {
+            tracker.begin(txn_id);
+            let mut prev_lsn = log.append(txn_id, INVALID_LSN, LogBody::Begin).unwrap();
+            tracker.logged(txn_id, prev_lsn);
+            for page_id in page_ids.iter() {
+                let mut guard = pool.fetch_page_write(*page_id).unwrap();
+                let body = LogBody::Update {
+                    page_id: *page_id,
+                    offset: 100,
+                    before: vec![guard[100]],
+                    after: vec![value],
+                };
+                prev_lsn = log.append(txn_id, prev_lsn, body).unwrap();
+                tracker.logged(txn_id, prev_lsn);
+                guard[100] = value;
+                page::set_lsn(&mut guard, prev_lsn);
+            }
+            log.append(txn_id, prev_lsn, LogBody::Commit).unwrap();
+            tracker.end(txn_id);
+        };
+        update(1, 1);
+
+        let done = AtomicBool::new(false);
+        let snapshot_dir = dir.clone() + "/snapshot";
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut txn_id = 2;
+                while !done.load(Ordering::Acquire) {
+                    update(txn_id, (txn_id % 255) as u8 + 1);
+                    pool.flush_all().unwrap();
+                    txn_id += 1;
+                }
+            });
+            let manifest = snapshot(&checkpoints, Path::new(&snapshot_dir)).unwrap();
+            done.store(true, Ordering::Release);
+            assert!(manifest.checkpoint_lsn != INVALID_LSN);
+        });
+        assert!(verify_backup(Path::new(&snapshot_dir)).unwrap().is_empty());
+
+        let snapshot_log = format!("{}/{}", snapshot_dir, SNAPSHOT_LOG_FILE);
+        let disk = DiskMgr::open(&format!("{}/{}", snapshot_dir, SNAPSHOT_DATA_FILE)).unwrap();
+        assert!(disk.free_pages() == vec![freed]);
+        let mut recovery =
+            RecoveryManager::new(LogManager::open(&snapshot_log).unwrap(), disk.clone());
+        recovery.set_master_record(&checkpoint::master_path(&snapshot_log));
+        recovery.recover().unwrap();
+        let mut buf = page::empty();
+        let values: Vec<u8> = page_ids
+            .iter()
+            .map(|page_id| {
+                disk.read_page(&mut buf, *page_id as u64).unwrap();
+                buf[100]
+            })
+            .collect();
+        assert!(values[0] > 0 && values.iter().all(|value| *value == values[0]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,401 @@
+/// This file implements ARC (adaptive replacement cache), which balances recency against frequency by itself instead of
+/// taking a fixed split like 2Q. Resident frames are on one of two LRU lists: `recent` (T1) for pages seen once since they
+/// were loaded, and `frequent` (T2) for pages seen again. Evicted pages are remembered, by page id, on a ghost list matching
+/// the list they were evicted from (B1 and B2), and the ghosts steer the split: a page that comes back while it's a ghost of
+/// `recent` means recently seen pages were evicted too soon, so the target size of `recent` grows, and one that comes back
+/// from `frequent`'s ghosts shrinks it. Eviction takes the least recently used evictable frame of `recent` while it's over
+/// its target, and of `frequent` otherwise.
+///
+/// Frame ids are reused for other pages after an eviction, so the pool tells the replacer which page a frame holds with
+/// `record_page` before its first access. The target is reported through `stats`, so its adaptation can be watched from
+/// `BufApi::stats` while a workload runs.
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    shared::{FrameId, PageId},
+    storage::buffer::replacer::{Replacer, ReplacerStats},
+    sync::{Latch as _, Synchronized},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum List {
+    Recent,
+    Frequent,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    list: List,
+    // when the frame was last accessed
+    stamp: u64,
+    evictable: bool,
+}
+
+pub struct ArcReplacerInternal {
+    // entry i is frame i + 1
+    entries: Vec<Option<Entry>>,
+    // the page each frame holds, as told by `record_page`, so it can be remembered once the frame is evicted
+    pages: Vec<Option<PageId>>,
+    // tracked frames of each list, least recently used first
+    recent: BTreeMap<u64, FrameId>,
+    frequent: BTreeMap<u64, FrameId>,
+    // pages evicted from each list, oldest first, and which list each one is a ghost of
+    recent_ghosts: BTreeMap<u64, PageId>,
+    frequent_ghosts: BTreeMap<u64, PageId>,
+    ghosts: HashMap<PageId, (List, u64)>,
+    // how many frames `recent` should hold, between 0 and the number of frames
+    target: usize,
+    clock: u64,
+    num_evictable: usize,
+}
+
+impl ArcReplacerInternal {
+    fn check_frame(&self, frame_id: FrameId) {
+        assert!(
+            frame_id >= 1 && frame_id as usize <= self.entries.len(),
+            "invalid frame id {}",
+            frame_id
+        );
+    }
+
+    fn list(&mut self, list: List) -> &mut BTreeMap<u64, FrameId> {
+        match list {
+            List::Recent => &mut self.recent,
+            List::Frequent => &mut self.frequent,
+        }
+    }
+
+    fn ghost_list(&mut self, list: List) -> &mut BTreeMap<u64, PageId> {
+        match list {
+            List::Recent => &mut self.recent_ghosts,
+            List::Frequent => &mut self.frequent_ghosts,
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// The least recently used evictable frame of `list`
+    fn oldest_evictable(&self, list: List) -> Option<FrameId> {
+        let list = match list {
+            List::Recent => &self.recent,
+            List::Frequent => &self.frequent,
+        };
+        list.values()
+            .copied()
+            .find(|frame_id| self.entries[(frame_id - 1) as usize].is_some_and(|e| e.evictable))
+    }
+
+    /// Stop tracking a frame, and return the list it was on
+    fn forget(&mut self, frame_id: FrameId) -> Option<List> {
+        let entry = self.entries[(frame_id - 1) as usize].take()?;
+        self.list(entry.list).remove(&entry.stamp);
+        if entry.evictable {
+            self.num_evictable -= 1;
+        }
+        Some(entry.list)
+    }
+
+    /// If `page_id` is a ghost, forget it and move the target towards the list it was evicted from. The step is larger the
+    /// smaller that ghost list is compared to the other one
+    fn adapt(&mut self, page_id: PageId) -> bool {
+        let Some((list, stamp)) = self.ghosts.remove(&page_id) else {
+            return false;
+        };
+        let (recent, frequent) = (self.recent_ghosts.len(), self.frequent_ghosts.len());
+        match list {
+            List::Recent => {
+                let step = (frequent / recent).max(1);
+                self.target = (self.target + step).min(self.entries.len());
+            }
+            List::Frequent => {
+                let step = (recent / frequent).max(1);
+                self.target = self.target.saturating_sub(step);
+            }
+        }
+        self.ghost_list(list).remove(&stamp);
+        true
+    }
+
+    /// Remember the page of an evicted frame as a ghost of `list`
+    fn remember(&mut self, page_id: PageId, list: List) {
+        let stamp = self.tick();
+        self.ghost_list(list).insert(stamp, page_id);
+        self.ghosts.insert(page_id, (list, stamp));
+        self.trim_ghosts();
+    }
+
+    fn drop_oldest_ghost(&mut self, list: List) -> bool {
+        match self.ghost_list(list).pop_first() {
+            Some((_, page_id)) => {
+                self.ghosts.remove(&page_id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Keep `recent` and its ghosts within the number of frames, and all the lists together within twice that
+    fn trim_ghosts(&mut self) {
+        let capacity = self.entries.len();
+        while self.recent.len() + self.recent_ghosts.len() > capacity {
+            if !self.drop_oldest_ghost(List::Recent) {
+                break;
+            }
+        }
+        while self.recent.len()
+            + self.frequent.len()
+            + self.recent_ghosts.len()
+            + self.frequent_ghosts.len()
+            > 2 * capacity
+        {
+            if !self.drop_oldest_ghost(List::Frequent) && !self.drop_oldest_ghost(List::Recent) {
+                break;
+            }
+        }
+    }
+}
+
+pub type ArcReplacer = Synchronized<ArcReplacerInternal>;
+
+pub trait ArcReplacerApi {
+    fn create(num_frames: usize) -> Self;
+}
+
+impl ArcReplacerApi for ArcReplacer {
+    /// Create a replacer for frames `1..=num_frames`. The target starts at zero, so until a ghost of `recent` comes back the
+    /// frequently used frames are kept ahead of the rest
+    fn create(num_frames: usize) -> Self {
+        Synchronized::init(ArcReplacerInternal {
+            entries: vec![None; num_frames],
+            pages: vec![None; num_frames],
+            recent: BTreeMap::new(),
+            frequent: BTreeMap::new(),
+            recent_ghosts: BTreeMap::new(),
+            frequent_ghosts: BTreeMap::new(),
+            ghosts: HashMap::new(),
+            target: 0,
+            clock: 0,
+            num_evictable: 0,
+        })
+    }
+}
+
+impl Replacer for ArcReplacer {
+    fn evict(&self) -> Option<FrameId> {
+        let mut inner = self.lock();
+        let victim = if inner.recent.len() > inner.target {
+            inner
+                .oldest_evictable(List::Recent)
+                .or_else(|| inner.oldest_evictable(List::Frequent))
+        } else {
+            inner
+                .oldest_evictable(List::Frequent)
+                .or_else(|| inner.oldest_evictable(List::Recent))
+        }?;
+        let list = inner.forget(victim)?;
+        if let Some(page_id) = inner.pages[(victim - 1) as usize].take() {
+            inner.remember(page_id, list);
+        }
+        Some(victim)
+    }
+
+    /// A new frame joins `recent`, unless its page is a ghost, in which case it adapts the target and joins `frequent`. A
+    /// tracked frame accessed again moves to the back of `frequent`
+    fn record_access(&self, frame_id: FrameId) {
+        let mut inner = self.lock();
+        inner.check_frame(frame_id);
+        let idx = (frame_id - 1) as usize;
+        let entry = match inner.entries[idx] {
+            None => {
+                let seen = inner.pages[idx].is_some_and(|page_id| inner.adapt(page_id));
+                Entry {
+                    list: if seen { List::Frequent } else { List::Recent },
+                    stamp: inner.tick(),
+                    evictable: false,
+                }
+            }
+            Some(entry) => {
+                inner.list(entry.list).remove(&entry.stamp);
+                Entry {
+                    list: List::Frequent,
+                    stamp: inner.tick(),
+                    evictable: entry.evictable,
+                }
+            }
+        };
+        inner.list(entry.list).insert(entry.stamp, frame_id);
+        inner.entries[idx] = Some(entry);
+        inner.trim_ghosts();
+    }
+
+    fn set_evictable(&self, frame_id: FrameId, evictable: bool) {
+        let mut inner = self.lock();
+        inner.check_frame(frame_id);
+        let Some(entry) = inner.entries[(frame_id - 1) as usize].as_mut() else {
+            return;
+        };
+        if entry.evictable == evictable {
+            return;
+        }
+        entry.evictable = evictable;
+        if evictable {
+            inner.num_evictable += 1;
+        } else {
+            inner.num_evictable -= 1;
+        }
+    }
+
+    /// A removed frame's page was deleted rather than evicted, so it isn't remembered
+    fn remove(&self, frame_id: FrameId) {
+        let mut inner = self.lock();
+        inner.check_frame(frame_id);
+        let idx = (frame_id - 1) as usize;
+        if inner.entries[idx].is_some_and(|entry| entry.evictable) {
+            inner.forget(frame_id);
+            inner.pages[idx] = None;
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.lock().num_evictable
+    }
+
+    /// The target keeps the same share of the frames it had before
+    fn resize(&self, num_frames: usize) {
+        let mut inner = self.lock();
+        debug_assert!(inner.entries.iter().skip(num_frames).all(Option::is_none));
+        let old = inner.entries.len().max(1);
+        inner.target = inner.target * num_frames / old;
+        inner.entries.resize(num_frames, None);
+        inner.pages.resize(num_frames, None);
+        inner.trim_ghosts();
+    }
+
+    fn record_page(&self, frame_id: FrameId, page_id: PageId) {
+        let mut inner = self.lock();
+        inner.check_frame(frame_id);
+        inner.pages[(frame_id - 1) as usize] = Some(page_id);
+    }
+
+    fn stats(&self) -> Option<ReplacerStats> {
+        let inner = self.lock();
+        Some(ReplacerStats {
+            recent: inner.recent.len(),
+            frequent: inner.frequent.len(),
+            recent_ghosts: inner.recent_ghosts.len(),
+            frequent_ghosts: inner.frequent_ghosts.len(),
+            target: inner.target,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::{cwd, BUFFER_POOL_SIZE};
+    use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+
+    /// Load `page_id` into `frame_id` the way the pool does, and leave it evictable
+    fn load(replacer: &ArcReplacer, frame_id: FrameId, page_id: PageId) {
+        replacer.record_page(frame_id, page_id);
+        replacer.record_access(frame_id);
+        replacer.set_evictable(frame_id, true);
+    }
+
+    #[test]
+    fn test_adaptation() {
+        let replacer = ArcReplacer::create(4);
+        // frames 1 and 2 are used twice, frames 3 and 4 once
+        for frame_id in 1..=4 {
+            load(&replacer, frame_id, frame_id as PageId * 10);
+        }
+        replacer.record_access(1);
+        replacer.record_access(2);
+        let stats = replacer.stats().unwrap();
+        assert!(stats.recent == 2 && stats.frequent == 2 && stats.target == 0);
+
+        // `recent` is over its target, so it goes first and its page becomes a ghost
+        assert!(replacer.evict() == Some(3));
+        assert!(replacer.stats().unwrap().recent_ghosts == 1);
+        // page 30 comes back while it's remembered: the target grows and it's now frequent
+        load(&replacer, 3, 30);
+        let stats = replacer.stats().unwrap();
+        assert!(stats.target == 1 && stats.frequent == 3 && stats.recent_ghosts == 0);
+
+        // `recent` is within its target now, so the least recently used frequent frame goes
+        assert!(replacer.evict() == Some(1));
+        assert!(replacer.stats().unwrap().frequent_ghosts == 1);
+        // page 10 comes back from the frequent ghosts, and the target shrinks again
+        load(&replacer, 1, 10);
+        let stats = replacer.stats().unwrap();
+        assert!(stats.target == 0 && stats.frequent_ghosts == 0);
+        assert!(replacer.evict() == Some(4));
+    }
+
+    #[test]
+    fn test_pinned_and_removed_frames() {
+        let replacer = ArcReplacer::create(3);
+        for frame_id in 1..=3 {
+            replacer.record_page(frame_id, frame_id as PageId);
+            replacer.record_access(frame_id);
+        }
+        assert!(replacer.evict().is_none());
+        replacer.set_evictable(1, true);
+        replacer.set_evictable(2, true);
+        replacer.remove(2);
+        replacer.remove(3);
+        assert!(replacer.size() == 1);
+        assert!(replacer.evict() == Some(1));
+        assert!(replacer.evict().is_none());
+        // only the evicted page is remembered
+        let stats = replacer.stats().unwrap();
+        assert!(stats.recent_ghosts == 1 && stats.frequent_ghosts == 0);
+    }
+
+    #[test]
+    fn test_buffer_pool_adapts() {
+        let dir = cwd() + "/tests/arc_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        let path = dir.clone() + "/test_file.bin";
+        let frames = BUFFER_POOL_SIZE;
+        let buffer_pool =
+            BufferPool::create_with_replacer(&path, Box::new(ArcReplacer::create(frames))).unwrap();
+        let touch = |page_id: PageId| {
+            assert!(buffer_pool.fetch_page(page_id).is_ok());
+            assert!(buffer_pool.unpin_page(page_id, false));
+        };
+
+        // eight hot pages, fetched twice so they're frequent
+        let page_ids: Vec<PageId> = (0..frames * 4)
+            .map(|_| buffer_pool.alloc_page().unwrap())
+            .collect();
+        for page_id in &page_ids[..8] {
+            touch(*page_id);
+            touch(*page_id);
+        }
+        // a scan over four times as many pages as there are frames evicts itself, not the hot pages
+        for page_id in &page_ids[8..] {
+            touch(*page_id);
+        }
+        let misses = buffer_pool.stats().misses;
+        for page_id in &page_ids[..8] {
+            touch(*page_id);
+        }
+        assert!(buffer_pool.stats().misses == misses);
+        let target = buffer_pool.stats().replacer.unwrap().target;
+
+        // scanned pages coming back soon after being evicted make the pool favour recently used pages
+        for page_id in page_ids[page_ids.len() - frames * 2..].iter().rev() {
+            touch(*page_id);
+        }
+        let stats = buffer_pool.stats().replacer.unwrap();
+        assert!(stats.target > target);
+        assert!(stats.recent + stats.frequent <= frames);
+        assert!(stats.recent + stats.recent_ghosts <= frames);
+
+        std::fs::remove_dir_all(std::path::Path::new(&dir)).unwrap();
+    }
+}
@@ -0,0 +1,295 @@
+/// This file implements an asynchronous front end to the disk manager (behind the `async-io` feature), on a tokio runtime.
+/// Reads and writes run on the runtime's blocking pool, the way `tokio::fs` runs file operations, and return an `IoFuture`
+/// right away, so the caller doesn't stall for the read or the fsync. They still go through `DiskMgr`, so checksums, the sync
+/// policy and fsync failure handling are the same as for synchronous IO. The future can be awaited from async code, or, for
+/// synchronous callers like the buffer pool, polled with `is_done` and collected with `wait`.
+///
+/// `prefetch` is the buffer pool's side of it: read-ahead whose reads are queued on the runtime, instead of taking a thread
+/// that waits on each read in turn. Each read completes into the pool from the thread that ran it, like a miss does: the page
+/// is installed in its frame and the fetches waiting for it are woken through the pool's condvar, so nobody polls. Reads
+/// still running show up in `BufStats::reads_in_flight`.
+///
+/// Requests run on whichever blocking thread is free, so two requests for the same page may complete in either order.
+/// Callers that care (e.g. a read after a write of the same page) wait for the first one before submitting the second.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::runtime::{Builder, Handle, Runtime};
+use tokio::task::{JoinError, JoinHandle};
+
+use crate::shared::{PageId, PAGE_SIZE};
+use crate::storage::buffer::bufmgr::{reserve_prefetch, BufferPool, Prefetch, PrefetchTicket};
+use crate::storage::buffer::diskmgr::{DiskApi as _, DiskMgr};
+use crate::storage::buffer::page::{self, Page};
+use crate::storage::error::StorageError;
+
+/// Most requests `AsyncDiskMgr::new` runs at once
+pub const IO_THREADS: usize = 4;
+
+/// A panic in a request is the caller's panic
+fn rethrow(err: JoinError) -> ! {
+    std::panic::resume_unwind(err.into_panic())
+}
+
+/// The result of a queued IO request
+pub struct IoFuture<T> {
+    task: JoinHandle<Result<T, StorageError>>,
+    runtime: Handle,
+}
+
+impl<T> IoFuture<T> {
+    /// Whether the request has completed, i.e. `wait` won't block
+    pub fn is_done(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Block the calling thread until the request completes and return its result. Only for synchronous callers: async code
+    /// awaits the future instead, and calling this on one of the runtime's threads panics
+    pub fn wait(self) -> Result<T, StorageError> {
+        self.runtime
+            .block_on(self.task)
+            .unwrap_or_else(|err| rethrow(err))
+    }
+}
+
+impl<T> Future for IoFuture<T> {
+    type Output = Result<T, StorageError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.task)
+            .poll(cx)
+            .map(|result| result.unwrap_or_else(|err| rethrow(err)))
+    }
+}
+
+/// Handle to the runtime IO requests run on. Clones share it; a runtime `new` started shuts down once every handle to it
+/// has been dropped, which, as for any tokio runtime, mustn't happen in async code
+#[derive(Clone)]
+pub struct AsyncDiskMgr {
+    disk: DiskMgr,
+    runtime: Handle,
+    // the runtime, if this disk manager started it, kept alive for as long as a handle is
+    _owned: Option<Arc<Runtime>>,
+}
+
+impl AsyncDiskMgr {
+    /// Start a runtime of its own, running up to `IO_THREADS` requests at once
+    pub fn new(disk: DiskMgr) -> Self {
+        AsyncDiskMgr::with_threads(disk, IO_THREADS)
+    }
+
+    pub fn with_threads(disk: DiskMgr, threads: usize) -> Self {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .max_blocking_threads(threads.max(1))
+            .thread_name("async-io")
+            .build()
+            .expect("couldn't start the IO runtime");
+        AsyncDiskMgr {
+            disk,
+            runtime: runtime.handle().clone(),
+            _owned: Some(Arc::new(runtime)),
+        }
+    }
+
+    /// Run requests on an existing runtime, e.g. the application's
+    pub fn with_runtime(disk: DiskMgr, runtime: Handle) -> Self {
+        AsyncDiskMgr {
+            disk,
+            runtime,
+            _owned: None,
+        }
+    }
+
+    /// The underlying disk manager, for synchronous access
+    pub fn disk(&self) -> &DiskMgr {
+        &self.disk
+    }
+
+    fn submit<T: Send + 'static>(
+        &self,
+        io: impl FnOnce(&DiskMgr) -> Result<T, StorageError> + Send + 'static,
+    ) -> IoFuture<T> {
+        let disk = self.disk.clone();
+        IoFuture {
+            task: self.runtime.spawn_blocking(move || io(&disk)),
+            runtime: self.runtime.clone(),
+        }
+    }
+
+    /// Queue a read of `page_id`
+    pub fn read_page(&self, page_id: PageId) -> IoFuture<Box<Page>> {
+        self.submit(move |disk| {
+            let mut buf = Box::new(page::empty());
+            disk.read_page(&mut buf, page_id as u64)?;
+            Ok(buf)
+        })
+    }
+
+    /// Queue a write of `buf` to `page_id`
+    pub fn write_page(&self, buf: &[u8; PAGE_SIZE], page_id: PageId) -> IoFuture<()> {
+        let buf = Box::new(*buf);
+        self.submit(move |disk| disk.write_page(&buf, page_id as u64))
+    }
+
+    /// Read `count` pages from `page_id` on into free frames of `pool`, like `BufApi::prefetch`: pages that are resident
+    /// already are skipped, it stops at the first page that isn't in the file, and it never evicts anything. The frames are
+    /// reserved before this returns, so a fetch of one of the pages waits for its read instead of reading it again; the reads
+    /// themselves are queued on the runtime, and read the pool's data file rather than this disk manager's. The future
+    /// yields how many pages were read. `wait_for_prefetch` and `close` wait for the reads too
+    pub fn prefetch(&self, pool: &BufferPool, page_id: PageId, count: usize) -> IoFuture<usize> {
+        let ticket = PrefetchTicket::new(pool);
+        let mut reads = Vec::new();
+        for page_id in page_id..page_id + count as PageId {
+            match reserve_prefetch(pool, page_id) {
+                Prefetch::Resident => {}
+                Prefetch::Stop => break,
+                Prefetch::Read(read) => {
+                    let pool = pool.clone();
+                    reads.push(self.runtime.spawn_blocking(move || read.read(&pool)));
+                }
+            }
+        }
+        let task = self.runtime.spawn(async move {
+            let _ticket = ticket;
+            let mut read = 0;
+            for task in reads {
+                if task.await.unwrap_or_else(|err| rethrow(err)) {
+                    read += 1;
+                }
+            }
+            Ok(read)
+        });
+        IoFuture {
+            task,
+            runtime: self.runtime.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::{Wake, Waker};
+    use std::thread::Thread;
+
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::buffer::bufmgr::BufApi as _;
+    use crate::storage::buffer::io;
+    use crate::testing::Song;
+
+    struct ThreadWaker {
+        thread: Thread,
+        woken: AtomicBool,
+    }
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.woken.store(true, Ordering::Release);
+            self.thread.unpark();
+        }
+    }
+
+    /// Minimal executor: poll `future` on this thread, parking between polls
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Arc::new(ThreadWaker {
+            thread: std::thread::current(),
+            woken: AtomicBool::new(false),
+        });
+        let mut future = std::pin::pin!(future);
+        let task_waker = Waker::from(waker.clone());
+        let mut cx = Context::from_waker(&task_waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            while !waker.woken.swap(false, Ordering::AcqRel) {
+                std::thread::park();
+            }
+        }
+    }
+
+    #[test]
+    fn test_async_read_write() {
+        let dir = cwd() + "/tests/asyncdisk_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let disk = DiskMgr::create(&(dir.clone() + "/test_file.bin")).unwrap();
+        for _ in 0..8 {
+            disk.append_page(&page::empty()).unwrap();
+        }
+        let mgr = AsyncDiskMgr::new(disk);
+
+        let writes: Vec<IoFuture<()>> = (0..8)
+            .map(|i| {
+                let buf = io::to_buffer(Song::new(i, "Cry Baby", "The Neighbourhood")).unwrap();
+                mgr.write_page(&buf, i as PageId)
+            })
+            .collect();
+        block_on(async {
+            for write in writes {
+                write.await.unwrap();
+            }
+            // every read is in flight before the first is awaited
+            let reads: Vec<IoFuture<Box<Page>>> = (0..8).map(|i| mgr.read_page(i)).collect();
+            for (i, read) in reads.into_iter().enumerate() {
+                let buf = read.await.unwrap();
+                assert!(io::from_buffer::<Song>(&buf).unwrap().id == i as i32);
+            }
+        });
+
+        // synchronous callers
+        let read = mgr.read_page(3);
+        let buf = read.wait().unwrap();
+        assert!(io::from_buffer::<Song>(&buf).unwrap().id == 3);
+        let missing = mgr.read_page(8);
+        assert!(missing.wait().is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_async_prefetch() {
+        let dir = cwd() + "/tests/asyncdisk_prefetch_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.clone() + "/test_file.bin";
+        let buffer_pool = BufferPool::create(&path).unwrap();
+        for i in 0..20 {
+            let mut guard = buffer_pool.new_page_write().unwrap();
+            *guard.data_mut() =
+                io::to_buffer(Song::new(i, "Sweater Weather", "The Neighbourhood")).unwrap();
+        }
+        buffer_pool.close().unwrap();
+        drop(buffer_pool);
+
+        let buffer_pool = BufferPool::open(&path).unwrap();
+        let mgr = AsyncDiskMgr::new(DiskMgr::create(&(dir.clone() + "/other.bin")).unwrap());
+        // the pages past the end of the file aren't read
+        let prefetch = mgr.prefetch(&buffer_pool, 10, 15);
+        assert!(prefetch.wait().unwrap() == 10);
+        // resident pages are skipped
+        assert!(mgr.prefetch(&buffer_pool, 5, 10).wait().unwrap() == 5);
+        let misses = buffer_pool.stats().misses;
+        for page_id in 5..20 {
+            let frame = buffer_pool.fetch_page(page_id).unwrap();
+            assert!(io::from_buffer::<Song>(frame.read().page()).unwrap().id == page_id as i32);
+            assert!(buffer_pool.unpin_page(page_id, false));
+        }
+        assert!(buffer_pool.stats().misses == misses);
+
+        // a fetch racing the reads waits for them instead of reading the page again
+        drop(buffer_pool);
+        let buffer_pool = BufferPool::open(&path).unwrap();
+        let prefetch = mgr.prefetch(&buffer_pool, 0, 20);
+        let frame = buffer_pool.fetch_page(3).unwrap();
+        assert!(io::from_buffer::<Song>(frame.read().page()).unwrap().id == 3);
+        assert!(buffer_pool.unpin_page(3, false));
+        assert!(prefetch.wait().unwrap() == 20);
+        assert!(buffer_pool.stats().misses == 0);
+
+        drop(buffer_pool);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
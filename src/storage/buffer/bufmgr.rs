@@ -1,81 +1,813 @@
 #![allow(unused)]
 
-use std::cell::RefCell;
-use std::collections::{HashMap, LinkedList};
+use std::collections::{HashMap, HashSet, LinkedList};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{JoinHandle, ThreadId};
+use std::time::Duration;
 
-use crate::shared::{FrameId, PageId, BUFFER_POOL_SIZE, PAGE_SIZE};
-use crate::storage::buffer::diskmgr::{DiskApi as _, DiskMgr};
+use rayon::prelude::*;
+
+use crate::shared::{FrameId, PageId, BUFFER_POOL_SIZE, INVALID_PAGE_ID, PAGE_SIZE};
+use crate::sim::{SimApi as _, Simulation};
+use crate::storage::buffer::compress::{BoxedCodec, CompressedApi as _, CompressedDiskMgr};
+use crate::storage::buffer::diskmgr::{DiskApi as _, DiskMgr, DiskStats, SharedDisk};
+use crate::storage::buffer::guard::{OptimisticGuard, PinnedPage, ReadPageGuard, WritePageGuard};
+use crate::storage::buffer::lruk::{LRUKReplacer, LRUKReplacerApi as _};
 use crate::storage::buffer::page;
 use crate::storage::buffer::page::Page;
-use crate::sync::hashtable::HashTable;
-use crate::sync::{Latch as _, RwLatch as _, RwSynchronized, Synchronized};
+use crate::storage::buffer::replacer::{BoxedReplacer, ReplacerStats};
+use crate::storage::buffer::temp::{
+    is_temp_page, read_temp_page, write_temp_page, TempFile, TempFiles,
+};
+use crate::storage::config::StorageConfig;
+use crate::storage::error::StorageError;
+use crate::storage::log::logmgr::{LogApi as _, LogManager};
+use crate::sync::hashtable::ShardedHashTable;
+use crate::sync::{
+    BinarySemaphore, BinarySemaphoreMethods as _, CondVar, CondVarMethods as _, ExclusiveGuard,
+    Latch as _, RwLatch as _, RwSynchronized, SharedGuard, Synchronized,
+};
+use crate::telemetry::trace::trace_event;
+use crate::telemetry::{EventBus, EventBusApi as _, StorageEvent};
+
+/// Number of accesses the LRU-K replacer keeps per frame
+pub const REPLACER_K: usize = 2;
+
+/// Consecutive page ids a thread has to fetch before read-ahead kicks in
+pub const SEQUENTIAL_THRESHOLD: usize = 4;
+
+/// Threads tracked by scan detection before the table is reset
+const MAX_TRACKED_SCANS: usize = 64;
+
+/// A frame's page memory, aligned to the page size so it can be the target of direct IO
+#[repr(C, align(4096))]
+pub struct FrameMemory(Page);
+
+impl FrameMemory {
+    #[inline]
+    pub fn page(&self) -> &Page {
+        &self.0
+    }
+
+    #[inline]
+    pub fn page_mut(&mut self) -> &mut Page {
+        &mut self.0
+    }
+}
+
+/// Buffer pool activity since the pool was created (see `BufApi::stats`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BufStats {
+    /// Fetches that found the page resident
+    pub hits: usize,
+    /// Fetches that had to read the page from disk
+    pub misses: usize,
+    /// Pages evicted to make room for another
+    pub evictions: usize,
+    /// Dirty pages written back, on eviction or by a flush
+    pub write_backs: usize,
+    /// Frames pinned right now
+    pub pinned: usize,
+    /// Pages being read into frames right now, by fetches that missed and by read-ahead. Other fetches of those pages wait
+    /// for the reads, and are woken as each one completes
+    pub reads_in_flight: usize,
+    /// Pages being written back right now, by evictions and flushes
+    pub writes_in_flight: usize,
+    /// IO done by the disk manager underneath
+    pub disk: DiskStats,
+    /// The replacer's lists, if it's an adaptive one
+    pub replacer: Option<ReplacerStats>,
+}
+
+impl BufStats {
+    /// Fraction of fetches that were hits, or 0 before the first fetch
+    pub fn hit_ratio(&self) -> f64 {
+        let fetches = self.hits + self.misses;
+        if fetches == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / fetches as f64
+    }
+}
+
+/// Counters behind `BufStats`. Atomic, so that paths holding only the pool's shared latch can update them too
+#[derive(Default)]
+struct BufCounters {
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    evictions: AtomicUsize,
+    write_backs: AtomicUsize,
+    // pages handed to the disk manager to be written and not yet done
+    writes_in_flight: AtomicUsize,
+}
 
+impl BufCounters {
+    fn bump(counter: &AtomicUsize, by: usize) {
+        counter.fetch_add(by, Ordering::Relaxed);
+    }
+}
+
+/// A frame: its page memory behind the frame's latch, and the pool's metadata about the page next to it. The metadata is
+/// atomic, so it can be read without latching anything, and is only changed under the pool's latch (see
+/// `BufferPoolContext`)
 pub struct BufferPoolFrameInternal {
-    page: Page,
     id: FrameId,
-    pin_count: usize,
-    dirty: bool,
+    memory: RwSynchronized<FrameMemory>,
+    page_id: AtomicIsize,
+    pin_count: AtomicUsize,
+    dirty: AtomicBool,
+    // set while the page is being read into the frame, without the pool's latch (see `BufApi::fetch_page`)
+    loading: AtomicBool,
+    // bumped on every release of a write guard, and odd while one is changing the page (see `OptimisticGuard`)
+    version: AtomicU64,
+}
+
+impl BufferPoolFrameInternal {
+    fn new(id: FrameId) -> Self {
+        BufferPoolFrameInternal {
+            id,
+            memory: RwSynchronized::init(FrameMemory(page::empty())),
+            page_id: AtomicIsize::new(INVALID_PAGE_ID),
+            pin_count: AtomicUsize::new(0),
+            dirty: AtomicBool::new(false),
+            loading: AtomicBool::new(false),
+            version: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn id(&self) -> FrameId {
+        self.id
+    }
+
+    /// Latch the page shared
+    #[inline]
+    pub fn read(&self) -> SharedGuard<'_, FrameMemory> {
+        self.memory.read()
+    }
+
+    /// Latch the page exclusively
+    #[inline]
+    pub fn write(&self) -> ExclusiveGuard<'_, FrameMemory> {
+        self.memory.write()
+    }
 }
 
 pub trait FrameApi {
     fn data(&self) -> Page;
     fn is_dirty(&self) -> bool;
     fn reset(&self);
+    fn page_id(&self) -> PageId;
+    fn pin_count(&self) -> usize;
+    fn version(&self) -> u64;
+    fn is_loading(&self) -> bool;
 }
 
-pub type BufferPoolFrame = RwSynchronized<BufferPoolFrameInternal>;
+pub type BufferPoolFrame = Arc<BufferPoolFrameInternal>;
 
-/// Latching functions taken care of by RwLatch implementation
+/// `data` and `reset` latch the page; the rest read the metadata without latching anything
 impl FrameApi for BufferPoolFrame {
     fn data(&self) -> Page {
-        let inner = unsafe { &*self.data_ptr() };
-        inner.page
+        self.read().0
     }
 
     fn is_dirty(&self) -> bool {
-        let inner = unsafe { &*self.data_ptr() };
-        inner.dirty
+        self.dirty.load(Ordering::Acquire)
     }
 
     fn reset(&self) {
-        let mut inner = unsafe { &mut *self.data_ptr() };
-        inner.page = [0u8; PAGE_SIZE];
+        self.write().0 = [0u8; PAGE_SIZE];
+    }
+
+    fn page_id(&self) -> PageId {
+        self.page_id.load(Ordering::Acquire)
+    }
+
+    fn pin_count(&self) -> usize {
+        self.pin_count.load(Ordering::Acquire)
+    }
+
+    /// The page's version: even while nobody is changing it, and moved on every time a write guard on it is released
+    fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    /// Whether the page is still being read into the frame
+    fn is_loading(&self) -> bool {
+        self.loading.load(Ordering::Acquire)
     }
 }
 
+/// Frame metadata (page id, pin count, dirty bit) is only modified while holding the pool's latch. Pinning a resident page and
+/// mapping a page onto a free frame only need the shared latch: the page table's entry API settles racing misses, and the loser
+/// hands its frame back. Unpinning, eviction and unmapping take the exclusive latch, which is what keeps a pinned frame from
+/// being evicted out from under a reader. Page contents are protected by each frame's own RwLatch: callers
+/// latch the frame returned by `fetch_page`/`new_page` to read or modify the page, then unpin it.
+///
+/// The pool never waits for a frame latch while holding its own latch. Callers are allowed to hold a frame latch while calling
+/// back into the pool (e.g. latch a parent page, then fetch a child), so doing so would deadlock. Metadata is atomic and kept
+/// outside the frame latch instead, and under its own latch the pool only ever tries a frame's latch (see `memory`): on
+/// victims, which are unpinned, and on frames nobody else can reach, because they're free or their page is being read.
+///
+/// With a log manager attached the pool enforces write-ahead logging: a page is only written back once the log is durable up
+/// to the page's LSN.
+///
+/// With a simulation attached, the free frame handed to each new page is drawn from the simulation's RNG instead of being
+/// taken in order, so tests exercise varied frame placement while staying reproducible from the seed.
+///
+/// Pages are read from disk without the pool's latch. The first thread to miss on a page reserves a frame for it, maps the
+/// page to the frame and marks it as loading (see `claim`); threads that ask for the page meanwhile pin that frame and wait on
+/// `loaded` for the read to finish instead of reading the page into frames of their own, so each miss is read exactly once.
+///
+/// Frame memory never moves. `frames` is a slab of handles: each frame is its own heap allocation, made once and kept for the
+/// life of the pool, so growing the slab moves the handles but not the pages they point to. A page's address is therefore
+/// stable for as long as it's pinned (see `PinnedPage`), and debug builds check this whenever the slab grows.
 pub struct BufferPoolContext {
-    mgr: DiskMgr,
-    frames: Vec<RwSynchronized<BufferPoolFrameInternal>>,
-    free_list: RefCell<LinkedList<FrameId>>,
-    page_table: HashTable<PageId, FrameId>,
+    mgr: SharedDisk,
+    // number of frames the pool may allocate
+    capacity: usize,
+    frames: Vec<BufferPoolFrame>,
+    // page address of every allocated frame, to check that growing `frames` didn't move any of them
+    frame_addrs: Vec<usize>,
+    // latched on its own, so that misses can take free frames under the pool's shared latch
+    free_list: Synchronized<LinkedList<FrameId>>,
+    page_table: ShardedHashTable<PageId, FrameId>,
+    replacer: BoxedReplacer,
+    log: Option<LogManager>,
+    sim: Option<Simulation>,
+    bus: Option<EventBus>,
+    // files holding the temp pages of live `TempPageAllocator`s
+    temp: TempFiles,
+    // sequential scans being read ahead of
+    scans: Synchronized<ScanTracker>,
+    // read-aheads in progress, and a signal for when the last one finishes
+    prefetches: Synchronized<usize>,
+    prefetched: CondVar,
+    // held by each write-back from the moment it takes the dirty bits until its pages are durable (see `write_back`)
+    flushing: Synchronized<()>,
+    // frames whose page is being read, and a signal for when a read finishes
+    loads: Synchronized<HashSet<FrameId>>,
+    loaded: CondVar,
+    // pages kept resident by `pin_resident`, each holding one pin of its own
+    pinned_resident: HashSet<PageId>,
+    // handles to the background writers started on the pool, stopped when it's closed or dropped
+    writers: Vec<BackgroundWriter>,
+    counters: BufCounters,
+}
+
+/// A frame's version counter, for the guards
+#[inline]
+pub(crate) fn frame_version(frame: &BufferPoolFrame) -> &AtomicU64 {
+    &frame.version
+}
+
+/// A frame's latch, for the guards, which take it in one scope and release it in another
+#[inline]
+pub(crate) fn frame_latch(frame: &BufferPoolFrame) -> &RwSynchronized<FrameMemory> {
+    &frame.memory
+}
+
+/// Pointer to a frame's page memory. Dereferencing it is up to the caller: the guards do so under the frame's latch, and
+/// `OptimisticGuard` copies the page without it and validates the copy against the version
+#[inline]
+pub(crate) fn frame_page(frame: &BufferPoolFrame) -> *mut Page {
+    unsafe { std::ptr::addr_of_mut!((*frame.memory.data_ptr()).0) }
+}
+
+/// The page of a frame that nobody else can be latching (see `BufferPoolContext`), latched exclusively. The latch is only
+/// tried, since the pool never waits for a frame latch while holding its own
+fn memory(frame: &BufferPoolFrame) -> ExclusiveGuard<'_, FrameMemory> {
+    frame
+        .memory
+        .try_write()
+        .expect("frame latched while it's free or loading")
+}
+
+impl BufferPoolContext {
+    /// Frame ids start at 1. Frames are allocated the first time their id comes off the free list
+    fn frame(&mut self, frame_id: FrameId) -> BufferPoolFrame {
+        let idx = (frame_id - 1) as usize;
+        if self.frames.len() <= idx {
+            while self.frames.len() <= idx {
+                let id = self.frames.len() as FrameId + 1;
+                let frame = Arc::new(BufferPoolFrameInternal::new(id));
+                self.frame_addrs.push(frame_page(&frame) as usize);
+                self.frames.push(frame);
+            }
+            debug_assert!(
+                self.frames
+                    .iter()
+                    .zip(self.frame_addrs.iter())
+                    .all(|(frame, addr)| frame_page(frame) as usize == *addr),
+                "frame memory moved while growing the pool"
+            );
+        }
+        self.frames[idx].clone()
+    }
+
+    /// Find a frame to hold a new page: take one from the free list if possible, otherwise ask the replacer for a victim. A
+    /// dirty victim is written back before its frame is reused. Returns `None` if every frame is pinned.
+    fn acquire_frame(&mut self) -> Option<FrameId> {
+        if let Some(frame_id) = self.take_free() {
+            return Some(frame_id);
+        }
+        let Some(frame_id) = self.replacer.evict() else {
+            if let Some(bus) = &self.bus {
+                bus.publish(StorageEvent::EvictionPressure {
+                    frames: self.capacity,
+                });
+            }
+            return None;
+        };
+        let frame = self.frame(frame_id);
+        // the victim is unpinned, so its latch should be free. If a caller still holds it, don't wait for them (see above):
+        // the victim stays resident and the allocation fails like it would with every frame pinned
+        let Some(memory) = frame.memory.try_write() else {
+            self.replacer.record_access(frame_id);
+            self.replacer.set_evictable(frame_id, true);
+            return None;
+        };
+        let victim = frame.page_id();
+        let dirty = frame.is_dirty();
+        if dirty {
+            BufCounters::bump(&self.counters.writes_in_flight, 1);
+            let written = self.write_page(&memory.0, victim);
+            self.counters
+                .writes_in_flight
+                .fetch_sub(1, Ordering::Relaxed);
+            if written.is_err() {
+                // keep the victim resident (and evictable) rather than losing its contents
+                self.replacer.record_access(frame_id);
+                self.replacer.set_evictable(frame_id, true);
+                return None;
+            }
+            BufCounters::bump(&self.counters.write_backs, 1);
+        }
+        BufCounters::bump(&self.counters.evictions, 1);
+        trace_event!(page_id = victim, frame_id, dirty, "evict");
+        self.page_table.remove(&victim);
+        frame.page_id.store(INVALID_PAGE_ID, Ordering::Release);
+        frame.dirty.store(false, Ordering::Release);
+        Some(frame_id)
+    }
+
+    /// Take a frame off the free list: the first one, or one drawn from the simulation's RNG if there is one
+    fn take_free(&self) -> Option<FrameId> {
+        let mut free_list = self.free_list.lock();
+        if let Some(sim) = &self.sim {
+            if !free_list.is_empty() {
+                let at = sim.gen_range(0..free_list.len());
+                let mut rest = free_list.split_off(at);
+                let frame_id = rest.pop_front();
+                free_list.append(&mut rest);
+                return frame_id;
+            }
+        }
+        free_list.pop_front()
+    }
+
+    /// A frame that has been allocated, i.e. has come off the free list before. Frames a page is mapped to always have
+    fn allocated(&self, frame_id: FrameId) -> Option<BufferPoolFrame> {
+        self.frames.get((frame_id - 1) as usize).cloned()
+    }
+
+    /// Write a page image back to disk, flushing the log up to the page's LSN first. Temp pages go to their temp file, unlogged
+    fn write_page(&self, image: &Page, page_id: PageId) -> Result<(), StorageError> {
+        if is_temp_page(page_id) {
+            return self.temp.write_page(image, page_id);
+        }
+        if let Some(log) = &self.log {
+            log.flush_to_lsn(page::lsn(image))?;
+        }
+        self.mgr.write_page(image, page_id as u64)
+    }
+
+    /// Read a page from the data file, or from its temp file if it's a temp page
+    fn read_page(&self, buf: &mut Page, page_id: PageId) -> Result<(), StorageError> {
+        read_page(&self.mgr, self.temp.handle(page_id).as_ref(), buf, page_id)
+    }
+
+    /// Whether a page is in the data file, or has been handed out by its temp file if it's a temp page
+    fn page_exists(&self, page_id: PageId) -> bool {
+        if is_temp_page(page_id) {
+            return self.temp.exists(page_id);
+        }
+        self.mgr.read_page_exists(page_id)
+    }
+
+    /// Free a page in the data file, or in its temp file if it's a temp page
+    fn deallocate(&self, page_id: PageId) -> Result<(), StorageError> {
+        if is_temp_page(page_id) {
+            return self.temp.deallocate(page_id);
+        }
+        self.mgr.deallocate_page(page_id)
+    }
+
+    /// Pin `page_id`'s entry in the page table, making one if there is none: a frame is reserved for the page and marked as
+    /// loading, and the caller has to read the page into it and call `finish_load`. Fails with `StorageError::PoolExhausted`
+    /// if the page isn't resident and every frame is pinned
+    fn claim(&mut self, page_id: PageId) -> Result<Claim, StorageError> {
+        if let Some(claim) = self.try_claim(page_id) {
+            return Ok(claim);
+        }
+        // nothing free that can be had under the shared latch, so allocate a frame or evict one
+        let frame_id = self.acquire_frame().ok_or(StorageError::PoolExhausted)?;
+        let frame = self.frame(frame_id);
+        Ok(match self.map_free(page_id, frame_id, frame) {
+            Mapping::Reserved(frame_id, frame) => self.vacant(page_id, frame_id, frame),
+            Mapping::Taken(frame_id) => self.join(page_id, frame_id),
+        })
+    }
+
+    /// `claim` for callers holding only the pool's shared latch, which keeps frames from being evicted, reused or given up
+    /// meanwhile. A hit pins the frame the page table maps the page to. A miss takes a free frame and maps the page to it
+    /// through the page table's entry API, so of the threads that miss on a page at once exactly one reserves a frame for it,
+    /// and the others pin that frame and wait for the read. Returns `None` if the page isn't resident and no free frame has
+    /// been allocated yet, which needs the exclusive latch
+    fn try_claim(&self, page_id: PageId) -> Option<Claim> {
+        if let Some(frame_id) = self.page_table.get(&page_id) {
+            return Some(self.join(page_id, frame_id));
+        }
+        let frame_id = self.take_free()?;
+        let Some(frame) = self.allocated(frame_id) else {
+            self.free_list.lock().push_front(frame_id);
+            return None;
+        };
+        Some(match self.map_free(page_id, frame_id, frame) {
+            Mapping::Reserved(frame_id, frame) => self.vacant(page_id, frame_id, frame),
+            Mapping::Taken(frame_id) => self.join(page_id, frame_id),
+        })
+    }
+
+    /// Pin the frame `page_id` is mapped to on behalf of a fetch, which has to wait for the page if it's still being read
+    fn join(&self, page_id: PageId, frame_id: FrameId) -> Claim {
+        let frame = self.pin(frame_id);
+        if frame.is_loading() {
+            return Claim::Loading(frame_id, frame, self.loads.clone(), self.loaded.clone());
+        }
+        BufCounters::bump(&self.counters.hits, 1);
+        trace_event!(page_id, frame_id, hit = true, "fetch");
+        self.replacer.record_access(frame_id);
+        Claim::Resident(frame)
+    }
+
+    /// Hand a frame `map_free` reserved to the fetch that missed on `page_id`, to read the page into
+    fn vacant(&self, page_id: PageId, frame_id: FrameId, frame: BufferPoolFrame) -> Claim {
+        BufCounters::bump(&self.counters.misses, 1);
+        trace_event!(page_id, hit = false, "fetch");
+        Claim::Vacant(frame_id, frame, self.mgr.clone(), self.temp.handle(page_id))
+    }
+
+    /// Map `page_id` to `frame_id`, a free frame the caller took, unless another thread maps the page first. The frame is
+    /// made ready before the mapping is published (pinned once, loading, in `loads`, its version odd), since threads that find
+    /// the mapping pin the frame and wait for the read right away. If the page is mapped already the frame goes back on the
+    /// free list
+    fn map_free(&self, page_id: PageId, frame_id: FrameId, frame: BufferPoolFrame) -> Mapping {
+        frame.page_id.store(page_id, Ordering::Release);
+        frame.pin_count.store(1, Ordering::Release);
+        frame.dirty.store(false, Ordering::Release);
+        frame.loading.store(true, Ordering::Release);
+        // nobody may copy the frame optimistically until it holds the page
+        frame.version.fetch_add(1, Ordering::AcqRel);
+        self.replacer.record_page(frame_id, page_id);
+        self.replacer.record_access(frame_id);
+        self.replacer.set_evictable(frame_id, false);
+        self.loads.lock().insert(frame_id);
+        let mapped = self.page_table.get_or_insert_with(page_id, || frame_id);
+        if mapped == frame_id {
+            return Mapping::Reserved(frame_id, frame);
+        }
+        self.loads.lock().remove(&frame_id);
+        self.replacer.set_evictable(frame_id, true);
+        self.replacer.remove(frame_id);
+        frame.page_id.store(INVALID_PAGE_ID, Ordering::Release);
+        frame.pin_count.store(0, Ordering::Release);
+        frame.loading.store(false, Ordering::Release);
+        frame.version.fetch_add(1, Ordering::AcqRel);
+        self.free_list.lock().push_front(frame_id);
+        Mapping::Taken(mapped)
+    }
+
+    /// Finish reading `page_id` into the frame `claim` reserved for it, which the reader did straight into the frame's memory.
+    /// Returns whether the page was installed. It isn't if the read failed or the page was freed and allocated again
+    /// meanwhile (see `reserve`); the frame is then dropped from the pool along with the reader's pin, and threads waiting
+    /// for it are left to try again
+    fn finish_load(&mut self, page_id: PageId, frame_id: FrameId, read: bool) -> bool {
+        let frame = self.frame(frame_id);
+        let installed = read && frame.page_id() == page_id;
+        if !installed {
+            if self.page_table.get(&page_id) == Some(frame_id) {
+                self.page_table.remove(&page_id);
+            }
+            frame.page_id.store(INVALID_PAGE_ID, Ordering::Release);
+        }
+        frame.loading.store(false, Ordering::Release);
+        frame.version.fetch_add(1, Ordering::AcqRel);
+        if !installed {
+            self.release(frame_id);
+        }
+        self.loads.lock().remove(&frame_id);
+        self.loaded.notify_all();
+        installed
+    }
+
+    /// Drop a pin on a frame whose page failed to load, returning the frame to the free list with the last one
+    fn release(&mut self, frame_id: FrameId) {
+        let frame = self.frame(frame_id);
+        if frame.pin_count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.replacer.set_evictable(frame_id, true);
+            self.replacer.remove(frame_id);
+            memory(&frame).0 = page::empty();
+            self.free_list.lock().push_back(frame_id);
+        }
+    }
+
+    /// Bring `page_id`, which the caller has just allocated on disk, into the pool as a zeroed page, pinned. Returns `None` if
+    /// every frame is pinned
+    pub(crate) fn adopt(&mut self, page_id: PageId) -> Option<BufferPoolFrame> {
+        let frame_id = self.acquire_frame()?;
+        Some(self.install(frame_id, page_id, &page::empty()))
+    }
+
+    /// The disk manager of the data file, for reading pages around the pool
+    pub(crate) fn disk(&self) -> SharedDisk {
+        self.mgr.clone()
+    }
+
+    pub(crate) fn is_resident(&self, page_id: PageId) -> bool {
+        self.page_table.contains_key(&page_id)
+    }
+
+    /// Create a temp file in `dir` for a `TempPageAllocator`
+    pub(crate) fn create_temp_file(&mut self, dir: &Path) -> Result<TempFile, StorageError> {
+        self.temp.create(dir)
+    }
+
+    /// Allocate a page in `file` and bring it into the pool as a zeroed page, pinned. Fails with `StorageError::PoolExhausted`
+    /// if every frame is pinned
+    pub(crate) fn new_temp_page(
+        &mut self,
+        file: &TempFile,
+    ) -> Result<(PageId, BufferPoolFrame), StorageError> {
+        let page_id = file.lock().allocate();
+        match self.adopt(page_id) {
+            Some(frame) => Ok((page_id, frame)),
+            None => {
+                self.temp.deallocate(page_id)?;
+                Err(StorageError::PoolExhausted)
+            }
+        }
+    }
+
+    /// Forget the temp file numbered `file_no`, dropping its unpinned pages from the pool without writing them back. Pinned
+    /// ones are dropped when they're evicted, since writes to a forgotten temp file are discarded
+    pub(crate) fn drop_temp_file(&mut self, file_no: u32) {
+        self.temp.remove(file_no);
+        let resident: Vec<(PageId, FrameId)> = self.page_table.entries();
+        for (page_id, frame_id) in resident {
+            if !is_temp_page(page_id) || self.temp.handle(page_id).is_some() {
+                continue;
+            }
+            let frame = self.frame(frame_id);
+            let memory = match frame.pin_count() {
+                0 => frame.memory.try_write(),
+                _ => None,
+            };
+            if let Some(mut memory) = memory {
+                self.discard(page_id, frame_id, &frame, &mut memory);
+            }
+        }
+    }
+
+    /// Drop a resident page from the pool without writing it back, returning its frame to the free list. The caller holds the
+    /// frame's exclusive latch, through which `memory` is the page
+    fn discard(
+        &mut self,
+        page_id: PageId,
+        frame_id: FrameId,
+        frame: &BufferPoolFrame,
+        memory: &mut FrameMemory,
+    ) {
+        self.page_table.remove(&page_id);
+        self.replacer.remove(frame_id);
+        memory.0 = page::empty();
+        frame.page_id.store(INVALID_PAGE_ID, Ordering::Release);
+        frame.dirty.store(false, Ordering::Release);
+        self.free_list.lock().push_back(frame_id);
+    }
+
+    /// Install `page_id` in `frame_id` with a pin count of one
+    fn install(&mut self, frame_id: FrameId, page_id: PageId, page: &Page) -> BufferPoolFrame {
+        let frame = self.reserve(frame_id, page_id);
+        {
+            let mut memory = memory(&frame);
+            memory.0 = *page;
+            page::set_page_id(&mut memory.0, page_id);
+        }
+        frame
+    }
+
+    /// Map `page_id` to `frame_id` with a pin count of one, leaving the frame's contents as they are. The only frame that can
+    /// already be mapped to the page is one it's being read into after it was freed, when it has just been allocated again:
+    /// that frame is detached, so the read is dropped instead of installing the freed page's contents
+    fn reserve(&mut self, frame_id: FrameId, page_id: PageId) -> BufferPoolFrame {
+        if let Some(stale) = self.page_table.insert(page_id, frame_id) {
+            let stale = self.frame(stale);
+            debug_assert!(stale.is_loading(), "page {} is already resident", page_id);
+            stale.page_id.store(INVALID_PAGE_ID, Ordering::Release);
+        }
+        let frame = self.frame(frame_id);
+        frame.page_id.store(page_id, Ordering::Release);
+        frame.pin_count.store(1, Ordering::Release);
+        frame.dirty.store(false, Ordering::Release);
+        self.replacer.record_page(frame_id, page_id);
+        self.replacer.record_access(frame_id);
+        self.replacer.set_evictable(frame_id, false);
+        frame
+    }
+
+    /// Pin the frame of a resident page. Only needs the pool's shared latch, under which frames aren't evicted. This doesn't
+    /// count as an access for the replacer; `fetch_page` records that itself so that internal pins (e.g. for write-back) don't
+    /// skew eviction order.
+    fn pin(&self, frame_id: FrameId) -> BufferPoolFrame {
+        let frame = self.frames[(frame_id - 1) as usize].clone();
+        frame.pin_count.fetch_add(1, Ordering::AcqRel);
+        self.replacer.set_evictable(frame_id, false);
+        frame
+    }
+
+    fn unpin(&mut self, page_id: PageId, is_dirty: bool) -> bool {
+        let Some(frame_id) = self.page_table.get(&page_id) else {
+            return false;
+        };
+        let frame = self.frame(frame_id);
+        if frame.pin_count() == 0 {
+            return false;
+        }
+        if is_dirty {
+            frame.dirty.store(true, Ordering::Release);
+        }
+        if frame.pin_count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.replacer.set_evictable(frame_id, true);
+        }
+        true
+    }
+
+    /// Change the number of frames to `new_size` (see `BufApi::resize`). Returns how many frames were given up
+    fn resize(&mut self, new_size: usize) -> usize {
+        assert!(new_size > 0, "a buffer pool needs at least one frame");
+        let old = self.capacity;
+        if new_size >= old {
+            let mut free_list = self.free_list.lock();
+            for frame_id in old + 1..=new_size {
+                free_list.push_back(frame_id as FrameId);
+            }
+            drop(free_list);
+            self.capacity = new_size;
+            self.replacer.resize(new_size);
+            return 0;
+        }
+        // frame ids stay contiguous, so give frames up from the top and stop at the first one that has to stay
+        let mut capacity = old;
+        while capacity > new_size && self.reclaim(capacity as FrameId) {
+            capacity -= 1;
+        }
+        let mut free_list = self.free_list.lock();
+        *free_list = std::mem::take(&mut *free_list)
+            .into_iter()
+            .filter(|frame_id| *frame_id as usize <= capacity)
+            .collect();
+        drop(free_list);
+        self.frames.truncate(capacity);
+        self.frame_addrs.truncate(capacity);
+        self.capacity = capacity;
+        self.replacer.resize(capacity);
+        old - capacity
+    }
+
+    /// Empty a frame so it can be given up, writing back its page if it's dirty. Returns false if the frame is pinned or
+    /// latched, or its page couldn't be written back
+    fn reclaim(&mut self, frame_id: FrameId) -> bool {
+        let Some(frame) = self.frames.get((frame_id - 1) as usize).cloned() else {
+            // never allocated, so it's still on the free list
+            return true;
+        };
+        let page_id = frame.page_id();
+        if page_id == INVALID_PAGE_ID {
+            return true;
+        }
+        if frame.pin_count() > 0 {
+            return false;
+        }
+        let Some(memory) = frame.memory.try_write() else {
+            return false;
+        };
+        let dirty = frame.is_dirty();
+        if dirty {
+            if self.write_page(&memory.0, page_id).is_err() {
+                return false;
+            }
+            BufCounters::bump(&self.counters.write_backs, 1);
+        }
+        BufCounters::bump(&self.counters.evictions, 1);
+        trace_event!(page_id, frame_id, dirty, "evict");
+        self.page_table.remove(&page_id);
+        self.replacer.remove(frame_id);
+        frame.page_id.store(INVALID_PAGE_ID, Ordering::Release);
+        frame.dirty.store(false, Ordering::Release);
+        true
+    }
 }
 
 pub trait BufApi {
-    fn create(path: &str) -> Self;
+    fn create(path: &str) -> Result<Self, StorageError>
+    where
+        Self: Sized;
+    fn create_with_replacer(path: &str, replacer: BoxedReplacer) -> Result<Self, StorageError>
+    where
+        Self: Sized;
+    fn open(path: &str) -> Result<Self, StorageError>
+    where
+        Self: Sized;
+    fn create_with_config(config: &StorageConfig) -> Result<Self, StorageError>
+    where
+        Self: Sized;
+    fn open_with_config(config: &StorageConfig) -> Result<Self, StorageError>
+    where
+        Self: Sized;
     fn size(&self) -> usize;
-    fn new_page(&self, page_id: PageId) -> Option<Page>;
-    fn fetch_page(&self, page_id: PageId) -> Page;
-    fn unpin_page(&self, page_id: PageId);
-    fn flush_page(&self, page_id: PageId) -> bool;
-    fn flush_all(&self);
-    fn delete_page(&self, page_id: PageId) -> bool;
-    fn alloc_page(&self) -> PageId;
+    fn resize(&self, new_size: usize) -> usize;
+    fn new_page(&self) -> Result<(PageId, BufferPoolFrame), StorageError>;
+    fn fetch_page(&self, page_id: PageId) -> Result<BufferPoolFrame, StorageError>;
+    fn prefetch(&self, page_id: PageId, count: usize);
+    fn set_read_ahead(&self, distance: usize);
+    fn wait_for_prefetch(&self);
+    fn warm_up(&self, page_ids: &[PageId]) -> usize;
+    fn pin_resident(&self, page_id: PageId) -> Result<(), StorageError>;
+    fn unpin_resident(&self, page_id: PageId) -> bool;
+    fn fetch_page_read(&self, page_id: PageId) -> Result<ReadPageGuard, StorageError>;
+    fn fetch_page_write(&self, page_id: PageId) -> Result<WritePageGuard, StorageError>;
+    fn new_page_write(&self) -> Result<WritePageGuard, StorageError>;
+    fn fetch_page_pinned(&self, page_id: PageId) -> Result<PinnedPage, StorageError>;
+    fn fetch_page_optimistic(&self, page_id: PageId) -> Result<OptimisticGuard, StorageError>;
+    fn read_page_optimistic(&self, page_id: PageId) -> Option<Page>;
+    fn unpin_page(&self, page_id: PageId, is_dirty: bool) -> bool;
+    fn flush_page(&self, page_id: PageId) -> Result<(), StorageError>;
+    fn flush_all(&self) -> Result<(), StorageError>;
+    fn flush_unpinned(&self) -> Result<usize, StorageError>;
+    fn dirty_pages(&self) -> Vec<PageId>;
+    fn dirty_count(&self) -> usize;
+    fn stats(&self) -> BufStats;
+    fn start_background_writer(&self, interval: Duration) -> BackgroundWriter;
+    fn close(&self) -> Result<(), StorageError>;
+    fn delete_page(&self, page_id: PageId) -> Result<(), StorageError>;
+    fn alloc_page(&self) -> Result<PageId, StorageError>;
+    fn set_log_manager(&self, log: LogManager);
+    fn set_simulation(&self, sim: Simulation);
+    fn set_event_bus(&self, bus: EventBus);
 }
 
 pub type BufferPool = RwSynchronized<BufferPoolContext>;
 
 impl BufApi for BufferPool {
-    fn create(path: &str) -> Self {
-        let mut free_list: LinkedList<FrameId> = LinkedList::new();
-        for i in 1..BUFFER_POOL_SIZE + 1 {
-            free_list.push_back(i as FrameId);
-        }
-        RwSynchronized::init(BufferPoolContext {
-            mgr: DiskMgr::create(&path),
-            frames: Vec::new(),
-            free_list: RefCell::new(free_list),
-            page_table: Synchronized::init(HashMap::new()),
-        })
+    /// Create a buffer pool backed by the file at `path`, using LRU-K replacement
+    fn create(path: &str) -> Result<Self, StorageError> {
+        BufferPool::create_with_config(&StorageConfig::for_path(path))
+    }
+
+    /// Create a buffer pool that uses `replacer` to pick eviction victims. The replacer must accept frame ids
+    /// `1..=BUFFER_POOL_SIZE`.
+    fn create_with_replacer(path: &str, replacer: BoxedReplacer) -> Result<Self, StorageError> {
+        Ok(with_disk(
+            Arc::new(DiskMgr::create(path)?),
+            replacer,
+            BUFFER_POOL_SIZE,
+        ))
+    }
+
+    /// Open a buffer pool over the existing database file at `path` (or a new one, if there's none), using LRU-K replacement.
+    /// Unlike `create`, pages already in the file are kept.
+    fn open(path: &str) -> Result<Self, StorageError> {
+        BufferPool::open_with_config(&StorageConfig::for_path(path))
+    }
+
+    /// Create a buffer pool of `config.pool_size()` frames over a new data file (see `DiskApi::create_with_config`), using
+    /// LRU-K replacement with the configured k
+    fn create_with_config(config: &StorageConfig) -> Result<Self, StorageError> {
+        Ok(with_config(
+            Arc::new(DiskMgr::create_with_config(config)?),
+            config,
+        ))
+    }
+
+    /// Like `create_with_config`, but keeping the pages already in the data file (see `open`)
+    fn open_with_config(config: &StorageConfig) -> Result<Self, StorageError> {
+        Ok(with_config(
+            Arc::new(DiskMgr::open_with_config(config)?),
+            config,
+        ))
     }
 
     #[inline]
@@ -84,60 +816,901 @@ impl BufApi for BufferPool {
         inner.frames.len()
     }
 
+    /// Grow or shrink the pool to `new_size` frames. Growing only makes the new frames available; they're allocated as they
+    /// come into use. Shrinking gives up the highest-numbered frames, evicting their pages (dirty ones are written back first),
+    /// and stops early at a frame that's pinned, so the pool may end up larger than asked. Returns how many frames were given
+    /// up, which is 0 when growing.
+    fn resize(&self, new_size: usize) -> usize {
+        let mut inner = self.write();
+        inner.resize(new_size)
+    }
+
+    /// Allocate a new page on disk and bring it into the pool, pinned. Returns the new page id along with its frame. Fails
+    /// with `StorageError::PoolExhausted` if all frames are currently pinned. The frame is zeroed; latch it to fill in the
+    /// page, then unpin it (dirty).
+    fn new_page(&self) -> Result<(PageId, BufferPoolFrame), StorageError> {
+        let mut inner = self.write();
+        let frame_id = inner.acquire_frame().ok_or(StorageError::PoolExhausted)?;
+        let buf = page::empty();
+        let page_id = match inner.mgr.allocate_page(&buf) {
+            Ok(page_id) => page_id,
+            Err(err) => {
+                inner.free_list.lock().push_back(frame_id);
+                return Err(err);
+            }
+        };
+        Ok((page_id, inner.install(frame_id, page_id, &buf)))
+    }
+
+    /// Pin `page_id` in the pool, reading it from disk if it isn't resident. Fails with `StorageError::PoolExhausted` if the
+    /// page isn't resident and every frame is pinned, and with the disk manager's error if the read fails. A page that fails
+    /// checksum verification is reported as `StorageError::Corruption` and is never installed in the pool. Every successful
+    /// fetch must be paired with an `unpin_page`.
     ///
-    /// TODO Add implementation
-    ///
-    /// @brief Create a new page in the buffer pool. Set page_id to the new page's id, or nullptr if all frames
-    /// are currently in use and not evictable (in another word, pinned).
-    ///
-    /// You should pick the replacement frame from either the free list or the replacer (always find from the free list
-    /// first), and then call the AllocatePage() method to get a new page id. If the replacement frame has a dirty page,
-    /// you should write it back to the disk first. You also need to reset the memory and metadata for the new page.
-    ///
-    /// Remember to "Pin" the frame by calling replacer.SetEvictable(frame_id, false)
-    /// so that the replacer wouldn't evict the frame before the buffer pool manager "Unpin"s it.
-    /// Also, remember to record the access history of the frame in the replacer for the lru-k algorithm to work.
-    ///
-    /// @param[out] page_id id of created page
-    /// @return nullptr if no new pages could be created, otherwise pointer to new page
-    ///
-    fn new_page(&self, page_id: PageId) -> Option<Page> {
-        todo!()
+    /// Hits, and misses that find a free frame, only take the pool's shared latch (see `BufferPoolContext::try_claim`), and the
+    /// read happens without the pool's latch. Threads that miss on the same page at once don't each read it: the first one
+    /// does, and the others wait for it and share its frame.
+    fn fetch_page(&self, page_id: PageId) -> Result<BufferPoolFrame, StorageError> {
+        let (claim, read_ahead) = {
+            let inner = self.read();
+            let read_ahead = {
+                let mut scans = inner.scans.lock();
+                let read_ahead = scans.read_ahead();
+                scans.detect(page_id).map(|start| (start, read_ahead))
+            };
+            (inner.try_claim(page_id), read_ahead)
+        };
+        let claim = match claim {
+            Some(claim) => Ok(claim),
+            None => self.write().claim(page_id),
+        };
+        if let Some((start, count)) = read_ahead {
+            self.prefetch(start, count);
+        }
+        load(self, page_id, claim)
+    }
+
+    /// Start reading `count` pages from `page_id` on into free frames in the background, so a scan about to visit them doesn't
+    /// have to wait for the reads. The pages are left unpinned and are the first to go if frames run short. Read-ahead stops
+    /// at the first page that isn't in the file, and never evicts anything to make room.
+    fn prefetch(&self, page_id: PageId, count: usize) {
+        let pool = self.clone();
+        let ticket = PrefetchTicket::new(self);
+        rayon::spawn(move || {
+            let _ticket = ticket;
+            for page_id in page_id..page_id + count as PageId {
+                if !prefetch_page(&pool, page_id) {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Read ahead `distance` pages whenever a thread fetches pages in ascending order (see `prefetch`); 0 turns it off, which
+    /// is the default
+    fn set_read_ahead(&self, distance: usize) {
+        self.read().scans.lock().set_read_ahead(distance);
+    }
+
+    /// Block until every read-ahead started so far (by `prefetch` or a detected scan) has finished
+    fn wait_for_prefetch(&self) {
+        let (prefetches, prefetched) = {
+            let inner = self.read();
+            (inner.prefetches.clone(), inner.prefetched.clone())
+        };
+        let mut prefetches = prefetches.lock();
+        prefetched.wait_while(&mut prefetches, |prefetches| *prefetches > 0);
+    }
+
+    /// Read `page_ids` into free frames, unpinned, e.g. to load the pages a workload is known to need at startup. Like
+    /// `prefetch` this never evicts anything and reads without the pool's latch, but it reads synchronously and skips pages
+    /// that don't exist. Returns how many of the pages are resident afterwards
+    fn warm_up(&self, page_ids: &[PageId]) -> usize {
+        let mut resident = 0;
+        for page_id in page_ids.iter() {
+            if prefetch_page(self, *page_id) {
+                resident += 1;
+            } else if self.read().free_list.lock().is_empty() {
+                break;
+            }
+        }
+        resident
+    }
+
+    /// Keep `page_id` resident until `unpin_resident`, for hot pages like index roots that would otherwise be evicted under
+    /// memory pressure. The pool holds a pin of its own on the page, so it also counts against the frames available to everyone
+    /// else. Pinning a page that's already pinned this way does nothing
+    fn pin_resident(&self, page_id: PageId) -> Result<(), StorageError> {
+        if self.read().pinned_resident.contains(&page_id) {
+            return Ok(());
+        }
+        let claim = self.read().try_claim(page_id);
+        let claim = match claim {
+            Some(claim) => Ok(claim),
+            None => self.write().claim(page_id),
+        };
+        load(self, page_id, claim)?;
+        let mut inner = self.write();
+        // another thread may have pinned it meanwhile, and only one pin is kept
+        if !inner.pinned_resident.insert(page_id) {
+            inner.unpin(page_id, false);
+        }
+        Ok(())
+    }
+
+    /// Let a page pinned by `pin_resident` be evicted again. Returns false if it wasn't pinned that way
+    fn unpin_resident(&self, page_id: PageId) -> bool {
+        let mut inner = self.write();
+        inner.pinned_resident.remove(&page_id) && inner.unpin(page_id, false)
     }
 
-    fn fetch_page(&self, page_id: PageId) -> Page {
-        todo!();
+    /// Pin `page_id` and latch it shared. The guard unlatches and unpins the page when dropped
+    fn fetch_page_read(&self, page_id: PageId) -> Result<ReadPageGuard, StorageError> {
+        let frame = self.fetch_page(page_id)?;
+        Ok(ReadPageGuard::new(self.clone(), frame, page_id))
     }
 
-    fn unpin_page(&self, page_id: PageId) {
-        todo!();
+    /// Pin `page_id` and latch it exclusively. The guard unlatches and unpins the page when dropped, marking it dirty if it was
+    /// modified
+    fn fetch_page_write(&self, page_id: PageId) -> Result<WritePageGuard, StorageError> {
+        let frame = self.fetch_page(page_id)?;
+        Ok(WritePageGuard::new(self.clone(), frame, page_id))
     }
 
-    fn flush_page(&self, page_id: PageId) -> bool {
-        todo!();
+    /// Allocate a new page and latch it exclusively (see `new_page`)
+    fn new_page_write(&self) -> Result<WritePageGuard, StorageError> {
+        let (page_id, frame) = self.new_page()?;
+        Ok(WritePageGuard::new(self.clone(), frame, page_id))
     }
 
-    fn flush_all(&self) {
-        todo!();
+    /// Pin `page_id` without latching it, for handing the page's memory to code outside the pool (see `PinnedPage`)
+    fn fetch_page_pinned(&self, page_id: PageId) -> Result<PinnedPage, StorageError> {
+        let frame = self.fetch_page(page_id)?;
+        Ok(PinnedPage::new(self.clone(), frame, page_id))
     }
 
-    fn delete_page(&self, page_id: PageId) -> bool {
-        todo!();
+    /// Pin `page_id` without latching it, noting its version so reads of the page can be validated afterwards (see
+    /// `OptimisticGuard`)
+    fn fetch_page_optimistic(&self, page_id: PageId) -> Result<OptimisticGuard, StorageError> {
+        let frame = self.fetch_page(page_id)?;
+        Ok(OptimisticGuard::new(self.clone(), frame, page_id))
     }
 
-    fn alloc_page(&self) -> PageId {
+    /// Copy `page_id` out of the pool without pinning it or latching its frame, only holding the pool's shared latch so the
+    /// frame can't be given to another page meanwhile. The copy is checked against the frame's version, so it's never torn.
+    /// Returns `None` if the page isn't resident or a write guard is changing it; fall back to `fetch_page_read` then. Counts
+    /// as a hit, and as an access for the replacer
+    fn read_page_optimistic(&self, page_id: PageId) -> Option<Page> {
         let inner = self.read();
+        let frame_id = inner.page_table.get(&page_id)?;
+        let frame = &inner.frames[(frame_id - 1) as usize];
+        let version = frame.version();
+        if version % 2 == 1 {
+            return None;
+        }
+        let mut copy = page::empty();
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                frame_page(frame) as *const u8,
+                copy.as_mut_ptr(),
+                PAGE_SIZE,
+            )
+        };
+        std::sync::atomic::fence(Ordering::Acquire);
+        if frame.version() != version {
+            return None;
+        }
+        BufCounters::bump(&inner.counters.hits, 1);
+        inner.replacer.record_access(frame_id);
+        Some(copy)
+    }
+
+    /// Drop one pin on `page_id`, marking it dirty if the caller modified it. Once the pin count reaches zero the frame becomes
+    /// a candidate for eviction. Returns false if the page isn't resident or isn't pinned.
+    fn unpin_page(&self, page_id: PageId, is_dirty: bool) -> bool {
+        let mut inner = self.write();
+        inner.unpin(page_id, is_dirty)
+    }
+
+    /// Write `page_id` to disk (dirty or not) and clear its dirty bit. Fails with `StorageError::PageNotFound` if the page
+    /// isn't resident.
+    fn flush_page(&self, page_id: PageId) -> Result<(), StorageError> {
+        match write_back(self, &[page_id])? {
+            0 => Err(StorageError::PageNotFound(page_id)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write every dirty resident page to disk, concurrently and without holding the pool latch during the writes, then sync
+    /// once (see `write_back`). Fails if a write or the sync failed, leaving the pages dirty
+    fn flush_all(&self) -> Result<(), StorageError> {
+        let dirty: Vec<PageId> = {
+            let mut inner = self.write();
+            let resident: Vec<(PageId, FrameId)> = inner.page_table.entries();
+            resident
+                .into_iter()
+                .filter(|(page_id, frame_id)| {
+                    !is_temp_page(*page_id) && inner.frame(*frame_id).is_dirty()
+                })
+                .map(|(page_id, _)| page_id)
+                .collect()
+        };
+        write_back(self, &dirty)?;
+        Ok(())
+    }
+
+    /// Write back every dirty page that nobody has pinned, leaving pages that are in use alone. Returns how many were written
+    fn flush_unpinned(&self) -> Result<usize, StorageError> {
+        let dirty: Vec<PageId> = {
+            let mut inner = self.write();
+            let resident: Vec<(PageId, FrameId)> = inner.page_table.entries();
+            resident
+                .into_iter()
+                .filter(|(page_id, frame_id)| {
+                    let frame = inner.frame(*frame_id);
+                    !is_temp_page(*page_id) && frame.is_dirty() && frame.pin_count() == 0
+                })
+                .map(|(page_id, _)| page_id)
+                .collect()
+        };
+        write_back(self, &dirty)
+    }
+
+    /// Resident pages that may be newer than their copy on disk: every dirty page, and every pinned one, since whoever
+    /// pinned it may be modifying it and only marks it dirty when they unpin it. Collected under the pool's exclusive latch,
+    /// so no page is pinned or unpinned while the list is being made
+    fn dirty_pages(&self) -> Vec<PageId> {
+        let mut inner = self.write();
+        let resident: Vec<(PageId, FrameId)> = inner.page_table.entries();
+        let mut dirty: Vec<PageId> = resident
+            .into_iter()
+            .filter(|(page_id, frame_id)| {
+                let frame = inner.frame(*frame_id);
+                !is_temp_page(*page_id) && (frame.is_dirty() || frame.pin_count() > 0)
+            })
+            .map(|(page_id, _)| page_id)
+            .collect();
+        dirty.sort_unstable();
+        dirty
+    }
+
+    /// Number of dirty frames. Only takes the pool's shared latch, so it's cheap enough to poll, but the count may be out of
+    /// date by the time it's returned
+    fn dirty_count(&self) -> usize {
+        let inner = self.read();
+        inner.frames.iter().filter(|frame| frame.is_dirty()).count()
+    }
+
+    /// Counters since the pool was created, along with the disk manager's. Like `dirty_count`, only takes the shared latch
+    fn stats(&self) -> BufStats {
+        let inner = self.read();
+        let counters = &inner.counters;
+        let reads_in_flight = inner.loads.lock().len();
+        BufStats {
+            hits: counters.hits.load(Ordering::Relaxed),
+            misses: counters.misses.load(Ordering::Relaxed),
+            evictions: counters.evictions.load(Ordering::Relaxed),
+            write_backs: counters.write_backs.load(Ordering::Relaxed),
+            pinned: inner
+                .frames
+                .iter()
+                .filter(|frame| frame.pin_count() > 0)
+                .count(),
+            reads_in_flight,
+            writes_in_flight: counters.writes_in_flight.load(Ordering::Relaxed),
+            disk: inner.mgr.stats(),
+            replacer: inner.replacer.stats(),
+        }
+    }
+
+    /// Run `flush_unpinned` every `interval` on a background thread until the returned handle is stopped or dropped. Keeping
+    /// the number of dirty pages down bounds how much work eviction and recovery have to do.
+    fn start_background_writer(&self, interval: Duration) -> BackgroundWriter {
+        // the thread doesn't keep the pool alive: dropping the pool stops it
+        let pool = Arc::downgrade(self);
+        let writer = BackgroundWriter::spawn(interval, move || {
+            if let Some(pool) = pool.upgrade() {
+                let _ = pool.flush_unpinned();
+            }
+        });
+        register_writer(self, &writer);
+        writer
+    }
+
+    /// Shut the pool down in order: stop its background writers, let read-ahead finish, make the log durable, write back
+    /// every dirty page and close the data file, which leaves a clean-shutdown marker so that the next open can skip recovery
+    /// (see `DiskApi::close`). The marker promises there is nothing to redo or undo, so only close the pool once every
+    /// transaction has ended and nothing is pinned. The pool can still be used afterwards, and the first write to the data
+    /// file takes the marker back.
+    fn close(&self) -> Result<(), StorageError> {
+        shut_down(self)?;
+        let mgr = self.read().mgr.clone();
+        mgr.close()
+    }
+
+    /// Delete `page_id`: free it on disk, so a later `new_page` or `alloc_page` can reuse it, and if it's resident, drop it
+    /// from the pool without writing it back and return its frame to the free list. Fails with `StorageError::PagePinned`,
+    /// changing nothing, if the page is pinned or its frame is latched, and with `StorageError::PageNotFound` if the page isn't
+    /// in the file. Deleting a page that's already free succeeds. The page is freed on disk before it leaves the pool, so if
+    /// that fails the page stays resident as it was.
+    fn delete_page(&self, page_id: PageId) -> Result<(), StorageError> {
+        let mut inner = self.write();
+        let resident = inner
+            .page_table
+            .get(&page_id)
+            .map(|frame_id| (frame_id, inner.frame(frame_id)));
+        let Some((frame_id, frame)) = resident else {
+            if !inner.page_exists(page_id) {
+                return Err(StorageError::PageNotFound(page_id));
+            }
+            return inner.deallocate(page_id);
+        };
+        // unpinned frames shouldn't be latched, but don't wait for one that is (see `acquire_frame`)
+        let memory = match frame.pin_count() {
+            0 => frame.memory.try_write(),
+            _ => None,
+        };
+        let Some(mut memory) = memory else {
+            return Err(StorageError::PagePinned(page_id));
+        };
+        inner.deallocate(page_id)?;
+        inner.discard(page_id, frame_id, &frame, &mut memory);
+        drop(memory);
+        trace_event!(page_id, frame_id, "delete");
+        Ok(())
+    }
+
+    /// Allocate a page on disk without bringing it into the pool, reusing a deleted page if there is one
+    fn alloc_page(&self) -> Result<PageId, StorageError> {
+        let inner = self.write();
         let buf = page::empty();
-        let page_id = inner.mgr.append_page(&buf).unwrap();
-        page_id
+        inner.mgr.allocate_page(&buf)
+    }
+
+    /// Attach the write-ahead log. From now on dirty pages are only written back once the log is durable up to their LSN
+    fn set_log_manager(&self, log: LogManager) {
+        self.write().log = Some(log);
+    }
+
+    /// Run the pool in deterministic mode, drawing frame choices from `sim`
+    fn set_simulation(&self, sim: Simulation) {
+        self.write().sim = Some(sim);
+    }
+
+    /// Publish buffer pool events (see `StorageEvent`) to `bus`
+    fn set_event_bus(&self, bus: EventBus) {
+        self.write().bus = Some(bus);
+    }
+}
+
+impl Drop for BufferPoolContext {
+    /// Stop the background writers and write back every dirty page, as `close` does but without leaving a clean-shutdown
+    /// marker, since a pool may be dropped with transactions still open. Nothing else can reach the pool by now, so the pages
+    /// are copied and written without pinning them
+    fn drop(&mut self) {
+        for writer in self.writers.drain(..) {
+            writer.stop();
+        }
+        if self.mgr.recovery_required() {
+            return;
+        }
+        if let Some(log) = &self.log {
+            if log.flush().is_err() {
+                return;
+            }
+        }
+        let resident: Vec<(PageId, FrameId)> = self.page_table.entries();
+        let mut images: Vec<(PageId, Page)> = Vec::new();
+        for (page_id, frame_id) in resident {
+            let frame = self.frame(frame_id);
+            if !is_temp_page(page_id) && frame.is_dirty() {
+                images.push((page_id, frame.data()));
+            }
+        }
+        if images.is_empty() {
+            return;
+        }
+        let pages: Vec<(PageId, &Page)> = images.iter().map(|(id, image)| (*id, image)).collect();
+        if self.mgr.write_pages(&pages).is_ok() {
+            let _ = self.mgr.sync();
+        }
+    }
+}
+
+/// A pool of `frames` frames over `mgr`. `replacer` must accept frame ids `1..=frames`
+pub(crate) fn with_disk(mgr: SharedDisk, replacer: BoxedReplacer, frames: usize) -> BufferPool {
+    let mut free_list: LinkedList<FrameId> = LinkedList::new();
+    for i in 1..frames + 1 {
+        free_list.push_back(i as FrameId);
+    }
+    RwSynchronized::init(BufferPoolContext {
+        mgr,
+        capacity: frames,
+        frames: Vec::new(),
+        frame_addrs: Vec::new(),
+        free_list: Synchronized::init(free_list),
+        page_table: ShardedHashTable::new(),
+        replacer,
+        log: None,
+        sim: None,
+        bus: None,
+        temp: TempFiles::default(),
+        scans: Synchronized::init(ScanTracker::new()),
+        prefetches: Synchronized::init(0),
+        prefetched: CondVar::init(),
+        flushing: Synchronized::init(()),
+        loads: Synchronized::init(HashSet::new()),
+        loaded: CondVar::init(),
+        pinned_resident: HashSet::new(),
+        writers: Vec::new(),
+        counters: BufCounters::default(),
+    })
+}
+
+/// A pool sized and replacing as `config` says, over `mgr`
+pub(crate) fn with_config(mgr: SharedDisk, config: &StorageConfig) -> BufferPool {
+    let replacer = LRUKReplacer::create(config.pool_size(), config.replacer_k());
+    with_disk(mgr, Box::new(replacer), config.pool_size())
+}
+
+/// Like `BufApi::create_with_config`, but every page is compressed with `codec` on its way to disk (see `compress`)
+pub fn create_compressed(
+    config: &StorageConfig,
+    codec: BoxedCodec,
+) -> Result<BufferPool, StorageError> {
+    let mgr = CompressedDiskMgr::create_with_codec(config, codec)?;
+    Ok(with_config(Arc::new(mgr), config))
+}
+
+/// Like `create_compressed`, but keeping the pages already in the data file. `codec` must be the one they were written with
+pub fn open_compressed(
+    config: &StorageConfig,
+    codec: BoxedCodec,
+) -> Result<BufferPool, StorageError> {
+    let mgr = CompressedDiskMgr::open_with_codec(config, codec)?;
+    Ok(with_config(Arc::new(mgr), config))
+}
+
+/// What `BufferPoolContext::claim` found for a page. In each case the frame has been pinned for the caller
+enum Claim {
+    /// The page is resident
+    Resident(BufferPoolFrame),
+    /// Another thread is reading the page into this frame; wait for it on the pool's `loads`
+    Loading(
+        FrameId,
+        BufferPoolFrame,
+        Synchronized<HashSet<FrameId>>,
+        CondVar,
+    ),
+    /// The frame was reserved for the page, which the caller has to read with the data file's disk manager, or the temp
+    /// file if it's a temp page
+    Vacant(FrameId, BufferPoolFrame, SharedDisk, Option<TempFile>),
+}
+
+/// What `BufferPoolContext::map_free` did with a free frame
+enum Mapping {
+    /// The page was mapped to the frame, which is pinned and loading: read the page into it and call `finish_load`
+    Reserved(FrameId, BufferPoolFrame),
+    /// Another thread had mapped the page to this frame already
+    Taken(FrameId),
+}
+
+/// Read a page from `mgr`, or from `temp` if it's a temp page
+fn read_page(
+    mgr: &SharedDisk,
+    temp: Option<&TempFile>,
+    buf: &mut Page,
+    page_id: PageId,
+) -> Result<(), StorageError> {
+    if is_temp_page(page_id) {
+        return read_temp_page(temp, buf, page_id);
+    }
+    mgr.read_page(buf, page_id as u64)
+}
+
+/// Read `page_id` into the frame reserved for it, without the pool's latch
+fn read_into(
+    frame: &BufferPoolFrame,
+    mgr: &SharedDisk,
+    temp: Option<&TempFile>,
+    page_id: PageId,
+) -> Result<(), StorageError> {
+    let mut memory = memory(frame);
+    read_page(mgr, temp, &mut memory.0, page_id)?;
+    page::set_page_id(&mut memory.0, page_id);
+    Ok(())
+}
+
+/// Read `page_id` into a free frame, unpinned, unless it's already resident (see `reserve_prefetch`). Returns false once
+/// there's no point going on: no free frame is left, the page is past the end of the file or it couldn't be read
+pub(crate) fn prefetch_page(pool: &BufferPool, page_id: PageId) -> bool {
+    match reserve_prefetch(pool, page_id) {
+        Prefetch::Resident => true,
+        Prefetch::Stop => false,
+        Prefetch::Read(read) => read.read(pool),
+    }
+}
+
+/// What `reserve_prefetch` made of a page
+pub(crate) enum Prefetch {
+    /// Nothing to read: the page is resident, or another thread is reading it
+    Resident,
+    /// No point going on: no free frame is left, or the page is past the end of the file
+    Stop,
+    /// A free frame is set aside for the page, which `PrefetchRead::read` reads into it
+    Read(PrefetchRead),
+}
+
+/// A page being read ahead into the frame reserved for it. Other fetches of the page wait for the read
+pub(crate) struct PrefetchRead {
+    page_id: PageId,
+    frame_id: FrameId,
+    frame: BufferPoolFrame,
+    mgr: SharedDisk,
+    temp: Option<TempFile>,
+}
+
+impl PrefetchRead {
+    /// Read the page without the pool's latch and leave it unpinned. Returns false if it couldn't be read
+    pub(crate) fn read(self, pool: &BufferPool) -> bool {
+        let read = read_into(&self.frame, &self.mgr, self.temp.as_ref(), self.page_id);
+        let mut inner = pool.write();
+        inner.finish_load(self.page_id, self.frame_id, read.is_ok())
+            && inner.unpin(self.page_id, false)
+    }
+}
+
+/// Reserve a free frame for reading `page_id` ahead, unless it's already resident. The frame is reserved under the pool's
+/// shared latch, or under the exclusive one if it has never been used and has to be allocated, as on a miss in `fetch_page`.
+/// Never evicts anything
+pub(crate) fn reserve_prefetch(pool: &BufferPool, page_id: PageId) -> Prefetch {
+    let (reserved, mgr, temp) = {
+        let inner = pool.read();
+        if inner.is_resident(page_id) {
+            return Prefetch::Resident;
+        }
+        if !inner.page_exists(page_id) {
+            return Prefetch::Stop;
+        }
+        let Some(frame_id) = inner.take_free() else {
+            return Prefetch::Stop;
+        };
+        let reserved = inner
+            .allocated(frame_id)
+            .map(|frame| inner.map_free(page_id, frame_id, frame))
+            .ok_or(frame_id);
+        (reserved, inner.mgr.clone(), inner.temp.handle(page_id))
+    };
+    let mapping = match reserved {
+        Ok(mapping) => mapping,
+        Err(frame_id) => {
+            let mut inner = pool.write();
+            let frame = inner.frame(frame_id);
+            inner.map_free(page_id, frame_id, frame)
+        }
+    };
+    match mapping {
+        Mapping::Reserved(frame_id, frame) => Prefetch::Read(PrefetchRead {
+            page_id,
+            frame_id,
+            frame,
+            mgr,
+            temp,
+        }),
+        Mapping::Taken(_) => Prefetch::Resident,
     }
 }
 
+/// Counts a read-ahead as in progress for as long as it's alive: `wait_for_prefetch`, and so `close`, waits for it
+pub(crate) struct PrefetchTicket {
+    prefetches: Synchronized<usize>,
+    prefetched: CondVar,
+}
+
+impl PrefetchTicket {
+    pub(crate) fn new(pool: &BufferPool) -> Self {
+        let (prefetches, prefetched) = {
+            let inner = pool.read();
+            (inner.prefetches.clone(), inner.prefetched.clone())
+        };
+        *prefetches.lock() += 1;
+        PrefetchTicket {
+            prefetches,
+            prefetched,
+        }
+    }
+}
+
+impl Drop for PrefetchTicket {
+    fn drop(&mut self) {
+        let mut prefetches = self.prefetches.lock();
+        *prefetches -= 1;
+        if *prefetches == 0 {
+            self.prefetched.notify_all();
+        }
+    }
+}
+
+/// Finish a fetch of `page_id` that `claim` started: read the page if the frame was reserved for it, or wait for the thread
+/// reading it. A waiter whose reader failed claims the page again, so it reads the page itself unless another thread got
+/// there first
+fn load(
+    pool: &BufferPool,
+    page_id: PageId,
+    claim: Result<Claim, StorageError>,
+) -> Result<BufferPoolFrame, StorageError> {
+    let mut claim = claim?;
+    loop {
+        match claim {
+            Claim::Resident(frame) => return Ok(frame),
+            Claim::Vacant(frame_id, frame, mgr, temp) => {
+                let read = read_into(&frame, &mgr, temp.as_ref(), page_id);
+                let mut inner = pool.write();
+                if inner.finish_load(page_id, frame_id, read.is_ok()) {
+                    return Ok(frame);
+                }
+                read?;
+                // the page was freed and allocated again during the read, so fetch it as it is now
+                claim = inner.claim(page_id)?;
+            }
+            Claim::Loading(frame_id, frame, loads, loaded) => {
+                {
+                    let mut loads = loads.lock();
+                    loaded.wait_while(&mut loads, |loads| loads.contains(&frame_id));
+                }
+                let mut inner = pool.write();
+                if frame.page_id() == page_id {
+                    BufCounters::bump(&inner.counters.hits, 1);
+                    inner.replacer.record_access(frame_id);
+                    return Ok(frame);
+                }
+                inner.release(frame_id);
+                claim = inner.claim(page_id)?;
+            }
+        }
+    }
+}
+
+/// Sequential scan detection for read-ahead
+pub(crate) struct ScanTracker {
+    // pages to read ahead of a sequential scan; 0 turns read-ahead off
+    read_ahead: usize,
+    // last page id each thread fetched and how many consecutive ids it has fetched up to it
+    scans: HashMap<ThreadId, (PageId, usize)>,
+}
+
+impl ScanTracker {
+    pub(crate) fn new() -> Self {
+        ScanTracker {
+            read_ahead: 0,
+            scans: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn read_ahead(&self) -> usize {
+        self.read_ahead
+    }
+
+    pub(crate) fn set_read_ahead(&mut self, distance: usize) {
+        self.read_ahead = distance;
+        self.scans.clear();
+    }
+
+    /// Track the calling thread's fetches. Returns where to start reading ahead once it has fetched `SEQUENTIAL_THRESHOLD`
+    /// consecutive pages, and again every `read_ahead` pages after that, so each window is requested once
+    pub(crate) fn detect(&mut self, page_id: PageId) -> Option<PageId> {
+        if self.read_ahead == 0 {
+            return None;
+        }
+        if self.scans.len() >= MAX_TRACKED_SCANS {
+            self.scans.clear();
+        }
+        let (last, run) = self
+            .scans
+            .entry(std::thread::current().id())
+            .or_insert((INVALID_PAGE_ID, 0));
+        *run = if *last + 1 == page_id { *run + 1 } else { 1 };
+        *last = page_id;
+        let past = run.checked_sub(SEQUENTIAL_THRESHOLD)?;
+        past.is_multiple_of(self.read_ahead).then_some(page_id + 1)
+    }
+}
+
+/// Handle to a background writer thread (see `start_background_writer`). Dropping it stops the thread. The pool it writes back
+/// keeps a handle of its own (see `register_writer`), so closing or dropping the pool stops the thread too
+pub struct BackgroundWriter {
+    stop: BinarySemaphore,
+    handle: Synchronized<Option<JoinHandle<()>>>,
+}
+
+impl BackgroundWriter {
+    /// Run `write_back` every `interval` on a new thread until the handle is stopped or dropped
+    pub(crate) fn spawn(interval: Duration, write_back: impl Fn() + Send + 'static) -> Self {
+        let stop = BinarySemaphore::init(false);
+        let handle = {
+            let stop = stop.clone();
+            std::thread::spawn(move || loop {
+                write_back();
+                // closing the semaphore wakes the thread right away
+                if stop.wait_timeout(interval) == Some(false) {
+                    return;
+                }
+            })
+        };
+        BackgroundWriter {
+            stop,
+            handle: Synchronized::init(Some(handle)),
+        }
+    }
+
+    /// Another handle to the same thread
+    pub(crate) fn share(&self) -> Self {
+        BackgroundWriter {
+            stop: self.stop.clone(),
+            handle: self.handle.clone(),
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.handle
+            .lock()
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+    }
+
+    /// Stop the thread, waiting for a write-back in progress to finish
+    pub fn stop(self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&self) {
+        self.stop.close();
+        let handle = self.handle.lock().take();
+        // the thread itself stops the writer when it lets go of the last handle to the pool it was writing back
+        if let Some(handle) =
+            handle.filter(|handle| handle.thread().id() != std::thread::current().id())
+        {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Keep a handle to `writer` in `pool`, so that closing or dropping the pool stops it
+pub(crate) fn register_writer(pool: &BufferPool, writer: &BackgroundWriter) {
+    let mut inner = pool.write();
+    inner.writers.retain(BackgroundWriter::is_running);
+    inner.writers.push(writer.share());
+}
+
+/// Everything `close` does short of closing the data file: stop the pool's background writers, let read-ahead finish, make
+/// the log durable and write back every dirty page
+pub(crate) fn shut_down(pool: &BufferPool) -> Result<(), StorageError> {
+    let writers = std::mem::take(&mut pool.write().writers);
+    for writer in writers {
+        writer.stop();
+    }
+    pool.wait_for_prefetch();
+    let log = pool.read().log.clone();
+    if let Some(log) = log {
+        log.flush()?;
+    }
+    pool.flush_all()
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Write the given resident pages to disk and clear their dirty bits. Each page is pinned under the pool latch, so it can't be
+/// evicted or deleted while it's written back, and the pool latch is then released: the pages are copied under their
+/// frames' read latches and written concurrently on the rayon pool (see `DiskApi::write_pages_concurrent`) while the pool
+/// keeps serving other callers, and only taken again to unpin them. The data file is synced once at the end, so flushed
+/// pages are durable whatever the disk manager's sync policy. Returns how many pages were written (pages that aren't
+/// resident are skipped). If a write or the sync fails the pages stay dirty.
+///
+/// Write-backs run one at a time. A page whose dirty bit another write-back has taken isn't dirty anymore, but it's only
+/// durable once that write-back is done, so waiting for it is what lets a flush promise that every page dirty when it was
+/// called is on disk by the time it returns (which checkpoints rely on), even if it had nothing left to write itself.
+fn write_back(pool: &BufferPool, page_ids: &[PageId]) -> Result<usize, StorageError> {
+    let flushing = pool.read().flushing.clone();
+    let _flushing = flushing.lock();
+    let (frames, mgr, log, temp) = {
+        let mut inner = pool.write();
+        // a page still being read has nothing to write back, and its frame doesn't hold it yet. The dirty bit is taken here,
+        // under the latch `unpin` sets it under and before the page is copied, so a modification made after this re-dirties
+        // the frame instead of being lost
+        let frames: Vec<(PageId, BufferPoolFrame, bool)> = page_ids
+            .iter()
+            .filter_map(|page_id| {
+                let frame_id = inner.page_table.get(page_id)?;
+                if inner.frame(frame_id).is_loading() {
+                    return None;
+                }
+                let frame = inner.pin(frame_id);
+                let dirty = frame.dirty.swap(false, Ordering::AcqRel);
+                Some((*page_id, frame, dirty))
+            })
+            .collect();
+        let temp: HashMap<PageId, TempFile> = frames
+            .iter()
+            .filter(|(page_id, _, _)| is_temp_page(*page_id))
+            .filter_map(|(page_id, _, _)| Some((*page_id, inner.temp.handle(*page_id)?)))
+            .collect();
+        BufCounters::bump(&inner.counters.writes_in_flight, frames.len());
+        (frames, inner.mgr.clone(), inner.log.clone(), temp)
+    };
+    if frames.is_empty() {
+        return Ok(0);
+    }
+    let images: Vec<(PageId, Page, bool)> = frames
+        .par_iter()
+        .map(|(page_id, frame, dirty)| (*page_id, frame.data(), *dirty))
+        .collect();
+    let dirty = images.iter().filter(|(_, _, dirty)| *dirty).count();
+    let written = write_images(&mgr, log.as_ref(), &temp, &images);
+    let mut inner = pool.write();
+    inner
+        .counters
+        .writes_in_flight
+        .fetch_sub(images.len(), Ordering::Relaxed);
+    for (page_id, _, dirty) in images.iter() {
+        inner.unpin(*page_id, *dirty && written.is_err());
+    }
+    written?;
+    BufCounters::bump(&inner.counters.write_backs, dirty);
+    trace_event!(pages = images.len(), dirty, "flush");
+    Ok(images.len())
+}
+
+/// Write page images back to disk concurrently and sync them, flushing the log up to the newest page LSN first. Temp pages
+/// are written to their temp files (see `TempPageAllocator`), which are neither logged nor synced
+fn write_images(
+    mgr: &SharedDisk,
+    log: Option<&LogManager>,
+    temp: &HashMap<PageId, TempFile>,
+    images: &[(PageId, Page, bool)],
+) -> Result<(), StorageError> {
+    for (page_id, image, _) in images
+        .iter()
+        .filter(|(page_id, _, _)| is_temp_page(*page_id))
+    {
+        write_temp_page(temp.get(page_id), image, *page_id)?;
+    }
+    let images: Vec<&(PageId, Page, bool)> = images
+        .iter()
+        .filter(|(page_id, _, _)| !is_temp_page(*page_id))
+        .collect();
+    if images.is_empty() {
+        return Ok(());
+    }
+    if let Some(log) = log {
+        let lsn = images.iter().map(|(_, image, _)| page::lsn(image)).max();
+        log.flush_to_lsn(lsn.unwrap_or_default())?;
+    }
+    let batch: Vec<(PageId, &Page)> = images
+        .iter()
+        .map(|(page_id, image, _)| (*page_id, image))
+        .collect();
+    mgr.write_pages_concurrent(&batch)?;
+    // under a lazy sync policy the writes above aren't durable yet. A failed sync leaves the disk manager refusing writes
+    mgr.sync()
+}
+
 #[cfg(test)]
 mod tests {
+    use parking_lot::Mutex;
+    use rayon::ThreadPoolBuilder;
+
     use super::*;
 
     use crate::shared::cwd;
+    use crate::storage::buffer::diskmgr::{self, SyncPolicy};
+    use crate::storage::buffer::freemap;
+    use crate::storage::buffer::io;
+    use crate::storage::buffer::replacer::Replacer;
+    use crate::testing::Song;
 
     #[test]
     fn test_create() {
@@ -145,16 +1718,994 @@ mod tests {
         std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
 
         let path = cwd() + "/tests/bufmgr_tests/test_create_file.bin";
-        let buffer_pool = BufferPool::create(&path);
+        let buffer_pool = BufferPool::create(&path).unwrap();
 
         let inner = buffer_pool.read();
-        let lst = &mut inner.free_list.clone().into_inner();
+        let lst = &mut inner.free_list.lock().clone();
         assert!(lst.len() == BUFFER_POOL_SIZE);
-        assert!(inner.frames.len() == 0);
+        assert!(inner.frames.is_empty());
 
         let x = lst.pop_front().unwrap();
         let y = lst.pop_back().unwrap();
         assert!(x == 1);
         assert!(y == 50);
     }
+
+    #[test]
+    fn test_create_with_config() {
+        let dir = cwd() + "/tests/bufmgr_tests/config";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        let config = StorageConfig::new(&dir)
+            .with_data_file("songs.db")
+            .with_pool_size(3)
+            .with_replacer_k(1)
+            .with_sync_policy(SyncPolicy::OnFlush);
+        let buffer_pool = BufferPool::create_with_config(&config).unwrap();
+        assert!(buffer_pool.read().free_list.lock().len() == 3);
+        assert!(buffer_pool.read().mgr.sync_policy() == SyncPolicy::OnFlush);
+
+        let page_ids: Vec<PageId> = (0..3).map(|_| buffer_pool.new_page().unwrap().0).collect();
+        assert!(matches!(
+            buffer_pool.new_page(),
+            Err(StorageError::PoolExhausted)
+        ));
+        for page_id in page_ids {
+            assert!(buffer_pool.unpin_page(page_id, true));
+        }
+        buffer_pool.flush_all().unwrap();
+        drop(buffer_pool);
+
+        // the data file is where the config put it, and opening it with the same config keeps its pages
+        assert!(std::path::Path::new(&(dir.clone() + "/songs.db")).exists());
+        let buffer_pool = BufferPool::open_with_config(&config).unwrap();
+        assert!(buffer_pool.alloc_page().unwrap() == 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn setup(name: &str) -> (BufferPool, String) {
+        let dir = cwd() + "/tests/bufmgr_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        let path = format!("{}/{}.bin", dir, name);
+        (BufferPool::create(&path).unwrap(), path)
+    }
+
+    fn cleanup(path: &str) {
+        std::fs::remove_file(std::path::Path::new(path)).unwrap();
+        let _ = std::fs::remove_file(freemap::map_path(path));
+    }
+
+    #[test]
+    fn test_open_keeps_pages() {
+        let (buffer_pool, path) = setup("test_open_keeps_pages");
+        let mut page_ids = Vec::new();
+        for i in 0..3 {
+            let mut guard = buffer_pool.new_page_write().unwrap();
+            let song = Song::new(i, "Sweater Weather", "The Neighbourhood");
+            *guard.data_mut() = io::to_buffer(song).unwrap();
+            page_ids.push(guard.page_id());
+        }
+        buffer_pool.flush_all().unwrap();
+        drop(buffer_pool);
+
+        let buffer_pool = BufferPool::open(&path).unwrap();
+        for (i, page_id) in page_ids.iter().enumerate() {
+            let guard = buffer_pool.fetch_page_read(*page_id).unwrap();
+            assert!(io::from_buffer::<Song>(guard.data()).unwrap().id == i as i32);
+        }
+        // new pages go after the existing ones
+        assert!(buffer_pool.alloc_page().unwrap() == 3);
+        drop(buffer_pool);
+
+        // whereas create starts over
+        let buffer_pool = BufferPool::create(&path).unwrap();
+        assert!(buffer_pool.alloc_page().unwrap() == 0);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_background_writer() {
+        let (buffer_pool, path) = setup("test_background_writer");
+        let mut page_ids = Vec::new();
+        for i in 0..4 {
+            let (page_id, frame) = buffer_pool.new_page().unwrap();
+            *frame.write().page_mut() =
+                io::to_buffer(Song::new(i, "Flawless", "The Neighbourhood")).unwrap();
+            page_ids.push((page_id, frame));
+        }
+        // all but the last page are unpinned, so only those get written
+        for (page_id, _) in page_ids.iter().take(3) {
+            assert!(buffer_pool.unpin_page(*page_id, true));
+        }
+        page_ids[3].1.dirty.store(true, Ordering::Release);
+
+        let writer = buffer_pool.start_background_writer(Duration::from_millis(5));
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while page_ids[..3].iter().any(|(_, frame)| frame.is_dirty()) {
+            assert!(std::time::Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        writer.stop();
+        assert!(page_ids[3].1.is_dirty());
+
+        let mut buf = page::empty();
+        buffer_pool
+            .read()
+            .mgr
+            .read_page(&mut buf, page_ids[2].0 as u64)
+            .unwrap();
+        assert!(io::from_buffer::<Song>(&buf).unwrap().id == 2);
+        assert!(buffer_pool.flush_unpinned().unwrap() == 0);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_close_and_drop() {
+        let (buffer_pool, path) = setup("test_close_and_drop");
+        for i in 0..4 {
+            let mut guard = buffer_pool.new_page_write().unwrap();
+            *guard.data_mut() = io::to_buffer(Song::new(i, "Afraid", "The Neighbourhood")).unwrap();
+        }
+        let writer = buffer_pool.start_background_writer(Duration::from_secs(60));
+        // the writer's first pass runs right away: wait for it to let go of the pool, so that the drop below is the last one.
+        // The next pass is a minute away
+        while buffer_pool.dirty_count() != 0 || Arc::strong_count(&buffer_pool) != 1 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let mut guard = buffer_pool.fetch_page_write(3).unwrap();
+        *guard.data_mut() = io::to_buffer(Song::new(6, "Afraid", "The Neighbourhood")).unwrap();
+        drop(guard);
+        // dropping the pool stops its writer and writes back the dirty pages, but doesn't vouch for a clean shutdown
+        drop(buffer_pool);
+        assert!(!writer.is_running());
+        let buffer_pool = BufferPool::open(&path).unwrap();
+        assert!(!buffer_pool.read().mgr.clean_shutdown());
+        let guard = buffer_pool.fetch_page_read(3).unwrap();
+        assert!(io::from_buffer::<Song>(guard.data()).unwrap().id == 6);
+        drop(guard);
+
+        let mut guard = buffer_pool.fetch_page_write(1).unwrap();
+        *guard.data_mut() = io::to_buffer(Song::new(5, "Softcore", "The Neighbourhood")).unwrap();
+        drop(guard);
+        buffer_pool.close().unwrap();
+        assert!(buffer_pool.dirty_count() == 0);
+        assert!(std::path::Path::new(&diskmgr::marker_path(&path)).exists());
+        drop(buffer_pool);
+
+        // the marker is taken back on open, and the file no longer counts as clean once it's written to
+        let mgr = DiskMgr::open(&path).unwrap();
+        assert!(mgr.clean_shutdown());
+        assert!(!std::path::Path::new(&diskmgr::marker_path(&path)).exists());
+        let mut buf = page::empty();
+        mgr.read_page(&mut buf, 1).unwrap();
+        assert!(io::from_buffer::<Song>(&buf).unwrap().id == 5);
+        mgr.write_page(&buf, 2).unwrap();
+        assert!(!mgr.clean_shutdown());
+        cleanup(&path);
+    }
+
+    fn is_resident(buffer_pool: &BufferPool, page_id: PageId) -> bool {
+        buffer_pool.read().page_table.contains_key(&page_id)
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let (buffer_pool, path) = setup("test_prefetch");
+        for i in 0..40 {
+            let mut guard = buffer_pool.new_page_write().unwrap();
+            *guard.data_mut() =
+                io::to_buffer(Song::new(i, "Scary Love", "The Neighbourhood")).unwrap();
+        }
+        buffer_pool.flush_all().unwrap();
+        drop(buffer_pool);
+
+        let buffer_pool = BufferPool::open(&path).unwrap();
+        buffer_pool.prefetch(5, 10);
+        buffer_pool.wait_for_prefetch();
+        assert!((5..15).all(|page_id| is_resident(&buffer_pool, page_id)));
+        let frame = buffer_pool.fetch_page(9).unwrap();
+        assert!(frame.pin_count() == 1);
+        assert!(io::from_buffer::<Song>(frame.read().page()).unwrap().id == 9);
+        assert!(buffer_pool.unpin_page(9, false));
+
+        // a sequential scan gets read ahead of once it's recognised, and only then
+        buffer_pool.set_read_ahead(8);
+        for page_id in 20..20 + SEQUENTIAL_THRESHOLD as PageId {
+            assert!(buffer_pool.fetch_page(page_id).is_ok());
+            assert!(buffer_pool.unpin_page(page_id, false));
+        }
+        buffer_pool.wait_for_prefetch();
+        assert!((24..32).all(|page_id| is_resident(&buffer_pool, page_id)));
+        assert!(!is_resident(&buffer_pool, 32));
+
+        // nothing past the end of the file
+        buffer_pool.prefetch(38, 5);
+        buffer_pool.wait_for_prefetch();
+        assert!((38..40).all(|page_id| is_resident(&buffer_pool, page_id)));
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_fetch_corrupted_page() {
+        let (buffer_pool, path) = setup("test_fetch_corrupted_page");
+        let (page_id, frame) = buffer_pool.new_page().unwrap();
+        frame.write().page_mut()[PAGE_SIZE - 1] = 1;
+        buffer_pool.unpin_page(page_id, true);
+        buffer_pool.flush_all().unwrap();
+        assert!(buffer_pool.delete_page(page_id).is_ok());
+
+        // flip the byte back on disk, leaving the checksum stale
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        let offset = (page_id as usize * PAGE_SIZE + PAGE_SIZE - 1) as u64;
+        std::os::unix::fs::FileExt::write_all_at(&file, &[0u8], offset).unwrap();
+        match buffer_pool.fetch_page(page_id) {
+            Err(StorageError::Corruption(corrupted)) => assert!(corrupted == page_id),
+            _ => panic!("expected a corruption error"),
+        }
+        assert!(buffer_pool.fetch_page(page_id).is_err());
+        assert!(matches!(
+            buffer_pool.fetch_page(page_id + 1),
+            Err(StorageError::PageNotFound(_))
+        ));
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_evict_and_refetch() {
+        let (buffer_pool, path) = setup("test_evict_and_refetch");
+
+        let mut page_ids = Vec::new();
+        for i in 0..BUFFER_POOL_SIZE * 2 {
+            let (page_id, frame) = buffer_pool.new_page().unwrap();
+            let song = Song::new(i as i32, "Sweater Weather", "The Neighbourhood");
+            *frame.write().page_mut() = io::to_buffer(song).unwrap();
+            assert!(buffer_pool.unpin_page(page_id, true));
+            page_ids.push(page_id);
+        }
+        assert!(buffer_pool.size() == BUFFER_POOL_SIZE);
+
+        // the first half of the pages were evicted (and written back) to make room for the second half
+        for (i, page_id) in page_ids.iter().enumerate() {
+            let frame = buffer_pool.fetch_page(*page_id).unwrap();
+            let song: Song = io::from_buffer(frame.read().page()).unwrap();
+            assert!(song.id == i as i32);
+            assert!(buffer_pool.unpin_page(*page_id, false));
+        }
+        // every refetch missed, since the second half was evicted in turn to bring the first half back (only those pages were
+        // dirty), and the last page is still resident
+        assert!(buffer_pool.fetch_page(page_ids[99]).is_ok());
+        let stats = buffer_pool.stats();
+        assert!(stats.hits == 1 && stats.misses == 100 && stats.hit_ratio() == 1.0 / 101.0);
+        assert!(stats.evictions == 150 && stats.write_backs == 100 && stats.pinned == 1);
+        // each page written once when it was allocated and once when it was evicted
+        assert!(stats.disk.writes == 200);
+        assert!(buffer_pool.unpin_page(page_ids[99], false));
+
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_pinned_frames_are_not_evicted() {
+        let (buffer_pool, path) = setup("test_pinned_frames_are_not_evicted");
+
+        let mut page_ids = Vec::new();
+        for _ in 0..BUFFER_POOL_SIZE {
+            let (page_id, _) = buffer_pool.new_page().unwrap();
+            page_ids.push(page_id);
+        }
+        let bus = EventBus::create();
+        let events = bus.subscribe();
+        buffer_pool.set_event_bus(bus);
+        assert!(buffer_pool.new_page().is_err());
+        assert!(
+            events.try_recv().unwrap()
+                == StorageEvent::EvictionPressure {
+                    frames: BUFFER_POOL_SIZE
+                }
+        );
+        let extra = buffer_pool.alloc_page().unwrap();
+        assert!(buffer_pool.fetch_page(extra).is_err());
+
+        // pages can be pinned more than once; they only become evictable once every pin is dropped
+        assert!(buffer_pool.fetch_page(page_ids[0]).is_ok());
+        assert!(buffer_pool.unpin_page(page_ids[0], false));
+        assert!(buffer_pool.fetch_page(extra).is_err());
+        assert!(matches!(
+            buffer_pool.delete_page(page_ids[0]),
+            Err(StorageError::PagePinned(_))
+        ));
+
+        assert!(buffer_pool.unpin_page(page_ids[0], false));
+        assert!(!buffer_pool.unpin_page(page_ids[0], false));
+        let frame = buffer_pool.fetch_page(extra).unwrap();
+        assert!(frame.page_id() == extra);
+        assert!(frame.pin_count() == 1);
+
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_flush_and_delete() {
+        let (buffer_pool, path) = setup("test_flush_and_delete");
+
+        let (page_id, frame) = buffer_pool.new_page().unwrap();
+        let song = Song::new(7, "Cry Baby", "The Neighbourhood");
+        *frame.write().page_mut() = io::to_buffer(song).unwrap();
+        assert!(buffer_pool.unpin_page(page_id, true));
+        assert!(frame.is_dirty());
+        assert!(buffer_pool.flush_page(page_id).is_ok());
+        assert!(!frame.is_dirty());
+        // writing the page again doesn't count as a write-back, since it's clean
+        assert!(buffer_pool.flush_page(page_id).is_ok());
+        assert!(buffer_pool.stats().write_backs == 1);
+
+        let mut buf = page::empty();
+        buffer_pool
+            .read()
+            .mgr
+            .read_page(&mut buf, page_id as u64)
+            .unwrap();
+        let on_disk: Song = io::from_buffer(&buf).unwrap();
+        assert!(on_disk.id == 7);
+
+        assert!(buffer_pool.delete_page(page_id).is_ok());
+        assert!(buffer_pool.read().free_list.lock().len() == BUFFER_POOL_SIZE);
+        assert!(buffer_pool.flush_page(page_id).is_err());
+        // deleting it again does nothing, and a page past the end of the file can't be deleted
+        assert!(buffer_pool.delete_page(page_id).is_ok());
+        assert!(matches!(
+            buffer_pool.delete_page(page_id + 100),
+            Err(StorageError::PageNotFound(_))
+        ));
+
+        // the deleted page is the next one handed out
+        assert!(buffer_pool.alloc_page().unwrap() == page_id);
+        assert!(buffer_pool.alloc_page().unwrap() == page_id + 1);
+        assert!(buffer_pool.delete_page(page_id + 1).is_ok());
+        let (reused, _) = buffer_pool.new_page().unwrap();
+        assert!(reused == page_id + 1);
+        assert!(buffer_pool.unpin_page(reused, false));
+
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_concurrent_fetch() {
+        let (buffer_pool, path) = setup("test_concurrent_fetch");
+
+        let mut page_ids = Vec::new();
+        for i in 0..BUFFER_POOL_SIZE * 2 {
+            let (page_id, frame) = buffer_pool.new_page().unwrap();
+            let song = Song::new(i as i32, "Softcore", "The Neighbourhood");
+            *frame.write().page_mut() = io::to_buffer(song).unwrap();
+            buffer_pool.unpin_page(page_id, true);
+            page_ids.push(page_id);
+        }
+
+        let pool = ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        pool.scope(|s| {
+            for t in 0..8 {
+                let buffer_pool = buffer_pool.clone();
+                let page_ids = &page_ids;
+                s.spawn(move |_| {
+                    for round in 0..50 {
+                        let i = (t * 13 + round * 7) % page_ids.len();
+                        let frame = buffer_pool.fetch_page(page_ids[i]).unwrap();
+                        let song: Song = io::from_buffer(frame.read().page()).unwrap();
+                        assert!(song.id == i as i32);
+                        assert!(frame.page_id() == page_ids[i]);
+                        assert!(buffer_pool.unpin_page(page_ids[i], false));
+                    }
+                });
+            }
+        });
+
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_concurrent_misses_read_once() {
+        let (buffer_pool, path) = setup("test_concurrent_misses_read_once");
+        let page_ids: Vec<PageId> = (0..BUFFER_POOL_SIZE / 2)
+            .map(|_| buffer_pool.alloc_page().unwrap())
+            .collect();
+
+        // every thread misses on the same pages at about the same time
+        let pool = ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        pool.scope(|s| {
+            for _ in 0..8 {
+                let buffer_pool = buffer_pool.clone();
+                let page_ids = &page_ids;
+                s.spawn(move |_| {
+                    for page_id in page_ids.iter() {
+                        let frame = buffer_pool.fetch_page(*page_id).unwrap();
+                        assert!(frame.page_id() == *page_id);
+                        assert!(page::page_id(frame.read().page()) == *page_id);
+                        assert!(buffer_pool.unpin_page(*page_id, false));
+                    }
+                });
+            }
+        });
+        // each page was read once, into a single frame, and the other fetches shared it
+        let stats = buffer_pool.stats();
+        assert!(stats.misses == page_ids.len());
+        assert!(stats.hits == page_ids.len() * 7);
+        assert!(stats.pinned == 0);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_in_flight_reads_wake_waiting_fetches() {
+        let (buffer_pool, path) = setup("test_in_flight_reads");
+        let page_id = buffer_pool.alloc_page().unwrap();
+        buffer_pool.close().unwrap();
+        drop(buffer_pool);
+
+        let buffer_pool = BufferPool::open(&path).unwrap();
+        let Prefetch::Read(read) = reserve_prefetch(&buffer_pool, page_id) else {
+            panic!("page {} should have needed a read", page_id);
+        };
+        assert!(buffer_pool.stats().reads_in_flight == 1);
+        std::thread::scope(|s| {
+            // the fetch finds the page loading and sleeps until the read completes
+            let fetch = s.spawn(|| {
+                let frame = buffer_pool.fetch_page(page_id).unwrap();
+                assert!(page::page_id(frame.read().page()) == page_id);
+                assert!(buffer_pool.unpin_page(page_id, false));
+            });
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(read.read(&buffer_pool));
+            fetch.join().unwrap();
+        });
+        let stats = buffer_pool.stats();
+        assert!(stats.reads_in_flight == 0 && stats.writes_in_flight == 0);
+        assert!(stats.misses == 0 && stats.hits == 1);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_flush_all_writes_concurrently_and_syncs_once() {
+        let (buffer_pool, path) = setup("test_flush_all_concurrent");
+        let page_ids: Vec<PageId> = (0..BUFFER_POOL_SIZE)
+            .map(|_| buffer_pool.alloc_page().unwrap())
+            .collect();
+        // every other page is dirtied, so each one is its own run
+        for (i, page_id) in page_ids.iter().enumerate().step_by(2) {
+            let mut guard = buffer_pool.fetch_page_write(*page_id).unwrap();
+            let song = Song::new(i as i32, "Daddy Issues", "The Neighbourhood");
+            *guard.data_mut() = io::to_buffer(song).unwrap();
+        }
+        let before = buffer_pool.stats();
+
+        // the pool stays usable while the flush runs
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        pool.scope(|s| {
+            s.spawn(|_| buffer_pool.flush_all().unwrap());
+            for page_id in page_ids.iter().skip(1).step_by(2) {
+                let buffer_pool = buffer_pool.clone();
+                s.spawn(move |_| {
+                    let guard = buffer_pool.fetch_page_read(*page_id).unwrap();
+                    assert!(guard.page_id() == *page_id);
+                });
+            }
+        });
+
+        let after = buffer_pool.stats();
+        assert!(buffer_pool.dirty_count() == 0);
+        assert!(after.write_backs - before.write_backs == BUFFER_POOL_SIZE / 2);
+        assert!(after.disk.writes - before.disk.writes == BUFFER_POOL_SIZE / 2);
+        assert!(after.disk.flushes - before.disk.flushes == 1);
+        let mut buf = page::empty();
+        for (i, page_id) in page_ids.iter().enumerate().step_by(2) {
+            buffer_pool
+                .read()
+                .mgr
+                .read_page(&mut buf, *page_id as u64)
+                .unwrap();
+            assert!(io::from_buffer::<Song>(&buf).unwrap().id == i as i32);
+        }
+
+        cleanup(&path);
+    }
+
+    /// Evicts the most recently used frame. Only used to check that the pool defers to whatever replacer it's given
+    struct MruReplacer {
+        order: Synchronized<Vec<(FrameId, bool)>>,
+    }
+
+    impl Replacer for MruReplacer {
+        fn evict(&self) -> Option<FrameId> {
+            let mut order = self.order.lock();
+            let pos = order.iter().rposition(|(_, evictable)| *evictable)?;
+            Some(order.remove(pos).0)
+        }
+
+        fn record_access(&self, frame_id: FrameId) {
+            let mut order = self.order.lock();
+            let evictable = match order.iter().position(|(id, _)| *id == frame_id) {
+                Some(pos) => order.remove(pos).1,
+                None => false,
+            };
+            order.push((frame_id, evictable));
+        }
+
+        fn set_evictable(&self, frame_id: FrameId, evictable: bool) {
+            let mut order = self.order.lock();
+            if let Some(entry) = order.iter_mut().find(|(id, _)| *id == frame_id) {
+                entry.1 = evictable;
+            }
+        }
+
+        fn remove(&self, frame_id: FrameId) {
+            self.order.lock().retain(|(id, _)| *id != frame_id);
+        }
+
+        fn size(&self) -> usize {
+            self.order.lock().iter().filter(|(_, e)| *e).count()
+        }
+
+        fn resize(&self, _num_frames: usize) {}
+    }
+
+    #[test]
+    fn test_create_with_replacer() {
+        let dir = cwd() + "/tests/bufmgr_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        let path = dir + "/test_create_with_replacer.bin";
+        let replacer = MruReplacer {
+            order: Synchronized::init(Vec::new()),
+        };
+        let buffer_pool = BufferPool::create_with_replacer(&path, Box::new(replacer)).unwrap();
+
+        let mut frame_ids = Vec::new();
+        for _ in 0..BUFFER_POOL_SIZE {
+            let (page_id, frame) = buffer_pool.new_page().unwrap();
+            frame_ids.push(frame.id());
+            buffer_pool.unpin_page(page_id, false);
+        }
+        // the most recently used frame is the one that gets reused
+        let (_, frame) = buffer_pool.new_page().unwrap();
+        assert!(frame.id() == frame_ids[BUFFER_POOL_SIZE - 1]);
+
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_page_guards() {
+        let (buffer_pool, path) = setup("test_page_guards");
+
+        let (page_id, _) = buffer_pool.new_page().unwrap();
+        buffer_pool.unpin_page(page_id, false);
+
+        {
+            let mut guard = buffer_pool.fetch_page_write(page_id).unwrap();
+            let song = Song::new(3, "Daddy Issues", "The Neighbourhood");
+            *guard = io::to_buffer(song).unwrap();
+            assert!(guard.page_id() == page_id);
+        }
+        let frame = buffer_pool.fetch_page(page_id).unwrap();
+        assert!(frame.is_dirty());
+        assert!(frame.pin_count() == 1);
+        buffer_pool.unpin_page(page_id, false);
+        assert!(buffer_pool.flush_page(page_id).is_ok());
+
+        // read-only access through a write guard doesn't dirty the page
+        {
+            let guard = buffer_pool.fetch_page_write(page_id).unwrap();
+            let song: Song = io::from_buffer(guard.data()).unwrap();
+            assert!(song.id == 3);
+        }
+        assert!(!frame.is_dirty());
+
+        // any number of readers can hold the page at once
+        let first = buffer_pool.fetch_page_read(page_id).unwrap();
+        let second = buffer_pool.fetch_page_read(page_id).unwrap();
+        assert!(frame.pin_count() == 2);
+        assert!(first[..] == second[..]);
+        drop(first);
+        drop(second);
+        assert!(frame.pin_count() == 0);
+        assert!(buffer_pool.delete_page(page_id).is_ok());
+
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_concurrent_page_guards() {
+        let (buffer_pool, path) = setup("test_concurrent_page_guards");
+        let (page_id, _) = buffer_pool.new_page().unwrap();
+        buffer_pool.unpin_page(page_id, false);
+
+        let pool = ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        pool.scope(|s| {
+            for _ in 0..8 {
+                let buffer_pool = buffer_pool.clone();
+                s.spawn(move |_| {
+                    for _ in 0..100 {
+                        let mut guard = buffer_pool.fetch_page_write(page_id).unwrap();
+                        let count = u32::from_le_bytes(guard[..4].try_into().unwrap());
+                        guard[..4].copy_from_slice(&(count + 1).to_le_bytes());
+                        drop(guard);
+                        let guard = buffer_pool.fetch_page_read(page_id).unwrap();
+                        assert!(u32::from_le_bytes(guard[..4].try_into().unwrap()) > 0);
+                    }
+                });
+            }
+        });
+
+        let guard = buffer_pool.fetch_page_read(page_id).unwrap();
+        assert!(u32::from_le_bytes(guard[..4].try_into().unwrap()) == 800);
+        drop(guard);
+
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_optimistic_guards() {
+        let (buffer_pool, path) = setup("test_optimistic_guards");
+        let (page_id, frame) = buffer_pool.new_page().unwrap();
+        buffer_pool.unpin_page(page_id, false);
+
+        let optimistic = buffer_pool.fetch_page_optimistic(page_id).unwrap();
+        assert!(optimistic.copy().is_some() && frame.pin_count() == 1);
+        // a read guard leaves the version alone, releasing a write guard moves it on
+        drop(buffer_pool.fetch_page_read(page_id).unwrap());
+        assert!(optimistic.validate());
+        buffer_pool.fetch_page_write(page_id).unwrap()[0] = 1;
+        assert!(!optimistic.validate() && optimistic.copy().is_none());
+        assert!(optimistic.upgrade().is_none());
+        assert!(frame.pin_count() == 0);
+        let version = frame.version();
+        drop(buffer_pool.fetch_page_write(page_id).unwrap());
+        assert!(frame.version() == version + 2);
+
+        // resident pages can be copied without a pin at all
+        let hits = buffer_pool.stats().hits;
+        assert!(buffer_pool.read_page_optimistic(page_id).unwrap()[0] == 1);
+        assert!(buffer_pool.stats().hits == hits + 1 && frame.pin_count() == 0);
+        let guard = buffer_pool.fetch_page_write(page_id).unwrap();
+        assert!(buffer_pool.read_page_optimistic(page_id).is_some());
+        let mut guard = guard;
+        guard[2] = 1;
+        assert!(buffer_pool.read_page_optimistic(page_id).is_none());
+        drop(guard);
+        assert!(buffer_pool.read_page_optimistic(page_id + 100).is_none());
+
+        // an unchanged page upgrades to a write guard, keeping the pin
+        let optimistic = buffer_pool.fetch_page_optimistic(page_id).unwrap();
+        let mut guard = optimistic.upgrade().unwrap();
+        guard[1] = 1;
+        assert!(frame.pin_count() == 1 && frame.version() % 2 == 1);
+        drop(guard);
+        assert!(frame.pin_count() == 0 && frame.is_dirty());
+
+        // writers keep the first two counters equal, so a validated copy never sees them differ
+        buffer_pool.fetch_page_write(page_id).unwrap()[..8].fill(0);
+        let pool = ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        pool.scope(|s| {
+            for t in 0..8 {
+                let buffer_pool = buffer_pool.clone();
+                s.spawn(move |_| {
+                    for _ in 0..200 {
+                        if t % 2 == 0 {
+                            let mut guard = buffer_pool.fetch_page_write(page_id).unwrap();
+                            let count = u32::from_le_bytes(guard[..4].try_into().unwrap());
+                            guard[..4].copy_from_slice(&(count + 1).to_le_bytes());
+                            guard[4..8].copy_from_slice(&(count + 1).to_le_bytes());
+                        } else if t % 4 == 1 {
+                            let guard = buffer_pool.fetch_page_optimistic(page_id).unwrap();
+                            if let Some(page) = guard.copy() {
+                                assert!(page[..4] == page[4..8]);
+                            }
+                        } else if let Some(page) = buffer_pool.read_page_optimistic(page_id) {
+                            assert!(page[..4] == page[4..8]);
+                        }
+                    }
+                });
+            }
+        });
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_resize() {
+        let (buffer_pool, path) = setup("test_resize");
+        let page_ids: Vec<PageId> = (0..BUFFER_POOL_SIZE)
+            .map(|i| {
+                let mut guard = buffer_pool.new_page_write().unwrap();
+                guard[0] = i as u8;
+                guard.page_id()
+            })
+            .collect();
+        assert!(buffer_pool.size() == BUFFER_POOL_SIZE);
+
+        // frames are given up from the top, and the pinned page in frame 3 stops the shrink there
+        let pinned = buffer_pool.fetch_page(page_ids[2]).unwrap();
+        assert!(pinned.id() == 3);
+        let write_backs = buffer_pool.stats().write_backs;
+        assert!(buffer_pool.resize(1) == BUFFER_POOL_SIZE - 3);
+        assert!(buffer_pool.size() == 3);
+        assert!(buffer_pool.stats().write_backs == write_backs + BUFFER_POOL_SIZE - 3);
+        // the evicted pages come back from disk through the frames that are left
+        for (i, page_id) in page_ids.iter().enumerate().skip(3) {
+            assert!(buffer_pool.fetch_page_read(*page_id).unwrap()[0] == i as u8);
+        }
+        let held: Vec<_> = page_ids[..2]
+            .iter()
+            .map(|page_id| buffer_pool.fetch_page_read(*page_id).unwrap())
+            .collect();
+        assert!(matches!(
+            buffer_pool.new_page(),
+            Err(StorageError::PoolExhausted)
+        ));
+        drop(held);
+        drop(pinned);
+        buffer_pool.unpin_page(page_ids[2], false);
+        assert!(buffer_pool.resize(1) == 2 && buffer_pool.size() == 1);
+
+        // growing hands out the new frames as they're needed
+        assert!(buffer_pool.resize(BUFFER_POOL_SIZE * 2) == 0);
+        let guards: Vec<_> = page_ids
+            .iter()
+            .map(|page_id| buffer_pool.fetch_page_read(*page_id).unwrap())
+            .chain((0..BUFFER_POOL_SIZE).map(|_| {
+                let guard = buffer_pool.new_page_write().unwrap();
+                let page_id = guard.page_id();
+                drop(guard);
+                buffer_pool.fetch_page_read(page_id).unwrap()
+            }))
+            .collect();
+        assert!(buffer_pool.size() == BUFFER_POOL_SIZE * 2);
+        assert!(matches!(
+            buffer_pool.new_page(),
+            Err(StorageError::PoolExhausted)
+        ));
+        drop(guards);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_pin_resident_and_warm_up() {
+        let (buffer_pool, path) = setup("test_pin_resident_and_warm_up");
+        let page_ids: Vec<PageId> = (0..BUFFER_POOL_SIZE * 2)
+            .map(|i| {
+                let mut guard = buffer_pool.new_page_write().unwrap();
+                guard[0] = i as u8;
+                guard.page_id()
+            })
+            .collect();
+
+        // the root survives any number of scans over the rest
+        let root = page_ids[0];
+        buffer_pool.pin_resident(root).unwrap();
+        buffer_pool.pin_resident(root).unwrap();
+        for _ in 0..3 {
+            for page_id in page_ids[1..].iter() {
+                drop(buffer_pool.fetch_page_read(*page_id).unwrap());
+            }
+            assert!(buffer_pool.read().is_resident(root));
+        }
+        let frame = buffer_pool.fetch_page(root).unwrap();
+        assert!(frame.pin_count() == 2);
+        buffer_pool.unpin_page(root, false);
+        assert!(matches!(
+            buffer_pool.delete_page(root),
+            Err(StorageError::PagePinned(_))
+        ));
+        assert!(buffer_pool.unpin_resident(root) && !buffer_pool.unpin_resident(root));
+        assert!(frame.pin_count() == 0);
+        buffer_pool.flush_all().unwrap();
+        drop(buffer_pool);
+
+        // a fresh pool is warmed up with as many pages as it has frames, skipping ones that don't exist
+        let buffer_pool = BufferPool::open(&path).unwrap();
+        let mut wanted = vec![page_ids.last().unwrap() + 100];
+        wanted.extend_from_slice(&page_ids);
+        assert!(buffer_pool.warm_up(&wanted) == BUFFER_POOL_SIZE);
+        let misses = buffer_pool.stats().misses;
+        for (i, page_id) in page_ids.iter().enumerate().take(BUFFER_POOL_SIZE) {
+            assert!(buffer_pool.fetch_page_read(*page_id).unwrap()[0] == i as u8);
+        }
+        assert!(buffer_pool.stats().misses == misses);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_wal_before_data() {
+        use crate::shared::INVALID_LSN;
+        use crate::storage::log::logmgr::LogApi;
+        use crate::storage::log::record::LogBody;
+
+        let (buffer_pool, path) = setup("test_wal_before_data");
+        let log_path = format!("{}.log", path);
+        let log = LogManager::create(&log_path).unwrap();
+        buffer_pool.set_log_manager(log.clone());
+
+        let (page_id, frame) = buffer_pool.new_page().unwrap();
+        let begin = log.append(1, INVALID_LSN, LogBody::Begin).unwrap();
+        let update = log
+            .append(
+                1,
+                begin,
+                LogBody::Update {
+                    page_id,
+                    offset: 8,
+                    before: vec![0],
+                    after: vec![1],
+                },
+            )
+            .unwrap();
+        let mut data = frame.write();
+        data.page_mut()[8] = 1;
+        page::set_lsn(data.page_mut(), update);
+        drop(data);
+        buffer_pool.unpin_page(page_id, true);
+        assert!(log.persistent_lsn() == INVALID_LSN);
+
+        // evicting the page forces the log out first
+        for _ in 0..BUFFER_POOL_SIZE {
+            let (new_page_id, _) = buffer_pool.new_page().unwrap();
+            buffer_pool.unpin_page(new_page_id, false);
+        }
+        assert!(buffer_pool.read().page_table.get(&page_id).is_none());
+        assert!(log.persistent_lsn() >= update);
+
+        // and so does an explicit flush
+        let commit = log.append(1, update, LogBody::Commit).unwrap();
+        let mut guard = buffer_pool.fetch_page_write(page_id).unwrap();
+        assert!(page::lsn(&guard) == update);
+        page::set_lsn(&mut guard, commit);
+        drop(guard);
+        assert!(buffer_pool.flush_page(page_id).is_ok());
+        assert!(log.persistent_lsn() >= commit);
+
+        cleanup(&path);
+        cleanup(&log_path);
+    }
+
+    #[test]
+    fn test_pinned_page_memory_is_stable() {
+        let (buffer_pool, path) = setup("test_pinned_page_memory_is_stable");
+        let (page_id, _) = buffer_pool.new_page().unwrap();
+        assert!(buffer_pool.unpin_page(page_id, false));
+
+        let mut pinned = buffer_pool.fetch_page_pinned(page_id).unwrap();
+        let addr = pinned.as_ptr();
+        assert!((addr as usize).is_multiple_of(PAGE_SIZE));
+        // e.g. a direct read landing in the frame
+        unsafe { std::ptr::write_bytes(pinned.as_mut_ptr(), 7, PAGE_SIZE) };
+        pinned.mark_dirty();
+
+        // fill the rest of the pool and churn through it, growing the frame slab along the way
+        for _ in 0..BUFFER_POOL_SIZE * 2 {
+            let (other, _) = buffer_pool.new_page().unwrap();
+            assert!(buffer_pool.unpin_page(other, true));
+        }
+        assert!(buffer_pool.size() == BUFFER_POOL_SIZE);
+        assert!(pinned.as_ptr() == addr);
+        drop(pinned);
+
+        buffer_pool.flush_all().unwrap();
+        let mut buf = page::empty();
+        buffer_pool
+            .read()
+            .mgr
+            .read_page(&mut buf, page_id as u64)
+            .unwrap();
+        // everything but the page id and checksum, which are stamped on the way out
+        let stamped = [
+            page::PAGE_CHECKSUM_OFFSET..page::PAGE_CHECKSUM_OFFSET + page::PAGE_CHECKSUM_SIZE,
+            page::PAGE_CHECKSUM_ALGORITHM_OFFSET..page::PAGE_CHECKSUM_ALGORITHM_OFFSET + 1,
+            page::PAGE_ID_OFFSET..page::PAGE_HEADER_SIZE,
+        ];
+        assert!(buf
+            .iter()
+            .enumerate()
+            .all(|(i, byte)| stamped.iter().any(|field| field.contains(&i)) || *byte == 7));
+        cleanup(&path);
+    }
+
+    /// Three clients allocating and re-reading pages, interleaved by the scheduler. Returns every (page, frame) placement
+    fn simulated_run(name: &str, seed: u64) -> Vec<(PageId, FrameId)> {
+        use crate::sim::Scheduler;
+
+        let (buffer_pool, path) = setup(name);
+        let sim = Simulation::create(seed);
+        buffer_pool.set_simulation(sim.clone());
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let mut scheduler = Scheduler::new(sim.clone());
+        for client in 0..3 {
+            let buffer_pool = buffer_pool.clone();
+            let trace = trace.clone();
+            let sim = sim.clone();
+            let mut page_ids = Vec::new();
+            scheduler.spawn(move || {
+                let page_id = if page_ids.is_empty() || sim.gen_range(0..2) == 0 {
+                    let (page_id, _) = buffer_pool.new_page().unwrap();
+                    page_ids.push(page_id);
+                    page_id
+                } else {
+                    let page_id = page_ids[sim.gen_range(0..page_ids.len())];
+                    buffer_pool.fetch_page(page_id).unwrap();
+                    page_id
+                };
+                let frame = buffer_pool.fetch_page(page_id).unwrap();
+                trace.lock().push((page_id, frame.id()));
+                buffer_pool.unpin_page(page_id, false);
+                buffer_pool.unpin_page(page_id, true);
+                page_ids.len() < 40 + client
+            });
+        }
+        scheduler.run();
+        cleanup(&path);
+        let trace = trace.lock().clone();
+        trace
+    }
+
+    #[test]
+    fn test_deterministic_mode() {
+        let first = simulated_run("test_deterministic_mode_1", 42);
+        assert!(first == simulated_run("test_deterministic_mode_2", 42));
+        assert!(first != simulated_run("test_deterministic_mode_3", 43));
+    }
+
+    /// A fetch of the page that's next in line for eviction races a new page that needs its frame. Whichever wins, the
+    /// fetched frame has to hold the fetched page for as long as it's pinned, and the evicted page has to be written back
+    #[cfg(feature = "loom")]
+    #[test]
+    fn loom_pin_evict_race() {
+        use crate::storage::buffer::diskmgr::{DurabilityMode, SyncPolicy};
+
+        let dir = cwd() + "/tests/bufmgr_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        let path = dir + "/loom_pin_evict_race.bin";
+        let mut model = loom::model::Builder::new();
+        model.preemption_bound = Some(2);
+        let model_path = path.clone();
+        model.check(move || {
+            let mgr = DiskMgr::create_with_sync_policy(
+                &model_path,
+                DurabilityMode::Data,
+                SyncPolicy::Never,
+            )
+            .unwrap();
+            let buffer_pool = with_disk(
+                Arc::new(mgr),
+                Box::new(LRUKReplacer::create(BUFFER_POOL_SIZE, REPLACER_K)),
+                BUFFER_POOL_SIZE,
+            );
+            let page_ids: Vec<PageId> = (0..2)
+                .map(|i| {
+                    let mut guard = buffer_pool.new_page_write().unwrap();
+                    *guard.data_mut() =
+                        io::to_buffer(Song::new(i, "Afraid", "The Neighbourhood")).unwrap();
+                    guard.page_id()
+                })
+                .collect();
+            // two frames, both full, and the first page is the eviction victim
+            buffer_pool.read().free_list.lock().clear();
+
+            let reader = {
+                let buffer_pool = buffer_pool.clone();
+                let page_id = page_ids[0];
+                loom::thread::spawn(move || {
+                    let guard = buffer_pool.fetch_page_read(page_id).unwrap();
+                    assert!(guard.page_id() == page_id);
+                    assert!(io::from_buffer::<Song>(guard.data()).unwrap().id == 0);
+                })
+            };
+            let (new_page, frame) = buffer_pool.new_page().unwrap();
+            assert!(frame.pin_count() == 1 && frame.page_id() == new_page);
+            assert!(buffer_pool.unpin_page(new_page, false));
+            reader.join().unwrap();
+
+            // both old pages are still readable, one of them from disk
+            for (i, page_id) in page_ids.iter().enumerate() {
+                let guard = buffer_pool.fetch_page_read(*page_id).unwrap();
+                assert!(io::from_buffer::<Song>(guard.data()).unwrap().id == i as i32);
+            }
+            assert!(buffer_pool.stats().evictions >= 1);
+        });
+        let _ = std::fs::remove_file(std::path::Path::new(&path));
+        let _ = std::fs::remove_file(freemap::map_path(&path));
+    }
 }
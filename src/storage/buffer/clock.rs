@@ -0,0 +1,193 @@
+/// This file implements the Clock (second-chance) replacement policy. Frames sit on a circular buffer with a reference bit that
+/// is set on every access. To evict, a clock hand sweeps the buffer: an evictable frame with its reference bit set gets a second
+/// chance (the bit is cleared and the hand moves on), and the first evictable frame found with a clear bit is the victim.
+/// Recording an access is O(1) and eviction is amortized O(1).
+use crate::{
+    shared::FrameId,
+    storage::buffer::replacer::Replacer,
+    sync::{Latch as _, Synchronized},
+};
+
+#[derive(Clone, Copy, Default)]
+struct Slot {
+    tracked: bool,
+    evictable: bool,
+    referenced: bool,
+}
+
+pub struct ClockReplacerInternal {
+    // slot i holds frame i + 1
+    slots: Vec<Slot>,
+    hand: usize,
+    num_evictable: usize,
+}
+
+impl ClockReplacerInternal {
+    fn slot(&mut self, frame_id: FrameId) -> &mut Slot {
+        assert!(
+            frame_id >= 1 && frame_id as usize <= self.slots.len(),
+            "invalid frame id {}",
+            frame_id
+        );
+        &mut self.slots[(frame_id - 1) as usize]
+    }
+}
+
+pub type ClockReplacer = Synchronized<ClockReplacerInternal>;
+
+pub trait ClockReplacerApi {
+    fn create(num_frames: usize) -> Self;
+}
+
+impl ClockReplacerApi for ClockReplacer {
+    /// Create a replacer for frames `1..=num_frames`
+    fn create(num_frames: usize) -> Self {
+        Synchronized::init(ClockReplacerInternal {
+            slots: vec![Slot::default(); num_frames],
+            hand: 0,
+            num_evictable: 0,
+        })
+    }
+}
+
+impl Replacer for ClockReplacer {
+    /// Sweep the clock hand until an evictable frame with a clear reference bit is found. Terminates within two revolutions
+    /// since the first one clears every reference bit it passes.
+    fn evict(&self) -> Option<FrameId> {
+        let mut inner = self.lock();
+        if inner.num_evictable == 0 {
+            return None;
+        }
+        loop {
+            let hand = inner.hand;
+            inner.hand = (hand + 1) % inner.slots.len();
+            let slot = &mut inner.slots[hand];
+            if !slot.tracked || !slot.evictable {
+                continue;
+            }
+            if slot.referenced {
+                slot.referenced = false;
+                continue;
+            }
+            *slot = Slot::default();
+            inner.num_evictable -= 1;
+            return Some(hand as FrameId + 1);
+        }
+    }
+
+    fn record_access(&self, frame_id: FrameId) {
+        let mut inner = self.lock();
+        let slot = inner.slot(frame_id);
+        slot.tracked = true;
+        slot.referenced = true;
+    }
+
+    fn set_evictable(&self, frame_id: FrameId, evictable: bool) {
+        let mut inner = self.lock();
+        let slot = inner.slot(frame_id);
+        if !slot.tracked || slot.evictable == evictable {
+            return;
+        }
+        slot.evictable = evictable;
+        if evictable {
+            inner.num_evictable += 1;
+        } else {
+            inner.num_evictable -= 1;
+        }
+    }
+
+    fn remove(&self, frame_id: FrameId) {
+        let mut inner = self.lock();
+        let slot = inner.slot(frame_id);
+        if !slot.tracked || !slot.evictable {
+            return;
+        }
+        *slot = Slot::default();
+        inner.num_evictable -= 1;
+    }
+
+    fn size(&self) -> usize {
+        let inner = self.lock();
+        inner.num_evictable
+    }
+
+    fn resize(&self, num_frames: usize) {
+        let mut inner = self.lock();
+        debug_assert!(inner
+            .slots
+            .iter()
+            .skip(num_frames)
+            .all(|slot| !slot.tracked));
+        inner.slots.resize(num_frames, Slot::default());
+        if inner.hand >= num_frames {
+            inner.hand = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::{cwd, BUFFER_POOL_SIZE};
+    use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+
+    #[test]
+    fn test_second_chance() {
+        let replacer = ClockReplacer::create(4);
+        for frame_id in 1..=4 {
+            replacer.record_access(frame_id);
+            replacer.set_evictable(frame_id, true);
+        }
+        assert!(replacer.size() == 4);
+
+        // every frame is referenced, so the first sweep clears all the bits and the second evicts from the start
+        assert!(replacer.evict() == Some(1));
+        // frame 2 is accessed again and survives the next sweep
+        replacer.record_access(2);
+        assert!(replacer.evict() == Some(3));
+        assert!(replacer.evict() == Some(4));
+        assert!(replacer.evict() == Some(2));
+        assert!(replacer.evict().is_none());
+    }
+
+    #[test]
+    fn test_pinned_and_removed_frames() {
+        let replacer = ClockReplacer::create(3);
+        for frame_id in 1..=3 {
+            replacer.record_access(frame_id);
+        }
+        // nothing is evictable until set_evictable
+        assert!(replacer.evict().is_none());
+        replacer.set_evictable(1, true);
+        replacer.set_evictable(2, true);
+        replacer.remove(2);
+        replacer.remove(3);
+        assert!(replacer.size() == 1);
+        assert!(replacer.evict() == Some(1));
+        assert!(replacer.evict().is_none());
+    }
+
+    #[test]
+    fn test_buffer_pool_with_clock() {
+        let dir = cwd() + "/tests/clock_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        let path = dir.clone() + "/test_file.bin";
+        let replacer = ClockReplacer::create(BUFFER_POOL_SIZE);
+        let buffer_pool = BufferPool::create_with_replacer(&path, Box::new(replacer)).unwrap();
+
+        let mut page_ids = Vec::new();
+        for i in 0..BUFFER_POOL_SIZE * 2 {
+            let (page_id, frame) = buffer_pool.new_page().unwrap();
+            frame.write().page_mut()[0] = i as u8;
+            assert!(buffer_pool.unpin_page(page_id, true));
+            page_ids.push(page_id);
+        }
+        for (i, page_id) in page_ids.iter().enumerate() {
+            let frame = buffer_pool.fetch_page(*page_id).unwrap();
+            assert!(frame.read().page()[0] == i as u8);
+            assert!(buffer_pool.unpin_page(*page_id, false));
+        }
+
+        std::fs::remove_dir_all(std::path::Path::new(&dir)).unwrap();
+    }
+}
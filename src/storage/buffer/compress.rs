@@ -0,0 +1,912 @@
+/// This file implements transparent page compression: a disk manager that compresses every page it writes with a `Codec` and
+/// stores it in an extent of as many `SECTOR_SIZE` sectors as it needs, instead of a whole page slot. Pages that are mostly
+/// zeros, like a page holding one small serialized item, shrink to a sector or two. The buffer pool and everything above it
+/// still see whole, uncompressed pages.
+///
+/// An extent starts with a small header, followed by the compressed page:
+///
+/// ```text
+/// | page id (8) | codec (1) | reserved (1) | payload length (2) | payload |
+/// ```
+///
+/// The page table maps each page id to its extent. A rewrite that still fits in the page's extent goes in place; one that
+/// doesn't gets a new extent, and the old one is released once the next sync has made the table that no longer points to it
+/// durable. The table lives in its own file next to the data file (`<data file>.ptab`) and is replaced atomically on every
+/// sync that changed it, and when the disk manager is dropped. Free sectors aren't stored: they're the gaps between extents.
+///
+/// Pages are checksummed before they're compressed, so a torn or corrupted extent fails verification when it's read back like
+/// a page of `DiskMgr` would. A page that doesn't compress is stored as it is. `ZeroRunCodec`, which drops runs of zeros, is
+/// always built in; `Lz4Codec` and `ZstdCodec` come with the `lz4` and `zstd` features.
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::time::Instant;
+
+use crate::shared::{PageId, INVALID_PAGE_ID, PAGE_SIZE};
+use crate::storage::buffer::diskmgr::{
+    stamped, verify, DiskApi, DiskStats, DurabilityMode, FsyncFailed, SyncPolicy,
+};
+use crate::storage::buffer::freemap::FreePageMap;
+use crate::storage::buffer::fs;
+use crate::storage::buffer::page::{self, ChecksumAlgorithm, Page};
+use crate::storage::config::StorageConfig;
+use crate::storage::error::StorageError;
+use crate::sync::{RwLatch as _, RwSynchronized};
+use crate::telemetry::trace;
+
+/// Unit extents are made of
+pub const SECTOR_SIZE: usize = 256;
+
+pub const EXTENT_HEADER_SIZE: usize = 12;
+
+pub const PAGE_TABLE_EXTENSION: &str = "ptab";
+
+/// Version of the page table file layout. Bump it whenever the layout changes
+pub const PAGE_TABLE_VERSION: u32 = 1;
+
+/// Codec id of pages stored as they are
+pub const UNCOMPRESSED: u8 = 0;
+
+/// A page compression scheme. Its id is stored with every page it compresses, so it must not change once pages have been
+/// written with it
+pub trait Codec {
+    fn id(&self) -> u8;
+    /// Append the compressed form of `page` to `out`
+    fn compress(&self, page: &Page, out: &mut Vec<u8>);
+    /// Restore a page from what `compress` produced. Returns false if `bytes` aren't that
+    fn decompress(&self, bytes: &[u8], page: &mut Page) -> bool;
+}
+
+/// The codec type stored by the disk manager
+pub type BoxedCodec = Box<dyn Codec + Send + Sync>;
+
+/// Zeros shorter than this are kept as literals, since eliding them would cost more than it saves
+const MIN_ZERO_RUN: usize = 4;
+
+/// Codec that drops runs of zeros. A page becomes a sequence of tokens, each some literal bytes followed by some zeros:
+///
+/// ```text
+/// | literal length (2) | zeros (2) | literal bytes |
+/// ```
+pub struct ZeroRunCodec;
+
+impl Codec for ZeroRunCodec {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn compress(&self, page: &Page, out: &mut Vec<u8>) {
+        let zero_run_at = |i: usize| page[i..].iter().take(MIN_ZERO_RUN).all(|byte| *byte == 0);
+        let mut i = 0;
+        while i < PAGE_SIZE {
+            let literal = i;
+            while i < PAGE_SIZE && !zero_run_at(i) {
+                i += 1;
+            }
+            let zeros = i;
+            while i < PAGE_SIZE && page[i] == 0 {
+                i += 1;
+            }
+            out.extend_from_slice(&((zeros - literal) as u16).to_le_bytes());
+            out.extend_from_slice(&((i - zeros) as u16).to_le_bytes());
+            out.extend_from_slice(&page[literal..zeros]);
+        }
+    }
+
+    fn decompress(&self, mut bytes: &[u8], page: &mut Page) -> bool {
+        let mut i = 0;
+        while !bytes.is_empty() {
+            if bytes.len() < 4 {
+                return false;
+            }
+            let literal = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+            let zeros = u16::from_le_bytes([bytes[2], bytes[3]]) as usize;
+            bytes = &bytes[4..];
+            if literal > bytes.len() || i + literal + zeros > PAGE_SIZE {
+                return false;
+            }
+            page[i..i + literal].copy_from_slice(&bytes[..literal]);
+            page[i + literal..i + literal + zeros].fill(0);
+            bytes = &bytes[literal..];
+            i += literal + zeros;
+        }
+        i == PAGE_SIZE
+    }
+}
+
+/// LZ4 block compression: fast, and good enough for pages that are mostly zeros or repeated values
+#[cfg(feature = "lz4")]
+pub struct Lz4Codec;
+
+#[cfg(feature = "lz4")]
+impl Codec for Lz4Codec {
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn compress(&self, page: &Page, out: &mut Vec<u8>) {
+        out.extend_from_slice(&lz4_flex::block::compress(page));
+    }
+
+    fn decompress(&self, bytes: &[u8], page: &mut Page) -> bool {
+        lz4_flex::block::decompress_into(bytes, page).ok() == Some(PAGE_SIZE)
+    }
+}
+
+/// Zstd compression at a fixed level: slower than LZ4, but it shrinks pages with real content further
+#[cfg(feature = "zstd")]
+pub struct ZstdCodec {
+    level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl ZstdCodec {
+    /// A codec compressing at `level`, clamped to the levels zstd supports. The level isn't stored with the pages, so it
+    /// can change between runs
+    pub fn new(level: i32) -> Self {
+        let levels = zstd::compression_level_range();
+        ZstdCodec {
+            level: level.clamp(*levels.start(), *levels.end()),
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Default for ZstdCodec {
+    fn default() -> Self {
+        ZstdCodec::new(zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Codec for ZstdCodec {
+    fn id(&self) -> u8 {
+        3
+    }
+
+    fn compress(&self, page: &Page, out: &mut Vec<u8>) {
+        // only fails on a bad level, which `new` rules out
+        let compressed = zstd::bulk::compress(page, self.level).expect("zstd compression failed");
+        out.extend_from_slice(&compressed);
+    }
+
+    fn decompress(&self, bytes: &[u8], page: &mut Page) -> bool {
+        zstd::bulk::decompress_to_buffer(bytes, page.as_mut_slice()).ok() == Some(PAGE_SIZE)
+    }
+}
+
+/// Path of the page table belonging to the data file at `data_path`
+pub fn table_path(data_path: &str) -> String {
+    format!("{}.{}", data_path, PAGE_TABLE_EXTENSION)
+}
+
+/// Where a page is stored: `sectors` sectors starting at sector `start`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Extent {
+    start: u64,
+    sectors: u64,
+}
+
+impl Extent {
+    fn offset(&self) -> u64 {
+        self.start * SECTOR_SIZE as u64
+    }
+
+    fn len(&self) -> usize {
+        self.sectors as usize * SECTOR_SIZE
+    }
+}
+
+fn sectors_for(bytes: usize) -> u64 {
+    bytes.div_ceil(SECTOR_SIZE) as u64
+}
+
+pub struct CompressedDiskMgrCtx {
+    path: String,
+    handle: File,
+    codec: BoxedCodec,
+    // extent of each page, by page id. Pages that were never written have none and read as zeros
+    table: Vec<Option<Extent>>,
+    // whether the table changed since it was last saved
+    table_dirty: bool,
+    // runs of free sectors, by first sector
+    free: BTreeMap<u64, u64>,
+    // extents given up since the last sync. The saved table may still point to them
+    released: Vec<Extent>,
+    // sectors in the file
+    end: u64,
+    durability: DurabilityMode,
+    policy: SyncPolicy,
+    checksum: ChecksumAlgorithm,
+    last_sync: Instant,
+    // pages written since the last successful sync
+    unsynced: HashSet<PageId>,
+    // pages that were unsynced when a sync failed. their contents on disk are unknown
+    suspect: HashSet<PageId>,
+    recovery_required: bool,
+    free_map: FreePageMap,
+    num_writes: usize,
+    num_flushes: usize,
+}
+
+impl CompressedDiskMgrCtx {
+    /// Find `sectors` free sectors, first fit, growing the file if no run is long enough
+    fn allocate(&mut self, sectors: u64) -> Extent {
+        let run = self
+            .free
+            .iter()
+            .find(|(_, len)| **len >= sectors)
+            .map(|(start, len)| (*start, *len));
+        let Some((start, len)) = run else {
+            let start = self.end;
+            self.end += sectors;
+            return Extent { start, sectors };
+        };
+        self.free.remove(&start);
+        if len > sectors {
+            self.free.insert(start + sectors, len - sectors);
+        }
+        Extent { start, sectors }
+    }
+
+    /// Give an extent's sectors back, merging them with the free runs around them
+    fn release(&mut self, extent: Extent) {
+        let (mut start, mut sectors) = (extent.start, extent.sectors);
+        if let Some((prev, len)) = self.free.range(..start).next_back().map(|(s, l)| (*s, *l)) {
+            if prev + len == start {
+                self.free.remove(&prev);
+                start = prev;
+                sectors += len;
+            }
+        }
+        if let Some(len) = self.free.remove(&(start + sectors)) {
+            sectors += len;
+        }
+        self.free.insert(start, sectors);
+    }
+
+    /// Compress `buf` and store it as `page_id`. Doesn't sync
+    fn put_page(&mut self, buf: &Page, page_id: PageId) -> std::io::Result<()> {
+        self.check_writable()?;
+        let page = stamped(buf, page_id, self.checksum);
+        let mut extent = page_id.to_le_bytes().to_vec();
+        extent.extend_from_slice(&[self.codec.id(), 0, 0, 0]);
+        self.codec.compress(&page, &mut extent);
+        if extent.len() >= EXTENT_HEADER_SIZE + PAGE_SIZE {
+            extent.truncate(EXTENT_HEADER_SIZE);
+            extent[8] = UNCOMPRESSED;
+            extent.extend_from_slice(&page);
+        }
+        let payload = (extent.len() - EXTENT_HEADER_SIZE) as u16;
+        extent[10..12].copy_from_slice(&payload.to_le_bytes());
+
+        let sectors = sectors_for(extent.len());
+        // padded to whole sectors, so the file always ends on a sector boundary
+        extent.resize(sectors as usize * SECTOR_SIZE, 0);
+        if self.table.len() <= page_id as usize {
+            self.table.resize(page_id as usize + 1, None);
+            self.table_dirty = true;
+        }
+        let target = match self.table[page_id as usize] {
+            Some(current) if current.sectors >= sectors => current,
+            current => {
+                let target = self.allocate(sectors);
+                self.released.extend(current);
+                self.table[page_id as usize] = Some(target);
+                self.table_dirty = true;
+                target
+            }
+        };
+        self.unsynced.insert(page_id);
+        fs::write_all_at(&self.handle, &extent, target.offset())?;
+        self.num_writes += 1;
+        Ok(())
+    }
+
+    fn write_page(&mut self, buf: &Page, page_id: PageId) -> std::io::Result<()> {
+        self.put_page(buf, page_id)?;
+        self.sync_after_write()
+    }
+
+    fn append_page(&mut self, buf: &Page) -> std::io::Result<PageId> {
+        let page_id = self.table.len() as PageId;
+        self.write_page(buf, page_id)?;
+        Ok(page_id)
+    }
+
+    /// Read and decompress `page_id`, verifying its checksum
+    fn read_page(&self, buf: &mut Page, page_id: PageId) -> Result<(), StorageError> {
+        if page_id < 0 || page_id as usize >= self.table.len() {
+            return Err(StorageError::PageNotFound(page_id));
+        }
+        let Some(extent) = self.table[page_id as usize] else {
+            *buf = page::empty();
+            return Ok(());
+        };
+        let mut bytes = vec![0; extent.len()];
+        fs::read_exact_at(&self.handle, &mut bytes, extent.offset())?;
+        let stored_id = PageId::from_le_bytes(bytes[..8].try_into().unwrap());
+        let payload = u16::from_le_bytes([bytes[10], bytes[11]]) as usize;
+        if stored_id != page_id || EXTENT_HEADER_SIZE + payload > bytes.len() {
+            return Err(StorageError::Corruption(page_id));
+        }
+        let payload = &bytes[EXTENT_HEADER_SIZE..EXTENT_HEADER_SIZE + payload];
+        let restored = match bytes[8] {
+            UNCOMPRESSED if payload.len() == PAGE_SIZE => {
+                buf.copy_from_slice(payload);
+                true
+            }
+            id if id == self.codec.id() => self.codec.decompress(payload, buf),
+            _ => false,
+        };
+        if !restored {
+            return Err(StorageError::Corruption(page_id));
+        }
+        verify(buf, page_id)
+    }
+
+    /// Sync the data file and then save the table. On failure, every unsynced page becomes suspect and the disk manager
+    /// enters the recovery-required state (see `DiskMgrCtx::sync`)
+    fn sync(&mut self) -> std::io::Result<()> {
+        let synced = trace::io("sync", INVALID_PAGE_ID, || {
+            match self.durability {
+                DurabilityMode::Full => self.handle.sync_all()?,
+                _ => self.handle.sync_data()?,
+            }
+            self.save_table(true)
+        });
+        if synced.is_err() {
+            return Err(self.fail_sync());
+        }
+        // the saved table no longer points to the released extents
+        for extent in std::mem::take(&mut self.released) {
+            self.release(extent);
+        }
+        self.unsynced.clear();
+        self.num_flushes += 1;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    fn sync_after_write(&mut self) -> std::io::Result<()> {
+        match self.policy {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Interval(interval) if self.last_sync.elapsed() >= interval => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    fn fail_sync(&mut self) -> std::io::Error {
+        self.suspect.extend(self.unsynced.drain());
+        self.recovery_required = true;
+        self.fsync_error()
+    }
+
+    fn check_writable(&self) -> std::io::Result<()> {
+        if self.recovery_required {
+            return Err(self.fsync_error());
+        }
+        Ok(())
+    }
+
+    fn fsync_error(&self) -> std::io::Error {
+        let mut pages: Vec<PageId> = self.suspect.iter().copied().collect();
+        pages.sort_unstable();
+        std::io::Error::other(FsyncFailed { pages })
+    }
+
+    /// Replace the table file with the current table, if it changed: write a new file next to it, then rename it over the old
+    /// one. With `durable`, the new file is synced before the rename
+    fn save_table(&mut self, durable: bool) -> std::io::Result<()> {
+        if !self.table_dirty {
+            return Ok(());
+        }
+        let mut bytes = PAGE_TABLE_VERSION.to_le_bytes().to_vec();
+        bytes.push(self.codec.id());
+        bytes.extend_from_slice(&(self.table.len() as u64).to_le_bytes());
+        for extent in self.table.iter() {
+            let (start, sectors) = extent.map_or((u64::MAX, 0), |e| (e.start, e.sectors));
+            bytes.extend_from_slice(&start.to_le_bytes());
+            bytes.extend_from_slice(&sectors.to_le_bytes());
+        }
+        bytes.extend_from_slice(&crc32c::crc32c(&bytes).to_le_bytes());
+
+        let path = table_path(&self.path);
+        let new_path = path.clone() + ".new";
+        let file = File::create(&new_path)?;
+        fs::write_all_at(&file, &bytes, 0)?;
+        if durable {
+            file.sync_all()?;
+        }
+        std::fs::rename(&new_path, &path)?;
+        self.table_dirty = false;
+        Ok(())
+    }
+}
+
+impl Drop for CompressedDiskMgrCtx {
+    /// Pages written since the last sync are in the data file (if not on disk yet), so keep the table pointing to them
+    fn drop(&mut self) {
+        if !self.recovery_required {
+            let _ = self.save_table(false);
+        }
+    }
+}
+
+fn invalid_table(path: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("page table {} is corrupted", path),
+    )
+}
+
+/// Load the page table saved for the data file at `data_path`. A missing table is an empty one
+fn load_table(data_path: &str, codec: u8) -> std::io::Result<Vec<Option<Extent>>> {
+    let path = table_path(data_path);
+    let bytes = match std::fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    if bytes.len() < 17 {
+        return Err(invalid_table(&path));
+    }
+    let (body, crc) = bytes.split_at(bytes.len() - 4);
+    let u64_at = |i: usize| u64::from_le_bytes(body[i..i + 8].try_into().unwrap());
+    if crc32c::crc32c(body).to_le_bytes() != crc
+        || u32::from_le_bytes(body[..4].try_into().unwrap()) != PAGE_TABLE_VERSION
+        || body.len() != 13 + u64_at(5) as usize * 16
+    {
+        return Err(invalid_table(&path));
+    }
+    if body[4] != codec {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "{} was written with codec {}, not {}",
+                data_path, body[4], codec
+            ),
+        ));
+    }
+    Ok((0..u64_at(5) as usize)
+        .map(|i| {
+            let (start, sectors) = (u64_at(13 + i * 16), u64_at(21 + i * 16));
+            (start != u64::MAX).then_some(Extent { start, sectors })
+        })
+        .collect())
+}
+
+fn open_file(
+    config: &StorageConfig,
+    codec: BoxedCodec,
+    truncate: bool,
+) -> std::io::Result<CompressedDiskMgr> {
+    let path = config.data_path().to_string_lossy().into_owned();
+    let handle = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .truncate(truncate)
+        .open(&path)?;
+    if truncate {
+        match std::fs::remove_file(table_path(&path)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    let table = load_table(&path, codec.id())?;
+    let mut free_map = FreePageMap::open(&path, truncate)?;
+    // free bits past the last page are left over from a vacuum that didn't finish
+    free_map.truncate(table.len() as PageId)?;
+
+    // the gaps between extents are free
+    let mut extents: Vec<Extent> = table.iter().flatten().copied().collect();
+    extents.sort_by_key(|extent| extent.start);
+    let end = sectors_for(handle.metadata()?.len() as usize);
+    let mut free = BTreeMap::new();
+    let mut next = 0;
+    for extent in extents.iter().chain(std::iter::once(&Extent {
+        start: end,
+        sectors: 0,
+    })) {
+        if extent.start > next {
+            free.insert(next, extent.start - next);
+        }
+        next = next.max(extent.start + extent.sectors);
+    }
+
+    Ok(RwSynchronized::init(CompressedDiskMgrCtx {
+        path,
+        handle,
+        codec,
+        table,
+        table_dirty: false,
+        free,
+        released: Vec::new(),
+        end: end.max(next),
+        durability: config.durability(),
+        policy: config.sync_policy(),
+        checksum: config.checksum(),
+        last_sync: Instant::now(),
+        unsynced: HashSet::new(),
+        suspect: HashSet::new(),
+        recovery_required: false,
+        free_map,
+        num_writes: 0,
+        num_flushes: 0,
+    }))
+}
+
+/// Disk manager that compresses pages. Clones refer to the same disk manager
+pub type CompressedDiskMgr = RwSynchronized<CompressedDiskMgrCtx>;
+
+pub trait CompressedApi {
+    fn create_with_codec(config: &StorageConfig, codec: BoxedCodec) -> Result<Self, StorageError>
+    where
+        Self: Sized;
+    fn open_with_codec(config: &StorageConfig, codec: BoxedCodec) -> Result<Self, StorageError>
+    where
+        Self: Sized;
+    fn stored_bytes(&self) -> u64;
+}
+
+impl CompressedApi for CompressedDiskMgr {
+    /// Create a new, empty data file whose pages are compressed with `codec`
+    fn create_with_codec(config: &StorageConfig, codec: BoxedCodec) -> Result<Self, StorageError> {
+        Ok(open_file(config, codec, true)?)
+    }
+
+    /// Open a data file whose pages were compressed with `codec`, keeping its contents. Fails if they were compressed with
+    /// another codec
+    fn open_with_codec(config: &StorageConfig, codec: BoxedCodec) -> Result<Self, StorageError> {
+        Ok(open_file(config, codec, false)?)
+    }
+
+    /// Size of the data file, in bytes, including free sectors
+    fn stored_bytes(&self) -> u64 {
+        self.read().end * SECTOR_SIZE as u64
+    }
+}
+
+impl DiskApi for CompressedDiskMgr {
+    fn create(path: &str) -> Result<Self, StorageError> {
+        CompressedDiskMgr::create_with_durability(path, DurabilityMode::default())
+    }
+
+    fn create_with_durability(
+        path: &str,
+        durability: DurabilityMode,
+    ) -> Result<Self, StorageError> {
+        CompressedDiskMgr::create_with_sync_policy(path, durability, SyncPolicy::default())
+    }
+
+    fn create_with_sync_policy(
+        path: &str,
+        durability: DurabilityMode,
+        policy: SyncPolicy,
+    ) -> Result<Self, StorageError> {
+        CompressedDiskMgr::create_with_config(
+            &StorageConfig::for_path(path)
+                .with_durability(durability)
+                .with_sync_policy(policy),
+        )
+    }
+
+    fn open(path: &str) -> Result<Self, StorageError> {
+        CompressedDiskMgr::open_with_durability(path, DurabilityMode::default())
+    }
+
+    fn open_with_durability(path: &str, durability: DurabilityMode) -> Result<Self, StorageError> {
+        CompressedDiskMgr::open_with_config(
+            &StorageConfig::for_path(path).with_durability(durability),
+        )
+    }
+
+    /// Create a new, empty data file, compressing pages with `ZeroRunCodec`
+    fn create_with_config(config: &StorageConfig) -> Result<Self, StorageError> {
+        CompressedDiskMgr::create_with_codec(config, Box::new(ZeroRunCodec))
+    }
+
+    fn open_with_config(config: &StorageConfig) -> Result<Self, StorageError> {
+        CompressedDiskMgr::open_with_codec(config, Box::new(ZeroRunCodec))
+    }
+
+    /// Read a page and decompress it. Fails like `DiskMgr::read_page`
+    fn read_page(&self, buf: &mut [u8; PAGE_SIZE], loc: u64) -> Result<(), StorageError> {
+        trace::io("read", loc as PageId, || {
+            self.read().read_page(buf, loc as PageId)
+        })
+    }
+
+    fn read_page_exists(&self, page_id: PageId) -> bool {
+        page_id >= 0 && (page_id as usize) < self.read().table.len()
+    }
+
+    fn write_page(&self, buf: &[u8; PAGE_SIZE], loc: u64) -> Result<(), StorageError> {
+        Ok(trace::io("write", loc as PageId, || {
+            self.write().write_page(buf, loc as PageId)
+        })?)
+    }
+
+    fn append_page(&self, buf: &[u8; PAGE_SIZE]) -> Result<PageId, StorageError> {
+        Ok(trace::io("append", INVALID_PAGE_ID, || {
+            self.write().append_page(buf)
+        })?)
+    }
+
+    /// See `DiskMgr::allocate_page`
+    fn allocate_page(&self, buf: &[u8; PAGE_SIZE]) -> Result<PageId, StorageError> {
+        let mut inner = self.write();
+        inner.check_writable()?;
+        let Some(page_id) = inner.free_map.first_free() else {
+            return Ok(inner.append_page(buf)?);
+        };
+        inner.free_map.set_free(page_id, false)?;
+        inner.write_page(buf, page_id)?;
+        Ok(page_id)
+    }
+
+    fn deallocate_page(&self, page_id: PageId) -> Result<(), StorageError> {
+        let mut inner = self.write();
+        inner.check_writable()?;
+        if inner.free_map.is_free(page_id) || page_id < 0 || page_id as usize >= inner.table.len() {
+            return Ok(());
+        }
+        Ok(inner.free_map.set_free(page_id, true)?)
+    }
+
+    fn free_pages(&self) -> Vec<PageId> {
+        self.read().free_map.free_pages()
+    }
+
+    /// Forget the free pages at the end of the table and release their extents (see `DiskMgr::vacuum`). The file is cut
+    /// after the last extent still in use
+    fn vacuum(&self) -> Result<usize, StorageError> {
+        let mut inner = self.write();
+        inner.check_writable()?;
+        let num_pages = inner.table.len();
+        let mut end = num_pages as PageId;
+        while end > 0 && inner.free_map.is_free(end - 1) {
+            end -= 1;
+        }
+        if end == num_pages as PageId {
+            return Ok(0);
+        }
+        let removed: Vec<Extent> = inner.table.drain(end as usize..).flatten().collect();
+        inner.released.extend(removed);
+        inner.unsynced.retain(|page_id| *page_id < end);
+        inner.table_dirty = true;
+        inner.sync()?;
+        if let Some((start, len)) = inner.free.last_key_value().map(|(s, l)| (*s, *l)) {
+            if start + len == inner.end {
+                inner.free.remove(&start);
+                inner.end = start;
+                let len = inner.end * SECTOR_SIZE as u64;
+                inner.handle.set_len(len)?;
+            }
+        }
+        inner.free_map.truncate(end)?;
+        Ok(num_pages - end as usize)
+    }
+
+    /// Write a batch of pages, syncing once at the end if the sync policy says so
+    fn write_pages(&self, pages: &[(PageId, &[u8; PAGE_SIZE])]) -> Result<(), StorageError> {
+        let mut inner = self.write();
+        for (page_id, buf) in pages {
+            trace::io("write", *page_id, || inner.put_page(buf, *page_id))?;
+        }
+        Ok(inner.sync_after_write()?)
+    }
+
+    fn read_pages(&self, pages: &mut [(PageId, &mut [u8; PAGE_SIZE])]) -> Result<(), StorageError> {
+        let inner = self.read();
+        for (page_id, buf) in pages.iter_mut() {
+            trace::io("read", *page_id, || inner.read_page(buf, *page_id))?;
+        }
+        Ok(())
+    }
+
+    fn num_pages(&self) -> Result<usize, StorageError> {
+        Ok(self.read().table.len())
+    }
+
+    fn verify_all(&self) -> Result<Vec<PageId>, StorageError> {
+        let inner = self.read();
+        let mut corrupted = Vec::new();
+        let mut buf = page::empty();
+        for page_id in 0..inner.table.len() as PageId {
+            match inner.read_page(&mut buf, page_id) {
+                Err(StorageError::Corruption(_)) => corrupted.push(page_id),
+                result => result?,
+            }
+        }
+        Ok(corrupted)
+    }
+
+    fn sync(&self) -> Result<(), StorageError> {
+        let mut inner = self.write();
+        inner.check_writable()?;
+        if inner.policy == SyncPolicy::Never || (inner.unsynced.is_empty() && !inner.table_dirty) {
+            return Ok(());
+        }
+        Ok(inner.sync()?)
+    }
+
+    fn sync_policy(&self) -> SyncPolicy {
+        self.read().policy
+    }
+
+    fn recovery_required(&self) -> bool {
+        self.read().recovery_required
+    }
+
+    fn suspect_pages(&self) -> Vec<PageId> {
+        let mut pages: Vec<PageId> = self.read().suspect.iter().copied().collect();
+        pages.sort_unstable();
+        pages
+    }
+
+    fn stats(&self) -> DiskStats {
+        let inner = self.read();
+        DiskStats {
+            writes: inner.num_writes,
+            flushes: inner.num_flushes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::buffer::io;
+    use crate::testing::Song;
+
+    fn setup(name: &str) -> (String, String) {
+        let dir = cwd() + "/tests/compress_" + name + "_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.clone() + "/data.bin";
+        (dir, path)
+    }
+
+    fn song_page(id: i32, title: &str) -> Page {
+        io::to_buffer(Song::new(id, title, "Men I Trust")).unwrap()
+    }
+
+    fn read_song(mgr: &impl DiskApi, page_id: PageId) -> Result<Song, StorageError> {
+        let mut buf = page::empty();
+        mgr.read_page(&mut buf, page_id as u64)?;
+        io::from_buffer(&buf)
+    }
+
+    #[test]
+    fn test_zero_run_codec() {
+        let mut page = song_page(1, "Show Me How");
+        page[PAGE_SIZE - 3] = 9;
+        let mut bytes = Vec::new();
+        ZeroRunCodec.compress(&page, &mut bytes);
+        assert!(bytes.len() < 128);
+        let mut restored = [1; PAGE_SIZE];
+        assert!(ZeroRunCodec.decompress(&bytes, &mut restored));
+        assert!(restored == page);
+        // a page of noise doesn't shrink, and truncated input is rejected
+        let noise: Vec<u8> = (0..PAGE_SIZE).map(|i| (i % 251) as u8 + 1).collect();
+        let mut bytes = Vec::new();
+        ZeroRunCodec.compress(&noise.try_into().unwrap(), &mut bytes);
+        assert!(bytes.len() > PAGE_SIZE);
+        assert!(!ZeroRunCodec.decompress(&bytes[..100], &mut restored));
+    }
+
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    #[test]
+    fn test_lz4_and_zstd_codecs() {
+        let (dir, path) = setup("codecs");
+        let config = StorageConfig::for_path(&path);
+        let codecs: &[fn() -> BoxedCodec] = &[
+            #[cfg(feature = "lz4")]
+            || Box::new(Lz4Codec),
+            #[cfg(feature = "zstd")]
+            || Box::new(ZstdCodec::default()),
+        ];
+        for codec in codecs {
+            let page = song_page(1, "Seven");
+            let mut bytes = Vec::new();
+            codec().compress(&page, &mut bytes);
+            assert!(bytes.len() < SECTOR_SIZE - EXTENT_HEADER_SIZE);
+            let mut restored = [1; PAGE_SIZE];
+            assert!(codec().decompress(&bytes, &mut restored));
+            assert!(restored == page);
+            assert!(!codec().decompress(&bytes[..bytes.len() / 2], &mut restored));
+
+            let mgr = CompressedDiskMgr::create_with_codec(&config, codec()).unwrap();
+            for id in 0..10 {
+                mgr.append_page(&song_page(id, "Seven")).unwrap();
+            }
+            mgr.sync().unwrap();
+            assert!(mgr.stored_bytes() == 10 * SECTOR_SIZE as u64);
+            drop(mgr);
+            // pages are only readable with the codec that wrote them
+            assert!(CompressedDiskMgr::open_with_codec(&config, Box::new(ZeroRunCodec)).is_err());
+            let mgr = CompressedDiskMgr::open_with_codec(&config, codec()).unwrap();
+            assert!(read_song(&mgr, 7).unwrap().id == 7);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compressed_pages() {
+        let (dir, path) = setup("pages");
+        let mgr = CompressedDiskMgr::create_with_sync_policy(
+            &path,
+            DurabilityMode::Data,
+            SyncPolicy::OnFlush,
+        )
+        .unwrap();
+        for id in 0..100 {
+            assert!(mgr.append_page(&song_page(id, "Tailwhip")).unwrap() == id as PageId);
+        }
+        // a song page fits in a sector
+        assert!(mgr.stored_bytes() == 100 * SECTOR_SIZE as u64);
+
+        // a page that no longer fits its extent moves, and its old extent is reused after the next sync
+        let mut big = song_page(-1, "Numb");
+        big[PAGE_SIZE / 2..].fill(7);
+        mgr.write_page(&big, 5).unwrap();
+        assert!(read_song(&mgr, 5).unwrap().id == -1);
+        mgr.sync().unwrap();
+        let stored = mgr.stored_bytes();
+        mgr.write_page(&song_page(5, "Numb"), 100).unwrap();
+        assert!(mgr.stored_bytes() == stored);
+        assert!(
+            mgr.read().table[100]
+                == Some(Extent {
+                    start: 5,
+                    sectors: 1
+                })
+        );
+
+        // page 101 was never written, pages past it were never allocated
+        mgr.write_page(&song_page(102, "Seven"), 102).unwrap();
+        let mut buf = page::empty();
+        mgr.read_page(&mut buf, 101).unwrap();
+        assert!(page::is_zeroed(&buf));
+        assert!(matches!(
+            read_song(&mgr, 103),
+            Err(StorageError::PageNotFound(103))
+        ));
+        drop(mgr);
+
+        let reopened = CompressedDiskMgr::open(&path).unwrap();
+        assert!(reopened.num_pages().unwrap() == 103);
+        assert!(read_song(&reopened, 5).unwrap().id == -1);
+        assert!(read_song(&reopened, 100).unwrap().id == 5);
+        assert!(reopened.verify_all().unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compressed_corruption_and_vacuum() {
+        let (dir, path) = setup("vacuum");
+        let mgr = CompressedDiskMgr::create(&path).unwrap();
+        for id in 0..4 {
+            mgr.allocate_page(&song_page(id, "Lauren")).unwrap();
+        }
+        // flip a byte of page 2's payload behind the disk manager's back
+        let extent = mgr.read().table[2].unwrap();
+        let handle = OpenOptions::new().write(true).open(&path).unwrap();
+        fs::write_all_at(&handle, &[0xff], extent.offset() + 40).unwrap();
+        assert!(matches!(
+            read_song(&mgr, 2),
+            Err(StorageError::Corruption(2))
+        ));
+        assert!(mgr.verify_all().unwrap() == vec![2]);
+
+        mgr.deallocate_page(2).unwrap();
+        mgr.deallocate_page(3).unwrap();
+        assert!(mgr.vacuum().unwrap() == 2);
+        assert!(mgr.num_pages().unwrap() == 2);
+        assert!(mgr.stored_bytes() == 2 * SECTOR_SIZE as u64);
+        assert!(mgr.allocate_page(&song_page(9, "Lauren")).unwrap() == 2);
+        assert!(read_song(&mgr, 2).unwrap().id == 9);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+/// This file implements the data directory: a disk manager per relation, each backed by its own file named after the
+/// relation's oid (`<oid>.db`). Pages are addressed by `(file_id, page_id)`, where page ids count from zero within each file.
+/// Files are opened lazily, the first time one of their pages is touched, and existing files are opened without truncation
+/// so their contents survive restarts. Durability and fsync failure handling are those of the per-file `DiskMgr`.
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::PathBuf;
+
+use crate::shared::{Oid, PageId, PAGE_SIZE};
+use crate::storage::buffer::diskmgr::{DiskApi as _, DiskMgr, DurabilityMode};
+use crate::storage::buffer::freemap;
+use crate::sync::{Latch as _, Synchronized};
+
+/// Identifies a file in the data directory. Tables and indexes use their oid
+pub type FileId = Oid;
+
+pub const DATA_FILE_EXTENSION: &str = "db";
+
+/// Address of a page in the data directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FilePageId {
+    pub file_id: FileId,
+    pub page_id: PageId,
+}
+
+impl FilePageId {
+    pub fn new(file_id: FileId, page_id: PageId) -> Self {
+        FilePageId { file_id, page_id }
+    }
+}
+
+impl Display for FilePageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file_id, self.page_id)
+    }
+}
+
+pub struct DataDirInternal {
+    dir: PathBuf,
+    durability: DurabilityMode,
+    // files opened so far
+    files: HashMap<FileId, DiskMgr>,
+}
+
+impl DataDirInternal {
+    fn path(&self, file_id: FileId) -> PathBuf {
+        self.dir
+            .join(format!("{}.{}", file_id, DATA_FILE_EXTENSION))
+    }
+}
+
+pub type DataDir = Synchronized<DataDirInternal>;
+
+pub trait DataDirApi {
+    fn open(dir: &str) -> std::io::Result<Self>
+    where
+        Self: Sized;
+    fn open_with_durability(dir: &str, durability: DurabilityMode) -> std::io::Result<Self>
+    where
+        Self: Sized;
+    fn file(&self, file_id: FileId) -> std::io::Result<DiskMgr>;
+    fn file_ids(&self) -> std::io::Result<Vec<FileId>>;
+    fn open_files(&self) -> usize;
+    fn read_page(&self, buf: &mut [u8; PAGE_SIZE], page: FilePageId) -> std::io::Result<()>;
+    fn write_page(&self, buf: &[u8; PAGE_SIZE], page: FilePageId) -> std::io::Result<()>;
+    fn append_page(&self, file_id: FileId, buf: &[u8; PAGE_SIZE]) -> std::io::Result<FilePageId>;
+    fn remove_file(&self, file_id: FileId) -> std::io::Result<()>;
+}
+
+impl DataDirApi for DataDir {
+    /// Open the data directory at `dir`, creating it if needed. No files are opened until they're used
+    fn open(dir: &str) -> std::io::Result<Self> {
+        DataDir::open_with_durability(dir, DurabilityMode::default())
+    }
+
+    fn open_with_durability(dir: &str, durability: DurabilityMode) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Synchronized::init(DataDirInternal {
+            dir: PathBuf::from(dir),
+            durability,
+            files: HashMap::new(),
+        }))
+    }
+
+    /// The disk manager for `file_id`, opening (or creating) its file on first use
+    fn file(&self, file_id: FileId) -> std::io::Result<DiskMgr> {
+        let mut inner = self.lock();
+        if let Some(mgr) = inner.files.get(&file_id) {
+            return Ok(mgr.clone());
+        }
+        let path = inner.path(file_id);
+        let mgr = DiskMgr::open_with_durability(path.to_str().unwrap(), inner.durability)?;
+        inner.files.insert(file_id, mgr.clone());
+        Ok(mgr)
+    }
+
+    /// Every file in the directory, opened or not, in id order
+    fn file_ids(&self) -> std::io::Result<Vec<FileId>> {
+        let dir = self.lock().dir.clone();
+        let mut ids = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(DATA_FILE_EXTENSION) {
+                continue;
+            }
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok())
+            {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// Number of files with an open handle
+    fn open_files(&self) -> usize {
+        self.lock().files.len()
+    }
+
+    fn read_page(&self, buf: &mut [u8; PAGE_SIZE], page: FilePageId) -> std::io::Result<()> {
+        Ok(self
+            .file(page.file_id)?
+            .read_page(buf, page.page_id as u64)?)
+    }
+
+    fn write_page(&self, buf: &[u8; PAGE_SIZE], page: FilePageId) -> std::io::Result<()> {
+        Ok(self
+            .file(page.file_id)?
+            .write_page(buf, page.page_id as u64)?)
+    }
+
+    /// Add a page to the end of `file_id`'s file and return its address
+    fn append_page(&self, file_id: FileId, buf: &[u8; PAGE_SIZE]) -> std::io::Result<FilePageId> {
+        let page_id = self.file(file_id)?.append_page(buf)?;
+        Ok(FilePageId::new(file_id, page_id))
+    }
+
+    /// Close and delete `file_id`'s file and its free page map, e.g. when its relation is dropped
+    fn remove_file(&self, file_id: FileId) -> std::io::Result<()> {
+        let mut inner = self.lock();
+        inner.files.remove(&file_id);
+        let path = inner.path(file_id);
+        let map_path = freemap::map_path(path.to_str().unwrap());
+        for path in [path, PathBuf::from(map_path)] {
+            match std::fs::remove_file(path) {
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::buffer::io;
+    use crate::testing::Song;
+
+    #[test]
+    fn test_files_survive_reopen() {
+        let dir = cwd() + "/tests/datadir_tests";
+        let _ = std::fs::remove_dir_all(&dir);
+        let data = DataDir::open(&dir).unwrap();
+        assert!(data.open_files() == 0);
+
+        let songs: FileId = 1;
+        let artists: FileId = 2;
+        let mut pages = Vec::new();
+        for i in 0..3 {
+            let buf = io::to_buffer(Song::new(i, "Wires", "The Neighbourhood")).unwrap();
+            pages.push(data.append_page(songs, &buf).unwrap());
+        }
+        let buf = io::to_buffer(Song::new(10, "Prey", "The Neighbourhood")).unwrap();
+        let artist_page = data.append_page(artists, &buf).unwrap();
+        // page ids are per file
+        assert!(pages[0] == FilePageId::new(songs, 0));
+        assert!(artist_page == FilePageId::new(artists, 0));
+        let buf = io::to_buffer(Song::new(7, "Compass", "The Neighbourhood")).unwrap();
+        data.write_page(&buf, pages[1]).unwrap();
+        assert!(data.open_files() == 2);
+        drop(data);
+
+        // nothing is truncated on reopen, and files are only opened when used
+        let data = DataDir::open(&dir).unwrap();
+        assert!(data.file_ids().unwrap() == vec![songs, artists]);
+        let mut buf = [0u8; PAGE_SIZE];
+        data.read_page(&mut buf, pages[1]).unwrap();
+        assert!(io::from_buffer::<Song>(&buf).unwrap().id == 7);
+        assert!(data.open_files() == 1);
+        assert!(data.append_page(songs, &buf).unwrap() == FilePageId::new(songs, 3));
+
+        data.remove_file(artists).unwrap();
+        assert!(data.file_ids().unwrap() == vec![songs]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,72 +1,767 @@
+use std::collections::HashSet;
+use std::fmt::Display;
 use std::fs::{File, OpenOptions};
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::shared::{PageId, PAGE_SIZE};
+use rayon::prelude::*;
+
+use crate::shared::{PageId, INVALID_PAGE_ID, PAGE_SIZE};
 use crate::storage::buffer;
-use crate::sync::{Latch as _, Synchronized};
+use crate::storage::buffer::freemap::FreePageMap;
+use crate::storage::buffer::page::{self, ChecksumAlgorithm, Page};
+use crate::storage::config::StorageConfig;
+use crate::storage::error::StorageError;
+use crate::sync::{Access as _, Latch as _, Synchronized};
+use crate::telemetry::trace;
 
-pub struct DiskMgrCtx {
-    num_writes: usize,
-    last_write: isize,
-    num_flushes: usize,
+/// Returned (wrapped in a `std::io::Error`) when an fsync of the data file fails. Once the kernel reports a writeback error it
+/// may already have dropped the dirty data, and a retried fsync can succeed without the data ever reaching disk. So we don't
+/// retry-and-pretend: every page written since the last successful sync is reported here and the disk manager refuses further
+/// writes until recovery has run.
+#[derive(Debug, Clone)]
+pub struct FsyncFailed {
+    pub pages: Vec<PageId>,
+}
+
+impl Display for FsyncFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "fsync failed, recovery required [suspect pages={:?}]",
+            self.pages
+        )
+    }
+}
+
+impl std::error::Error for FsyncFailed {}
+
+/// Extract the `FsyncFailed` payload from an error returned by the disk manager, if that's what it is
+pub fn fsync_failed(err: &StorageError) -> Option<&FsyncFailed> {
+    match err {
+        StorageError::Io(err) => err.get_ref().and_then(|e| e.downcast_ref::<FsyncFailed>()),
+        _ => None,
+    }
+}
+
+/// Check a page read from disk as `page_id` against its checksum and page id
+pub(crate) fn verify(buf: &Page, page_id: PageId) -> Result<(), StorageError> {
+    if page::verify_checksum(buf) && (page::page_id(buf) == page_id || page::is_zeroed(buf)) {
+        return Ok(());
+    }
+    Err(StorageError::Corruption(page_id))
+}
+
+/// A copy of `buf` with its page id and `algorithm`'s checksum filled in, ready to go to disk as `page_id`
+pub(crate) fn stamped(buf: &Page, page_id: PageId, algorithm: ChecksumAlgorithm) -> Page {
+    let mut copy = *buf;
+    page::set_page_id(&mut copy, page_id);
+    page::set_checksum_with(&mut copy, algorithm);
+    copy
+}
+
+/// Extension of the clean-shutdown marker, an empty file left next to the data file by `DiskApi::close`
+pub const CLEAN_SHUTDOWN_EXTENSION: &str = "clean";
+
+/// Path of the clean-shutdown marker belonging to the data file at `data_path`
+pub fn marker_path(data_path: &str) -> String {
+    format!("{}.{}", data_path, CLEAN_SHUTDOWN_EXTENSION)
+}
+
+/// How the disk manager makes a write durable. `Full` flushes file metadata along with the data (`fsync`), `Data` skips
+/// metadata that isn't needed to read the data back (`fdatasync`), and `DSync` opens the file with `O_DSYNC` so every write is
+/// durable when it returns and no separate sync is issued. Platforms without `O_DSYNC` fall back to `Data`, and platforms
+/// without `fdatasync` fall back to a full sync (that's what `File::sync_data` does there).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DurabilityMode {
+    #[default]
+    Full,
+    Data,
+    DSync,
+}
+
+impl DurabilityMode {
+    /// The mode actually in effect on this platform
+    fn effective(self) -> DurabilityMode {
+        if self == DurabilityMode::DSync && !cfg!(unix) {
+            return DurabilityMode::Data;
+        }
+        self
+    }
+}
+
+/// When the disk manager syncs the data file. `Always` syncs after every page write, so a write is durable once it returns.
+/// The others trade that away for throughput: `OnFlush` only syncs when `DiskApi::sync` is called (the buffer pool does so
+/// after flushing pages, and the WAL makes anything written in between recoverable), `Interval` additionally syncs on a write
+/// once the given time has passed since the last sync, and `Never` doesn't sync at all, not even on request, which is only
+/// fit for scratch files. The durability mode still decides how a sync is done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    #[default]
+    Always,
+    OnFlush,
+    Interval(Duration),
+    Never,
+}
+
+/// IO done by a disk manager since it was created
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DiskStats {
+    /// Pages written, including appends and each page of a batch
+    pub writes: usize,
+    /// Successful syncs of the data file
+    pub flushes: usize,
+}
+
+/// The part of the disk manager that's used without its latch: the data file's handle, which is never replaced once the disk
+/// manager is created and which positional IO only needs a shared reference to, and the IO counters
+struct DataFile {
     handle: File,
+    checksum: ChecksumAlgorithm,
+    num_writes: AtomicUsize,
+    num_flushes: AtomicUsize,
+    // last page written, or -1 before the first write
+    last_write: AtomicIsize,
 }
 
-pub type DiskMgr = Synchronized<DiskMgrCtx>;
+impl DataFile {
+    fn num_pages(&self) -> std::io::Result<usize> {
+        let len = self.handle.metadata()?.len();
+        Ok((len / PAGE_SIZE as u64) as usize)
+    }
+
+    /// Count `count` pages written, the last of them `last`
+    fn wrote(&self, count: usize, last: PageId) {
+        self.num_writes.fetch_add(count, Ordering::Relaxed);
+        self.last_write.store(last, Ordering::Release);
+    }
+}
+
+pub struct DiskMgrCtx {
+    file: Arc<DataFile>,
+    durability: DurabilityMode,
+    policy: SyncPolicy,
+    last_sync: Instant,
+    // pages written since the last successful sync
+    unsynced: HashSet<PageId>,
+    // pages that were unsynced when a sync failed. their contents on disk are unknown
+    suspect: HashSet<PageId>,
+    recovery_required: bool,
+    free_map: FreePageMap,
+    marker: String,
+    // the file was closed cleanly before it was opened, and hasn't been written to since (see `DiskApi::clean_shutdown`)
+    clean: bool,
+    // the marker is on disk, left by a `close` that nothing has been written since
+    marked: bool,
+    #[cfg(test)]
+    fail_next_sync: bool,
+}
+
+impl DiskMgrCtx {
+    /// Sync the data file. On failure, every unsynced page becomes suspect and the disk manager enters the recovery-required
+    /// state. The error is never retried.
+    fn sync(&mut self) -> std::io::Result<()> {
+        if trace::io("sync", INVALID_PAGE_ID, || self.sync_handle()).is_err() {
+            return Err(self.fail_sync());
+        }
+        self.unsynced.clear();
+        self.file.num_flushes.fetch_add(1, Ordering::Relaxed);
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Sync after a page write if the policy calls for it
+    fn sync_after_write(&mut self) -> std::io::Result<()> {
+        match self.policy {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Interval(interval) if self.last_sync.elapsed() >= interval => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    fn fail_sync(&mut self) -> std::io::Error {
+        self.suspect.extend(self.unsynced.drain());
+        self.recovery_required = true;
+        self.fsync_error()
+    }
+
+    /// With `O_DSYNC` the write itself is the sync, so a failed write has the same consequences as a failed fsync
+    fn write_failed(&mut self, err: std::io::Error) -> std::io::Error {
+        if self.durability == DurabilityMode::DSync {
+            return self.fail_sync();
+        }
+        err
+    }
+
+    fn sync_handle(&mut self) -> std::io::Result<()> {
+        #[cfg(test)]
+        if self.fail_next_sync {
+            self.fail_next_sync = false;
+            return Err(std::io::Error::from_raw_os_error(5));
+        }
+        match self.durability {
+            DurabilityMode::Full => self.file.handle.sync_all(),
+            DurabilityMode::Data => self.file.handle.sync_data(),
+            DurabilityMode::DSync => Ok(()),
+        }
+    }
+
+    /// Refuse to touch the file once a sync has failed
+    fn check_writable(&self) -> std::io::Result<()> {
+        if self.recovery_required {
+            return Err(self.fsync_error());
+        }
+        Ok(())
+    }
+
+    /// Check the file can be written to, and take back a clean-shutdown marker left by `close` before changing it
+    fn begin_write(&mut self) -> std::io::Result<()> {
+        self.check_writable()?;
+        if self.marked {
+            remove_marker(&self.marker)?;
+            self.marked = false;
+        }
+        self.clean = false;
+        Ok(())
+    }
+
+    fn fsync_error(&self) -> std::io::Error {
+        let mut pages: Vec<PageId> = self.suspect.iter().copied().collect();
+        pages.sort_unstable();
+        std::io::Error::other(FsyncFailed { pages })
+    }
+
+    fn write_page(&mut self, buf: &[u8; PAGE_SIZE], loc: u64) -> std::io::Result<()> {
+        self.begin_write()?;
+        self.unsynced.insert(loc as PageId);
+        let buf = stamped(buf, loc as PageId, self.file.checksum);
+        if let Err(err) = buffer::fs::write_bytes(&self.file.handle, &buf, loc * PAGE_SIZE as u64) {
+            return Err(self.write_failed(err));
+        }
+        self.file.wrote(1, loc as PageId);
+        self.sync_after_write()
+    }
+
+    /// The bookkeeping for a write of `page_id` that ran without the latch, once it's done. The page is unsynced from now on,
+    /// or suspect if a sync failed while it was being written
+    fn finish_write(
+        &mut self,
+        page_id: PageId,
+        written: std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        if self.recovery_required {
+            self.suspect.insert(page_id);
+            return Err(self.fsync_error());
+        }
+        self.unsynced.insert(page_id);
+        if let Err(err) = written {
+            return Err(self.write_failed(err));
+        }
+        self.sync_after_write()
+    }
+
+    fn append_page(&mut self, buf: &[u8; PAGE_SIZE]) -> std::io::Result<PageId> {
+        self.begin_write()?;
+        // appends hold the latch, and so do writes past the end of the file, so nobody else can take this id in between
+        let page_id = self.file.num_pages()? as PageId;
+        let buf = stamped(buf, page_id, self.file.checksum);
+        if let Err(err) =
+            buffer::fs::write_bytes(&self.file.handle, &buf, page_id as u64 * PAGE_SIZE as u64)
+        {
+            return Err(self.write_failed(err));
+        }
+        self.unsynced.insert(page_id);
+        self.file.wrote(1, page_id);
+        self.sync_after_write()?;
+        Ok(page_id)
+    }
+}
+
+impl Drop for DiskMgrCtx {
+    /// Sync whatever was written since the last sync, as the policy would have on the next flush
+    fn drop(&mut self) {
+        if !self.recovery_required && self.policy != SyncPolicy::Never && !self.unsynced.is_empty()
+        {
+            let _ = self.sync();
+        }
+    }
+}
+
+/// Remove the clean-shutdown marker at `path`, if there is one, and make sure it stays removed
+fn remove_marker(path: &str) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+        Ok(()) => buffer::fs::sync_parent(path),
+    }
+}
+
+/// Whether `handle` holds all of `page_id`
+fn page_exists(handle: &File, page_id: PageId) -> bool {
+    page_id >= 0
+        && handle
+            .metadata()
+            .is_ok_and(|stat| stat.len() >= (page_id as u64 + 1) * PAGE_SIZE as u64)
+}
+
+/// Reads don't take the disk manager's latch: they're positional and only touch the data file's handle, which is shared
+/// immutably, so any number of them can run concurrently with each other and with writes. Neither do the IO counters, which
+/// are atomics. Writes of pages already in the file (`write_page` and `write_pages_concurrent`) are positional too and run
+/// without the latch, which is only taken to check the disk manager is writable and to update the bookkeeping around them.
+/// Appends, and every other operation, update the bookkeeping in `DiskMgrCtx` and latch the disk manager for as long as they
+/// run, so callers must not hold the latch themselves when calling into it. Clones refer to the same disk manager
+#[derive(Clone)]
+pub struct DiskMgr {
+    file: Arc<DataFile>,
+    ctx: Synchronized<DiskMgrCtx>,
+}
+
+/// A disk manager of any kind, as the buffer pool holds it. Clones refer to the same disk manager
+pub type SharedDisk = Arc<dyn DiskApi + Send + Sync>;
 
 pub trait DiskApi {
-    fn create(path: &str) -> Self;
-    fn read_page(&self, buf: &mut [u8; PAGE_SIZE], offset: u64) -> std::io::Result<()>;
-    fn write_page(&self, buf: &[u8; PAGE_SIZE], offset: u64) -> std::io::Result<()>;
-    fn append_page(&self, buf: &[u8; PAGE_SIZE]) -> std::io::Result<PageId>;
-    fn inner(&self) -> &mut DiskMgrCtx;
+    fn create(path: &str) -> Result<Self, StorageError>
+    where
+        Self: Sized;
+    fn create_with_durability(path: &str, durability: DurabilityMode) -> Result<Self, StorageError>
+    where
+        Self: Sized;
+    fn create_with_sync_policy(
+        path: &str,
+        durability: DurabilityMode,
+        policy: SyncPolicy,
+    ) -> Result<Self, StorageError>
+    where
+        Self: Sized;
+    fn open(path: &str) -> Result<Self, StorageError>
+    where
+        Self: Sized;
+    fn open_with_durability(path: &str, durability: DurabilityMode) -> Result<Self, StorageError>
+    where
+        Self: Sized;
+    fn create_with_config(config: &StorageConfig) -> Result<Self, StorageError>
+    where
+        Self: Sized;
+    fn open_with_config(config: &StorageConfig) -> Result<Self, StorageError>
+    where
+        Self: Sized;
+    fn read_page(&self, buf: &mut [u8; PAGE_SIZE], offset: u64) -> Result<(), StorageError>;
+    fn read_page_exists(&self, page_id: PageId) -> bool;
+    fn write_page(&self, buf: &[u8; PAGE_SIZE], offset: u64) -> Result<(), StorageError>;
+    fn append_page(&self, buf: &[u8; PAGE_SIZE]) -> Result<PageId, StorageError>;
+    fn allocate_page(&self, buf: &[u8; PAGE_SIZE]) -> Result<PageId, StorageError>;
+    fn deallocate_page(&self, page_id: PageId) -> Result<(), StorageError>;
+    fn free_pages(&self) -> Vec<PageId>;
+    fn vacuum(&self) -> Result<usize, StorageError>;
+    fn write_pages(&self, pages: &[(PageId, &[u8; PAGE_SIZE])]) -> Result<(), StorageError>;
+    fn read_pages(&self, pages: &mut [(PageId, &mut [u8; PAGE_SIZE])]) -> Result<(), StorageError>;
+
+    /// Write a batch of pages like `write_pages`, but never sync: the caller syncs once the batch is written. Implementations
+    /// that can write pages concurrently do so; the rest write the batch with `write_pages`
+    fn write_pages_concurrent(
+        &self,
+        pages: &[(PageId, &[u8; PAGE_SIZE])],
+    ) -> Result<(), StorageError> {
+        self.write_pages(pages)
+    }
+
+    fn num_pages(&self) -> Result<usize, StorageError>;
+    fn verify_all(&self) -> Result<Vec<PageId>, StorageError>;
+    fn sync(&self) -> Result<(), StorageError>;
+    fn sync_policy(&self) -> SyncPolicy;
+    fn recovery_required(&self) -> bool;
+    fn suspect_pages(&self) -> Vec<PageId>;
+    fn stats(&self) -> DiskStats;
+
+    /// Make everything written so far durable before the file is put away. Implementations that can tell the next open about
+    /// it leave a clean-shutdown marker (see `clean_shutdown`)
+    fn close(&self) -> Result<(), StorageError> {
+        self.sync()
+    }
+
+    /// Whether the file was closed cleanly the last time it was used, so that recovery has nothing to do
+    fn clean_shutdown(&self) -> bool {
+        false
+    }
+}
+
+fn open_file(config: &StorageConfig, truncate: bool) -> std::io::Result<DiskMgr> {
+    let path = config.data_path();
+    let path = path.to_string_lossy();
+    let durability = config.durability().effective();
+    let policy = config.sync_policy();
+    let mut options = OpenOptions::new();
+    options
+        .create(true)
+        .read(true)
+        .write(true)
+        .truncate(truncate);
+    #[cfg(unix)]
+    if durability == DurabilityMode::DSync {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_DSYNC);
+    }
+    let handle = options.open(std::path::Path::new(path.as_ref()))?;
+    let mut free_map = FreePageMap::open(&path, truncate)?;
+    // free bits past the end of the file are left over from a vacuum that didn't finish
+    free_map.truncate((handle.metadata()?.len() / PAGE_SIZE as u64) as PageId)?;
+    // the marker only vouches for the session that left it: taken back right away, a crash from now on means recovery
+    let marker = marker_path(&path);
+    let clean = !truncate && std::path::Path::new(&marker).exists();
+    remove_marker(&marker)?;
+
+    let file = Arc::new(DataFile {
+        handle,
+        checksum: config.checksum(),
+        num_writes: AtomicUsize::new(0),
+        num_flushes: AtomicUsize::new(0),
+        last_write: AtomicIsize::new(-1),
+    });
+    let ctx = Synchronized::init(DiskMgrCtx {
+        file: file.clone(),
+        durability,
+        policy,
+        last_sync: Instant::now(),
+        unsynced: HashSet::new(),
+        suspect: HashSet::new(),
+        recovery_required: false,
+        free_map,
+        marker,
+        clean,
+        marked: false,
+        #[cfg(test)]
+        fail_next_sync: false,
+    });
+    Ok(DiskMgr { file, ctx })
 }
 
 impl DiskApi for DiskMgr {
-    fn create(path: &str) -> Self {
-        let handle = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .truncate(true)
-            .open(std::path::Path::new(path))
-            .unwrap();
-
-        Synchronized::init(DiskMgrCtx {
-            handle,
-            num_writes: 0,
-            num_flushes: 0,
-            last_write: -1,
-        })
-    }
-
-    fn read_page(&self, buf: &mut [u8; PAGE_SIZE], loc: u64) -> std::io::Result<()> {
-        let inner = self.inner();
-        buffer::fs::read_bytes(&inner.handle, buf, loc * PAGE_SIZE as u64)?;
+    fn create(path: &str) -> Result<Self, StorageError> {
+        DiskMgr::create_with_durability(path, DurabilityMode::default())
+    }
+
+    /// Create a new, empty data file at `path`, replacing any existing file. Only for databases that are meant to start out
+    /// empty; use `open` to reuse an existing one
+    fn create_with_durability(
+        path: &str,
+        durability: DurabilityMode,
+    ) -> Result<Self, StorageError> {
+        DiskMgr::create_with_sync_policy(path, durability, SyncPolicy::default())
+    }
+
+    fn create_with_sync_policy(
+        path: &str,
+        durability: DurabilityMode,
+        policy: SyncPolicy,
+    ) -> Result<Self, StorageError> {
+        DiskMgr::create_with_config(
+            &StorageConfig::for_path(path)
+                .with_durability(durability)
+                .with_sync_policy(policy),
+        )
+    }
+
+    /// Open the data file at `path`, keeping its contents, or create it if it doesn't exist. Pages appended afterwards get ids
+    /// following the last page already in the file
+    fn open(path: &str) -> Result<Self, StorageError> {
+        DiskMgr::open_with_durability(path, DurabilityMode::default())
+    }
+
+    fn open_with_durability(path: &str, durability: DurabilityMode) -> Result<Self, StorageError> {
+        DiskMgr::open_with_config(&StorageConfig::for_path(path).with_durability(durability))
+    }
+
+    /// Create a new, empty data file at `config.data_path()`, with the configured durability mode and sync policy (see
+    /// `create_with_durability`)
+    fn create_with_config(config: &StorageConfig) -> Result<Self, StorageError> {
+        Ok(open_file(config, true)?)
+    }
+
+    /// Open the data file at `config.data_path()`, keeping its contents (see `open`)
+    fn open_with_config(config: &StorageConfig) -> Result<Self, StorageError> {
+        Ok(open_file(config, false)?)
+    }
+
+    /// Read a whole page and verify its checksum. Fails with `StorageError::PageNotFound` if the file ends before the page
+    /// does, and with `StorageError::Corruption` if the checksum doesn't match
+    fn read_page(&self, buf: &mut [u8; PAGE_SIZE], loc: u64) -> Result<(), StorageError> {
+        let read = trace::io("read", loc as PageId, || {
+            buffer::fs::read_bytes(&self.file.handle, buf, loc * PAGE_SIZE as u64)
+        });
+        match read {
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                Err(StorageError::PageNotFound(loc as PageId))
+            }
+            result => {
+                result?;
+                verify(buf, loc as PageId)
+            }
+        }
+    }
+
+    /// Whether the file holds all of `page_id`, so that reading it can only fail because of an IO error
+    fn read_page_exists(&self, page_id: PageId) -> bool {
+        page_exists(&self.file.handle, page_id)
+    }
+
+    /// Write a page in place. A page already in the file is written without the latch, so the write runs alongside reads
+    /// and other writes. A page past the end of the file is written under the latch, so it can't race an append for its id
+    fn write_page(&self, buf: &[u8; PAGE_SIZE], loc: u64) -> Result<(), StorageError> {
+        let page_id = loc as PageId;
+        if !page_exists(&self.file.handle, page_id) {
+            return Ok(trace::io("write", page_id, || {
+                self.ctx.with_mut(|inner| inner.write_page(buf, loc))
+            })?);
+        }
+        self.ctx.with_mut(|inner| inner.begin_write())?;
+        let buf = stamped(buf, page_id, self.file.checksum);
+        let written = trace::io("write", page_id, || {
+            buffer::fs::write_bytes(&self.file.handle, &buf, loc * PAGE_SIZE as u64)
+        });
+        if written.is_ok() {
+            self.file.wrote(1, page_id);
+        }
+        Ok(self
+            .ctx
+            .with_mut(|inner| inner.finish_write(page_id, written))?)
+    }
+
+    fn append_page(&self, buf: &[u8; PAGE_SIZE]) -> Result<PageId, StorageError> {
+        Ok(trace::io("append", INVALID_PAGE_ID, || {
+            self.ctx.with_mut(|inner| inner.append_page(buf))
+        })?)
+    }
+
+    /// Write a batch of pages, in any order. Runs of consecutive page ids go out in a single vectored write, and the batch is
+    /// synced (per the sync policy) once at the end rather than after every page
+    fn write_pages(&self, pages: &[(PageId, &[u8; PAGE_SIZE])]) -> Result<(), StorageError> {
+        let mut inner = self.ctx.exclusive();
+        inner.begin_write()?;
+        let mut sorted: Vec<(PageId, Page)> = pages
+            .iter()
+            .map(|(page_id, buf)| (*page_id, stamped(buf, *page_id, self.file.checksum)))
+            .collect();
+        sorted.sort_by_key(|(page_id, _)| *page_id);
+        for run in sorted.chunk_by(|(a, _), (b, _)| a + 1 == *b) {
+            inner
+                .unsynced
+                .extend(run.iter().map(|(page_id, _)| *page_id));
+            let bufs: Vec<&[u8; PAGE_SIZE]> = run.iter().map(|(_, buf)| buf).collect();
+            let offset = run[0].0 as u64 * PAGE_SIZE as u64;
+            let written = trace::io("write", run[0].0, || {
+                buffer::fs::write_run(&inner.file.handle, &bufs, offset)
+            });
+            if let Err(err) = written {
+                return Err(inner.write_failed(err).into());
+            }
+            inner.file.wrote(run.len(), run[run.len() - 1].0);
+        }
+        Ok(inner.sync_after_write()?)
+    }
+
+    /// Write a batch of pages, in any order, with each run of consecutive page ids written (as a single vectored write) on the
+    /// rayon pool, concurrently with the other runs. The latch is only held to check the disk manager is writable and to
+    /// update the bookkeeping, so the writes also run alongside reads and other batches. Never syncs, whatever the sync
+    /// policy. Callers must not write the same page from two batches at once
+    fn write_pages_concurrent(
+        &self,
+        pages: &[(PageId, &[u8; PAGE_SIZE])],
+    ) -> Result<(), StorageError> {
+        let mut sorted: Vec<(PageId, Page)> = pages
+            .iter()
+            .map(|(page_id, buf)| (*page_id, stamped(buf, *page_id, self.file.checksum)))
+            .collect();
+        sorted.sort_by_key(|(page_id, _)| *page_id);
+        let Some((last, _)) = sorted.last() else {
+            return Ok(());
+        };
+        let last = *last;
+        {
+            let mut inner = self.ctx.exclusive();
+            inner.begin_write()?;
+            // marked before the writes, so a sync that fails while they run reports these pages as suspect
+            inner
+                .unsynced
+                .extend(sorted.iter().map(|(page_id, _)| *page_id));
+        }
+        let handle = &self.file.handle;
+        let runs: Vec<&[(PageId, Page)]> = sorted.chunk_by(|(a, _), (b, _)| a + 1 == *b).collect();
+        let written = runs.par_iter().try_for_each(|run| {
+            let bufs: Vec<&[u8; PAGE_SIZE]> = run.iter().map(|(_, buf)| buf).collect();
+            let offset = run[0].0 as u64 * PAGE_SIZE as u64;
+            trace::io("write", run[0].0, || {
+                buffer::fs::write_run(handle, &bufs, offset)
+            })
+        });
+        let mut inner = self.ctx.exclusive();
+        if let Err(err) = written {
+            return Err(inner.write_failed(err).into());
+        }
+        self.file.wrote(sorted.len(), last);
         Ok(())
     }
 
-    fn write_page(&self, buf: &[u8; PAGE_SIZE], loc: u64) -> std::io::Result<()> {
-        let mut inner = self.inner();
-        buffer::fs::write_bytes(&inner.handle, buf, loc * PAGE_SIZE as u64)?;
-        inner.num_writes += 1;
-        inner.handle.sync_all()?;
-        inner.num_flushes += 1;
-        inner.last_write = loc as isize;
+    /// Read a batch of pages, in any order, coalescing runs of consecutive page ids like `write_pages`. Fails like `read_page`
+    /// on the first page that lies past the end of the file or fails its checksum
+    fn read_pages(&self, pages: &mut [(PageId, &mut [u8; PAGE_SIZE])]) -> Result<(), StorageError> {
+        let handle = &self.file.handle;
+        let mut sorted: Vec<(PageId, &mut [u8; PAGE_SIZE])> = pages
+            .iter_mut()
+            .map(|(page_id, buf)| (*page_id, &mut **buf))
+            .collect();
+        sorted.sort_by_key(|(page_id, _)| *page_id);
+        for run in sorted.chunk_by_mut(|(a, _), (b, _)| a + 1 == *b) {
+            let first = run[0].0;
+            let mut bufs: Vec<&mut [u8; PAGE_SIZE]> =
+                run.iter_mut().map(|(_, buf)| &mut **buf).collect();
+            let read = trace::io("read", first, || {
+                buffer::fs::read_run(handle, &mut bufs, first as u64 * PAGE_SIZE as u64)
+            });
+            match read {
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    let missing = (first..first + run.len() as PageId)
+                        .find(|page_id| !page_exists(handle, *page_id))
+                        .unwrap_or(first);
+                    return Err(StorageError::PageNotFound(missing));
+                }
+                result => result?,
+            }
+            for (page_id, buf) in run.iter() {
+                verify(buf, *page_id)?;
+            }
+        }
         Ok(())
     }
 
-    fn append_page(&self, buf: &[u8; PAGE_SIZE]) -> std::io::Result<PageId> {
-        let mut inner = self.inner();
-        let page_id = buffer::fs::append_bytes(&inner.handle, &buf)?;
-        inner.num_writes += 1;
-        inner.handle.sync_all()?;
-        inner.num_flushes += 1;
-        inner.last_write = page_id;
+    /// Number of whole pages in the file, which is also the id the next appended page gets
+    fn num_pages(&self) -> Result<usize, StorageError> {
+        Ok(self.file.num_pages()?)
+    }
+
+    /// Store `buf` in a free page, reusing the lowest page freed by `deallocate_page` if there is one and appending to the
+    /// file otherwise. Returns the page's id
+    fn allocate_page(&self, buf: &[u8; PAGE_SIZE]) -> Result<PageId, StorageError> {
+        let mut inner = self.ctx.exclusive();
+        inner.begin_write()?;
+        let Some(page_id) = inner.free_map.first_free() else {
+            return Ok(inner.append_page(buf)?);
+        };
+        // in use before it's overwritten, so a crash in between leaks the page rather than handing it out twice
+        inner.free_map.set_free(page_id, false)?;
+        inner.write_page(buf, page_id as u64)?;
         Ok(page_id)
     }
 
-    fn inner(&self) -> &mut DiskMgrCtx {
-        unsafe { &mut *self.data_ptr() }
+    /// Give `page_id` back, to be reused by a later `allocate_page`. Its contents are left as they are until then. Freeing a
+    /// page that is already free, or isn't in the file, does nothing
+    fn deallocate_page(&self, page_id: PageId) -> Result<(), StorageError> {
+        let mut inner = self.ctx.exclusive();
+        inner.begin_write()?;
+        if inner.free_map.is_free(page_id) || !page_exists(&inner.file.handle, page_id) {
+            return Ok(());
+        }
+        Ok(inner.free_map.set_free(page_id, true)?)
+    }
+
+    /// Pages freed by `deallocate_page` and not reused yet, in order
+    fn free_pages(&self) -> Vec<PageId> {
+        self.ctx.with(|inner| inner.free_map.free_pages())
+    }
+
+    /// Shrink the file by cutting off the free pages at its end. Returns the number of pages removed. Free pages with pages in
+    /// use after them stay in the file, to be reused by `allocate_page`
+    fn vacuum(&self) -> Result<usize, StorageError> {
+        let mut inner = self.ctx.exclusive();
+        inner.begin_write()?;
+        let num_pages = inner.file.num_pages()?;
+        let mut end = num_pages as PageId;
+        while end > 0 && inner.free_map.is_free(end - 1) {
+            end -= 1;
+        }
+        if end == num_pages as PageId {
+            return Ok(0);
+        }
+        // the file shrinks before the map forgets the pages. A crash in between leaves free bits past the end of the file,
+        // which are dropped the next time the file is opened
+        inner.file.handle.set_len(end as u64 * PAGE_SIZE as u64)?;
+        inner.sync()?;
+        inner.free_map.truncate(end)?;
+        Ok(num_pages - end as usize)
+    }
+
+    /// Check every page in the file against its checksum and return the ones that fail, for offline integrity checks. Only
+    /// IO errors are returned as errors
+    fn verify_all(&self) -> Result<Vec<PageId>, StorageError> {
+        let mut corrupted = Vec::new();
+        let mut buf = page::empty();
+        for page_id in 0..self.num_pages()? as PageId {
+            match self.read_page(&mut buf, page_id as u64) {
+                Err(StorageError::Corruption(_)) => corrupted.push(page_id),
+                result => result?,
+            }
+        }
+        Ok(corrupted)
+    }
+
+    /// Make every page written so far durable, whatever the sync policy (except `Never`). Does nothing if there's nothing
+    /// to sync
+    fn sync(&self) -> Result<(), StorageError> {
+        let mut inner = self.ctx.exclusive();
+        inner.check_writable()?;
+        if inner.policy == SyncPolicy::Never || inner.unsynced.is_empty() {
+            return Ok(());
+        }
+        Ok(inner.sync()?)
+    }
+
+    fn sync_policy(&self) -> SyncPolicy {
+        self.ctx.with(|inner| inner.policy)
+    }
+
+    /// Whether a failed sync has put the disk manager into the recovery-required state. Writes are refused until the
+    /// process restarts and runs recovery.
+    fn recovery_required(&self) -> bool {
+        self.ctx.with(|inner| inner.recovery_required)
+    }
+
+    /// Pages that may not have reached disk because the sync covering them failed
+    fn suspect_pages(&self) -> Vec<PageId> {
+        let mut pages: Vec<PageId> = self
+            .ctx
+            .with(|inner| inner.suspect.iter().copied().collect());
+        pages.sort_unstable();
+        pages
+    }
+
+    fn stats(&self) -> DiskStats {
+        DiskStats {
+            writes: self.file.num_writes.load(Ordering::Relaxed),
+            flushes: self.file.num_flushes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Sync the data file and leave a clean-shutdown marker next to it (see `marker_path`), telling the next `open` that
+    /// every page written so far is on disk. Writing to the file afterwards takes the marker back. With `SyncPolicy::Never`
+    /// nothing is synced, so no marker is left either. Fails, leaving no marker, if the disk manager requires recovery
+    fn close(&self) -> Result<(), StorageError> {
+        let mut inner = self.ctx.exclusive();
+        inner.check_writable()?;
+        if inner.policy == SyncPolicy::Never {
+            return Ok(());
+        }
+        if !inner.unsynced.is_empty() {
+            inner.sync()?;
+        }
+        File::create(&inner.marker)?.sync_all()?;
+        buffer::fs::sync_parent(&inner.marker)?;
+        inner.marked = true;
+        Ok(())
+    }
+
+    /// Whether the data file had a clean-shutdown marker when it was opened, and nothing has been written to it since
+    fn clean_shutdown(&self) -> bool {
+        self.ctx.with(|inner| inner.clean)
     }
 }
 
@@ -77,9 +772,10 @@ mod tests {
     use std::sync::Arc;
 
     use super::*;
-    use crate::shared::{cwd, Song};
+    use crate::shared::cwd;
     use crate::storage::buffer::io;
-    use crate::sync::{BinarySemaphore, BinarySemaphoreMethods as _};
+    use crate::sync::{CountingSemaphore, CountingSemaphoreMethods as _};
+    use crate::testing::Song;
 
     fn setup() -> std::io::Result<String> {
         let dir = cwd() + "/tests/diskmgr_tests";
@@ -93,40 +789,33 @@ mod tests {
         Ok(())
     }
 
-    fn write_song(mgr: &DiskMgr, song: &Song, sem: &BinarySemaphore) -> std::io::Result<()> {
-        mgr.latch();
-        let inner = unsafe { &mut *mgr.data_ptr() };
-        if inner.num_writes >= 5 {
-            sem.post();
-            return Ok(());
-        }
+    fn write_song(mgr: &DiskMgr, song: &Song, sem: &CountingSemaphore) -> Result<(), StorageError> {
         let buf = io::to_buffer(song).unwrap();
-        mgr.write_page(&buf, song.id as u64)?;
-        println!("written song with id {}", song.id);
-        mgr.unlatch();
-        Ok(())
+        let written = mgr.write_page(&buf, song.id as u64);
+        sem.post();
+        written
     }
 
-    fn read_song(mgr: &DiskMgr) -> std::io::Result<()> {
-        mgr.latch();
-        let inner = unsafe { &*mgr.data_ptr() };
+    fn read_song(mgr: &DiskMgr) -> Result<(), StorageError> {
+        let last_write = mgr.file.last_write.load(Ordering::Acquire);
+        if last_write < 0 {
+            return Ok(());
+        }
         let mut buf = [0u8; PAGE_SIZE];
-        mgr.read_page(&mut buf, inner.last_write as u64)?;
+        mgr.read_page(&mut buf, last_write as u64)?;
         let decoded: Song = io::from_buffer(&buf).unwrap();
-        println!("last written: {}", inner.last_write);
-        println!("read: {}", decoded);
-        mgr.unlatch();
+        assert!(decoded.id as isize == last_write);
         Ok(())
     }
 
     #[test]
     fn test_concurrent_diskmgr() {
         let setup_result = setup();
-        assert!(!setup_result.is_err());
+        assert!(setup_result.is_ok());
         let path = setup_result.unwrap();
         let pool = ThreadPoolBuilder::new().num_threads(20).build().unwrap();
-        let sem = BinarySemaphore::init(false);
-        let diskmgr = DiskMgr::create(&path);
+        let sem = CountingSemaphore::init(0);
+        let diskmgr = DiskMgr::create(&path).unwrap();
 
         let sweater_weather = Song::new(1, "Sweater Weather", "The Neighbourhood");
         let softcore = Song::new(2, "Softcore", "The Neighbourhood");
@@ -168,19 +857,344 @@ mod tests {
             let songs = songs.clone();
             pool.spawn(move || {
                 let song = songs[i];
-                assert!(!write_song(&diskmgr, &song, &sem).is_err());
+                assert!(write_song(&diskmgr, &song, &sem).is_ok());
             })
         }
 
         for _ in 0..songs.len() / 2 {
             let diskmgr = diskmgr.clone();
             pool.spawn(move || {
-                assert!(!read_song(&diskmgr).is_err());
+                assert!(read_song(&diskmgr).is_ok());
             })
         }
 
-        let state = sem.wait();
-        assert!(state);
-        assert!(!cleanup().is_err());
+        for _ in 0..songs.len() / 2 {
+            sem.wait();
+        }
+        assert!(diskmgr.stats().writes == songs.len() / 2);
+        assert!(cleanup().is_ok());
+    }
+
+    #[test]
+    fn test_reads_skip_latch() {
+        let dir = cwd() + "/tests/diskmgr_read_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        let diskmgr = DiskMgr::create(&(dir.clone() + "/test_file.bin")).unwrap();
+        for i in 0..8 {
+            let buf = io::to_buffer(Song::new(i, "Afraid", "The Neighbourhood")).unwrap();
+            diskmgr.append_page(&buf).unwrap();
+        }
+
+        // readers make progress while the latch is held, and don't disturb each other's offsets
+        diskmgr.ctx.latch();
+        assert!(diskmgr.stats().writes == 8);
+        let pool = ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        pool.scope(|s| {
+            for t in 0..8 {
+                let diskmgr = diskmgr.clone();
+                s.spawn(move |_| {
+                    for i in 0..100 {
+                        let page_id = (t + i) % 8;
+                        let mut buf = [0u8; PAGE_SIZE];
+                        diskmgr.read_page(&mut buf, page_id as u64).unwrap();
+                        assert!(io::from_buffer::<Song>(&buf).unwrap().id == page_id);
+                    }
+                });
+            }
+        });
+        diskmgr.ctx.unlatch();
+
+        std::fs::remove_dir_all(std::path::Path::new(&dir)).unwrap();
+    }
+
+    #[test]
+    fn test_read_past_end() {
+        let dir = cwd() + "/tests/diskmgr_eof_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        let path = dir.clone() + "/test_file.bin";
+        let diskmgr = DiskMgr::create(&path).unwrap();
+        let buf = io::to_buffer(Song::new(0, "Softcore", "The Neighbourhood")).unwrap();
+        diskmgr.append_page(&buf).unwrap();
+        assert!(diskmgr.read_page_exists(0));
+        assert!(!diskmgr.read_page_exists(1));
+
+        let mut read = [0u8; PAGE_SIZE];
+        let err = diskmgr.read_page(&mut read, 1).unwrap_err();
+        assert!(matches!(err, StorageError::PageNotFound(page_id) if page_id == 1));
+
+        // a torn append leaves a partial page, which doesn't count as written either
+        let file = OpenOptions::new().append(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut &file, &buf[..100]).unwrap();
+        assert!(!diskmgr.read_page_exists(1));
+        let err = diskmgr.read_page(&mut read, 1).unwrap_err();
+        assert!(err.kind() == std::io::ErrorKind::UnexpectedEof);
+        assert!(matches!(err, StorageError::PageNotFound(_)));
+
+        std::fs::remove_dir_all(std::path::Path::new(&dir)).unwrap();
+    }
+
+    #[test]
+    fn test_fsync_failure_requires_recovery() {
+        let dir = cwd() + "/tests/diskmgr_fsync_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        let diskmgr = DiskMgr::create(&(dir.clone() + "/test_file.bin")).unwrap();
+
+        let buf = io::to_buffer(Song::new(0, "Car Radio", "Twenty-One Pilots")).unwrap();
+        assert!(diskmgr.append_page(&buf).is_ok());
+        assert!(diskmgr.suspect_pages().is_empty());
+
+        diskmgr.ctx.with_mut(|inner| inner.fail_next_sync = true);
+        let err = diskmgr.append_page(&buf).unwrap_err();
+        assert_eq!(fsync_failed(&err).unwrap().pages, vec![1]);
+        assert!(diskmgr.recovery_required());
+
+        // the next sync would succeed, but the failure must not be papered over by a retry
+        let err = diskmgr.write_page(&buf, 1).unwrap_err();
+        assert_eq!(fsync_failed(&err).unwrap().pages, vec![1]);
+        assert_eq!(diskmgr.suspect_pages(), vec![1]);
+
+        std::fs::remove_dir_all(std::path::Path::new(&dir)).unwrap();
+    }
+
+    #[test]
+    fn test_checksums() {
+        let dir = cwd() + "/tests/diskmgr_checksum_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        let path = dir.clone() + "/test_file.bin";
+        let diskmgr = DiskMgr::create(&path).unwrap();
+        for i in 0..4 {
+            let buf = io::to_buffer(Song::new(i, "Reflections", "The Neighbourhood")).unwrap();
+            diskmgr.append_page(&buf).unwrap();
+        }
+        // a page that was never filled in is fine
+        diskmgr.append_page(&page::empty()).unwrap();
+        assert!(diskmgr.verify_all().unwrap().is_empty());
+
+        // flip a byte of page 2 behind the disk manager's back
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        buffer::fs::write_bytes(&file, &[0xffu8; PAGE_SIZE], 3 * PAGE_SIZE as u64).unwrap();
+        let mut torn = page::empty();
+        diskmgr.read_page(&mut torn, 2).unwrap();
+        torn[PAGE_SIZE - 1] ^= 1;
+        buffer::fs::write_bytes(&file, &torn, 2 * PAGE_SIZE as u64).unwrap();
+
+        let mut buf = page::empty();
+        let err = diskmgr.read_page(&mut buf, 2).unwrap_err();
+        assert!(err.kind() == std::io::ErrorKind::InvalidData);
+        assert!(matches!(err, StorageError::Corruption(page_id) if page_id == 2));
+        let mut other = page::empty();
+        let err = diskmgr
+            .read_pages(&mut [(1, &mut buf), (2, &mut other)])
+            .unwrap_err();
+        assert!(matches!(err, StorageError::Corruption(page_id) if page_id == 2));
+        assert!(diskmgr.verify_all().unwrap() == vec![2, 3]);
+
+        // rewriting a page repairs it
+        diskmgr.write_page(&torn, 2).unwrap();
+        assert!(diskmgr.verify_all().unwrap() == vec![3]);
+
+        // a valid page in the wrong place is caught too
+        diskmgr.read_page(&mut buf, 1).unwrap();
+        assert!(page::page_id(&buf) == 1);
+        buffer::fs::write_bytes(&file, &buf, 3 * PAGE_SIZE as u64).unwrap();
+        let err = diskmgr.read_page(&mut buf, 3).unwrap_err();
+        assert!(matches!(err, StorageError::Corruption(page_id) if page_id == 3));
+
+        std::fs::remove_dir_all(std::path::Path::new(&dir)).unwrap();
+    }
+
+    #[test]
+    fn test_checksum_algorithms() {
+        let dir = cwd() + "/tests/diskmgr_checksum_algorithm_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        let path = dir.clone() + "/test_file.bin";
+        let config = StorageConfig::for_path(&path).with_checksum(ChecksumAlgorithm::XxHash64);
+        let diskmgr = DiskMgr::create_with_config(&config).unwrap();
+        let song = io::to_buffer(Song::new(1, "Softcore", "The Neighbourhood")).unwrap();
+        for _ in 0..3 {
+            diskmgr.append_page(&song).unwrap();
+        }
+        let mut buf = page::empty();
+        diskmgr.read_page(&mut buf, 0).unwrap();
+        assert!(page::checksum_algorithm(&buf) == Some(ChecksumAlgorithm::XxHash64));
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        buf[PAGE_SIZE - 1] ^= 1;
+        buffer::fs::write_bytes(&file, &buf, 0).unwrap();
+        assert!(diskmgr.verify_all().unwrap() == vec![0]);
+        drop(diskmgr);
+
+        // pages keep the algorithm they were written with, whatever the file is opened with
+        let config = config.with_checksum(ChecksumAlgorithm::Off);
+        let diskmgr = DiskMgr::open_with_config(&config).unwrap();
+        assert!(diskmgr.verify_all().unwrap() == vec![0]);
+        diskmgr.write_page(&buf, 0).unwrap();
+        diskmgr.read_page(&mut buf, 0).unwrap();
+        assert!(page::checksum_algorithm(&buf) == Some(ChecksumAlgorithm::Off));
+        // without a checksum a flipped byte goes unnoticed, but a page in the wrong place doesn't
+        buf[PAGE_SIZE - 1] ^= 1;
+        buffer::fs::write_bytes(&file, &buf, 0).unwrap();
+        buffer::fs::write_bytes(&file, &buf, PAGE_SIZE as u64).unwrap();
+        assert!(diskmgr.verify_all().unwrap() == vec![1]);
+
+        // an algorithm this version doesn't know fails verification
+        diskmgr.read_page(&mut buf, 2).unwrap();
+        buf[page::PAGE_CHECKSUM_ALGORITHM_OFFSET] = 0xff;
+        buffer::fs::write_bytes(&file, &buf, 2 * PAGE_SIZE as u64).unwrap();
+        assert!(diskmgr.verify_all().unwrap() == vec![1, 2]);
+
+        std::fs::remove_dir_all(std::path::Path::new(&dir)).unwrap();
+    }
+
+    #[test]
+    fn test_deallocate_and_vacuum() {
+        let dir = cwd() + "/tests/diskmgr_vacuum_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        let path = dir.clone() + "/test_file.bin";
+        let diskmgr = DiskMgr::create(&path).unwrap();
+        for i in 0..6 {
+            let buf = io::to_buffer(Song::new(i, "Afraid", "The Neighbourhood")).unwrap();
+            assert!(diskmgr.allocate_page(&buf).unwrap() == i as PageId);
+        }
+        for page_id in [1, 4, 5] {
+            diskmgr.deallocate_page(page_id).unwrap();
+        }
+        // freeing twice, or past the end of the file, changes nothing
+        diskmgr.deallocate_page(4).unwrap();
+        diskmgr.deallocate_page(10).unwrap();
+        assert!(diskmgr.free_pages() == vec![1, 4, 5]);
+        drop(diskmgr);
+
+        // the map survives a restart, and the lowest free page is reused first
+        let diskmgr = DiskMgr::open(&path).unwrap();
+        let buf = io::to_buffer(Song::new(10, "Afraid", "The Neighbourhood")).unwrap();
+        assert!(diskmgr.allocate_page(&buf).unwrap() == 1);
+        let mut read = page::empty();
+        diskmgr.read_page(&mut read, 1).unwrap();
+        assert!(io::from_buffer::<Song>(&read).unwrap().id == 10);
+
+        // only the free tail is cut off
+        diskmgr.deallocate_page(2).unwrap();
+        assert!(diskmgr.vacuum().unwrap() == 2);
+        assert!(diskmgr.num_pages().unwrap() == 4);
+        assert!(diskmgr.free_pages() == vec![2]);
+        assert!(diskmgr.vacuum().unwrap() == 0);
+        assert!(diskmgr.allocate_page(&buf).unwrap() == 2);
+        assert!(diskmgr.allocate_page(&buf).unwrap() == 4);
+
+        // creating the file again starts with an empty map
+        drop(diskmgr);
+        let diskmgr = DiskMgr::create(&path).unwrap();
+        assert!(diskmgr.free_pages().is_empty());
+        std::fs::remove_dir_all(std::path::Path::new(&dir)).unwrap();
+    }
+
+    #[test]
+    fn test_batched_io() {
+        let dir = cwd() + "/tests/diskmgr_batch_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        let diskmgr = DiskMgr::create(&(dir.clone() + "/test_file.bin")).unwrap();
+        let pages: Vec<Page> = (0..10)
+            .map(|i| io::to_buffer(Song::new(i, "Wires", "The Neighbourhood")).unwrap())
+            .collect();
+        // two runs (0..4 and 6..10), given out of order, synced once
+        let batch: Vec<(PageId, &Page)> = [7, 0, 1, 8, 2, 9, 3, 6]
+            .into_iter()
+            .map(|i| (i as PageId, &pages[i]))
+            .collect();
+        diskmgr.write_pages(&batch).unwrap();
+        assert!(
+            diskmgr.stats()
+                == DiskStats {
+                    writes: 8,
+                    flushes: 1
+                }
+        );
+
+        let mut read = [page::empty(); 3];
+        let [a, b, c] = &mut read;
+        diskmgr.read_pages(&mut [(9, a), (2, b), (3, c)]).unwrap();
+        let ids: Vec<i32> = read
+            .iter()
+            .map(|buf| io::from_buffer::<Song>(buf).unwrap().id)
+            .collect();
+        assert!(ids == vec![9, 2, 3]);
+
+        let [a, b, _] = &mut read;
+        let err = diskmgr.read_pages(&mut [(9, a), (10, b)]).unwrap_err();
+        assert!(matches!(err, StorageError::PageNotFound(page_id) if page_id == 10));
+
+        std::fs::remove_dir_all(std::path::Path::new(&dir)).unwrap();
+    }
+
+    #[test]
+    fn test_sync_policies() {
+        let dir = cwd() + "/tests/diskmgr_policy_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        let buf = io::to_buffer(Song::new(0, "Stargazing", "The Neighbourhood")).unwrap();
+
+        let policies = [
+            (SyncPolicy::Always, 3, 3),
+            (SyncPolicy::OnFlush, 0, 1),
+            (SyncPolicy::Interval(Duration::from_secs(3600)), 0, 1),
+            (SyncPolicy::Interval(Duration::ZERO), 3, 3),
+            (SyncPolicy::Never, 0, 0),
+        ];
+        for (i, (policy, after_writes, after_sync)) in policies.into_iter().enumerate() {
+            let path = format!("{}/test_file_{}.bin", dir, i);
+            let diskmgr =
+                DiskMgr::create_with_sync_policy(&path, DurabilityMode::Full, policy).unwrap();
+            for _ in 0..3 {
+                diskmgr.append_page(&buf).unwrap();
+            }
+            assert!(diskmgr.stats().flushes == after_writes);
+            diskmgr.sync().unwrap();
+            assert!(diskmgr.stats().flushes == after_sync);
+            // nothing new to sync
+            diskmgr.sync().unwrap();
+            assert!(diskmgr.stats().flushes == after_sync);
+        }
+
+        // a failed explicit sync is as fatal as any other
+        let diskmgr = DiskMgr::create_with_sync_policy(
+            &format!("{}/test_file_failed.bin", dir),
+            DurabilityMode::Full,
+            SyncPolicy::OnFlush,
+        )
+        .unwrap();
+        diskmgr.append_page(&buf).unwrap();
+        diskmgr.ctx.with_mut(|inner| inner.fail_next_sync = true);
+        let err = diskmgr.sync().unwrap_err();
+        assert!(fsync_failed(&err).unwrap().pages == vec![0]);
+        assert!(diskmgr.recovery_required());
+
+        std::fs::remove_dir_all(std::path::Path::new(&dir)).unwrap();
+    }
+
+    #[test]
+    fn test_durability_modes() {
+        let dir = cwd() + "/tests/diskmgr_durability_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+
+        let modes = [
+            DurabilityMode::Full,
+            DurabilityMode::Data,
+            DurabilityMode::DSync,
+        ];
+        for (i, mode) in modes.iter().enumerate() {
+            let path = format!("{}/test_file_{}.bin", dir, i);
+            let diskmgr = DiskMgr::create_with_durability(&path, *mode).unwrap();
+            let song = Song::new(i as i32, "Nervous", "The Neighbourhood");
+            let buf = io::to_buffer(song).unwrap();
+            let page_id = diskmgr.append_page(&buf).unwrap();
+            diskmgr.write_page(&buf, page_id as u64).unwrap();
+
+            let mut read = [0u8; PAGE_SIZE];
+            diskmgr.read_page(&mut read, page_id as u64).unwrap();
+            let decoded: Song = io::from_buffer(&read).unwrap();
+            assert_eq!(decoded.id, i as i32);
+            assert!(diskmgr.ctx.with(|inner| inner.unsynced.is_empty()));
+        }
+
+        std::fs::remove_dir_all(std::path::Path::new(&dir)).unwrap();
     }
 }
@@ -0,0 +1,407 @@
+/// This file implements a disk manager that fails on purpose, for testing recovery and the buffer pool against IO errors.
+/// `FaultInjectingDiskMgr` wraps a `DiskMgr` and passes every call through, except that it can be told to fail the Nth page
+/// write, to return short reads, or to crash: `crash` throws away everything written since the last successful sync, leaving
+/// the data file as it would be found after a power loss, and fails every later read and write. It's compiled for tests and
+/// with the `fault-injection` feature.
+///
+/// Unsynced writes are tracked by keeping the synced contents of each page the first time it's overwritten after a sync, and
+/// the length of the file at that sync. A crash writes those pages back and cuts the file to that length. The free page map
+/// lives in its own file and isn't rolled back.
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+use crate::shared::{PageId, PAGE_SIZE};
+use crate::storage::buffer;
+use crate::storage::buffer::diskmgr::{
+    verify, DiskApi, DiskMgr, DiskStats, DurabilityMode, SyncPolicy,
+};
+use crate::storage::buffer::page::{self, Page};
+use crate::storage::config::StorageConfig;
+use crate::storage::error::StorageError;
+use crate::sync::{Access as _, Latch as _, Synchronized};
+
+#[derive(Default)]
+struct FaultState {
+    // page writes left until the one that fails, if one is set to fail
+    fail_in: Option<usize>,
+    short_reads: Option<usize>,
+    crashed: bool,
+    // flushes seen so far, to notice syncs done by the wrapped disk manager
+    flushes: usize,
+    synced_len: u64,
+    preimages: HashMap<PageId, Page>,
+}
+
+/// Disk manager that injects IO faults. Clones refer to the same disk manager and the same faults
+#[derive(Clone)]
+pub struct FaultInjectingDiskMgr {
+    inner: DiskMgr,
+    // a second handle on the data file, to save and restore synced pages behind the wrapped disk manager's back
+    handle: Synchronized<File>,
+    faults: Synchronized<FaultState>,
+}
+
+fn injected() -> StorageError {
+    StorageError::Io(std::io::Error::from_raw_os_error(5))
+}
+
+fn crashed() -> StorageError {
+    StorageError::Io(std::io::Error::other("simulated crash"))
+}
+
+impl FaultInjectingDiskMgr {
+    fn wrap(inner: DiskMgr, path: &Path, synced: bool) -> Result<Self, StorageError> {
+        let handle = OpenOptions::new().read(true).write(true).open(path)?;
+        let synced_len = if synced { handle.metadata()?.len() } else { 0 };
+        let flushes = inner.stats().flushes;
+        Ok(FaultInjectingDiskMgr {
+            inner,
+            handle: Synchronized::init(handle),
+            faults: Synchronized::init(FaultState {
+                flushes,
+                synced_len,
+                ..FaultState::default()
+            }),
+        })
+    }
+
+    /// The wrapped disk manager
+    pub fn inner(&self) -> &DiskMgr {
+        &self.inner
+    }
+
+    /// Fail the `n`th page write from now on (1 is the next one) with `EIO`, without writing anything. Each page of a batch
+    /// counts as a write, and a batch containing the failing write fails as a whole. `0` clears a pending failure
+    pub fn fail_nth_write(&self, n: usize) {
+        self.faults
+            .with_mut(|faults| faults.fail_in = (n > 0).then_some(n));
+    }
+
+    /// Make every read return only the first `len` bytes of a page, the rest zeroed, as if the read came up short. A page that
+    /// no longer matches its checksum fails with `StorageError::Corruption`. `None` makes reads whole again
+    pub fn short_reads(&self, len: Option<usize>) {
+        self.faults
+            .with_mut(|faults| faults.short_reads = len.map(|len| len.min(PAGE_SIZE)));
+    }
+
+    /// Drop every write that hasn't been synced, as a power loss would, and fail every read and write from then on. Reopen
+    /// the file with a new disk manager to see what survived
+    pub fn crash(&self) -> Result<(), StorageError> {
+        let mut faults = self.faults.exclusive();
+        self.synced(&mut faults);
+        let handle = self.handle.exclusive();
+        for (page_id, buf) in faults.preimages.drain() {
+            buffer::fs::write_bytes(&handle, &buf, page_id as u64 * PAGE_SIZE as u64)?;
+        }
+        handle.set_len(faults.synced_len)?;
+        handle.sync_all()?;
+        faults.crashed = true;
+        Ok(())
+    }
+
+    /// Forget the saved pages once the wrapped disk manager has synced, since they can't be lost anymore
+    fn synced(&self, faults: &mut FaultState) {
+        let flushes = self.inner.stats().flushes;
+        if flushes == faults.flushes {
+            return;
+        }
+        faults.flushes = flushes;
+        faults.preimages.clear();
+        if let Ok(metadata) = self.handle.exclusive().metadata() {
+            faults.synced_len = metadata.len();
+        }
+    }
+
+    /// Get ready to write `pages`: fail if the crash already happened or one of the writes is set to fail, otherwise save
+    /// the synced contents of the pages about to be overwritten
+    fn before_write(&self, pages: &[PageId]) -> Result<(), StorageError> {
+        let mut faults = self.faults.exclusive();
+        if faults.crashed {
+            return Err(crashed());
+        }
+        if let Some(fail_in) = faults.fail_in {
+            if fail_in <= pages.len() {
+                faults.fail_in = None;
+                return Err(injected());
+            }
+            faults.fail_in = Some(fail_in - pages.len());
+        }
+        self.synced(&mut faults);
+        let handle = self.handle.exclusive();
+        for &page_id in pages {
+            let offset = page_id as u64 * PAGE_SIZE as u64;
+            if offset >= faults.synced_len || faults.preimages.contains_key(&page_id) {
+                continue;
+            }
+            let mut buf = page::empty();
+            buffer::fs::read_bytes(&handle, &mut buf, offset)?;
+            faults.preimages.insert(page_id, buf);
+        }
+        Ok(())
+    }
+
+    /// Check a write has been done, noting any sync it did
+    fn after_write<T>(&self, written: Result<T, StorageError>) -> Result<T, StorageError> {
+        self.faults.with_mut(|faults| self.synced(faults));
+        written
+    }
+
+    fn before_read(&self) -> Result<Option<usize>, StorageError> {
+        self.faults.with(|faults| {
+            if faults.crashed {
+                return Err(crashed());
+            }
+            Ok(faults.short_reads)
+        })
+    }
+
+    fn next_page_id(&self) -> Result<PageId, StorageError> {
+        Ok(self.inner.num_pages()? as PageId)
+    }
+}
+
+/// Cut a page read down to its first `len` bytes, failing if it no longer passes verification
+fn shorten(buf: &mut [u8; PAGE_SIZE], page_id: PageId, len: usize) -> Result<(), StorageError> {
+    buf[len..].fill(0);
+    verify(buf, page_id)
+}
+
+impl DiskApi for FaultInjectingDiskMgr {
+    fn create(path: &str) -> Result<Self, StorageError> {
+        FaultInjectingDiskMgr::create_with_durability(path, DurabilityMode::default())
+    }
+
+    fn create_with_durability(
+        path: &str,
+        durability: DurabilityMode,
+    ) -> Result<Self, StorageError> {
+        FaultInjectingDiskMgr::create_with_sync_policy(path, durability, SyncPolicy::default())
+    }
+
+    fn create_with_sync_policy(
+        path: &str,
+        durability: DurabilityMode,
+        policy: SyncPolicy,
+    ) -> Result<Self, StorageError> {
+        FaultInjectingDiskMgr::create_with_config(
+            &StorageConfig::for_path(path)
+                .with_durability(durability)
+                .with_sync_policy(policy),
+        )
+    }
+
+    fn open(path: &str) -> Result<Self, StorageError> {
+        FaultInjectingDiskMgr::open_with_durability(path, DurabilityMode::default())
+    }
+
+    fn open_with_durability(path: &str, durability: DurabilityMode) -> Result<Self, StorageError> {
+        FaultInjectingDiskMgr::open_with_config(
+            &StorageConfig::for_path(path).with_durability(durability),
+        )
+    }
+
+    fn create_with_config(config: &StorageConfig) -> Result<Self, StorageError> {
+        let inner = DiskMgr::create_with_config(config)?;
+        FaultInjectingDiskMgr::wrap(inner, &config.data_path(), false)
+    }
+
+    fn open_with_config(config: &StorageConfig) -> Result<Self, StorageError> {
+        let inner = DiskMgr::open_with_config(config)?;
+        FaultInjectingDiskMgr::wrap(inner, &config.data_path(), true)
+    }
+
+    fn read_page(&self, buf: &mut [u8; PAGE_SIZE], offset: u64) -> Result<(), StorageError> {
+        let short = self.before_read()?;
+        self.inner.read_page(buf, offset)?;
+        match short {
+            Some(len) => shorten(buf, offset as PageId, len),
+            None => Ok(()),
+        }
+    }
+
+    fn read_page_exists(&self, page_id: PageId) -> bool {
+        self.inner.read_page_exists(page_id)
+    }
+
+    fn write_page(&self, buf: &[u8; PAGE_SIZE], offset: u64) -> Result<(), StorageError> {
+        self.before_write(&[offset as PageId])?;
+        self.after_write(self.inner.write_page(buf, offset))
+    }
+
+    fn append_page(&self, buf: &[u8; PAGE_SIZE]) -> Result<PageId, StorageError> {
+        self.before_write(&[self.next_page_id()?])?;
+        self.after_write(self.inner.append_page(buf))
+    }
+
+    fn allocate_page(&self, buf: &[u8; PAGE_SIZE]) -> Result<PageId, StorageError> {
+        // the lowest free page is the one that gets reused, if there is one
+        let page_id = match self.inner.free_pages().into_iter().min() {
+            Some(page_id) => page_id,
+            None => self.next_page_id()?,
+        };
+        self.before_write(&[page_id])?;
+        self.after_write(self.inner.allocate_page(buf))
+    }
+
+    fn deallocate_page(&self, page_id: PageId) -> Result<(), StorageError> {
+        self.before_write(&[])?;
+        self.inner.deallocate_page(page_id)
+    }
+
+    fn free_pages(&self) -> Vec<PageId> {
+        self.inner.free_pages()
+    }
+
+    fn vacuum(&self) -> Result<usize, StorageError> {
+        self.before_write(&[])?;
+        self.after_write(self.inner.vacuum())
+    }
+
+    fn write_pages(&self, pages: &[(PageId, &[u8; PAGE_SIZE])]) -> Result<(), StorageError> {
+        let page_ids: Vec<PageId> = pages.iter().map(|(page_id, _)| *page_id).collect();
+        self.before_write(&page_ids)?;
+        self.after_write(self.inner.write_pages(pages))
+    }
+
+    fn read_pages(&self, pages: &mut [(PageId, &mut [u8; PAGE_SIZE])]) -> Result<(), StorageError> {
+        let short = self.before_read()?;
+        self.inner.read_pages(pages)?;
+        if let Some(len) = short {
+            for (page_id, buf) in pages.iter_mut() {
+                shorten(buf, *page_id, len)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn num_pages(&self) -> Result<usize, StorageError> {
+        self.inner.num_pages()
+    }
+
+    fn verify_all(&self) -> Result<Vec<PageId>, StorageError> {
+        self.before_read()?;
+        self.inner.verify_all()
+    }
+
+    fn sync(&self) -> Result<(), StorageError> {
+        self.before_write(&[])?;
+        self.after_write(self.inner.sync())
+    }
+
+    fn sync_policy(&self) -> SyncPolicy {
+        self.inner.sync_policy()
+    }
+
+    fn recovery_required(&self) -> bool {
+        self.inner.recovery_required()
+    }
+
+    fn suspect_pages(&self) -> Vec<PageId> {
+        self.inner.suspect_pages()
+    }
+
+    fn stats(&self) -> DiskStats {
+        self.inner.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::buffer::io;
+    use crate::testing::Song;
+
+    /// A data file in a directory of its own, so the tests can run in parallel
+    fn setup(name: &str) -> (String, String) {
+        let dir = cwd() + "/tests/faults_" + name + "_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.clone() + "/data.bin";
+        (dir, path)
+    }
+
+    fn song_page(id: i32, name: &str) -> Page {
+        io::to_buffer(Song::new(id, name, "Slowdive")).unwrap()
+    }
+
+    fn read_song(mgr: &impl DiskApi, page_id: PageId) -> Result<Song, StorageError> {
+        let mut buf = page::empty();
+        mgr.read_page(&mut buf, page_id as u64)?;
+        io::from_buffer(&buf)
+    }
+
+    #[test]
+    fn test_fail_nth_write() {
+        let (dir, path) = setup("fail_nth_write");
+        let mgr = FaultInjectingDiskMgr::create(&path).unwrap();
+        mgr.fail_nth_write(3);
+        assert!(mgr.append_page(&song_page(0, "Alison")).is_ok());
+        assert!(mgr.append_page(&song_page(1, "When the Sun Hits")).is_ok());
+        // the third write fails and leaves nothing behind, the one after succeeds again
+        assert!(mgr
+            .write_page(&song_page(0, "Souvlaki Space Station"), 0)
+            .is_err());
+        assert!(read_song(&mgr, 0).unwrap().id == 0);
+        assert!(mgr
+            .write_page(&song_page(0, "Souvlaki Space Station"), 0)
+            .is_ok());
+
+        // a batch containing the failing write fails whole
+        mgr.fail_nth_write(2);
+        let (a, b) = (
+            song_page(2, "Machine Gun"),
+            song_page(3, "Catch the Breeze"),
+        );
+        assert!(mgr.write_pages(&[(2, &a), (3, &b)]).is_err());
+        assert!(mgr.num_pages().unwrap() == 2);
+        assert!(mgr.write_pages(&[(2, &a), (3, &b)]).is_ok());
+        assert!(mgr.num_pages().unwrap() == 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_short_reads() {
+        let (dir, path) = setup("short_reads");
+        let mgr = FaultInjectingDiskMgr::create(&path).unwrap();
+        let page_id = mgr.append_page(&song_page(0, "Alison")).unwrap();
+        mgr.short_reads(Some(PAGE_SIZE / 2));
+        // the page header and the song are in the first half, the checksum covers the whole page and still matches
+        assert!(read_song(&mgr, page_id).is_ok());
+        mgr.short_reads(Some(page::PAGE_HEADER_SIZE));
+        let err = read_song(&mgr, page_id).err().unwrap();
+        assert!(matches!(err, StorageError::Corruption(corrupted) if corrupted == page_id));
+        mgr.short_reads(None);
+        assert!(read_song(&mgr, page_id).unwrap().id == 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_crash_drops_unsynced_writes() {
+        let (dir, path) = setup("crash");
+        let mgr = FaultInjectingDiskMgr::create_with_sync_policy(
+            &path,
+            DurabilityMode::default(),
+            SyncPolicy::OnFlush,
+        )
+        .unwrap();
+        for id in 0..3 {
+            mgr.append_page(&song_page(id, "Alison")).unwrap();
+        }
+        mgr.sync().unwrap();
+        // overwritten and appended after the sync, so lost in the crash
+        mgr.write_page(&song_page(10, "Dagger"), 1).unwrap();
+        mgr.append_page(&song_page(11, "Dagger")).unwrap();
+        assert!(read_song(&mgr, 1).unwrap().id == 10);
+        mgr.crash().unwrap();
+        assert!(read_song(&mgr, 1).is_err());
+        assert!(mgr.write_page(&song_page(12, "Dagger"), 0).is_err());
+
+        let reopened = DiskMgr::open(&path).unwrap();
+        assert!(reopened.num_pages().unwrap() == 3);
+        for id in 0..3 {
+            assert!(read_song(&reopened, id).unwrap().id == id as i32);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+/// This file implements the free page map: a bitmap with one bit per page of a data file, set while the page is free. It
+/// lives in its own file next to the data file (`<data file>.fpm`, like a fork), so that reserving its pages doesn't shift
+/// the ids of the data pages, and it's only created once the first page is freed. Every map page has the usual page header,
+/// with its index in the map file as its page id, and is checksummed like a data page.
+///
+/// The map is written through, and synced, on every change. Changes are ordered so that a crash can only leak a page, never
+/// hand one out twice: a page is marked free only after it has been given up, and marked in use before it's reused.
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+
+use crate::shared::{PageId, PAGE_SIZE};
+use crate::storage::buffer;
+use crate::storage::buffer::page::{self, Page, PageType, PAGE_HEADER_SIZE};
+
+pub const FREE_MAP_EXTENSION: &str = "fpm";
+
+/// Data pages covered by one page of the map
+pub const PAGES_PER_MAP_PAGE: usize = (PAGE_SIZE - PAGE_HEADER_SIZE) * 8;
+
+/// Path of the free page map belonging to the data file at `data_path`
+pub fn map_path(data_path: &str) -> String {
+    format!("{}.{}", data_path, FREE_MAP_EXTENSION)
+}
+
+pub struct FreePageMap {
+    path: String,
+    // opened when the map is first written to
+    handle: Option<File>,
+    pages: Vec<Page>,
+    free: BTreeSet<PageId>,
+}
+
+impl FreePageMap {
+    /// Load the map for the data file at `data_path`. With `truncate` (a data file that's being created from scratch) any
+    /// existing map is discarded. Fails with `InvalidData` if a map page fails its checksum
+    pub fn open(data_path: &str, truncate: bool) -> std::io::Result<Self> {
+        let path = map_path(data_path);
+        let mut map = FreePageMap {
+            path,
+            handle: None,
+            pages: Vec::new(),
+            free: BTreeSet::new(),
+        };
+        if truncate {
+            match std::fs::remove_file(&map.path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                _ => return Ok(map),
+            }
+        }
+        let handle = match OpenOptions::new().read(true).write(true).open(&map.path) {
+            Ok(handle) => handle,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(map),
+            Err(err) => return Err(err),
+        };
+        let count = handle.metadata()?.len() as usize / PAGE_SIZE;
+        for index in 0..count {
+            let mut buf = page::empty();
+            buffer::fs::read_bytes(&handle, &mut buf, (index * PAGE_SIZE) as u64)?;
+            if !page::verify_checksum(&buf) || page::page_id(&buf) != index as PageId {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("free page map page {} is corrupted", index),
+                ));
+            }
+            for (byte_index, byte) in buf[PAGE_HEADER_SIZE..].iter().enumerate() {
+                for bit in (0..8).filter(|bit| byte & (1 << bit) != 0) {
+                    map.free
+                        .insert((index * PAGES_PER_MAP_PAGE + byte_index * 8 + bit) as PageId);
+                }
+            }
+            map.pages.push(buf);
+        }
+        map.handle = Some(handle);
+        Ok(map)
+    }
+
+    pub fn is_free(&self, page_id: PageId) -> bool {
+        self.free.contains(&page_id)
+    }
+
+    /// The lowest free page, if any
+    pub fn first_free(&self) -> Option<PageId> {
+        self.free.first().copied()
+    }
+
+    /// Every free page, in order
+    pub fn free_pages(&self) -> Vec<PageId> {
+        self.free.iter().copied().collect()
+    }
+
+    /// Mark `page_id` free or in use, and make the change durable before returning
+    pub fn set_free(&mut self, page_id: PageId, free: bool) -> std::io::Result<()> {
+        self.update(&[page_id], free)
+    }
+
+    /// Forget every page from `page_id` on, e.g. after the data file has been truncated there
+    pub fn truncate(&mut self, page_id: PageId) -> std::io::Result<()> {
+        let dropped: Vec<PageId> = self.free.range(page_id..).copied().collect();
+        self.update(&dropped, false)
+    }
+
+    fn update(&mut self, page_ids: &[PageId], free: bool) -> std::io::Result<()> {
+        let mut touched = BTreeSet::new();
+        for page_id in page_ids {
+            let bit = *page_id as usize;
+            let index = bit / PAGES_PER_MAP_PAGE;
+            while self.pages.len() <= index {
+                let mut map_page = page::empty();
+                page::set_page_type(&mut map_page, PageType::FreePageMap);
+                page::set_page_id(&mut map_page, self.pages.len() as PageId);
+                self.pages.push(map_page);
+            }
+            let byte = PAGE_HEADER_SIZE + (bit % PAGES_PER_MAP_PAGE) / 8;
+            if free {
+                self.pages[index][byte] |= 1 << (bit % 8);
+                self.free.insert(*page_id);
+            } else {
+                self.pages[index][byte] &= !(1 << (bit % 8));
+                self.free.remove(page_id);
+            }
+            touched.insert(index);
+        }
+        if touched.is_empty() {
+            return Ok(());
+        }
+        if self.handle.is_none() {
+            self.handle = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .read(true)
+                    .write(true)
+                    .open(&self.path)?,
+            );
+        }
+        let handle = self.handle.as_ref().unwrap();
+        for index in touched {
+            let map_page = &mut self.pages[index];
+            page::set_checksum(map_page);
+            buffer::fs::write_bytes(handle, map_page, (index * PAGE_SIZE) as u64)?;
+        }
+        handle.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::cwd;
+
+    #[test]
+    fn test_free_page_map() {
+        let dir = cwd() + "/tests/freemap_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let data_path = dir.clone() + "/data.bin";
+        let mut map = FreePageMap::open(&data_path, true).unwrap();
+        assert!(map.first_free().is_none());
+        // nothing is written until a page is freed
+        assert!(!std::path::Path::new(&map_path(&data_path)).exists());
+
+        let far = (PAGES_PER_MAP_PAGE + 3) as PageId;
+        for page_id in [9, 4, far] {
+            map.set_free(page_id, true).unwrap();
+        }
+        map.set_free(9, false).unwrap();
+        assert!(map.first_free() == Some(4));
+        drop(map);
+
+        let mut map = FreePageMap::open(&data_path, false).unwrap();
+        assert!(map.free_pages() == vec![4, far]);
+        map.truncate(5).unwrap();
+        assert!(map.free_pages() == vec![4]);
+        assert!(FreePageMap::open(&data_path, false).unwrap().free_pages() == vec![4]);
+
+        // a torn map page is detected
+        let file = OpenOptions::new()
+            .write(true)
+            .open(map_path(&data_path))
+            .unwrap();
+        buffer::fs::write_bytes(&file, &[1u8; PAGE_SIZE], 0).unwrap();
+        assert!(FreePageMap::open(&data_path, false).is_err());
+
+        assert!(FreePageMap::open(&data_path, true)
+            .unwrap()
+            .first_free()
+            .is_none());
+        assert!(!std::path::Path::new(&map_path(&data_path)).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,39 +1,211 @@
-/// This file implements a file API utilized primarily by the disk manager
+/// This file implements a file API utilized primarily by the disk manager. All IO is positional (`pread`/`pwrite` on unix), so
+/// it never moves the handle's cursor and any number of threads can read through the same `File` at once.
 use std::fs::File;
-use std::io::SeekFrom;
 
 use crate::shared::{PageId, PAGE_SIZE};
 
 /// Used to write a buffer to a specified offset in the file handle passed in
-pub fn write_bytes(mut handle: &File, bytes: &[u8; PAGE_SIZE], offset: u64) -> std::io::Result<()> {
-    use std::io::prelude::*;
-    handle.seek(SeekFrom::Start(offset))?;
-    handle.write(bytes)?;
-    Ok(())
+pub fn write_bytes(handle: &File, bytes: &[u8; PAGE_SIZE], offset: u64) -> std::io::Result<()> {
+    write_all_at(handle, bytes, offset)
 }
 
-/// Used to append a buffer to the end of the file handle. Returns the id of the page
-pub fn append_bytes(mut handle: &File, bytes: &[u8; PAGE_SIZE]) -> std::io::Result<PageId> {
-    use std::io::prelude::*;
-    let stat = handle.metadata().unwrap();
-    let page_id = stat.len() / PAGE_SIZE as u64;
-    handle.seek(SeekFrom::End(0))?;
-    handle.write(bytes)?;
+/// Used to append a buffer to the end of the file handle. Returns the id of the page. Concurrent appends to the same file
+/// must be serialized by the caller, since both would pick the same page id
+pub fn append_bytes(handle: &File, bytes: &[u8; PAGE_SIZE]) -> std::io::Result<PageId> {
+    let page_id = handle.metadata()?.len() / PAGE_SIZE as u64;
+    write_all_at(handle, bytes, page_id * PAGE_SIZE as u64)?;
     Ok(page_id as PageId)
 }
 
 /// Used to read from a specified offset, enough bytes to fill the passed in buffer
-pub fn read_bytes(
-    mut handle: &File,
-    buffer: &mut [u8; PAGE_SIZE],
+pub fn read_bytes(handle: &File, buffer: &mut [u8; PAGE_SIZE], offset: u64) -> std::io::Result<()> {
+    read_exact_at(handle, buffer, offset)
+}
+
+/// Write `pages` to consecutive pages of the file, the first at `offset`, with as few syscalls as possible (`pwritev` on unix)
+pub fn write_run(handle: &File, pages: &[&[u8; PAGE_SIZE]], offset: u64) -> std::io::Result<()> {
+    for (i, chunk) in pages.chunks(MAX_IOVECS).enumerate() {
+        let offset = offset + (i * MAX_IOVECS * PAGE_SIZE) as u64;
+        let written = write_vectored_at(handle, chunk, offset)?;
+        // finish a short transfer page by page
+        let done = written / PAGE_SIZE;
+        if done < chunk.len() {
+            let partial = written % PAGE_SIZE;
+            let offset = offset + (done * PAGE_SIZE) as u64;
+            write_all_at(handle, &chunk[done][partial..], offset + partial as u64)?;
+            for (j, page) in chunk.iter().enumerate().skip(done + 1) {
+                write_all_at(handle, *page, offset + ((j - done) * PAGE_SIZE) as u64)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fill `pages` from consecutive pages of the file, the first at `offset`, with as few syscalls as possible (`preadv` on
+/// unix). Fails with `UnexpectedEof` if the file ends first
+pub fn read_run(
+    handle: &File,
+    pages: &mut [&mut [u8; PAGE_SIZE]],
+    offset: u64,
+) -> std::io::Result<()> {
+    for (i, chunk) in pages.chunks_mut(MAX_IOVECS).enumerate() {
+        let offset = offset + (i * MAX_IOVECS * PAGE_SIZE) as u64;
+        let read = read_vectored_at(handle, chunk, offset)?;
+        let done = read / PAGE_SIZE;
+        if done < chunk.len() {
+            let partial = read % PAGE_SIZE;
+            let offset = offset + (done * PAGE_SIZE) as u64;
+            read_exact_at(handle, &mut chunk[done][partial..], offset + partial as u64)?;
+            for (j, page) in chunk.iter_mut().enumerate().skip(done + 1) {
+                read_exact_at(handle, *page, offset + ((j - done) * PAGE_SIZE) as u64)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Pages per vectored call, within every platform's `IOV_MAX`
+const MAX_IOVECS: usize = 512;
+
+/// One `pwritev`, returning how many bytes it wrote
+#[cfg(unix)]
+fn write_vectored_at(
+    handle: &File,
+    pages: &[&[u8; PAGE_SIZE]],
+    offset: u64,
+) -> std::io::Result<usize> {
+    use std::os::unix::io::AsRawFd;
+    let iovecs: Vec<libc::iovec> = pages
+        .iter()
+        .map(|page| libc::iovec {
+            iov_base: page.as_ptr() as *mut libc::c_void,
+            iov_len: PAGE_SIZE,
+        })
+        .collect();
+    loop {
+        let written = unsafe {
+            libc::pwritev(
+                handle.as_raw_fd(),
+                iovecs.as_ptr(),
+                iovecs.len() as libc::c_int,
+                offset as libc::off_t,
+            )
+        };
+        if written >= 0 {
+            return Ok(written as usize);
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// One `preadv`, returning how many bytes it read
+#[cfg(unix)]
+fn read_vectored_at(
+    handle: &File,
+    pages: &mut [&mut [u8; PAGE_SIZE]],
     offset: u64,
+) -> std::io::Result<usize> {
+    use std::os::unix::io::AsRawFd;
+    let iovecs: Vec<libc::iovec> = pages
+        .iter_mut()
+        .map(|page| libc::iovec {
+            iov_base: page.as_mut_ptr() as *mut libc::c_void,
+            iov_len: PAGE_SIZE,
+        })
+        .collect();
+    loop {
+        let read = unsafe {
+            libc::preadv(
+                handle.as_raw_fd(),
+                iovecs.as_ptr(),
+                iovecs.len() as libc::c_int,
+                offset as libc::off_t,
+            )
+        };
+        if read >= 0 {
+            return Ok(read as usize);
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// No positional vectored IO here: report nothing done, so the caller falls back to page by page
+#[cfg(not(unix))]
+fn write_vectored_at(_: &File, _: &[&[u8; PAGE_SIZE]], _: u64) -> std::io::Result<usize> {
+    Ok(0)
+}
+
+#[cfg(not(unix))]
+fn read_vectored_at(_: &File, _: &mut [&mut [u8; PAGE_SIZE]], _: u64) -> std::io::Result<usize> {
+    Ok(0)
+}
+
+#[cfg(unix)]
+pub(crate) fn read_exact_at(handle: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    handle.read_exact_at(buf, offset)
+}
+
+#[cfg(unix)]
+pub(crate) fn write_all_at(handle: &File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    handle.write_all_at(buf, offset)
+}
+
+/// `seek_read` moves the cursor, but every access goes through here so nothing depends on where it is
+#[cfg(windows)]
+pub(crate) fn read_exact_at(
+    handle: &File,
+    mut buf: &mut [u8],
+    mut offset: u64,
 ) -> std::io::Result<()> {
-    use std::io::prelude::*;
-    handle.seek(SeekFrom::Start(offset))?;
-    handle.read(buffer)?;
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match handle.seek_read(buf, offset) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
     Ok(())
 }
 
+#[cfg(windows)]
+pub(crate) fn write_all_at(handle: &File, mut buf: &[u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match handle.seek_write(buf, offset) {
+            Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Sync the directory holding `path`, so that creating, renaming or removing the file there is durable
+pub(crate) fn sync_parent(path: &str) -> std::io::Result<()> {
+    let dir = std::path::Path::new(path)
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(std::path::Path::new("."));
+    File::open(dir)?.sync_all()
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::{File, OpenOptions};
@@ -41,38 +213,41 @@ mod tests {
     use rayon::ThreadPoolBuilder;
 
     use super::*;
-    use crate::shared::{cwd, Song};
+    use crate::shared::cwd;
     use crate::storage::buffer::io;
-    use crate::sync::{BinarySemaphore, BinarySemaphoreMethods as _, Latch as _, Synchronized};
+    use crate::sync::{CountingSemaphore, CountingSemaphoreMethods as _, Latch as _, Synchronized};
+    use crate::testing::Song;
     use std::sync::Arc;
 
     struct Context {
-        sem: BinarySemaphore,
+        // one permit per finished write
+        sem: CountingSemaphore,
         handle: File,
         last_written_id: i32,
-        num_writes: usize,
     }
 
     type Ctx = Synchronized<Context>;
 
     trait FsCtx {
         fn create(handle: File) -> Self;
-        fn wait(&self);
+        fn wait(&self, writes: usize);
     }
 
     impl FsCtx for Ctx {
         fn create(handle: File) -> Self {
             Synchronized::init(Context {
-                sem: BinarySemaphore::init(false),
+                sem: CountingSemaphore::init(0),
                 handle,
                 last_written_id: 0,
-                num_writes: 1,
             })
         }
 
-        fn wait(&self) {
-            let inner = unsafe { &(*self.data_ptr()) };
-            inner.sem.wait();
+        /// Wait for `writes` writes to finish
+        fn wait(&self, writes: usize) {
+            let sem = self.lock().sem.clone();
+            for _ in 0..writes {
+                sem.wait();
+            }
         }
     }
 
@@ -89,7 +264,7 @@ mod tests {
             ))?;
 
         let car_radio = Song::new(0, "Car Radio", "Twenty-One Pilots");
-        let buf = io::to_buffer(&car_radio).unwrap();
+        let buf = io::to_buffer(car_radio).unwrap();
         write_bytes(&handle, &buf, 0)?;
         Ok(handle)
     }
@@ -103,17 +278,15 @@ mod tests {
     fn write_song(ctx: &Ctx, song: Song) -> std::io::Result<()> {
         ctx.latch();
         let inner = unsafe { &mut *ctx.data_ptr() };
-        if inner.num_writes >= 10 {
-            inner.sem.post();
-            return Ok(());
-        }
         let handle = &inner.handle;
         let buf = io::to_buffer(song).unwrap();
-        write_bytes(&handle, &buf, song.id as u64 * PAGE_SIZE as u64)?;
-        inner.last_written_id = song.id;
-        inner.num_writes += 1;
+        let written = write_bytes(handle, &buf, song.id as u64 * PAGE_SIZE as u64);
+        if written.is_ok() {
+            inner.last_written_id = song.id;
+        }
+        inner.sem.post();
         ctx.unlatch();
-        Ok(())
+        written
     }
 
     fn read_song(ctx: &Ctx) -> std::io::Result<()> {
@@ -123,7 +296,7 @@ mod tests {
         let ctx_last_written_id = unsafe { &(*ctx.data_ptr()).last_written_id };
         let mut buf = [0u8; PAGE_SIZE];
         read_bytes(
-            &handle,
+            handle,
             &mut buf,
             *ctx_last_written_id as u64 * PAGE_SIZE as u64,
         )?;
@@ -136,7 +309,7 @@ mod tests {
     #[test]
     fn test_concurrent_file_io() {
         let setup_result = setup();
-        assert!(!setup_result.is_err());
+        assert!(setup_result.is_ok());
         let handle = setup_result.unwrap();
         let pool = ThreadPoolBuilder::new().num_threads(20).build().unwrap();
 
@@ -182,21 +355,51 @@ mod tests {
             let ctx = ctx.clone();
             let songs = songs.clone();
             pool.spawn(move || {
-                assert!(!write_song(&ctx, songs[i]).is_err());
+                assert!(write_song(&ctx, songs[i]).is_ok());
             })
         }
 
         for _ in 0..5 {
             let ctx = ctx.clone();
             pool.spawn(move || {
-                assert!(!read_song(&ctx).is_err());
+                assert!(read_song(&ctx).is_ok());
             })
         }
 
-        ctx.wait();
+        ctx.wait(num_songs);
 
         let cleanup_result = cleanup();
-        assert!(!cleanup_result.is_err());
+        assert!(cleanup_result.is_ok());
+    }
+
+    #[test]
+    fn test_vectored_runs() {
+        let dir = cwd() + "/tests/fs_vectored_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        let handle = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(std::path::Path::new(&(dir.clone() + "/test_file.bin")))
+            .unwrap();
+
+        // more pages than fit in one call
+        let pages: Vec<[u8; PAGE_SIZE]> = (0..MAX_IOVECS as i32 + 10)
+            .map(|i| io::to_buffer(Song::new(i, "Nervous", "The Neighbourhood")).unwrap())
+            .collect();
+        let refs: Vec<&[u8; PAGE_SIZE]> = pages.iter().collect();
+        write_run(&handle, &refs, PAGE_SIZE as u64).unwrap();
+
+        let mut read = vec![[0u8; PAGE_SIZE]; pages.len()];
+        let mut refs: Vec<&mut [u8; PAGE_SIZE]> = read.iter_mut().collect();
+        read_run(&handle, &mut refs, PAGE_SIZE as u64).unwrap();
+        assert!(read == pages);
+        let mut refs: Vec<&mut [u8; PAGE_SIZE]> = read.iter_mut().collect();
+        let err = read_run(&handle, &mut refs, 2 * PAGE_SIZE as u64).unwrap_err();
+        assert!(err.kind() == std::io::ErrorKind::UnexpectedEof);
+
+        std::fs::remove_dir_all(std::path::Path::new(&dir)).unwrap();
     }
 
     #[test]
@@ -217,11 +420,11 @@ mod tests {
         let so_sad_so_sexy = Song::new(1, "So Sad So Sexy", "Lykke Li");
         let sex_money_feelings_die = Song::new(2, "Sex Money Feelings Die", "Lykke Li");
 
-        let buf_one = io::to_buffer(&car_radio).unwrap();
+        let buf_one = io::to_buffer(car_radio).unwrap();
         let first: PageId = append_bytes(&handle, &buf_one).unwrap();
-        let buf_two = io::to_buffer(&so_sad_so_sexy).unwrap();
+        let buf_two = io::to_buffer(so_sad_so_sexy).unwrap();
         let second: PageId = append_bytes(&handle, &buf_two).unwrap();
-        let buf_three = io::to_buffer(&sex_money_feelings_die).unwrap();
+        let buf_three = io::to_buffer(sex_money_feelings_die).unwrap();
         let third: PageId = append_bytes(&handle, &buf_three).unwrap();
 
         assert!(first == 0);
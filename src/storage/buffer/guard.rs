@@ -0,0 +1,309 @@
+/// This file implements RAII guards over buffer pool pages. A guard owns one pin on its page and holds the frame's latch (shared
+/// for `ReadPageGuard`, exclusive for `WritePageGuard`) for as long as it lives, so the page can neither be evicted nor modified
+/// underneath it. Dropping the guard releases the latch and then the pin; a write guard whose page was mutably borrowed unpins
+/// the page as dirty.
+///
+/// A `PinnedPage` owns a pin but no latch. It exists to give code outside the pool (e.g. a library doing direct IO) a stable
+/// pointer to the page's memory.
+///
+/// The rest are building blocks for coupling latches on the way down a tree. A `LatchPath` holds the write guards of latch
+/// crabbing. An `OptimisticGuard` owns a pin and the page's version instead of a latch, for optimistic lock coupling: a write
+/// guard makes its frame's version odd when it first changes the page, and moves it on to the next even value when it's
+/// dropped, changed or not, so a reader that copies the page and then finds the version unchanged knows the copy is
+/// consistent. Changes made through a `PinnedPage`, or straight through a frame's latch, don't bump the version.
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{fence, Ordering};
+
+use crate::shared::{PageId, PAGE_SIZE};
+use crate::storage::buffer::bufmgr::{
+    frame_latch, frame_page, frame_version, BufApi as _, BufferPool, BufferPoolFrame, FrameApi as _,
+};
+use crate::storage::buffer::page::{self, Page};
+use crate::sync::RwLatch as _;
+
+pub struct ReadPageGuard {
+    pool: BufferPool,
+    frame: BufferPoolFrame,
+    page_id: PageId,
+}
+
+pub struct WritePageGuard {
+    pool: BufferPool,
+    frame: BufferPoolFrame,
+    page_id: PageId,
+    dirty: bool,
+}
+
+impl ReadPageGuard {
+    /// Wrap a frame that has already been pinned on behalf of the guard
+    pub(crate) fn new(pool: BufferPool, frame: BufferPoolFrame, page_id: PageId) -> Self {
+        frame_latch(&frame).latch_shared();
+        ReadPageGuard {
+            pool,
+            frame,
+            page_id,
+        }
+    }
+
+    #[inline]
+    pub fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    #[inline]
+    pub fn data(&self) -> &Page {
+        unsafe { &*frame_page(&self.frame) }
+    }
+}
+
+impl Deref for ReadPageGuard {
+    type Target = Page;
+
+    fn deref(&self) -> &Page {
+        self.data()
+    }
+}
+
+impl Drop for ReadPageGuard {
+    fn drop(&mut self) {
+        frame_latch(&self.frame).unlatch_shared();
+        self.pool.unpin_page(self.page_id, false);
+    }
+}
+
+impl WritePageGuard {
+    /// Wrap a frame that has already been pinned on behalf of the guard
+    pub(crate) fn new(pool: BufferPool, frame: BufferPoolFrame, page_id: PageId) -> Self {
+        frame_latch(&frame).latch_excl();
+        WritePageGuard {
+            pool,
+            frame,
+            page_id,
+            dirty: false,
+        }
+    }
+
+    #[inline]
+    pub fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    #[inline]
+    pub fn data(&self) -> &Page {
+        unsafe { &*frame_page(&self.frame) }
+    }
+
+    /// Mutable access to the page. Marks the page dirty, and the first time makes its version odd until the guard is dropped
+    #[inline]
+    pub fn data_mut(&mut self) -> &mut Page {
+        if !self.dirty {
+            frame_version(&self.frame).fetch_add(1, Ordering::AcqRel);
+            fence(Ordering::Release);
+            self.dirty = true;
+        }
+        unsafe { &mut *frame_page(&self.frame) }
+    }
+}
+
+impl Deref for WritePageGuard {
+    type Target = Page;
+
+    fn deref(&self) -> &Page {
+        self.data()
+    }
+}
+
+impl DerefMut for WritePageGuard {
+    fn deref_mut(&mut self) -> &mut Page {
+        self.data_mut()
+    }
+}
+
+impl Drop for WritePageGuard {
+    fn drop(&mut self) {
+        let bump = if self.dirty { 1 } else { 2 };
+        frame_version(&self.frame).fetch_add(bump, Ordering::Release);
+        frame_latch(&self.frame).unlatch_excl();
+        self.pool.unpin_page(self.page_id, self.dirty);
+    }
+}
+
+pub struct PinnedPage {
+    pool: BufferPool,
+    frame: BufferPoolFrame,
+    page_id: PageId,
+    dirty: bool,
+}
+
+impl PinnedPage {
+    /// Wrap a frame that has already been pinned on behalf of the guard
+    pub(crate) fn new(pool: BufferPool, frame: BufferPoolFrame, page_id: PageId) -> Self {
+        PinnedPage {
+            pool,
+            frame,
+            page_id,
+            dirty: false,
+        }
+    }
+
+    #[inline]
+    pub fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    /// Address of the page's `PAGE_SIZE` bytes, aligned to `PAGE_SIZE`. The page stays at this address for as long as the
+    /// `PinnedPage` lives. The pin doesn't latch the page: whoever uses the pointer must make sure nobody else is modifying
+    /// the page at the same time, e.g. by holding the frame latch or by coordinating through higher level locks.
+    #[inline]
+    pub fn as_ptr(&self) -> *const u8 {
+        frame_page(&self.frame) as *const u8
+    }
+
+    /// Mutable version of `as_ptr`. Call `mark_dirty` after writing through it
+    #[inline]
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        frame_page(&self.frame) as *mut u8
+    }
+
+    /// Note that the page was modified through `as_mut_ptr`, so it's written back before being evicted
+    #[inline]
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+}
+
+impl Drop for PinnedPage {
+    fn drop(&mut self) {
+        self.pool.unpin_page(self.page_id, self.dirty);
+    }
+}
+
+/// A pin on a page and the version the page had when it was taken, for reading the page without latching it. Nothing stops
+/// writers from changing the page meanwhile; `validate` tells whether one did
+pub struct OptimisticGuard {
+    pool: BufferPool,
+    frame: BufferPoolFrame,
+    page_id: PageId,
+    version: u64,
+}
+
+impl OptimisticGuard {
+    /// Wrap a frame that has already been pinned on behalf of the guard. If a writer is in the middle of changing the page,
+    /// wait until it's done
+    pub(crate) fn new(pool: BufferPool, frame: BufferPoolFrame, page_id: PageId) -> Self {
+        let mut version = frame.version();
+        while version % 2 == 1 {
+            frame_latch(&frame).latch_shared();
+            frame_latch(&frame).unlatch_shared();
+            version = frame.version();
+        }
+        OptimisticGuard {
+            pool,
+            frame,
+            page_id,
+            version,
+        }
+    }
+
+    #[inline]
+    pub fn page_id(&self) -> PageId {
+        self.page_id
+    }
+
+    #[inline]
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Whether the page is still the way it was when the guard was taken
+    #[inline]
+    pub fn validate(&self) -> bool {
+        fence(Ordering::Acquire);
+        self.frame.version() == self.version
+    }
+
+    /// A copy of the page, or `None` if it was changed since the guard was taken. Only a validated copy is handed out, since a
+    /// copy made while a writer was at work can be torn
+    pub fn copy(&self) -> Option<Page> {
+        let mut copy = page::empty();
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                frame_page(&self.frame) as *const u8,
+                copy.as_mut_ptr(),
+                PAGE_SIZE,
+            )
+        };
+        self.validate().then_some(copy)
+    }
+
+    /// Latch the page exclusively and hand back a write guard, keeping the pin, if the page is still unchanged. Otherwise
+    /// the pin is dropped and `None` is returned
+    pub fn upgrade(self) -> Option<WritePageGuard> {
+        frame_latch(&self.frame).latch_excl();
+        if self.frame.version() != self.version {
+            frame_latch(&self.frame).unlatch_excl();
+            return None;
+        }
+        let this = ManuallyDrop::new(self);
+        // move the pool and frame handles out instead of dropping the guard, which would unpin the page
+        let (pool, frame) = unsafe { (std::ptr::read(&this.pool), std::ptr::read(&this.frame)) };
+        Some(WritePageGuard {
+            pool,
+            frame,
+            page_id: this.page_id,
+            dirty: false,
+        })
+    }
+}
+
+impl Drop for OptimisticGuard {
+    fn drop(&mut self) {
+        self.pool.unpin_page(self.page_id, false);
+    }
+}
+
+/// The write guards on a path down a tree, top-down, for latch crabbing: each node is latched before its parent is let go,
+/// and the nodes above one are let go of once it's safe, i.e. once the operation can't change anything above it (an insert
+/// into a node with room to spare can't split it)
+#[derive(Default)]
+pub struct LatchPath {
+    guards: Vec<WritePageGuard>,
+}
+
+impl LatchPath {
+    pub fn new() -> Self {
+        LatchPath::default()
+    }
+
+    /// Add the next node down, latched while the ones above are still held. If it's safe, the ones above are dropped
+    pub fn push(&mut self, guard: WritePageGuard, safe: bool) {
+        if safe {
+            self.guards.clear();
+        }
+        self.guards.push(guard);
+    }
+
+    /// Take the lowest guard off the path
+    pub fn pop(&mut self) -> Option<WritePageGuard> {
+        self.guards.pop()
+    }
+
+    /// Drop every guard on the path
+    pub fn release(&mut self) {
+        self.guards.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.guards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.guards.is_empty()
+    }
+
+    /// Page ids of the nodes still latched, top-down
+    pub fn page_ids(&self) -> Vec<PageId> {
+        self.guards.iter().map(WritePageGuard::page_id).collect()
+    }
+}
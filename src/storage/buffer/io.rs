@@ -1,59 +1,146 @@
-#![allow(dead_code)]
-
 /// This file implements an IO API which includes functions to encode/decode arbitrary structures as long as they implement the
 /// required traits
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
 use crate::shared::PAGE_SIZE;
+use crate::storage::buffer::page::{self, PageType, PAGE_HEADER_SIZE};
+use crate::storage::error::StorageError;
+use crate::storage::tuple::{Schema, Tuple};
 
 /// Used to encode a generic item to a vector of u8s as long as it implements the Sized and Serialize traits
-pub fn encode<T>(item: T) -> Option<Vec<u8>>
+pub fn encode<T>(item: T) -> Result<Vec<u8>, StorageError>
 where
     T: Sized + Serialize,
 {
-    if let Ok(encoded) = bincode::serialize(&item) {
-        return Some(encoded);
-    }
-    None
+    Ok(bincode::serialize(&item)?)
 }
 
 /// Used to decode a vector of u8s into a generic item as long as it implements the Sized, Serialize, and DeserializeOwned traits
-pub fn decode<T>(bytes: Vec<u8>) -> Option<T>
+pub fn decode<T>(bytes: Vec<u8>) -> Result<T, StorageError>
 where
     T: Sized + Serialize + DeserializeOwned,
 {
-    if let Ok(decoded) = bincode::deserialize(&bytes) {
-        return Some(decoded);
+    Ok(bincode::deserialize(&bytes)?)
+}
+
+/// Pages written by `to_buffer` and `to_buffer_with_schema` are `PageType::Raw` pages. After the page header comes a small
+/// header of their own, saying how the payload was encoded and how long it is, so decoding reads exactly the bytes that were
+/// written instead of running into the zero padding:
+///
+/// ```text
+/// | page header | format (1) | payload length (4) | payload |
+/// ```
+pub const RAW_FORMAT_OFFSET: usize = PAGE_HEADER_SIZE;
+pub const RAW_LENGTH_OFFSET: usize = RAW_FORMAT_OFFSET + 1;
+pub const RAW_HEADER_SIZE: usize = 1 + 4;
+
+/// Most bytes a raw page can hold
+pub const RAW_PAYLOAD_CAPACITY: usize = PAGE_SIZE - PAGE_HEADER_SIZE - RAW_HEADER_SIZE;
+
+/// How the payload of a raw page was encoded. Stored as a single byte after the page header
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RawFormat {
+    /// An item encoded with `encode`
+    Bincode = 1,
+    /// A tuple serialized according to its schema
+    Tuple = 2,
+}
+
+/// Put `bytes` in a page of their own, after the page header and the raw page header. Fails if they don't fit
+fn raw_page(bytes: &[u8], format: RawFormat) -> Result<[u8; PAGE_SIZE], StorageError> {
+    if bytes.len() > RAW_PAYLOAD_CAPACITY {
+        return Err(StorageError::Serialization(format!(
+            "{} bytes don't fit in a page, which holds at most {}",
+            bytes.len(),
+            RAW_PAYLOAD_CAPACITY
+        )));
+    }
+    let mut buf = page::empty();
+    page::set_page_type(&mut buf, PageType::Raw);
+    buf[RAW_FORMAT_OFFSET] = format as u8;
+    buf[RAW_LENGTH_OFFSET..RAW_LENGTH_OFFSET + 4]
+        .copy_from_slice(&(bytes.len() as u32).to_le_bytes());
+    let payload = PAGE_HEADER_SIZE + RAW_HEADER_SIZE;
+    buf[payload..payload + bytes.len()].copy_from_slice(bytes);
+    Ok(buf)
+}
+
+/// The payload of a raw page holding data in `format`. Fails if the page isn't one, e.g. because it was never written
+fn raw_payload(buf: &[u8; PAGE_SIZE], format: RawFormat) -> Result<&[u8], StorageError> {
+    if page::page_type(buf) != Some(PageType::Raw) {
+        return Err(StorageError::Serialization(
+            "not a page written by to_buffer".to_string(),
+        ));
     }
-    None
+    if buf[RAW_FORMAT_OFFSET] != format as u8 {
+        return Err(StorageError::Serialization(format!(
+            "page holds format {}, expected {:?}",
+            buf[RAW_FORMAT_OFFSET], format
+        )));
+    }
+    let len = u32::from_le_bytes(
+        buf[RAW_LENGTH_OFFSET..RAW_LENGTH_OFFSET + 4]
+            .try_into()
+            .unwrap(),
+    ) as usize;
+    if len > RAW_PAYLOAD_CAPACITY {
+        return Err(StorageError::Serialization(format!(
+            "page claims a {} byte payload, which doesn't fit",
+            len
+        )));
+    }
+    let payload = PAGE_HEADER_SIZE + RAW_HEADER_SIZE;
+    Ok(&buf[payload..payload + len])
 }
 
-/// Used to convert a generic item into a buffer of a static size that's writable by file APIs. Calls `encode` internally
-pub fn to_buffer<T>(item: T) -> Option<[u8; PAGE_SIZE]>
+/// Used to convert a generic item into a buffer of a static size that's writable by file APIs. Calls `encode` internally.
+/// The item goes after the page header, so the buffer can be written as a page. Fails if it's larger than
+/// `RAW_PAYLOAD_CAPACITY` once encoded
+pub fn to_buffer<T>(item: T) -> Result<[u8; PAGE_SIZE], StorageError>
 where
     T: Sized + Serialize,
 {
-    if let Some(encoded) = encode(item) {
-        let mut buf = [0u8; PAGE_SIZE];
-        buf[..std::mem::size_of_val(&*encoded)].copy_from_slice(&*encoded);
-        return Some(buf);
-    }
-    None
+    raw_page(&encode(item)?, RawFormat::Bincode)
 }
 
-/// Used to convert a buffer of a static size to a generic item. Calls `decode` internally
-pub fn from_buffer<T>(buf: &[u8; PAGE_SIZE]) -> Option<T>
+/// Used to convert a buffer of a static size to a generic item. Calls `decode` internally. Fails if the buffer wasn't
+/// written by `to_buffer`
+pub fn from_buffer<T>(buf: &[u8; PAGE_SIZE]) -> Result<T, StorageError>
 where
     T: Sized + Serialize + DeserializeOwned,
 {
-    decode::<T>(buf.to_vec())
+    decode::<T>(raw_payload(buf, RawFormat::Bincode)?.to_vec())
+}
+
+/// Used to serialize a tuple laid out according to `schema` into a buffer of a static size. Fails if the tuple doesn't match
+/// the schema or doesn't fit
+pub fn to_buffer_with_schema(
+    tuple: &Tuple,
+    schema: &Schema,
+) -> Result<[u8; PAGE_SIZE], StorageError> {
+    let bytes = tuple
+        .serialize(schema)
+        .ok_or_else(|| StorageError::Serialization("tuple doesn't match the schema".to_string()))?;
+    raw_page(&bytes, RawFormat::Tuple)
+}
+
+/// Used to read a tuple laid out according to `schema` back out of a buffer of a static size. Fails if the buffer wasn't
+/// written by `to_buffer_with_schema`
+pub fn from_buffer_with_schema(
+    buf: &[u8; PAGE_SIZE],
+    schema: &Schema,
+) -> Result<Tuple, StorageError> {
+    Tuple::deserialize(schema, raw_payload(buf, RawFormat::Tuple)?).ok_or_else(|| {
+        StorageError::Serialization("page doesn't hold a tuple of the schema".to_string())
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::shared::Song;
+    use crate::testing::Song;
 
     #[test]
     fn encode_decode() {
@@ -76,4 +163,27 @@ mod tests {
         assert_eq!(cry_baby.artist, decoded.artist);
         assert_eq!(cry_baby.title, decoded.title);
     }
+
+    #[test]
+    fn bounds_checked_buffer() {
+        // a payload that fills the page exactly fits, one more byte doesn't
+        let bytes = vec![7u8; RAW_PAYLOAD_CAPACITY - 8];
+        let buf = to_buffer(&bytes).unwrap();
+        assert!(from_buffer::<Vec<u8>>(&buf).unwrap() == bytes);
+        let too_long = vec![7u8; RAW_PAYLOAD_CAPACITY - 7];
+        assert!(matches!(
+            to_buffer(&too_long),
+            Err(StorageError::Serialization(_))
+        ));
+
+        // a page that was never written, or holds a tuple, or claims too long a payload, doesn't decode
+        assert!(from_buffer::<Vec<u8>>(&page::empty()).is_err());
+        let mut tuple_page = buf;
+        tuple_page[RAW_FORMAT_OFFSET] = RawFormat::Tuple as u8;
+        assert!(from_buffer::<Vec<u8>>(&tuple_page).is_err());
+        let mut corrupted = buf;
+        corrupted[RAW_LENGTH_OFFSET..RAW_LENGTH_OFFSET + 4]
+            .copy_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+        assert!(from_buffer::<Vec<u8>>(&corrupted).is_err());
+    }
 }
@@ -0,0 +1,900 @@
+/// This file implements a log-structured disk manager, to compare against `DiskMgr`'s update-in-place writes. Pages are never
+/// overwritten: every write appends the new version of the page to the active segment, a file of up to `segment_pages`
+/// pages, and moves the page's entry in the index to it. Once the active segment is full, writes move on to a new one. The
+/// versions left behind are garbage, and compaction reclaims it: a sealed segment that's mostly garbage has its live pages
+/// appended to the active segment again and is then deleted. `compact` runs one pass, `start_compaction` runs them in the
+/// background.
+///
+/// Segments live next to the data file as `<data file>.<n>.seg` and hold stamped pages back to back, so every version names
+/// the page it belongs to and carries its checksum, which makes the segments the source of truth: a sync only syncs them. The
+/// index maps each page id to the segment and slot of its latest version. It's kept in memory and saved to `<data file>.idx`
+/// (replaced atomically), along with the end of the log at that point, as a checkpoint: by the first sync after
+/// `CHECKPOINT_PAGES` versions were appended since the last one, by compaction and vacuum, and when the disk manager is
+/// dropped. Opening replays the versions appended after the checkpoint; a version that fails verification is taken for the
+/// torn end of its segment. A missing or corrupt index is rebuilt by replaying every segment, which can bring back pages that
+/// `vacuum` dropped.
+///
+/// A segment is only deleted after a checkpoint that no longer points to it is durable. Page ids, checksums, the free
+/// page map, the sync policy and fsync failure handling behave as they do for `DiskMgr`, so the two are interchangeable behind
+/// `DiskApi`. `DurabilityMode::DSync` behaves like `Data`.
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::shared::{PageId, INVALID_PAGE_ID, PAGE_SIZE};
+use crate::storage::buffer::bufmgr::BackgroundWriter;
+use crate::storage::buffer::diskmgr::{
+    stamped, verify, DiskApi, DiskStats, DurabilityMode, FsyncFailed, SyncPolicy,
+};
+use crate::storage::buffer::freemap::FreePageMap;
+use crate::storage::buffer::fs;
+use crate::storage::buffer::page::{self, ChecksumAlgorithm, Page};
+use crate::storage::config::StorageConfig;
+use crate::storage::error::StorageError;
+use crate::sync::{RwLatch as _, RwSynchronized};
+use crate::telemetry::trace;
+
+/// Pages a segment holds, unless configured otherwise
+pub const DEFAULT_SEGMENT_PAGES: usize = 256;
+
+/// A sealed segment is compacted once no more than this fraction of its pages are live
+/// Versions appended between checkpoints of the index. Bounds how much of the log opening replays
+pub const CHECKPOINT_PAGES: usize = 1024;
+pub const COMPACTION_THRESHOLD: f64 = 0.5;
+
+pub const SEGMENT_EXTENSION: &str = "seg";
+
+pub const INDEX_EXTENSION: &str = "idx";
+
+/// Version of the index file layout. Bump it whenever the layout changes
+pub const INDEX_VERSION: u32 = 1;
+
+/// Path of segment `segment` of the data file at `data_path`
+pub fn segment_path(data_path: &str, segment: u64) -> String {
+    format!("{}.{}.{}", data_path, segment, SEGMENT_EXTENSION)
+}
+
+/// Path of the index belonging to the data file at `data_path`
+pub fn index_path(data_path: &str) -> String {
+    format!("{}.{}", data_path, INDEX_EXTENSION)
+}
+
+/// Where a version of a page is stored: slot `slot` of segment `segment`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    segment: u64,
+    slot: u64,
+}
+
+impl Location {
+    fn offset(&self) -> u64 {
+        self.slot * PAGE_SIZE as u64
+    }
+}
+
+struct Segment {
+    handle: File,
+    // versions written to it
+    len: u64,
+    // versions the index points to
+    live: u64,
+}
+
+impl Segment {
+    fn open(path: &str, truncate: bool) -> std::io::Result<Segment> {
+        let handle = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(truncate)
+            .open(path)?;
+        let len = handle.metadata()?.len() / PAGE_SIZE as u64;
+        Ok(Segment {
+            handle,
+            len,
+            live: 0,
+        })
+    }
+}
+
+/// What a compaction pass did
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompactionStats {
+    /// Segments deleted
+    pub segments: usize,
+    /// Live pages copied out of them
+    pub moved: usize,
+}
+
+pub struct LogStructuredDiskMgrCtx {
+    path: String,
+    segment_pages: u64,
+    segments: BTreeMap<u64, Segment>,
+    // the segment writes are appended to, always the last one
+    active: u64,
+    // location of each page's latest version, by page id. Pages that were never written have none and read as zeros
+    index: Vec<Option<Location>>,
+    // whether the index changed since it was last saved
+    index_dirty: bool,
+    // versions appended since the index was last saved
+    since_checkpoint: usize,
+    // segments written since the last sync
+    unsynced_segments: BTreeSet<u64>,
+    durability: DurabilityMode,
+    policy: SyncPolicy,
+    checksum: ChecksumAlgorithm,
+    last_sync: Instant,
+    // pages written since the last successful sync
+    unsynced: HashSet<PageId>,
+    // pages that were unsynced when a sync failed. their contents on disk are unknown
+    suspect: HashSet<PageId>,
+    recovery_required: bool,
+    free_map: FreePageMap,
+    num_writes: usize,
+    num_flushes: usize,
+}
+
+impl LogStructuredDiskMgrCtx {
+    /// Append `page`, already stamped, to the active segment as the latest version of `page_id`. Moves on to a new segment
+    /// first if the active one is full
+    fn append(&mut self, page: &Page, page_id: PageId) -> std::io::Result<()> {
+        if self.segments[&self.active].len >= self.segment_pages {
+            let next = self.active + 1;
+            let segment = Segment::open(&segment_path(&self.path, next), true)?;
+            self.segments.insert(next, segment);
+            self.active = next;
+        }
+        let active = self.segments.get_mut(&self.active).unwrap();
+        let location = Location {
+            segment: self.active,
+            slot: active.len,
+        };
+        fs::write_all_at(&active.handle, page, location.offset())?;
+        active.len += 1;
+        active.live += 1;
+        if self.index.len() <= page_id as usize {
+            self.index.resize(page_id as usize + 1, None);
+        }
+        if let Some(old) = self.index[page_id as usize].replace(location) {
+            self.segments.get_mut(&old.segment).unwrap().live -= 1;
+        }
+        self.index_dirty = true;
+        self.since_checkpoint += 1;
+        self.unsynced_segments.insert(self.active);
+        self.unsynced.insert(page_id);
+        Ok(())
+    }
+
+    /// Store `buf` as the latest version of `page_id`. Doesn't sync
+    fn put_page(&mut self, buf: &Page, page_id: PageId) -> std::io::Result<()> {
+        self.check_writable()?;
+        self.append(&stamped(buf, page_id, self.checksum), page_id)?;
+        self.num_writes += 1;
+        Ok(())
+    }
+
+    fn write_page(&mut self, buf: &Page, page_id: PageId) -> std::io::Result<()> {
+        self.put_page(buf, page_id)?;
+        self.sync_after_write()
+    }
+
+    fn append_page(&mut self, buf: &Page) -> std::io::Result<PageId> {
+        let page_id = self.index.len() as PageId;
+        self.write_page(buf, page_id)?;
+        Ok(page_id)
+    }
+
+    fn read_at(&self, buf: &mut Page, location: Location) -> std::io::Result<()> {
+        fs::read_exact_at(
+            &self.segments[&location.segment].handle,
+            buf,
+            location.offset(),
+        )
+    }
+
+    /// Read the latest version of `page_id`, verifying its checksum
+    fn read_page(&self, buf: &mut Page, page_id: PageId) -> Result<(), StorageError> {
+        if page_id < 0 || page_id as usize >= self.index.len() {
+            return Err(StorageError::PageNotFound(page_id));
+        }
+        let Some(location) = self.index[page_id as usize] else {
+            *buf = page::empty();
+            return Ok(());
+        };
+        self.read_at(buf, location)?;
+        verify(buf, page_id)
+    }
+
+    /// Sync every segment written since the last sync, then save the index if `checkpoint` says so or enough has been appended
+    /// since it was last saved. On failure, every unsynced page becomes suspect and the disk manager enters the
+    /// recovery-required state (see `DiskMgrCtx::sync`)
+    fn sync(&mut self, checkpoint: bool) -> std::io::Result<()> {
+        let synced: std::io::Result<()> = trace::io("sync", INVALID_PAGE_ID, || {
+            for segment in self.unsynced_segments.iter() {
+                let Some(segment) = self.segments.get(segment) else {
+                    continue;
+                };
+                match self.durability {
+                    DurabilityMode::Full => segment.handle.sync_all()?,
+                    _ => segment.handle.sync_data()?,
+                }
+            }
+            if checkpoint || self.since_checkpoint >= CHECKPOINT_PAGES {
+                self.save_index(true)?;
+            }
+            Ok(())
+        });
+        if synced.is_err() {
+            return Err(self.fail_sync());
+        }
+        self.unsynced_segments.clear();
+        self.unsynced.clear();
+        self.num_flushes += 1;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    fn sync_after_write(&mut self) -> std::io::Result<()> {
+        match self.policy {
+            SyncPolicy::Always => self.sync(false),
+            SyncPolicy::Interval(interval) if self.last_sync.elapsed() >= interval => {
+                self.sync(false)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn fail_sync(&mut self) -> std::io::Error {
+        self.suspect.extend(self.unsynced.drain());
+        self.recovery_required = true;
+        self.fsync_error()
+    }
+
+    fn check_writable(&self) -> std::io::Result<()> {
+        if self.recovery_required {
+            return Err(self.fsync_error());
+        }
+        Ok(())
+    }
+
+    fn fsync_error(&self) -> std::io::Error {
+        let mut pages: Vec<PageId> = self.suspect.iter().copied().collect();
+        pages.sort_unstable();
+        std::io::Error::other(FsyncFailed { pages })
+    }
+
+    /// Replace the index file with the current index and the end of the log, if the index changed: write a new file next to
+    /// it, then rename it over the old one. With `durable`, the new file is synced before the rename
+    fn save_index(&mut self, durable: bool) -> std::io::Result<()> {
+        if !self.index_dirty {
+            return Ok(());
+        }
+        let mut bytes = INDEX_VERSION.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&self.active.to_le_bytes());
+        bytes.extend_from_slice(&self.segments[&self.active].len.to_le_bytes());
+        bytes.extend_from_slice(&(self.index.len() as u64).to_le_bytes());
+        for location in self.index.iter() {
+            let (segment, slot) = location.map_or((u64::MAX, 0), |l| (l.segment, l.slot));
+            bytes.extend_from_slice(&segment.to_le_bytes());
+            bytes.extend_from_slice(&slot.to_le_bytes());
+        }
+        bytes.extend_from_slice(&crc32c::crc32c(&bytes).to_le_bytes());
+
+        let path = index_path(&self.path);
+        let new_path = path.clone() + ".new";
+        let file = File::create(&new_path)?;
+        fs::write_all_at(&file, &bytes, 0)?;
+        if durable {
+            file.sync_all()?;
+        }
+        std::fs::rename(&new_path, &path)?;
+        self.index_dirty = false;
+        self.since_checkpoint = 0;
+        Ok(())
+    }
+
+    /// Point the index at every valid version from `from` to the end of the log. A version that doesn't verify ends its
+    /// segment: the segment is cut there and replay goes on with the next one
+    fn replay(&mut self, from: Location) -> std::io::Result<()> {
+        let mut buf = page::empty();
+        let numbers: Vec<u64> = self
+            .segments
+            .range(from.segment..)
+            .map(|(n, _)| *n)
+            .collect();
+        for number in numbers {
+            let start = if number == from.segment { from.slot } else { 0 };
+            for slot in start..self.segments[&number].len {
+                let location = Location {
+                    segment: number,
+                    slot,
+                };
+                self.read_at(&mut buf, location)?;
+                let page_id = page::page_id(&buf);
+                if page::is_zeroed(&buf) || page_id < 0 || verify(&buf, page_id).is_err() {
+                    let segment = self.segments.get_mut(&number).unwrap();
+                    segment.handle.set_len(location.offset())?;
+                    segment.len = slot;
+                    break;
+                }
+                if self.index.len() <= page_id as usize {
+                    self.index.resize(page_id as usize + 1, None);
+                }
+                self.index[page_id as usize] = Some(location);
+                self.index_dirty = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LogStructuredDiskMgrCtx {
+    /// Pages written since the last sync are in the segments (if not on disk yet), so keep the index pointing to them
+    fn drop(&mut self) {
+        if !self.recovery_required {
+            let _ = self.save_index(false);
+        }
+    }
+}
+
+/// Load the index saved for the data file at `data_path`, and the end of the log when it was saved. `None` if there's no
+/// index or it doesn't check out, in which case it's rebuilt from the segments
+fn load_index(data_path: &str) -> Option<(Vec<Option<Location>>, Location)> {
+    let bytes = std::fs::read(index_path(data_path)).ok()?;
+    if bytes.len() < 32 {
+        return None;
+    }
+    let (body, crc) = bytes.split_at(bytes.len() - 4);
+    let u64_at = |i: usize| u64::from_le_bytes(body[i..i + 8].try_into().unwrap());
+    if crc32c::crc32c(body).to_le_bytes() != crc
+        || u32::from_le_bytes(body[..4].try_into().unwrap()) != INDEX_VERSION
+        || body.len() != 28 + u64_at(20) as usize * 16
+    {
+        return None;
+    }
+    let end = Location {
+        segment: u64_at(4),
+        slot: u64_at(12),
+    };
+    let index = (0..u64_at(20) as usize)
+        .map(|i| {
+            let (segment, slot) = (u64_at(28 + i * 16), u64_at(36 + i * 16));
+            (segment != u64::MAX).then_some(Location { segment, slot })
+        })
+        .collect();
+    Some((index, end))
+}
+
+/// Numbers of the segments of the data file at `data_path`, in order
+fn segment_numbers(data_path: &str) -> std::io::Result<Vec<u64>> {
+    let path = Path::new(data_path);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = format!("{}.", path.file_name().unwrap().to_string_lossy());
+    let suffix = format!(".{}", SEGMENT_EXTENSION);
+    let mut numbers = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        let number = name
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.strip_suffix(&suffix))
+            .and_then(|number| number.parse::<u64>().ok());
+        numbers.extend(number);
+    }
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+fn open_file(
+    config: &StorageConfig,
+    segment_pages: usize,
+    truncate: bool,
+) -> std::io::Result<LogStructuredDiskMgr> {
+    assert!(segment_pages > 0);
+    let path = config.data_path().to_string_lossy().into_owned();
+    let mut numbers = segment_numbers(&path)?;
+    if truncate {
+        for number in numbers.drain(..) {
+            std::fs::remove_file(segment_path(&path, number))?;
+        }
+        match std::fs::remove_file(index_path(&path)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    if numbers.is_empty() {
+        numbers.push(0);
+    }
+    let mut segments = BTreeMap::new();
+    for number in numbers.iter() {
+        segments.insert(
+            *number,
+            Segment::open(&segment_path(&path, *number), false)?,
+        );
+    }
+    let first = Location {
+        segment: numbers[0],
+        slot: 0,
+    };
+    // an index that points past the segments there are is as good as lost
+    let (index, end) = load_index(&path)
+        .filter(|(index, _)| {
+            index.iter().flatten().all(|location| {
+                segments
+                    .get(&location.segment)
+                    .is_some_and(|segment: &Segment| location.slot < segment.len)
+            })
+        })
+        .unwrap_or((Vec::new(), first));
+
+    let mut ctx = LogStructuredDiskMgrCtx {
+        segment_pages: segment_pages as u64,
+        active: numbers[numbers.len() - 1],
+        segments,
+        index,
+        index_dirty: false,
+        since_checkpoint: 0,
+        unsynced_segments: BTreeSet::new(),
+        durability: config.durability(),
+        policy: config.sync_policy(),
+        checksum: config.checksum(),
+        last_sync: Instant::now(),
+        unsynced: HashSet::new(),
+        suspect: HashSet::new(),
+        recovery_required: false,
+        free_map: FreePageMap::open(&path, truncate)?,
+        num_writes: 0,
+        num_flushes: 0,
+        path,
+    };
+    ctx.replay(end)?;
+    // free bits past the last page are left over from a vacuum that didn't finish
+    let num_pages = ctx.index.len() as PageId;
+    ctx.free_map.truncate(num_pages)?;
+    for location in ctx.index.iter().flatten() {
+        ctx.segments.get_mut(&location.segment).unwrap().live += 1;
+    }
+    Ok(RwSynchronized::init(ctx))
+}
+
+/// Log-structured disk manager. Clones refer to the same disk manager
+pub type LogStructuredDiskMgr = RwSynchronized<LogStructuredDiskMgrCtx>;
+
+pub trait LogStructuredApi {
+    fn create_with_segment_pages(
+        config: &StorageConfig,
+        segment_pages: usize,
+    ) -> Result<Self, StorageError>
+    where
+        Self: Sized;
+    fn open_with_segment_pages(
+        config: &StorageConfig,
+        segment_pages: usize,
+    ) -> Result<Self, StorageError>
+    where
+        Self: Sized;
+    fn compact(&self) -> Result<CompactionStats, StorageError>;
+    fn start_compaction(&self, interval: Duration) -> BackgroundWriter;
+    fn segment_count(&self) -> usize;
+    fn stored_bytes(&self) -> u64;
+}
+
+impl LogStructuredApi for LogStructuredDiskMgr {
+    /// Create a new, empty log whose segments hold `segment_pages` pages each
+    fn create_with_segment_pages(
+        config: &StorageConfig,
+        segment_pages: usize,
+    ) -> Result<Self, StorageError> {
+        Ok(open_file(config, segment_pages, true)?)
+    }
+
+    /// Open an existing log (or a new one, if there's none), starting new segments of `segment_pages` pages. Segments that
+    /// were written with another size are read as they are
+    fn open_with_segment_pages(
+        config: &StorageConfig,
+        segment_pages: usize,
+    ) -> Result<Self, StorageError> {
+        Ok(open_file(config, segment_pages, false)?)
+    }
+
+    /// Compact every sealed segment with no more than `COMPACTION_THRESHOLD` of its pages live: append its live pages to the
+    /// active segment again, then sync and delete it. Pages are moved one at a time under the latch, so reads and writes go on
+    /// in between; a page written meanwhile no longer needs moving
+    fn compact(&self) -> Result<CompactionStats, StorageError> {
+        let victims: Vec<u64> = {
+            let inner = self.read();
+            inner
+                .segments
+                .iter()
+                .filter(|(number, segment)| {
+                    **number != inner.active
+                        && segment.live as f64 <= segment.len as f64 * COMPACTION_THRESHOLD
+                })
+                .map(|(number, _)| *number)
+                .collect()
+        };
+        let mut stats = CompactionStats::default();
+        if victims.is_empty() {
+            return Ok(stats);
+        }
+        let pages: Vec<(PageId, Location)> = {
+            let inner = self.read();
+            inner
+                .index
+                .iter()
+                .enumerate()
+                .filter_map(|(page_id, location)| Some((page_id as PageId, (*location)?)))
+                .filter(|(_, location)| victims.contains(&location.segment))
+                .collect()
+        };
+        let mut buf = page::empty();
+        for (page_id, location) in pages {
+            let mut inner = self.write();
+            inner.check_writable()?;
+            if inner.index[page_id as usize] != Some(location) {
+                continue;
+            }
+            inner.read_at(&mut buf, location)?;
+            trace::io("compact", page_id, || inner.append(&buf, page_id))?;
+            stats.moved += 1;
+        }
+
+        let mut inner = self.write();
+        inner.check_writable()?;
+        // the saved index must stop pointing to a segment before it goes
+        inner.index_dirty = true;
+        inner.sync(true)?;
+        for number in victims {
+            if inner.segments[&number].live > 0 {
+                continue;
+            }
+            inner.segments.remove(&number);
+            inner.unsynced_segments.remove(&number);
+            std::fs::remove_file(segment_path(&inner.path, number))?;
+            stats.segments += 1;
+        }
+        Ok(stats)
+    }
+
+    /// Run `compact` every `interval` on a background thread, until the handle is stopped or dropped
+    fn start_compaction(&self, interval: Duration) -> BackgroundWriter {
+        let mgr = self.clone();
+        BackgroundWriter::spawn(interval, move || {
+            let _ = mgr.compact();
+        })
+    }
+
+    fn segment_count(&self) -> usize {
+        self.read().segments.len()
+    }
+
+    /// Size of every segment, in bytes, garbage included
+    fn stored_bytes(&self) -> u64 {
+        let inner = self.read();
+        inner
+            .segments
+            .values()
+            .map(|segment| segment.len * PAGE_SIZE as u64)
+            .sum()
+    }
+}
+
+impl DiskApi for LogStructuredDiskMgr {
+    fn create(path: &str) -> Result<Self, StorageError> {
+        LogStructuredDiskMgr::create_with_durability(path, DurabilityMode::default())
+    }
+
+    fn create_with_durability(
+        path: &str,
+        durability: DurabilityMode,
+    ) -> Result<Self, StorageError> {
+        LogStructuredDiskMgr::create_with_sync_policy(path, durability, SyncPolicy::default())
+    }
+
+    fn create_with_sync_policy(
+        path: &str,
+        durability: DurabilityMode,
+        policy: SyncPolicy,
+    ) -> Result<Self, StorageError> {
+        LogStructuredDiskMgr::create_with_config(
+            &StorageConfig::for_path(path)
+                .with_durability(durability)
+                .with_sync_policy(policy),
+        )
+    }
+
+    fn open(path: &str) -> Result<Self, StorageError> {
+        LogStructuredDiskMgr::open_with_durability(path, DurabilityMode::default())
+    }
+
+    fn open_with_durability(path: &str, durability: DurabilityMode) -> Result<Self, StorageError> {
+        LogStructuredDiskMgr::open_with_config(
+            &StorageConfig::for_path(path).with_durability(durability),
+        )
+    }
+
+    /// Create a new, empty log with segments of `DEFAULT_SEGMENT_PAGES` pages
+    fn create_with_config(config: &StorageConfig) -> Result<Self, StorageError> {
+        LogStructuredDiskMgr::create_with_segment_pages(config, DEFAULT_SEGMENT_PAGES)
+    }
+
+    fn open_with_config(config: &StorageConfig) -> Result<Self, StorageError> {
+        LogStructuredDiskMgr::open_with_segment_pages(config, DEFAULT_SEGMENT_PAGES)
+    }
+
+    /// Read the latest version of a page. Fails like `DiskMgr::read_page`
+    fn read_page(&self, buf: &mut [u8; PAGE_SIZE], loc: u64) -> Result<(), StorageError> {
+        trace::io("read", loc as PageId, || {
+            self.read().read_page(buf, loc as PageId)
+        })
+    }
+
+    fn read_page_exists(&self, page_id: PageId) -> bool {
+        page_id >= 0 && (page_id as usize) < self.read().index.len()
+    }
+
+    fn write_page(&self, buf: &[u8; PAGE_SIZE], loc: u64) -> Result<(), StorageError> {
+        Ok(trace::io("write", loc as PageId, || {
+            self.write().write_page(buf, loc as PageId)
+        })?)
+    }
+
+    fn append_page(&self, buf: &[u8; PAGE_SIZE]) -> Result<PageId, StorageError> {
+        Ok(trace::io("append", INVALID_PAGE_ID, || {
+            self.write().append_page(buf)
+        })?)
+    }
+
+    /// See `DiskMgr::allocate_page`
+    fn allocate_page(&self, buf: &[u8; PAGE_SIZE]) -> Result<PageId, StorageError> {
+        let mut inner = self.write();
+        inner.check_writable()?;
+        let Some(page_id) = inner.free_map.first_free() else {
+            return Ok(inner.append_page(buf)?);
+        };
+        inner.free_map.set_free(page_id, false)?;
+        inner.write_page(buf, page_id)?;
+        Ok(page_id)
+    }
+
+    fn deallocate_page(&self, page_id: PageId) -> Result<(), StorageError> {
+        let mut inner = self.write();
+        inner.check_writable()?;
+        if inner.free_map.is_free(page_id) || page_id < 0 || page_id as usize >= inner.index.len() {
+            return Ok(());
+        }
+        Ok(inner.free_map.set_free(page_id, true)?)
+    }
+
+    fn free_pages(&self) -> Vec<PageId> {
+        self.read().free_map.free_pages()
+    }
+
+    /// Forget the free pages at the end of the index (see `DiskMgr::vacuum`). Their versions become garbage, for compaction
+    /// to reclaim
+    fn vacuum(&self) -> Result<usize, StorageError> {
+        let mut inner = self.write();
+        inner.check_writable()?;
+        let num_pages = inner.index.len();
+        let mut end = num_pages as PageId;
+        while end > 0 && inner.free_map.is_free(end - 1) {
+            end -= 1;
+        }
+        if end == num_pages as PageId {
+            return Ok(0);
+        }
+        let removed: Vec<Location> = inner.index.drain(end as usize..).flatten().collect();
+        for location in removed {
+            inner.segments.get_mut(&location.segment).unwrap().live -= 1;
+        }
+        inner.unsynced.retain(|page_id| *page_id < end);
+        inner.index_dirty = true;
+        inner.sync(true)?;
+        inner.free_map.truncate(end)?;
+        Ok(num_pages - end as usize)
+    }
+
+    /// Write a batch of pages, syncing once at the end if the sync policy says so
+    fn write_pages(&self, pages: &[(PageId, &[u8; PAGE_SIZE])]) -> Result<(), StorageError> {
+        let mut inner = self.write();
+        for (page_id, buf) in pages {
+            trace::io("write", *page_id, || inner.put_page(buf, *page_id))?;
+        }
+        Ok(inner.sync_after_write()?)
+    }
+
+    fn read_pages(&self, pages: &mut [(PageId, &mut [u8; PAGE_SIZE])]) -> Result<(), StorageError> {
+        let inner = self.read();
+        for (page_id, buf) in pages.iter_mut() {
+            trace::io("read", *page_id, || inner.read_page(buf, *page_id))?;
+        }
+        Ok(())
+    }
+
+    fn num_pages(&self) -> Result<usize, StorageError> {
+        Ok(self.read().index.len())
+    }
+
+    fn verify_all(&self) -> Result<Vec<PageId>, StorageError> {
+        let inner = self.read();
+        let mut corrupted = Vec::new();
+        let mut buf = page::empty();
+        for page_id in 0..inner.index.len() as PageId {
+            match inner.read_page(&mut buf, page_id) {
+                Err(StorageError::Corruption(_)) => corrupted.push(page_id),
+                result => result?,
+            }
+        }
+        Ok(corrupted)
+    }
+
+    fn sync(&self) -> Result<(), StorageError> {
+        let mut inner = self.write();
+        inner.check_writable()?;
+        if inner.policy == SyncPolicy::Never || inner.unsynced.is_empty() {
+            return Ok(());
+        }
+        Ok(inner.sync(false)?)
+    }
+
+    fn sync_policy(&self) -> SyncPolicy {
+        self.read().policy
+    }
+
+    fn recovery_required(&self) -> bool {
+        self.read().recovery_required
+    }
+
+    fn suspect_pages(&self) -> Vec<PageId> {
+        let mut pages: Vec<PageId> = self.read().suspect.iter().copied().collect();
+        pages.sort_unstable();
+        pages
+    }
+
+    fn stats(&self) -> DiskStats {
+        let inner = self.read();
+        DiskStats {
+            writes: inner.num_writes,
+            flushes: inner.num_flushes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::buffer::io;
+    use crate::testing::Song;
+
+    fn setup(name: &str) -> (String, StorageConfig) {
+        let dir = cwd() + "/tests/logstruct_" + name + "_tests";
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = StorageConfig::for_path(&(dir.clone() + "/data.bin"))
+            .with_sync_policy(SyncPolicy::OnFlush);
+        (dir, config)
+    }
+
+    fn song_page(id: i32, title: &str) -> Page {
+        io::to_buffer(Song::new(id, title, "Men I Trust")).unwrap()
+    }
+
+    fn read_song(mgr: &impl DiskApi, page_id: PageId) -> Result<Song, StorageError> {
+        let mut buf = page::empty();
+        mgr.read_page(&mut buf, page_id as u64)?;
+        io::from_buffer(&buf)
+    }
+
+    #[test]
+    fn test_log_structured_pages() {
+        let (dir, config) = setup("pages");
+        let mgr = LogStructuredDiskMgr::create_with_segment_pages(&config, 8).unwrap();
+        for id in 0..20 {
+            assert!(mgr.append_page(&song_page(id, "Tailwhip")).unwrap() == id as PageId);
+        }
+        assert!(mgr.segment_count() == 3);
+        // rewrites append new versions, and reads see the latest
+        for i in 0..4 {
+            mgr.write_page(&song_page(100 + i, "Numb"), 3).unwrap();
+        }
+        assert!(read_song(&mgr, 3).unwrap().id == 103);
+        assert!(mgr.stored_bytes() == 24 * PAGE_SIZE as u64);
+        // page 21 was never written, pages past 22 were never allocated
+        mgr.write_page(&song_page(22, "Seven"), 22).unwrap();
+        let mut buf = page::empty();
+        mgr.read_page(&mut buf, 21).unwrap();
+        assert!(page::is_zeroed(&buf));
+        assert!(matches!(
+            read_song(&mgr, 23),
+            Err(StorageError::PageNotFound(23))
+        ));
+        drop(mgr);
+
+        let reopened = LogStructuredDiskMgr::open_with_config(&config).unwrap();
+        assert!(reopened.num_pages().unwrap() == 23);
+        assert!(read_song(&reopened, 3).unwrap().id == 103);
+        assert!(reopened.verify_all().unwrap().is_empty());
+
+        // writes after the index was last saved are replayed from the segments, and a lost index is rebuilt from them
+        reopened.write_page(&song_page(-1, "Lauren"), 5).unwrap();
+        std::mem::forget(reopened);
+        let replayed = LogStructuredDiskMgr::open_with_config(&config).unwrap();
+        assert!(read_song(&replayed, 5).unwrap().id == -1);
+        drop(replayed);
+        let path = config.data_path().to_string_lossy().into_owned();
+        std::fs::remove_file(index_path(&path)).unwrap();
+        let rebuilt = LogStructuredDiskMgr::open_with_config(&config).unwrap();
+        assert!(rebuilt.num_pages().unwrap() == 23);
+        assert!(read_song(&rebuilt, 3).unwrap().id == 103);
+        assert!(read_song(&rebuilt, 5).unwrap().id == -1);
+        assert!(read_song(&rebuilt, 19).unwrap().id == 19);
+
+        // a torn version at the end of the log is cut off
+        let handle = OpenOptions::new()
+            .write(true)
+            .open(segment_path(&path, rebuilt.read().active))
+            .unwrap();
+        let stored = rebuilt.stored_bytes();
+        rebuilt.write_page(&song_page(-2, "Lauren"), 6).unwrap();
+        let offset = rebuilt.read().index[6].unwrap().offset();
+        std::mem::forget(rebuilt);
+        fs::write_all_at(&handle, &[0xff; 16], offset + 100).unwrap();
+        let recovered = LogStructuredDiskMgr::open_with_config(&config).unwrap();
+        assert!(read_song(&recovered, 6).unwrap().id == 6);
+        assert!(recovered.stored_bytes() == stored);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compaction() {
+        let (dir, config) = setup("compaction");
+        let mgr = LogStructuredDiskMgr::create_with_segment_pages(&config, 4).unwrap();
+        for id in 0..16 {
+            mgr.allocate_page(&song_page(id, "Show Me How")).unwrap();
+        }
+        // the first three segments become mostly garbage
+        for id in 0..10 {
+            mgr.write_page(&song_page(id + 100, "Show Me How"), id as u64)
+                .unwrap();
+        }
+        let before = mgr.segment_count();
+        let stats = mgr.compact().unwrap();
+        assert!(stats.segments == 3 && stats.moved == 2);
+        assert!(mgr.segment_count() == before - 3);
+        for id in 0..16 {
+            let expected = if id < 10 { id + 100 } else { id };
+            assert!(read_song(&mgr, id as PageId).unwrap().id == expected);
+        }
+        assert!(mgr.compact().unwrap() == CompactionStats::default());
+
+        // compaction in the background keeps up with concurrent writers without losing a write
+        let compaction = mgr.start_compaction(Duration::from_millis(1));
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let mgr = mgr.clone();
+                s.spawn(move || {
+                    for round in 0..200 {
+                        let page_id = t * 4 + round % 4;
+                        let song = song_page(round, "Seven");
+                        mgr.write_page(&song, page_id as u64).unwrap();
+                    }
+                });
+            }
+        });
+        compaction.stop();
+        mgr.compact().unwrap();
+        for page_id in 0..16 {
+            assert!(read_song(&mgr, page_id).unwrap().id >= 196);
+        }
+        assert!(mgr.stored_bytes() <= 32 * PAGE_SIZE as u64);
+        drop(mgr);
+        let reopened = LogStructuredDiskMgr::open_with_config(&config).unwrap();
+        assert!(reopened.verify_all().unwrap().is_empty());
+        assert!(read_song(&reopened, 15).unwrap().id == 199);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
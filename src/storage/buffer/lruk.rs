@@ -1,15 +1,249 @@
-#![allow(dead_code)]
+/// This file implements the LRU-K replacement policy used by the buffer pool. The replacer tracks the timestamps of the last k
+/// accesses to every frame and evicts the evictable frame whose backward k-distance (the time since its k-th most recent
+/// access) is largest. Frames with fewer than k recorded accesses have an infinite backward k-distance; among those, the one
+/// whose earliest recorded access is oldest is evicted first (i.e. plain LRU).
+use std::collections::{HashMap, VecDeque};
 
-use crate::{shared::FrameId, sync::Synchronized};
+use crate::{
+    shared::FrameId,
+    storage::buffer::replacer::Replacer,
+    sync::{Latch as _, Synchronized},
+};
+
+struct FrameHistory {
+    // timestamps of the last k accesses, oldest first
+    history: VecDeque<usize>,
+    evictable: bool,
+}
 
 pub struct LRUKReplacerInternal {
     num_frames: usize,
     k: usize,
+    current_timestamp: usize,
+    frames: HashMap<FrameId, FrameHistory>,
+    num_evictable: usize,
+}
+
+impl LRUKReplacerInternal {
+    /// Eviction priority of a frame: frames with fewer than k accesses come first (ordered by their oldest access), then
+    /// frames ordered by their k-th most recent access. The smallest key is evicted.
+    fn eviction_key(&self, entry: &FrameHistory) -> (bool, usize) {
+        let oldest = *entry.history.front().unwrap();
+        (entry.history.len() >= self.k, oldest)
+    }
+
+    fn check_frame(&self, frame_id: FrameId) {
+        assert!(
+            frame_id >= 1 && frame_id as usize <= self.num_frames,
+            "invalid frame id {}",
+            frame_id
+        );
+    }
 }
 
 pub type LRUKReplacer = Synchronized<LRUKReplacerInternal>;
 
-pub trait Replacer {
-    fn evict(frame_id: FrameId) -> bool;
-    fn record_access(frame_id: FrameId);
+pub trait LRUKReplacerApi {
+    fn create(num_frames: usize, k: usize) -> Self;
+}
+
+impl LRUKReplacerApi for LRUKReplacer {
+    /// Create a replacer for frames `1..=num_frames` that keeps the last `k` accesses of each
+    fn create(num_frames: usize, k: usize) -> Self {
+        assert!(k > 0);
+        Synchronized::init(LRUKReplacerInternal {
+            num_frames,
+            k,
+            current_timestamp: 0,
+            frames: HashMap::new(),
+            num_evictable: 0,
+        })
+    }
+}
+
+impl Replacer for LRUKReplacer {
+    /// Evict the evictable frame with the largest backward k-distance and forget its access history. Returns `None` if no
+    /// frame is evictable.
+    fn evict(&self) -> Option<FrameId> {
+        let mut inner = self.lock();
+        let victim = *inner
+            .frames
+            .iter()
+            .filter(|(_, entry)| entry.evictable)
+            .min_by_key(|(_, entry)| inner.eviction_key(entry))?
+            .0;
+        inner.frames.remove(&victim);
+        inner.num_evictable -= 1;
+        Some(victim)
+    }
+
+    /// Record an access to `frame_id` at the current logical timestamp. Frames seen for the first time start out non-evictable
+    fn record_access(&self, frame_id: FrameId) {
+        let mut inner = self.lock();
+        inner.check_frame(frame_id);
+        inner.current_timestamp += 1;
+        let now = inner.current_timestamp;
+        let k = inner.k;
+        let entry = inner.frames.entry(frame_id).or_insert(FrameHistory {
+            history: VecDeque::with_capacity(k),
+            evictable: false,
+        });
+        if entry.history.len() == k {
+            entry.history.pop_front();
+        }
+        entry.history.push_back(now);
+    }
+
+    /// Mark a frame as (non-)evictable. Frames the replacer has never seen are ignored
+    fn set_evictable(&self, frame_id: FrameId, evictable: bool) {
+        let mut inner = self.lock();
+        inner.check_frame(frame_id);
+        let Some(entry) = inner.frames.get_mut(&frame_id) else {
+            return;
+        };
+        if entry.evictable == evictable {
+            return;
+        }
+        entry.evictable = evictable;
+        if evictable {
+            inner.num_evictable += 1;
+        } else {
+            inner.num_evictable -= 1;
+        }
+    }
+
+    /// Forget a frame's access history (e.g. because its page was deleted). Only evictable frames can be removed; removing a
+    /// non-evictable or unknown frame is a no-op.
+    fn remove(&self, frame_id: FrameId) {
+        let mut inner = self.lock();
+        match inner.frames.get(&frame_id) {
+            Some(entry) if entry.evictable => {}
+            _ => return,
+        }
+        inner.frames.remove(&frame_id);
+        inner.num_evictable -= 1;
+    }
+
+    /// Number of evictable frames
+    fn size(&self) -> usize {
+        let inner = self.lock();
+        inner.num_evictable
+    }
+
+    fn resize(&self, num_frames: usize) {
+        let mut inner = self.lock();
+        debug_assert!(inner.frames.keys().all(|id| *id as usize <= num_frames));
+        inner.num_frames = num_frames;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rayon::ThreadPoolBuilder;
+
+    use super::*;
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let replacer = LRUKReplacer::create(4, 2);
+        for frame_id in 1..=4 {
+            replacer.record_access(frame_id);
+            replacer.set_evictable(frame_id, true);
+        }
+        replacer.record_access(1);
+        replacer.set_evictable(2, false);
+
+        assert!(replacer.evict() == Some(3));
+        assert!(replacer.evict() == Some(4));
+        assert!(replacer.evict() == Some(1));
+        assert!(replacer.evict().is_none());
+
+        replacer.set_evictable(2, true);
+        replacer.remove(2);
+        assert!(replacer.evict().is_none());
+    }
+
+    #[test]
+    fn test_backward_k_distance() {
+        let replacer = LRUKReplacer::create(7, 2);
+        // access order: 1 2 3 4 5 6 1 2 3 4 1 5 (frame 6 has a single access -> infinite k-distance)
+        for frame_id in [1, 2, 3, 4, 5, 6, 1, 2, 3, 4, 1, 5] {
+            replacer.record_access(frame_id);
+        }
+        for frame_id in 1..=6 {
+            replacer.set_evictable(frame_id, true);
+        }
+        assert!(replacer.size() == 6);
+
+        // frame 6 has fewer than k accesses, so it goes first
+        assert!(replacer.evict() == Some(6));
+        // 2nd most recent accesses: 1@7, 2@2, 3@3, 4@4, 5@5
+        assert!(replacer.evict() == Some(2));
+
+        replacer.set_evictable(3, false);
+        assert!(replacer.size() == 3);
+        assert!(replacer.evict() == Some(4));
+
+        // a fresh access moves frame 5's 2nd most recent access from 5 to 12, behind frame 1
+        replacer.record_access(5);
+        assert!(replacer.evict() == Some(1));
+        assert!(replacer.evict() == Some(5));
+        assert!(replacer.evict().is_none());
+
+        replacer.set_evictable(3, true);
+        replacer.remove(3);
+        assert!(replacer.size() == 0);
+    }
+
+    #[test]
+    fn test_concurrent_access() {
+        let replacer = LRUKReplacer::create(64, 3);
+        let pool = ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        pool.scope(|s| {
+            for t in 0..8 {
+                let replacer = replacer.clone();
+                s.spawn(move |_| {
+                    for round in 0..100 {
+                        let frame_id = (t * 8 + round % 8 + 1) as FrameId;
+                        replacer.record_access(frame_id);
+                        replacer.set_evictable(frame_id, round % 2 == 0);
+                    }
+                });
+            }
+        });
+        // frames are only ever touched on rounds of the same parity, so the even ones end up evictable
+        assert!(replacer.size() == 32);
+        for frame_id in 1..=64 {
+            replacer.set_evictable(frame_id, true);
+        }
+        assert!(replacer.size() == 64);
+        let mut evicted = 0;
+        while replacer.evict().is_some() {
+            evicted += 1;
+        }
+        assert!(evicted == 64);
+    }
+
+    #[cfg(feature = "loom")]
+    #[test]
+    fn loom_record_access() {
+        let mut model = loom::model::Builder::new();
+        model.preemption_bound = Some(3);
+        model.check(|| {
+            let replacer = LRUKReplacer::create(2, 2);
+            replacer.record_access(2);
+            replacer.set_evictable(2, true);
+            replacer.record_access(1);
+            let other = {
+                let replacer = replacer.clone();
+                loom::thread::spawn(move || replacer.record_access(1))
+            };
+            replacer.set_evictable(1, true);
+            other.join().unwrap();
+            // however the two threads interleave, frame 1 ends up with k accesses and evictable, so it goes last
+            assert!(replacer.size() == 2);
+            assert!(replacer.evict() == Some(2));
+            assert!(replacer.evict() == Some(1));
+        });
+    }
 }
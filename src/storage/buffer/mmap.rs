@@ -0,0 +1,472 @@
+/// This file implements a disk manager that memory-maps the data file instead of going through positional reads and writes,
+/// to compare the two IO paths. `read_page` copies a page out of the mapping and `write_page` copies one into it; the kernel
+/// writes dirty mapped pages back on its own schedule, and a sync forces them out with `msync`. Page ids, checksums, the free
+/// page map, the sync policy and fsync failure handling behave as they do for `DiskMgr`, so the two are interchangeable behind
+/// `DiskApi`.
+///
+/// The mapping covers at least `MIN_MAPPED_PAGES` pages and may extend past the end of the file, which is fine as long as
+/// nothing touches the part past the end. An append that needs more than the mapping covers extends the file and maps it again
+/// at twice the size. Remapping invalidates the old mapping, so reads take the disk manager's latch shared and everything that
+/// writes or resizes takes it exclusively.
+///
+/// `msync` only covers the data, so a sync also syncs the file itself when its length changed since the last one (and always
+/// under `DurabilityMode::Full`, for the rest of its metadata). `DurabilityMode::DSync` has no meaning for a mapping and
+/// behaves like `Data`. The mapping is made with `memmap2`, which comes with the `mmap` feature.
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::ops::Range;
+use std::time::Instant;
+
+use memmap2::{MmapMut, MmapOptions};
+
+use crate::shared::{PageId, INVALID_PAGE_ID, PAGE_SIZE};
+use crate::storage::buffer::diskmgr::{
+    stamped, verify, DiskApi, DiskStats, DurabilityMode, FsyncFailed, SyncPolicy,
+};
+use crate::storage::buffer::freemap::FreePageMap;
+use crate::storage::buffer::page::{ChecksumAlgorithm, Page};
+use crate::storage::config::StorageConfig;
+use crate::storage::error::StorageError;
+use crate::sync::{RwLatch as _, RwSynchronized};
+use crate::telemetry::trace;
+
+/// Fewest pages the mapping covers
+pub const MIN_MAPPED_PAGES: usize = 64;
+
+/// A shared, writable mapping of the start of a file. It's unmapped when dropped
+struct Mapping {
+    map: MmapMut,
+}
+
+impl Mapping {
+    /// Map the first `pages` pages of `handle`, whether or not the file is that long yet
+    fn map(handle: &File, pages: usize) -> std::io::Result<Mapping> {
+        // the disk manager owns the file, and never touches the part of the mapping past its end (see the top of the file)
+        let map = unsafe { MmapOptions::new().len(pages * PAGE_SIZE).map_mut(handle)? };
+        Ok(Mapping { map })
+    }
+
+    fn pages(&self) -> usize {
+        self.map.len() / PAGE_SIZE
+    }
+
+    fn page(&self, page_id: PageId) -> &Page {
+        let start = page_id as usize * PAGE_SIZE;
+        self.map[start..start + PAGE_SIZE].try_into().unwrap()
+    }
+
+    fn page_mut(&mut self, page_id: PageId) -> &mut Page {
+        let start = page_id as usize * PAGE_SIZE;
+        (&mut self.map[start..start + PAGE_SIZE])
+            .try_into()
+            .unwrap()
+    }
+
+    /// Write the pages in `pages` back to the file, waiting until they're written
+    fn flush(&self, pages: Range<usize>) -> std::io::Result<()> {
+        let end = pages.end.min(self.pages());
+        if pages.start >= end {
+            return Ok(());
+        }
+        self.map
+            .flush_range(pages.start * PAGE_SIZE, (end - pages.start) * PAGE_SIZE)
+    }
+}
+
+pub struct MmapDiskMgrCtx {
+    handle: File,
+    map: Mapping,
+    // whole pages in the file. The mapping may cover more
+    num_pages: usize,
+    durability: DurabilityMode,
+    policy: SyncPolicy,
+    checksum: ChecksumAlgorithm,
+    last_sync: Instant,
+    // pages written since the last successful sync
+    unsynced: HashSet<PageId>,
+    // pages that were unsynced when a sync failed. their contents on disk are unknown
+    suspect: HashSet<PageId>,
+    // whether the file's length changed since the last successful sync
+    resized: bool,
+    recovery_required: bool,
+    free_map: FreePageMap,
+    num_writes: usize,
+    num_flushes: usize,
+}
+
+impl MmapDiskMgrCtx {
+    /// Make the file `pages` pages long, mapping it again if the mapping no longer covers it
+    fn resize(&mut self, pages: usize) -> std::io::Result<()> {
+        self.handle.set_len((pages * PAGE_SIZE) as u64)?;
+        self.num_pages = pages;
+        self.resized = true;
+        if pages > self.map.pages() {
+            self.map = Mapping::map(&self.handle, pages.max(self.map.pages() * 2))?;
+        }
+        Ok(())
+    }
+
+    /// Sync the data file (see `DiskMgrCtx::sync`). On failure, every unsynced page becomes suspect and the disk manager
+    /// enters the recovery-required state
+    fn sync(&mut self) -> std::io::Result<()> {
+        if trace::io("sync", INVALID_PAGE_ID, || self.sync_mapping()).is_err() {
+            return Err(self.fail_sync());
+        }
+        self.unsynced.clear();
+        self.resized = false;
+        self.num_flushes += 1;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    fn sync_mapping(&self) -> std::io::Result<()> {
+        let first = self.unsynced.iter().min().copied().unwrap_or_default() as usize;
+        let last = self.unsynced.iter().max().copied().unwrap_or(-1);
+        self.map.flush(first..(last + 1) as usize)?;
+        match self.durability {
+            DurabilityMode::Full => self.handle.sync_all(),
+            _ if self.resized => self.handle.sync_data(),
+            _ => Ok(()),
+        }
+    }
+
+    fn sync_after_write(&mut self) -> std::io::Result<()> {
+        match self.policy {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Interval(interval) if self.last_sync.elapsed() >= interval => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    fn fail_sync(&mut self) -> std::io::Error {
+        self.suspect.extend(self.unsynced.drain());
+        self.recovery_required = true;
+        self.fsync_error()
+    }
+
+    fn check_writable(&self) -> std::io::Result<()> {
+        if self.recovery_required {
+            return Err(self.fsync_error());
+        }
+        Ok(())
+    }
+
+    fn fsync_error(&self) -> std::io::Error {
+        let mut pages: Vec<PageId> = self.suspect.iter().copied().collect();
+        pages.sort_unstable();
+        std::io::Error::other(FsyncFailed { pages })
+    }
+
+    /// Copy `buf` into `page_id`, extending the file if the page lies past its end. Doesn't sync
+    fn put_page(&mut self, buf: &Page, page_id: PageId) -> std::io::Result<()> {
+        self.check_writable()?;
+        if page_id as usize >= self.num_pages {
+            self.resize(page_id as usize + 1)?;
+        }
+        *self.map.page_mut(page_id) = stamped(buf, page_id, self.checksum);
+        self.unsynced.insert(page_id);
+        self.num_writes += 1;
+        Ok(())
+    }
+
+    fn write_page(&mut self, buf: &Page, page_id: PageId) -> std::io::Result<()> {
+        self.put_page(buf, page_id)?;
+        self.sync_after_write()
+    }
+
+    fn append_page(&mut self, buf: &Page) -> std::io::Result<PageId> {
+        let page_id = self.num_pages as PageId;
+        self.write_page(buf, page_id)?;
+        Ok(page_id)
+    }
+
+    fn read_page(&self, buf: &mut Page, page_id: PageId) -> Result<(), StorageError> {
+        if page_id < 0 || page_id as usize >= self.num_pages {
+            return Err(StorageError::PageNotFound(page_id));
+        }
+        buf.copy_from_slice(self.map.page(page_id));
+        verify(buf, page_id)
+    }
+}
+
+/// Disk manager over a memory-mapped data file. Clones refer to the same disk manager
+pub type MmapDiskMgr = RwSynchronized<MmapDiskMgrCtx>;
+
+fn open_file(config: &StorageConfig, truncate: bool) -> std::io::Result<MmapDiskMgr> {
+    let path = config.data_path();
+    let path = path.to_string_lossy();
+    let handle = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .truncate(truncate)
+        .open(std::path::Path::new(path.as_ref()))?;
+    let num_pages = (handle.metadata()?.len() / PAGE_SIZE as u64) as usize;
+    let mut free_map = FreePageMap::open(&path, truncate)?;
+    // free bits past the end of the file are left over from a vacuum that didn't finish
+    free_map.truncate(num_pages as PageId)?;
+    let map = Mapping::map(&handle, num_pages.max(MIN_MAPPED_PAGES))?;
+
+    Ok(RwSynchronized::init(MmapDiskMgrCtx {
+        handle,
+        map,
+        num_pages,
+        durability: config.durability(),
+        policy: config.sync_policy(),
+        checksum: config.checksum(),
+        last_sync: Instant::now(),
+        unsynced: HashSet::new(),
+        suspect: HashSet::new(),
+        resized: false,
+        recovery_required: false,
+        free_map,
+        num_writes: 0,
+        num_flushes: 0,
+    }))
+}
+
+impl DiskApi for MmapDiskMgr {
+    fn create(path: &str) -> Result<Self, StorageError> {
+        MmapDiskMgr::create_with_durability(path, DurabilityMode::default())
+    }
+
+    fn create_with_durability(
+        path: &str,
+        durability: DurabilityMode,
+    ) -> Result<Self, StorageError> {
+        MmapDiskMgr::create_with_sync_policy(path, durability, SyncPolicy::default())
+    }
+
+    fn create_with_sync_policy(
+        path: &str,
+        durability: DurabilityMode,
+        policy: SyncPolicy,
+    ) -> Result<Self, StorageError> {
+        MmapDiskMgr::create_with_config(
+            &StorageConfig::for_path(path)
+                .with_durability(durability)
+                .with_sync_policy(policy),
+        )
+    }
+
+    fn open(path: &str) -> Result<Self, StorageError> {
+        MmapDiskMgr::open_with_durability(path, DurabilityMode::default())
+    }
+
+    fn open_with_durability(path: &str, durability: DurabilityMode) -> Result<Self, StorageError> {
+        MmapDiskMgr::open_with_config(&StorageConfig::for_path(path).with_durability(durability))
+    }
+
+    fn create_with_config(config: &StorageConfig) -> Result<Self, StorageError> {
+        Ok(open_file(config, true)?)
+    }
+
+    fn open_with_config(config: &StorageConfig) -> Result<Self, StorageError> {
+        Ok(open_file(config, false)?)
+    }
+
+    /// Copy a page out of the mapping and verify its checksum. Fails like `DiskMgr::read_page`
+    fn read_page(&self, buf: &mut [u8; PAGE_SIZE], loc: u64) -> Result<(), StorageError> {
+        trace::io("read", loc as PageId, || {
+            self.read().read_page(buf, loc as PageId)
+        })
+    }
+
+    fn read_page_exists(&self, page_id: PageId) -> bool {
+        page_id >= 0 && (page_id as usize) < self.read().num_pages
+    }
+
+    /// Copy a page into the mapping, then sync it if the sync policy says so
+    fn write_page(&self, buf: &[u8; PAGE_SIZE], loc: u64) -> Result<(), StorageError> {
+        Ok(trace::io("write", loc as PageId, || {
+            self.write().write_page(buf, loc as PageId)
+        })?)
+    }
+
+    fn append_page(&self, buf: &[u8; PAGE_SIZE]) -> Result<PageId, StorageError> {
+        Ok(trace::io("append", INVALID_PAGE_ID, || {
+            self.write().append_page(buf)
+        })?)
+    }
+
+    /// See `DiskMgr::allocate_page`
+    fn allocate_page(&self, buf: &[u8; PAGE_SIZE]) -> Result<PageId, StorageError> {
+        let mut inner = self.write();
+        inner.check_writable()?;
+        let Some(page_id) = inner.free_map.first_free() else {
+            return Ok(inner.append_page(buf)?);
+        };
+        inner.free_map.set_free(page_id, false)?;
+        inner.write_page(buf, page_id)?;
+        Ok(page_id)
+    }
+
+    fn deallocate_page(&self, page_id: PageId) -> Result<(), StorageError> {
+        let mut inner = self.write();
+        inner.check_writable()?;
+        if inner.free_map.is_free(page_id) || page_id < 0 || page_id as usize >= inner.num_pages {
+            return Ok(());
+        }
+        Ok(inner.free_map.set_free(page_id, true)?)
+    }
+
+    fn free_pages(&self) -> Vec<PageId> {
+        self.read().free_map.free_pages()
+    }
+
+    /// See `DiskMgr::vacuum`. The mapping keeps its size
+    fn vacuum(&self) -> Result<usize, StorageError> {
+        let mut inner = self.write();
+        inner.check_writable()?;
+        let num_pages = inner.num_pages;
+        let mut end = num_pages as PageId;
+        while end > 0 && inner.free_map.is_free(end - 1) {
+            end -= 1;
+        }
+        if end == num_pages as PageId {
+            return Ok(0);
+        }
+        inner.unsynced.retain(|page_id| *page_id < end);
+        inner.resize(end as usize)?;
+        inner.sync()?;
+        inner.free_map.truncate(end)?;
+        Ok(num_pages - end as usize)
+    }
+
+    /// Copy a batch of pages into the mapping, syncing once at the end if the sync policy says so
+    fn write_pages(&self, pages: &[(PageId, &[u8; PAGE_SIZE])]) -> Result<(), StorageError> {
+        let mut inner = self.write();
+        for (page_id, buf) in pages {
+            trace::io("write", *page_id, || inner.put_page(buf, *page_id))?;
+        }
+        Ok(inner.sync_after_write()?)
+    }
+
+    fn read_pages(&self, pages: &mut [(PageId, &mut [u8; PAGE_SIZE])]) -> Result<(), StorageError> {
+        let inner = self.read();
+        for (page_id, buf) in pages.iter_mut() {
+            trace::io("read", *page_id, || inner.read_page(buf, *page_id))?;
+        }
+        Ok(())
+    }
+
+    fn num_pages(&self) -> Result<usize, StorageError> {
+        Ok(self.read().num_pages)
+    }
+
+    fn verify_all(&self) -> Result<Vec<PageId>, StorageError> {
+        let inner = self.read();
+        let mut buf = [0; PAGE_SIZE];
+        Ok((0..inner.num_pages as PageId)
+            .filter(|page_id| inner.read_page(&mut buf, *page_id).is_err())
+            .collect())
+    }
+
+    fn sync(&self) -> Result<(), StorageError> {
+        let mut inner = self.write();
+        inner.check_writable()?;
+        if inner.policy == SyncPolicy::Never || (inner.unsynced.is_empty() && !inner.resized) {
+            return Ok(());
+        }
+        Ok(inner.sync()?)
+    }
+
+    fn sync_policy(&self) -> SyncPolicy {
+        self.read().policy
+    }
+
+    fn recovery_required(&self) -> bool {
+        self.read().recovery_required
+    }
+
+    fn suspect_pages(&self) -> Vec<PageId> {
+        let mut pages: Vec<PageId> = self.read().suspect.iter().copied().collect();
+        pages.sort_unstable();
+        pages
+    }
+
+    fn stats(&self) -> DiskStats {
+        let inner = self.read();
+        DiskStats {
+            writes: inner.num_writes,
+            flushes: inner.num_flushes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::buffer::diskmgr::DiskMgr;
+    use crate::storage::buffer::io;
+    use crate::testing::Song;
+
+    fn setup(name: &str) -> (String, String) {
+        let dir = cwd() + "/tests/mmap_" + name + "_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.clone() + "/data.bin";
+        (dir, path)
+    }
+
+    fn read_song(mgr: &impl DiskApi, page_id: PageId) -> Result<Song, StorageError> {
+        let mut buf = [0; PAGE_SIZE];
+        mgr.read_page(&mut buf, page_id as u64)?;
+        io::from_buffer(&buf)
+    }
+
+    #[test]
+    fn test_mmap_read_write() {
+        let (dir, path) = setup("read_write");
+        let mgr =
+            MmapDiskMgr::create_with_sync_policy(&path, DurabilityMode::Data, SyncPolicy::OnFlush)
+                .unwrap();
+        // enough appends to outgrow the first mapping
+        for id in 0..MIN_MAPPED_PAGES as i32 * 2 {
+            let buf = io::to_buffer(Song::new(id, "Heat Waves", "Glass Animals")).unwrap();
+            assert!(mgr.append_page(&buf).unwrap() == id as PageId);
+        }
+        assert!(mgr.read().map.pages() >= MIN_MAPPED_PAGES * 2);
+        let buf = io::to_buffer(Song::new(-1, "Youth", "Glass Animals")).unwrap();
+        mgr.write_page(&buf, 3).unwrap();
+        assert!(read_song(&mgr, 3).unwrap().id == -1);
+        assert!(read_song(&mgr, 100).unwrap().id == 100);
+        assert!(matches!(
+            read_song(&mgr, 1000),
+            Err(StorageError::PageNotFound(1000))
+        ));
+        mgr.sync().unwrap();
+        assert!(mgr.stats().flushes == 1);
+        drop(mgr);
+
+        // the read/write disk manager sees the same file
+        let reopened = DiskMgr::open(&path).unwrap();
+        assert!(reopened.num_pages().unwrap() == MIN_MAPPED_PAGES * 2);
+        assert!(read_song(&reopened, 3).unwrap().id == -1);
+        assert!(reopened.verify_all().unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mmap_allocate_and_vacuum() {
+        let (dir, path) = setup("allocate");
+        let mgr = MmapDiskMgr::create(&path).unwrap();
+        let buf = io::to_buffer(Song::new(1, "Gooey", "Glass Animals")).unwrap();
+        for _ in 0..4 {
+            mgr.allocate_page(&buf).unwrap();
+        }
+        mgr.deallocate_page(1).unwrap();
+        mgr.deallocate_page(3).unwrap();
+        assert!(mgr.free_pages() == vec![1, 3]);
+        assert!(mgr.allocate_page(&buf).unwrap() == 1);
+        assert!(mgr.vacuum().unwrap() == 1);
+        assert!(mgr.num_pages().unwrap() == 3);
+        assert!(mgr.allocate_page(&buf).unwrap() == 3);
+        drop(mgr);
+
+        let reopened = MmapDiskMgr::open(&path).unwrap();
+        assert!(reopened.num_pages().unwrap() == 4);
+        assert!(read_song(&reopened, 3).unwrap().id == 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
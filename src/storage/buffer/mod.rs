@@ -1,9 +1,26 @@
-mod bufmgr;
-mod diskmgr;
-mod fs;
-mod io;
-mod lruk;
-mod page;
+pub mod arc;
+#[cfg(feature = "async-io")]
+pub mod asyncdisk;
+pub mod bufmgr;
+pub mod clock;
+pub mod compress;
+pub mod datadir;
+pub mod diskmgr;
+#[cfg(any(test, feature = "fault-injection"))]
+pub mod faults;
+pub mod freemap;
+pub mod fs;
+pub mod guard;
+pub mod io;
+pub mod logstruct;
+pub mod lruk;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+pub mod page;
+pub mod parallel;
+pub mod replacer;
+pub mod temp;
+pub mod twoq;
 
 #[cfg(test)]
 mod tests {}
@@ -1,8 +1,601 @@
-use crate::shared::PAGE_SIZE;
+use std::hash::Hasher as _;
+
+use twox_hash::XxHash64;
+
+use crate::shared::{Lsn, PageId, INVALID_PAGE_ID, PAGE_SIZE};
 
 pub type Page = [u8; PAGE_SIZE];
 
+/// Version of the page layouts in this file. Bump it whenever the byte layout of a page changes
+pub const PAGE_FORMAT_VERSION: u32 = 4;
+
+/// Every page starts with a header that the rest of the engine can rely on whatever the page holds:
+///
+/// ```text
+/// | LSN (8) | checksum (4) | page type (1) | checksum algorithm (1) | free space offset (2) | page id (8) |
+/// ```
+///
+/// The LSN is that of the last log record that modified the page. The buffer pool won't write a page back until the log is
+/// durable up to it.
+pub const PAGE_LSN_OFFSET: usize = 0;
+pub const PAGE_LSN_SIZE: usize = std::mem::size_of::<Lsn>();
+
+/// The checksum covers the rest of the page, computed with the algorithm named in the header (see `ChecksumAlgorithm`). The
+/// disk manager stamps it on every write and checks it on every read, so it's only meaningful for the copy on disk: a page in
+/// the buffer pool carries whatever checksum it was read with. A page that is all zeros has never been written and is
+/// considered valid.
+pub const PAGE_CHECKSUM_OFFSET: usize = PAGE_LSN_OFFSET + PAGE_LSN_SIZE;
+pub const PAGE_CHECKSUM_SIZE: usize = 4;
+
+/// The page type says which layout the rest of the page uses (see `PageType`). Layouts set it when they format a page
+pub const PAGE_TYPE_OFFSET: usize = PAGE_CHECKSUM_OFFSET + PAGE_CHECKSUM_SIZE;
+
+/// The checksum algorithm is the one the page's checksum was computed with. Every page records its own, so a data file can
+/// hold pages written under different settings, and a page is always verified the way it was stamped
+pub const PAGE_CHECKSUM_ALGORITHM_OFFSET: usize = PAGE_TYPE_OFFSET + 1;
+
+/// The free space offset is where the page's free space ends, for layouts that pack data from the end of the page (slotted
+/// pages). It's zero on pages whose layout doesn't track free space this way
+pub const PAGE_FREE_SPACE_OFFSET: usize = PAGE_CHECKSUM_ALGORITHM_OFFSET + 1;
+
+/// The page id is the page's own id. The buffer pool fills it in when a page is loaded or allocated and the disk manager
+/// stamps it on every write, so a page read back from the wrong place is caught like a checksum failure
+pub const PAGE_ID_OFFSET: usize = PAGE_FREE_SPACE_OFFSET + 2;
+
+/// Bytes at the start of every page that belong to the page header. Page layouts put their own data after it
+pub const PAGE_HEADER_SIZE: usize = PAGE_ID_OFFSET + 8;
+
+/// What a page holds. Stored as a single byte in the page header
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PageType {
+    /// Never formatted: a zeroed page, or one that was just allocated
+    Unformatted = 0,
+    /// Bytes written by `io::to_buffer`, opaque to the engine
+    Raw = 1,
+    /// A `SlottedPage`, e.g. a table heap page
+    Slotted = 2,
+    BLinkMeta = 3,
+    BLinkNode = 4,
+    HashDirectory = 5,
+    HashBucket = 6,
+    /// A page of a free page map (see `freemap`)
+    FreePageMap = 7,
+    /// A page of a table heap's free space map (see `table::fsm`)
+    FreeSpaceMap = 8,
+    /// A page of an overflow chain holding part of a large record (see `table::overflow`)
+    Overflow = 9,
+    /// The directory page of a Bloom filter, listing its blocks (see `index::bloom`)
+    BloomFilter = 10,
+    /// A block of a Bloom filter's bits
+    BloomBlock = 11,
+}
+
+impl TryFrom<u8> for PageType {
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        Ok(match byte {
+            0 => PageType::Unformatted,
+            1 => PageType::Raw,
+            2 => PageType::Slotted,
+            3 => PageType::BLinkMeta,
+            4 => PageType::BLinkNode,
+            5 => PageType::HashDirectory,
+            6 => PageType::HashBucket,
+            7 => PageType::FreePageMap,
+            8 => PageType::FreeSpaceMap,
+            9 => PageType::Overflow,
+            10 => PageType::BloomFilter,
+            11 => PageType::BloomBlock,
+            _ => return Err(byte),
+        })
+    }
+}
+
+/// How page checksums are computed. Stored as a single byte in the page header; pages written before the algorithm was
+/// recorded have a zero there, which is CRC32C. `XxHash64` keeps the low 32 bits of the hash, and `Off` stamps no checksum
+/// at all, so only the page id check is left to catch a bad read
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ChecksumAlgorithm {
+    /// Hardware-accelerated where the CPU supports it
+    #[default]
+    Crc32c = 0,
+    XxHash64 = 1,
+    Off = 2,
+}
+
+impl TryFrom<u8> for ChecksumAlgorithm {
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        Ok(match byte {
+            0 => ChecksumAlgorithm::Crc32c,
+            1 => ChecksumAlgorithm::XxHash64,
+            2 => ChecksumAlgorithm::Off,
+            _ => return Err(byte),
+        })
+    }
+}
+
+/// A decoded copy of a page header, e.g. for inspecting pages. Use the accessors below to read or change a single field of a
+/// page in place
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageHeader {
+    pub lsn: Lsn,
+    pub checksum: u32,
+    /// `None` if the type byte isn't one this version knows about
+    pub page_type: Option<PageType>,
+    /// `None` if the algorithm byte isn't one this version knows about
+    pub checksum_algorithm: Option<ChecksumAlgorithm>,
+    pub free_space_offset: usize,
+    pub page_id: PageId,
+}
+
+impl PageHeader {
+    pub fn read(page: &Page) -> Self {
+        PageHeader {
+            lsn: lsn(page),
+            checksum: checksum(page),
+            page_type: page_type(page),
+            checksum_algorithm: checksum_algorithm(page),
+            free_space_offset: free_space_offset(page),
+            page_id: page_id(page),
+        }
+    }
+}
+
 #[inline]
 pub fn empty() -> Page {
     [0u8; PAGE_SIZE]
 }
+
+/// Whether the page has never been written: every byte, header included, is zero
+pub fn is_zeroed(page: &Page) -> bool {
+    page.iter().all(|b| *b == 0)
+}
+
+#[inline]
+pub fn lsn(page: &Page) -> Lsn {
+    let bytes = &page[PAGE_LSN_OFFSET..PAGE_LSN_OFFSET + PAGE_LSN_SIZE];
+    Lsn::from_le_bytes(bytes.try_into().unwrap())
+}
+
+#[inline]
+pub fn set_lsn(page: &mut Page, lsn: Lsn) {
+    page[PAGE_LSN_OFFSET..PAGE_LSN_OFFSET + PAGE_LSN_SIZE].copy_from_slice(&lsn.to_le_bytes());
+}
+
+#[inline]
+pub fn page_type(page: &Page) -> Option<PageType> {
+    PageType::try_from(page[PAGE_TYPE_OFFSET]).ok()
+}
+
+#[inline]
+pub fn set_page_type(page: &mut Page, page_type: PageType) {
+    page[PAGE_TYPE_OFFSET] = page_type as u8;
+}
+
+#[inline]
+pub fn free_space_offset(page: &Page) -> usize {
+    let bytes = &page[PAGE_FREE_SPACE_OFFSET..PAGE_FREE_SPACE_OFFSET + 2];
+    u16::from_le_bytes(bytes.try_into().unwrap()) as usize
+}
+
+#[inline]
+pub fn set_free_space_offset(page: &mut Page, offset: usize) {
+    page[PAGE_FREE_SPACE_OFFSET..PAGE_FREE_SPACE_OFFSET + 2]
+        .copy_from_slice(&(offset as u16).to_le_bytes());
+}
+
+#[inline]
+pub fn page_id(page: &Page) -> PageId {
+    let bytes = &page[PAGE_ID_OFFSET..PAGE_ID_OFFSET + 8];
+    i64::from_le_bytes(bytes.try_into().unwrap()) as PageId
+}
+
+#[inline]
+pub fn set_page_id(page: &mut Page, page_id: PageId) {
+    page[PAGE_ID_OFFSET..PAGE_ID_OFFSET + 8].copy_from_slice(&(page_id as i64).to_le_bytes());
+}
+
+#[inline]
+pub fn checksum_algorithm(page: &Page) -> Option<ChecksumAlgorithm> {
+    ChecksumAlgorithm::try_from(page[PAGE_CHECKSUM_ALGORITHM_OFFSET]).ok()
+}
+
+/// Checksum of everything but the checksum field, computed with the algorithm named in the header. Zero for `Off`, and for
+/// an algorithm this version doesn't know
+pub fn compute_checksum(page: &Page) -> u32 {
+    let (head, tail) = (
+        &page[..PAGE_CHECKSUM_OFFSET],
+        &page[PAGE_CHECKSUM_OFFSET + PAGE_CHECKSUM_SIZE..],
+    );
+    match checksum_algorithm(page) {
+        Some(ChecksumAlgorithm::Crc32c) => crc32c::crc32c_append(crc32c::crc32c(head), tail),
+        Some(ChecksumAlgorithm::XxHash64) => {
+            let mut hasher = XxHash64::with_seed(0);
+            hasher.write(head);
+            hasher.write(tail);
+            hasher.finish() as u32
+        }
+        Some(ChecksumAlgorithm::Off) | None => 0,
+    }
+}
+
+#[inline]
+pub fn checksum(page: &Page) -> u32 {
+    let bytes = &page[PAGE_CHECKSUM_OFFSET..PAGE_CHECKSUM_OFFSET + PAGE_CHECKSUM_SIZE];
+    u32::from_le_bytes(bytes.try_into().unwrap())
+}
+
+/// Store the page's current checksum in its header, computed with the algorithm it names
+pub fn set_checksum(page: &mut Page) {
+    let checksum = compute_checksum(page);
+    page[PAGE_CHECKSUM_OFFSET..PAGE_CHECKSUM_OFFSET + PAGE_CHECKSUM_SIZE]
+        .copy_from_slice(&checksum.to_le_bytes());
+}
+
+/// Record `algorithm` in the page's header and store the page's checksum computed with it
+pub fn set_checksum_with(page: &mut Page, algorithm: ChecksumAlgorithm) {
+    page[PAGE_CHECKSUM_ALGORITHM_OFFSET] = algorithm as u8;
+    set_checksum(page);
+}
+
+/// Whether the page's contents match its checksum. A page that names an algorithm this version doesn't know fails
+pub fn verify_checksum(page: &Page) -> bool {
+    match checksum_algorithm(page) {
+        Some(ChecksumAlgorithm::Off) => true,
+        Some(_) => checksum(page) == compute_checksum(page) || is_zeroed(page),
+        None => false,
+    }
+}
+
+/// Slotted page layout for variable-length records:
+///
+/// ```text
+/// | page header (24) | slot count (2) | prev page (8) | next page (8) | slot directory -> ... <- records |
+/// ```
+///
+/// The prev/next page ids let slotted pages be chained into a doubly linked list (a table heap); they're `INVALID_PAGE_ID` on
+/// a page that isn't linked to anything.
+///
+/// Each slot directory entry holds a record's offset and length (2 bytes each). Records are packed from the end of the page
+/// towards the directory; the free space pointer, kept in the page header's free space offset, is the offset of the lowest
+/// record. Deleting a record leaves an empty slot
+/// (offset 0) so the slot numbers of the other records never change, and empty slots are reused by later inserts. Space freed
+/// by deletes and shrinking updates is reclaimed by compacting the record area when an insert or update doesn't otherwise fit.
+/// The top bit of a slot's length is a flag saying the record is an overflow stub, pointing at the chain of pages that hold
+/// the real record (see `table::overflow`); records are always shorter than a page, so lengths never reach it.
+pub const SLOT_COUNT_OFFSET: usize = PAGE_HEADER_SIZE;
+pub const FREE_SPACE_OFFSET: usize = PAGE_FREE_SPACE_OFFSET;
+pub const PREV_PAGE_OFFSET: usize = SLOT_COUNT_OFFSET + 2;
+pub const NEXT_PAGE_OFFSET: usize = PREV_PAGE_OFFSET + 8;
+pub const SLOTTED_HEADER_SIZE: usize = NEXT_PAGE_OFFSET + 8;
+pub const SLOT_SIZE: usize = 4;
+pub const SLOT_OVERFLOW_FLAG: usize = 0x8000;
+
+/// Largest record that fits on an empty slotted page
+pub const MAX_RECORD_SIZE: usize = PAGE_SIZE - SLOTTED_HEADER_SIZE - SLOT_SIZE;
+
+/// A slotted view over a page. Wrap `&Page` (or a read guard) to read records and `&mut Page` (or a write guard) to modify
+/// them.
+pub struct SlottedPage<P> {
+    page: P,
+}
+
+impl<P: std::ops::Deref<Target = Page>> SlottedPage<P> {
+    pub fn new(page: P) -> Self {
+        SlottedPage { page }
+    }
+
+    fn read_u16(&self, offset: usize) -> usize {
+        u16::from_le_bytes(self.page[offset..offset + 2].try_into().unwrap()) as usize
+    }
+
+    /// Number of slots in the directory, including empty ones
+    pub fn slot_count(&self) -> u16 {
+        self.read_u16(SLOT_COUNT_OFFSET) as u16
+    }
+
+    fn free_space_pointer(&self) -> usize {
+        self.read_u16(FREE_SPACE_OFFSET)
+    }
+
+    fn read_page_id(&self, offset: usize) -> PageId {
+        i64::from_le_bytes(self.page[offset..offset + 8].try_into().unwrap()) as PageId
+    }
+
+    pub fn prev_page_id(&self) -> PageId {
+        self.read_page_id(PREV_PAGE_OFFSET)
+    }
+
+    pub fn next_page_id(&self) -> PageId {
+        self.read_page_id(NEXT_PAGE_OFFSET)
+    }
+
+    fn directory_end(&self) -> usize {
+        SLOTTED_HEADER_SIZE + self.slot_count() as usize * SLOT_SIZE
+    }
+
+    /// (offset, length) of a slot's record. Offset 0 marks an empty slot
+    pub fn slot(&self, slot: u16) -> (usize, usize) {
+        let entry = SLOTTED_HEADER_SIZE + slot as usize * SLOT_SIZE;
+        (
+            self.read_u16(entry),
+            self.read_u16(entry + 2) & !SLOT_OVERFLOW_FLAG,
+        )
+    }
+
+    /// Whether a slot's record is an overflow stub rather than the record itself
+    pub fn is_overflow(&self, slot: u16) -> bool {
+        let entry = SLOTTED_HEADER_SIZE + slot as usize * SLOT_SIZE;
+        self.get(slot).is_some() && self.read_u16(entry + 2) & SLOT_OVERFLOW_FLAG != 0
+    }
+
+    /// Contiguous free bytes between the slot directory and the records
+    pub fn free_space(&self) -> usize {
+        self.free_space_pointer() - self.directory_end()
+    }
+
+    /// Free bytes including space that compaction would reclaim
+    pub fn reclaimable_space(&self) -> usize {
+        let live: usize = (0..self.slot_count()).map(|slot| self.slot(slot).1).sum();
+        PAGE_SIZE - self.directory_end() - live
+    }
+
+    pub fn get(&self, slot: u16) -> Option<&[u8]> {
+        if slot >= self.slot_count() {
+            return None;
+        }
+        let (offset, len) = self.slot(slot);
+        if offset == 0 {
+            return None;
+        }
+        Some(&self.page[offset..offset + len])
+    }
+
+    /// Slot numbers of every record on the page, in slot order
+    pub fn slots(&self) -> Vec<u16> {
+        (0..self.slot_count())
+            .filter(|slot| self.slot(*slot).0 != 0)
+            .collect()
+    }
+}
+
+impl<P: std::ops::DerefMut<Target = Page>> SlottedPage<P> {
+    /// Format `page` as an empty slotted page. Everything after the page header is discarded
+    pub fn init(mut page: P) -> Self {
+        page[SLOT_COUNT_OFFSET..].fill(0);
+        set_page_type(&mut page, PageType::Slotted);
+        let mut slotted = SlottedPage { page };
+        slotted.write_u16(FREE_SPACE_OFFSET, PAGE_SIZE);
+        slotted.set_prev_page_id(INVALID_PAGE_ID);
+        slotted.set_next_page_id(INVALID_PAGE_ID);
+        slotted
+    }
+
+    fn write_u16(&mut self, offset: usize, value: usize) {
+        self.page[offset..offset + 2].copy_from_slice(&(value as u16).to_le_bytes());
+    }
+
+    pub fn set_prev_page_id(&mut self, page_id: PageId) {
+        self.page[PREV_PAGE_OFFSET..PREV_PAGE_OFFSET + 8]
+            .copy_from_slice(&(page_id as i64).to_le_bytes());
+    }
+
+    pub fn set_next_page_id(&mut self, page_id: PageId) {
+        self.page[NEXT_PAGE_OFFSET..NEXT_PAGE_OFFSET + 8]
+            .copy_from_slice(&(page_id as i64).to_le_bytes());
+    }
+
+    /// Point a slot at a record. Clears its overflow flag
+    fn set_slot(&mut self, slot: u16, offset: usize, len: usize) {
+        let entry = SLOTTED_HEADER_SIZE + slot as usize * SLOT_SIZE;
+        self.write_u16(entry, offset);
+        self.write_u16(entry + 2, len);
+    }
+
+    /// Mark a slot's record as an overflow stub, or as the record itself. Inserts and updates store records unmarked, so a
+    /// stub is marked after it's stored. Returns false if the slot is empty
+    pub fn set_overflow(&mut self, slot: u16, overflow: bool) -> bool {
+        let Some(record) = self.get(slot) else {
+            return false;
+        };
+        let len = record.len() | if overflow { SLOT_OVERFLOW_FLAG } else { 0 };
+        let entry = SLOTTED_HEADER_SIZE + slot as usize * SLOT_SIZE;
+        self.write_u16(entry + 2, len);
+        true
+    }
+
+    /// Copy `record` into the record area and return its offset. The caller has made sure it fits
+    fn place(&mut self, record: &[u8]) -> usize {
+        let offset = self.free_space_pointer() - record.len();
+        self.page[offset..offset + record.len()].copy_from_slice(record);
+        self.write_u16(FREE_SPACE_OFFSET, offset);
+        offset
+    }
+
+    /// Insert a record and return its slot number, or `None` if it doesn't fit even after compaction
+    pub fn insert(&mut self, record: &[u8]) -> Option<u16> {
+        let empty_slot = (0..self.slot_count()).find(|slot| self.slot(*slot).0 == 0);
+        let needed = record.len() + if empty_slot.is_some() { 0 } else { SLOT_SIZE };
+        if record.is_empty() || needed > self.reclaimable_space() {
+            return None;
+        }
+        if needed > self.free_space() {
+            self.compact();
+        }
+        let slot = match empty_slot {
+            Some(slot) => slot,
+            None => {
+                let slot = self.slot_count();
+                self.write_u16(SLOT_COUNT_OFFSET, slot as usize + 1);
+                slot
+            }
+        };
+        let offset = self.place(record);
+        self.set_slot(slot, offset, record.len());
+        Some(slot)
+    }
+
+    /// Remove a record. Its slot stays in the directory, empty, so other slot numbers are unaffected
+    pub fn delete(&mut self, slot: u16) -> bool {
+        if self.get(slot).is_none() {
+            return false;
+        }
+        self.set_slot(slot, 0, 0);
+        // trailing empty slots can be dropped from the directory entirely
+        let mut count = self.slot_count();
+        while count > 0 && self.slot(count - 1).0 == 0 {
+            count -= 1;
+        }
+        self.write_u16(SLOT_COUNT_OFFSET, count as usize);
+        true
+    }
+
+    /// Replace a record, keeping its slot number. Returns false if the slot is empty or the new record doesn't fit
+    pub fn update(&mut self, slot: u16, record: &[u8]) -> bool {
+        let Some(old) = self.get(slot) else {
+            return false;
+        };
+        let old_len = old.len();
+        if record.is_empty() {
+            return false;
+        }
+        if record.len() <= old_len {
+            let (offset, _) = self.slot(slot);
+            self.page[offset..offset + record.len()].copy_from_slice(record);
+            self.set_slot(slot, offset, record.len());
+            return true;
+        }
+        if record.len() - old_len > self.reclaimable_space() {
+            return false;
+        }
+        // free the old copy first so compaction can reuse its space
+        self.set_slot(slot, 0, 0);
+        if record.len() > self.free_space() {
+            self.compact();
+        }
+        let offset = self.place(record);
+        self.set_slot(slot, offset, record.len());
+        true
+    }
+
+    /// Pack every record against the end of the page, leaving all free space contiguous. Slot numbers don't change
+    pub fn compact(&mut self) {
+        let mut records: Vec<(u16, Vec<u8>, bool)> = self
+            .slots()
+            .into_iter()
+            .map(|slot| {
+                let record = self.get(slot).unwrap().to_vec();
+                (slot, record, self.is_overflow(slot))
+            })
+            .collect();
+        // place records in their current order so the layout stays stable across repeated compactions
+        records.sort_by_key(|(slot, _, _)| std::cmp::Reverse(self.slot(*slot).0));
+        self.write_u16(FREE_SPACE_OFFSET, PAGE_SIZE);
+        for (slot, record, overflow) in records {
+            let offset = self.place(&record);
+            self.set_slot(slot, offset, record.len());
+            self.set_overflow(slot, overflow);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slotted_page() {
+        let mut page = empty();
+        set_lsn(&mut page, 42);
+        let mut slotted = SlottedPage::init(&mut page);
+        let a = slotted.insert(b"sweater weather").unwrap();
+        let b = slotted.insert(b"softcore").unwrap();
+        let c = slotted.insert(b"daddy issues").unwrap();
+        assert!((a, b, c) == (0, 1, 2));
+        assert!(slotted.get(b) == Some(&b"softcore"[..]));
+
+        assert!(slotted.delete(b));
+        assert!(!slotted.delete(b));
+        assert!(slotted.get(b).is_none());
+        assert!(slotted.get(c) == Some(&b"daddy issues"[..]));
+        // the empty slot is reused
+        assert!(slotted.insert(b"reflections") == Some(b));
+
+        assert!(slotted.update(a, b"the beach"));
+        assert!(slotted.update(a, b"sweater weather (live)"));
+        assert!(slotted.get(a) == Some(&b"sweater weather (live)"[..]));
+        assert!(slotted.slots() == vec![0, 1, 2]);
+        assert!(slotted.next_page_id() == INVALID_PAGE_ID);
+        slotted.set_next_page_id(7);
+        assert!(lsn(&page) == 42);
+
+        let slotted = SlottedPage::new(&page);
+        assert!(slotted.get(c) == Some(&b"daddy issues"[..]));
+        assert!(slotted.next_page_id() == 7);
+        assert!(slotted.prev_page_id() == INVALID_PAGE_ID);
+    }
+
+    #[test]
+    fn test_page_header() {
+        let mut page = empty();
+        assert!(page_type(&page) == Some(PageType::Unformatted));
+        set_lsn(&mut page, 42);
+        set_page_id(&mut page, 9);
+        SlottedPage::init(&mut page)
+            .insert(b"sweater weather")
+            .unwrap();
+        set_checksum(&mut page);
+        let header = PageHeader::read(&page);
+        assert!(header.lsn == 42);
+        assert!(header.page_id == 9);
+        assert!(header.page_type == Some(PageType::Slotted));
+        assert!(header.free_space_offset == PAGE_SIZE - b"sweater weather".len());
+        assert!(header.checksum == compute_checksum(&page));
+        assert!(verify_checksum(&page));
+
+        page[PAGE_TYPE_OFFSET] = 0xff;
+        assert!(page_type(&page).is_none());
+        assert!(!verify_checksum(&page));
+    }
+
+    #[test]
+    fn test_slotted_page_compaction() {
+        let mut page = empty();
+        let mut slotted = SlottedPage::init(&mut page);
+        let record = [7u8; 100];
+        let mut slots = Vec::new();
+        while let Some(slot) = slotted.insert(&record) {
+            slots.push(slot);
+        }
+        assert!(slots.len() == (PAGE_SIZE - SLOTTED_HEADER_SIZE) / (100 + SLOT_SIZE));
+        assert!(slotted.insert(&[1u8; MAX_RECORD_SIZE]).is_none());
+
+        // free every other record: the holes add up to enough room only once compacted
+        for slot in slots.iter().step_by(2) {
+            assert!(slotted.delete(*slot));
+        }
+        assert!(slotted.free_space() < 150);
+        let big = [9u8; 150];
+        let slot = slotted.insert(&big).unwrap();
+        assert!(slotted.get(slot) == Some(&big[..]));
+        for slot in slots.iter().skip(1).step_by(2) {
+            assert!(slotted.get(*slot) == Some(&record[..]));
+        }
+        // growing a record compacts too, and moved records keep their overflow flags
+        assert!(slotted.set_overflow(slots[3], true));
+        assert!(slotted.update(slots[1], &[3u8; 300]));
+        assert!(slotted.get(slots[1]) == Some(&[3u8; 300][..]));
+        assert!(slotted.get(slots[3]) == Some(&record[..]));
+        assert!(slotted.is_overflow(slots[3]) && !slotted.is_overflow(slots[1]));
+        assert!(slotted.slot(slots[3]).1 == record.len());
+        // rewriting a record stores it unmarked
+        assert!(slotted.update(slots[3], &[4u8; 50]));
+        assert!(!slotted.is_overflow(slots[3]));
+        assert!(!slotted.set_overflow(slots[2], true));
+    }
+}
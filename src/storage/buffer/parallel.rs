@@ -0,0 +1,473 @@
+/// This file implements a partitioned buffer pool. Every `BufferPool` operation that changes frame metadata takes the pool's
+/// exclusive latch, so under many concurrent fetches a single pool serializes on that latch. A `ParallelBufferPool` splits
+/// the frames over several independent `BufferPool` instances sharing one disk manager, and routes each page id to one of them
+/// by hash: fetches of pages in different instances latch different pools and don't contend, and a page is only ever resident
+/// in the instance it routes to.
+///
+/// Each instance has its own frames (the configured pool size, `BUFFER_POOL_SIZE` by default) and its own replacer, so eviction is per instance: a page can be
+/// evicted from a full instance while another one still has free frames. Read-ahead is done by the parallel pool rather than
+/// by the instances, since consecutive pages of a scan route to different instances.
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use rayon::prelude::*;
+
+use crate::shared::{PageId, BUFFER_POOL_SIZE};
+use crate::sim::Simulation;
+use crate::storage::buffer::bufmgr::{
+    prefetch_page, register_writer, shut_down, with_config, with_disk, BackgroundWriter, BufApi,
+    BufStats, BufferPool, BufferPoolFrame, ScanTracker,
+};
+use crate::storage::buffer::diskmgr::{DiskApi as _, DiskMgr};
+use crate::storage::buffer::guard::{OptimisticGuard, PinnedPage, ReadPageGuard, WritePageGuard};
+use crate::storage::buffer::page::{self, Page};
+use crate::storage::buffer::replacer::{BoxedReplacer, ReplacerStats};
+use crate::storage::config::StorageConfig;
+use crate::storage::error::StorageError;
+use crate::storage::log::logmgr::LogManager;
+use crate::sync::{CondVar, CondVarMethods as _, Latch as _, Synchronized};
+use crate::telemetry::EventBus;
+
+/// Number of instances `ParallelBufferPool::create` and `open` split the pool into
+pub const DEFAULT_INSTANCES: usize = 4;
+
+/// Buffer pool made of independent instances, each caching the pages whose ids hash to it. Clones refer to the same pool
+#[derive(Clone)]
+pub struct ParallelBufferPool {
+    mgr: DiskMgr,
+    instances: Vec<BufferPool>,
+    scans: Synchronized<ScanTracker>,
+    // read-aheads in progress, and a signal for when the last one finishes
+    prefetches: Synchronized<usize>,
+    prefetched: CondVar,
+}
+
+impl ParallelBufferPool {
+    /// Create a pool of `instances` instances backed by the file at `path`, each using LRU-K replacement
+    pub fn create_with_instances(path: &str, instances: usize) -> Result<Self, StorageError> {
+        Ok(ParallelBufferPool::with_instances(
+            DiskMgr::create(path)?,
+            instances,
+            &StorageConfig::default(),
+        ))
+    }
+
+    /// Open a pool of `instances` instances over the existing database file at `path` (see `BufApi::open`)
+    pub fn open_with_instances(path: &str, instances: usize) -> Result<Self, StorageError> {
+        Ok(ParallelBufferPool::with_instances(
+            DiskMgr::open(path)?,
+            instances,
+            &StorageConfig::default(),
+        ))
+    }
+
+    /// `instances` instances over `mgr`, each sized and replacing as `config` says
+    fn with_instances(mgr: DiskMgr, instances: usize, config: &StorageConfig) -> Self {
+        assert!(instances > 0);
+        let instances = (0..instances)
+            .map(|_| with_config(Arc::new(mgr.clone()), config))
+            .collect();
+        ParallelBufferPool::from_instances(mgr, instances)
+    }
+
+    fn from_instances(mgr: DiskMgr, instances: Vec<BufferPool>) -> Self {
+        ParallelBufferPool {
+            mgr,
+            instances,
+            scans: Synchronized::init(ScanTracker::new()),
+            prefetches: Synchronized::init(0),
+            prefetched: CondVar::init(),
+        }
+    }
+
+    pub fn instances(&self) -> usize {
+        self.instances.len()
+    }
+
+    /// Index of the instance `page_id` lives in. Page ids are mixed before being reduced, so that runs of ids spread evenly
+    fn route(&self, page_id: PageId) -> usize {
+        let hash = (page_id as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32;
+        hash as usize % self.instances.len()
+    }
+
+    fn instance(&self, page_id: PageId) -> &BufferPool {
+        &self.instances[self.route(page_id)]
+    }
+}
+
+impl BufApi for ParallelBufferPool {
+    /// Create a pool of `DEFAULT_INSTANCES` instances backed by the file at `path`
+    fn create(path: &str) -> Result<Self, StorageError> {
+        ParallelBufferPool::create_with_instances(path, DEFAULT_INSTANCES)
+    }
+
+    /// Create a pool with a single instance that uses `replacer`. A replacer tracks one set of frames, so it can't be shared
+    /// between instances; use `create_with_instances` to partition the pool
+    fn create_with_replacer(path: &str, replacer: BoxedReplacer) -> Result<Self, StorageError> {
+        let mgr = DiskMgr::create(path)?;
+        let instance = with_disk(Arc::new(mgr.clone()), replacer, BUFFER_POOL_SIZE);
+        Ok(ParallelBufferPool::from_instances(mgr, vec![instance]))
+    }
+
+    /// Open a pool of `DEFAULT_INSTANCES` instances over the existing database file at `path`
+    fn open(path: &str) -> Result<Self, StorageError> {
+        ParallelBufferPool::open_with_instances(path, DEFAULT_INSTANCES)
+    }
+
+    /// Create a pool of `DEFAULT_INSTANCES` instances over a new data file, each with `config.pool_size()` frames
+    fn create_with_config(config: &StorageConfig) -> Result<Self, StorageError> {
+        let mgr = DiskMgr::create_with_config(config)?;
+        Ok(ParallelBufferPool::with_instances(
+            mgr,
+            DEFAULT_INSTANCES,
+            config,
+        ))
+    }
+
+    /// Like `create_with_config`, but keeping the pages already in the data file
+    fn open_with_config(config: &StorageConfig) -> Result<Self, StorageError> {
+        let mgr = DiskMgr::open_with_config(config)?;
+        Ok(ParallelBufferPool::with_instances(
+            mgr,
+            DEFAULT_INSTANCES,
+            config,
+        ))
+    }
+
+    fn size(&self) -> usize {
+        self.instances.iter().map(|instance| instance.size()).sum()
+    }
+
+    /// Split `new_size` frames evenly over the instances and resize each (see `BufferPool::resize`)
+    fn resize(&self, new_size: usize) -> usize {
+        let n = self.instances.len();
+        assert!(new_size >= n, "every instance needs at least one frame");
+        self.instances
+            .iter()
+            .enumerate()
+            .map(|(i, instance)| instance.resize(new_size / n + usize::from(i < new_size % n)))
+            .sum()
+    }
+
+    /// Allocate a new page on disk and bring it into its instance, pinned (see `BufferPool::new_page`). The page id has to be
+    /// known to pick the instance, so it's allocated first, and freed again if the instance has no frame for it
+    fn new_page(&self) -> Result<(PageId, BufferPoolFrame), StorageError> {
+        let page_id = self.mgr.allocate_page(&page::empty())?;
+        match self.instance(page_id).write().adopt(page_id) {
+            Some(frame) => Ok((page_id, frame)),
+            None => {
+                let _ = self.mgr.deallocate_page(page_id);
+                Err(StorageError::PoolExhausted)
+            }
+        }
+    }
+
+    fn fetch_page(&self, page_id: PageId) -> Result<BufferPoolFrame, StorageError> {
+        let frame = self.instance(page_id).fetch_page(page_id);
+        let read_ahead = {
+            let mut scans = self.scans.lock();
+            let read_ahead = scans.read_ahead();
+            scans.detect(page_id).map(|start| (start, read_ahead))
+        };
+        if let Some((start, count)) = read_ahead {
+            self.prefetch(start, count);
+        }
+        frame
+    }
+
+    /// Read `count` pages from `page_id` on into their instances in the background (see `BufferPool::prefetch`). Read-ahead
+    /// stops at the first page that isn't in the file; a page whose instance has no free frame is skipped
+    fn prefetch(&self, page_id: PageId, count: usize) {
+        let pool = self.clone();
+        *self.prefetches.lock() += 1;
+        rayon::spawn(move || {
+            for page_id in page_id..page_id + count as PageId {
+                if !pool.mgr.read_page_exists(page_id) {
+                    break;
+                }
+                prefetch_page(pool.instance(page_id), page_id);
+            }
+            let mut prefetches = pool.prefetches.lock();
+            *prefetches -= 1;
+            if *prefetches == 0 {
+                pool.prefetched.notify_all();
+            }
+        });
+    }
+
+    fn set_read_ahead(&self, distance: usize) {
+        self.scans.lock().set_read_ahead(distance);
+    }
+
+    fn wait_for_prefetch(&self) {
+        let mut prefetches = self.prefetches.lock();
+        self.prefetched
+            .wait_while(&mut prefetches, |prefetches| *prefetches > 0);
+    }
+
+    fn fetch_page_read(&self, page_id: PageId) -> Result<ReadPageGuard, StorageError> {
+        self.instance(page_id).fetch_page_read(page_id)
+    }
+
+    fn fetch_page_write(&self, page_id: PageId) -> Result<WritePageGuard, StorageError> {
+        self.instance(page_id).fetch_page_write(page_id)
+    }
+
+    fn new_page_write(&self) -> Result<WritePageGuard, StorageError> {
+        let (page_id, frame) = self.new_page()?;
+        Ok(WritePageGuard::new(
+            self.instance(page_id).clone(),
+            frame,
+            page_id,
+        ))
+    }
+
+    fn fetch_page_pinned(&self, page_id: PageId) -> Result<PinnedPage, StorageError> {
+        self.instance(page_id).fetch_page_pinned(page_id)
+    }
+
+    fn fetch_page_optimistic(&self, page_id: PageId) -> Result<OptimisticGuard, StorageError> {
+        self.instance(page_id).fetch_page_optimistic(page_id)
+    }
+
+    fn warm_up(&self, page_ids: &[PageId]) -> usize {
+        page_ids
+            .iter()
+            .map(|page_id| self.instance(*page_id).warm_up(&[*page_id]))
+            .sum()
+    }
+
+    fn pin_resident(&self, page_id: PageId) -> Result<(), StorageError> {
+        self.instance(page_id).pin_resident(page_id)
+    }
+
+    fn unpin_resident(&self, page_id: PageId) -> bool {
+        self.instance(page_id).unpin_resident(page_id)
+    }
+
+    fn read_page_optimistic(&self, page_id: PageId) -> Option<Page> {
+        self.instance(page_id).read_page_optimistic(page_id)
+    }
+
+    fn unpin_page(&self, page_id: PageId, is_dirty: bool) -> bool {
+        self.instance(page_id).unpin_page(page_id, is_dirty)
+    }
+
+    fn flush_page(&self, page_id: PageId) -> Result<(), StorageError> {
+        self.instance(page_id).flush_page(page_id)
+    }
+
+    /// Flush every instance, concurrently, even after one has failed. Returns the first failure
+    fn flush_all(&self) -> Result<(), StorageError> {
+        let flushed: Vec<Result<(), StorageError>> = self
+            .instances
+            .par_iter()
+            .map(|instance| instance.flush_all())
+            .collect();
+        flushed.into_iter().collect()
+    }
+
+    fn flush_unpinned(&self) -> Result<usize, StorageError> {
+        self.instances
+            .iter()
+            .map(|instance| instance.flush_unpinned())
+            .sum()
+    }
+
+    /// See `BufferPool::dirty_pages`. Each instance's list is collected under that instance's latch only, so pages can be
+    /// pinned in one instance while another is being listed
+    fn dirty_pages(&self) -> Vec<PageId> {
+        let mut dirty: Vec<PageId> = self
+            .instances
+            .iter()
+            .flat_map(|instance| instance.dirty_pages())
+            .collect();
+        dirty.sort_unstable();
+        dirty
+    }
+
+    fn dirty_count(&self) -> usize {
+        self.instances
+            .iter()
+            .map(|instance| instance.dirty_count())
+            .sum()
+    }
+
+    /// The instances' counters added up. They're read one instance at a time, so they don't add up to a consistent snapshot
+    /// while the pool is in use
+    fn stats(&self) -> BufStats {
+        let mut total = BufStats {
+            disk: self.mgr.stats(),
+            ..BufStats::default()
+        };
+        for instance in self.instances.iter() {
+            let stats = instance.stats();
+            total.hits += stats.hits;
+            total.misses += stats.misses;
+            total.evictions += stats.evictions;
+            total.write_backs += stats.write_backs;
+            total.pinned += stats.pinned;
+            total.reads_in_flight += stats.reads_in_flight;
+            total.writes_in_flight += stats.writes_in_flight;
+            if let Some(replacer) = stats.replacer {
+                let sum = total.replacer.get_or_insert_with(ReplacerStats::default);
+                sum.recent += replacer.recent;
+                sum.frequent += replacer.frequent;
+                sum.recent_ghosts += replacer.recent_ghosts;
+                sum.frequent_ghosts += replacer.frequent_ghosts;
+                sum.target += replacer.target;
+            }
+        }
+        total
+    }
+
+    /// Run `flush_unpinned` over every instance every `interval`, on one background thread
+    fn start_background_writer(&self, interval: Duration) -> BackgroundWriter {
+        let instances: Vec<Weak<_>> = self.instances.iter().map(Arc::downgrade).collect();
+        let writer = BackgroundWriter::spawn(interval, move || {
+            for instance in instances.iter().filter_map(Weak::upgrade) {
+                let _ = instance.flush_unpinned();
+            }
+        });
+        for instance in self.instances.iter() {
+            register_writer(instance, &writer);
+        }
+        writer
+    }
+
+    /// Shut every instance down (see `BufferPool::close`), then close the shared data file
+    fn close(&self) -> Result<(), StorageError> {
+        self.wait_for_prefetch();
+        for instance in self.instances.iter() {
+            shut_down(instance)?;
+        }
+        self.mgr.close()
+    }
+
+    fn delete_page(&self, page_id: PageId) -> Result<(), StorageError> {
+        self.instance(page_id).delete_page(page_id)
+    }
+
+    fn alloc_page(&self) -> Result<PageId, StorageError> {
+        self.mgr.allocate_page(&page::empty())
+    }
+
+    fn set_log_manager(&self, log: LogManager) {
+        for instance in self.instances.iter() {
+            instance.set_log_manager(log.clone());
+        }
+    }
+
+    fn set_simulation(&self, sim: Simulation) {
+        for instance in self.instances.iter() {
+            instance.set_simulation(sim.clone());
+        }
+    }
+
+    fn set_event_bus(&self, bus: EventBus) {
+        for instance in self.instances.iter() {
+            instance.set_event_bus(bus.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rayon::ThreadPoolBuilder;
+
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::buffer::bufmgr::{FrameApi as _, SEQUENTIAL_THRESHOLD};
+    use crate::storage::buffer::freemap;
+    use crate::storage::buffer::io;
+    use crate::testing::Song;
+
+    fn setup(name: &str) -> String {
+        let dir = cwd() + "/tests/parallel_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        format!("{}/{}.bin", dir, name)
+    }
+
+    fn cleanup(path: &str) {
+        std::fs::remove_file(std::path::Path::new(path)).unwrap();
+        let _ = std::fs::remove_file(freemap::map_path(path));
+    }
+
+    /// Which instances `page_id` is resident in
+    fn residents(pool: &ParallelBufferPool, page_id: PageId) -> Vec<usize> {
+        (0..pool.instances())
+            .filter(|i| pool.instances[*i].read().is_resident(page_id))
+            .collect()
+    }
+
+    #[test]
+    fn test_partitioned_pages() {
+        let path = setup("test_partitioned_pages");
+        let pool = ParallelBufferPool::create(&path).unwrap();
+        // more pages than one instance has frames
+        let page_ids: Vec<PageId> = (0..120)
+            .map(|i| {
+                let mut guard = pool.new_page_write().unwrap();
+                *guard.data_mut() =
+                    io::to_buffer(Song::new(i, "Sweater Weather", "The Neighbourhood")).unwrap();
+                guard.page_id()
+            })
+            .collect();
+        assert!(pool.dirty_count() == 120);
+        assert!(pool.instances.iter().all(|instance| instance.size() > 0));
+        assert!(page_ids
+            .iter()
+            .all(|page_id| residents(&pool, *page_id).len() == 1));
+
+        let workers = ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        workers.scope(|s| {
+            for t in 0..8 {
+                let (pool, page_ids) = (&pool, &page_ids);
+                s.spawn(move |_| {
+                    for (i, page_id) in page_ids.iter().enumerate().skip(t).step_by(8) {
+                        let guard = pool.fetch_page_read(*page_id).unwrap();
+                        assert!(io::from_buffer::<Song>(guard.data()).unwrap().id == i as i32);
+                    }
+                });
+            }
+        });
+        assert!(pool.dirty_pages() == page_ids);
+        assert!(pool.flush_all().is_ok() && pool.dirty_count() == 0);
+        let stats = pool.stats();
+        assert!(stats.hits == 120 && stats.misses == 0 && stats.write_backs == 120);
+
+        // a deleted page is free to be reallocated, in whichever instance it routes to
+        assert!(pool.delete_page(page_ids[7]).is_ok());
+        assert!(residents(&pool, page_ids[7]).is_empty());
+        let (page_id, frame) = pool.new_page().unwrap();
+        assert!(page_id == page_ids[7] && frame.pin_count() == 1);
+        assert!(residents(&pool, page_id).len() == 1);
+        assert!(pool.unpin_page(page_id, false));
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_parallel_read_ahead() {
+        let path = setup("test_parallel_read_ahead");
+        let pool = ParallelBufferPool::create(&path).unwrap();
+        for i in 0..40 {
+            let mut guard = pool.new_page_write().unwrap();
+            *guard.data_mut() =
+                io::to_buffer(Song::new(i, "Daddy Issues", "The Neighbourhood")).unwrap();
+        }
+        assert!(pool.flush_all().is_ok());
+        drop(pool);
+
+        let pool = ParallelBufferPool::open(&path).unwrap();
+        pool.set_read_ahead(8);
+        for page_id in 10..10 + SEQUENTIAL_THRESHOLD as PageId {
+            assert!(pool.fetch_page(page_id).is_ok());
+            assert!(pool.unpin_page(page_id, false));
+        }
+        pool.wait_for_prefetch();
+        // read ahead into the instances the pages route to, and nowhere else
+        assert!((14..22).all(|page_id| residents(&pool, page_id) == vec![pool.route(page_id)]));
+        assert!(residents(&pool, 22).is_empty());
+        cleanup(&path);
+    }
+}
@@ -0,0 +1,48 @@
+/// This file defines the interface between the buffer pool and its page replacement policy. The buffer pool holds the policy as a
+/// trait object, so any implementation (LRU-K, Clock, 2Q, ...) can be swapped in through `BufApi::create_with_replacer`.
+use crate::shared::{FrameId, PageId};
+
+/// What an adaptive replacer reports about its lists through `BufApi::stats`, so its adaptation can be watched while a
+/// workload runs. Counts are in frames, or in pages for the ghost lists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReplacerStats {
+    /// Resident pages seen once since they were loaded
+    pub recent: usize,
+    /// Resident pages seen more than once
+    pub frequent: usize,
+    /// Evicted pages remembered from each list
+    pub recent_ghosts: usize,
+    pub frequent_ghosts: usize,
+    /// How many frames the policy currently wants to give to recently seen pages
+    pub target: usize,
+}
+
+/// A frame replacement policy. Frame ids handed to a replacer are in `1..=num_frames` for the pool it was created for.
+/// Implementations must be safe to call from multiple threads.
+pub trait Replacer {
+    /// Pick an evictable frame, stop tracking it, and return it. Returns `None` if no frame is evictable
+    fn evict(&self) -> Option<FrameId>;
+    /// Note that the frame was just accessed. Frames seen for the first time start out non-evictable
+    fn record_access(&self, frame_id: FrameId);
+    /// Mark a frame as (non-)evictable. Frames the replacer isn't tracking are ignored
+    fn set_evictable(&self, frame_id: FrameId, evictable: bool);
+    /// Stop tracking an evictable frame. Removing a non-evictable or unknown frame is a no-op
+    fn remove(&self, frame_id: FrameId);
+    /// Number of evictable frames
+    fn size(&self) -> usize;
+    /// Accept frame ids `1..=num_frames` from now on. The pool only shrinks a replacer once it has removed every frame above
+    /// the new size
+    fn resize(&self, num_frames: usize);
+
+    /// Note that `frame_id` now holds `page_id`. The pool calls it when it loads a page into a frame, just before recording
+    /// the first access. Policies that remember evicted pages need it; the rest ignore it
+    fn record_page(&self, _frame_id: FrameId, _page_id: PageId) {}
+
+    /// The state of an adaptive policy's lists, or `None` for policies without any worth reporting
+    fn stats(&self) -> Option<ReplacerStats> {
+        None
+    }
+}
+
+/// The replacer type stored by the buffer pool
+pub type BoxedReplacer = Box<dyn Replacer + Send + Sync>;
@@ -0,0 +1,339 @@
+/// This file implements temporary pages, the scratch space of operators that spill to disk (external sort, hash join). A
+/// `TempPageAllocator` owns a temp file and hands out pages in it that live in the buffer pool like any other page, behind
+/// the same guards. Temp pages are never logged, never checksummed or verified, and left out of flushes and checkpoints,
+/// since nothing needs them after a crash. The temp file is deleted when its allocator is dropped, and any of its pages still
+/// in the pool are dropped with it.
+///
+/// Temp pages are told apart from data pages by their id: every id from `TEMP_PAGE_BASE` on is a temp page, with the number
+/// of its file in the pool above bit 32 and its position in the file below.
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::shared::{PageId, PAGE_SIZE};
+use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+use crate::storage::buffer::fs::{read_exact_at, write_all_at};
+use crate::storage::buffer::guard::{ReadPageGuard, WritePageGuard};
+use crate::storage::buffer::page;
+use crate::storage::error::StorageError;
+use crate::sync::{Access as _, Latch as _, Synchronized};
+
+/// The first temp page id. Data files never get anywhere near this many pages
+pub const TEMP_PAGE_BASE: PageId = 1 << 48;
+
+const POSITION_BITS: u32 = 32;
+
+pub const TEMP_FILE_EXTENSION: &str = "spill";
+
+// makes temp file names unique across the pools of a process
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether `page_id` is a temp page
+pub fn is_temp_page(page_id: PageId) -> bool {
+    page_id >= TEMP_PAGE_BASE
+}
+
+fn file_no(page_id: PageId) -> u32 {
+    ((page_id - TEMP_PAGE_BASE) >> POSITION_BITS) as u32
+}
+
+fn position(page_id: PageId) -> PageId {
+    (page_id - TEMP_PAGE_BASE) & ((1 << POSITION_BITS) - 1)
+}
+
+pub struct TempFileInternal {
+    handle: File,
+    path: PathBuf,
+    file_no: u32,
+    // pages handed out so far, including freed ones
+    num_pages: PageId,
+    free: Vec<PageId>,
+}
+
+impl TempFileInternal {
+    fn page_id(&self, position: PageId) -> PageId {
+        TEMP_PAGE_BASE + ((self.file_no as PageId) << POSITION_BITS) + position
+    }
+
+    pub(crate) fn allocate(&mut self) -> PageId {
+        match self.free.pop() {
+            Some(page_id) => page_id,
+            None => {
+                self.num_pages += 1;
+                self.page_id(self.num_pages - 1)
+            }
+        }
+    }
+
+    fn holds(&self, page_id: PageId) -> bool {
+        file_no(page_id) == self.file_no
+            && position(page_id) < self.num_pages
+            && !self.free.contains(&page_id)
+    }
+
+    /// Read a page, which reads as zeroes if it was never written. Nothing is verified
+    fn read_page(&self, buf: &mut [u8; PAGE_SIZE], page_id: PageId) -> Result<(), StorageError> {
+        if !self.holds(page_id) {
+            return Err(StorageError::PageNotFound(page_id));
+        }
+        let offset = position(page_id) as u64 * PAGE_SIZE as u64;
+        if offset >= self.handle.metadata()?.len() {
+            *buf = page::empty();
+            return Ok(());
+        }
+        Ok(read_exact_at(&self.handle, buf, offset)?)
+    }
+
+    fn write_page(&self, buf: &[u8; PAGE_SIZE], page_id: PageId) -> Result<(), StorageError> {
+        let offset = position(page_id) as u64 * PAGE_SIZE as u64;
+        Ok(write_all_at(&self.handle, buf, offset)?)
+    }
+}
+
+impl Drop for TempFileInternal {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A temp file. Its pages are handed out in order, reusing freed ones first, and it's deleted when the last handle goes
+pub type TempFile = Synchronized<TempFileInternal>;
+
+/// The temp files of a buffer pool's allocators, by file number. Kept under the pool's latch
+#[derive(Default)]
+pub(crate) struct TempFiles {
+    files: HashMap<u32, TempFile>,
+    next_file_no: u32,
+}
+
+impl TempFiles {
+    /// Create a temp file in `dir`
+    pub(crate) fn create(&mut self, dir: &Path) -> Result<TempFile, StorageError> {
+        let file_no = self.next_file_no;
+        let path = dir.join(format!(
+            "temp_{}_{}.{}",
+            std::process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed),
+            TEMP_FILE_EXTENSION
+        ));
+        let handle = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let file = Synchronized::init(TempFileInternal {
+            handle,
+            path,
+            file_no,
+            num_pages: 0,
+            free: Vec::new(),
+        });
+        self.next_file_no += 1;
+        self.files.insert(file_no, file.clone());
+        Ok(file)
+    }
+
+    pub(crate) fn remove(&mut self, file_no: u32) {
+        self.files.remove(&file_no);
+    }
+
+    fn file(&self, page_id: PageId) -> Option<&TempFile> {
+        self.files.get(&file_no(page_id))
+    }
+
+    /// Clone the handle of the file holding `page_id`, to write to it without the pool latch
+    pub(crate) fn handle(&self, page_id: PageId) -> Option<TempFile> {
+        self.file(page_id).cloned()
+    }
+
+    pub(crate) fn exists(&self, page_id: PageId) -> bool {
+        self.file(page_id)
+            .is_some_and(|file| file.with(|inner| inner.holds(page_id)))
+    }
+
+    /// Write a page back to its file. The pages of an allocator that's gone are discarded
+    pub(crate) fn write_page(
+        &self,
+        buf: &[u8; PAGE_SIZE],
+        page_id: PageId,
+    ) -> Result<(), StorageError> {
+        write_temp_page(self.file(page_id), buf, page_id)
+    }
+
+    pub(crate) fn deallocate(&self, page_id: PageId) -> Result<(), StorageError> {
+        let Some(file) = self.file(page_id) else {
+            return Err(StorageError::PageNotFound(page_id));
+        };
+        let mut inner = file.exclusive();
+        if !inner.holds(page_id) {
+            return Err(StorageError::PageNotFound(page_id));
+        }
+        inner.free.push(page_id);
+        Ok(())
+    }
+}
+
+/// Read a page from `file`, a handle cloned with `TempFiles::handle`. Fails with `StorageError::PageNotFound` if there's no
+/// file, as when its allocator is gone
+pub(crate) fn read_temp_page(
+    file: Option<&TempFile>,
+    buf: &mut [u8; PAGE_SIZE],
+    page_id: PageId,
+) -> Result<(), StorageError> {
+    match file {
+        Some(file) => file.with(|inner| inner.read_page(buf, page_id)),
+        None => Err(StorageError::PageNotFound(page_id)),
+    }
+}
+
+pub(crate) fn write_temp_page(
+    file: Option<&TempFile>,
+    buf: &[u8; PAGE_SIZE],
+    page_id: PageId,
+) -> Result<(), StorageError> {
+    match file {
+        Some(file) => file.with(|inner| inner.write_page(buf, page_id)),
+        None => Ok(()),
+    }
+}
+
+/// Hands out temp pages in the buffer pool (see the top of this file). Dropping it deletes its temp file, along with its
+/// pages that are in the pool and unpinned; pinned ones are dropped when they're evicted
+pub struct TempPageAllocator {
+    pool: BufferPool,
+    file: TempFile,
+    file_no: u32,
+}
+
+impl TempPageAllocator {
+    /// Allocate temp pages in `pool`, kept in a temp file in the system's temp directory
+    pub fn create(pool: &BufferPool) -> Result<Self, StorageError> {
+        TempPageAllocator::create_in(pool, &std::env::temp_dir())
+    }
+
+    /// Allocate temp pages in `pool`, kept in a temp file in `dir`
+    pub fn create_in(pool: &BufferPool, dir: &Path) -> Result<Self, StorageError> {
+        let file = pool.write().create_temp_file(dir)?;
+        let file_no = file.with(|inner| inner.file_no);
+        Ok(TempPageAllocator {
+            pool: pool.clone(),
+            file,
+            file_no,
+        })
+    }
+
+    /// Allocate a temp page and latch it exclusively. The page starts out zeroed. Fails with `StorageError::PoolExhausted`
+    /// if every frame is pinned
+    pub fn new_page(&self) -> Result<WritePageGuard, StorageError> {
+        let (page_id, frame) = self.pool.write().new_temp_page(&self.file)?;
+        Ok(WritePageGuard::new(self.pool.clone(), frame, page_id))
+    }
+
+    /// Pin one of this allocator's pages and latch it shared
+    pub fn fetch_page_read(&self, page_id: PageId) -> Result<ReadPageGuard, StorageError> {
+        self.check(page_id)?;
+        self.pool.fetch_page_read(page_id)
+    }
+
+    /// Pin one of this allocator's pages and latch it exclusively
+    pub fn fetch_page_write(&self, page_id: PageId) -> Result<WritePageGuard, StorageError> {
+        self.check(page_id)?;
+        self.pool.fetch_page_write(page_id)
+    }
+
+    /// Give one of this allocator's pages back, to be reused by a later `new_page`. Fails like `BufApi::delete_page`
+    pub fn free_page(&self, page_id: PageId) -> Result<(), StorageError> {
+        self.check(page_id)?;
+        self.pool.delete_page(page_id)
+    }
+
+    /// Pages allocated and not freed
+    pub fn num_pages(&self) -> usize {
+        self.file
+            .with(|inner| inner.num_pages as usize - inner.free.len())
+    }
+
+    /// Path of the temp file
+    pub fn path(&self) -> PathBuf {
+        self.file.with(|inner| inner.path.clone())
+    }
+
+    fn check(&self, page_id: PageId) -> Result<(), StorageError> {
+        match self.file.with(|inner| inner.holds(page_id)) {
+            true => Ok(()),
+            false => Err(StorageError::PageNotFound(page_id)),
+        }
+    }
+}
+
+impl Drop for TempPageAllocator {
+    fn drop(&mut self) {
+        self.pool.write().drop_temp_file(self.file_no);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::buffer::io;
+    use crate::storage::config::StorageConfig;
+    use crate::testing::Song;
+
+    #[test]
+    fn test_temp_pages() {
+        let dir = cwd() + "/tests/temp_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        let config = StorageConfig::new(&dir).with_pool_size(3);
+        let buffer_pool = BufferPool::create_with_config(&config).unwrap();
+        let data_page = buffer_pool.alloc_page().unwrap();
+        let spill = TempPageAllocator::create_in(&buffer_pool, Path::new(&dir)).unwrap();
+        assert!(spill.path().exists());
+
+        // more temp pages than frames, so most of them are evicted to the temp file
+        let page_ids: Vec<PageId> = (0..8)
+            .map(|i| {
+                let mut guard = spill.new_page().unwrap();
+                *guard.data_mut() =
+                    io::to_buffer(Song::new(i, "Scary Love", "The Neighbourhood")).unwrap();
+                guard.page_id()
+            })
+            .collect();
+        assert!(page_ids.iter().all(|page_id| is_temp_page(*page_id)));
+        assert!(spill.num_pages() == 8);
+        for (i, page_id) in page_ids.iter().enumerate() {
+            let guard = spill.fetch_page_read(*page_id).unwrap();
+            assert!(io::from_buffer::<Song>(guard.data()).unwrap().id == i as i32);
+        }
+
+        // temp pages never reach the data file or the log, and aren't flushed
+        let writes = buffer_pool.stats().disk.writes;
+        spill.fetch_page_write(page_ids[7]).unwrap().data_mut()[100] = 1;
+        assert!(buffer_pool.dirty_pages().is_empty());
+        buffer_pool.flush_all().unwrap();
+        assert!(buffer_pool.stats().disk.writes == writes);
+        assert!(buffer_pool.alloc_page().unwrap() == data_page + 1);
+        assert!(spill.fetch_page_read(data_page).is_err());
+
+        // a freed page is the next one handed out, zeroed
+        spill.free_page(page_ids[2]).unwrap();
+        assert!(spill.fetch_page_read(page_ids[2]).is_err());
+        let guard = spill.new_page().unwrap();
+        assert!(guard.page_id() == page_ids[2] && guard.data()[PAGE_SIZE - 1] == 0);
+        drop(guard);
+
+        // dropping the allocator deletes the file and gives its frames back
+        let path = spill.path();
+        drop(spill);
+        assert!(!path.exists());
+        assert!(buffer_pool.fetch_page(page_ids[0]).is_err());
+        let evictions = buffer_pool.stats().evictions;
+        for _ in 0..3 {
+            assert!(buffer_pool.new_page().is_ok());
+        }
+        assert!(buffer_pool.stats().evictions == evictions);
+
+        std::fs::remove_dir_all(std::path::Path::new(&dir)).unwrap();
+    }
+}
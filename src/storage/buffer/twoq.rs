@@ -0,0 +1,281 @@
+/// This file implements a scan-resistant replacement policy, a simplified 2Q. A frame seen for the first time goes on a
+/// probationary FIFO queue; a second access while it's still there promotes it to the protected queue, which is kept in LRU
+/// order. Eviction takes the oldest evictable probationary frame while the probationary queue holds more than its share of
+/// the frames, and the least recently used protected frame otherwise. Pages touched once, like the pages of a large
+/// sequential scan, cycle through the probationary queue and evict each other instead of the frequently used pages.
+///
+/// The full 2Q also remembers recently evicted pages, so a page that comes back soon after being evicted goes straight to the
+/// protected queue. A replacer only ever sees frame ids, which are reused for other pages after an eviction, so that part is
+/// left out.
+use std::collections::BTreeMap;
+
+use crate::{
+    shared::FrameId,
+    storage::buffer::replacer::Replacer,
+    sync::{Latch as _, Synchronized},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Queue {
+    Probation,
+    Protected,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    queue: Queue,
+    // when the frame entered the probationary queue, or was last accessed in the protected one
+    stamp: u64,
+    evictable: bool,
+}
+
+pub struct TwoQReplacerInternal {
+    // entry i is frame i + 1
+    entries: Vec<Option<Entry>>,
+    // tracked frames of each queue, oldest first
+    probation: BTreeMap<u64, FrameId>,
+    protected: BTreeMap<u64, FrameId>,
+    // how many frames the probationary queue may hold before it's evicted from first
+    probation_limit: usize,
+    clock: u64,
+    num_evictable: usize,
+}
+
+impl TwoQReplacerInternal {
+    fn check_frame(&self, frame_id: FrameId) {
+        assert!(
+            frame_id >= 1 && frame_id as usize <= self.entries.len(),
+            "invalid frame id {}",
+            frame_id
+        );
+    }
+
+    fn queue(&mut self, queue: Queue) -> &mut BTreeMap<u64, FrameId> {
+        match queue {
+            Queue::Probation => &mut self.probation,
+            Queue::Protected => &mut self.protected,
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// The oldest evictable frame of `queue`
+    fn oldest_evictable(&self, queue: Queue) -> Option<FrameId> {
+        let queue = match queue {
+            Queue::Probation => &self.probation,
+            Queue::Protected => &self.protected,
+        };
+        queue
+            .values()
+            .copied()
+            .find(|frame_id| self.entries[(frame_id - 1) as usize].is_some_and(|e| e.evictable))
+    }
+
+    /// Stop tracking a frame
+    fn forget(&mut self, frame_id: FrameId) {
+        if let Some(entry) = self.entries[(frame_id - 1) as usize].take() {
+            self.queue(entry.queue).remove(&entry.stamp);
+            if entry.evictable {
+                self.num_evictable -= 1;
+            }
+        }
+    }
+}
+
+pub type TwoQReplacer = Synchronized<TwoQReplacerInternal>;
+
+pub trait TwoQReplacerApi {
+    fn create(num_frames: usize) -> Self;
+    fn create_with_probation(num_frames: usize, probation_limit: usize) -> Self;
+}
+
+impl TwoQReplacerApi for TwoQReplacer {
+    /// Create a replacer for frames `1..=num_frames` whose probationary queue gets a quarter of the frames
+    fn create(num_frames: usize) -> Self {
+        TwoQReplacer::create_with_probation(num_frames, (num_frames / 4).max(1))
+    }
+
+    /// Create a replacer for frames `1..=num_frames` that evicts from the probationary queue first while it holds more than
+    /// `probation_limit` frames
+    fn create_with_probation(num_frames: usize, probation_limit: usize) -> Self {
+        Synchronized::init(TwoQReplacerInternal {
+            entries: vec![None; num_frames],
+            probation: BTreeMap::new(),
+            protected: BTreeMap::new(),
+            probation_limit,
+            clock: 0,
+            num_evictable: 0,
+        })
+    }
+}
+
+impl Replacer for TwoQReplacer {
+    fn evict(&self) -> Option<FrameId> {
+        let mut inner = self.lock();
+        let victim = if inner.probation.len() > inner.probation_limit {
+            inner
+                .oldest_evictable(Queue::Probation)
+                .or_else(|| inner.oldest_evictable(Queue::Protected))
+        } else {
+            inner
+                .oldest_evictable(Queue::Protected)
+                .or_else(|| inner.oldest_evictable(Queue::Probation))
+        }?;
+        inner.forget(victim);
+        Some(victim)
+    }
+
+    /// A new frame joins the probationary queue. A probationary frame accessed again is promoted, and a protected frame
+    /// moves to the back of its queue
+    fn record_access(&self, frame_id: FrameId) {
+        let mut inner = self.lock();
+        inner.check_frame(frame_id);
+        let stamp = inner.tick();
+        let idx = (frame_id - 1) as usize;
+        let entry = match inner.entries[idx] {
+            None => Entry {
+                queue: Queue::Probation,
+                stamp,
+                evictable: false,
+            },
+            Some(entry) => {
+                inner.queue(entry.queue).remove(&entry.stamp);
+                Entry {
+                    queue: Queue::Protected,
+                    stamp,
+                    evictable: entry.evictable,
+                }
+            }
+        };
+        inner.queue(entry.queue).insert(stamp, frame_id);
+        inner.entries[idx] = Some(entry);
+    }
+
+    fn set_evictable(&self, frame_id: FrameId, evictable: bool) {
+        let mut inner = self.lock();
+        inner.check_frame(frame_id);
+        let Some(entry) = inner.entries[(frame_id - 1) as usize].as_mut() else {
+            return;
+        };
+        if entry.evictable == evictable {
+            return;
+        }
+        entry.evictable = evictable;
+        if evictable {
+            inner.num_evictable += 1;
+        } else {
+            inner.num_evictable -= 1;
+        }
+    }
+
+    fn remove(&self, frame_id: FrameId) {
+        let mut inner = self.lock();
+        inner.check_frame(frame_id);
+        if inner.entries[(frame_id - 1) as usize].is_some_and(|entry| entry.evictable) {
+            inner.forget(frame_id);
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.lock().num_evictable
+    }
+
+    /// The probationary queue keeps the same share of the frames it had before
+    fn resize(&self, num_frames: usize) {
+        let mut inner = self.lock();
+        debug_assert!(inner.entries.iter().skip(num_frames).all(Option::is_none));
+        let old = inner.entries.len().max(1);
+        inner.probation_limit = (inner.probation_limit * num_frames / old).max(1);
+        inner.entries.resize(num_frames, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::{cwd, PageId, BUFFER_POOL_SIZE};
+    use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+
+    #[test]
+    fn test_scan_resistance() {
+        let replacer = TwoQReplacer::create_with_probation(8, 2);
+        // frames 1-4 are used twice and promoted, frames 5-8 are a scan touching each once
+        for frame_id in 1..=4 {
+            replacer.record_access(frame_id);
+            replacer.record_access(frame_id);
+        }
+        for frame_id in 5..=8 {
+            replacer.record_access(frame_id);
+        }
+        for frame_id in 1..=8 {
+            replacer.set_evictable(frame_id, true);
+        }
+        // the probationary queue is over its limit, so the scan goes first, oldest first
+        assert!(replacer.evict() == Some(5));
+        assert!(replacer.evict() == Some(6));
+        // then the protected queue, least recently used first
+        replacer.record_access(1);
+        assert!(replacer.evict() == Some(2));
+        assert!(replacer.evict() == Some(3));
+        assert!(replacer.evict() == Some(4));
+        assert!(replacer.evict() == Some(1));
+        assert!(replacer.evict() == Some(7));
+        assert!(replacer.evict() == Some(8));
+        assert!(replacer.evict().is_none());
+    }
+
+    #[test]
+    fn test_pinned_and_removed_frames() {
+        let replacer = TwoQReplacer::create(3);
+        for frame_id in 1..=3 {
+            replacer.record_access(frame_id);
+        }
+        assert!(replacer.evict().is_none());
+        replacer.set_evictable(1, true);
+        replacer.set_evictable(2, true);
+        replacer.remove(2);
+        replacer.remove(3);
+        assert!(replacer.size() == 1);
+        assert!(replacer.evict() == Some(1));
+        assert!(replacer.evict().is_none());
+    }
+
+    #[test]
+    fn test_buffer_pool_hot_set_survives_scan() {
+        let dir = cwd() + "/tests/twoq_tests";
+        std::fs::create_dir_all(std::path::Path::new(&dir)).unwrap();
+        let path = dir.clone() + "/test_file.bin";
+        // the pool has BUFFER_POOL_SIZE frames
+        let frames = BUFFER_POOL_SIZE;
+        let buffer_pool =
+            BufferPool::create_with_replacer(&path, Box::new(TwoQReplacer::create(frames)))
+                .unwrap();
+        let touch = |page_id: PageId| {
+            assert!(buffer_pool.fetch_page(page_id).is_ok());
+            assert!(buffer_pool.unpin_page(page_id, false));
+        };
+
+        // eight hot pages, fetched twice so they're protected
+        let page_ids: Vec<PageId> = (0..frames * 4)
+            .map(|_| buffer_pool.alloc_page().unwrap())
+            .collect();
+        for page_id in &page_ids[..8] {
+            touch(*page_id);
+            touch(*page_id);
+        }
+        // a scan over four times as many pages as there are frames
+        for page_id in &page_ids[8..] {
+            touch(*page_id);
+        }
+        let misses = buffer_pool.stats().misses;
+        for page_id in &page_ids[..8] {
+            touch(*page_id);
+        }
+        assert!(buffer_pool.stats().misses == misses);
+
+        std::fs::remove_dir_all(std::path::Path::new(&dir)).unwrap();
+    }
+}
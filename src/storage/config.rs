@@ -0,0 +1,142 @@
+/// This file implements the runtime configuration of the storage engine: how many frames the buffer pool has, how many
+/// accesses its LRU-K replacer remembers, where the data file lives, how it's made durable and how its pages are checksummed. `BufApi::create_with_config`
+/// and `DiskApi::create_with_config` (and their `open` counterparts) take a `StorageConfig`; the plain constructors use the
+/// defaults below, so embedders only need one when they want to tune something.
+///
+/// The page size is not configurable: pages are `[u8; PAGE_SIZE]` arrays throughout the engine, so it stays a compile-time
+/// constant.
+use std::path::{Path, PathBuf};
+
+use crate::shared::BUFFER_POOL_SIZE;
+use crate::storage::buffer::bufmgr::REPLACER_K;
+use crate::storage::buffer::diskmgr::{DurabilityMode, SyncPolicy};
+use crate::storage::buffer::page::ChecksumAlgorithm;
+
+/// Name of the data file inside the data directory, unless configured otherwise
+pub const DEFAULT_DATA_FILE: &str = "data.bin";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageConfig {
+    pool_size: usize,
+    replacer_k: usize,
+    data_dir: PathBuf,
+    data_file: String,
+    durability: DurabilityMode,
+    sync_policy: SyncPolicy,
+    checksum: ChecksumAlgorithm,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            pool_size: BUFFER_POOL_SIZE,
+            replacer_k: REPLACER_K,
+            data_dir: PathBuf::from("."),
+            data_file: DEFAULT_DATA_FILE.to_string(),
+            durability: DurabilityMode::default(),
+            sync_policy: SyncPolicy::default(),
+            checksum: ChecksumAlgorithm::default(),
+        }
+    }
+}
+
+impl StorageConfig {
+    /// The default configuration, keeping its data file in `data_dir`
+    pub fn new(data_dir: impl AsRef<Path>) -> Self {
+        StorageConfig::default().with_data_dir(data_dir)
+    }
+
+    /// The default configuration for the data file at `path`, as the constructors that take a path use it
+    pub fn for_path(path: &str) -> Self {
+        let path = Path::new(path);
+        let config = StorageConfig::new(path.parent().unwrap_or(Path::new("")));
+        match path.file_name() {
+            Some(name) => config.with_data_file(&name.to_string_lossy()),
+            None => config,
+        }
+    }
+
+    /// Number of frames in the buffer pool (per instance, for a `ParallelBufferPool`). Must be at least 1
+    pub fn with_pool_size(mut self, frames: usize) -> Self {
+        assert!(frames > 0);
+        self.pool_size = frames;
+        self
+    }
+
+    /// Number of accesses the LRU-K replacer keeps per frame. Must be at least 1
+    pub fn with_replacer_k(mut self, k: usize) -> Self {
+        assert!(k > 0);
+        self.replacer_k = k;
+        self
+    }
+
+    pub fn with_data_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.data_dir = dir.as_ref().to_path_buf();
+        self
+    }
+
+    pub fn with_data_file(mut self, name: &str) -> Self {
+        self.data_file = name.to_string();
+        self
+    }
+
+    pub fn with_durability(mut self, durability: DurabilityMode) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    pub fn with_sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Algorithm pages are checksummed with as they're written. Pages already on disk keep the one they were written with,
+    /// which their header records, so this can change between opens of the same file
+    pub fn with_checksum(mut self, algorithm: ChecksumAlgorithm) -> Self {
+        self.checksum = algorithm;
+        self
+    }
+
+    pub fn pool_size(&self) -> usize {
+        self.pool_size
+    }
+
+    pub fn replacer_k(&self) -> usize {
+        self.replacer_k
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// Path of the data file: the data file name inside the data directory
+    pub fn data_path(&self) -> PathBuf {
+        self.data_dir.join(&self.data_file)
+    }
+
+    pub fn durability(&self) -> DurabilityMode {
+        self.durability
+    }
+
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
+
+    pub fn checksum(&self) -> ChecksumAlgorithm {
+        self.checksum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_for_path() {
+        let config = StorageConfig::for_path("/var/lib/songs/data.db");
+        assert!(config.data_dir() == Path::new("/var/lib/songs"));
+        assert!(config.data_path() == Path::new("/var/lib/songs/data.db"));
+        assert!(config.pool_size() == BUFFER_POOL_SIZE && config.replacer_k() == REPLACER_K);
+        assert!(StorageConfig::for_path("data.db").data_path() == Path::new("data.db"));
+    }
+}
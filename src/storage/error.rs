@@ -0,0 +1,89 @@
+/// This file implements the error type shared by the storage layer. The disk manager, the buffer pool and the page
+/// serialization helpers in `io` all return `StorageError`, so callers can tell a page that was never written from a corrupted
+/// one or from a pool with every frame pinned, instead of getting `None` or a panic. Code that still speaks `std::io::Result`
+/// (the log, recovery, backups) converts in both directions with `?`.
+use std::fmt::Display;
+
+use crate::shared::PageId;
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// The underlying file operation failed. A failed sync is reported this way too, wrapping `diskmgr::FsyncFailed`
+    Io(std::io::Error),
+    /// A value couldn't be encoded into a page or decoded out of one
+    Serialization(String),
+    /// The page lies past the end of the file, i.e. it was never written in full
+    PageNotFound(PageId),
+    /// The page isn't resident and every frame in the buffer pool is pinned
+    PoolExhausted,
+    /// The page is pinned, so it can't be deleted
+    PagePinned(PageId),
+    /// The page on disk failed checksum verification
+    Corruption(PageId),
+    /// A latch couldn't be taken in time
+    LatchTimeout,
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Io(err) => write!(f, "{}", err),
+            StorageError::Serialization(reason) => write!(f, "serialization failed: {}", reason),
+            StorageError::PageNotFound(page_id) => write!(f, "page {} was never written", page_id),
+            StorageError::PoolExhausted => write!(f, "no free frame: every frame is pinned"),
+            StorageError::PagePinned(page_id) => write!(f, "page {} is pinned", page_id),
+            StorageError::Corruption(page_id) => {
+                write!(f, "page {} failed checksum verification", page_id)
+            }
+            StorageError::LatchTimeout => write!(f, "timed out waiting for a latch"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(err: std::io::Error) -> Self {
+        StorageError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for StorageError {
+    fn from(err: bincode::Error) -> Self {
+        StorageError::Serialization(err.to_string())
+    }
+}
+
+impl StorageError {
+    /// The `std::io::ErrorKind` closest to this error
+    pub fn kind(&self) -> std::io::ErrorKind {
+        match self {
+            StorageError::Io(err) => err.kind(),
+            StorageError::Serialization(_) | StorageError::Corruption(_) => {
+                std::io::ErrorKind::InvalidData
+            }
+            StorageError::PageNotFound(_) => std::io::ErrorKind::UnexpectedEof,
+            StorageError::PoolExhausted => std::io::ErrorKind::OutOfMemory,
+            StorageError::PagePinned(_) => std::io::ErrorKind::ResourceBusy,
+            StorageError::LatchTimeout => std::io::ErrorKind::TimedOut,
+        }
+    }
+}
+
+/// For callers that return `std::io::Result`. IO errors are passed through as they are; the rest keep the `StorageError` as
+/// their payload, so it can be downcast back out
+impl From<StorageError> for std::io::Error {
+    fn from(err: StorageError) -> Self {
+        match err {
+            StorageError::Io(err) => err,
+            err => std::io::Error::new(err.kind(), err),
+        }
+    }
+}
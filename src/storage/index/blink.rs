@@ -0,0 +1,1766 @@
+/// This file implements the symmetric concurrent B-link tree of Lanin and Shasha. Every node carries a high key and a link to
+/// its right sibling, so a traversal that lands on a node which has since split simply moves right until it reaches the node
+/// covering its key. That lets every operation hold at most one node latch on its way down. Splits are published bottom-up:
+/// the new right sibling is linked in first and its separator is posted to the parent afterwards.
+///
+/// Deletes only remove the key from its leaf. Merging is the symmetric counterpart of splitting and is done by a separate
+/// compression pass: an underfull leaf absorbs its right sibling, the separator between them is removed from the parent, and
+/// the emptied sibling is marked deleted with an outlink pointing at the node that absorbed it. A traversal holding a stale
+/// pointer to the deleted node follows the outlink and then moves right as usual. Deleted nodes are never reused, since a
+/// traversal may still hold a pointer to them.
+///
+/// For comparison, searches, inserts and deletes can couple latches the classic ways instead (see `Coupling`): latch crabbing,
+/// which keeps the parent latched until the child is known to be safe, and optimistic lock coupling, which reads nodes without
+/// latches and validates them against their frame's version. Handles using different protocols can work on the same tree at
+/// once; the right links and outlinks keep each of them correct around changes made by the others.
+///
+/// A tree can keep a Bloom filter over its keys (see `bloom`), so a search for a key that isn't there usually stops before
+/// reaching any node. `bulk_load` builds one; `rebuild_filter` builds one for any tree, and `compress` rebuilds it once merges
+/// show keys have been deleted, since a filter can't forget them otherwise. An insert adds its key to the filter before the
+/// key can be found in a leaf, so the filter never rules out a key that's there.
+///
+/// Operations fail with a `StorageError` when a page can't be fetched or allocated. A split is linked in only once its new
+/// sibling exists, and a root split whose new root couldn't be installed is finished by the next traversal that needs the
+/// level above, so the tree stays usable after a failure.
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::shared::{PageId, INVALID_PAGE_ID, PAGE_SIZE};
+use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+use crate::storage::buffer::guard::{LatchPath, OptimisticGuard, WritePageGuard};
+use crate::storage::buffer::page::{self, Page, PageType, PAGE_HEADER_SIZE};
+use crate::storage::error::StorageError;
+use crate::storage::index::bloom::{self, BloomFilter};
+use crate::txn::session::{CancellationToken, Interrupted};
+
+pub const KEY_SIZE: usize = 16;
+pub type Key = [u8; KEY_SIZE];
+
+// node layout (after the page header): flags u8 | level u8 | key count u16 | right link i64 | outlink i64 | high key
+const FLAGS_OFFSET: usize = PAGE_HEADER_SIZE;
+const LEVEL_OFFSET: usize = FLAGS_OFFSET + 1;
+const COUNT_OFFSET: usize = LEVEL_OFFSET + 1;
+const RIGHT_OFFSET: usize = COUNT_OFFSET + 2;
+const OUTLINK_OFFSET: usize = RIGHT_OFFSET + 8;
+const HIGH_KEY_OFFSET: usize = OUTLINK_OFFSET + 8;
+const ENTRIES_OFFSET: usize = HIGH_KEY_OFFSET + KEY_SIZE;
+const ENTRY_SIZE: usize = KEY_SIZE + 8;
+
+const LEAF: u8 = 1;
+const DELETED: u8 = 2;
+const HAS_HIGH_KEY: u8 = 4;
+
+// the meta page stores the root page id right after the page header, then the Bloom filter, the filter being rebuilt, and how
+// many rebuilds have started
+const ROOT_OFFSET: usize = PAGE_HEADER_SIZE;
+const FILTER_OFFSET: usize = ROOT_OFFSET + 8;
+const PENDING_FILTER_OFFSET: usize = FILTER_OFFSET + 8;
+const GENERATION_OFFSET: usize = PENDING_FILTER_OFFSET + 8;
+
+/// Most keys a node can hold. Internal nodes hold one more child than keys
+pub const MAX_ENTRIES: usize = (PAGE_SIZE - ENTRIES_OFFSET - 8) / ENTRY_SIZE;
+
+/// How full `bulk_load` packs nodes by default, leaving some room so the first inserts after a load don't all split
+pub const DEFAULT_FILL_FACTOR: f64 = 0.9;
+
+pub const FILTER_FALSE_POSITIVE_RATE: f64 = bloom::DEFAULT_FALSE_POSITIVE_RATE;
+/// Filters are sized for this many times the keys they're built over, so inserts after a build don't degrade them right away
+pub const FILTER_HEADROOM: f64 = 1.5;
+
+fn filter_capacity(keys: usize) -> usize {
+    (keys as f64 * FILTER_HEADROOM).ceil() as usize
+}
+
+/// Build a key from an integer. Keys compare bytewise, so the integer is stored big endian
+pub fn key(n: u64) -> Key {
+    let mut key = [0u8; KEY_SIZE];
+    key[KEY_SIZE - 8..].copy_from_slice(&n.to_be_bytes());
+    key
+}
+
+/// A decoded node. A leaf maps `keys[i]` to `values[i]`. An internal node has one more child than keys: `values[i]` covers
+/// keys in `(keys[i - 1], keys[i]]`, and the last child covers everything up to the node's high key. `high_key` is `None` for
+/// the rightmost node of a level.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub leaf: bool,
+    pub deleted: bool,
+    pub level: u8,
+    pub right: PageId,
+    pub outlink: PageId,
+    pub high_key: Option<Key>,
+    pub keys: Vec<Key>,
+    pub values: Vec<PageId>,
+}
+
+fn read_page_id(page: &Page, offset: usize) -> PageId {
+    PageId::from_le_bytes(page[offset..offset + 8].try_into().unwrap())
+}
+
+fn write_page_id(page: &mut Page, offset: usize, page_id: PageId) {
+    page[offset..offset + 8].copy_from_slice(&page_id.to_le_bytes());
+}
+
+/// The root of the tree whose meta page is `meta`, or `None` if `meta` isn't a meta page. For tools that read a tree page by
+/// page, together with `Node::decode`
+pub fn root_page_id(meta: &Page) -> Option<PageId> {
+    (page::page_type(meta) == Some(PageType::BLinkMeta)).then(|| read_page_id(meta, ROOT_OFFSET))
+}
+
+/// The directory page of the Bloom filter of the tree whose meta page is `meta`, or `None` if the tree has none (or `meta`
+/// isn't a meta page)
+pub fn filter_page_id(meta: &Page) -> Option<PageId> {
+    root_page_id(meta)?;
+    Some(read_page_id(meta, FILTER_OFFSET)).filter(|page_id| *page_id != INVALID_PAGE_ID)
+}
+
+fn generation(meta: &Page) -> u64 {
+    u64::from_le_bytes(
+        meta[GENERATION_OFFSET..GENERATION_OFFSET + 8]
+            .try_into()
+            .unwrap(),
+    )
+}
+
+fn init_meta(meta: &mut Page, root_page_id: PageId, filter_page_id: PageId) {
+    page::set_page_type(meta, PageType::BLinkMeta);
+    write_page_id(meta, ROOT_OFFSET, root_page_id);
+    write_page_id(meta, FILTER_OFFSET, filter_page_id);
+    write_page_id(meta, PENDING_FILTER_OFFSET, INVALID_PAGE_ID);
+    meta[GENERATION_OFFSET..GENERATION_OFFSET + 8].fill(0);
+}
+
+impl Node {
+    fn leaf() -> Node {
+        Node {
+            leaf: true,
+            deleted: false,
+            level: 0,
+            right: INVALID_PAGE_ID,
+            outlink: INVALID_PAGE_ID,
+            high_key: None,
+            keys: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    /// Decode a node page, or return `None` if `page` isn't one
+    pub fn decode(page: &Page) -> Option<Node> {
+        (page::page_type(page) == Some(PageType::BLinkNode)).then(|| Node::read(page))
+    }
+
+    fn read(page: &Page) -> Node {
+        let flags = page[FLAGS_OFFSET];
+        let count =
+            u16::from_le_bytes(page[COUNT_OFFSET..COUNT_OFFSET + 2].try_into().unwrap()) as usize;
+        let leaf = flags & LEAF != 0;
+        let high_key = (flags & HAS_HIGH_KEY != 0).then(|| {
+            page[HIGH_KEY_OFFSET..HIGH_KEY_OFFSET + KEY_SIZE]
+                .try_into()
+                .unwrap()
+        });
+        let mut offset = ENTRIES_OFFSET;
+        let mut values = Vec::with_capacity(count + 1);
+        if !leaf {
+            values.push(read_page_id(page, offset));
+            offset += 8;
+        }
+        let mut keys = Vec::with_capacity(count);
+        for _ in 0..count {
+            keys.push(page[offset..offset + KEY_SIZE].try_into().unwrap());
+            values.push(read_page_id(page, offset + KEY_SIZE));
+            offset += ENTRY_SIZE;
+        }
+        Node {
+            leaf,
+            deleted: flags & DELETED != 0,
+            level: page[LEVEL_OFFSET],
+            right: read_page_id(page, RIGHT_OFFSET),
+            outlink: read_page_id(page, OUTLINK_OFFSET),
+            high_key,
+            keys,
+            values,
+        }
+    }
+
+    fn write(&self, page: &mut Page) {
+        assert!(self.keys.len() <= MAX_ENTRIES);
+        let mut flags = 0;
+        if self.leaf {
+            flags |= LEAF;
+        }
+        if self.deleted {
+            flags |= DELETED;
+        }
+        if self.high_key.is_some() {
+            flags |= HAS_HIGH_KEY;
+        }
+        page::set_page_type(page, PageType::BLinkNode);
+        page[FLAGS_OFFSET] = flags;
+        page[LEVEL_OFFSET] = self.level;
+        page[COUNT_OFFSET..COUNT_OFFSET + 2]
+            .copy_from_slice(&(self.keys.len() as u16).to_le_bytes());
+        write_page_id(page, RIGHT_OFFSET, self.right);
+        write_page_id(page, OUTLINK_OFFSET, self.outlink);
+        page[HIGH_KEY_OFFSET..HIGH_KEY_OFFSET + KEY_SIZE]
+            .copy_from_slice(&self.high_key.unwrap_or_default());
+        let mut offset = ENTRIES_OFFSET;
+        let mut values = self.values.iter();
+        if !self.leaf {
+            write_page_id(page, offset, *values.next().unwrap());
+            offset += 8;
+        }
+        for (key, value) in self.keys.iter().zip(values) {
+            page[offset..offset + KEY_SIZE].copy_from_slice(key);
+            write_page_id(page, offset + KEY_SIZE, *value);
+            offset += ENTRY_SIZE;
+        }
+    }
+
+    /// Whether `key` lies beyond this node's range, i.e. a traversal looking for it has to move right
+    fn beyond(&self, key: &Key) -> bool {
+        self.high_key.is_some_and(|high_key| *key > high_key)
+    }
+
+    /// Child of an internal node whose range contains `key`
+    fn child(&self, key: &Key) -> PageId {
+        self.values[self.keys.partition_point(|k| k < key)]
+    }
+
+    /// Move the upper half of this node into a new right sibling. Returns the separator (the new high key of this node) and
+    /// the sibling, which still has to be linked in.
+    fn split(&mut self) -> (Key, Node) {
+        let mid = self.keys.len() / 2;
+        let mut sibling = Node {
+            leaf: self.leaf,
+            deleted: false,
+            level: self.level,
+            right: self.right,
+            outlink: INVALID_PAGE_ID,
+            high_key: self.high_key,
+            keys: Vec::new(),
+            values: Vec::new(),
+        };
+        let separator = if self.leaf {
+            sibling.keys = self.keys.split_off(mid);
+            sibling.values = self.values.split_off(mid);
+            *self.keys.last().unwrap()
+        } else {
+            sibling.keys = self.keys.split_off(mid + 1);
+            sibling.values = self.values.split_off(mid + 1);
+            self.keys.pop().unwrap()
+        };
+        self.high_key = Some(separator);
+        (separator, sibling)
+    }
+}
+
+/// How `search`, `insert` and `delete` latch nodes on their way down the tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Coupling {
+    /// Lanin and Shasha's: one latch at a time, moving right past splits that haven't reached the parent yet
+    #[default]
+    Link,
+    /// Latch crabbing: a node is latched before its parent is let go of, and an insert keeps write latches on every node
+    /// above the leaf that a split could reach, up to the first one with room to spare
+    Crabbing,
+    /// Optimistic lock coupling: nodes are copied without latches and validated against their frame's version, each
+    /// parent again after its child, and only the leaf is write-latched. An insert that would split the leaf falls back to
+    /// crabbing
+    Optimistic,
+}
+
+/// Why `build` stopped before inserting every entry
+#[derive(Debug)]
+pub enum BuildError {
+    /// The statement was cancelled or ran past its deadline
+    Interrupted(Interrupted),
+    /// An insert failed, e.g. because every frame in the pool is pinned
+    Storage(StorageError),
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::Interrupted(interrupted) => write!(f, "{}", interrupted),
+            BuildError::Storage(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BuildError::Interrupted(interrupted) => Some(interrupted),
+            BuildError::Storage(err) => Some(err),
+        }
+    }
+}
+
+impl From<Interrupted> for BuildError {
+    fn from(interrupted: Interrupted) -> Self {
+        BuildError::Interrupted(interrupted)
+    }
+}
+
+impl From<StorageError> for BuildError {
+    fn from(err: StorageError) -> Self {
+        BuildError::Storage(err)
+    }
+}
+
+/// A B-link tree mapping fixed-size keys to page ids, stored in pages of `pool`. Keys are unique. Cloning the tree gives
+/// another handle to the same index.
+#[derive(Clone)]
+pub struct BLinkTree {
+    pool: BufferPool,
+    meta_page_id: PageId,
+    max_entries: usize,
+    coupling: Coupling,
+}
+
+impl BLinkTree {
+    /// Create an empty tree. Returns `None` if the buffer pool can't allocate its first pages
+    pub fn create(pool: BufferPool) -> Option<Self> {
+        BLinkTree::create_with_fanout(pool, MAX_ENTRIES)
+    }
+
+    /// Create an empty tree whose nodes split once they hold more than `max_entries` keys (at least 3, at most `MAX_ENTRIES`)
+    pub fn create_with_fanout(pool: BufferPool, max_entries: usize) -> Option<Self> {
+        assert!((3..=MAX_ENTRIES).contains(&max_entries));
+        let mut meta = pool.new_page_write().ok()?;
+        let mut root = pool.new_page_write().ok()?;
+        Node::leaf().write(&mut root);
+        init_meta(&mut meta, root.page_id(), INVALID_PAGE_ID);
+        Some(BLinkTree {
+            meta_page_id: meta.page_id(),
+            pool,
+            max_entries,
+            coupling: Coupling::Link,
+        })
+    }
+
+    /// Open a tree created earlier, identified by its meta page
+    pub fn open(pool: BufferPool, meta_page_id: PageId, max_entries: usize) -> Self {
+        BLinkTree {
+            pool,
+            meta_page_id,
+            max_entries,
+            coupling: Coupling::Link,
+        }
+    }
+
+    pub fn meta_page_id(&self) -> PageId {
+        self.meta_page_id
+    }
+
+    /// This handle, with `search`, `insert` and `delete` coupling latches the way `coupling` says. Other handles to the tree
+    /// keep theirs
+    pub fn with_coupling(mut self, coupling: Coupling) -> Self {
+        self.coupling = coupling;
+        self
+    }
+
+    pub fn coupling(&self) -> Coupling {
+        self.coupling
+    }
+
+    /// Build a tree from `entries`, which must be sorted by key with no duplicates, packing nodes to `DEFAULT_FILL_FACTOR`.
+    /// See `bulk_load_with`
+    pub fn bulk_load<I: IntoIterator<Item = (Key, PageId)>>(
+        pool: BufferPool,
+        entries: I,
+    ) -> Option<Self> {
+        BLinkTree::bulk_load_with(pool, MAX_ENTRIES, DEFAULT_FILL_FACTOR, entries)
+    }
+
+    /// Build a tree whose nodes split once they hold more than `max_entries` keys from `entries`, which must be sorted by key
+    /// with no duplicates. The leaves are written left to right, each filled to `fill_factor` of `max_entries` (in `(0, 1]`),
+    /// and then each level of internal nodes is built over the one below, until a level has a single node, which becomes the
+    /// root. Nothing is latched or searched along the way, since no one else can reach the tree until it's returned. The
+    /// tree gets a Bloom filter over the loaded keys.
+    ///
+    /// Returns `None` if the buffer pool can't allocate the pages, in which case those written so far are leaked. Panics if
+    /// the keys aren't strictly increasing.
+    pub fn bulk_load_with<I: IntoIterator<Item = (Key, PageId)>>(
+        pool: BufferPool,
+        max_entries: usize,
+        fill_factor: f64,
+        entries: I,
+    ) -> Option<Self> {
+        assert!((3..=MAX_ENTRIES).contains(&max_entries));
+        assert!(fill_factor > 0.0 && fill_factor <= 1.0);
+        let per_node = ((max_entries as f64 * fill_factor).ceil() as usize).clamp(1, max_entries);
+        let mut meta = pool.new_page_write().ok()?;
+
+        let mut level = LevelWriter::new(pool.clone());
+        let mut leaf = Node::leaf();
+        let mut keys = Vec::new();
+        for (key, value) in entries {
+            if let Some(last) = leaf.keys.last().or(level.last_key.as_ref()) {
+                assert!(
+                    key > *last,
+                    "bulk load entries must be sorted by key and unique"
+                );
+            }
+            keys.push(key);
+            leaf.keys.push(key);
+            leaf.values.push(value);
+            if leaf.keys.len() == per_node {
+                let leaf = std::mem::replace(&mut leaf, Node::leaf());
+                level.push(leaf.keys[per_node - 1], leaf)?;
+            }
+        }
+        if !leaf.keys.is_empty() || level.nodes.is_empty() {
+            level.push(leaf.keys.last().copied().unwrap_or_default(), leaf)?;
+        }
+        let mut nodes = level.finish()?;
+
+        // each internal node takes the high keys of all but its last child as separators, and the last child's as its own
+        let mut height = 0;
+        while nodes.len() > 1 {
+            height += 1;
+            let mut level = LevelWriter::new(pool.clone());
+            for children in nodes.chunks(per_node + 1) {
+                let (high_key, _) = *children.last().unwrap();
+                let node = Node {
+                    leaf: false,
+                    deleted: false,
+                    level: height,
+                    right: INVALID_PAGE_ID,
+                    outlink: INVALID_PAGE_ID,
+                    high_key: None,
+                    keys: children[..children.len() - 1]
+                        .iter()
+                        .map(|(key, _)| *key)
+                        .collect(),
+                    values: children.iter().map(|(_, page_id)| *page_id).collect(),
+                };
+                level.push(high_key, node)?;
+            }
+            nodes = level.finish()?;
+        }
+
+        let mut filter = BloomFilter::new(filter_capacity(keys.len()), FILTER_FALSE_POSITIVE_RATE);
+        for key in &keys {
+            filter.insert(key);
+        }
+        let filter_page_id = filter.write(&pool).ok()?;
+        init_meta(&mut meta, nodes[0].1, filter_page_id);
+        Some(BLinkTree {
+            meta_page_id: meta.page_id(),
+            pool,
+            max_entries,
+            coupling: Coupling::Link,
+        })
+    }
+
+    /// Iterate over the keys in `range` in order. If a page can't be fetched the scan ends early and `error` says why
+    pub fn scan<R: RangeBounds<Key>>(&self, range: R) -> IndexIterator {
+        let lower = range.start_bound().cloned();
+        let start = match lower {
+            Bound::Included(key) | Bound::Excluded(key) => self.find_leaf(&key, &mut Vec::new()),
+            Bound::Unbounded => self.leftmost_leaf(),
+        };
+        let (next_page_id, error) = match start {
+            Ok(page_id) => (page_id, None),
+            Err(err) => (INVALID_PAGE_ID, Some(err)),
+        };
+        IndexIterator {
+            tree: self.clone(),
+            buffer: VecDeque::new(),
+            next_page_id,
+            lower,
+            upper: range.end_bound().cloned(),
+            cancel: None,
+            interrupted: None,
+            error,
+        }
+    }
+
+    /// Insert every entry from `entries`, e.g. when building an index over an existing table. The statement owning `cancel` is
+    /// checked once per leaf's worth of entries; if it was cancelled or timed out the build stops there and the error is
+    /// returned. An insert that fails stops the build the same way. Entries inserted so far stay in the tree, so the caller
+    /// should drop the index as part of rolling back. Returns how many entries were inserted (duplicates are skipped). A new
+    /// index over sorted entries is much quicker to make with `bulk_load`.
+    pub fn build<I: IntoIterator<Item = (Key, PageId)>>(
+        &self,
+        entries: I,
+        cancel: &CancellationToken,
+    ) -> Result<usize, BuildError> {
+        let mut inserted = 0;
+        for (i, (key, value)) in entries.into_iter().enumerate() {
+            if i % self.max_entries == 0 {
+                cancel.check()?;
+            }
+            if self.insert(&key, value)? {
+                inserted += 1;
+            }
+        }
+        Ok(inserted)
+    }
+
+    /// Iterate over every key in order
+    pub fn iter(&self) -> IndexIterator {
+        self.scan(..)
+    }
+
+    pub fn search(&self, key: &Key) -> Result<Option<PageId>, StorageError> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        match self.coupling {
+            Coupling::Link => self.search_link(key),
+            Coupling::Crabbing => self.search_crabbing(key),
+            Coupling::Optimistic => self.search_optimistic(key),
+        }
+    }
+
+    /// Insert `key`. Returns false if it's already present. Fails if a page can't be fetched or allocated, e.g. with
+    /// `PoolExhausted`. A failure while carrying a split up the tree comes after the key reached its leaf, so the key is in
+    /// the tree then; only a separator is missing from a parent, which traversals move right past like any unposted split
+    pub fn insert(&self, key: &Key, value: PageId) -> Result<bool, StorageError> {
+        let generation = self.add_to_filters(key)?;
+        let inserted = match self.coupling {
+            Coupling::Link => self.insert_link(key, value)?,
+            Coupling::Crabbing => self.insert_crabbing(key, value)?,
+            Coupling::Optimistic => self.insert_optimistic(key, value)?,
+        };
+        // a rebuild that started since may have scanned past the key's leaf before the key got there
+        if self.filter_generation()? != generation {
+            self.add_to_filters(key)?;
+        }
+        Ok(inserted)
+    }
+
+    /// Whether `key` may be in the tree: false only if the tree's Bloom filter rules it out
+    pub fn may_contain(&self, key: &Key) -> bool {
+        let Ok(meta) = self.pool.fetch_page_read(self.meta_page_id) else {
+            return true;
+        };
+        // the meta latch keeps the filter from being freed while it's read
+        let filter = read_page_id(&meta, FILTER_OFFSET);
+        filter == INVALID_PAGE_ID || bloom::may_contain(&self.pool, filter, key).unwrap_or(true)
+    }
+
+    pub fn has_filter(&self) -> Result<bool, StorageError> {
+        let meta = self.pool.fetch_page_read(self.meta_page_id)?;
+        Ok(read_page_id(&meta, FILTER_OFFSET) != INVALID_PAGE_ID)
+    }
+
+    /// Build a Bloom filter over the keys in the tree and swap it in for the current one, if any, dropping the bits deleted
+    /// keys left behind. The filter is sized for `FILTER_HEADROOM` times the keys in the tree now. Inserts carry on meanwhile,
+    /// adding their keys to both filters, so the new one misses nothing. Returns false if no filter was built: the pool
+    /// couldn't allocate one, another rebuild is running, or an insert couldn't add its key to it. Fails if the tree can't be
+    /// scanned, leaving the current filter in place
+    pub fn rebuild_filter(&self) -> Result<bool, StorageError> {
+        // pinned throughout, so latching the meta page to register and swap in the new filter needs no free frame
+        let _pinned = self.pool.fetch_page_pinned(self.meta_page_id)?;
+        let mut keys = self.iter();
+        let count = keys.by_ref().count();
+        if let Some(err) = keys.error.take() {
+            return Err(err);
+        }
+        let mut filter = BloomFilter::new(filter_capacity(count), FILTER_FALSE_POSITIVE_RATE);
+        let Ok(pending) = filter.write(&self.pool) else {
+            return Ok(false);
+        };
+        {
+            let mut meta = self.pool.fetch_page_write(self.meta_page_id)?;
+            if read_page_id(&meta, PENDING_FILTER_OFFSET) != INVALID_PAGE_ID {
+                drop(meta);
+                bloom::free(&self.pool, pending);
+                return Ok(false);
+            }
+            write_page_id(&mut meta, PENDING_FILTER_OFFSET, pending);
+            let generation = generation(&meta) + 1;
+            meta[GENERATION_OFFSET..GENERATION_OFFSET + 8]
+                .copy_from_slice(&generation.to_le_bytes());
+        }
+        // every key inserted from here on goes into the pending filter too, so only the keys already in the tree are missing
+        let mut keys = self.iter();
+        for (key, _) in keys.by_ref() {
+            filter.insert(&key);
+        }
+        // a filter missing some of the keys mustn't be swapped in, so a failed scan discards it
+        let scanned = keys.error.take();
+        let merged = scanned.is_none() && filter.merge_into(&self.pool, pending).is_ok();
+        let old = {
+            let mut meta = self.pool.fetch_page_write(self.meta_page_id)?;
+            // an insert that couldn't add its key to the pending filter has already unregistered it
+            let registered = read_page_id(&meta, PENDING_FILTER_OFFSET) == pending;
+            if registered {
+                write_page_id(&mut meta, PENDING_FILTER_OFFSET, INVALID_PAGE_ID);
+            }
+            if !merged || !registered {
+                drop(meta);
+                bloom::free(&self.pool, pending);
+                return match scanned {
+                    Some(err) => Err(err),
+                    None => Ok(false),
+                };
+            }
+            let old = read_page_id(&meta, FILTER_OFFSET);
+            write_page_id(&mut meta, FILTER_OFFSET, pending);
+            old
+        };
+        if old != INVALID_PAGE_ID {
+            bloom::free(&self.pool, old);
+        }
+        Ok(true)
+    }
+
+    fn filter_generation(&self) -> Result<u64, StorageError> {
+        let meta = self.pool.fetch_page_read(self.meta_page_id)?;
+        Ok(generation(&meta))
+    }
+
+    /// Add `key` to the tree's filter and to the one being rebuilt, and return the rebuild generation they belong to. A filter
+    /// the key can't be added to is dropped, since it would no longer hold every key: the tree's own is freed, and a pending
+    /// one is unregistered for its rebuild to free
+    fn add_to_filters(&self, key: &Key) -> Result<u64, StorageError> {
+        let (generation, failed) = {
+            let meta = self.pool.fetch_page_read(self.meta_page_id)?;
+            let failed: Vec<(usize, PageId)> = [FILTER_OFFSET, PENDING_FILTER_OFFSET]
+                .into_iter()
+                .map(|offset| (offset, read_page_id(&meta, offset)))
+                .filter(|(_, filter)| {
+                    *filter != INVALID_PAGE_ID && bloom::insert(&self.pool, *filter, key).is_err()
+                })
+                .collect();
+            (generation(&meta), failed)
+        };
+        for (offset, filter) in failed {
+            let mut meta = self.pool.fetch_page_write(self.meta_page_id)?;
+            if read_page_id(&meta, offset) != filter {
+                continue;
+            }
+            write_page_id(&mut meta, offset, INVALID_PAGE_ID);
+            drop(meta);
+            if offset == FILTER_OFFSET {
+                bloom::free(&self.pool, filter);
+            }
+        }
+        Ok(generation)
+    }
+
+    /// Remove `key`. Returns false if it isn't present. The leaf is left as is even if it becomes underfull; `compress` merges
+    /// underfull leaves.
+    pub fn delete(&self, key: &Key) -> Result<bool, StorageError> {
+        match self.coupling {
+            Coupling::Link => self.delete_link(key),
+            Coupling::Crabbing => self.delete_crabbing(key),
+            Coupling::Optimistic => self.delete_optimistic(key),
+        }
+    }
+
+    fn search_link(&self, key: &Key) -> Result<Option<PageId>, StorageError> {
+        let mut page_id = self.find_leaf(key, &mut Vec::new())?;
+        loop {
+            let guard = self.pool.fetch_page_read(page_id)?;
+            let node = Node::read(&guard);
+            match next_hop(&node, key) {
+                Some(next) => page_id = next,
+                None => {
+                    let pos = node.keys.binary_search(key).ok();
+                    return Ok(pos.map(|pos| node.values[pos]));
+                }
+            }
+        }
+    }
+
+    fn insert_link(&self, key: &Key, value: PageId) -> Result<bool, StorageError> {
+        let mut stack = Vec::new();
+        let leaf_id = self.find_leaf(key, &mut stack)?;
+        let (mut guard, mut node) = self.latch(leaf_id, key)?;
+        let pos = match node.keys.binary_search(key) {
+            Ok(_) => return Ok(false),
+            Err(pos) => pos,
+        };
+        node.keys.insert(pos, *key);
+        node.values.insert(pos, value);
+        if node.keys.len() <= self.max_entries {
+            node.write(&mut guard);
+            return Ok(true);
+        }
+        let (separator, right_id) = self.split(&mut guard, &mut node)?;
+        let left_id = guard.page_id();
+        drop(guard);
+        self.post(stack, left_id, separator, right_id, node.level)?;
+        Ok(true)
+    }
+
+    /// Post the separator of a split node at `level` to its parent, the last node on `stack`, splitting upwards for as long
+    /// as parents overflow. Holds one latch at a time. A parent that already links to `right_id` had the separator posted by
+    /// `install_root`. If this fails, the split below stays linked in and traversals move right past it as usual
+    fn post(
+        &self,
+        mut stack: Vec<PageId>,
+        mut left_id: PageId,
+        mut separator: Key,
+        mut right_id: PageId,
+        mut level: u8,
+    ) -> Result<(), StorageError> {
+        loop {
+            let parent_id = match stack.pop() {
+                Some(parent_id) => parent_id,
+                None => match self.grow_root(left_id, separator, right_id, level)? {
+                    Some(parent_id) => parent_id,
+                    None => return Ok(()),
+                },
+            };
+            let (mut guard, mut node) = self.latch(parent_id, &separator)?;
+            if node.values.contains(&right_id) {
+                return Ok(());
+            }
+            let pos = node.keys.partition_point(|k| *k < separator);
+            node.keys.insert(pos, separator);
+            node.values.insert(pos + 1, right_id);
+            if node.keys.len() <= self.max_entries {
+                node.write(&mut guard);
+                return Ok(());
+            }
+            (separator, right_id) = self.split(&mut guard, &mut node)?;
+            left_id = guard.page_id();
+            level = node.level;
+        }
+    }
+
+    fn delete_link(&self, key: &Key) -> Result<bool, StorageError> {
+        let leaf_id = self.find_leaf(key, &mut Vec::new())?;
+        let (mut guard, mut node) = self.latch(leaf_id, key)?;
+        let Ok(pos) = node.keys.binary_search(key) else {
+            return Ok(false);
+        };
+        node.keys.remove(pos);
+        node.values.remove(pos);
+        node.write(&mut guard);
+        Ok(true)
+    }
+
+    /// Search with read latches handed over from each node to the next
+    fn search_crabbing(&self, key: &Key) -> Result<Option<PageId>, StorageError> {
+        let meta = self.pool.fetch_page_read(self.meta_page_id)?;
+        let mut guard = self
+            .pool
+            .fetch_page_read(read_page_id(&meta, ROOT_OFFSET))?;
+        drop(meta);
+        loop {
+            let node = Node::read(&guard);
+            let next = match next_hop(&node, key) {
+                Some(next) => next,
+                None if node.leaf => {
+                    let pos = node.keys.binary_search(key).ok();
+                    return Ok(pos.map(|pos| node.values[pos]));
+                }
+                None => node.child(key),
+            };
+            // the next node is latched before this one is let go of
+            guard = self.pool.fetch_page_read(next)?;
+        }
+    }
+
+    /// Write-latch the path down to the leaf covering `key`, keeping the nodes from the last one that isn't `safe` down. The
+    /// meta page stays latched too while the root isn't safe, since splitting the root changes it. Returns the path, with the
+    /// leaf last, and the leaf
+    fn crab(
+        &self,
+        key: &Key,
+        safe: impl Fn(&Node) -> bool,
+    ) -> Result<(LatchPath, Node), StorageError> {
+        let mut path = LatchPath::new();
+        let meta = self.pool.fetch_page_write(self.meta_page_id)?;
+        let mut page_id = read_page_id(&meta, ROOT_OFFSET);
+        path.push(meta, false);
+        loop {
+            let guard = self.pool.fetch_page_write(page_id)?;
+            let node = Node::read(&guard);
+            // only a link or optimistic insert, or compression, can have moved the key's range elsewhere
+            if let Some(next) = next_hop(&node, key) {
+                drop(guard);
+                page_id = next;
+                continue;
+            }
+            path.push(guard, safe(&node));
+            if node.leaf {
+                return Ok((path, node));
+            }
+            page_id = node.child(key);
+        }
+    }
+
+    fn insert_crabbing(&self, key: &Key, value: PageId) -> Result<bool, StorageError> {
+        let (mut path, mut node) = self.crab(key, |node| node.keys.len() < self.max_entries)?;
+        let mut guard = path.pop().unwrap();
+        let pos = match node.keys.binary_search(key) {
+            Ok(_) => return Ok(false),
+            Err(pos) => pos,
+        };
+        node.keys.insert(pos, *key);
+        node.values.insert(pos, value);
+        if node.keys.len() <= self.max_entries {
+            node.write(&mut guard);
+            return Ok(true);
+        }
+
+        self.split_crabbing(path, guard, node)?;
+        Ok(true)
+    }
+
+    /// Split the latched leaf `node` and carry the splits up `path`, the rest of what `crab` latched. Every node that can
+    /// split is still latched, along with the one above the highest of them
+    fn split_crabbing(
+        &self,
+        mut path: LatchPath,
+        mut guard: WritePageGuard,
+        mut node: Node,
+    ) -> Result<(), StorageError> {
+        loop {
+            let (separator, right_id) = self.split(&mut guard, &mut node)?;
+            let left_id = guard.page_id();
+            let level = node.level;
+            drop(guard);
+            guard = path.pop().unwrap();
+            if guard.page_id() == self.meta_page_id {
+                if read_page_id(&guard, ROOT_OFFSET) == left_id {
+                    return self.new_root(&mut guard, left_id, separator, right_id, level);
+                }
+                drop(guard);
+                return self.post(Vec::new(), left_id, separator, right_id, level);
+            }
+            node = Node::read(&guard);
+            if node.deleted || node.beyond(&separator) {
+                // another protocol moved the range: post the separator the link way
+                let mut stack = path.page_ids();
+                stack.retain(|page_id| *page_id != self.meta_page_id);
+                stack.push(guard.page_id());
+                drop(guard);
+                path.release();
+                return self.post(stack, left_id, separator, right_id, level);
+            }
+            let pos = node.keys.partition_point(|k| *k < separator);
+            node.keys.insert(pos, separator);
+            node.values.insert(pos + 1, right_id);
+            if node.keys.len() <= self.max_entries {
+                node.write(&mut guard);
+                return Ok(());
+            }
+        }
+    }
+
+    /// A delete never changes anything above the leaf, so every node is safe and the write latches are simply handed down
+    fn delete_crabbing(&self, key: &Key) -> Result<bool, StorageError> {
+        let (mut path, mut node) = self.crab(key, |_| true)?;
+        let mut guard = path.pop().unwrap();
+        let Ok(pos) = node.keys.binary_search(key) else {
+            return Ok(false);
+        };
+        node.keys.remove(pos);
+        node.values.remove(pos);
+        node.write(&mut guard);
+        Ok(true)
+    }
+
+    /// Descend to the leaf covering `key` without latching anything, copying each node and checking its parent is unchanged
+    /// once the node's version has been taken. Returns the leaf's guard and a validated copy of the leaf, or `None` if a
+    /// node changed under the descent and it has to start over
+    fn descend_optimistic(
+        &self,
+        key: &Key,
+    ) -> Result<Option<(OptimisticGuard, Node)>, StorageError> {
+        let mut parent = self.pool.fetch_page_optimistic(self.meta_page_id)?;
+        let Some(meta) = parent.copy() else {
+            return Ok(None);
+        };
+        let mut page_id = read_page_id(&meta, ROOT_OFFSET);
+        loop {
+            let guard = self.pool.fetch_page_optimistic(page_id)?;
+            if !parent.validate() {
+                return Ok(None);
+            }
+            let Some(page) = guard.copy() else {
+                return Ok(None);
+            };
+            let node = Node::read(&page);
+            match next_hop(&node, key) {
+                Some(next) => page_id = next,
+                None if node.leaf => return Ok(Some((guard, node))),
+                None => page_id = node.child(key),
+            }
+            parent = guard;
+        }
+    }
+
+    fn search_optimistic(&self, key: &Key) -> Result<Option<PageId>, StorageError> {
+        loop {
+            if let Some((_, node)) = self.descend_optimistic(key)? {
+                let pos = node.keys.binary_search(key).ok();
+                return Ok(pos.map(|pos| node.values[pos]));
+            }
+        }
+    }
+
+    fn insert_optimistic(&self, key: &Key, value: PageId) -> Result<bool, StorageError> {
+        loop {
+            let Some((guard, mut node)) = self.descend_optimistic(key)? else {
+                continue;
+            };
+            let pos = match node.keys.binary_search(key) {
+                Ok(_) => return Ok(false),
+                Err(pos) => pos,
+            };
+            if node.keys.len() >= self.max_entries {
+                // the leaf would split, and its parent has to be latched for that
+                drop(guard);
+                return self.insert_crabbing(key, value);
+            }
+            let Some(mut guard) = guard.upgrade() else {
+                continue;
+            };
+            node.keys.insert(pos, *key);
+            node.values.insert(pos, value);
+            node.write(&mut guard);
+            return Ok(true);
+        }
+    }
+
+    fn delete_optimistic(&self, key: &Key) -> Result<bool, StorageError> {
+        loop {
+            let Some((guard, mut node)) = self.descend_optimistic(key)? else {
+                continue;
+            };
+            let Ok(pos) = node.keys.binary_search(key) else {
+                return Ok(false);
+            };
+            let Some(mut guard) = guard.upgrade() else {
+                continue;
+            };
+            node.keys.remove(pos);
+            node.values.remove(pos);
+            node.write(&mut guard);
+            return Ok(true);
+        }
+    }
+
+    /// Merge adjacent leaves under the same parent whose combined size is at most half a node. Returns how many leaves were
+    /// merged away. Latches are taken top-down and left to right (parent, left leaf, right leaf), while every other operation
+    /// holds one node latch at a time, so compression can run alongside them. If anything was merged, the tree's Bloom filter
+    /// (if it has one) is rebuilt. A failure stops the pass; the merges made so far stay.
+    pub fn compress(&self) -> Result<usize, StorageError> {
+        let mut merged = 0;
+        let mut parent_id = self.root()?;
+        loop {
+            let guard = self.pool.fetch_page_read(parent_id)?;
+            let node = Node::read(&guard);
+            if node.leaf {
+                return Ok(merged);
+            }
+            if node.level == 1 || node.deleted {
+                break;
+            }
+            parent_id = node.values[0];
+        }
+
+        while parent_id != INVALID_PAGE_ID {
+            let mut parent_guard = self.pool.fetch_page_write(parent_id)?;
+            let mut parent = Node::read(&parent_guard);
+            let mut changed = false;
+            let mut i = 0;
+            while !parent.deleted && i + 1 < parent.values.len() {
+                match self.merge(&mut parent, i) {
+                    Ok(true) => {
+                        merged += 1;
+                        changed = true;
+                    }
+                    Ok(false) => i += 1,
+                    Err(err) => {
+                        if changed {
+                            parent.write(&mut parent_guard);
+                        }
+                        return Err(err);
+                    }
+                }
+            }
+            if changed {
+                parent.write(&mut parent_guard);
+            }
+            parent_id = parent.right;
+        }
+        // the keys that made those leaves underfull were deleted, but are still in the filter
+        if merged > 0 && self.has_filter()? {
+            self.rebuild_filter()?;
+        }
+        Ok(merged)
+    }
+
+    /// Run `compress` every `interval` on a background thread until the returned handle is dropped. A pass that fails, e.g.
+    /// because the pool is short of frames, is simply tried again at the next interval
+    pub fn spawn_compressor(&self, interval: Duration) -> Compressor {
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let tree = self.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Acquire) {
+                    let _ = tree.compress();
+                    std::thread::park_timeout(interval);
+                }
+            })
+        };
+        Compressor {
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    /// Try to merge child `i + 1` of `parent` into child `i`. The caller holds the parent's write latch
+    fn merge(&self, parent: &mut Node, i: usize) -> Result<bool, StorageError> {
+        let (left_id, right_id) = (parent.values[i], parent.values[i + 1]);
+        let mut left_guard = self.pool.fetch_page_write(left_id)?;
+        let mut left = Node::read(&left_guard);
+        // the left child may have split without the new sibling being posted to the parent yet
+        if !left.leaf || left.deleted || left.right != right_id {
+            return Ok(false);
+        }
+        let mut right_guard = self.pool.fetch_page_write(right_id)?;
+        let mut right = Node::read(&right_guard);
+        if right.deleted || left.keys.len() + right.keys.len() > self.max_entries / 2 {
+            return Ok(false);
+        }
+        left.keys.append(&mut right.keys);
+        left.values.append(&mut right.values);
+        left.high_key = right.high_key;
+        left.right = right.right;
+        right.deleted = true;
+        right.outlink = left_id;
+        left.write(&mut left_guard);
+        right.write(&mut right_guard);
+        parent.keys.remove(i);
+        parent.values.remove(i + 1);
+        Ok(true)
+    }
+
+    /// Split an overflowing node in place and link in its new right sibling. Returns the separator and the sibling's page id.
+    /// The sibling's page is allocated first, so if that fails the node is left as it was on its page
+    fn split(
+        &self,
+        guard: &mut WritePageGuard,
+        node: &mut Node,
+    ) -> Result<(Key, PageId), StorageError> {
+        let mut sibling_guard = self.pool.new_page_write()?;
+        let (separator, sibling) = node.split();
+        sibling.write(&mut sibling_guard);
+        node.right = sibling_guard.page_id();
+        node.write(guard);
+        Ok((separator, sibling_guard.page_id()))
+    }
+
+    /// Called when a split node had no parent on the stack. If it's still the root, install a new root above it. Otherwise the
+    /// tree has grown since the node was reached, so return the node one level up whose range contains `separator`.
+    fn grow_root(
+        &self,
+        left_id: PageId,
+        separator: Key,
+        right_id: PageId,
+        level: u8,
+    ) -> Result<Option<PageId>, StorageError> {
+        let mut meta = self.pool.fetch_page_write(self.meta_page_id)?;
+        if read_page_id(&meta, ROOT_OFFSET) != left_id {
+            drop(meta);
+            return self.find_at_level(&separator, level + 1).map(Some);
+        }
+        self.new_root(&mut meta, left_id, separator, right_id, level)?;
+        Ok(None)
+    }
+
+    /// Install a new root over `left_id` and `right_id`, the halves of the old root, on the latched meta page
+    fn new_root(
+        &self,
+        meta: &mut WritePageGuard,
+        left_id: PageId,
+        separator: Key,
+        right_id: PageId,
+        level: u8,
+    ) -> Result<(), StorageError> {
+        let mut root_guard = self.pool.new_page_write()?;
+        Node {
+            leaf: false,
+            deleted: false,
+            level: level + 1,
+            right: INVALID_PAGE_ID,
+            outlink: INVALID_PAGE_ID,
+            high_key: None,
+            keys: vec![separator],
+            values: vec![left_id, right_id],
+        }
+        .write(&mut root_guard);
+        write_page_id(meta, ROOT_OFFSET, root_guard.page_id());
+        Ok(())
+    }
+
+    /// Install a root above the root's level if the root has split and no new root went in after, because the thread that
+    /// split it is yet to get to it or failed to. The new root takes every node of the old root's level as a child, up to a
+    /// node's worth; nodes past those are reached by moving right from the last one. The splitters then find their
+    /// separators already posted
+    fn install_root(&self) -> Result<(), StorageError> {
+        let mut meta = self.pool.fetch_page_write(self.meta_page_id)?;
+        let root_id = read_page_id(&meta, ROOT_OFFSET);
+        let root = Node::read(&*self.pool.fetch_page_read(root_id)?);
+        if root.right == INVALID_PAGE_ID {
+            return Ok(());
+        }
+        let mut keys = Vec::new();
+        let mut values = vec![root_id];
+        let mut node = root;
+        while node.right != INVALID_PAGE_ID && keys.len() < self.max_entries {
+            keys.push(node.high_key.unwrap());
+            values.push(node.right);
+            node = Node::read(&*self.pool.fetch_page_read(node.right)?);
+        }
+        let mut root_guard = self.pool.new_page_write()?;
+        Node {
+            leaf: false,
+            deleted: false,
+            level: node.level + 1,
+            right: INVALID_PAGE_ID,
+            outlink: INVALID_PAGE_ID,
+            high_key: None,
+            keys,
+            values,
+        }
+        .write(&mut root_guard);
+        write_page_id(&mut meta, ROOT_OFFSET, root_guard.page_id());
+        Ok(())
+    }
+
+    fn root(&self) -> Result<PageId, StorageError> {
+        let meta = self.pool.fetch_page_read(self.meta_page_id)?;
+        Ok(read_page_id(&meta, ROOT_OFFSET))
+    }
+
+    /// Descend to the leaf whose range should contain `key`, pushing the internal nodes passed through onto `stack`. The leaf
+    /// isn't latched on return, so callers move right from it again once they hold its latch.
+    fn find_leaf(&self, key: &Key, stack: &mut Vec<PageId>) -> Result<PageId, StorageError> {
+        let mut page_id = self.root()?;
+        loop {
+            let guard = self.pool.fetch_page_read(page_id)?;
+            let node = Node::read(&guard);
+            if let Some(next) = next_hop(&node, key) {
+                page_id = next;
+                continue;
+            }
+            if node.leaf {
+                return Ok(page_id);
+            }
+            stack.push(page_id);
+            page_id = node.child(key);
+        }
+    }
+
+    /// The first leaf of the tree. Merges always remove the right node of a pair, so it's never deleted
+    fn leftmost_leaf(&self) -> Result<PageId, StorageError> {
+        let mut page_id = self.root()?;
+        loop {
+            let guard = self.pool.fetch_page_read(page_id)?;
+            let node = Node::read(&guard);
+            if node.leaf {
+                return Ok(page_id);
+            }
+            page_id = node.values[0];
+        }
+    }
+
+    /// The node at `level` whose range contains `key`
+    fn find_at_level(&self, key: &Key, level: u8) -> Result<PageId, StorageError> {
+        let mut page_id = self.root()?;
+        loop {
+            let guard = self.pool.fetch_page_read(page_id)?;
+            let node = Node::read(&guard);
+            if node.level < level {
+                // the root is below `level`: it has split and no new root has been installed above it yet
+                drop(guard);
+                self.install_root()?;
+                page_id = self.root()?;
+            } else if let Some(next) = next_hop(&node, key) {
+                page_id = next;
+            } else if node.level == level {
+                return Ok(page_id);
+            } else {
+                page_id = node.child(key);
+            }
+        }
+    }
+
+    /// Write-latch the node whose range contains `key`, starting at `page_id` and moving right (or out of deleted nodes) as
+    /// needed
+    fn latch(
+        &self,
+        mut page_id: PageId,
+        key: &Key,
+    ) -> Result<(WritePageGuard, Node), StorageError> {
+        loop {
+            let guard = self.pool.fetch_page_write(page_id)?;
+            let node = Node::read(&guard);
+            match next_hop(&node, key) {
+                Some(next) => page_id = next,
+                None => return Ok((guard, node)),
+            }
+        }
+    }
+}
+
+/// Writes the nodes of one level of a bulk-loaded tree left to right. A node is kept latched until the next one is pushed,
+/// so it can be linked to it and given its high key
+struct LevelWriter {
+    pool: BufferPool,
+    pending: Option<(WritePageGuard, Node, Key)>,
+    // (high key, page id) of every node pushed so far. The rightmost node's high key isn't stored on its page
+    nodes: Vec<(Key, PageId)>,
+    // largest key pushed so far
+    last_key: Option<Key>,
+}
+
+impl LevelWriter {
+    fn new(pool: BufferPool) -> Self {
+        LevelWriter {
+            pool,
+            pending: None,
+            nodes: Vec::new(),
+            last_key: None,
+        }
+    }
+
+    fn push(&mut self, high_key: Key, node: Node) -> Option<()> {
+        let guard = self.pool.new_page_write().ok()?;
+        if let Some((mut prev, mut prev_node, prev_high_key)) = self.pending.take() {
+            prev_node.right = guard.page_id();
+            prev_node.high_key = Some(prev_high_key);
+            prev_node.write(&mut prev);
+        }
+        self.nodes.push((high_key, guard.page_id()));
+        self.last_key = Some(high_key);
+        self.pending = Some((guard, node, high_key));
+        Some(())
+    }
+
+    /// Write the rightmost node and return every node of the level
+    fn finish(mut self) -> Option<Vec<(Key, PageId)>> {
+        let (mut guard, node, _) = self.pending.take()?;
+        node.write(&mut guard);
+        Some(self.nodes)
+    }
+}
+
+/// Where a traversal looking for `key` has to go instead of `node`, if `node` doesn't cover it
+fn next_hop(node: &Node, key: &Key) -> Option<PageId> {
+    if node.deleted {
+        Some(node.outlink)
+    } else if node.beyond(key) {
+        Some(node.right)
+    } else {
+        None
+    }
+}
+
+/// Ordered iterator over a key range. It copies one leaf at a time under the leaf's read latch and holds no latch between
+/// calls, so it never blocks writers. Crossing to the next leaf follows the right link read under the latch; entries at or below
+/// the last key returned are skipped, so a leaf that split (moving keys right) or merged (moving keys left) in the meantime
+/// doesn't produce duplicates or lose keys that were present for the whole scan.
+pub struct IndexIterator {
+    tree: BLinkTree,
+    buffer: VecDeque<(Key, PageId)>,
+    next_page_id: PageId,
+    // everything at or below this bound has been returned already (or was never in range)
+    lower: Bound<Key>,
+    upper: Bound<Key>,
+    cancel: Option<CancellationToken>,
+    interrupted: Option<Interrupted>,
+    error: Option<StorageError>,
+}
+
+impl IndexIterator {
+    /// Stop the scan early once the statement owning `cancel` is cancelled or runs past its deadline. The token is checked
+    /// before each leaf is loaded; after the iterator ends, `interrupted` tells whether it finished or was cut short.
+    pub fn interruptible(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Why the scan stopped early, if it did
+    pub fn interrupted(&self) -> Option<Interrupted> {
+        self.interrupted
+    }
+
+    /// The error that ended the scan early, if a page couldn't be fetched
+    pub fn error(&self) -> Option<&StorageError> {
+        self.error.as_ref()
+    }
+
+    /// Load the next leaf with entries in range. Returns false at the end of the scan
+    fn load(&mut self) -> bool {
+        while self.next_page_id != INVALID_PAGE_ID {
+            if let Some(Err(interrupted)) = self.cancel.as_ref().map(CancellationToken::check) {
+                self.interrupted = Some(interrupted);
+                self.next_page_id = INVALID_PAGE_ID;
+                return false;
+            }
+            let guard = match self.tree.pool.fetch_page_read(self.next_page_id) {
+                Ok(guard) => guard,
+                Err(err) => {
+                    self.error = Some(err);
+                    self.next_page_id = INVALID_PAGE_ID;
+                    return false;
+                }
+            };
+            let node = Node::read(&guard);
+            drop(guard);
+            if node.deleted {
+                self.next_page_id = node.outlink;
+                continue;
+            }
+            self.next_page_id = node.right;
+            let above_lower = |key: &Key| match &self.lower {
+                Bound::Included(lower) => key >= lower,
+                Bound::Excluded(lower) => key > lower,
+                Bound::Unbounded => true,
+            };
+            self.buffer.extend(
+                node.keys
+                    .into_iter()
+                    .zip(node.values)
+                    .filter(|(key, _)| above_lower(key)),
+            );
+            if !self.buffer.is_empty() {
+                return true;
+            }
+            // nothing left in range past this leaf
+            if node
+                .high_key
+                .is_some_and(|high_key| !self.below_upper(&high_key))
+            {
+                self.next_page_id = INVALID_PAGE_ID;
+            }
+        }
+        false
+    }
+
+    fn below_upper(&self, key: &Key) -> bool {
+        match &self.upper {
+            Bound::Included(upper) => key <= upper,
+            Bound::Excluded(upper) => key < upper,
+            Bound::Unbounded => true,
+        }
+    }
+}
+
+impl Iterator for IndexIterator {
+    type Item = (Key, PageId);
+
+    fn next(&mut self) -> Option<(Key, PageId)> {
+        if self.buffer.is_empty() && !self.load() {
+            return None;
+        }
+        let (key, value) = self.buffer.pop_front()?;
+        if !self.below_upper(&key) {
+            self.buffer.clear();
+            self.next_page_id = INVALID_PAGE_ID;
+            return None;
+        }
+        self.lower = Bound::Excluded(key);
+        Some((key, value))
+    }
+}
+
+/// Handle to a background compression thread. Dropping it stops the thread
+pub struct Compressor {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for Compressor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rayon::ThreadPoolBuilder;
+
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::config::StorageConfig;
+    use crate::txn::session::Session;
+
+    fn setup(name: &str) -> (BufferPool, String) {
+        let dir = cwd() + "/tests/blink_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/{}.bin", dir, name);
+        (BufferPool::create(&path).unwrap(), path)
+    }
+
+    fn cleanup(path: &str) {
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_node_layout() {
+        let node = Node {
+            leaf: false,
+            deleted: false,
+            level: 2,
+            right: 7,
+            outlink: INVALID_PAGE_ID,
+            high_key: Some(key(100)),
+            keys: vec![key(10), key(20)],
+            values: vec![1, 2, 3],
+        };
+        let mut page = [0u8; PAGE_SIZE];
+        node.write(&mut page);
+        assert!(Node::read(&page) == node);
+        assert!(node.child(&key(10)) == 1);
+        assert!(node.child(&key(11)) == 2);
+        assert!(node.child(&key(99)) == 3);
+        assert!(node.beyond(&key(101)));
+    }
+
+    #[test]
+    fn test_insert_search_delete() {
+        let (pool, path) = setup("test_insert_search_delete");
+        let tree = BLinkTree::create_with_fanout(pool, 4).unwrap();
+        for n in (0..500u64).rev() {
+            assert!(tree.insert(&key(n * 2), n as PageId).unwrap());
+        }
+        assert!(!tree.insert(&key(10), 0).unwrap());
+        for n in 0..500u64 {
+            assert!(tree.search(&key(n * 2)).unwrap() == Some(n as PageId));
+            assert!(tree.search(&key(n * 2 + 1)).unwrap().is_none());
+        }
+
+        for n in 0..450u64 {
+            assert!(tree.delete(&key(n * 2)).unwrap());
+        }
+        assert!(!tree.delete(&key(0)).unwrap());
+        assert!(tree.compress().unwrap() > 0);
+        for n in 0..500u64 {
+            assert!(tree.search(&key(n * 2)).unwrap() == (n >= 450).then_some(n as PageId));
+        }
+        // the tree keeps working after merges
+        for n in 0..450u64 {
+            assert!(tree.insert(&key(n * 2), n as PageId).unwrap());
+        }
+        for n in 0..500u64 {
+            assert!(tree.search(&key(n * 2)).unwrap() == Some(n as PageId));
+        }
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_pool_exhausted() {
+        let dir = cwd() + "/tests/blink_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir + "/test_pool_exhausted.bin";
+        let pool =
+            BufferPool::create_with_config(&StorageConfig::for_path(&path).with_pool_size(8))
+                .unwrap();
+        for coupling in [Coupling::Link, Coupling::Crabbing, Coupling::Optimistic] {
+            let tree = BLinkTree::create_with_fanout(pool.clone(), 4)
+                .unwrap()
+                .with_coupling(coupling);
+            for n in 0..4u64 {
+                assert!(tree.insert(&key(n), n as PageId).unwrap());
+            }
+            // with all but one frame pinned elsewhere, the root can't split
+            let pinned: Vec<_> = (0..7).map(|_| pool.new_page_write().unwrap()).collect();
+            assert!(matches!(
+                tree.insert(&key(4), 4),
+                Err(StorageError::PoolExhausted)
+            ));
+            drop(pinned);
+            assert!(tree.search(&key(4)).unwrap().is_none());
+            for n in 4..100u64 {
+                assert!(tree.insert(&key(n), n as PageId).unwrap());
+            }
+            assert!(tree.iter().map(|(_, value)| value).eq(0..100));
+        }
+        cleanup(&path);
+        let _ = std::fs::remove_file(crate::storage::buffer::freemap::map_path(&path));
+    }
+
+    #[test]
+    fn test_range_scan() {
+        let (pool, path) = setup("test_range_scan");
+        let tree = BLinkTree::create_with_fanout(pool, 4).unwrap();
+        for n in 0..300u64 {
+            assert!(tree.insert(&key(n * 3), n as PageId).unwrap());
+        }
+        let keys = |iter: IndexIterator| iter.map(|(_, value)| value).collect::<Vec<PageId>>();
+        assert!(keys(tree.iter()) == (0..300).collect::<Vec<PageId>>());
+        assert!(keys(tree.scan(key(30)..key(60))) == (10..20).collect::<Vec<PageId>>());
+        assert!(keys(tree.scan(key(31)..=key(60))) == (11..21).collect::<Vec<PageId>>());
+        assert!(keys(tree.scan((Bound::Excluded(key(30)), Bound::Unbounded))).len() == 289);
+        assert!(keys(tree.scan(key(1000)..)).is_empty());
+
+        // deleted and merged leaves are skipped over
+        for n in 50..250u64 {
+            assert!(tree.delete(&key(n * 3)).unwrap());
+        }
+        tree.compress().unwrap();
+        let expected: Vec<PageId> = (40..50).chain(250..260).collect();
+        assert!(keys(tree.scan(key(120)..key(780))) == expected);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_interrupted_scan_and_build() {
+        let (pool, path) = setup("test_interrupted_scan_and_build");
+        let tree = BLinkTree::create_with_fanout(pool, 4).unwrap();
+        let mut session = Session::new();
+        session.begin_statement();
+        let entries = (0..100u64).map(|n| (key(n), n as PageId));
+        assert!(tree.build(entries, &session.cancellation_token()).unwrap() == 100);
+
+        let mut scan = tree.iter().interruptible(session.cancellation_token());
+        assert!(scan.by_ref().take(10).count() == 10);
+        session.cancellation_token().cancel();
+        // whatever was already buffered from the current leaf is still returned
+        assert!(scan.by_ref().count() < 4);
+        assert!(scan.interrupted() == Some(Interrupted::Cancelled));
+
+        // a zero timeout means the statement's deadline has passed by the time it starts
+        session.set_statement_timeout(Some(Duration::ZERO));
+        session.begin_statement();
+        let entries = (100..200u64).map(|n| (key(n), n as PageId));
+        let result = tree.build(entries, &session.cancellation_token());
+        assert!(matches!(
+            result,
+            Err(BuildError::Interrupted(Interrupted::StatementTimeout))
+        ));
+        assert!(tree.search(&key(100)).unwrap().is_none());
+
+        session.set_statement_timeout(None);
+        session.begin_statement();
+        let mut scan = tree.iter().interruptible(session.cancellation_token());
+        assert!(scan.by_ref().count() == 100);
+        assert!(scan.interrupted().is_none());
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let (pool, path) = setup("test_bulk_load");
+        let tree = BLinkTree::bulk_load_with(
+            pool.clone(),
+            8,
+            0.75,
+            (0..10_000u64).map(|n| (key(n * 2), n as PageId)),
+        )
+        .unwrap();
+        // leaves hold six keys each, apart from the last one
+        let mut leaves = Vec::new();
+        let mut page_id = tree.leftmost_leaf().unwrap();
+        while page_id != INVALID_PAGE_ID {
+            let node = Node::read(&pool.fetch_page_read(page_id).unwrap());
+            leaves.push(node.keys.len());
+            page_id = node.right;
+        }
+        assert!(leaves.len() == 1667);
+        assert!(leaves[..1666].iter().all(|len| *len == 6) && leaves[1666] == 4);
+
+        let values: Vec<PageId> = tree.iter().map(|(_, value)| value).collect();
+        assert!(values == (0..10_000).collect::<Vec<PageId>>());
+        for n in 0..10_000u64 {
+            assert!(tree.search(&key(n * 2)).unwrap() == Some(n as PageId));
+            assert!(tree.search(&key(n * 2 + 1)).unwrap().is_none());
+        }
+        assert!(tree.scan(key(100)..key(120)).count() == 10);
+        // the loaded tree takes inserts and deletes like any other
+        for n in 0..10_000u64 {
+            assert!(tree.insert(&key(n * 2 + 1), 0).unwrap());
+        }
+        for n in 0..5_000u64 {
+            assert!(tree.delete(&key(n * 2)).unwrap());
+        }
+        tree.compress().unwrap();
+        assert!(tree.iter().count() == 15_000);
+
+        // no entries gives an empty tree
+        let empty = BLinkTree::bulk_load(pool, std::iter::empty()).unwrap();
+        assert!(empty.iter().count() == 0);
+        assert!(empty.insert(&key(1), 1).unwrap() && empty.search(&key(1)).unwrap() == Some(1));
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let (pool, path) = setup("test_bloom_filter");
+        let tree = BLinkTree::bulk_load_with(
+            pool.clone(),
+            16,
+            1.0,
+            (0..2000u64).map(|n| (key(n * 2), n as PageId)),
+        )
+        .unwrap();
+        assert!(tree.has_filter().unwrap());
+        assert!((0..2000u64).all(|n| tree.may_contain(&key(n * 2))));
+        let maybe = |tree: &BLinkTree, keys: std::ops::Range<u64>| {
+            keys.filter(|n| tree.may_contain(&key(n * 2 + 1))).count()
+        };
+        assert!(maybe(&tree, 0..2000) < 40);
+
+        // inserts racing with rebuilds are never ruled out
+        std::thread::scope(|s| {
+            for t in 0..4u64 {
+                let tree = tree.clone();
+                s.spawn(move || {
+                    for n in (0..500u64).map(|n| n * 4 + t) {
+                        assert!(tree.insert(&key(n * 2 + 1), n as PageId).unwrap());
+                    }
+                });
+            }
+            for _ in 0..5 {
+                tree.rebuild_filter().unwrap();
+            }
+        });
+        assert!((0..4000u64).all(|n| tree.search(&key(n)).unwrap().is_some()));
+
+        // deleted keys linger in the filter until compression rebuilds it
+        for n in (0..4000u64).filter(|n| n % 8 != 0) {
+            assert!(tree.delete(&key(n)).unwrap());
+        }
+        assert!((0..4000u64).all(|n| tree.may_contain(&key(n))));
+        assert!(tree.compress().unwrap() > 0);
+        let lingering = (0..4000u64)
+            .filter(|n| n % 8 != 0 && tree.may_contain(&key(*n)))
+            .count();
+        assert!(lingering < 70);
+        assert!((0..500u64).all(|n| tree.search(&key(n * 8)).unwrap() == Some(n as PageId * 4)));
+
+        // a tree built by inserts has no filter until one is built
+        let tree = BLinkTree::create(pool).unwrap();
+        tree.insert(&key(1), 1).unwrap();
+        assert!(!tree.has_filter().unwrap() && tree.may_contain(&key(2)));
+        assert!(tree.rebuild_filter().unwrap());
+        assert!(tree.may_contain(&key(1)) && tree.search(&key(1)).unwrap() == Some(1));
+        cleanup(&path);
+        let _ = std::fs::remove_file(crate::storage::buffer::freemap::map_path(&path));
+    }
+
+    #[test]
+    fn test_coupling_protocols() {
+        let (pool, path) = setup("test_coupling_protocols");
+        let couplings = [Coupling::Link, Coupling::Crabbing, Coupling::Optimistic];
+        for coupling in couplings {
+            let tree = BLinkTree::create_with_fanout(pool.clone(), 4)
+                .unwrap()
+                .with_coupling(coupling);
+            for n in (0..300u64).rev() {
+                assert!(tree.insert(&key(n * 2), n as PageId).unwrap());
+            }
+            assert!(!tree.insert(&key(10), 0).unwrap());
+            for n in 0..300u64 {
+                assert!(tree.search(&key(n * 2)).unwrap() == Some(n as PageId));
+                assert!(tree.search(&key(n * 2 + 1)).unwrap().is_none());
+            }
+            for n in 0..100u64 {
+                assert!(tree.delete(&key(n * 2)).unwrap());
+            }
+            assert!(!tree.delete(&key(0)).unwrap());
+            assert!(tree.iter().map(|(_, value)| value).eq(100..300));
+        }
+
+        // handles using every protocol work on one tree at once, with compression running underneath
+        let tree = BLinkTree::create_with_fanout(pool, 8).unwrap();
+        let compressor = tree.spawn_compressor(Duration::from_millis(1));
+        let threads = ThreadPoolBuilder::new().num_threads(9).build().unwrap();
+        threads.scope(|s| {
+            for t in 0..9u64 {
+                let tree = tree.clone().with_coupling(couplings[t as usize % 3]);
+                s.spawn(move |_| {
+                    for n in 0..200u64 {
+                        let k = key(n * 9 + t);
+                        assert!(tree.insert(&k, (n * 9 + t) as PageId).unwrap());
+                        assert!(tree.search(&k).unwrap() == Some((n * 9 + t) as PageId));
+                    }
+                    for n in 0..100u64 {
+                        assert!(tree.delete(&key(n * 9 + t)).unwrap());
+                    }
+                });
+            }
+        });
+        drop(compressor);
+        for coupling in couplings {
+            let tree = tree.clone().with_coupling(coupling);
+            for n in 0..1800u64 {
+                assert!(tree.search(&key(n)).unwrap() == (n >= 900).then_some(n as PageId));
+            }
+        }
+        assert!(tree.iter().count() == 900);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_scan_during_concurrent_inserts() {
+        let (pool, path) = setup("test_scan_during_concurrent_inserts");
+        let tree = BLinkTree::create_with_fanout(pool, 4).unwrap();
+        // even keys are present for the whole scan, odd keys are inserted while it runs
+        for n in 0..500u64 {
+            assert!(tree.insert(&key(n * 2), n as PageId).unwrap());
+        }
+        let writer = {
+            let tree = tree.clone();
+            std::thread::spawn(move || {
+                for n in 0..500u64 {
+                    assert!(tree.insert(&key(n * 2 + 1), 0).unwrap());
+                }
+            })
+        };
+        let scanned: Vec<Key> = tree.iter().map(|(key, _)| key).collect();
+        writer.join().unwrap();
+        assert!(scanned.windows(2).all(|w| w[0] < w[1]));
+        let even: Vec<Key> = scanned
+            .into_iter()
+            .filter(|k| k[KEY_SIZE - 1] % 2 == 0)
+            .collect();
+        assert!(even == (0..500u64).map(|n| key(n * 2)).collect::<Vec<Key>>());
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_concurrent_insert_delete_with_compression() {
+        let (pool, path) = setup("test_concurrent_insert_delete_with_compression");
+        let tree = BLinkTree::create_with_fanout(pool, 8).unwrap();
+        let compressor = tree.spawn_compressor(Duration::from_millis(1));
+        let threads = ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        threads.scope(|s| {
+            for t in 0..8u64 {
+                let tree = tree.clone();
+                s.spawn(move |_| {
+                    for n in 0..200u64 {
+                        let k = key(n * 8 + t);
+                        assert!(tree.insert(&k, (n * 8 + t) as PageId).unwrap());
+                        assert!(tree.search(&k).unwrap() == Some((n * 8 + t) as PageId));
+                    }
+                    for n in 0..100u64 {
+                        assert!(tree.delete(&key(n * 8 + t)).unwrap());
+                    }
+                });
+            }
+        });
+        drop(compressor);
+        for n in 0..1600u64 {
+            let expected = (n >= 800).then_some(n as PageId);
+            assert!(tree.search(&key(n)).unwrap() == expected);
+        }
+        cleanup(&path);
+    }
+}
@@ -0,0 +1,317 @@
+/// This file implements Bloom filters stored in pages, so a lookup of a key that isn't there can be answered without reading
+/// the structure the keys live in. A filter says a key is either maybe present or definitely absent: it never forgets a key
+/// that was added, and says maybe for one that wasn't with a probability chosen when it's built. Keys can't be taken out
+/// again, so a filter over keys that get deleted slowly fills up with stale bits and has to be rebuilt from scratch.
+///
+/// The filter is blocked: a key hashes to one block, a page's worth of bits, and all of its bits are set within that block.
+/// Checking or adding a key touches a single block page, for a slightly higher false positive rate than spreading the bits
+/// over the whole filter. A stored filter is a directory page listing its blocks, and the blocks themselves:
+///
+/// ```text
+/// directory: | page header (24) | hashes (4) | block count (4) | block page ids (8 each) ... |
+/// block:     | page header (24) | bits ... |
+/// ```
+///
+/// `BloomFilter` builds a filter in memory and writes it out. `may_contain` and `insert` work on a stored filter through the
+/// buffer pool, latching only the block they need, so any number of them can run at once.
+use crate::shared::{PageId, PAGE_SIZE};
+use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+use crate::storage::buffer::page::{self, Page, PageType, PAGE_HEADER_SIZE};
+use crate::storage::error::StorageError;
+
+const HASHES_OFFSET: usize = PAGE_HEADER_SIZE;
+const BLOCK_COUNT_OFFSET: usize = HASHES_OFFSET + 4;
+const BLOCKS_OFFSET: usize = BLOCK_COUNT_OFFSET + 4;
+
+/// Bytes of bits in a block page
+pub const BLOCK_SIZE: usize = PAGE_SIZE - PAGE_HEADER_SIZE;
+pub const BLOCK_BITS: usize = BLOCK_SIZE * 8;
+/// Most blocks a directory page can list. A filter can't grow past this, so beyond about 1.7 million keys at 1% it gets
+/// less selective than asked for
+pub const MAX_BLOCKS: usize = (PAGE_SIZE - BLOCKS_OFFSET) / 8;
+pub const MAX_HASHES: u32 = 16;
+pub const DEFAULT_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/// Spread a hash's bits over all 64 (the SplitMix64 finalizer)
+fn mix(mut x: u64) -> u64 {
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// The block `key` belongs to, and the positions of its bits within that block. Built on CRC32C, which is stable across
+/// processes, since the bits are stored. CRCs of similar keys are linearly related, so everything is drawn from the mixed
+/// CRC rather than from CRCs with different seeds
+fn probe(key: &[u8], hashes: u32, blocks: usize) -> (usize, impl Iterator<Item = usize>) {
+    let h = mix(crc32c::crc32c(key) as u64);
+    let block = (h >> 32) as usize % blocks;
+    let a = h as u32 as usize;
+    // odd, so the positions don't repeat before covering the block
+    let b = mix(h) as u32 as usize | 1;
+    let positions =
+        (0..hashes as usize).map(move |i| a.wrapping_add(i.wrapping_mul(b)) % BLOCK_BITS);
+    (block, positions)
+}
+
+fn set_bits(bits: &mut [u8], positions: impl Iterator<Item = usize>) {
+    for position in positions {
+        bits[position / 8] |= 1 << (position % 8);
+    }
+}
+
+fn all_set(bits: &[u8], mut positions: impl Iterator<Item = usize>) -> bool {
+    positions.all(|position| bits[position / 8] & (1 << (position % 8)) != 0)
+}
+
+/// A filter's directory: how many bits each key sets, and its block pages in order. Returns `None` if `page` isn't a
+/// directory page. For tools that read a filter page by page, and for the functions below
+pub fn directory(page: &Page) -> Option<(u32, Vec<PageId>)> {
+    if page::page_type(page) != Some(PageType::BloomFilter) {
+        return None;
+    }
+    let hashes = u32::from_le_bytes(page[HASHES_OFFSET..HASHES_OFFSET + 4].try_into().unwrap());
+    let count = u32::from_le_bytes(
+        page[BLOCK_COUNT_OFFSET..BLOCK_COUNT_OFFSET + 4]
+            .try_into()
+            .unwrap(),
+    ) as usize;
+    if hashes == 0 || count == 0 || count > MAX_BLOCKS {
+        return None;
+    }
+    let blocks = (0..count)
+        .map(|i| {
+            let offset = BLOCKS_OFFSET + i * 8;
+            PageId::from_le_bytes(page[offset..offset + 8].try_into().unwrap())
+        })
+        .collect();
+    Some((hashes, blocks))
+}
+
+/// The block page of the filter whose directory is `page_id` that `key` belongs to, and the key's bits within it
+fn locate(
+    pool: &BufferPool,
+    page_id: PageId,
+    key: &[u8],
+) -> Result<(PageId, impl Iterator<Item = usize>), StorageError> {
+    let guard = pool.fetch_page_read(page_id)?;
+    let (hashes, blocks) = directory(guard.data()).ok_or(StorageError::Corruption(page_id))?;
+    let (block, positions) = probe(key, hashes, blocks.len());
+    Ok((blocks[block], positions))
+}
+
+/// Whether `key` may have been added to the filter stored at `page_id`
+pub fn may_contain(pool: &BufferPool, page_id: PageId, key: &[u8]) -> Result<bool, StorageError> {
+    let (block_id, positions) = locate(pool, page_id, key)?;
+    let guard = pool.fetch_page_read(block_id)?;
+    Ok(all_set(&guard.data()[PAGE_HEADER_SIZE..], positions))
+}
+
+/// Add `key` to the filter stored at `page_id`
+pub fn insert(pool: &BufferPool, page_id: PageId, key: &[u8]) -> Result<(), StorageError> {
+    let (block_id, positions) = locate(pool, page_id, key)?;
+    let mut guard = pool.fetch_page_write(block_id)?;
+    set_bits(&mut guard.data_mut()[PAGE_HEADER_SIZE..], positions);
+    Ok(())
+}
+
+/// Free the pages of the filter stored at `page_id`, and return how many were freed. A directory that can't be read is left
+/// alone
+pub fn free(pool: &BufferPool, page_id: PageId) -> usize {
+    let blocks = match pool.fetch_page_read(page_id) {
+        Ok(guard) => match directory(guard.data()) {
+            Some((_, blocks)) => blocks,
+            None => return 0,
+        },
+        Err(_) => return 0,
+    };
+    blocks
+        .into_iter()
+        .chain(std::iter::once(page_id))
+        .filter(|page_id| pool.delete_page(*page_id).is_ok())
+        .count()
+}
+
+/// A Bloom filter built in memory, to be written to pages or merged into a stored filter of the same shape
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    hashes: u32,
+    blocks: Vec<Vec<u8>>,
+}
+
+impl BloomFilter {
+    /// An empty filter sized so that, once `expected_keys` keys are in, a key that isn't says maybe with probability about
+    /// `false_positive_rate` (in `(0, 1)`)
+    pub fn new(expected_keys: usize, false_positive_rate: f64) -> Self {
+        assert!(false_positive_rate > 0.0 && false_positive_rate < 1.0);
+        let keys = expected_keys.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-keys * false_positive_rate.ln() / (ln2 * ln2)).ceil();
+        let hashes = ((bits / keys) * ln2).round().clamp(1.0, MAX_HASHES as f64) as u32;
+        let blocks = (bits as usize).div_ceil(BLOCK_BITS).clamp(1, MAX_BLOCKS);
+        BloomFilter::with_shape(hashes, blocks)
+    }
+
+    fn with_shape(hashes: u32, blocks: usize) -> Self {
+        BloomFilter {
+            hashes,
+            blocks: vec![vec![0u8; BLOCK_SIZE]; blocks],
+        }
+    }
+
+    pub fn hashes(&self) -> u32 {
+        self.hashes
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        let (block, positions) = probe(key, self.hashes, self.blocks.len());
+        set_bits(&mut self.blocks[block], positions);
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let (block, positions) = probe(key, self.hashes, self.blocks.len());
+        all_set(&self.blocks[block], positions)
+    }
+
+    /// Write the filter to new pages and return its directory page. If a page can't be allocated, the pages written so far
+    /// are freed
+    pub fn write(&self, pool: &BufferPool) -> Result<PageId, StorageError> {
+        let mut block_ids = Vec::with_capacity(self.blocks.len());
+        for bits in &self.blocks {
+            let mut guard = match pool.new_page_write() {
+                Ok(guard) => guard,
+                Err(err) => {
+                    for page_id in block_ids {
+                        let _ = pool.delete_page(page_id);
+                    }
+                    return Err(err);
+                }
+            };
+            let page = guard.data_mut();
+            page::set_page_type(page, PageType::BloomBlock);
+            page[PAGE_HEADER_SIZE..].copy_from_slice(bits);
+            block_ids.push(guard.page_id());
+        }
+        let mut guard = match pool.new_page_write() {
+            Ok(guard) => guard,
+            Err(err) => {
+                for page_id in block_ids {
+                    let _ = pool.delete_page(page_id);
+                }
+                return Err(err);
+            }
+        };
+        let page = guard.data_mut();
+        page[PAGE_HEADER_SIZE..].fill(0);
+        page::set_page_type(page, PageType::BloomFilter);
+        page[HASHES_OFFSET..HASHES_OFFSET + 4].copy_from_slice(&self.hashes.to_le_bytes());
+        page[BLOCK_COUNT_OFFSET..BLOCK_COUNT_OFFSET + 4]
+            .copy_from_slice(&(block_ids.len() as u32).to_le_bytes());
+        for (i, block_id) in block_ids.iter().enumerate() {
+            let offset = BLOCKS_OFFSET + i * 8;
+            page[offset..offset + 8].copy_from_slice(&block_id.to_le_bytes());
+        }
+        Ok(guard.page_id())
+    }
+
+    /// Read back the filter stored at `page_id`
+    pub fn read(pool: &BufferPool, page_id: PageId) -> Result<BloomFilter, StorageError> {
+        let (hashes, block_ids) = {
+            let guard = pool.fetch_page_read(page_id)?;
+            directory(guard.data()).ok_or(StorageError::Corruption(page_id))?
+        };
+        let mut filter = BloomFilter::with_shape(hashes, block_ids.len());
+        for (bits, block_id) in filter.blocks.iter_mut().zip(block_ids) {
+            let guard = pool.fetch_page_read(block_id)?;
+            bits.copy_from_slice(&guard.data()[PAGE_HEADER_SIZE..]);
+        }
+        Ok(filter)
+    }
+
+    /// Add every key of this filter to the one stored at `page_id`, which must have the same shape. Each block is merged
+    /// under its own latch, so keys added to the stored filter meanwhile are kept
+    pub fn merge_into(&self, pool: &BufferPool, page_id: PageId) -> Result<(), StorageError> {
+        let block_ids = {
+            let guard = pool.fetch_page_read(page_id)?;
+            match directory(guard.data()) {
+                Some((hashes, block_ids))
+                    if hashes == self.hashes && block_ids.len() == self.blocks.len() =>
+                {
+                    block_ids
+                }
+                _ => return Err(StorageError::Corruption(page_id)),
+            }
+        };
+        for (bits, block_id) in self.blocks.iter().zip(block_ids) {
+            let mut guard = pool.fetch_page_write(block_id)?;
+            let stored = &mut guard.data_mut()[PAGE_HEADER_SIZE..];
+            for (stored, bits) in stored.iter_mut().zip(bits) {
+                *stored |= bits;
+            }
+        }
+        Ok(())
+    }
+
+    /// An empty filter with the same shape as this one
+    pub fn cleared(&self) -> BloomFilter {
+        BloomFilter::with_shape(self.hashes, self.blocks.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::buffer::freemap;
+
+    #[test]
+    fn test_bloom_filter() {
+        let keys = 20_000u64;
+        let mut filter = BloomFilter::new(keys as usize, DEFAULT_FALSE_POSITIVE_RATE);
+        assert!(filter.blocks() == 6 && filter.hashes() == 7);
+        for n in 0..keys {
+            filter.insert(&n.to_be_bytes());
+        }
+        // never a false negative, and false positives near the rate asked for
+        assert!((0..keys).all(|n| filter.may_contain(&n.to_be_bytes())));
+        let false_positives = (keys..keys * 2)
+            .filter(|n| filter.may_contain(&n.to_be_bytes()))
+            .count();
+        assert!(false_positives < keys as usize * 2 / 100);
+
+        let dir = cwd() + "/tests/bloom_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir + "/test_bloom_filter.bin";
+        let pool = BufferPool::create(&path).unwrap();
+        let page_id = filter.write(&pool).unwrap();
+        assert!(BloomFilter::read(&pool, page_id).unwrap() == filter);
+        assert!(may_contain(&pool, page_id, &7u64.to_be_bytes()).unwrap());
+
+        // keys added to the stored filter show up in it, and survive a merge
+        let absent = (keys..keys * 2)
+            .find(|n| !filter.may_contain(&n.to_be_bytes()))
+            .unwrap();
+        assert!(!may_contain(&pool, page_id, &absent.to_be_bytes()).unwrap());
+        insert(&pool, page_id, &absent.to_be_bytes()).unwrap();
+        filter.cleared().merge_into(&pool, page_id).unwrap();
+        assert!(may_contain(&pool, page_id, &absent.to_be_bytes()).unwrap());
+        assert!(matches!(
+            BloomFilter::new(10, 0.5).merge_into(&pool, page_id),
+            Err(StorageError::Corruption(_))
+        ));
+
+        assert!(free(&pool, page_id) == filter.blocks() + 1);
+        assert!(matches!(
+            may_contain(&pool, page_id, &absent.to_be_bytes()),
+            Err(StorageError::Corruption(_) | StorageError::PageNotFound(_))
+        ));
+        drop(pool);
+        std::fs::remove_file(&path).unwrap();
+        let _ = std::fs::remove_file(freemap::map_path(&path));
+    }
+}
@@ -0,0 +1,344 @@
+/// This file implements a disk-backed extendible hash index. A directory page maps the low `global_depth` bits of a key's hash
+/// to bucket pages; each slot also records the local depth of its bucket. When a bucket overflows it's split in two on the next
+/// hash bit, doubling the directory first if the bucket was already at the global depth. All pages live in the buffer pool.
+///
+/// Lookups and ordinary inserts latch the directory shared and then one bucket. A split takes the directory latch exclusively,
+/// which keeps every other operation out of the index while bucket pointers move. Buckets are never merged on delete.
+use crate::shared::{PageId, PAGE_SIZE};
+use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+use crate::storage::buffer::page::{self, Page, PageType, PAGE_HEADER_SIZE};
+use crate::storage::error::StorageError;
+use crate::storage::index::blink::{Key, KEY_SIZE};
+
+/// The directory has to fit in one page, which bounds it to `2^MAX_GLOBAL_DEPTH` slots
+pub const MAX_GLOBAL_DEPTH: u32 = 8;
+const MAX_SLOTS: usize = 1 << MAX_GLOBAL_DEPTH;
+
+// directory layout (after the page header): global depth u8, then one bucket page id per slot, then one local depth per slot
+const GLOBAL_DEPTH_OFFSET: usize = PAGE_HEADER_SIZE;
+const BUCKETS_OFFSET: usize = GLOBAL_DEPTH_OFFSET + 8;
+const LOCAL_DEPTHS_OFFSET: usize = BUCKETS_OFFSET + MAX_SLOTS * 8;
+
+// bucket layout (after the page header): entry count u16, then (key, page id) entries
+const COUNT_OFFSET: usize = PAGE_HEADER_SIZE;
+const ENTRIES_OFFSET: usize = COUNT_OFFSET + 8;
+const ENTRY_SIZE: usize = KEY_SIZE + 8;
+
+/// Most entries a bucket page can hold
+pub const MAX_BUCKET_SIZE: usize = (PAGE_SIZE - ENTRIES_OFFSET) / ENTRY_SIZE;
+
+/// Stable across processes, unlike `DefaultHasher`, since the hash decides where keys live on disk
+fn hash(key: &Key) -> u32 {
+    crc32c::crc32c(key)
+}
+
+fn read_page_id(page: &Page, offset: usize) -> PageId {
+    PageId::from_le_bytes(page[offset..offset + 8].try_into().unwrap())
+}
+
+fn write_page_id(page: &mut Page, offset: usize, page_id: PageId) {
+    page[offset..offset + 8].copy_from_slice(&page_id.to_le_bytes());
+}
+
+struct Directory {
+    global_depth: u32,
+    buckets: Vec<PageId>,
+    local_depths: Vec<u32>,
+}
+
+impl Directory {
+    fn read(page: &Page) -> Directory {
+        let global_depth = page[GLOBAL_DEPTH_OFFSET] as u32;
+        let slots = 1 << global_depth;
+        Directory {
+            global_depth,
+            buckets: (0..slots)
+                .map(|i| read_page_id(page, BUCKETS_OFFSET + i * 8))
+                .collect(),
+            local_depths: (0..slots)
+                .map(|i| page[LOCAL_DEPTHS_OFFSET + i] as u32)
+                .collect(),
+        }
+    }
+
+    fn write(&self, page: &mut Page) {
+        page::set_page_type(page, PageType::HashDirectory);
+        page[GLOBAL_DEPTH_OFFSET] = self.global_depth as u8;
+        for (i, (bucket, depth)) in self.buckets.iter().zip(&self.local_depths).enumerate() {
+            write_page_id(page, BUCKETS_OFFSET + i * 8, *bucket);
+            page[LOCAL_DEPTHS_OFFSET + i] = *depth as u8;
+        }
+    }
+
+    fn slot(&self, key: &Key) -> usize {
+        (hash(key) & ((1 << self.global_depth) - 1)) as usize
+    }
+}
+
+fn read_bucket(page: &Page) -> Vec<(Key, PageId)> {
+    let count = u16::from_le_bytes(page[COUNT_OFFSET..COUNT_OFFSET + 2].try_into().unwrap());
+    (0..count as usize)
+        .map(|i| {
+            let offset = ENTRIES_OFFSET + i * ENTRY_SIZE;
+            let key = page[offset..offset + KEY_SIZE].try_into().unwrap();
+            (key, read_page_id(page, offset + KEY_SIZE))
+        })
+        .collect()
+}
+
+fn write_bucket(page: &mut Page, entries: &[(Key, PageId)]) {
+    page::set_page_type(page, PageType::HashBucket);
+    page[COUNT_OFFSET..COUNT_OFFSET + 2].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+    for (i, (key, value)) in entries.iter().enumerate() {
+        let offset = ENTRIES_OFFSET + i * ENTRY_SIZE;
+        page[offset..offset + KEY_SIZE].copy_from_slice(key);
+        write_page_id(page, offset + KEY_SIZE, *value);
+    }
+}
+
+/// An extendible hash index mapping unique fixed-size keys to page ids. Cloning gives another handle to the same index
+#[derive(Clone)]
+pub struct ExtendibleHashIndex {
+    pool: BufferPool,
+    directory_page_id: PageId,
+    bucket_size: usize,
+}
+
+impl ExtendibleHashIndex {
+    /// Create an empty index with a single bucket. Returns `None` if the buffer pool can't allocate its pages
+    pub fn create(pool: BufferPool) -> Option<Self> {
+        ExtendibleHashIndex::create_with_bucket_size(pool, MAX_BUCKET_SIZE)
+    }
+
+    /// Create an empty index whose buckets split once they hold `bucket_size` entries (at most `MAX_BUCKET_SIZE`)
+    pub fn create_with_bucket_size(pool: BufferPool, bucket_size: usize) -> Option<Self> {
+        assert!((1..=MAX_BUCKET_SIZE).contains(&bucket_size));
+        let mut directory = pool.new_page_write().ok()?;
+        let mut bucket = pool.new_page_write().ok()?;
+        write_bucket(&mut bucket, &[]);
+        Directory {
+            global_depth: 0,
+            buckets: vec![bucket.page_id()],
+            local_depths: vec![0],
+        }
+        .write(&mut directory);
+        Some(ExtendibleHashIndex {
+            directory_page_id: directory.page_id(),
+            pool,
+            bucket_size,
+        })
+    }
+
+    /// Open an index created earlier, identified by its directory page
+    pub fn open(pool: BufferPool, directory_page_id: PageId, bucket_size: usize) -> Self {
+        ExtendibleHashIndex {
+            pool,
+            directory_page_id,
+            bucket_size,
+        }
+    }
+
+    pub fn directory_page_id(&self) -> PageId {
+        self.directory_page_id
+    }
+
+    pub fn global_depth(&self) -> Result<u32, StorageError> {
+        let directory = self.pool.fetch_page_read(self.directory_page_id)?;
+        Ok(directory[GLOBAL_DEPTH_OFFSET] as u32)
+    }
+
+    pub fn get(&self, key: &Key) -> Result<Option<PageId>, StorageError> {
+        let directory = self.pool.fetch_page_read(self.directory_page_id)?;
+        let dir = Directory::read(&directory);
+        let bucket = self.pool.fetch_page_read(dir.buckets[dir.slot(key)])?;
+        Ok(read_bucket(&bucket)
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value))
+    }
+
+    /// Insert `key`. Returns false if it's already present, or if its bucket is full and can't be split because the directory
+    /// is at `MAX_GLOBAL_DEPTH`. Fails if a page can't be fetched or allocated, e.g. with `PoolExhausted`, leaving the key out
+    pub fn insert(&self, key: &Key, value: PageId) -> Result<bool, StorageError> {
+        loop {
+            {
+                let directory = self.pool.fetch_page_read(self.directory_page_id)?;
+                let dir = Directory::read(&directory);
+                let mut bucket = self.pool.fetch_page_write(dir.buckets[dir.slot(key)])?;
+                let mut entries = read_bucket(&bucket);
+                if entries.iter().any(|(k, _)| k == key) {
+                    return Ok(false);
+                }
+                if entries.len() < self.bucket_size {
+                    entries.push((*key, value));
+                    write_bucket(&mut bucket, &entries);
+                    return Ok(true);
+                }
+            }
+            if !self.split(key)? {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Remove `key`. Returns false if it isn't present
+    pub fn remove(&self, key: &Key) -> Result<bool, StorageError> {
+        let directory = self.pool.fetch_page_read(self.directory_page_id)?;
+        let dir = Directory::read(&directory);
+        let mut bucket = self.pool.fetch_page_write(dir.buckets[dir.slot(key)])?;
+        let mut entries = read_bucket(&bucket);
+        let Some(pos) = entries.iter().position(|(k, _)| k == key) else {
+            return Ok(false);
+        };
+        entries.swap_remove(pos);
+        write_bucket(&mut bucket, &entries);
+        Ok(true)
+    }
+
+    /// Split the bucket `key` hashes to, doubling the directory if needed. Returns false if the directory can't grow. The
+    /// bucket may have been split by someone else since the caller saw it full, in which case this only re-checks and returns.
+    /// Nothing is written until the new bucket's page has been allocated, so a failure leaves the index as it was
+    fn split(&self, key: &Key) -> Result<bool, StorageError> {
+        let mut directory = self.pool.fetch_page_write(self.directory_page_id)?;
+        let mut dir = Directory::read(&directory);
+        let slot = dir.slot(key);
+        let bucket_id = dir.buckets[slot];
+        let mut bucket = self.pool.fetch_page_write(bucket_id)?;
+        let entries = read_bucket(&bucket);
+        if entries.len() < self.bucket_size {
+            return Ok(true);
+        }
+        let local_depth = dir.local_depths[slot];
+        if local_depth == dir.global_depth {
+            if dir.global_depth == MAX_GLOBAL_DEPTH {
+                return Ok(false);
+            }
+            dir.buckets.extend_from_within(..);
+            dir.local_depths.extend_from_within(..);
+            dir.global_depth += 1;
+        }
+
+        let mut sibling = self.pool.new_page_write()?;
+        let bit = 1u32 << local_depth;
+        let (moved, kept): (Vec<_>, Vec<_>) =
+            entries.into_iter().partition(|(k, _)| hash(k) & bit != 0);
+        write_bucket(&mut bucket, &kept);
+        write_bucket(&mut sibling, &moved);
+        for i in 0..dir.buckets.len() {
+            if dir.buckets[i] == bucket_id {
+                dir.local_depths[i] = local_depth + 1;
+                if i as u32 & bit != 0 {
+                    dir.buckets[i] = sibling.page_id();
+                }
+            }
+        }
+        dir.write(&mut directory);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rayon::ThreadPoolBuilder;
+
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::config::StorageConfig;
+    use crate::storage::index::blink::key;
+
+    fn setup(name: &str) -> (BufferPool, String) {
+        let dir = cwd() + "/tests/hash_index_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/{}.bin", dir, name);
+        (BufferPool::create(&path).unwrap(), path)
+    }
+
+    #[test]
+    fn test_insert_get_remove() {
+        let (pool, path) = setup("test_insert_get_remove");
+        let index = ExtendibleHashIndex::create_with_bucket_size(pool, 4).unwrap();
+        for n in 0..300u64 {
+            assert!(index.insert(&key(n), n as PageId).unwrap());
+        }
+        assert!(index.global_depth().unwrap() > 0);
+        assert!(!index.insert(&key(7), 0).unwrap());
+        for n in 0..300u64 {
+            assert!(index.get(&key(n)).unwrap() == Some(n as PageId));
+        }
+        assert!(index.get(&key(300)).unwrap().is_none());
+        for n in (0..300u64).step_by(2) {
+            assert!(index.remove(&key(n)).unwrap());
+        }
+        assert!(!index.remove(&key(0)).unwrap());
+        for n in 0..300u64 {
+            assert!(index.get(&key(n)).unwrap() == (n % 2 == 1).then_some(n as PageId));
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_directory_limit() {
+        let (pool, path) = setup("test_directory_limit");
+        let index = ExtendibleHashIndex::create_with_bucket_size(pool, 1).unwrap();
+        // with one entry per bucket, two keys whose hashes agree on the low MAX_GLOBAL_DEPTH bits can't be separated
+        let first = key(0);
+        let mask = (1 << MAX_GLOBAL_DEPTH) - 1;
+        let second = (1..u64::MAX)
+            .map(key)
+            .find(|k| hash(k) & mask == hash(&first) & mask)
+            .unwrap();
+        assert!(index.insert(&first, 1).unwrap());
+        assert!(!index.insert(&second, 2).unwrap());
+        assert!(index.global_depth().unwrap() == MAX_GLOBAL_DEPTH);
+        assert!(index.get(&first).unwrap() == Some(1));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_pool_exhausted() {
+        let dir = cwd() + "/tests/hash_index_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir + "/test_pool_exhausted.bin";
+        let pool =
+            BufferPool::create_with_config(&StorageConfig::for_path(&path).with_pool_size(4))
+                .unwrap();
+        let index = ExtendibleHashIndex::create_with_bucket_size(pool.clone(), 1).unwrap();
+        assert!(index.insert(&key(0), 0).unwrap());
+        // the full bucket has to split, and there's no frame left for the new one
+        let pinned: Vec<_> = (0..2).map(|_| pool.new_page_write().unwrap()).collect();
+        assert!(matches!(
+            index.insert(&key(1), 1),
+            Err(StorageError::PoolExhausted)
+        ));
+        drop(pinned);
+        assert!(index.get(&key(1)).unwrap().is_none());
+        for n in 1..50u64 {
+            assert!(index.insert(&key(n), n as PageId).unwrap());
+        }
+        assert!((0..50u64).all(|n| index.get(&key(n)).unwrap() == Some(n as PageId)));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_inserts() {
+        let (pool, path) = setup("test_concurrent_inserts");
+        let index = ExtendibleHashIndex::create_with_bucket_size(pool, 8).unwrap();
+        let threads = ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        threads.scope(|s| {
+            for t in 0..8u64 {
+                let index = index.clone();
+                s.spawn(move |_| {
+                    for n in 0..100u64 {
+                        assert!(index
+                            .insert(&key(n * 8 + t), (n * 8 + t) as PageId)
+                            .unwrap());
+                    }
+                });
+            }
+        });
+        for n in 0..800u64 {
+            assert!(index.get(&key(n)).unwrap() == Some(n as PageId));
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,3 @@
+pub mod blink;
+pub mod bloom;
+pub mod hash;
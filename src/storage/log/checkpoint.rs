@@ -0,0 +1,268 @@
+/// This file implements checkpoints, which bound how much of the log crash recovery has to read. A checkpoint writes back every
+/// dirty page and forces the log, then logs a `BeginCheckpoint`/`EndCheckpoint` pair: the end record carries the dirty page
+/// table (pages that may still be newer in memory than on disk, with the LSN redo has to start from for each) and the active
+/// transaction table (open transactions with their last LSN). Once both records are durable, the LSN of the begin record is
+/// written to the master record, a small file next to the log, and recovery starts its analysis there instead of at the start
+/// of the log.
+///
+/// Checkpoints are fuzzy: transactions keep running while one is taken. The dirty page table is collected under the buffer
+/// pool's exclusive latch, which holds off new pins for as long as that takes. Pages are only added to it if they were dirtied
+/// or pinned after the flush, so the exact LSN of their first change isn't known; they're given the oldest first LSN of any
+/// open transaction instead (or the begin LSN if that's older), which is never later than the truth.
+use std::io::Write;
+
+use crate::shared::{Lsn, INVALID_LSN, INVALID_TXN_ID};
+use crate::storage::buffer;
+use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+use crate::storage::log::logmgr::{LogApi as _, LogManager};
+use crate::storage::log::record::LogBody;
+use crate::txn::tracker::{TxnTracker, TxnTrackerApi as _};
+
+pub const MASTER_MAGIC: &[u8; 8] = b"SCCKPT01";
+/// Magic, checkpoint LSN, and a CRC32C of the two
+pub const MASTER_RECORD_SIZE: usize = 20;
+
+/// Path of the master record belonging to the log at `log_path`
+pub fn master_path(log_path: &str) -> String {
+    format!("{}.ckpt", log_path)
+}
+
+/// The LSN of the last complete checkpoint, or `None` if the master record doesn't exist yet. Fails with `InvalidData` if the
+/// master record is corrupted
+pub fn read_master(path: &str) -> std::io::Result<Option<Lsn>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let valid = bytes.len() == MASTER_RECORD_SIZE
+        && bytes[..8] == MASTER_MAGIC[..]
+        && crc32c::crc32c(&bytes[..16]).to_le_bytes() == bytes[16..];
+    if !valid {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("master record {} is corrupted", path),
+        ));
+    }
+    Ok(Some(u64::from_le_bytes(bytes[8..16].try_into().unwrap())))
+}
+
+/// Replace the master record with one pointing at `lsn`. The record is written to a temporary file, synced and renamed into
+/// place, so a crash leaves either the old checkpoint or the new one
+pub(crate) fn write_master(path: &str, lsn: Lsn) -> std::io::Result<()> {
+    let mut bytes = Vec::with_capacity(MASTER_RECORD_SIZE);
+    bytes.extend_from_slice(MASTER_MAGIC);
+    bytes.extend_from_slice(&lsn.to_le_bytes());
+    bytes.extend_from_slice(&crc32c::crc32c(&bytes).to_le_bytes());
+    let tmp = format!("{}.tmp", path);
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    buffer::fs::sync_parent(path)
+}
+
+/// What a checkpoint recorded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub begin_lsn: Lsn,
+    pub end_lsn: Lsn,
+    pub dirty_pages: usize,
+    pub active_txns: usize,
+}
+
+pub struct CheckpointManager {
+    pool: BufferPool,
+    log: LogManager,
+    tracker: TxnTracker,
+    master: String,
+}
+
+impl CheckpointManager {
+    /// Checkpoint `pool`, which must have `log` attached, with open transactions taken from `tracker`. The master record is
+    /// kept at `master` (see `master_path`)
+    pub fn new(pool: BufferPool, log: LogManager, tracker: TxnTracker, master: &str) -> Self {
+        CheckpointManager {
+            pool,
+            log,
+            tracker,
+            master: master.to_string(),
+        }
+    }
+
+    pub fn pool(&self) -> &BufferPool {
+        &self.pool
+    }
+
+    pub fn log(&self) -> &LogManager {
+        &self.log
+    }
+
+    /// The LSN of the last complete checkpoint, if any
+    pub fn last_checkpoint(&self) -> std::io::Result<Option<Lsn>> {
+        read_master(&self.master)
+    }
+
+    /// Take a checkpoint. On error the master record still points at the previous checkpoint, which stays valid
+    pub fn checkpoint(&self) -> std::io::Result<Checkpoint> {
+        let begin_lsn = self
+            .log
+            .append(INVALID_TXN_ID, INVALID_LSN, LogBody::BeginCheckpoint)?;
+        self.pool.flush_all()?;
+
+        let dirty = self.pool.dirty_pages();
+        let rec_lsn = self
+            .tracker
+            .log_horizon()
+            .map_or(begin_lsn, |horizon| horizon.min(begin_lsn));
+        let dirty_pages: Vec<_> = dirty
+            .into_iter()
+            .map(|page_id| (page_id, rec_lsn))
+            .collect();
+        let active_txns: Vec<_> = self
+            .tracker
+            .oldest(usize::MAX)
+            .into_iter()
+            .filter(|info| info.last_lsn != INVALID_LSN)
+            .map(|info| (info.txn_id, info.last_lsn))
+            .collect();
+        let counts = (dirty_pages.len(), active_txns.len());
+        let end_lsn = self.log.append(
+            INVALID_TXN_ID,
+            begin_lsn,
+            LogBody::EndCheckpoint {
+                begin_lsn,
+                dirty_pages,
+                active_txns,
+            },
+        )?;
+        self.log.flush()?;
+        write_master(&self.master, begin_lsn)?;
+        Ok(Checkpoint {
+            begin_lsn,
+            end_lsn,
+            dirty_pages: counts.0,
+            active_txns: counts.1,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::{cwd, PageId, TxnId};
+    use crate::storage::buffer::diskmgr::{DiskApi as _, DiskMgr};
+    use crate::storage::buffer::page;
+    use crate::storage::log::recovery::RecoveryManager;
+
+    /// Log an update of byte 100 of `page_id` to `value` for `txn_id` and apply it in the pool
+    fn update(
+        pool: &BufferPool,
+        log: &LogManager,
+        tracker: &TxnTracker,
+        txn_id: TxnId,
+        prev_lsn: Lsn,
+        page_id: PageId,
+        value: u8,
+    ) -> Lsn {
+        let mut guard = pool.fetch_page_write(page_id).unwrap();
+        let body = LogBody::Update {
+            page_id,
+            offset: 100,
+            before: vec![guard[100]],
+            after: vec![value],
+        };
+        let lsn = log.append(txn_id, prev_lsn, body).unwrap();
+        tracker.logged(txn_id, lsn);
+        guard[100] = value;
+        page::set_lsn(&mut guard, lsn);
+        lsn
+    }
+
+    #[test]
+    fn test_recover_from_checkpoint() {
+        let dir = cwd() + "/tests/checkpoint_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let data_path = dir.clone() + "/data.bin";
+        let log_path = dir.clone() + "/wal.log";
+        let pool = BufferPool::create(&data_path).unwrap();
+        let log = LogManager::create(&log_path).unwrap();
+        pool.set_log_manager(log.clone());
+        let tracker = TxnTracker::create();
+        let page_ids: Vec<PageId> = (0..3).map(|_| pool.alloc_page().unwrap()).collect();
+        let checkpoints = CheckpointManager::new(
+            pool.clone(),
+            log.clone(),
+            tracker.clone(),
+            &master_path(&log_path),
+        );
+        assert!(checkpoints.last_checkpoint().unwrap().is_none());
+
+        // txn 1 commits before the checkpoint, txn 2 is still open during it and never commits
+        for txn_id in [1, 2] {
+            tracker.begin(txn_id);
+        }
+        let lsn = update(&pool, &log, &tracker, 1, INVALID_LSN, page_ids[0], 1);
+        log.append(1, lsn, LogBody::Commit).unwrap();
+        tracker.end(1);
+        let txn_2 = update(&pool, &log, &tracker, 2, INVALID_LSN, page_ids[1], 2);
+        // a pinned page goes into the dirty page table even though it was just written back
+        let pinned = pool.fetch_page(page_ids[2]).unwrap();
+
+        let checkpoint = checkpoints.checkpoint().unwrap();
+        assert!(checkpoints.last_checkpoint().unwrap() == Some(checkpoint.begin_lsn));
+        assert!(checkpoint.dirty_pages == 1 && checkpoint.active_txns == 1);
+        match log.read_record(checkpoint.end_lsn).unwrap().body {
+            LogBody::EndCheckpoint {
+                begin_lsn,
+                dirty_pages,
+                active_txns,
+            } => {
+                assert!(begin_lsn == checkpoint.begin_lsn);
+                assert!(dirty_pages == vec![(page_ids[2], txn_2)]);
+                assert!(active_txns == vec![(2, txn_2)]);
+            }
+            body => panic!("unexpected record {:?}", body),
+        }
+        drop(pinned);
+        pool.unpin_page(page_ids[2], false);
+
+        // txn 3 commits after the checkpoint, and the pool goes away without writing anything back
+        tracker.begin(3);
+        let lsn = update(&pool, &log, &tracker, 3, INVALID_LSN, page_ids[2], 3);
+        log.append(3, lsn, LogBody::Commit).unwrap();
+        log.flush().unwrap();
+        drop(pool);
+
+        let disk = DiskMgr::open(&data_path).unwrap();
+        let mut recovery = RecoveryManager::new(log.clone(), disk.clone());
+        recovery.set_master_record(&master_path(&log_path));
+        let stats = recovery.recover().unwrap();
+        assert!(stats.redone == 1 && stats.undone == 1);
+        assert!(stats.losers == vec![2]);
+        let mut buf = page::empty();
+        for (page_id, value) in page_ids.iter().zip([1, 0, 3]) {
+            disk.read_page(&mut buf, *page_id as u64).unwrap();
+            assert!(buf[100] == value);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_master_record() {
+        let dir = cwd() + "/tests/checkpoint_master_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = master_path(&(dir.clone() + "/wal.log"));
+        assert!(read_master(&path).unwrap().is_none());
+        write_master(&path, 42).unwrap();
+        write_master(&path, 4096).unwrap();
+        assert!(read_master(&path).unwrap() == Some(4096));
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[9] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+        let err = read_master(&path).unwrap_err();
+        assert!(err.kind() == std::io::ErrorKind::InvalidData);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
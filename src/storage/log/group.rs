@@ -0,0 +1,192 @@
+/// This file implements group commit. Each commit has to wait for the log to be durable up to its commit record, and with
+/// many small transactions committing at once, a sync per commit would dominate their cost. Instead, committing threads
+/// register the LSN they need and sleep on a condition variable, while a dedicated flusher thread syncs the log once for
+/// everyone waiting and wakes every committer whose LSN the sync covered.
+///
+/// After the first committer of a group arrives, the flusher waits up to `max_group_delay` before syncing, so that commits
+/// arriving in the meantime join the group. A longer delay means fewer syncs but a slower commit when there's no contention;
+/// zero syncs as soon as anyone is waiting, which still groups whatever arrives while the previous sync is running.
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::shared::{Lsn, INVALID_LSN};
+use crate::storage::log::logmgr::{LogApi as _, LogManager};
+
+/// Default time the flusher waits for more committers before syncing
+pub const MAX_GROUP_DELAY: Duration = Duration::from_micros(500);
+
+struct GroupState {
+    // highest LSN a committer is waiting for
+    requested: Lsn,
+    // the log is durable up to here
+    durable: Lsn,
+    max_group_delay: Duration,
+    // syncs done by the flusher, and how many of them failed
+    syncs: usize,
+    failures: usize,
+    stopping: bool,
+}
+
+/// Handle to a group commit flusher over a log. Dropping it stops the flusher once nobody is waiting
+pub struct GroupCommit {
+    log: LogManager,
+    state: Arc<(Mutex<GroupState>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl GroupCommit {
+    /// Start a flusher for `log` that waits up to `max_group_delay` for each group to fill up
+    pub fn start(log: LogManager, max_group_delay: Duration) -> Self {
+        let state = Arc::new((
+            Mutex::new(GroupState {
+                requested: INVALID_LSN,
+                durable: log.persistent_lsn(),
+                max_group_delay,
+                syncs: 0,
+                failures: 0,
+                stopping: false,
+            }),
+            Condvar::new(),
+        ));
+        let handle = {
+            let log = log.clone();
+            let state = state.clone();
+            std::thread::spawn(move || flusher(&log, &state))
+        };
+        GroupCommit {
+            log,
+            state,
+            handle: Some(handle),
+        }
+    }
+
+    /// Block until the log is durable up to `lsn` (normally a commit record). If the flusher's sync fails, the committer
+    /// falls back to flushing the log itself and returns that result, so a failed sync is reported to every committer it
+    /// affected
+    pub fn commit(&self, lsn: Lsn) -> std::io::Result<()> {
+        let (mutex, condvar) = &*self.state;
+        let mut state = mutex.lock();
+        let failures = state.failures;
+        if lsn > state.requested {
+            state.requested = lsn;
+            condvar.notify_all();
+        }
+        while state.durable < lsn {
+            if state.failures != failures {
+                drop(state);
+                return self.log.flush_to_lsn(lsn);
+            }
+            condvar.wait(&mut state);
+        }
+        Ok(())
+    }
+
+    /// Change how long the flusher waits for a group to fill up. Applies from the next group on
+    pub fn set_max_group_delay(&self, max_group_delay: Duration) {
+        self.state.0.lock().max_group_delay = max_group_delay;
+    }
+
+    /// Number of syncs the flusher has done so far
+    pub fn syncs(&self) -> usize {
+        self.state.0.lock().syncs
+    }
+}
+
+impl Drop for GroupCommit {
+    fn drop(&mut self) {
+        let (mutex, condvar) = &*self.state;
+        mutex.lock().stopping = true;
+        condvar.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn flusher(log: &LogManager, state: &(Mutex<GroupState>, Condvar)) {
+    let (mutex, condvar) = state;
+    loop {
+        let mut guard = mutex.lock();
+        while guard.requested <= guard.durable && !guard.stopping {
+            condvar.wait(&mut guard);
+        }
+        if guard.requested <= guard.durable {
+            return;
+        }
+        let delay = guard.max_group_delay;
+        drop(guard);
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+        // one sync covers everything appended so far, including records of committers that haven't registered yet
+        let result = log.flush();
+        let mut guard = mutex.lock();
+        guard.syncs += 1;
+        match result {
+            Ok(()) => guard.durable = guard.durable.max(log.persistent_lsn()),
+            Err(_) => {
+                guard.failures += 1;
+                // the committers waiting now fall back to flushing themselves, so stop asking for their LSNs
+                guard.requested = guard.durable;
+            }
+        }
+        condvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::log::record::LogBody;
+
+    #[test]
+    fn test_group_commit() {
+        let dir = cwd() + "/tests/group_commit_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = LogManager::create(&(dir.clone() + "/wal.log")).unwrap();
+        let group = Arc::new(GroupCommit::start(log.clone(), Duration::from_millis(2)));
+
+        let threads = 16;
+        let commits = 20;
+        let barrier = Arc::new(Barrier::new(threads));
+        let handles: Vec<_> = (1..=threads as u64)
+            .map(|txn_id| {
+                let log = log.clone();
+                let group = group.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    for _ in 0..commits {
+                        let begin = log.append(txn_id, INVALID_LSN, LogBody::Begin).unwrap();
+                        let commit = log.append(txn_id, begin, LogBody::Commit).unwrap();
+                        group.commit(commit).unwrap();
+                        assert!(log.persistent_lsn() >= commit);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        // commits were synced together
+        let syncs = group.syncs();
+        assert!(syncs > 0 && syncs < threads * commits);
+
+        // nothing to wait for if the record is already durable
+        group.commit(log.persistent_lsn()).unwrap();
+        assert!(group.syncs() == syncs);
+
+        group.set_max_group_delay(Duration::ZERO);
+        let lsn = log.append(1, INVALID_LSN, LogBody::Begin).unwrap();
+        group.commit(lsn).unwrap();
+        assert!(log.persistent_lsn() == lsn);
+        drop(group);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
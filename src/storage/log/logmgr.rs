@@ -0,0 +1,358 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::shared::{Lsn, TxnId, INVALID_LSN};
+use crate::storage::buffer::diskmgr::{DurabilityMode, FsyncFailed};
+use crate::storage::log::record::{self, LogBody, LogRecord};
+use crate::sync::{Latch as _, Synchronized};
+
+/// Every log file starts with this header, so no record lives at offset 0 and `INVALID_LSN` never names a real record
+pub const LOG_MAGIC: &[u8; 8] = b"SCLOG001";
+/// Appended records are buffered in memory and written out once the buffer grows past this size, or when a flush asks for them
+pub const LOG_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The write-ahead log. A record's LSN is its byte offset in the log file, so LSNs increase monotonically and a record can be
+/// read back directly from its LSN. `persistent_lsn` is the LSN of the last record known to be durable; a page may only be
+/// written back once the log is durable up to the page's LSN.
+pub struct LogManagerInternal {
+    handle: File,
+    durability: DurabilityMode,
+    // framed records that haven't been written to the file yet. the first one starts at `buffer_lsn`
+    buffer: Vec<u8>,
+    buffer_lsn: Lsn,
+    next_lsn: Lsn,
+    last_lsn: Lsn,
+    persistent_lsn: Lsn,
+    // a failed sync is fatal for the log just like it is for the data file (see `FsyncFailed`)
+    failed: bool,
+}
+
+impl LogManagerInternal {
+    fn write_buffer(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.handle.seek(SeekFrom::Start(self.buffer_lsn))?;
+        self.handle.write_all(&self.buffer)?;
+        self.buffer_lsn += self.buffer.len() as Lsn;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        let synced = match self.durability {
+            DurabilityMode::Full => self.handle.sync_all(),
+            DurabilityMode::Data | DurabilityMode::DSync => self.handle.sync_data(),
+        };
+        if synced.is_err() {
+            self.failed = true;
+            return Err(failed_error());
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self) -> std::io::Result<()> {
+        if self.failed {
+            return Err(failed_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LogManagerInternal {
+    /// Write out and sync the records appended since the last flush, unless a sync has already failed
+    fn drop(&mut self) {
+        if self.failed || self.persistent_lsn == self.last_lsn {
+            return;
+        }
+        let _ = self.write_buffer().and_then(|_| self.sync());
+    }
+}
+
+fn failed_error() -> std::io::Error {
+    std::io::Error::other(FsyncFailed { pages: vec![] })
+}
+
+pub type LogManager = Synchronized<LogManagerInternal>;
+
+pub trait LogApi {
+    fn create(path: &str) -> std::io::Result<Self>
+    where
+        Self: Sized;
+    fn create_with_durability(path: &str, durability: DurabilityMode) -> std::io::Result<Self>
+    where
+        Self: Sized;
+    fn open(path: &str) -> std::io::Result<Self>
+    where
+        Self: Sized;
+    fn append(&self, txn_id: TxnId, prev_lsn: Lsn, body: LogBody) -> std::io::Result<Lsn>;
+    fn flush_to_lsn(&self, lsn: Lsn) -> std::io::Result<()>;
+    fn flush(&self) -> std::io::Result<()>;
+    fn persistent_lsn(&self) -> Lsn;
+    fn last_lsn(&self) -> Lsn;
+    fn next_lsn(&self) -> Lsn;
+    fn read_record(&self, lsn: Lsn) -> Option<LogRecord>;
+    fn records(&self, from: Lsn) -> std::io::Result<Vec<LogRecord>>;
+    fn copy_to(&self, path: &str) -> std::io::Result<Lsn>;
+}
+
+impl LogApi for LogManager {
+    /// Create a new, empty log at `path`, truncating any existing file
+    fn create(path: &str) -> std::io::Result<Self> {
+        LogManager::create_with_durability(path, DurabilityMode::default())
+    }
+
+    fn create_with_durability(path: &str, durability: DurabilityMode) -> std::io::Result<Self> {
+        let mut handle = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(std::path::Path::new(path))?;
+        handle.write_all(LOG_MAGIC)?;
+        handle.sync_all()?;
+        let start = LOG_MAGIC.len() as Lsn;
+        Ok(Synchronized::init(LogManagerInternal {
+            handle,
+            durability,
+            buffer: Vec::with_capacity(LOG_BUFFER_SIZE),
+            buffer_lsn: start,
+            next_lsn: start,
+            last_lsn: INVALID_LSN,
+            persistent_lsn: INVALID_LSN,
+            failed: false,
+        }))
+    }
+
+    /// Open an existing log. The file is scanned to find the end of the log; a torn or corrupt record at the tail (from a crash
+    /// in the middle of a write) is truncated away along with anything after it.
+    fn open(path: &str) -> std::io::Result<Self> {
+        let mut handle = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut bytes = Vec::new();
+        handle.read_to_end(&mut bytes)?;
+        if bytes.len() < LOG_MAGIC.len() || &bytes[..LOG_MAGIC.len()] != LOG_MAGIC {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "not a log file",
+            ));
+        }
+        let mut offset = LOG_MAGIC.len();
+        let mut last_lsn = INVALID_LSN;
+        while let Some((_, size)) = record::decode(&bytes[offset..]) {
+            last_lsn = offset as Lsn;
+            offset += size;
+        }
+        if offset < bytes.len() {
+            handle.set_len(offset as u64)?;
+            handle.sync_all()?;
+        }
+        Ok(Synchronized::init(LogManagerInternal {
+            handle,
+            durability: DurabilityMode::default(),
+            buffer: Vec::with_capacity(LOG_BUFFER_SIZE),
+            buffer_lsn: offset as Lsn,
+            next_lsn: offset as Lsn,
+            last_lsn,
+            persistent_lsn: last_lsn,
+            failed: false,
+        }))
+    }
+
+    /// Append a record to the log and return its LSN. The record isn't durable until the log is flushed past it
+    fn append(&self, txn_id: TxnId, prev_lsn: Lsn, body: LogBody) -> std::io::Result<Lsn> {
+        let mut inner = self.lock();
+        inner.check_writable()?;
+        let lsn = inner.next_lsn;
+        let framed = record::encode(&LogRecord {
+            lsn,
+            txn_id,
+            prev_lsn,
+            body,
+        });
+        inner.buffer.extend_from_slice(&framed);
+        inner.next_lsn += framed.len() as Lsn;
+        inner.last_lsn = lsn;
+        if inner.buffer.len() >= LOG_BUFFER_SIZE {
+            inner.write_buffer()?;
+        }
+        Ok(lsn)
+    }
+
+    /// Make every record up to and including `lsn` durable. Returns immediately if they already are
+    fn flush_to_lsn(&self, lsn: Lsn) -> std::io::Result<()> {
+        let mut inner = self.lock();
+        if lsn <= inner.persistent_lsn {
+            return Ok(());
+        }
+        inner.check_writable()?;
+        // everything buffered is written and synced, which covers `lsn` if it names a record we appended
+        let last_lsn = inner.last_lsn;
+        inner.write_buffer()?;
+        inner.sync()?;
+        inner.persistent_lsn = last_lsn;
+        Ok(())
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        let last_lsn = self.last_lsn();
+        self.flush_to_lsn(last_lsn)
+    }
+
+    fn persistent_lsn(&self) -> Lsn {
+        self.lock().persistent_lsn
+    }
+
+    fn last_lsn(&self) -> Lsn {
+        self.lock().last_lsn
+    }
+
+    /// LSN the next appended record will get, i.e. the end of the log
+    fn next_lsn(&self) -> Lsn {
+        self.lock().next_lsn
+    }
+
+    /// Read the record at `lsn`, whether or not it has been flushed yet
+    fn read_record(&self, lsn: Lsn) -> Option<LogRecord> {
+        let mut inner = self.lock();
+        if lsn < LOG_MAGIC.len() as Lsn || lsn >= inner.next_lsn {
+            return None;
+        }
+        if lsn >= inner.buffer_lsn {
+            let start = (lsn - inner.buffer_lsn) as usize;
+            return record::decode(&inner.buffer[start..]).map(|(record, _)| record);
+        }
+        let mut header = [0u8; record::RECORD_HEADER_SIZE];
+        inner.handle.seek(SeekFrom::Start(lsn)).ok()?;
+        inner.handle.read_exact(&mut header).ok()?;
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let mut framed = header.to_vec();
+        framed.resize(record::RECORD_HEADER_SIZE + len, 0);
+        inner
+            .handle
+            .read_exact(&mut framed[record::RECORD_HEADER_SIZE..])
+            .ok()?;
+        record::decode(&framed).map(|(record, _)| record)
+    }
+
+    /// Every record with an LSN of at least `from`, in log order. Buffered records are written out first
+    fn records(&self, from: Lsn) -> std::io::Result<Vec<LogRecord>> {
+        let mut inner = self.lock();
+        inner.check_writable()?;
+        inner.write_buffer()?;
+        let mut bytes = Vec::new();
+        inner.handle.seek(SeekFrom::Start(0))?;
+        inner.handle.read_to_end(&mut bytes)?;
+        let mut offset = LOG_MAGIC.len();
+        let mut records = Vec::new();
+        while let Some((record, size)) = record::decode(&bytes[offset..]) {
+            if record.lsn >= from {
+                records.push(record);
+            }
+            offset += size;
+        }
+        Ok(records)
+    }
+
+    /// Flush the log and copy it to a new file at `path`, which `open` reads back as the same log. Appends wait while the copy
+    /// is made. Returns the LSN of the last record copied
+    fn copy_to(&self, path: &str) -> std::io::Result<Lsn> {
+        let mut inner = self.lock();
+        inner.check_writable()?;
+        let last_lsn = inner.last_lsn;
+        inner.write_buffer()?;
+        inner.sync()?;
+        inner.persistent_lsn = last_lsn;
+        let mut copy = File::create(path)?;
+        inner.handle.seek(SeekFrom::Start(0))?;
+        std::io::copy(&mut (&inner.handle).take(inner.buffer_lsn), &mut copy)?;
+        copy.sync_all()?;
+        Ok(last_lsn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::cwd;
+
+    fn setup(name: &str) -> String {
+        let dir = cwd() + "/tests/logmgr_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        format!("{}/{}.log", dir, name)
+    }
+
+    fn cleanup(path: &str) {
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_dir(cwd() + "/tests/logmgr_tests");
+    }
+
+    fn update(page_id: isize, value: u8) -> LogBody {
+        LogBody::Update {
+            page_id,
+            offset: 8,
+            before: vec![0; 4],
+            after: vec![value; 4],
+        }
+    }
+
+    #[test]
+    fn test_append_flush_and_reopen() {
+        let path = setup("append_flush_and_reopen");
+        let log = LogManager::create(&path).unwrap();
+        let begin = log.append(1, INVALID_LSN, LogBody::Begin).unwrap();
+        let first = log.append(1, begin, update(1, 7)).unwrap();
+        let second = log.append(1, first, update(2, 9)).unwrap();
+        assert!(begin < first && first < second);
+        assert!(log.persistent_lsn() == INVALID_LSN);
+
+        // records are readable from the log buffer before they're flushed
+        assert!(log.read_record(first).unwrap().body == update(1, 7));
+
+        log.flush_to_lsn(first).unwrap();
+        assert!(log.persistent_lsn() >= first);
+        let commit = log.append(1, second, LogBody::Commit).unwrap();
+        log.flush().unwrap();
+        assert!(log.persistent_lsn() == commit);
+        assert!(log.read_record(second).unwrap().prev_lsn == first);
+
+        let reopened = LogManager::open(&path).unwrap();
+        assert!(reopened.persistent_lsn() == commit);
+        let records = reopened.records(first).unwrap();
+        assert!(records.len() == 3);
+        assert!(records[2].body == LogBody::Commit);
+        let next = reopened.append(2, INVALID_LSN, LogBody::Begin).unwrap();
+        assert!(next > commit);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_create_error() {
+        let path = setup("create_error") + ".missing/wal.log";
+        let result = LogManager::create(&path);
+        assert!(matches!(result, Err(err) if err.kind() == std::io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn test_torn_tail_is_truncated() {
+        let path = setup("torn_tail_is_truncated");
+        let log = LogManager::create(&path).unwrap();
+        let begin = log.append(1, INVALID_LSN, LogBody::Begin).unwrap();
+        let update_lsn = log.append(1, begin, update(1, 7)).unwrap();
+        log.flush().unwrap();
+        drop(log);
+
+        // simulate a crash halfway through writing the last record
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 3).unwrap();
+        drop(file);
+
+        let log = LogManager::open(&path).unwrap();
+        assert!(log.persistent_lsn() == begin);
+        assert!(log.read_record(update_lsn).is_none());
+        assert!(std::fs::metadata(&path).unwrap().len() == update_lsn);
+        let next = log.append(1, begin, LogBody::Abort).unwrap();
+        assert!(next == update_lsn);
+        cleanup(&path);
+    }
+}
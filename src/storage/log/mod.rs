@@ -0,0 +1,6 @@
+pub mod checkpoint;
+pub mod group;
+pub mod logmgr;
+pub mod record;
+pub mod recovery;
+pub mod replication;
@@ -0,0 +1,112 @@
+/// This file defines the records stored in the write-ahead log and their on-disk framing. Every record is stored as a 4 byte
+/// little endian payload length, a 4 byte CRC32C of the payload, and the bincode-encoded record. The checksum lets the log
+/// manager tell a torn write at the tail of the log apart from valid records.
+use serde::{Deserialize, Serialize};
+
+use crate::shared::{Lsn, PageId, TxnId};
+
+pub const RECORD_HEADER_SIZE: usize = 8;
+/// Version of the record framing and encoding. Bump it whenever the byte layout of a record changes
+pub const LOG_FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum LogBody {
+    Begin,
+    Commit,
+    /// The transaction is rolling back. It stays active until its `End` record
+    Abort,
+    /// The transaction has been fully rolled back
+    End,
+    /// Physical update of `before.len()` bytes at `offset` in a page. `before` and `after` always have the same length
+    Update {
+        page_id: PageId,
+        offset: u16,
+        #[serde(with = "serde_bytes")]
+        before: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        after: Vec<u8>,
+    },
+    /// Compensation log record, written while undoing an `Update`. CLRs are redone but never undone; `undo_next_lsn` is the
+    /// next record of the transaction left to undo
+    Clr {
+        page_id: PageId,
+        offset: u16,
+        #[serde(with = "serde_bytes")]
+        after: Vec<u8>,
+        undo_next_lsn: Lsn,
+    },
+    /// Start of a checkpoint. Written outside of any transaction
+    BeginCheckpoint,
+    /// End of the checkpoint whose `BeginCheckpoint` is `begin_lsn`: the pages that may still be newer in memory than on disk,
+    /// each with the LSN redo has to start from for it, and every transaction that was open, with the last record it wrote
+    EndCheckpoint {
+        begin_lsn: Lsn,
+        dirty_pages: Vec<(PageId, Lsn)>,
+        active_txns: Vec<(TxnId, Lsn)>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LogRecord {
+    pub lsn: Lsn,
+    pub txn_id: TxnId,
+    /// Previous record written by the same transaction (`INVALID_LSN` for its first record)
+    pub prev_lsn: Lsn,
+    pub body: LogBody,
+}
+
+/// Frame a record for the log file
+pub fn encode(record: &LogRecord) -> Vec<u8> {
+    let payload = bincode::serialize(record).unwrap();
+    let mut framed = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    framed.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    framed.extend_from_slice(&crc32c::crc32c(&payload).to_le_bytes());
+    framed.extend_from_slice(&payload);
+    framed
+}
+
+/// Decode the record framed at the start of `bytes`, returning it along with the size of its frame. Returns `None` if `bytes`
+/// holds an incomplete or corrupt frame.
+pub fn decode(bytes: &[u8]) -> Option<(LogRecord, usize)> {
+    if bytes.len() < RECORD_HEADER_SIZE {
+        return None;
+    }
+    let len = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    let payload = bytes.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len)?;
+    if crc32c::crc32c(payload) != checksum {
+        return None;
+    }
+    let record = bincode::deserialize(payload).ok()?;
+    Some((record, RECORD_HEADER_SIZE + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let record = LogRecord {
+            lsn: 8,
+            txn_id: 1,
+            prev_lsn: 0,
+            body: LogBody::Update {
+                page_id: 3,
+                offset: 16,
+                before: vec![0; 4],
+                after: vec![1, 2, 3, 4],
+            },
+        };
+        let mut framed = encode(&record);
+        let (decoded, size) = decode(&framed).unwrap();
+        assert!(decoded == record);
+        assert!(size == framed.len());
+
+        // truncated and corrupted frames are rejected
+        assert!(decode(&framed[..framed.len() - 1]).is_none());
+        let last = framed.len() - 1;
+        framed[last] ^= 0xff;
+        assert!(decode(&framed).is_none());
+    }
+}
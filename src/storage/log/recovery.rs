@@ -0,0 +1,588 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use rayon::prelude::*;
+
+use crate::shared::{Lsn, PageId, TxnId, INVALID_LSN};
+use crate::storage::buffer::diskmgr::{DiskApi as _, DiskMgr};
+use crate::storage::buffer::page::{self, Page};
+use crate::storage::error::StorageError;
+use crate::storage::log::checkpoint;
+use crate::storage::log::logmgr::{LogApi as _, LogManager};
+use crate::storage::log::record::{LogBody, LogRecord};
+use crate::telemetry::trace::trace_info;
+use crate::telemetry::{EventBus, EventBusApi as _, RecoveryPhase, StorageEvent};
+
+/// Records processed between progress reports
+pub const PROGRESS_INTERVAL: usize = 1000;
+
+/// What a recovery run did
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RecoveryStats {
+    /// Updates and CLRs reapplied because the page on disk was older than the record
+    pub redone: usize,
+    /// Updates rolled back for loser transactions
+    pub undone: usize,
+    /// Transactions that were active at the crash and have now been rolled back
+    pub losers: Vec<TxnId>,
+}
+
+/// ARIES-style crash recovery over the write-ahead log and the data file. Run it on startup, before the buffer pool serves any
+/// pages:
+///
+/// - analysis scans the log to rebuild the active transaction table (transaction -> last LSN) and the dirty page table
+///   (page -> LSN of the first record that may have dirtied it)
+/// - redo repeats history: every update and CLR whose LSN is newer than the page LSN on disk is reapplied
+/// - undo rolls back every transaction without a `Commit` or `End` record, newest record first, writing a CLR for each undone
+///   update so that a crash during recovery never undoes anything twice
+///
+/// Pages are modified in memory while recovering and only written back once the log (including the new CLRs) is durable.
+/// Redo is spread over worker threads, each owning the pages whose id falls in its partition and replaying their updates in
+/// log order; updates to different pages commute, so this gives the same result as a serial replay. Recovery runs before the
+/// buffer pool is started, so no redone page can be evicted or read half-recovered.
+///
+/// With a master record set, analysis starts at the last checkpoint instead of at the start of the log, with the tables
+/// seeded from the checkpoint's `EndCheckpoint` record. Redo still starts at the oldest LSN in the dirty page table, which
+/// may be before the checkpoint, and undo follows each loser's chain as far back as it goes.
+///
+/// Each phase reports its progress every `PROGRESS_INTERVAL` records and when it finishes, both as an info-level trace event
+/// (with the `tracing` feature) and, if one is attached, to the event bus, which is where code should follow it. Redo estimates its remaining time from how fast it has moved through the log so
+/// far; undo walks the log backwards along each loser's chain, so it can't tell how far it has left to go.
+pub struct RecoveryManager {
+    log: LogManager,
+    disk: DiskMgr,
+    pages: HashMap<PageId, Page>,
+    bus: Option<EventBus>,
+    redo_workers: usize,
+    master: Option<String>,
+}
+
+impl RecoveryManager {
+    pub fn new(log: LogManager, disk: DiskMgr) -> Self {
+        RecoveryManager {
+            log,
+            disk,
+            pages: HashMap::new(),
+            bus: None,
+            redo_workers: std::thread::available_parallelism().map_or(1, usize::from),
+            master: None,
+        }
+    }
+
+    /// Split redo across `workers` threads (one per CPU by default)
+    pub fn set_redo_workers(&mut self, workers: usize) {
+        self.redo_workers = workers;
+    }
+
+    /// Publish recovery events (see `StorageEvent`) to `bus`
+    pub fn set_event_bus(&mut self, bus: EventBus) {
+        self.bus = Some(bus);
+    }
+
+    /// Start from the checkpoint named by the master record at `path` (see `checkpoint::master_path`), if there is one
+    pub fn set_master_record(&mut self, path: &str) {
+        self.master = Some(path.to_string());
+    }
+
+    fn publish(&self, event: StorageEvent) {
+        trace_info!("{}", event);
+        if let Some(bus) = &self.bus {
+            bus.publish(event);
+        }
+    }
+
+    pub fn recover(&mut self) -> std::io::Result<RecoveryStats> {
+        // a clean shutdown wrote every page back after the last transaction ended (see `BufApi::close`)
+        if self.disk.clean_shutdown() {
+            return Ok(RecoveryStats::default());
+        }
+        let checkpoint = match &self.master {
+            Some(path) => checkpoint::read_master(path)?,
+            None => None,
+        };
+        let records = self.log.records(checkpoint.unwrap_or(INVALID_LSN))?;
+        let end_lsn = records.last().map_or(INVALID_LSN, |record| record.lsn);
+        self.publish(StorageEvent::RecoveryStarted { end_lsn });
+        let (mut active, dirty) = analysis(&records);
+        // the checkpoint may have caught a transaction between logging its commit and being forgotten by the tracker
+        active.retain(|_, last_lsn| {
+            !matches!(
+                self.log.read_record(*last_lsn).map(|record| record.body),
+                Some(LogBody::Commit | LogBody::End)
+            )
+        });
+        self.publish(StorageEvent::RecoveryProgress {
+            phase: RecoveryPhase::Analysis,
+            processed: records.len(),
+            lsn: end_lsn,
+            end_lsn,
+            eta: Some(Duration::ZERO),
+        });
+        // pages dirty at the checkpoint may need records from before it
+        let earlier = match dirty.values().min() {
+            Some(start) if checkpoint.is_some_and(|lsn| *start < lsn) => {
+                Some(self.log.records(*start)?)
+            }
+            _ => None,
+        };
+        let mut stats = RecoveryStats {
+            redone: self.redo(earlier.as_deref().unwrap_or(&records), &dirty, true)?,
+            ..RecoveryStats::default()
+        };
+        let (undone, losers) = self.undo(active, end_lsn)?;
+        stats.undone = undone;
+        stats.losers = losers;
+
+        self.write_back()?;
+        self.publish(StorageEvent::RecoveryFinished {
+            redone: stats.redone,
+            undone: stats.undone,
+            losers: stats.losers.len(),
+        });
+        Ok(stats)
+    }
+
+    /// Redo `records`, a stretch of the log already in this manager's log, and write back the pages they touch. Nothing is
+    /// undone and nothing is reported: a follower applies the primary's log this way as it arrives (see `replication`), and
+    /// the transactions still open when it's promoted are rolled back by `recover`. Records the pages already have are
+    /// skipped, so replaying a stretch twice is harmless
+    pub fn replay(&mut self, records: &[LogRecord]) -> std::io::Result<usize> {
+        let (_, dirty) = analysis(records);
+        let redone = self.redo(records, &dirty, false)?;
+        self.write_back()?;
+        Ok(redone)
+    }
+
+    /// Make the log durable, then write every page modified in memory back to the data file
+    fn write_back(&mut self) -> std::io::Result<()> {
+        self.log.flush()?;
+        let mut pages: Vec<(PageId, Page)> = self.pages.drain().collect();
+        pages.sort_unstable_by_key(|(page_id, _)| *page_id);
+        for (page_id, page) in pages {
+            self.disk.write_page(&page, page_id as u64)?;
+        }
+        Ok(())
+    }
+
+    fn redo(
+        &mut self,
+        records: &[LogRecord],
+        dirty: &HashMap<PageId, Lsn>,
+        progress: bool,
+    ) -> std::io::Result<usize> {
+        let Some(start) = dirty.values().min().copied() else {
+            return Ok(0);
+        };
+        let end_lsn = records.last().map_or(start, |record| record.lsn);
+        let started = Instant::now();
+        let report = |processed: usize, lsn: Lsn| {
+            // assume the rest of the log replays as fast as what's been replayed so far
+            let eta = (lsn > start).then(|| {
+                started
+                    .elapsed()
+                    .mul_f64((end_lsn - lsn) as f64 / (lsn - start) as f64)
+            });
+            StorageEvent::RecoveryProgress {
+                phase: RecoveryPhase::Redo,
+                processed,
+                lsn,
+                end_lsn,
+                eta,
+            }
+        };
+
+        // split the updates by page, keeping each page's in log order
+        let workers = self.redo_workers.max(1);
+        let mut partitions: Vec<Vec<Redo>> = (0..workers).map(|_| Vec::new()).collect();
+        let mut processed = 0;
+        for record in records.iter().filter(|record| record.lsn >= start) {
+            processed += 1;
+            let (page_id, offset, after) = match &record.body {
+                LogBody::Update {
+                    page_id,
+                    offset,
+                    after,
+                    ..
+                } => (*page_id, *offset, after),
+                LogBody::Clr {
+                    page_id,
+                    offset,
+                    after,
+                    ..
+                } => (*page_id, *offset, after),
+                _ => continue,
+            };
+            // the page can't contain this record if it was only dirtied later
+            if dirty
+                .get(&page_id)
+                .is_none_or(|rec_lsn| record.lsn < *rec_lsn)
+            {
+                continue;
+            }
+            partitions[page_id as usize % workers].push(Redo {
+                lsn: record.lsn,
+                page_id,
+                offset,
+                after,
+            });
+        }
+        let mut pages: Vec<HashMap<PageId, Page>> = (0..workers).map(|_| HashMap::new()).collect();
+        for (page_id, page) in self.pages.drain() {
+            pages[page_id as usize % workers].insert(page_id, page);
+        }
+
+        let applied = AtomicUsize::new(0);
+        let this = &*self;
+        let results: Vec<std::io::Result<usize>> = partitions
+            .par_iter()
+            .zip(pages.par_iter_mut())
+            .map(|(partition, pages)| {
+                let mut redone = 0;
+                for redo in partition {
+                    let applied = applied.fetch_add(1, Ordering::Relaxed) + 1;
+                    if progress && applied.is_multiple_of(PROGRESS_INTERVAL) {
+                        this.publish(report(applied, redo.lsn));
+                    }
+                    let page = load_page(&this.disk, pages, redo.page_id)?;
+                    if page::lsn(page) >= redo.lsn {
+                        continue;
+                    }
+                    apply(page, redo.offset, redo.after, redo.lsn);
+                    redone += 1;
+                }
+                Ok(redone)
+            })
+            .collect();
+        for partition in pages {
+            self.pages.extend(partition);
+        }
+        let redone = results.into_iter().sum::<std::io::Result<usize>>()?;
+        if progress {
+            self.publish(report(processed, end_lsn));
+        }
+        Ok(redone)
+    }
+
+    fn undo(
+        &mut self,
+        active: HashMap<TxnId, Lsn>,
+        end_lsn: Lsn,
+    ) -> std::io::Result<(usize, Vec<TxnId>)> {
+        let mut losers: Vec<TxnId> = active.keys().copied().collect();
+        losers.sort_unstable();
+        // next LSN to undo -> (transaction, last LSN the transaction wrote)
+        let mut to_undo: BTreeMap<Lsn, (TxnId, Lsn)> = active
+            .into_iter()
+            .map(|(txn_id, last_lsn)| (last_lsn, (txn_id, last_lsn)))
+            .collect();
+        let report = |processed: usize, lsn: Lsn| StorageEvent::RecoveryProgress {
+            phase: RecoveryPhase::Undo,
+            processed,
+            lsn,
+            end_lsn,
+            eta: None,
+        };
+        let mut undone = 0;
+        let mut processed = 0;
+        while let Some((lsn, (txn_id, last_lsn))) = to_undo.pop_last() {
+            processed += 1;
+            if processed % PROGRESS_INTERVAL == 0 {
+                self.publish(report(processed, lsn));
+            }
+            let record = self.log.read_record(lsn).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("missing log record at lsn {}", lsn),
+                )
+            })?;
+            let (next, last_lsn) = match record.body {
+                LogBody::Update {
+                    page_id,
+                    offset,
+                    before,
+                    ..
+                } => {
+                    let clr_lsn = self.log.append(
+                        txn_id,
+                        last_lsn,
+                        LogBody::Clr {
+                            page_id,
+                            offset,
+                            after: before.clone(),
+                            undo_next_lsn: record.prev_lsn,
+                        },
+                    )?;
+                    apply(self.page(page_id)?, offset, &before, clr_lsn);
+                    undone += 1;
+                    (record.prev_lsn, clr_lsn)
+                }
+                LogBody::Clr { undo_next_lsn, .. } => (undo_next_lsn, last_lsn),
+                _ => (record.prev_lsn, last_lsn),
+            };
+            if next == INVALID_LSN {
+                self.log.append(txn_id, last_lsn, LogBody::End)?;
+            } else {
+                to_undo.insert(next, (txn_id, last_lsn));
+            }
+        }
+        self.publish(report(processed, INVALID_LSN));
+        Ok((undone, losers))
+    }
+
+    /// The in-memory copy of `page_id`, read from disk on first use
+    fn page(&mut self, page_id: PageId) -> std::io::Result<&mut Page> {
+        load_page(&self.disk, &mut self.pages, page_id)
+    }
+}
+
+/// An update or CLR to reapply
+struct Redo<'a> {
+    lsn: Lsn,
+    page_id: PageId,
+    offset: u16,
+    after: &'a [u8],
+}
+
+/// The copy of `page_id` in `pages`, read from disk if it isn't there yet. Pages past the end of the file read as empty
+fn load_page<'a>(
+    disk: &DiskMgr,
+    pages: &'a mut HashMap<PageId, Page>,
+    page_id: PageId,
+) -> std::io::Result<&'a mut Page> {
+    match pages.entry(page_id) {
+        Entry::Occupied(entry) => Ok(entry.into_mut()),
+        Entry::Vacant(entry) => {
+            let mut buf = page::empty();
+            match disk.read_page(&mut buf, page_id as u64) {
+                Ok(()) => {}
+                Err(StorageError::PageNotFound(_)) => buf = page::empty(),
+                Err(err) => return Err(err.into()),
+            }
+            Ok(entry.insert(buf))
+        }
+    }
+}
+
+/// Rebuild the active transaction table and the dirty page table from the log. If `records` starts at a checkpoint, the tables
+/// are seeded from its end record: its dirty pages are merged in, and its transactions are added unless a record written
+/// since the checkpoint began already says where they stand
+fn analysis(records: &[LogRecord]) -> (HashMap<TxnId, Lsn>, HashMap<PageId, Lsn>) {
+    let mut active = HashMap::new();
+    let mut dirty: HashMap<PageId, Lsn> = HashMap::new();
+    let mut seen = HashSet::new();
+    for record in records {
+        match &record.body {
+            LogBody::BeginCheckpoint => {}
+            LogBody::EndCheckpoint {
+                dirty_pages,
+                active_txns,
+                ..
+            } => {
+                for (page_id, rec_lsn) in dirty_pages {
+                    dirty
+                        .entry(*page_id)
+                        .and_modify(|lsn| *lsn = (*lsn).min(*rec_lsn))
+                        .or_insert(*rec_lsn);
+                }
+                for (txn_id, last_lsn) in active_txns {
+                    if !seen.contains(txn_id) {
+                        active.insert(*txn_id, *last_lsn);
+                    }
+                }
+            }
+            LogBody::Commit | LogBody::End => {
+                seen.insert(record.txn_id);
+                active.remove(&record.txn_id);
+            }
+            body => {
+                seen.insert(record.txn_id);
+                active.insert(record.txn_id, record.lsn);
+                if let LogBody::Update { page_id, .. } | LogBody::Clr { page_id, .. } = body {
+                    dirty.entry(*page_id).or_insert(record.lsn);
+                }
+            }
+        }
+    }
+    (active, dirty)
+}
+
+fn apply(page: &mut Page, offset: u16, bytes: &[u8], lsn: Lsn) {
+    let offset = offset as usize;
+    page[offset..offset + bytes.len()].copy_from_slice(bytes);
+    page::set_lsn(page, lsn);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::cwd;
+
+    fn update(page_id: PageId, before: u8, after: u8) -> LogBody {
+        LogBody::Update {
+            page_id,
+            offset: 100,
+            before: vec![before],
+            after: vec![after],
+        }
+    }
+
+    fn read(disk: &DiskMgr, page_id: PageId) -> Page {
+        let mut buf = page::empty();
+        disk.read_page(&mut buf, page_id as u64).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_parallel_redo() {
+        let dir = cwd() + "/tests/recovery_parallel_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = LogManager::create(&(dir.clone() + "/wal.log")).unwrap();
+        // committed updates to 16 pages, several per page, none of them written back
+        let mut prev = log.append(1, INVALID_LSN, LogBody::Begin).unwrap();
+        for i in 0..200u16 {
+            let body = LogBody::Update {
+                page_id: (i % 16) as PageId,
+                offset: 100 + i,
+                before: vec![0, 0],
+                after: vec![i as u8, (i % 16) as u8],
+            };
+            prev = log.append(1, prev, body).unwrap();
+        }
+        log.append(1, prev, LogBody::Commit).unwrap();
+        log.flush().unwrap();
+
+        let mut images = Vec::new();
+        for workers in [1, 4] {
+            let disk = DiskMgr::create(&format!("{}/data_{}.bin", dir, workers)).unwrap();
+            let mut recovery = RecoveryManager::new(log.clone(), disk.clone());
+            recovery.set_redo_workers(workers);
+            let stats = recovery.recover().unwrap();
+            assert!(stats.redone == 200);
+            images.push(
+                (0..16)
+                    .map(|page_id| read(&disk, page_id))
+                    .collect::<Vec<Page>>(),
+            );
+        }
+        assert!(images[0] == images[1]);
+        assert!(images[1][3][100 + 19..100 + 21] == [19, 3]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_clean_shutdown_skips_recovery() {
+        use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+
+        let dir = cwd() + "/tests/recovery_clean_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let data_path = dir.clone() + "/data.bin";
+        let log = LogManager::create(&(dir.clone() + "/wal.log")).unwrap();
+        let pool = BufferPool::create(&data_path).unwrap();
+        pool.set_log_manager(log.clone());
+        let page_id = pool.alloc_page().unwrap();
+        let begin = log.append(1, INVALID_LSN, LogBody::Begin).unwrap();
+        let lsn = log.append(1, begin, update(page_id, 0, 1)).unwrap();
+        let mut guard = pool.fetch_page_write(page_id).unwrap();
+        apply(guard.data_mut(), 100, &[1], lsn);
+        drop(guard);
+        log.append(1, lsn, LogBody::Commit).unwrap();
+        pool.close().unwrap();
+        drop(pool);
+
+        let disk = DiskMgr::open(&data_path).unwrap();
+        let bus = EventBus::create();
+        let events = bus.subscribe();
+        let mut recovery = RecoveryManager::new(log.clone(), disk.clone());
+        recovery.set_event_bus(bus);
+        assert!(recovery.recover().unwrap() == RecoveryStats::default());
+        assert!(events.try_recv().is_err());
+        assert!(read(&disk, page_id)[100] == 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_redo_and_undo() {
+        let dir = cwd() + "/tests/recovery_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let disk = DiskMgr::create(&(dir.clone() + "/data.bin")).unwrap();
+        let log = LogManager::create(&(dir.clone() + "/wal.log")).unwrap();
+        for _ in 0..3 {
+            disk.append_page(&page::empty()).unwrap();
+        }
+
+        // txn 1 commits but its update never reached the data file
+        let begin = log.append(1, INVALID_LSN, LogBody::Begin).unwrap();
+        let committed = log.append(1, begin, update(0, 0, 1)).unwrap();
+        log.append(1, committed, LogBody::Commit).unwrap();
+        // txn 2 never commits, and its second update was written back before the crash
+        let begin = log.append(2, INVALID_LSN, LogBody::Begin).unwrap();
+        let first = log.append(2, begin, update(1, 0, 2)).unwrap();
+        let second = log.append(2, first, update(2, 0, 3)).unwrap();
+        log.flush().unwrap();
+        let mut page = page::empty();
+        apply(&mut page, 100, &[3], second);
+        disk.write_page(&page, 2).unwrap();
+
+        let bus = EventBus::create();
+        let events = bus.subscribe();
+        let mut recovery = RecoveryManager::new(log.clone(), disk.clone());
+        recovery.set_event_bus(bus);
+        let stats = recovery.recover().unwrap();
+        assert!(events.try_recv().unwrap() == StorageEvent::RecoveryStarted { end_lsn: second });
+        let phases: Vec<(RecoveryPhase, usize)> = events
+            .try_iter()
+            .take(3)
+            .map(|event| match event {
+                StorageEvent::RecoveryProgress {
+                    phase, processed, ..
+                } => (phase, processed),
+                event => panic!("unexpected event {}", event),
+            })
+            .collect();
+        // 6 records analysed, redo from txn 1's update on (5 records), txn 2's 3 records undone
+        assert!(
+            phases
+                == vec![
+                    (RecoveryPhase::Analysis, 6),
+                    (RecoveryPhase::Redo, 5),
+                    (RecoveryPhase::Undo, 3)
+                ]
+        );
+        assert!(
+            events.try_recv().unwrap()
+                == StorageEvent::RecoveryFinished {
+                    redone: 2,
+                    undone: 2,
+                    losers: 1
+                }
+        );
+        assert!(stats.redone == 2);
+        assert!(stats.undone == 2);
+        assert!(stats.losers == vec![2]);
+        assert!(read(&disk, 0)[100] == 1);
+        assert!(read(&disk, 1)[100] == 0);
+        assert!(read(&disk, 2)[100] == 0);
+
+        let records = log.records(second).unwrap();
+        assert!(matches!(records.last().unwrap().body, LogBody::End));
+        assert!(
+            records
+                .iter()
+                .filter(|record| matches!(record.body, LogBody::Clr { .. }))
+                .count()
+                == 2
+        );
+
+        // recovering again is a no-op: the loser has ended and every page is current
+        let stats = RecoveryManager::new(log.clone(), disk.clone())
+            .recover()
+            .unwrap();
+        assert!(stats == RecoveryStats::default());
+        assert!(read(&disk, 2)[100] == 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,560 @@
+/// This file implements log shipping replication. A primary runs a `ReplicationManager`, which listens for followers and
+/// streams each one the write-ahead log from wherever it left off. A follower keeps a log and data file of its own: it appends
+/// every record it receives to its log (so its log stays a byte-for-byte copy of the primary's, with the same LSNs), redoes the
+/// record through `RecoveryManager::replay`, and acknowledges it once both are durable. Nothing is undone while following;
+/// when the primary is gone, `Follower::promote` runs a full recovery, which rolls back the transactions that never finished,
+/// and leaves a log and data file the follower can serve from as the new primary.
+///
+/// Only records that are durable on the primary are shipped, so a follower is never ahead of what the primary can recover.
+/// Pages themselves aren't shipped: a page a follower never saw a logged update for isn't in its data file.
+///
+/// The wire protocol is deliberately small:
+///
+/// - the follower opens with `LOG_MAGIC` and the end of its log (8 bytes, little endian), which is the LSN it wants next
+/// - the primary sends records framed as they are in the log file (see `record::encode`), in log order
+/// - the follower sends back the LSN of the last record it has applied (8 bytes, little endian) after every batch
+///
+/// The primary tracks the acknowledged LSN of each follower. In `CommitMode::Synchronous`, `ReplicationManager::commit`
+/// doesn't return until some follower has acknowledged the commit record, so a committed transaction survives losing the
+/// primary.
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::shared::{Lsn, INVALID_LSN};
+use crate::storage::buffer::diskmgr::DiskMgr;
+use crate::storage::log::logmgr::{LogApi as _, LogManager, LOG_MAGIC};
+use crate::storage::log::record::{self, LogRecord, RECORD_HEADER_SIZE};
+use crate::storage::log::recovery::{RecoveryManager, RecoveryStats};
+
+/// How often the primary checks for new followers, and for records made durable without going through `commit`
+pub const POLL_INTERVAL: Duration = Duration::from_millis(5);
+/// Most records a follower applies before it acknowledges them
+pub const MAX_BATCH: usize = 256;
+
+/// What `ReplicationManager::commit` waits for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CommitMode {
+    /// The commit record is durable on the primary. Followers catch up on their own
+    #[default]
+    Asynchronous,
+    /// The commit record has also been acknowledged by a follower. Fails with `TimedOut` if none does within the duration
+    Synchronous(Duration),
+}
+
+/// What the primary knows about a connected follower
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FollowerStatus {
+    pub addr: SocketAddr,
+    /// Last record sent to the follower
+    pub sent_lsn: Lsn,
+    /// Last record the follower has made durable and applied
+    pub acked_lsn: Lsn,
+}
+
+struct PrimaryState {
+    // every accepted connection, so they can all be shut down when the primary stops
+    streams: HashMap<usize, TcpStream>,
+    // connections that have completed the handshake
+    followers: HashMap<usize, FollowerStatus>,
+    next_id: usize,
+    mode: CommitMode,
+    threads: Vec<JoinHandle<()>>,
+    stopping: bool,
+}
+
+struct Primary {
+    log: LogManager,
+    state: Mutex<PrimaryState>,
+    // signalled when records become durable and when a follower acknowledges or goes away
+    condvar: Condvar,
+}
+
+/// The primary's side of replication. Dropping it disconnects every follower
+pub struct ReplicationManager {
+    primary: Arc<Primary>,
+    addr: SocketAddr,
+    listener: Option<JoinHandle<()>>,
+}
+
+impl ReplicationManager {
+    /// Listen for followers on `addr` and ship them `log`. Bind to port 0 to let the OS pick one (see `local_addr`)
+    pub fn start<A: ToSocketAddrs>(log: LogManager, addr: A) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let primary = Arc::new(Primary {
+            log,
+            state: Mutex::new(PrimaryState {
+                streams: HashMap::new(),
+                followers: HashMap::new(),
+                next_id: 0,
+                mode: CommitMode::default(),
+                threads: Vec::new(),
+                stopping: false,
+            }),
+            condvar: Condvar::new(),
+        });
+        let handle = {
+            let primary = primary.clone();
+            std::thread::spawn(move || listen(&primary, listener))
+        };
+        Ok(ReplicationManager {
+            primary,
+            addr,
+            listener: Some(handle),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn set_commit_mode(&self, mode: CommitMode) {
+        self.primary.state.lock().mode = mode;
+    }
+
+    /// The followers connected now, in the order they connected
+    pub fn followers(&self) -> Vec<FollowerStatus> {
+        let state = self.primary.state.lock();
+        let mut ids: Vec<&usize> = state.followers.keys().collect();
+        ids.sort_unstable();
+        ids.into_iter()
+            .map(|id| state.followers[id].clone())
+            .collect()
+    }
+
+    /// Make the log durable up to `lsn` (normally a commit record) and ship it. In synchronous mode, also wait until a follower
+    /// has acknowledged it. A timed out commit is still durable on the primary
+    pub fn commit(&self, lsn: Lsn) -> std::io::Result<()> {
+        self.primary.log.flush_to_lsn(lsn)?;
+        self.primary.condvar.notify_all();
+        let mut state = self.primary.state.lock();
+        let CommitMode::Synchronous(timeout) = state.mode else {
+            return Ok(());
+        };
+        let deadline = Instant::now() + timeout;
+        while !state
+            .followers
+            .values()
+            .any(|follower| follower.acked_lsn >= lsn)
+        {
+            if self
+                .primary
+                .condvar
+                .wait_until(&mut state, deadline)
+                .timed_out()
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("no follower acknowledged lsn {} in time", lsn),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ReplicationManager {
+    fn drop(&mut self) {
+        {
+            let mut state = self.primary.state.lock();
+            state.stopping = true;
+            for stream in state.streams.values() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        self.primary.condvar.notify_all();
+        if let Some(handle) = self.listener.take() {
+            let _ = handle.join();
+        }
+        // nothing registers a thread once `stopping` is set
+        let threads = std::mem::take(&mut self.primary.state.lock().threads);
+        for handle in threads {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn listen(primary: &Arc<Primary>, listener: TcpListener) {
+    loop {
+        let accepted = listener.accept();
+        let mut state = primary.state.lock();
+        if state.stopping {
+            return;
+        }
+        let Ok((stream, addr)) = accepted else {
+            drop(state);
+            std::thread::sleep(POLL_INTERVAL);
+            continue;
+        };
+        let Ok(registered) = stream.try_clone() else {
+            continue;
+        };
+        let id = state.next_id;
+        state.next_id += 1;
+        state.streams.insert(id, registered);
+        let handle = {
+            let primary = primary.clone();
+            std::thread::spawn(move || serve(&primary, id, stream, addr))
+        };
+        state.threads.push(handle);
+    }
+}
+
+/// Ship the log to one follower until it disconnects or the primary stops
+fn serve(primary: &Arc<Primary>, id: usize, stream: TcpStream, addr: SocketAddr) {
+    let _ = ship(primary, id, stream, addr);
+    let mut state = primary.state.lock();
+    state.followers.remove(&id);
+    if let Some(stream) = state.streams.remove(&id) {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    primary.condvar.notify_all();
+}
+
+fn ship(
+    primary: &Arc<Primary>,
+    id: usize,
+    stream: TcpStream,
+    addr: SocketAddr,
+) -> std::io::Result<()> {
+    // accepted streams may inherit the listener's non-blocking mode
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    let mut reader = stream.try_clone()?;
+    let mut handshake = [0u8; 16];
+    reader.read_exact(&mut handshake)?;
+    if &handshake[..8] != LOG_MAGIC {
+        return Err(invalid_data(format!("{} isn't a follower", addr)));
+    }
+    let mut next = u64::from_le_bytes(handshake[8..].try_into().unwrap());
+    if next > primary.log.next_lsn() {
+        return Err(invalid_data(format!(
+            "follower {} is ahead of the primary",
+            addr
+        )));
+    }
+    {
+        let mut state = primary.state.lock();
+        if state.stopping {
+            return Ok(());
+        }
+        state.followers.insert(
+            id,
+            FollowerStatus {
+                addr,
+                sent_lsn: INVALID_LSN,
+                acked_lsn: INVALID_LSN,
+            },
+        );
+        let handle = {
+            let primary = primary.clone();
+            std::thread::spawn(move || acknowledgements(&primary, id, reader))
+        };
+        state.threads.push(handle);
+    }
+
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let durable = primary.log.persistent_lsn();
+        let mut sent = INVALID_LSN;
+        while next <= durable {
+            let record = primary
+                .log
+                .read_record(next)
+                .ok_or_else(|| invalid_data(format!("no log record at lsn {}", next)))?;
+            let framed = record::encode(&record);
+            writer.write_all(&framed)?;
+            sent = next;
+            next += framed.len() as Lsn;
+        }
+        if sent != INVALID_LSN {
+            writer.flush()?;
+        }
+        let mut state = primary.state.lock();
+        let Some(follower) = state.followers.get_mut(&id) else {
+            return Ok(());
+        };
+        if sent != INVALID_LSN {
+            follower.sent_lsn = follower.sent_lsn.max(sent);
+        }
+        if state.stopping {
+            return Ok(());
+        }
+        // woken by `commit`; records flushed any other way are picked up on the next poll
+        primary.condvar.wait_for(&mut state, POLL_INTERVAL);
+    }
+}
+
+/// Record a follower's acknowledgements until its connection closes
+fn acknowledgements(primary: &Primary, id: usize, mut stream: TcpStream) {
+    let mut ack = [0u8; 8];
+    while stream.read_exact(&mut ack).is_ok() {
+        let lsn = u64::from_le_bytes(ack);
+        if let Some(follower) = primary.state.lock().followers.get_mut(&id) {
+            follower.acked_lsn = follower.acked_lsn.max(lsn);
+            // the ack can beat the sender back to the lock after its flush
+            follower.sent_lsn = follower.sent_lsn.max(lsn);
+        }
+        primary.condvar.notify_all();
+    }
+    primary.state.lock().followers.remove(&id);
+    primary.condvar.notify_all();
+}
+
+struct FollowerState {
+    applied_lsn: Lsn,
+    connected: bool,
+    error: Option<String>,
+}
+
+/// The follower's side of replication. Dropping it disconnects from the primary, keeping everything applied so far
+pub struct Follower {
+    stream: TcpStream,
+    state: Arc<(Mutex<FollowerState>, Condvar)>,
+    handle: Option<JoinHandle<RecoveryManager>>,
+}
+
+impl Follower {
+    /// Follow the primary at `addr`, applying what it ships to `log` and `disk`. The log is replayed first, since the follower
+    /// may have crashed between logging a batch and writing back its pages; the primary then ships from the end of the log on
+    pub fn start<A: ToSocketAddrs>(
+        addr: A,
+        log: LogManager,
+        disk: DiskMgr,
+    ) -> std::io::Result<Self> {
+        let mut recovery = RecoveryManager::new(log.clone(), disk);
+        recovery.replay(&log.records(INVALID_LSN)?)?;
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut handshake = LOG_MAGIC.to_vec();
+        handshake.extend_from_slice(&log.next_lsn().to_le_bytes());
+        stream.write_all(&handshake)?;
+
+        let state = Arc::new((
+            Mutex::new(FollowerState {
+                applied_lsn: log.last_lsn(),
+                connected: true,
+                error: None,
+            }),
+            Condvar::new(),
+        ));
+        let handle = {
+            let stream = stream.try_clone()?;
+            let state = state.clone();
+            std::thread::spawn(move || {
+                let result = follow(&log, &mut recovery, stream, &state);
+                let mut guard = state.0.lock();
+                guard.connected = false;
+                // the connection closing is how following normally ends
+                guard.error = result
+                    .err()
+                    .filter(|err| err.kind() != std::io::ErrorKind::UnexpectedEof)
+                    .map(|err| err.to_string());
+                state.1.notify_all();
+                recovery
+            })
+        };
+        Ok(Follower {
+            stream,
+            state,
+            handle: Some(handle),
+        })
+    }
+
+    /// Last record applied
+    pub fn applied_lsn(&self) -> Lsn {
+        self.state.0.lock().applied_lsn
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state.0.lock().connected
+    }
+
+    /// Why the follower stopped following, if it was anything but the primary closing the connection
+    pub fn error(&self) -> Option<String> {
+        self.state.0.lock().error.clone()
+    }
+
+    /// Block until every record up to `lsn` has been applied. Returns false if that didn't happen within `timeout`, or the
+    /// follower disconnected first
+    pub fn wait_for(&self, lsn: Lsn, timeout: Duration) -> bool {
+        let (mutex, condvar) = &*self.state;
+        let deadline = Instant::now() + timeout;
+        let mut state = mutex.lock();
+        while state.applied_lsn < lsn {
+            if !state.connected || condvar.wait_until(&mut state, deadline).timed_out() {
+                return state.applied_lsn >= lsn;
+            }
+        }
+        true
+    }
+
+    /// Stop following and recover, rolling back every transaction the primary hadn't finished. The log and data file are then
+    /// ready to be served by a new primary
+    pub fn promote(mut self) -> std::io::Result<RecoveryStats> {
+        let mut recovery = self.stop().expect("follower is running");
+        recovery.recover()
+    }
+
+    fn stop(&mut self) -> Option<RecoveryManager> {
+        let _ = self.stream.shutdown(Shutdown::Both);
+        let handle = self.handle.take()?;
+        Some(handle.join().expect("follower thread panicked"))
+    }
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Apply what the primary ships, a batch at a time, until the connection closes
+fn follow(
+    log: &LogManager,
+    recovery: &mut RecoveryManager,
+    stream: TcpStream,
+    state: &(Mutex<FollowerState>, Condvar),
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    loop {
+        // block for one record, then take whatever else has already arrived
+        let mut batch = vec![receive(&mut reader)?];
+        while batch.len() < MAX_BATCH && frame_buffered(reader.buffer()) {
+            batch.push(receive(&mut reader)?);
+        }
+        for record in &batch {
+            let next = log.next_lsn();
+            if record.lsn != next {
+                return Err(invalid_data(format!(
+                    "primary sent lsn {}, but the log ends at {}",
+                    record.lsn, next
+                )));
+            }
+            log.append(record.txn_id, record.prev_lsn, record.body.clone())?;
+        }
+        recovery.replay(&batch)?;
+        let applied = batch.last().unwrap().lsn;
+        let (mutex, condvar) = state;
+        mutex.lock().applied_lsn = applied;
+        condvar.notify_all();
+        writer.write_all(&applied.to_le_bytes())?;
+    }
+}
+
+fn receive(reader: &mut impl BufRead) -> std::io::Result<LogRecord> {
+    let mut framed = vec![0u8; RECORD_HEADER_SIZE];
+    reader.read_exact(&mut framed)?;
+    let len = u32::from_le_bytes(framed[..4].try_into().unwrap()) as usize;
+    framed.resize(RECORD_HEADER_SIZE + len, 0);
+    reader.read_exact(&mut framed[RECORD_HEADER_SIZE..])?;
+    record::decode(&framed)
+        .map(|(record, _)| record)
+        .ok_or_else(|| invalid_data("corrupt log record from the primary".to_string()))
+}
+
+/// Whether `bytes` starts with a whole frame
+fn frame_buffered(bytes: &[u8]) -> bool {
+    bytes.len() >= RECORD_HEADER_SIZE
+        && bytes.len()
+            >= RECORD_HEADER_SIZE + u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::{cwd, PageId};
+    use crate::storage::buffer::diskmgr::DiskApi as _;
+    use crate::storage::buffer::page::{self, Page};
+    use crate::storage::log::record::LogBody;
+
+    const WAIT: Duration = Duration::from_secs(10);
+
+    fn update(page_id: PageId, after: u8) -> LogBody {
+        LogBody::Update {
+            page_id,
+            offset: 100,
+            before: vec![0],
+            after: vec![after],
+        }
+    }
+
+    fn read(disk: &DiskMgr, page_id: PageId) -> Page {
+        let mut buf = page::empty();
+        disk.read_page(&mut buf, page_id as u64).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_log_shipping() {
+        let dir = cwd() + "/tests/replication_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = LogManager::create(&(dir.clone() + "/primary.log")).unwrap();
+        let primary = ReplicationManager::start(log.clone(), "127.0.0.1:0").unwrap();
+
+        // nobody to acknowledge a synchronous commit yet, but it's durable on the primary all the same
+        primary.set_commit_mode(CommitMode::Synchronous(Duration::from_millis(20)));
+        let begin = log.append(1, INVALID_LSN, LogBody::Begin).unwrap();
+        let lsn = log.append(1, begin, update(0, 1)).unwrap();
+        let commit = log.append(1, lsn, LogBody::Commit).unwrap();
+        assert!(matches!(
+            primary.commit(commit),
+            Err(err) if err.kind() == std::io::ErrorKind::TimedOut
+        ));
+        assert!(log.persistent_lsn() == commit);
+
+        // a follower that connects late catches up from the start of the log
+        let follower_log = LogManager::create(&(dir.clone() + "/follower.log")).unwrap();
+        let disk = DiskMgr::create(&(dir.clone() + "/follower.bin")).unwrap();
+        let follower =
+            Follower::start(primary.local_addr(), follower_log.clone(), disk.clone()).unwrap();
+        assert!(follower.wait_for(commit, WAIT));
+        assert!(read(&disk, 0)[100] == 1);
+
+        // a synchronous commit returns once the follower has applied it
+        primary.set_commit_mode(CommitMode::Synchronous(WAIT));
+        let begin = log.append(2, INVALID_LSN, LogBody::Begin).unwrap();
+        let lsn = log.append(2, begin, update(1, 2)).unwrap();
+        let commit = log.append(2, lsn, LogBody::Commit).unwrap();
+        primary.commit(commit).unwrap();
+        assert!(follower.applied_lsn() >= commit);
+        assert!(read(&disk, 1)[100] == 2);
+        let followers = primary.followers();
+        assert!(followers.len() == 1);
+        assert!(followers[0].acked_lsn >= commit && followers[0].sent_lsn >= commit);
+
+        // txn 3 is still open when the primary goes away: the follower has its update, and rolls it back once promoted
+        let begin = log.append(3, INVALID_LSN, LogBody::Begin).unwrap();
+        let open = log.append(3, begin, update(2, 3)).unwrap();
+        log.flush().unwrap();
+        assert!(follower.wait_for(open, WAIT));
+        assert!(read(&disk, 2)[100] == 3);
+        drop(primary);
+        assert!(!follower.wait_for(open + 1, WAIT));
+        assert!(follower.error().is_none());
+        let stats = follower.promote().unwrap();
+        assert!(stats.losers == vec![3]);
+        assert!(read(&disk, 1)[100] == 2);
+        assert!(read(&disk, 2)[100] == 0);
+
+        // the follower's log is the primary's, followed by the rollback
+        let shipped = log.records(INVALID_LSN).unwrap();
+        let promoted = follower_log.records(INVALID_LSN).unwrap();
+        assert!(promoted[..shipped.len()] == shipped[..]);
+        assert!(matches!(promoted.last().unwrap().body, LogBody::End));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1 +1,9 @@
-mod buffer;
+pub mod backup;
+pub mod buffer;
+pub mod config;
+pub mod error;
+pub mod index;
+pub mod log;
+pub mod object_store;
+pub mod table;
+pub mod tuple;
@@ -0,0 +1,268 @@
+/// This file defines the object store interface used to move backups and archived files off the machine, along with a
+/// filesystem implementation and, behind the `s3` feature, one backed by S3 or an S3-compatible service. Keys are
+/// `/`-separated paths relative to the store's root; they may not be empty or contain `.` or `..` components.
+use std::io::Write;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "s3")]
+use std::sync::Arc;
+
+#[cfg(feature = "s3")]
+use futures::TryStreamExt as _;
+#[cfg(feature = "s3")]
+use object_store::aws::AmazonS3Builder;
+#[cfg(feature = "s3")]
+use object_store::path::Path as ObjectPath;
+#[cfg(feature = "s3")]
+use object_store::{ObjectStore as _, PutPayload};
+#[cfg(feature = "s3")]
+use tokio::runtime::{Builder, Runtime};
+
+pub trait ObjectStore: Send + Sync {
+    /// Store `data` under `key`, replacing any existing object. The object is either fully written or not visible at all
+    fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()>;
+    /// Fetch the object stored under `key`. Fails with `ErrorKind::NotFound` if there is none
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>>;
+    /// Every key starting with `prefix`, sorted
+    fn list(&self, prefix: &str) -> std::io::Result<Vec<String>>;
+    /// Delete the object stored under `key`. Deleting a missing object succeeds
+    fn delete(&self, key: &str) -> std::io::Result<()>;
+}
+
+/// Object store backed by a directory tree: the object `a/b` is the file `<root>/a/b`
+pub struct FsObjectStore {
+    root: PathBuf,
+}
+
+fn invalid_key(key: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("invalid object key {:?}", key),
+    )
+}
+
+fn check_key(key: &str) -> std::io::Result<()> {
+    let valid = !key.is_empty()
+        && key
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..");
+    if !valid {
+        return Err(invalid_key(key));
+    }
+    Ok(())
+}
+
+impl FsObjectStore {
+    pub fn open(root: &Path) -> std::io::Result<FsObjectStore> {
+        std::fs::create_dir_all(root)?;
+        Ok(FsObjectStore {
+            root: root.to_path_buf(),
+        })
+    }
+
+    fn path(&self, key: &str) -> std::io::Result<PathBuf> {
+        check_key(key)?;
+        Ok(key
+            .split('/')
+            .fold(self.root.clone(), |path, part| path.join(part)))
+    }
+
+    fn collect(&self, dir: &Path, prefix: &str, keys: &mut Vec<String>) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            // in-flight puts
+            if name.ends_with(".tmp") {
+                continue;
+            }
+            let key = if prefix.is_empty() {
+                name
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            if entry.file_type()?.is_dir() {
+                self.collect(&entry.path(), &key, keys)?;
+            } else {
+                keys.push(key);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ObjectStore for FsObjectStore {
+    /// Writes to a temporary file, syncs it, and renames it into place
+    fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        let path = self.path(key)?;
+        let dir = path.parent().unwrap();
+        std::fs::create_dir_all(dir)?;
+        let tmp = path.with_file_name(format!(
+            "{}.tmp",
+            path.file_name().unwrap().to_string_lossy()
+        ));
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &path)?;
+        std::fs::File::open(dir)?.sync_all()
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        std::fs::read(self.path(key)?)
+    }
+
+    fn list(&self, prefix: &str) -> std::io::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        self.collect(&self.root, "", &mut keys)?;
+        keys.retain(|key| key.starts_with(prefix));
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> std::io::Result<()> {
+        match std::fs::remove_file(self.path(key)?) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// Object store backed by an `object_store` implementation, normally S3 or an S3-compatible service (see `s3`). Its async
+/// calls are run to completion on a small runtime of the store's own, so callers stay synchronous and may call in from
+/// several threads at once
+#[cfg(feature = "s3")]
+pub struct RemoteObjectStore {
+    store: Arc<dyn object_store::ObjectStore>,
+    runtime: Runtime,
+}
+
+#[cfg(feature = "s3")]
+fn remote_error(err: object_store::Error) -> std::io::Error {
+    match err {
+        object_store::Error::NotFound { .. } => {
+            std::io::Error::new(std::io::ErrorKind::NotFound, err)
+        }
+        err => std::io::Error::other(err),
+    }
+}
+
+#[cfg(feature = "s3")]
+impl RemoteObjectStore {
+    /// Store objects in `bucket`, with the region, credentials and endpoint taken from the usual `AWS_*` environment
+    /// variables. Point `AWS_ENDPOINT` at an S3-compatible service (e.g. MinIO) to use that instead of AWS
+    pub fn s3(bucket: &str) -> std::io::Result<RemoteObjectStore> {
+        let store = AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .build()
+            .map_err(remote_error)?;
+        RemoteObjectStore::new(Arc::new(store))
+    }
+
+    pub fn new(store: Arc<dyn object_store::ObjectStore>) -> std::io::Result<RemoteObjectStore> {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("object-store")
+            .enable_all()
+            .build()?;
+        Ok(RemoteObjectStore { store, runtime })
+    }
+
+    fn path(key: &str) -> std::io::Result<ObjectPath> {
+        check_key(key)?;
+        ObjectPath::parse(key).map_err(|_| invalid_key(key))
+    }
+}
+
+#[cfg(feature = "s3")]
+impl ObjectStore for RemoteObjectStore {
+    /// A put is a single request, which the service applies atomically
+    fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        let path = RemoteObjectStore::path(key)?;
+        let payload = PutPayload::from(data.to_vec());
+        self.runtime
+            .block_on(self.store.put(&path, payload))
+            .map(|_| ())
+            .map_err(remote_error)
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        let path = RemoteObjectStore::path(key)?;
+        self.runtime
+            .block_on(async { self.store.get(&path).await?.bytes().await })
+            .map(|bytes| bytes.to_vec())
+            .map_err(remote_error)
+    }
+
+    /// Listing goes by whole path segments, so this lists everything under the last complete segment of `prefix` and keeps
+    /// the keys that start with the rest of it
+    fn list(&self, prefix: &str) -> std::io::Result<Vec<String>> {
+        let dir = prefix
+            .rfind('/')
+            .map(|end| ObjectPath::from(&prefix[..end]));
+        let objects: Vec<object_store::ObjectMeta> = self
+            .runtime
+            .block_on(self.store.list(dir.as_ref()).try_collect())
+            .map_err(remote_error)?;
+        let mut keys: Vec<String> = objects
+            .into_iter()
+            .map(|object| object.location.to_string())
+            .filter(|key| key.starts_with(prefix))
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> std::io::Result<()> {
+        let path = RemoteObjectStore::path(key)?;
+        match self.runtime.block_on(self.store.delete(&path)) {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(err) => Err(remote_error(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::cwd;
+
+    fn check_store(store: &dyn ObjectStore) {
+        store.put("backups/1/data.bin", b"pages").unwrap();
+        store.put("backups/1/backup_manifest", b"manifest").unwrap();
+        store.put("backups/2/data.bin", b"more pages").unwrap();
+        store.put("wal/000001", b"records").unwrap();
+        store.put("backups/1/data.bin", b"new pages").unwrap();
+
+        assert!(store.get("backups/1/data.bin").unwrap() == b"new pages");
+        assert!(
+            store.get("backups/3/data.bin").unwrap_err().kind() == std::io::ErrorKind::NotFound
+        );
+        assert!(
+            store.list("backups/1/").unwrap()
+                == vec!["backups/1/backup_manifest", "backups/1/data.bin"]
+        );
+        assert!(store.list("").unwrap().len() == 4);
+
+        store.delete("backups/2/data.bin").unwrap();
+        store.delete("backups/2/data.bin").unwrap();
+        assert!(store.list("backups/2").unwrap().is_empty());
+
+        for key in ["", "/abs", "a//b", "../escape", "a/./b"] {
+            assert!(store.put(key, b"x").unwrap_err().kind() == std::io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_fs_object_store() {
+        let dir = cwd() + "/tests/object_store_tests";
+        check_store(&FsObjectStore::open(Path::new(&dir)).unwrap());
+        std::fs::remove_dir_all(Path::new(&dir)).unwrap();
+    }
+
+    #[cfg(feature = "s3")]
+    #[test]
+    fn test_remote_object_store() {
+        let store =
+            RemoteObjectStore::new(Arc::new(object_store::memory::InMemory::new())).unwrap();
+        check_store(&store);
+    }
+}
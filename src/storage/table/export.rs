@@ -0,0 +1,167 @@
+/// This file implements writing a table heap back out as text, in either format `import` reads (see `import::Format`). The
+/// heap is streamed through a sequential scan, one row at a time, so a table of any size can be exported; rows come out in
+/// heap order, which need not be the order they were imported in. NULLs are written so that `import` reads them back as NULL:
+/// an empty unquoted field in CSV (the empty string is written as `""`) and `null` in JSON Lines.
+use std::io::Write;
+
+use crate::storage::table::heap::TableHeap;
+use crate::storage::table::import::Format;
+use crate::storage::tuple::{Schema, Tuple, Value};
+
+/// A CSV field, quoted if it has to be
+fn csv_field(text: &str) -> String {
+    if text.is_empty() || text.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", text.replace('"', "\"\""));
+    }
+    text.to_string()
+}
+
+/// A JSON string literal
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn write_row<W: Write>(
+    output: &mut W,
+    schema: &Schema,
+    format: Format,
+    tuple: &Tuple,
+) -> std::io::Result<()> {
+    let fields: Vec<String> = match format {
+        Format::Csv => tuple
+            .values()
+            .iter()
+            .map(|value| match value {
+                Value::Null => String::new(),
+                Value::Str(s) => csv_field(s),
+                value => value.to_string(),
+            })
+            .collect(),
+        Format::JsonLines => tuple
+            .values()
+            .iter()
+            .zip(schema.columns())
+            .map(|(value, column)| {
+                let value = match value {
+                    Value::Null => "null".to_string(),
+                    Value::Str(s) => json_string(s),
+                    value => value.to_string(),
+                };
+                format!("{}:{}", json_string(&column.name), value)
+            })
+            .collect(),
+    };
+    match format {
+        Format::Csv => writeln!(output, "{}", fields.join(",")),
+        Format::JsonLines => writeln!(output, "{{{}}}", fields.join(",")),
+    }
+}
+
+/// Write every row of `heap`, whose tuples were serialized with `schema`, to `output`, preceded by a header line for CSV.
+/// Returns the number of rows written. Fails with `InvalidData` on a tuple that doesn't match the schema
+pub fn export<W: Write>(
+    heap: &TableHeap,
+    schema: &Schema,
+    format: Format,
+    output: W,
+) -> std::io::Result<usize> {
+    let mut output = std::io::BufWriter::new(output);
+    if format == Format::Csv {
+        let names: Vec<String> = schema
+            .columns()
+            .iter()
+            .map(|column| csv_field(&column.name))
+            .collect();
+        writeln!(output, "{}", names.join(","))?;
+    }
+    let mut rows = 0;
+    for (rid, bytes) in heap.iter() {
+        let tuple = Tuple::deserialize(schema, &bytes).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("the tuple at {:?} doesn't match the schema", rid),
+            )
+        })?;
+        write_row(&mut output, schema, format, &tuple)?;
+        rows += 1;
+    }
+    output.flush()?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+    use crate::storage::table::import;
+    use crate::storage::tuple::{Column, ColumnType};
+
+    #[test]
+    fn test_export_round_trip() {
+        let dir = cwd() + "/tests/export_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir + "/test_export_round_trip.bin";
+        let pool = BufferPool::create(&path).unwrap();
+        let schema = Schema::new(vec![
+            Column::new("id", ColumnType::Int64),
+            Column::new("title", ColumnType::Varchar(100)).nullable(),
+            Column::new("explicit", ColumnType::Bool),
+        ]);
+        let titles = [
+            "Sweater Weather",
+            "",
+            "Car Radio, \"live\"",
+            "line\nbreak\ttab\u{1}",
+            "é🎵",
+        ];
+        let mut tuples: Vec<Tuple> = (0..200i64)
+            .map(|i| {
+                let title = match i % 6 {
+                    5 => Value::Null,
+                    n => Value::Str(titles[n as usize].to_string()),
+                };
+                Tuple::new(vec![Value::Int64(i - 100), title, Value::Bool(i % 3 == 0)])
+            })
+            .collect();
+        let heap = TableHeap::create(pool.clone()).unwrap();
+        for tuple in tuples.iter() {
+            heap.insert_tuple(&tuple.serialize(&schema).unwrap())
+                .unwrap();
+        }
+
+        tuples.sort_by_key(|tuple| tuple.to_string());
+        for format in [Format::Csv, Format::JsonLines] {
+            let mut text = Vec::new();
+            assert!(export(&heap, &schema, format, &mut text).unwrap() == 200);
+            let copy = TableHeap::create(pool.clone()).unwrap();
+            assert!(
+                import::import(&copy, &schema, format, text.as_slice())
+                    .unwrap()
+                    .len()
+                    == 200
+            );
+            let mut copied: Vec<Tuple> = copy
+                .iter()
+                .map(|(_, bytes)| Tuple::deserialize(&schema, &bytes).unwrap())
+                .collect();
+            copied.sort_by_key(|tuple| tuple.to_string());
+            assert!(copied == tuples);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
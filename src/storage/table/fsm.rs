@@ -0,0 +1,386 @@
+/// This file implements the free space map of a table heap: roughly how many bytes each heap page has free, so an insert can
+/// go straight to a page with room instead of walking the page chain. Free space is kept as a one-byte category per page,
+/// in units of `PAGE_SIZE / 256` bytes rounded down, and the pages of each category are indexed in memory, so finding a page
+/// for a tuple looks at no more than 256 categories however large the heap is.
+///
+/// The map is stored in a chain of its own pages, written through the buffer pool like any other page:
+///
+/// ```text
+/// | page header (24) | next map page (8) | entry count (2) | entries: heap page id (8) + category (1) ... |
+/// ```
+///
+/// It's only ever a hint. Nothing is logged for it, an insert re-checks the page it's pointed to and corrects the entry if
+/// it was wrong, and pages missing from the map are only found again by the append path or a rebuild. The map is loaded
+/// lazily on first use and rebuilt from a scan of the heap if its pages aren't there or don't look like map pages, e.g. a
+/// table opened without its map or after a crash that lost map pages.
+use std::collections::{BTreeSet, HashMap};
+
+use crate::shared::{PageId, INVALID_PAGE_ID, PAGE_SIZE};
+use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+use crate::storage::buffer::page::{self, Page, PageType, SlottedPage, PAGE_HEADER_SIZE};
+use crate::sync::{Latch as _, Synchronized};
+
+pub const NEXT_MAP_PAGE_OFFSET: usize = PAGE_HEADER_SIZE;
+pub const ENTRY_COUNT_OFFSET: usize = NEXT_MAP_PAGE_OFFSET + 8;
+pub const ENTRIES_OFFSET: usize = ENTRY_COUNT_OFFSET + 2;
+pub const ENTRY_SIZE: usize = 9;
+
+/// Heap pages covered by one page of the map
+pub const ENTRIES_PER_MAP_PAGE: usize = (PAGE_SIZE - ENTRIES_OFFSET) / ENTRY_SIZE;
+
+/// Bytes per free space category
+pub const CATEGORY_SIZE: usize = PAGE_SIZE / 256;
+
+/// The category of a page with `free` bytes free: the number of whole `CATEGORY_SIZE` units in it
+fn category(free: usize) -> u8 {
+    (free / CATEGORY_SIZE).min(u8::MAX as usize) as u8
+}
+
+/// The lowest category whose pages are sure to have `needed` bytes free, if there is one
+fn needed_category(needed: usize) -> Option<u8> {
+    u8::try_from(needed.div_ceil(CATEGORY_SIZE)).ok()
+}
+
+fn read_page_id(page: &Page, offset: usize) -> PageId {
+    i64::from_le_bytes(page[offset..offset + 8].try_into().unwrap()) as PageId
+}
+
+fn write_page_id(page: &mut Page, offset: usize, page_id: PageId) {
+    page[offset..offset + 8].copy_from_slice(&(page_id as i64).to_le_bytes());
+}
+
+fn entry_count(page: &Page) -> usize {
+    u16::from_le_bytes(
+        page[ENTRY_COUNT_OFFSET..ENTRY_COUNT_OFFSET + 2]
+            .try_into()
+            .unwrap(),
+    ) as usize
+}
+
+fn set_entry_count(page: &mut Page, count: usize) {
+    page[ENTRY_COUNT_OFFSET..ENTRY_COUNT_OFFSET + 2].copy_from_slice(&(count as u16).to_le_bytes());
+}
+
+fn init_map_page(page: &mut Page) {
+    page[PAGE_HEADER_SIZE..].fill(0);
+    page::set_page_type(page, PageType::FreeSpaceMap);
+    write_page_id(page, NEXT_MAP_PAGE_OFFSET, INVALID_PAGE_ID);
+}
+
+fn write_entry(page: &mut Page, index: usize, page_id: PageId, category: u8) {
+    let offset = ENTRIES_OFFSET + index * ENTRY_SIZE;
+    write_page_id(page, offset, page_id);
+    page[offset + 8] = category;
+}
+
+pub struct FreeSpaceMapInternal {
+    pool: BufferPool,
+    heap_first_page_id: PageId,
+    // the map's pages in chain order. Empty until the map is loaded, if it was opened without a root
+    map_pages: Vec<PageId>,
+    // heap page -> (entry index, category)
+    entries: HashMap<PageId, (usize, u8)>,
+    // heap pages of each category
+    by_category: Vec<BTreeSet<PageId>>,
+    loaded: bool,
+}
+
+impl FreeSpaceMapInternal {
+    fn track(&mut self, page_id: PageId, index: usize, category: u8) {
+        if let Some((_, old)) = self.entries.insert(page_id, (index, category)) {
+            self.by_category[old as usize].remove(&page_id);
+        }
+        self.by_category[category as usize].insert(page_id);
+    }
+
+    /// Read the map's chain of pages into memory. Returns false if the chain is missing or broken, with `map_pages` holding
+    /// the pages that were read, or at least the root, so a rebuild writes the map where it's expected to be
+    fn read_map(&mut self) -> bool {
+        let Some(&root) = self.map_pages.first() else {
+            return false;
+        };
+        self.map_pages.clear();
+        let mut map_page_id = root;
+        while map_page_id != INVALID_PAGE_ID {
+            let Ok(guard) = self.pool.fetch_page_read(map_page_id) else {
+                if self.map_pages.is_empty() {
+                    self.map_pages.push(root);
+                }
+                return false;
+            };
+            let count = entry_count(&guard);
+            if page::page_type(&guard) != Some(PageType::FreeSpaceMap)
+                || count > ENTRIES_PER_MAP_PAGE
+                || (count < ENTRIES_PER_MAP_PAGE
+                    && read_page_id(&guard, NEXT_MAP_PAGE_OFFSET) != INVALID_PAGE_ID)
+            {
+                if self.map_pages.is_empty() {
+                    self.map_pages.push(root);
+                }
+                return false;
+            }
+            self.map_pages.push(map_page_id);
+            let base = (self.map_pages.len() - 1) * ENTRIES_PER_MAP_PAGE;
+            for i in 0..count {
+                let offset = ENTRIES_OFFSET + i * ENTRY_SIZE;
+                self.track(read_page_id(&guard, offset), base + i, guard[offset + 8]);
+            }
+            map_page_id = read_page_id(&guard, NEXT_MAP_PAGE_OFFSET);
+        }
+        true
+    }
+
+    /// Replace the map with `spaces`, a (page, free bytes) pair for every heap page, reusing the map pages found so far
+    fn install(&mut self, spaces: Vec<(PageId, usize)>) -> Option<()> {
+        self.entries.clear();
+        self.by_category.iter_mut().for_each(BTreeSet::clear);
+        let mut reusable = std::mem::take(&mut self.map_pages).into_iter();
+        let chunks: Vec<_> = spaces.chunks(ENTRIES_PER_MAP_PAGE).collect();
+        for i in 0..chunks.len().max(1) {
+            let mut guard = match reusable
+                .next()
+                .map(|page_id| self.pool.fetch_page_write(page_id))
+            {
+                Some(Ok(guard)) => guard,
+                _ => self.pool.new_page_write().ok()?,
+            };
+            init_map_page(&mut guard);
+            let chunk = chunks.get(i).copied().unwrap_or_default();
+            for (j, (page_id, free)) in chunk.iter().enumerate() {
+                write_entry(&mut guard, j, *page_id, category(*free));
+                self.track(*page_id, i * ENTRIES_PER_MAP_PAGE + j, category(*free));
+            }
+            set_entry_count(&mut guard, chunk.len());
+            if let Some(&prev) = self.map_pages.last() {
+                let mut prev = self.pool.fetch_page_write(prev).ok()?;
+                write_page_id(&mut prev, NEXT_MAP_PAGE_OFFSET, guard.page_id());
+            }
+            self.map_pages.push(guard.page_id());
+        }
+        self.loaded = true;
+        Some(())
+    }
+
+    /// Record `category` for `page_id` in memory and in its map entry, appending an entry (and a map page) for a new page
+    fn set(&mut self, page_id: PageId, category: u8) -> Option<()> {
+        let index = match self.entries.get(&page_id) {
+            Some(&(_, old)) if old == category => return Some(()),
+            Some(&(index, _)) => index,
+            None => self.entries.len(),
+        };
+        let page_index = index / ENTRIES_PER_MAP_PAGE;
+        if page_index == self.map_pages.len() {
+            let mut next = self.pool.new_page_write().ok()?;
+            init_map_page(&mut next);
+            let mut last = self.pool.fetch_page_write(*self.map_pages.last()?).ok()?;
+            write_page_id(&mut last, NEXT_MAP_PAGE_OFFSET, next.page_id());
+            self.map_pages.push(next.page_id());
+        }
+        let mut guard = self
+            .pool
+            .fetch_page_write(self.map_pages[page_index])
+            .ok()?;
+        let entry = index % ENTRIES_PER_MAP_PAGE;
+        write_entry(&mut guard, entry, page_id, category);
+        if entry == entry_count(&guard) {
+            set_entry_count(&mut guard, entry + 1);
+        }
+        self.track(page_id, index, category);
+        Some(())
+    }
+}
+
+pub type FreeSpaceMap = Synchronized<FreeSpaceMapInternal>;
+
+pub trait FreeSpaceMapApi {
+    fn create(pool: BufferPool, heap_first_page_id: PageId) -> Option<Self>
+    where
+        Self: Sized;
+    fn open(pool: BufferPool, heap_first_page_id: PageId, root: PageId) -> Self;
+    fn root(&self) -> PageId;
+    fn find(&self, needed: usize) -> Option<PageId>;
+    fn update(&self, page_id: PageId, free: usize);
+    fn free_space(&self, page_id: PageId) -> Option<usize>;
+    fn rebuild(&self);
+}
+
+/// Load `map` if it isn't yet, from its pages or else by rebuilding it
+fn load(map: &FreeSpaceMap) {
+    {
+        let mut inner = map.lock();
+        if inner.loaded {
+            return;
+        }
+        if inner.read_map() {
+            inner.loaded = true;
+            return;
+        }
+    }
+    map.rebuild();
+}
+
+impl FreeSpaceMapApi for FreeSpaceMap {
+    /// Create an empty map, with a single page, for the heap starting at `heap_first_page_id`
+    fn create(pool: BufferPool, heap_first_page_id: PageId) -> Option<Self> {
+        let mut guard = pool.new_page_write().ok()?;
+        init_map_page(&mut guard);
+        let root = guard.page_id();
+        drop(guard);
+        let map = FreeSpaceMap::open(pool, heap_first_page_id, root);
+        map.lock().loaded = true;
+        Some(map)
+    }
+
+    /// Open the map rooted at `root`, or `INVALID_PAGE_ID` to build a new one, for the heap starting at
+    /// `heap_first_page_id`. Nothing is read until the map is first used
+    fn open(pool: BufferPool, heap_first_page_id: PageId, root: PageId) -> Self {
+        Synchronized::init(FreeSpaceMapInternal {
+            pool,
+            heap_first_page_id,
+            map_pages: if root == INVALID_PAGE_ID {
+                vec![]
+            } else {
+                vec![root]
+            },
+            entries: HashMap::new(),
+            by_category: vec![BTreeSet::new(); u8::MAX as usize + 1],
+            loaded: false,
+        })
+    }
+
+    /// The map's first page, `INVALID_PAGE_ID` if it hasn't been built yet
+    fn root(&self) -> PageId {
+        self.lock()
+            .map_pages
+            .first()
+            .copied()
+            .unwrap_or(INVALID_PAGE_ID)
+    }
+
+    /// A heap page that should have `needed` bytes free, the one with the least room to spare if there are several. Loads
+    /// the map first if need be, so it must not be called while holding a latch on a heap page
+    fn find(&self, needed: usize) -> Option<PageId> {
+        let needed = needed_category(needed)?;
+        load(self);
+        let inner = self.lock();
+        inner.by_category[needed as usize..]
+            .iter()
+            .find_map(|pages| pages.first().copied())
+    }
+
+    /// Note that heap page `page_id` now has `free` bytes free. Ignored until the map is loaded, since loading it will look at
+    /// the page anyway
+    fn update(&self, page_id: PageId, free: usize) {
+        let mut inner = self.lock();
+        if inner.loaded {
+            // if the map page can't be written the entry stays as it was, which is just a stale hint
+            let _ = inner.set(page_id, category(free));
+        }
+    }
+
+    /// Free bytes recorded for `page_id`, rounded down to a whole category
+    fn free_space(&self, page_id: PageId) -> Option<usize> {
+        load(self);
+        let inner = self.lock();
+        inner
+            .entries
+            .get(&page_id)
+            .map(|(_, category)| *category as usize * CATEGORY_SIZE)
+    }
+
+    /// Throw the map away and rebuild it from a scan of the heap. The heap is scanned without holding the map's latch, one
+    /// page at a time, so inserts carry on meanwhile; changes they make to pages already scanned are missed until they
+    /// touch those pages again
+    fn rebuild(&self) {
+        let (pool, first_page_id) = {
+            let inner = self.lock();
+            (inner.pool.clone(), inner.heap_first_page_id)
+        };
+        let mut spaces = Vec::new();
+        let mut page_id = first_page_id;
+        while page_id != INVALID_PAGE_ID {
+            let Ok(guard) = pool.fetch_page_read(page_id) else {
+                break;
+            };
+            let page = SlottedPage::new(guard.data());
+            spaces.push((page_id, page.reclaimable_space()));
+            page_id = page.next_page_id();
+        }
+        let mut inner = self.lock();
+        if inner.install(spaces).is_none() {
+            // left unloaded, so the next use tries again
+            inner.loaded = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::table::heap::TableHeap;
+
+    fn setup(name: &str) -> (BufferPool, String) {
+        let dir = cwd() + "/tests/fsm_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/{}.bin", dir, name);
+        (BufferPool::create(&path).unwrap(), path)
+    }
+
+    #[test]
+    fn test_find_and_update() {
+        let (pool, path) = setup("test_find_and_update");
+        let map = FreeSpaceMap::create(pool.clone(), INVALID_PAGE_ID).unwrap();
+        assert!(map.find(1).is_none());
+        map.update(10, 100);
+        map.update(11, 2000);
+        map.update(12, 500);
+        // the tightest fit
+        assert!(map.find(90) == Some(10));
+        assert!(map.find(101) == Some(12));
+        assert!(map.find(1000) == Some(11));
+        assert!(map.find(2001).is_none());
+        assert!(map.find(PAGE_SIZE * 2).is_none());
+        map.update(11, 0);
+        assert!(map.find(1000).is_none());
+        assert!(map.free_space(12) == Some(496));
+
+        // entries spill over to further map pages and are read back from them
+        for page_id in 100..100 + 2 * ENTRIES_PER_MAP_PAGE as PageId {
+            map.update(page_id, 3000);
+        }
+        let reopened = FreeSpaceMap::open(pool, INVALID_PAGE_ID, map.root());
+        assert!(reopened.free_space(11) == Some(0));
+        assert!(reopened.free_space(99 + 2 * ENTRIES_PER_MAP_PAGE as PageId) == Some(2992));
+        assert!(reopened.lock().map_pages.len() == 3);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_rebuild_from_heap() {
+        let (pool, path) = setup("test_rebuild_from_heap");
+        let heap = TableHeap::create(pool.clone()).unwrap();
+        let rids: Vec<_> = (0..200)
+            .map(|_| heap.insert_tuple(&[1; 100]).unwrap())
+            .collect();
+        let pages: BTreeSet<PageId> = rids.iter().map(|rid| rid.page_id).collect();
+
+        // a map whose root was overwritten is rebuilt from the heap
+        let mut root = pool.fetch_page_write(heap.fsm().root()).unwrap();
+        page::set_page_type(&mut root, PageType::Raw);
+        drop(root);
+        let map = FreeSpaceMap::open(pool.clone(), heap.first_page_id(), heap.fsm().root());
+        assert!(map.find(100).is_some());
+        assert!(map.root() == heap.fsm().root());
+        for page_id in pages.iter() {
+            let page = pool.fetch_page_read(*page_id).unwrap();
+            let free = SlottedPage::new(page.data()).reclaimable_space();
+            assert!(map.free_space(*page_id) == Some(free / CATEGORY_SIZE * CATEGORY_SIZE));
+        }
+        // so is one that was never built
+        let map = FreeSpaceMap::open(pool, heap.first_page_id(), INVALID_PAGE_ID);
+        assert!(map.find(100).is_some());
+        assert!(map.root() != INVALID_PAGE_ID);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,595 @@
+/// This file implements the table heap: an unordered collection of tuples stored in slotted pages that are chained together
+/// through the prev/next page ids in each page's header. A tuple is addressed by its `Rid`, the page it lives on and its slot
+/// on that page, which stays valid until the tuple is deleted. Inserts go to a page the heap's free space map (see `fsm`)
+/// says has room, and otherwise to the last page in the chain, with a new page linked in once it fills up; the link is made
+/// while the old last page is latched, so concurrent inserters that were waiting on it simply follow the next link.
+///
+/// Tuples longer than `OVERFLOW_THRESHOLD` are stored in overflow chains (see `overflow`), with a stub in their slot. Every
+/// read reassembles them through the buffer pool under the heap page's read latch, so they look like any other tuple, and
+/// deletes, updates and vacuum free the chains they replace.
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::shared::{PageId, INVALID_PAGE_ID, PAGE_SIZE};
+use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+use crate::storage::buffer::page::{Page, SlottedPage, SLOT_SIZE};
+use crate::storage::table::fsm::{FreeSpaceMap, FreeSpaceMapApi as _};
+use crate::storage::table::overflow::{self, Stub, OVERFLOW_THRESHOLD};
+
+/// Record id: where a tuple lives
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Rid {
+    pub page_id: PageId,
+    pub slot: u16,
+}
+
+impl Rid {
+    pub fn new(page_id: PageId, slot: u16) -> Self {
+        Rid { page_id, slot }
+    }
+
+    /// Index values are page ids, so a rid is packed into one to be indexed: the page id in the high bits, the slot in the
+    /// low 16
+    pub fn pack(self) -> PageId {
+        (self.page_id << 16) | self.slot as PageId
+    }
+
+    pub fn unpack(value: PageId) -> Rid {
+        Rid::new(value >> 16, (value & 0xffff) as u16)
+    }
+}
+
+/// What a vacuum pass did (see `TableHeap::vacuum`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VacuumStats {
+    /// Pages visited
+    pub pages: usize,
+    /// Tuples removed
+    pub removed: usize,
+    /// Pages whose records were packed together
+    pub compacted: usize,
+    /// Contiguous free bytes gained across all pages
+    pub reclaimed: usize,
+}
+
+/// What goes in a tuple's slot: the tuple itself, or the stub of the overflow chain it was written to
+enum Stored<'a> {
+    Inline(&'a [u8]),
+    Overflow(Stub),
+}
+
+impl Stored<'_> {
+    fn bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            Stored::Inline(tuple) => Cow::Borrowed(tuple),
+            Stored::Overflow(stub) => Cow::Owned(stub.encode().to_vec()),
+        }
+    }
+
+    /// Put the record in a new slot of `page`
+    fn insert<P: std::ops::DerefMut<Target = Page>>(
+        &self,
+        page: &mut SlottedPage<P>,
+    ) -> Option<u16> {
+        let slot = page.insert(&self.bytes())?;
+        page.set_overflow(slot, matches!(self, Stored::Overflow(_)));
+        Some(slot)
+    }
+
+    /// Put the record in `slot` of `page`, in place of what's there
+    fn update<P: std::ops::DerefMut<Target = Page>>(
+        &self,
+        page: &mut SlottedPage<P>,
+        slot: u16,
+    ) -> bool {
+        page.update(slot, &self.bytes())
+            && page.set_overflow(slot, matches!(self, Stored::Overflow(_)))
+    }
+
+    /// Free the overflow chain written for a record that didn't get stored after all
+    fn discard(self, pool: &BufferPool) {
+        if let Stored::Overflow(stub) = self {
+            overflow::free_chain(pool, stub.first_page_id);
+        }
+    }
+}
+
+/// The stub in `slot` of `page`, if the slot holds one
+fn stub<P: std::ops::Deref<Target = Page>>(page: &SlottedPage<P>, slot: u16) -> Option<Stub> {
+    if !page.is_overflow(slot) {
+        return None;
+    }
+    Stub::decode(page.get(slot)?)
+}
+
+#[derive(Clone)]
+pub struct TableHeap {
+    pool: BufferPool,
+    first_page_id: PageId,
+    // where inserts start looking for the end of the chain. Only a hint: it can lag behind the real last page
+    last_page_id: Arc<AtomicIsize>,
+    fsm: FreeSpaceMap,
+    // bytes of every page inserts leave free (see `with_fill_factor`)
+    reserved: usize,
+}
+
+impl TableHeap {
+    /// Create an empty heap consisting of a single page, and its free space map
+    pub fn create(pool: BufferPool) -> Option<Self> {
+        let mut guard = pool.new_page_write().ok()?;
+        SlottedPage::init(guard.data_mut());
+        let first_page_id = guard.page_id();
+        drop(guard);
+        let fsm = FreeSpaceMap::create(pool.clone(), first_page_id)?;
+        Some(TableHeap::open_with_fsm(pool, first_page_id, fsm.root()))
+    }
+
+    /// Open a heap created earlier, identified by its first page. It gets a new free space map, built from a scan of the
+    /// heap on the first insert; use `open_with_fsm` to keep using the one it had
+    pub fn open(pool: BufferPool, first_page_id: PageId) -> Self {
+        TableHeap::open_with_fsm(pool, first_page_id, INVALID_PAGE_ID)
+    }
+
+    /// Open a heap created earlier along with its free space map, identified by the map's first page (`fsm().root()`)
+    pub fn open_with_fsm(pool: BufferPool, first_page_id: PageId, fsm_page_id: PageId) -> Self {
+        TableHeap {
+            fsm: FreeSpaceMap::open(pool.clone(), first_page_id, fsm_page_id),
+            pool,
+            first_page_id,
+            last_page_id: Arc::new(AtomicIsize::new(first_page_id)),
+            reserved: 0,
+        }
+    }
+
+    /// Fill pages only up to `fill_factor` percent on insert, leaving the rest for the tuples already on them to grow into.
+    /// Updates that stay on their page may use the reserved space. A tuple that doesn't fit under the fill factor on any page
+    /// still gets a new page of its own. Not persisted: a reopened heap fills pages completely unless it's set again
+    pub fn with_fill_factor(mut self, fill_factor: u8) -> Self {
+        assert!((1..=100).contains(&fill_factor));
+        self.reserved = PAGE_SIZE * (100 - fill_factor as usize) / 100;
+        self
+    }
+
+    pub fn first_page_id(&self) -> PageId {
+        self.first_page_id
+    }
+
+    pub fn pool(&self) -> &BufferPool {
+        &self.pool
+    }
+
+    pub fn fsm(&self) -> &FreeSpaceMap {
+        &self.fsm
+    }
+
+    /// Write `tuple` to an overflow chain if it's too long to go in a slot
+    fn store<'a>(&self, tuple: &'a [u8]) -> Option<Stored<'a>> {
+        if tuple.len() <= OVERFLOW_THRESHOLD {
+            return Some(Stored::Inline(tuple));
+        }
+        overflow::write_chain(&self.pool, tuple)
+            .ok()
+            .map(Stored::Overflow)
+    }
+
+    /// The tuple in `slot` of `page`, read back from its overflow chain if it has one
+    fn load<'a, P: std::ops::Deref<Target = Page>>(
+        &self,
+        page: &'a SlottedPage<P>,
+        slot: u16,
+    ) -> Option<Cow<'a, [u8]>> {
+        let record = page.get(slot)?;
+        match stub(page, slot) {
+            Some(stub) => overflow::read_chain(&self.pool, stub).ok().map(Cow::Owned),
+            None => Some(Cow::Borrowed(record)),
+        }
+    }
+
+    /// Store `tuple` and return its record id. Returns `None` if the tuple is empty, or a page was needed for it or its
+    /// overflow chain and couldn't be allocated.
+    pub fn insert_tuple(&self, tuple: &[u8]) -> Option<Rid> {
+        if tuple.is_empty() {
+            return None;
+        }
+        let stored = self.store(tuple)?;
+        let rid = self.insert_stored(&stored);
+        if rid.is_none() {
+            stored.discard(&self.pool);
+        }
+        rid
+    }
+
+    /// Put `stored` in a new slot of `page`, unless that would fill the page past the fill factor
+    fn insert_within_fill<P: std::ops::DerefMut<Target = Page>>(
+        &self,
+        stored: &Stored,
+        page: &mut SlottedPage<P>,
+    ) -> Option<u16> {
+        if page.reclaimable_space() < stored.bytes().len() + SLOT_SIZE + self.reserved {
+            return None;
+        }
+        stored.insert(page)
+    }
+
+    fn insert_stored(&self, stored: &Stored) -> Option<Rid> {
+        let len = stored.bytes().len();
+        // the map is only a hint: a page it suggests that turns out to be full gets its entry corrected, and the next one is
+        // tried
+        while let Some(page_id) = self.fsm.find(len + SLOT_SIZE + self.reserved) {
+            let Ok(mut guard) = self.pool.fetch_page_write(page_id) else {
+                self.fsm.update(page_id, 0);
+                continue;
+            };
+            let mut page = SlottedPage::new(guard.data_mut());
+            let slot = self.insert_within_fill(stored, &mut page);
+            self.fsm.update(page_id, page.reclaimable_space());
+            if let Some(slot) = slot {
+                return Some(Rid::new(page_id, slot));
+            }
+        }
+        let mut page_id = self.last_page_id.load(Ordering::Acquire);
+        loop {
+            let mut guard = self.pool.fetch_page_write(page_id).ok()?;
+            let next_page_id = SlottedPage::new(guard.data()).next_page_id();
+            if next_page_id != INVALID_PAGE_ID {
+                page_id = next_page_id;
+                continue;
+            }
+            let mut page = SlottedPage::new(guard.data_mut());
+            if let Some(slot) = self.insert_within_fill(stored, &mut page) {
+                self.fsm.update(page_id, page.reclaimable_space());
+                return Some(Rid::new(page_id, slot));
+            }
+            self.fsm.update(page_id, page.reclaimable_space());
+            // the last page is full: link in a new one while still holding it
+            let mut next = self.pool.new_page_write().ok()?;
+            let mut page = SlottedPage::init(next.data_mut());
+            page.set_prev_page_id(page_id);
+            let slot = stored.insert(&mut page).unwrap();
+            let free = page.reclaimable_space();
+            self.fsm.update(next.page_id(), free);
+            SlottedPage::new(guard.data_mut()).set_next_page_id(next.page_id());
+            self.last_page_id.store(next.page_id(), Ordering::Release);
+            return Some(Rid::new(next.page_id(), slot));
+        }
+    }
+
+    /// A copy of the tuple at `rid`, if there is one
+    pub fn get_tuple(&self, rid: Rid) -> Option<Vec<u8>> {
+        self.read_tuple(rid, <[u8]>::to_vec)
+    }
+
+    /// Remove the tuple at `rid`, and free its overflow chain if it has one. Returns false if there's no tuple there
+    pub fn delete_tuple(&self, rid: Rid) -> bool {
+        let Ok(mut guard) = self.pool.fetch_page_write(rid.page_id) else {
+            return false;
+        };
+        let mut page = SlottedPage::new(guard.data_mut());
+        let stub = stub(&page, rid.slot);
+        if !page.delete(rid.slot) {
+            return false;
+        }
+        self.fsm.update(rid.page_id, page.reclaimable_space());
+        drop(guard);
+        if let Some(stub) = stub {
+            overflow::free_chain(&self.pool, stub.first_page_id);
+        }
+        true
+    }
+
+    /// Hand the tuple at `rid` to `f` under its page's read latch, rather than copying it out (tuples in overflow chains are
+    /// still copied, to put them back together). Returns `None` if there's no tuple there
+    pub fn read_tuple<R>(&self, rid: Rid, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        let guard = self.pool.fetch_page_read(rid.page_id).ok()?;
+        let page = SlottedPage::new(guard.data());
+        self.load(&page, rid.slot).map(|tuple| f(&tuple))
+    }
+
+    /// Hand a copy of the tuple at `rid` to `f` under its page's write latch, and store the copy in its place if `f` changed
+    /// it, so nothing else can change the tuple in between. Returns `None` if there's no tuple there or the changed tuple
+    /// doesn't fit on its page, in which case the tuple is left as it was
+    pub fn modify_tuple<R>(&self, rid: Rid, f: impl FnOnce(&mut Vec<u8>) -> R) -> Option<R> {
+        let mut guard = self.pool.fetch_page_write(rid.page_id).ok()?;
+        let page = SlottedPage::new(guard.data());
+        let old = self.load(&page, rid.slot)?.into_owned();
+        let old_stub = stub(&page, rid.slot);
+        let mut tuple = old.clone();
+        let result = f(&mut tuple);
+        if tuple != old {
+            let stored = self.store(&tuple)?;
+            if !stored.update(&mut SlottedPage::new(guard.data_mut()), rid.slot) {
+                stored.discard(&self.pool);
+                return None;
+            }
+            drop(guard);
+            if let Some(stub) = old_stub {
+                overflow::free_chain(&self.pool, stub.first_page_id);
+            }
+        }
+        Some(result)
+    }
+
+    /// Replace the tuple at `rid` in place. Returns false if there's no tuple there or the new tuple doesn't fit on its page
+    pub fn update_tuple(&self, rid: Rid, tuple: &[u8]) -> bool {
+        let Some(stored) = self.store(tuple) else {
+            return false;
+        };
+        let Ok(mut guard) = self.pool.fetch_page_write(rid.page_id) else {
+            stored.discard(&self.pool);
+            return false;
+        };
+        let mut page = SlottedPage::new(guard.data_mut());
+        let old_stub = stub(&page, rid.slot);
+        if !stored.update(&mut page, rid.slot) {
+            drop(guard);
+            stored.discard(&self.pool);
+            return false;
+        }
+        drop(guard);
+        if let Some(stub) = old_stub {
+            overflow::free_chain(&self.pool, stub.first_page_id);
+        }
+        true
+    }
+
+    /// Remove the tuples at `dead`, freeing their overflow chains, and compact every page that has space to reclaim, from
+    /// deleted tuples or shrunken updates, one page at a time under its write latch, and record each page's free space in the
+    /// free space map. Rids of the tuples that remain don't change
+    pub fn vacuum(&self, dead: &HashSet<Rid>) -> VacuumStats {
+        let mut stats = VacuumStats::default();
+        let mut page_id = self.first_page_id;
+        while page_id != INVALID_PAGE_ID {
+            let Ok(mut guard) = self.pool.fetch_page_write(page_id) else {
+                break;
+            };
+            let mut page = SlottedPage::new(guard.data_mut());
+            let free = page.free_space();
+            for slot in page.slots() {
+                if !dead.contains(&Rid::new(page_id, slot)) {
+                    continue;
+                }
+                let stub = stub(&page, slot);
+                if page.delete(slot) {
+                    stats.removed += 1;
+                    if let Some(stub) = stub {
+                        overflow::free_chain(&self.pool, stub.first_page_id);
+                    }
+                }
+            }
+            if page.reclaimable_space() > page.free_space() {
+                page.compact();
+                stats.compacted += 1;
+            }
+            stats.reclaimed += page.free_space().saturating_sub(free);
+            self.fsm.update(page_id, page.reclaimable_space());
+            stats.pages += 1;
+            page_id = page.next_page_id();
+        }
+        stats
+    }
+
+    /// Iterate over every tuple in the heap, page by page
+    pub fn iter(&self) -> TableIterator {
+        self.filter_map(|rid, tuple| Some((rid, tuple.to_vec())))
+    }
+
+    /// Iterate over the heap like `iter`, handing each tuple to `f` under its page's read latch and returning what `f` makes
+    /// of the tuples it doesn't skip
+    pub fn filter_map<T>(
+        &self,
+        f: impl FnMut(Rid, &[u8]) -> Option<T> + Send + 'static,
+    ) -> TableIterator<T> {
+        TableIterator {
+            heap: self.clone(),
+            buffer: VecDeque::new(),
+            next_page_id: self.first_page_id,
+            f: Box::new(f),
+        }
+    }
+}
+
+/// What a `TableIterator` makes of each tuple, if it doesn't skip it
+type TupleFilter<T> = Box<dyn FnMut(Rid, &[u8]) -> Option<T> + Send>;
+
+/// Sequential scan over a table heap. Like the index iterator, it copies one page's tuples at a time under the page's read
+/// latch and holds no latch between calls. Tuples inserted into pages the scan has already passed are not returned.
+pub struct TableIterator<T = (Rid, Vec<u8>)> {
+    heap: TableHeap,
+    buffer: VecDeque<T>,
+    next_page_id: PageId,
+    f: TupleFilter<T>,
+}
+
+impl<T> Iterator for TableIterator<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        while self.buffer.is_empty() {
+            if self.next_page_id == INVALID_PAGE_ID {
+                return None;
+            }
+            let page_id = self.next_page_id;
+            let guard = self.heap.pool.fetch_page_read(page_id).ok()?;
+            let page = SlottedPage::new(guard.data());
+            self.next_page_id = page.next_page_id();
+            for slot in page.slots() {
+                let tuple = self.heap.load(&page, slot)?;
+                self.buffer
+                    .extend((self.f)(Rid::new(page_id, slot), &tuple));
+            }
+        }
+        self.buffer.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rayon::ThreadPoolBuilder;
+
+    use super::*;
+    use crate::shared::{cwd, PAGE_SIZE};
+    use crate::storage::buffer::{freemap, io};
+    use crate::testing::Song;
+
+    fn setup(name: &str) -> (BufferPool, String) {
+        let dir = cwd() + "/tests/heap_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/{}.bin", dir, name);
+        (BufferPool::create(&path).unwrap(), path)
+    }
+
+    fn cleanup(path: &str) {
+        std::fs::remove_file(path).unwrap();
+        let _ = std::fs::remove_file(freemap::map_path(path));
+    }
+
+    #[test]
+    fn test_insert_get_delete() {
+        let (pool, path) = setup("test_insert_get_delete");
+        let heap = TableHeap::create(pool.clone()).unwrap();
+        let mut rids = Vec::new();
+        for i in 0..500 {
+            let song = Song::new(i, "Sweater Weather", "The Neighbourhood");
+            rids.push(heap.insert_tuple(&io::encode(song).unwrap()).unwrap());
+        }
+        // the tuples spill over onto more pages
+        assert!(rids.last().unwrap().page_id != heap.first_page_id());
+        for (i, rid) in rids.iter().enumerate() {
+            let song: Song = io::decode(heap.get_tuple(*rid).unwrap()).unwrap();
+            assert!(song.id == i as i32);
+        }
+
+        for rid in rids.iter().step_by(2) {
+            assert!(heap.delete_tuple(*rid));
+        }
+        assert!(!heap.delete_tuple(rids[0]));
+        assert!(heap.get_tuple(rids[0]).is_none());
+        let updated = io::encode(Song::new(-1, "Softcore", "The Neighbourhood")).unwrap();
+        assert!(heap.update_tuple(rids[1], &updated));
+        assert!(heap.insert_tuple(&[]).is_none());
+
+        // a reopened heap scans the same tuples in page order
+        pool.flush_all().unwrap();
+        let heap = TableHeap::open(pool, heap.first_page_id());
+        let ids: Vec<i32> = heap
+            .iter()
+            .map(|(_, tuple)| io::decode::<Song>(tuple).unwrap().id)
+            .collect();
+        let expected: Vec<i32> = std::iter::once(-1).chain((3..500).step_by(2)).collect();
+        assert!(ids == expected);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_large_tuples() {
+        let (pool, path) = setup("test_large_tuples");
+        let heap = TableHeap::create(pool.clone()).unwrap();
+        let large = |seed: u8, len: usize| -> Vec<u8> {
+            (0..len).map(|i| seed.wrapping_add(i as u8)).collect()
+        };
+        let small = heap.insert_tuple(b"sweater weather").unwrap();
+        let blob = heap.insert_tuple(&large(1, PAGE_SIZE * 3)).unwrap();
+        let medium = heap
+            .insert_tuple(&large(2, OVERFLOW_THRESHOLD + 1))
+            .unwrap();
+        // the stubs sit next to the small tuple on the first page
+        assert!(blob.page_id == small.page_id && medium.page_id == small.page_id);
+        assert!(heap.get_tuple(blob).unwrap() == large(1, PAGE_SIZE * 3));
+        assert!(heap.read_tuple(medium, |tuple| tuple.len()) == Some(OVERFLOW_THRESHOLD + 1));
+        let scanned: Vec<usize> = heap.iter().map(|(_, tuple)| tuple.len()).collect();
+        assert!(scanned == vec![15, PAGE_SIZE * 3, OVERFLOW_THRESHOLD + 1]);
+
+        // deleting a tuple frees its chain for reuse
+        let doomed = heap.insert_tuple(&large(4, PAGE_SIZE)).unwrap();
+        let high_water = pool.alloc_page().unwrap();
+        assert!(heap.delete_tuple(doomed));
+        assert!(pool.alloc_page().unwrap() < high_water);
+
+        // updates move tuples in and out of overflow chains
+        assert!(heap.update_tuple(small, &large(3, PAGE_SIZE * 2)));
+        assert!(heap.get_tuple(small).unwrap() == large(3, PAGE_SIZE * 2));
+        assert!(heap.update_tuple(medium, b"softcore"));
+        assert!(heap.get_tuple(medium).unwrap() == b"softcore");
+        let len = heap.modify_tuple(blob, |tuple| {
+            tuple.push(0);
+            tuple.len()
+        });
+        assert!(len == Some(PAGE_SIZE * 3 + 1));
+        assert!(heap.get_tuple(blob).unwrap().len() == PAGE_SIZE * 3 + 1);
+
+        assert!(heap.delete_tuple(blob));
+        assert!(heap.get_tuple(blob).is_none());
+
+        // the stubs survive compaction and a reopen
+        let vacuumed = heap.vacuum(&HashSet::from([medium]));
+        assert!(vacuumed.removed == 1 && vacuumed.compacted == 1);
+        pool.flush_all().unwrap();
+        let heap = TableHeap::open(pool, heap.first_page_id());
+        assert!(heap.get_tuple(small).unwrap() == large(3, PAGE_SIZE * 2));
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_inserts_reuse_free_space() {
+        let (pool, path) = setup("test_inserts_reuse_free_space");
+        let heap = TableHeap::create(pool.clone()).unwrap();
+        let rids: Vec<Rid> = (0..400)
+            .map(|_| heap.insert_tuple(&[1; 100]).unwrap())
+            .collect();
+        let pages: HashSet<PageId> = rids.iter().map(|rid| rid.page_id).collect();
+
+        // the space freed on the first page is found without walking the chain, and used before a new page is linked in
+        let freed: Vec<&Rid> = rids
+            .iter()
+            .filter(|rid| rid.page_id == heap.first_page_id())
+            .collect();
+        for rid in freed.iter() {
+            assert!(heap.delete_tuple(**rid));
+        }
+        let reused: Vec<Rid> = (0..freed.len())
+            .map(|_| heap.insert_tuple(&[2; 100]).unwrap())
+            .collect();
+        assert!(reused.iter().all(|rid| pages.contains(&rid.page_id)));
+        assert!(reused.iter().any(|rid| rid.page_id == heap.first_page_id()));
+
+        // a heap reopened with its map keeps using it, one reopened without gets a new one built from the pages
+        pool.flush_all().unwrap();
+        let reopened =
+            TableHeap::open_with_fsm(pool.clone(), heap.first_page_id(), heap.fsm().root());
+        assert!(reopened.fsm().root() == heap.fsm().root());
+        let reopened = TableHeap::open(pool, heap.first_page_id());
+        assert!(pages.contains(&reopened.insert_tuple(&[3; 100]).unwrap().page_id));
+        assert!(reopened.fsm().root() != heap.fsm().root());
+        cleanup(&path);
+    }
+
+    #[test]
+    fn test_concurrent_inserts() {
+        let (pool, path) = setup("test_concurrent_inserts");
+        let heap = TableHeap::create(pool).unwrap();
+        let threads = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        threads.scope(|s| {
+            for t in 0..4u32 {
+                let heap = heap.clone();
+                s.spawn(move |_| {
+                    for i in 0..300u32 {
+                        let tuple = (t * 1000 + i).to_le_bytes();
+                        let rid = heap.insert_tuple(&tuple).unwrap();
+                        assert!(heap.get_tuple(rid).unwrap() == tuple);
+                    }
+                });
+            }
+        });
+        let tuples: Vec<(Rid, Vec<u8>)> = heap.iter().collect();
+        assert!(tuples.len() == 1200);
+        let rids: HashSet<Rid> = tuples.iter().map(|(rid, _)| *rid).collect();
+        assert!(rids.len() == 1200);
+        cleanup(&path);
+    }
+}
@@ -0,0 +1,630 @@
+/// This file implements bulk loading rows from text into a table heap. Two formats are understood:
+///
+/// - CSV: a header line naming the columns, in any order, then one record per row. Fields may be quoted, with `""` for a
+///   quote inside a quoted field, and quoted fields may span lines. An empty unquoted field is NULL; `""` is the empty string
+/// - JSON Lines: one flat object per line, mapping column names to strings, numbers, booleans or null
+///
+/// Columns left out of the header or an object are NULL, so only nullable columns may be left out. Every row is checked
+/// against the `Schema` and serialized as a `Tuple`; the first row that doesn't fit stops the import with an
+/// `InvalidData` error naming its line, and the rows inserted before it stay in the heap. `import_with_index` also bulk loads
+/// a B-link tree over one column, mapping each row's key (see `index_key`) to its packed rid (see `Rid::pack`).
+use std::io::BufRead;
+
+use crate::shared::PageId;
+use crate::storage::index::blink::{BLinkTree, Key, KEY_SIZE};
+use crate::storage::table::heap::{Rid, TableHeap};
+use crate::storage::tuple::{Column, ColumnType, Schema, Tuple, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    JsonLines,
+}
+
+/// A field as read from the input, before it's checked against its column
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Field {
+    Null,
+    Text(String),
+    Bool(bool),
+}
+
+fn invalid(line: usize, message: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("line {}: {}", line, message),
+    )
+}
+
+/// Turn a field into a value of `column`'s type
+fn convert(field: Field, column: &Column) -> Result<Value, String> {
+    let text = match field {
+        Field::Null if column.nullable => return Ok(Value::Null),
+        Field::Null => return Err(format!("column {} can't be null", column.name)),
+        Field::Bool(b) if column.column_type == ColumnType::Bool => return Ok(Value::Bool(b)),
+        Field::Bool(b) => b.to_string(),
+        Field::Text(text) => text,
+    };
+    let bad = || {
+        format!(
+            "{:?} isn't a valid {:?} for column {}",
+            text, column.column_type, column.name
+        )
+    };
+    match column.column_type {
+        ColumnType::Bool => match text.as_str() {
+            "true" | "t" | "1" => Ok(Value::Bool(true)),
+            "false" | "f" | "0" => Ok(Value::Bool(false)),
+            _ => Err(bad()),
+        },
+        ColumnType::Int32 => text.parse().map(Value::Int32).map_err(|_| bad()),
+        ColumnType::Int64 => text.parse().map(Value::Int64).map_err(|_| bad()),
+        ColumnType::Char(len) | ColumnType::Varchar(len) if text.len() > len as usize => {
+            Err(format!(
+                "{:?} is longer than the {} bytes column {} holds",
+                text, len, column.name
+            ))
+        }
+        ColumnType::Char(_) | ColumnType::Varchar(_) => Ok(Value::Str(text)),
+    }
+}
+
+/// Split one CSV record into its fields. Quoted fields come back as text even when empty, unquoted empty fields as NULL
+fn split_csv(record: &str) -> Result<Vec<Field>, String> {
+    let mut fields = Vec::new();
+    let mut chars = record.chars().peekable();
+    loop {
+        let mut text = String::new();
+        let field = if chars.next_if_eq(&'"').is_some() {
+            loop {
+                match chars.next() {
+                    Some('"') if chars.next_if_eq(&'"').is_some() => text.push('"'),
+                    Some('"') => break,
+                    Some(c) => text.push(c),
+                    None => return Err("unterminated quoted field".to_string()),
+                }
+            }
+            Field::Text(text)
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ',') {
+                if c == '"' {
+                    return Err("quote inside an unquoted field".to_string());
+                }
+                text.push(c);
+            }
+            if text.is_empty() {
+                Field::Null
+            } else {
+                Field::Text(text)
+            }
+        };
+        fields.push(field);
+        match chars.next() {
+            None => return Ok(fields),
+            Some(',') => {}
+            Some(c) => return Err(format!("unexpected {:?} after a quoted field", c)),
+        }
+    }
+}
+
+/// A parser for the single flat object on a JSON Lines line
+struct JsonObject<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl JsonObject<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("expected {:?}, found {:?}", expected, c)),
+            None => Err(format!(
+                "expected {:?}, found the end of the line",
+                expected
+            )),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut text = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(text),
+                Some('\\') => {
+                    let c = match self.chars.next() {
+                        Some(c @ ('"' | '\\' | '/')) => c,
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => self.unicode_escape()?,
+                        _ => return Err("invalid escape in a string".to_string()),
+                    };
+                    text.push(c);
+                }
+                Some(c) => text.push(c),
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+    }
+
+    /// The character of a `\u` escape, whose `\u` has been read. Characters outside the basic plane are written as a
+    /// surrogate pair of escapes
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.hex4()?;
+        if !(0xd800..0xdc00).contains(&high) {
+            return char::from_u32(high).ok_or_else(|| "invalid \\u escape".to_string());
+        }
+        if self.chars.next() != Some('\\') || self.chars.next() != Some('u') {
+            return Err("unpaired surrogate in a \\u escape".to_string());
+        }
+        let low = self.hex4()?;
+        if !(0xdc00..0xe000).contains(&low) {
+            return Err("unpaired surrogate in a \\u escape".to_string());
+        }
+        char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00))
+            .ok_or_else(|| "invalid \\u escape".to_string())
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits: String = (0..4).filter_map(|_| self.chars.next()).collect();
+        u32::from_str_radix(&digits, 16).map_err(|_| "invalid \\u escape".to_string())
+    }
+
+    fn value(&mut self) -> Result<Field, String> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some('"') => self.string().map(Field::Text),
+            Some('{' | '[') => Err("nested objects and arrays aren't supported".to_string()),
+            Some(_) => {
+                let mut word = String::new();
+                while let Some(c) = self
+                    .chars
+                    .next_if(|c| c.is_alphanumeric() || "+-.".contains(*c))
+                {
+                    word.push(c);
+                }
+                match word.as_str() {
+                    "null" => Ok(Field::Null),
+                    "true" => Ok(Field::Bool(true)),
+                    "false" => Ok(Field::Bool(false)),
+                    _ if word.starts_with(|c: char| c == '-' || c.is_ascii_digit()) => {
+                        Ok(Field::Text(word))
+                    }
+                    _ => Err(format!("invalid value {:?}", word)),
+                }
+            }
+            None => Err("expected a value, found the end of the line".to_string()),
+        }
+    }
+
+    /// Parse `line` as an object, returning its members in order
+    fn parse(line: &str) -> Result<Vec<(String, Field)>, String> {
+        let mut object = JsonObject {
+            chars: line.chars().peekable(),
+        };
+        let mut members = Vec::new();
+        object.expect('{')?;
+        object.skip_whitespace();
+        if object.chars.next_if_eq(&'}').is_none() {
+            loop {
+                let name = object.string()?;
+                object.expect(':')?;
+                members.push((name, object.value()?));
+                object.skip_whitespace();
+                match object.chars.next() {
+                    Some(',') => continue,
+                    Some('}') => break,
+                    _ => return Err("expected ',' or '}' after a member".to_string()),
+                }
+            }
+        }
+        object.skip_whitespace();
+        if object.chars.next().is_some() {
+            return Err("unexpected text after the object".to_string());
+        }
+        Ok(members)
+    }
+}
+
+/// The rows of a CSV or JSON Lines input as tuples of a schema, each with the line it starts on. See `rows`
+pub struct Rows<'a, R> {
+    input: R,
+    schema: &'a Schema,
+    format: Format,
+    // next line to be read
+    line: usize,
+    // for CSV, where each column's field is in a record, from the header
+    positions: Option<Vec<Option<usize>>>,
+    failed: bool,
+}
+
+impl<R: BufRead> Rows<'_, R> {
+    /// Read the next non-blank record along with the line it starts on. A CSV record goes on for as long as it has a quote
+    /// open
+    fn record(&mut self) -> std::io::Result<Option<(usize, String)>> {
+        let mut record = String::new();
+        loop {
+            record.clear();
+            let start = self.line;
+            loop {
+                if self.input.read_line(&mut record)? == 0 {
+                    if record.trim().is_empty() {
+                        return Ok(None);
+                    }
+                    break;
+                }
+                self.line += 1;
+                if self.format == Format::JsonLines || record.matches('"').count().is_multiple_of(2)
+                {
+                    break;
+                }
+            }
+            if !record.trim().is_empty() {
+                let trimmed = record.trim_end_matches(['\n', '\r']).len();
+                record.truncate(trimmed);
+                return Ok(Some((start, record)));
+            }
+        }
+    }
+
+    /// Find each column of the schema in the CSV header
+    fn header(&self, line: usize, record: &str) -> std::io::Result<Vec<Option<usize>>> {
+        let names = split_csv(record).map_err(|err| invalid(line, err))?;
+        let mut positions = vec![None; self.schema.len()];
+        for (i, name) in names.into_iter().enumerate() {
+            let Field::Text(name) = name else {
+                return Err(invalid(line, "empty column name in the header"));
+            };
+            let column = self
+                .schema
+                .column_index(&name)
+                .ok_or_else(|| invalid(line, format!("no column called {}", name)))?;
+            if positions[column].replace(i).is_some() {
+                return Err(invalid(line, format!("column {} appears twice", name)));
+            }
+        }
+        Ok(positions)
+    }
+
+    /// Turn a record into a tuple, with `fields[i]` going to column `i`
+    fn tuple(&self, line: usize, fields: Vec<Field>) -> std::io::Result<Tuple> {
+        let values = fields
+            .into_iter()
+            .zip(self.schema.columns())
+            .map(|(field, column)| convert(field, column))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| invalid(line, err))?;
+        Ok(Tuple::new(values))
+    }
+
+    fn next_row(&mut self) -> std::io::Result<Option<(usize, Tuple)>> {
+        let Some((line, record)) = self.record()? else {
+            return Ok(None);
+        };
+        let mut fields = vec![Field::Null; self.schema.len()];
+        match self.format {
+            Format::Csv => {
+                let Some(positions) = &self.positions else {
+                    self.positions = Some(self.header(line, &record)?);
+                    return self.next_row();
+                };
+                let mut record = split_csv(&record).map_err(|err| invalid(line, err))?;
+                let expected = positions.iter().flatten().count();
+                if record.len() != expected {
+                    return Err(invalid(
+                        line,
+                        format!("{} fields where the header has {}", record.len(), expected),
+                    ));
+                }
+                for (field, position) in fields.iter_mut().zip(positions) {
+                    if let Some(position) = position {
+                        *field = std::mem::replace(&mut record[*position], Field::Null);
+                    }
+                }
+            }
+            Format::JsonLines => {
+                for (name, field) in JsonObject::parse(&record).map_err(|err| invalid(line, err))? {
+                    let column = self
+                        .schema
+                        .column_index(&name)
+                        .ok_or_else(|| invalid(line, format!("no column called {}", name)))?;
+                    fields[column] = field;
+                }
+            }
+        }
+        Ok(Some((line, self.tuple(line, fields)?)))
+    }
+}
+
+impl<R: BufRead> Iterator for Rows<'_, R> {
+    type Item = std::io::Result<(usize, Tuple)>;
+
+    /// The next row, or the error that ends the input
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let row = self.next_row().transpose();
+        self.failed = matches!(row, Some(Err(_)));
+        row
+    }
+}
+
+/// Parse `input` as rows of `schema` without storing them anywhere
+pub fn rows<R: BufRead>(schema: &Schema, format: Format, input: R) -> Rows<'_, R> {
+    Rows {
+        input,
+        schema,
+        format,
+        line: 1,
+        positions: None,
+        failed: false,
+    }
+}
+
+/// Insert every row of `input` into `heap`, returning their rids in input order
+pub fn import<R: BufRead>(
+    heap: &TableHeap,
+    schema: &Schema,
+    format: Format,
+    input: R,
+) -> std::io::Result<Vec<Rid>> {
+    let mut rids = Vec::new();
+    import_rows(heap, schema, format, input, |_, _, rid| {
+        rids.push(rid);
+        Ok(())
+    })?;
+    Ok(rids)
+}
+
+/// Like `import`, then bulk load a B-link tree in `heap`'s buffer pool that maps each row's `key_column` to its rid. The key
+/// column can't be NULL, and the keys of all rows have to differ
+pub fn import_with_index<R: BufRead>(
+    heap: &TableHeap,
+    schema: &Schema,
+    format: Format,
+    input: R,
+    key_column: &str,
+) -> std::io::Result<(Vec<Rid>, BLinkTree)> {
+    let column = schema.column_index(key_column).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("no column called {}", key_column),
+        )
+    })?;
+    let mut rids = Vec::new();
+    let mut entries: Vec<(Key, PageId, usize)> = Vec::new();
+    import_rows(heap, schema, format, input, |line, tuple, rid| {
+        let key = tuple
+            .value(column)
+            .and_then(index_key)
+            .ok_or_else(|| invalid(line, format!("key column {} is null", key_column)))?;
+        entries.push((key, rid.pack(), line));
+        rids.push(rid);
+        Ok(())
+    })?;
+    entries.sort_by_key(|entry| entry.0);
+    if let Some(pair) = entries.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        return Err(invalid(
+            pair[0].2.max(pair[1].2),
+            format!("duplicate key in column {}", key_column),
+        ));
+    }
+    let index = BLinkTree::bulk_load(
+        heap.pool().clone(),
+        entries.into_iter().map(|(key, value, _)| (key, value)),
+    )
+    .ok_or_else(|| std::io::Error::other("couldn't allocate the index pages"))?;
+    Ok((rids, index))
+}
+
+fn import_rows<R: BufRead>(
+    heap: &TableHeap,
+    schema: &Schema,
+    format: Format,
+    input: R,
+    mut f: impl FnMut(usize, &Tuple, Rid) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    for row in rows(schema, format, input) {
+        let (line, tuple) = row?;
+        // the fields were checked against the schema as they were converted
+        let bytes = tuple.serialize(schema).unwrap();
+        let rid = heap
+            .insert_tuple(&bytes)
+            .ok_or_else(|| invalid(line, "no page could be allocated for the row"))?;
+        f(line, &tuple, rid)?;
+    }
+    Ok(())
+}
+
+/// The index key of a value, ordered like the values: integers with their sign bit flipped, big endian, and strings as their
+/// first `KEY_SIZE` bytes, zero padded (so strings sharing a prefix that long get the same key). Booleans and NULL have none
+pub fn index_key(value: &Value) -> Option<Key> {
+    let mut key = [0u8; KEY_SIZE];
+    match value {
+        Value::Int32(n) => {
+            key[KEY_SIZE - 8..].copy_from_slice(&((*n as i64 as u64) ^ (1 << 63)).to_be_bytes())
+        }
+        Value::Int64(n) => {
+            key[KEY_SIZE - 8..].copy_from_slice(&((*n as u64) ^ (1 << 63)).to_be_bytes())
+        }
+        Value::Str(s) => {
+            let len = s.len().min(KEY_SIZE);
+            key[..len].copy_from_slice(&s.as_bytes()[..len]);
+        }
+        Value::Null | Value::Bool(_) => return None,
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+
+    fn song_schema() -> Schema {
+        Schema::new(vec![
+            Column::new("id", ColumnType::Int32),
+            Column::new("title", ColumnType::Varchar(100)),
+            Column::new("artist", ColumnType::Char(50)),
+            Column::new("plays", ColumnType::Int64).nullable(),
+            Column::new("explicit", ColumnType::Bool),
+        ])
+    }
+
+    fn song(id: i32, title: &str, artist: &str, plays: Option<i64>, explicit: bool) -> Tuple {
+        Tuple::new(vec![
+            Value::Int32(id),
+            Value::Str(title.to_string()),
+            Value::Str(artist.to_string()),
+            plays.map_or(Value::Null, Value::Int64),
+            Value::Bool(explicit),
+        ])
+    }
+
+    fn parse(format: Format, input: &str) -> std::io::Result<Vec<(usize, Tuple)>> {
+        rows(&song_schema(), format, input.as_bytes()).collect()
+    }
+
+    #[test]
+    fn test_parse_rows() {
+        let csv = "artist,id,title,explicit,plays\n\
+                   The Neighbourhood,1,Sweater Weather,false,1000\n\
+                   \n\
+                   \"Twenty One Pilots\",2,\"Car Radio, live\",t,\n\
+                   Mitski,-3,\"Nobody\n(\"\"demo\"\")\",0,\"7\"\n";
+        let rows = parse(Format::Csv, csv).unwrap();
+        assert!(
+            rows == vec![
+                (
+                    2,
+                    song(1, "Sweater Weather", "The Neighbourhood", Some(1000), false)
+                ),
+                (
+                    4,
+                    song(2, "Car Radio, live", "Twenty One Pilots", None, true)
+                ),
+                (5, song(-3, "Nobody\n(\"demo\")", "Mitski", Some(7), false)),
+            ]
+        );
+
+        let json = "{\"id\": 1, \"title\": \"Sweater Weather\", \"artist\": \"The Neighbourhood\", \"plays\": 1000, \"explicit\": false}\n\
+                    {\"explicit\":true,\"title\":\"Car Radio, live\",\"artist\":\"Twenty One Pilots\",\"id\":2}\n\
+                    \n\
+                    {\"id\":-3,\"title\":\"Nobody\\n(\\\"demo\\\") \\u00e9\\ud83c\\udfb5\",\"artist\":\"Mitski\",\"plays\":\"7\",\"explicit\":false}\n";
+        let rows = parse(Format::JsonLines, json).unwrap();
+        assert!(
+            rows == vec![
+                (
+                    1,
+                    song(1, "Sweater Weather", "The Neighbourhood", Some(1000), false)
+                ),
+                (
+                    2,
+                    song(2, "Car Radio, live", "Twenty One Pilots", None, true)
+                ),
+                (
+                    4,
+                    song(-3, "Nobody\n(\"demo\") é🎵", "Mitski", Some(7), false)
+                ),
+            ]
+        );
+
+        // errors name the line of the row at fault
+        for (format, input, line) in [
+            (
+                Format::Csv,
+                "id,title,artist,explicit\n1,a,b,true\n2,a,b\n",
+                3,
+            ),
+            (Format::Csv, "id,title,artist,explicit\n1,a,b,maybe\n", 2),
+            (Format::Csv, "id,title,artist,explicit\n1,\"a,b,true\n", 2),
+            (Format::Csv, "id,name\n", 1),
+            (Format::Csv, "title,artist,explicit\nx,y,true\n", 2),
+            (
+                Format::JsonLines,
+                "{\"id\":1,\"title\":\"a\",\"artist\":\"b\",\"explicit\":true}\n{\"id\":1.5}\n",
+                2,
+            ),
+            (
+                Format::JsonLines,
+                "{\"id\":1,\"title\":[],\"artist\":\"b\",\"explicit\":true}\n",
+                1,
+            ),
+            (
+                Format::JsonLines,
+                "{\"id\":1,\"title\":\"a\",\"artist\":\"b\",\"explicit\":true,\"x\":1}\n",
+                1,
+            ),
+        ] {
+            let err = parse(format, input).unwrap_err();
+            assert!(err.kind() == std::io::ErrorKind::InvalidData);
+            assert!(
+                err.to_string().starts_with(&format!("line {}:", line)),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_import_with_index() {
+        let dir = cwd() + "/tests/import_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir + "/test_import_with_index.bin";
+        let heap = TableHeap::create(BufferPool::create(&path).unwrap()).unwrap();
+        let schema = song_schema();
+        let mut csv = "id,title,artist,plays,explicit\n".to_string();
+        for id in (0..500).rev() {
+            csv += &format!(
+                "{},Song {},Artist {},{},{}\n",
+                id - 250,
+                id,
+                id % 7,
+                id * 10,
+                id % 2 == 0
+            );
+        }
+        let (rids, index) =
+            import_with_index(&heap, &schema, Format::Csv, csv.as_bytes(), "id").unwrap();
+        assert!(rids.len() == 500 && heap.iter().count() == 500);
+
+        // the index is ordered by id, negative ones first
+        let ids: Vec<i32> = index
+            .iter()
+            .map(|(_, value)| {
+                let tuple =
+                    Tuple::deserialize(&schema, &heap.get_tuple(Rid::unpack(value)).unwrap())
+                        .unwrap();
+                match tuple.value(0) {
+                    Some(Value::Int32(id)) => *id,
+                    _ => panic!("bad id"),
+                }
+            })
+            .collect();
+        assert!(ids == (-250..250).collect::<Vec<_>>());
+        let rid = Rid::unpack(
+            index
+                .search(&index_key(&Value::Int32(-7)).unwrap())
+                .unwrap()
+                .unwrap(),
+        );
+        let tuple = Tuple::deserialize(&schema, &heap.get_tuple(rid).unwrap()).unwrap();
+        assert!(tuple == song(-7, "Song 243", "Artist 5", Some(2430), false));
+
+        let duplicate = "id,title,artist,explicit\n1,a,b,true\n2,a,b,true\n1,c,d,false\n";
+        let err = import_with_index(&heap, &schema, Format::Csv, duplicate.as_bytes(), "id")
+            .map(|_| ())
+            .unwrap_err();
+        assert!(err.to_string().starts_with("line 4:"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
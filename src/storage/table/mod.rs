@@ -0,0 +1,7 @@
+pub mod export;
+pub mod fsm;
+pub mod heap;
+pub mod import;
+pub mod mvcc;
+pub mod overflow;
+pub mod vacuum;
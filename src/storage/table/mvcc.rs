@@ -0,0 +1,637 @@
+/// This file implements multi-version concurrency control on top of the table heap. Every version of a row is a tuple in the
+/// heap, prefixed with a header holding the timestamps of the transactions that created it (begin) and replaced or deleted
+/// it (end), and the rid of the version it replaced. An update leaves the old version in place, ends it, and inserts the new
+/// one pointing back at it, so a row's versions form a chain from newest to oldest.
+///
+/// A transaction reads from the snapshot taken when it began: a version is visible if it was created by a transaction that
+/// committed before the snapshot and not ended by one that did, or if the transaction wrote it itself. Readers therefore
+/// never wait for writers and take no locks from the lock manager. Writers still conflict with each other: updating or
+/// deleting a version that another transaction has already ended, committed or not, fails with `MvccError::WriteConflict`
+/// (first updater wins).
+///
+/// Until its transaction commits, a version is stamped with the transaction's id rather than a timestamp, and readers look
+/// the transaction up in `MvccManager` to see how it ended. Commit takes a timestamp, records it, and then stamps the
+/// versions the transaction wrote; abort removes the versions it created and reopens the ones it ended. Readers look a
+/// transaction up while still holding the latch of the page they read its id from, and a transaction is only forgotten once
+/// all its versions are stamped, so a reader never finds an id it can't resolve.
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::shared::{TxnId, INVALID_PAGE_ID, INVALID_TXN_ID};
+use crate::storage::table::heap::{Rid, TableHeap, TableIterator, VacuumStats};
+use crate::sync::{Latch as _, Synchronized};
+use crate::txn::oracle::{Timestamp, TimestampOracle, TimestampOracleApi as _};
+
+/// Size of the header in front of every version
+pub const VERSION_HEADER_SIZE: usize = 50;
+
+/// The end of a version nobody has replaced or deleted
+const INFINITY: Timestamp = Timestamp {
+    physical: u64::MAX,
+    logical: u32::MAX,
+};
+
+const NO_VERSION: Rid = Rid {
+    page_id: INVALID_PAGE_ID,
+    slot: 0,
+};
+
+/// A version's begin or end: the commit timestamp of the transaction that set it, or that transaction's id until it commits
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    txn_id: TxnId,
+    ts: Timestamp,
+}
+
+impl Stamp {
+    const OPEN: Stamp = Stamp::committed(INFINITY);
+
+    const fn committed(ts: Timestamp) -> Self {
+        Stamp {
+            txn_id: INVALID_TXN_ID,
+            ts,
+        }
+    }
+
+    fn pending(txn_id: TxnId) -> Self {
+        Stamp {
+            txn_id,
+            ts: Timestamp::ZERO,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+struct VersionHeader {
+    begin: Stamp,
+    end: Stamp,
+    // the version this one replaced, or `NO_VERSION`
+    prev: Rid,
+}
+
+impl VersionHeader {
+    fn read(tuple: &[u8]) -> Self {
+        bincode::deserialize(&tuple[..VERSION_HEADER_SIZE]).expect("corrupt version header")
+    }
+
+    fn write(&self, tuple: &mut [u8]) {
+        tuple[..VERSION_HEADER_SIZE].copy_from_slice(&bincode::serialize(self).unwrap());
+    }
+}
+
+/// Why a write was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MvccError {
+    /// There's no version at the rid that the transaction can see
+    NotFound(Rid),
+    /// Another transaction has already replaced or deleted the version
+    WriteConflict(Rid),
+    /// The new version couldn't be stored in the heap
+    NoSpace,
+}
+
+impl Display for MvccError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MvccError::NotFound(rid) => write!(f, "no visible version at {:?}", rid),
+            MvccError::WriteConflict(rid) => {
+                write!(f, "version at {:?} was changed by another transaction", rid)
+            }
+            MvccError::NoSpace => write!(f, "no space for a new version"),
+        }
+    }
+}
+
+impl std::error::Error for MvccError {}
+
+/// What a transaction sees: everything committed before `read_ts`, plus its own writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    pub txn_id: TxnId,
+    pub read_ts: Timestamp,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Bound {
+    Begin,
+    End,
+}
+
+/// An open transaction. It has to be ended with `MvccManagerApi::commit` or `abort`
+pub struct MvccTxn {
+    snapshot: Snapshot,
+    // versions this transaction created (`Begin`) or ended (`End`), in the order it wrote them
+    writes: Vec<(TableHeap, Rid, Bound)>,
+}
+
+impl MvccTxn {
+    pub fn txn_id(&self) -> TxnId {
+        self.snapshot.txn_id
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshot
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TxnStatus {
+    // with the transaction's snapshot timestamp
+    Active(Timestamp),
+    Committed(Timestamp),
+    Aborted,
+}
+
+pub struct MvccManagerInternal {
+    oracle: TimestampOracle,
+    // transactions whose versions may still carry their id
+    txns: HashMap<TxnId, TxnStatus>,
+    next_txn_id: TxnId,
+}
+
+impl MvccManagerInternal {
+    /// A transaction is only forgotten once none of its versions carry its id, so an unknown id was left behind by a
+    /// transaction that never finished, e.g. before a restart
+    fn status(&self, txn_id: TxnId) -> TxnStatus {
+        self.txns
+            .get(&txn_id)
+            .copied()
+            .unwrap_or(TxnStatus::Aborted)
+    }
+
+    /// When a stamp took effect as `snapshot` sees it: its timestamp, or `None` if it hasn't (yet) for this snapshot. A
+    /// transaction's own writes took effect at the start of time
+    fn effective(&self, stamp: Stamp, snapshot: &Snapshot) -> Option<Timestamp> {
+        if stamp.txn_id == INVALID_TXN_ID {
+            return Some(stamp.ts);
+        }
+        if stamp.txn_id == snapshot.txn_id {
+            return Some(Timestamp::ZERO);
+        }
+        match self.status(stamp.txn_id) {
+            TxnStatus::Committed(ts) => Some(ts),
+            TxnStatus::Active(_) | TxnStatus::Aborted => None,
+        }
+    }
+
+    /// Whether no snapshot at or after `horizon` can see a version, so vacuum may remove it: it was ended by a commit before
+    /// the horizon, or created by a transaction that was forgotten without committing. Versions carrying the id of a
+    /// transaction that's still known are left alone, since committing or rolling back still has to get to them
+    fn dead(&self, header: &VersionHeader, horizon: Timestamp) -> bool {
+        let known =
+            |stamp: Stamp| stamp.txn_id != INVALID_TXN_ID && self.txns.contains_key(&stamp.txn_id);
+        if known(header.begin) || known(header.end) {
+            return false;
+        }
+        if header.begin.txn_id != INVALID_TXN_ID {
+            return true;
+        }
+        header.end.txn_id == INVALID_TXN_ID && header.end.ts <= horizon
+    }
+
+    fn visible(&self, header: &VersionHeader, snapshot: &Snapshot) -> bool {
+        let begun = self
+            .effective(header.begin, snapshot)
+            .is_some_and(|ts| ts <= snapshot.read_ts);
+        let ended = self
+            .effective(header.end, snapshot)
+            .is_some_and(|ts| ts <= snapshot.read_ts);
+        begun && !ended
+    }
+}
+
+/// Hands out transactions and snapshots, and keeps track of the transactions whose versions aren't stamped yet. Shared by
+/// every table the transactions write to
+pub type MvccManager = Synchronized<MvccManagerInternal>;
+
+pub trait MvccManagerApi {
+    fn create(oracle: TimestampOracle) -> Self;
+    fn begin(&self) -> MvccTxn;
+    fn commit(&self, txn: MvccTxn) -> Timestamp;
+    fn abort(&self, txn: MvccTxn);
+    fn active(&self) -> usize;
+    fn horizon(&self) -> Timestamp;
+}
+
+impl MvccManagerApi for MvccManager {
+    fn create(oracle: TimestampOracle) -> Self {
+        Synchronized::init(MvccManagerInternal {
+            oracle,
+            txns: HashMap::new(),
+            next_txn_id: INVALID_TXN_ID + 1,
+        })
+    }
+
+    /// Start a transaction, taking its snapshot. The snapshot is taken under the same latch commits record their timestamps
+    /// under, so every transaction with an earlier commit timestamp is already known to have committed
+    fn begin(&self) -> MvccTxn {
+        let mut inner = self.lock();
+        let txn_id = inner.next_txn_id;
+        inner.next_txn_id += 1;
+        let read_ts = inner.oracle.now();
+        inner.txns.insert(txn_id, TxnStatus::Active(read_ts));
+        MvccTxn {
+            snapshot: Snapshot { txn_id, read_ts },
+            writes: Vec::new(),
+        }
+    }
+
+    /// Commit `txn` and return its commit timestamp. Its writes are visible to every snapshot taken from now on
+    fn commit(&self, txn: MvccTxn) -> Timestamp {
+        let txn_id = txn.txn_id();
+        let commit_ts = {
+            let mut inner = self.lock();
+            let commit_ts = inner.oracle.now();
+            inner.txns.insert(txn_id, TxnStatus::Committed(commit_ts));
+            commit_ts
+        };
+        let pending = Stamp::pending(txn_id);
+        let mut stamped = true;
+        for (heap, rid, bound) in txn.writes {
+            stamped &= heap
+                .modify_tuple(rid, |tuple| {
+                    let mut header = VersionHeader::read(tuple);
+                    match bound {
+                        Bound::Begin if header.begin == pending => {
+                            header.begin = Stamp::committed(commit_ts)
+                        }
+                        Bound::End if header.end == pending => {
+                            header.end = Stamp::committed(commit_ts)
+                        }
+                        _ => {}
+                    }
+                    header.write(tuple);
+                })
+                .is_some();
+        }
+        // a version that couldn't be stamped is still resolved through the transaction's status, so it has to stay
+        if stamped {
+            self.lock().txns.remove(&txn_id);
+        }
+        commit_ts
+    }
+
+    /// Roll `txn` back: the versions it created are removed and the ones it ended are current again
+    fn abort(&self, txn: MvccTxn) {
+        let txn_id = txn.txn_id();
+        self.lock().txns.insert(txn_id, TxnStatus::Aborted);
+        let pending = Stamp::pending(txn_id);
+        let mut undone = true;
+        for (heap, rid, bound) in txn.writes.into_iter().rev() {
+            undone &= match bound {
+                Bound::Begin => heap.delete_tuple(rid),
+                // another transaction may have ended the version since, seeing this one had rolled back
+                Bound::End => heap
+                    .modify_tuple(rid, |tuple| {
+                        let mut header = VersionHeader::read(tuple);
+                        if header.end == pending {
+                            header.end = Stamp::OPEN;
+                        }
+                        header.write(tuple);
+                    })
+                    .is_some(),
+            };
+        }
+        if undone {
+            self.lock().txns.remove(&txn_id);
+        }
+    }
+
+    /// Transactions begun and not yet committed or rolled back
+    fn active(&self) -> usize {
+        let inner = self.lock();
+        inner
+            .txns
+            .values()
+            .filter(|status| matches!(status, TxnStatus::Active(_)))
+            .count()
+    }
+
+    /// The oldest snapshot any transaction may still read from: that of the oldest active transaction, or the current time if
+    /// there is none. Transactions begun later get later snapshots
+    fn horizon(&self) -> Timestamp {
+        let inner = self.lock();
+        let oldest = inner
+            .txns
+            .values()
+            .filter_map(|status| match status {
+                TxnStatus::Active(read_ts) => Some(*read_ts),
+                _ => None,
+            })
+            .min();
+        oldest.unwrap_or_else(|| inner.oracle.now())
+    }
+}
+
+/// A table heap holding versioned rows (see the top of this file)
+#[derive(Clone)]
+pub struct MvccHeap {
+    heap: TableHeap,
+    mvcc: MvccManager,
+}
+
+impl MvccHeap {
+    pub fn new(heap: TableHeap, mvcc: MvccManager) -> Self {
+        MvccHeap { heap, mvcc }
+    }
+
+    pub fn heap(&self) -> &TableHeap {
+        &self.heap
+    }
+
+    /// Insert a row as `txn`, returning the rid of its first version. Returns `None` if it couldn't be stored (see
+    /// `TableHeap::insert_tuple`)
+    pub fn insert(&self, txn: &mut MvccTxn, data: &[u8]) -> Option<Rid> {
+        self.store(txn, data, NO_VERSION)
+    }
+
+    /// The row at `rid` as `snapshot` sees it. `rid` should be the newest version the caller knows of (e.g. what `update`
+    /// returned); older versions are found by following the chain back from it
+    pub fn read(&self, snapshot: &Snapshot, rid: Rid) -> Option<Vec<u8>> {
+        let mut rid = rid;
+        loop {
+            let (data, prev) = self.heap.read_tuple(rid, |tuple| {
+                let header = VersionHeader::read(tuple);
+                let visible = self.mvcc.lock().visible(&header, snapshot);
+                (
+                    visible.then(|| tuple[VERSION_HEADER_SIZE..].to_vec()),
+                    header.prev,
+                )
+            })?;
+            if data.is_some() || prev == NO_VERSION {
+                return data;
+            }
+            rid = prev;
+        }
+    }
+
+    /// Replace the row at `rid` as `txn`, returning the rid of the new version. The version at `rid` has to be visible to
+    /// `txn` and not replaced or deleted by anyone else
+    pub fn update(&self, txn: &mut MvccTxn, rid: Rid, data: &[u8]) -> Result<Rid, MvccError> {
+        self.end(txn, rid)?;
+        match self.store(txn, data, rid) {
+            Some(new_rid) => Ok(new_rid),
+            None => Err(MvccError::NoSpace),
+        }
+    }
+
+    /// Delete the row at `rid` as `txn`, under the same conditions as `update`
+    pub fn delete(&self, txn: &mut MvccTxn, rid: Rid) -> Result<(), MvccError> {
+        self.end(txn, rid)
+    }
+
+    /// Every row `snapshot` sees, along with the rid of the version it sees. Takes no locks, and doesn't wait for writers
+    pub fn scan(&self, snapshot: &Snapshot) -> TableIterator<(Rid, Vec<u8>)> {
+        let mvcc = self.mvcc.clone();
+        let snapshot = *snapshot;
+        self.heap.filter_map(move |rid, tuple| {
+            let header = VersionHeader::read(tuple);
+            let visible = mvcc.lock().visible(&header, &snapshot);
+            visible.then(|| (rid, tuple[VERSION_HEADER_SIZE..].to_vec()))
+        })
+    }
+
+    /// Remove the versions no transaction can see any more (see `MvccManagerInternal::dead`) and compact the pages they were
+    /// on. Versions that pointed back at a removed one are unlinked from it first, so a removed version's rid can be reused
+    /// without a chain leading into it. Runs alongside readers and writers, latching one page at a time
+    pub fn vacuum(&self) -> VacuumStats {
+        let horizon = self.mvcc.horizon();
+        let mvcc = self.mvcc.clone();
+        let dead: HashSet<Rid> = self
+            .heap
+            .filter_map(move |rid, tuple| {
+                let header = VersionHeader::read(tuple);
+                mvcc.lock().dead(&header, horizon).then_some(rid)
+            })
+            .collect();
+        if dead.is_empty() {
+            return self.heap.vacuum(&dead);
+        }
+        let dead = Arc::new(dead);
+        let linked: Vec<Rid> = {
+            let dead = dead.clone();
+            self.heap
+                .filter_map(move |rid, tuple| {
+                    let prev = VersionHeader::read(tuple).prev;
+                    (!dead.contains(&rid) && dead.contains(&prev)).then_some(rid)
+                })
+                .collect()
+        };
+        for rid in linked {
+            self.heap.modify_tuple(rid, |tuple| {
+                let mut header = VersionHeader::read(tuple);
+                if dead.contains(&header.prev) {
+                    header.prev = NO_VERSION;
+                }
+                header.write(tuple);
+            });
+        }
+        self.heap.vacuum(&dead)
+    }
+
+    fn store(&self, txn: &mut MvccTxn, data: &[u8], prev: Rid) -> Option<Rid> {
+        let mut tuple = vec![0u8; VERSION_HEADER_SIZE];
+        VersionHeader {
+            begin: Stamp::pending(txn.txn_id()),
+            end: Stamp::OPEN,
+            prev,
+        }
+        .write(&mut tuple);
+        tuple.extend_from_slice(data);
+        let rid = self.heap.insert_tuple(&tuple)?;
+        txn.writes.push((self.heap.clone(), rid, Bound::Begin));
+        Some(rid)
+    }
+
+    /// End the version at `rid` as `txn`, checking under the page's write latch that `txn` sees it and nobody else has ended it
+    fn end(&self, txn: &mut MvccTxn, rid: Rid) -> Result<(), MvccError> {
+        let snapshot = txn.snapshot();
+        let ended = self.heap.modify_tuple(rid, |tuple| {
+            let mut header = VersionHeader::read(tuple);
+            let inner = self.mvcc.lock();
+            if !inner.visible(&header, &snapshot) {
+                return Err(MvccError::NotFound(rid));
+            }
+            // a version `txn` sees that isn't open was ended by a transaction that's still running or committed after the
+            // snapshot, unless it rolled back and hasn't reopened it yet
+            let reopened = header.end.txn_id != INVALID_TXN_ID
+                && inner.status(header.end.txn_id) == TxnStatus::Aborted;
+            if header.end != Stamp::OPEN && !reopened {
+                return Err(MvccError::WriteConflict(rid));
+            }
+            header.end = Stamp::pending(snapshot.txn_id);
+            header.write(tuple);
+            Ok(())
+        });
+        ended.ok_or(MvccError::NotFound(rid))??;
+        txn.writes.push((self.heap.clone(), rid, Bound::End));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rayon::ThreadPoolBuilder;
+
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+    use crate::storage::buffer::io;
+    use crate::testing::Song;
+
+    fn setup(name: &str) -> (MvccHeap, MvccManager, String) {
+        let dir = cwd() + "/tests/mvcc_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/{}.bin", dir, name);
+        let heap = TableHeap::create(BufferPool::create(&path).unwrap()).unwrap();
+        let mvcc = MvccManager::create(TimestampOracle::create());
+        (MvccHeap::new(heap, mvcc.clone()), mvcc, path)
+    }
+
+    fn song(id: i32, title: &str) -> Vec<u8> {
+        io::encode(Song::new(id, title, "The Neighbourhood")).unwrap()
+    }
+
+    fn title(data: Vec<u8>) -> String {
+        let title = io::decode::<Song>(data).unwrap().title;
+        String::from_utf8_lossy(&title)
+            .trim_end_matches('\0')
+            .to_string()
+    }
+
+    #[test]
+    fn test_header_size() {
+        let header = VersionHeader {
+            begin: Stamp::pending(7),
+            end: Stamp::OPEN,
+            prev: NO_VERSION,
+        };
+        assert!(bincode::serialized_size(&header).unwrap() as usize == VERSION_HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_snapshot_reads() {
+        let (table, mvcc, path) = setup("test_snapshot_reads");
+        let mut txn = mvcc.begin();
+        let rid = table.insert(&mut txn, &song(1, "Afraid")).unwrap();
+        // nobody else sees an uncommitted insert
+        let other = mvcc.begin();
+        assert!(table.read(&other.snapshot(), rid).is_none());
+        mvcc.abort(other);
+        assert!(table.read(&txn.snapshot(), rid).is_some());
+        mvcc.commit(txn);
+
+        let reader = mvcc.begin();
+        let mut writer = mvcc.begin();
+        let new_rid = table.update(&mut writer, rid, &song(1, "Prey")).unwrap();
+        assert!(title(table.read(&writer.snapshot(), new_rid).unwrap()) == "Prey");
+        // the reader follows the chain back to the version its snapshot sees, before and after the writer commits
+        assert!(title(table.read(&reader.snapshot(), new_rid).unwrap()) == "Afraid");
+        mvcc.commit(writer);
+        assert!(title(table.read(&reader.snapshot(), new_rid).unwrap()) == "Afraid");
+        let scanned: Vec<(Rid, Vec<u8>)> = table.scan(&reader.snapshot()).collect();
+        assert!(scanned.len() == 1 && scanned[0].0 == rid);
+
+        let later = mvcc.begin();
+        assert!(title(table.read(&later.snapshot(), new_rid).unwrap()) == "Prey");
+        assert!(table.read(&later.snapshot(), rid).is_none());
+        let scanned: Vec<(Rid, Vec<u8>)> = table.scan(&later.snapshot()).collect();
+        assert!(scanned.len() == 1 && scanned[0].0 == new_rid);
+
+        mvcc.commit(reader);
+        mvcc.commit(later);
+        assert!(mvcc.active() == 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_conflicts_and_abort() {
+        let (table, mvcc, path) = setup("test_write_conflicts_and_abort");
+        let mut txn = mvcc.begin();
+        let rid = table.insert(&mut txn, &song(2, "Wires")).unwrap();
+        let doomed = table.insert(&mut txn, &song(3, "Nervous")).unwrap();
+        mvcc.commit(txn);
+
+        // first updater wins
+        let mut first = mvcc.begin();
+        let mut second = mvcc.begin();
+        table
+            .update(&mut first, rid, &song(2, "Stuck with Me"))
+            .unwrap();
+        assert!(
+            table.update(&mut second, rid, &song(2, "R.I.P. 2 My Youth"))
+                == Err(MvccError::WriteConflict(rid))
+        );
+        // once the first rolls back, the version is free again
+        mvcc.abort(first);
+        let new_rid = table
+            .update(&mut second, rid, &song(2, "R.I.P. 2 My Youth"))
+            .unwrap();
+        table.delete(&mut second, doomed).unwrap();
+        assert!(table.delete(&mut second, doomed) == Err(MvccError::NotFound(doomed)));
+        let before = mvcc.begin();
+        mvcc.commit(second);
+
+        // a transaction can't change what its snapshot sees as replaced by a later commit
+        let mut stale = before;
+        assert!(table.delete(&mut stale, rid) == Err(MvccError::WriteConflict(rid)));
+        assert!(table.scan(&stale.snapshot()).count() == 2);
+        mvcc.abort(stale);
+
+        let now = mvcc.begin();
+        let rows: Vec<String> = table
+            .scan(&now.snapshot())
+            .map(|(_, data)| title(data))
+            .collect();
+        assert!(rows == vec!["R.I.P. 2 My Youth".to_string()]);
+        assert!(table.read(&now.snapshot(), new_rid).is_some());
+        mvcc.commit(now);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_scans_see_consistent_snapshots() {
+        let (table, mvcc, path) = setup("test_scans_see_consistent_snapshots");
+        let mut txn = mvcc.begin();
+        let rids: Vec<Rid> = (0..40)
+            .map(|i| table.insert(&mut txn, &song(i, "Daddy Issues")).unwrap())
+            .collect();
+        mvcc.commit(txn);
+
+        let threads = ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        threads.scope(|s| {
+            // each writer keeps replacing its own rows
+            for t in 0..4 {
+                let (table, mvcc) = (table.clone(), mvcc.clone());
+                let mut rids: Vec<Rid> = rids.iter().copied().skip(t).step_by(4).collect();
+                s.spawn(move |_| {
+                    for round in 0..20 {
+                        let mut txn = mvcc.begin();
+                        for rid in rids.iter_mut() {
+                            *rid = table
+                                .update(&mut txn, *rid, &song(round, "Softcore"))
+                                .unwrap();
+                        }
+                        mvcc.commit(txn);
+                    }
+                });
+            }
+            // readers always see exactly one version of every row
+            for _ in 0..4 {
+                let (table, mvcc) = (table.clone(), mvcc.clone());
+                s.spawn(move |_| {
+                    for _ in 0..20 {
+                        let txn = mvcc.begin();
+                        assert!(table.scan(&txn.snapshot()).count() == 40);
+                        mvcc.commit(txn);
+                    }
+                });
+            }
+        });
+        assert!(mvcc.active() == 0);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+/// This file implements overflow chains, which hold the records too large to live on a table heap page (big strings, blobs).
+/// A tuple longer than `OVERFLOW_THRESHOLD` is cut into pieces written to a chain of overflow pages, and its heap slot only
+/// holds a stub: the tuple's length and the chain's first page, with the slot's overflow flag set (see `SlottedPage`). The heap
+/// reads the chain back through the buffer pool whenever the tuple is read, so callers never see the stub.
+///
+/// Each overflow page holds one piece of the record:
+///
+/// ```text
+/// | page header (24) | next page (8) | length (2) | data ... |
+/// ```
+///
+/// A chain belongs to exactly one stub and is never changed in place: updating a large tuple writes a new chain and frees the
+/// old one once the slot points at the new one. Chains are written before their stub is stored, and freed after it's gone,
+/// so a reader that finds a stub under its heap page's latch always finds the whole chain behind it.
+use crate::shared::{PageId, INVALID_PAGE_ID, PAGE_SIZE};
+use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+use crate::storage::buffer::page::{self, Page, PageType, PAGE_HEADER_SIZE};
+use crate::storage::error::StorageError;
+
+pub const NEXT_OVERFLOW_PAGE_OFFSET: usize = PAGE_HEADER_SIZE;
+pub const OVERFLOW_LEN_OFFSET: usize = NEXT_OVERFLOW_PAGE_OFFSET + 8;
+pub const OVERFLOW_DATA_OFFSET: usize = OVERFLOW_LEN_OFFSET + 2;
+
+/// Bytes of a record each overflow page holds
+pub const OVERFLOW_DATA_SIZE: usize = PAGE_SIZE - OVERFLOW_DATA_OFFSET;
+
+/// Tuples longer than this are moved to an overflow chain, so a heap page always has room for a few tuples
+pub const OVERFLOW_THRESHOLD: usize = PAGE_SIZE / 4;
+
+/// Bytes of a stub: the record's length (8) and the chain's first page (8)
+pub const STUB_SIZE: usize = 16;
+
+/// What a heap slot holds in place of a record that was moved to an overflow chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stub {
+    pub len: usize,
+    pub first_page_id: PageId,
+}
+
+impl Stub {
+    pub fn encode(&self) -> [u8; STUB_SIZE] {
+        let mut bytes = [0u8; STUB_SIZE];
+        bytes[..8].copy_from_slice(&(self.len as u64).to_le_bytes());
+        bytes[8..].copy_from_slice(&(self.first_page_id as i64).to_le_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Stub> {
+        if bytes.len() != STUB_SIZE {
+            return None;
+        }
+        Some(Stub {
+            len: u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize,
+            first_page_id: i64::from_le_bytes(bytes[8..].try_into().unwrap()) as PageId,
+        })
+    }
+}
+
+fn next_page_id(page: &Page) -> PageId {
+    let bytes = &page[NEXT_OVERFLOW_PAGE_OFFSET..NEXT_OVERFLOW_PAGE_OFFSET + 8];
+    i64::from_le_bytes(bytes.try_into().unwrap()) as PageId
+}
+
+/// The piece of the record an overflow page holds, or `None` if it isn't an overflow page
+fn data(page: &Page) -> Option<&[u8]> {
+    if page::page_type(page) != Some(PageType::Overflow) {
+        return None;
+    }
+    let bytes = &page[OVERFLOW_LEN_OFFSET..OVERFLOW_LEN_OFFSET + 2];
+    let len = u16::from_le_bytes(bytes.try_into().unwrap()) as usize;
+    page.get(OVERFLOW_DATA_OFFSET..OVERFLOW_DATA_OFFSET + len)
+}
+
+fn init_overflow_page(page: &mut Page, next_page_id: PageId, data: &[u8]) {
+    page[PAGE_HEADER_SIZE..].fill(0);
+    page::set_page_type(page, PageType::Overflow);
+    page[NEXT_OVERFLOW_PAGE_OFFSET..NEXT_OVERFLOW_PAGE_OFFSET + 8]
+        .copy_from_slice(&(next_page_id as i64).to_le_bytes());
+    page[OVERFLOW_LEN_OFFSET..OVERFLOW_LEN_OFFSET + 2]
+        .copy_from_slice(&(data.len() as u16).to_le_bytes());
+    page[OVERFLOW_DATA_OFFSET..OVERFLOW_DATA_OFFSET + data.len()].copy_from_slice(data);
+}
+
+/// Write `record` to a new overflow chain and return the stub that points at it. Pages are written from the end of the record
+/// backwards, so each one already knows the page after it. If a page can't be allocated, the pages written so far are freed
+pub fn write_chain(pool: &BufferPool, record: &[u8]) -> Result<Stub, StorageError> {
+    let mut next_page_id = INVALID_PAGE_ID;
+    for piece in record.chunks(OVERFLOW_DATA_SIZE).rev() {
+        let mut guard = match pool.new_page_write() {
+            Ok(guard) => guard,
+            Err(err) => {
+                free_chain(pool, next_page_id);
+                return Err(err);
+            }
+        };
+        init_overflow_page(guard.data_mut(), next_page_id, piece);
+        next_page_id = guard.page_id();
+    }
+    Ok(Stub {
+        len: record.len(),
+        first_page_id: next_page_id,
+    })
+}
+
+/// Read back the record behind `stub`. A page that isn't an overflow page, or a chain that ends early or runs long, is
+/// reported as corruption of the page where it went wrong
+pub fn read_chain(pool: &BufferPool, stub: Stub) -> Result<Vec<u8>, StorageError> {
+    let mut record = Vec::with_capacity(stub.len);
+    let mut page_id = stub.first_page_id;
+    while record.len() < stub.len {
+        if page_id == INVALID_PAGE_ID {
+            return Err(StorageError::Corruption(stub.first_page_id));
+        }
+        let guard = pool.fetch_page_read(page_id)?;
+        let piece = data(guard.data()).ok_or(StorageError::Corruption(page_id))?;
+        if piece.is_empty() || record.len() + piece.len() > stub.len {
+            return Err(StorageError::Corruption(page_id));
+        }
+        record.extend_from_slice(piece);
+        page_id = next_page_id(guard.data());
+    }
+    Ok(record)
+}
+
+/// Free every page of the chain starting at `first_page_id`, and return how many were freed. Stops at the first page that
+/// isn't an overflow page or can't be freed, leaving the rest of the chain allocated
+pub fn free_chain(pool: &BufferPool, first_page_id: PageId) -> usize {
+    let mut freed = 0;
+    let mut page_id = first_page_id;
+    while page_id != INVALID_PAGE_ID {
+        let next = match pool.fetch_page_read(page_id) {
+            Ok(guard) if data(guard.data()).is_some() => next_page_id(guard.data()),
+            _ => break,
+        };
+        if pool.delete_page(page_id).is_err() {
+            break;
+        }
+        freed += 1;
+        page_id = next;
+    }
+    freed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::buffer::freemap;
+
+    #[test]
+    fn test_overflow_chain() {
+        let dir = cwd() + "/tests/overflow_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir + "/test_overflow_chain.bin";
+        let pool = BufferPool::create(&path).unwrap();
+
+        let record: Vec<u8> = (0..OVERFLOW_DATA_SIZE * 2 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let stub = write_chain(&pool, &record).unwrap();
+        assert!(Stub::decode(&stub.encode()) == Some(stub));
+        assert!(read_chain(&pool, stub).unwrap() == record);
+
+        // a stub that promises more than its chain holds is caught
+        let long = Stub {
+            len: record.len() + 1,
+            ..stub
+        };
+        assert!(matches!(
+            read_chain(&pool, long),
+            Err(StorageError::Corruption(_))
+        ));
+
+        // freed pages are reused by the next chain
+        assert!(free_chain(&pool, stub.first_page_id) == 3);
+        let again = write_chain(&pool, &record[..10]).unwrap();
+        assert!((stub.first_page_id - 2..=stub.first_page_id).contains(&again.first_page_id));
+        assert!(read_chain(&pool, again).unwrap() == record[..10]);
+        std::fs::remove_file(&path).unwrap();
+        let _ = std::fs::remove_file(freemap::map_path(&path));
+    }
+}
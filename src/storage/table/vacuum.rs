@@ -0,0 +1,141 @@
+/// This file implements vacuum, which gets rid of the dead space that builds up in table heaps: row versions that no
+/// transaction can see any more, and the holes left by deleted and shrunken tuples. `vacuum` cleans one table on demand;
+/// `VacuumTask` cleans a set of tables on a background thread every interval, for as long as it's kept. Either way the pages
+/// are cleaned one at a time under their own write latch, so readers and writers carry on meanwhile, and the space freed on
+/// each page is recorded in the table's free space map for later inserts to find.
+use std::time::Duration;
+
+use crate::storage::buffer::bufmgr::BackgroundWriter;
+use crate::storage::table::heap::VacuumStats;
+use crate::storage::table::mvcc::MvccHeap;
+use crate::sync::{Latch as _, Synchronized};
+
+/// Vacuum `table` once (see `MvccHeap::vacuum`)
+pub fn vacuum(table: &MvccHeap) -> VacuumStats {
+    table.vacuum()
+}
+
+/// A background vacuum of a set of tables. Dropping it stops the thread, waiting for a pass in progress to finish
+pub struct VacuumTask {
+    totals: Synchronized<VacuumStats>,
+    writer: BackgroundWriter,
+}
+
+impl VacuumTask {
+    /// Vacuum every table in `tables` right away and then every `interval`
+    pub fn start(tables: Vec<MvccHeap>, interval: Duration) -> Self {
+        let totals = Synchronized::init(VacuumStats::default());
+        let writer = {
+            let totals = totals.clone();
+            BackgroundWriter::spawn(interval, move || {
+                for table in tables.iter() {
+                    let stats = table.vacuum();
+                    let mut totals = totals.lock();
+                    totals.pages += stats.pages;
+                    totals.removed += stats.removed;
+                    totals.compacted += stats.compacted;
+                    totals.reclaimed += stats.reclaimed;
+                }
+            })
+        };
+        VacuumTask { totals, writer }
+    }
+
+    /// What every pass so far did, added up
+    pub fn totals(&self) -> VacuumStats {
+        *self.totals.lock()
+    }
+
+    pub fn stop(self) {
+        self.writer.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::shared::cwd;
+    use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+    use crate::storage::table::heap::{Rid, TableHeap};
+    use crate::storage::table::mvcc::{MvccManager, MvccManagerApi as _};
+    use crate::txn::oracle::{TimestampOracle, TimestampOracleApi as _};
+
+    fn setup(name: &str) -> (MvccHeap, MvccManager, String) {
+        let dir = cwd() + "/tests/vacuum_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/{}.bin", dir, name);
+        let heap = TableHeap::create(BufferPool::create(&path).unwrap()).unwrap();
+        let mvcc = MvccManager::create(TimestampOracle::create());
+        (MvccHeap::new(heap, mvcc.clone()), mvcc, path)
+    }
+
+    fn fill(table: &MvccHeap, mvcc: &MvccManager, rows: u32) -> Vec<Rid> {
+        let mut txn = mvcc.begin();
+        let rids = (0..rows)
+            .map(|i| table.insert(&mut txn, &[i as u8; 64]).unwrap())
+            .collect();
+        mvcc.commit(txn);
+        rids
+    }
+
+    #[test]
+    fn test_vacuum_removes_dead_versions() {
+        let (table, mvcc, path) = setup("test_vacuum_removes_dead_versions");
+        let rids = fill(&table, &mvcc, 100);
+
+        let reader = mvcc.begin();
+        let mut writer = mvcc.begin();
+        let new_rids: Vec<Rid> = rids
+            .iter()
+            .map(|rid| table.update(&mut writer, *rid, &[7; 64]).unwrap())
+            .collect();
+        mvcc.commit(writer);
+        // the reader may still need the old versions
+        assert!(vacuum(&table).removed == 0);
+        assert!(table.read(&reader.snapshot(), new_rids[0]).unwrap() == vec![0; 64]);
+        mvcc.commit(reader);
+
+        let stats = vacuum(&table);
+        assert!(stats.removed == 100 && stats.compacted > 0 && stats.reclaimed > 0);
+        let now = mvcc.begin();
+        assert!(table.scan(&now.snapshot()).count() == 100);
+        assert!(table.read(&now.snapshot(), new_rids[0]).unwrap() == vec![7; 64]);
+        // the chain no longer leads back to the removed version
+        assert!(table.read(&now.snapshot(), rids[0]).is_none());
+
+        // deleted rows go once nobody can see them
+        let mut deleter = now;
+        for rid in new_rids.iter().step_by(2) {
+            table.delete(&mut deleter, *rid).unwrap();
+        }
+        mvcc.commit(deleter);
+        assert!(vacuum(&table).removed == 50);
+        // and a second pass has nothing left to do
+        let stats = vacuum(&table);
+        assert!(stats.removed == 0 && stats.compacted == 0 && stats.reclaimed == 0);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_background_vacuum() {
+        let (table, mvcc, path) = setup("test_background_vacuum");
+        let rids = fill(&table, &mvcc, 40);
+        let task = VacuumTask::start(vec![table.clone()], Duration::from_millis(10));
+        let mut txn = mvcc.begin();
+        for rid in rids.iter() {
+            table.delete(&mut txn, *rid).unwrap();
+        }
+        mvcc.commit(txn);
+
+        let start = Instant::now();
+        while task.totals().removed < 40 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        task.stop();
+        assert!(table.heap().iter().count() == 0);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+/// This file implements schemas and tuples: a `Schema` describes a row layout as a list of typed columns, and a `Tuple` holds
+/// one row's values and knows how to serialize itself according to a schema. A serialized tuple looks like
+///
+/// ```text
+/// | null bitmap (1 bit per column) | fixed-size column slots | variable-length data |
+/// ```
+///
+/// Integers and booleans are stored little endian in their slot, fixed strings (`Char`) are zero padded to their declared
+/// length, and variable-length strings (`Varchar`) store a 2 byte offset and 2 byte length into the variable-length area. A
+/// null column's slot is left zeroed.
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnType {
+    Bool,
+    Int32,
+    Int64,
+    /// String of exactly this many bytes, zero padded. Trailing zeros are stripped when read back
+    Char(u16),
+    /// String of at most this many bytes
+    Varchar(u16),
+}
+
+impl ColumnType {
+    /// Bytes the column takes up in the fixed-size part of a tuple
+    fn slot_size(self) -> usize {
+        match self {
+            ColumnType::Bool => 1,
+            ColumnType::Int32 => 4,
+            ColumnType::Int64 => 8,
+            ColumnType::Char(len) => len as usize,
+            ColumnType::Varchar(_) => 4,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Column {
+    pub name: String,
+    pub column_type: ColumnType,
+    pub nullable: bool,
+}
+
+impl Column {
+    pub fn new(name: &str, column_type: ColumnType) -> Self {
+        Column {
+            name: name.to_string(),
+            column_type,
+            nullable: false,
+        }
+    }
+
+    pub fn nullable(mut self) -> Self {
+        self.nullable = true;
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Schema {
+    columns: Vec<Column>,
+}
+
+impl Schema {
+    pub fn new(columns: Vec<Column>) -> Self {
+        Schema { columns }
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    /// Position of the column called `name`
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| column.name == name)
+    }
+
+    fn bitmap_size(&self) -> usize {
+        self.columns.len().div_ceil(8)
+    }
+
+    /// Size of a tuple with every variable-length column empty
+    pub fn fixed_size(&self) -> usize {
+        self.bitmap_size()
+            + self
+                .columns
+                .iter()
+                .map(|column| column.column_type.slot_size())
+                .sum::<usize>()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int32(i32),
+    Int64(i64),
+    Str(String),
+}
+
+impl Value {
+    /// Whether the value can be stored in a column of `column`'s type
+    fn fits(&self, column: &Column) -> bool {
+        match (self, column.column_type) {
+            (Value::Null, _) => column.nullable,
+            (Value::Bool(_), ColumnType::Bool)
+            | (Value::Int32(_), ColumnType::Int32)
+            | (Value::Int64(_), ColumnType::Int64) => true,
+            (Value::Str(s), ColumnType::Char(len) | ColumnType::Varchar(len)) => {
+                s.len() <= len as usize
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int32(n) => write!(f, "{}", n),
+            Value::Int64(n) => write!(f, "{}", n),
+            Value::Str(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tuple {
+    values: Vec<Value>,
+}
+
+impl Tuple {
+    pub fn new(values: Vec<Value>) -> Self {
+        Tuple { values }
+    }
+
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    pub fn value(&self, index: usize) -> Option<&Value> {
+        self.values.get(index)
+    }
+
+    /// Serialize the tuple according to `schema`. Returns `None` if the values don't match the schema's columns (wrong count
+    /// or type, null in a non-nullable column, string too long)
+    pub fn serialize(&self, schema: &Schema) -> Option<Vec<u8>> {
+        if self.values.len() != schema.len() {
+            return None;
+        }
+        let mut bytes = vec![0u8; schema.fixed_size()];
+        let mut slot = schema.bitmap_size();
+        for (i, (value, column)) in self.values.iter().zip(schema.columns()).enumerate() {
+            if !value.fits(column) {
+                return None;
+            }
+            let size = column.column_type.slot_size();
+            match (value, column.column_type) {
+                (Value::Null, _) => bytes[i / 8] |= 1 << (i % 8),
+                (Value::Bool(b), _) => bytes[slot] = *b as u8,
+                (Value::Int32(n), _) => bytes[slot..slot + size].copy_from_slice(&n.to_le_bytes()),
+                (Value::Int64(n), _) => bytes[slot..slot + size].copy_from_slice(&n.to_le_bytes()),
+                (Value::Str(s), ColumnType::Char(_)) => {
+                    bytes[slot..slot + s.len()].copy_from_slice(s.as_bytes())
+                }
+                (Value::Str(s), _) => {
+                    let offset = bytes.len();
+                    bytes[slot..slot + 2].copy_from_slice(&(offset as u16).to_le_bytes());
+                    bytes[slot + 2..slot + 4].copy_from_slice(&(s.len() as u16).to_le_bytes());
+                    bytes.extend_from_slice(s.as_bytes());
+                }
+            }
+            slot += size;
+        }
+        Some(bytes)
+    }
+
+    /// Read a tuple serialized with `schema` from the start of `bytes`. Anything after the tuple is ignored. Returns `None` if
+    /// `bytes` is too short or holds something that isn't a valid value
+    pub fn deserialize(schema: &Schema, bytes: &[u8]) -> Option<Tuple> {
+        if bytes.len() < schema.fixed_size() {
+            return None;
+        }
+        let mut values = Vec::with_capacity(schema.len());
+        let mut slot = schema.bitmap_size();
+        for (i, column) in schema.columns().iter().enumerate() {
+            let size = column.column_type.slot_size();
+            let data = &bytes[slot..slot + size];
+            slot += size;
+            if bytes[i / 8] & (1 << (i % 8)) != 0 {
+                values.push(Value::Null);
+                continue;
+            }
+            let value = match column.column_type {
+                ColumnType::Bool => Value::Bool(data[0] != 0),
+                ColumnType::Int32 => Value::Int32(i32::from_le_bytes(data.try_into().unwrap())),
+                ColumnType::Int64 => Value::Int64(i64::from_le_bytes(data.try_into().unwrap())),
+                ColumnType::Char(_) => {
+                    let len = data
+                        .iter()
+                        .rposition(|b| *b != 0)
+                        .map_or(0, |last| last + 1);
+                    Value::Str(String::from_utf8(data[..len].to_vec()).ok()?)
+                }
+                ColumnType::Varchar(_) => {
+                    let offset = u16::from_le_bytes(data[..2].try_into().unwrap()) as usize;
+                    let len = u16::from_le_bytes(data[2..].try_into().unwrap()) as usize;
+                    Value::Str(String::from_utf8(bytes.get(offset..offset + len)?.to_vec()).ok()?)
+                }
+            };
+            values.push(value);
+        }
+        Some(Tuple { values })
+    }
+}
+
+impl Display for Tuple {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let values: Vec<String> = self.values.iter().map(Value::to_string).collect();
+        write!(f, "({})", values.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::buffer::io;
+
+    fn song_schema() -> Schema {
+        Schema::new(vec![
+            Column::new("id", ColumnType::Int32),
+            Column::new("title", ColumnType::Varchar(100)),
+            Column::new("artist", ColumnType::Char(50)),
+            Column::new("plays", ColumnType::Int64).nullable(),
+            Column::new("explicit", ColumnType::Bool),
+        ])
+    }
+
+    #[test]
+    fn test_serialize_deserialize() {
+        let schema = song_schema();
+        let tuple = Tuple::new(vec![
+            Value::Int32(1),
+            Value::Str("Sweater Weather".to_string()),
+            Value::Str("The Neighbourhood".to_string()),
+            Value::Null,
+            Value::Bool(false),
+        ]);
+        let bytes = tuple.serialize(&schema).unwrap();
+        assert!(bytes.len() == schema.fixed_size() + "Sweater Weather".len());
+        assert!(Tuple::deserialize(&schema, &bytes).unwrap() == tuple);
+        assert!(schema.column_index("artist") == Some(2));
+        assert!(tuple.to_string() == "(1, Sweater Weather, The Neighbourhood, NULL, false)");
+
+        // round trip through a page buffer
+        let buf = io::to_buffer_with_schema(&tuple, &schema).unwrap();
+        assert!(io::from_buffer_with_schema(&buf, &schema).unwrap() == tuple);
+
+        // values that don't match the schema are rejected
+        let mut values = tuple.values().to_vec();
+        values[0] = Value::Null;
+        assert!(Tuple::new(values.clone()).serialize(&schema).is_none());
+        values[0] = Value::Int64(1);
+        assert!(Tuple::new(values.clone()).serialize(&schema).is_none());
+        values[0] = Value::Int32(1);
+        values[2] = Value::Str("x".repeat(51));
+        assert!(Tuple::new(values.clone()).serialize(&schema).is_none());
+        assert!(Tuple::new(values[..4].to_vec())
+            .serialize(&schema)
+            .is_none());
+        assert!(Tuple::deserialize(&schema, &bytes[..schema.fixed_size() - 1]).is_none());
+    }
+}
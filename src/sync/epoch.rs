@@ -0,0 +1,336 @@
+/// This file implements epoch-based memory reclamation, for shared structures that readers reach through raw pointers without
+/// holding a latch (a lock-free page table, B-link nodes handed back for reuse, anything read through `data_ptr()` while its
+/// owner may replace it). A thread pins itself before it follows such a pointer and keeps the `Guard` it gets for as long as it
+/// uses what it found. A writer that unlinks something passes it to `Guard::defer_destroy` (or any clean-up to `Guard::defer`)
+/// instead of freeing it, and it's destroyed once every thread that was pinned at the time has unpinned, so no reader can
+/// still be looking at it.
+///
+/// Time is divided into epochs. Pinning records the global epoch the thread saw, and the global epoch only moves on from `e`
+/// once every pinned thread has seen `e`. Garbage retired in epoch `e` can only be reached by threads pinned in `e - 1` or `e`,
+/// so it's freed once the global epoch reaches `e + 2`. Advancing and freeing happen on the side: every `COLLECT_EVERY`
+/// deferrals, when a guard is flushed, and on `Collector::collect`.
+///
+/// `Guard::pin` uses a process-wide collector, registering each thread the first time it pins. A component whose garbage
+/// should be kept apart, and freed when the component is dropped, makes its own `Collector` and pins through a `LocalHandle`.
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::atomic::{fence, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use super::Mutex;
+
+/// Number of deferrals between attempts to advance the epoch and free garbage
+pub const COLLECT_EVERY: usize = 64;
+
+type Deferred = Box<dyn FnOnce() + Send>;
+
+/// A registered thread. Its state is the epoch it's pinned in, shifted left by one, with the low bit set while it's pinned
+struct Local {
+    state: AtomicU64,
+    // guards the thread holds. Only the owning thread touches it
+    pins: AtomicUsize,
+}
+
+struct Global {
+    epoch: AtomicU64,
+    locals: Mutex<Vec<Arc<Local>>>,
+    // deferred functions with the epoch they were retired in, oldest first
+    garbage: Mutex<VecDeque<(u64, Deferred)>>,
+    // deferrals since the last collection
+    retired: AtomicUsize,
+}
+
+impl Global {
+    /// Move the global epoch on if every pinned thread has seen it, then run the garbage that's two epochs old. Returns how
+    /// many deferred functions ran
+    fn collect(&self) -> usize {
+        self.try_advance();
+        let epoch = self.epoch.load(Ordering::Acquire);
+        let mut ready = Vec::new();
+        {
+            let mut garbage = self.garbage.lock();
+            while garbage
+                .front()
+                .is_some_and(|(retired, _)| retired + 2 <= epoch)
+            {
+                ready.push(garbage.pop_front().unwrap().1);
+            }
+        }
+        // run outside the latch, so a deferred function can retire garbage of its own
+        let count = ready.len();
+        for deferred in ready {
+            deferred();
+        }
+        count
+    }
+
+    fn try_advance(&self) {
+        let epoch = self.epoch.load(Ordering::Relaxed);
+        // pairs with the fence in `pin`: a thread that pinned before this point is seen pinned here
+        fence(Ordering::SeqCst);
+        let locals = self.locals.lock();
+        let lagging = locals.iter().any(|local| {
+            let state = local.state.load(Ordering::Relaxed);
+            state & 1 == 1 && state >> 1 != epoch
+        });
+        if !lagging {
+            let _ =
+                self.epoch
+                    .compare_exchange(epoch, epoch + 1, Ordering::Release, Ordering::Relaxed);
+        }
+    }
+
+    fn defer(&self, deferred: Deferred) {
+        let epoch = self.epoch.load(Ordering::Acquire);
+        self.garbage.lock().push_back((epoch, deferred));
+        if self.retired.fetch_add(1, Ordering::Relaxed) + 1 >= COLLECT_EVERY {
+            self.retired.store(0, Ordering::Relaxed);
+            self.collect();
+        }
+    }
+}
+
+impl Drop for Global {
+    /// Nobody can be pinned once the collector and every handle are gone, so whatever is left is unreachable
+    fn drop(&mut self) {
+        let garbage: Vec<(u64, Deferred)> = self.garbage.lock().drain(..).collect();
+        for (_, deferred) in garbage {
+            deferred();
+        }
+    }
+}
+
+/// An epoch-based garbage collector. Threads register with `register` and pin through the handle they get. Clones refer to the
+/// same collector
+#[derive(Clone)]
+pub struct Collector {
+    global: Arc<Global>,
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Collector::new()
+    }
+}
+
+impl Collector {
+    pub fn new() -> Self {
+        Collector {
+            global: Arc::new(Global {
+                epoch: AtomicU64::new(0),
+                locals: Mutex::new(Vec::new()),
+                garbage: Mutex::new(VecDeque::new()),
+                retired: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Register the calling thread. The handle isn't `Send`: every thread that pins registers for itself
+    pub fn register(&self) -> LocalHandle {
+        let local = Arc::new(Local {
+            state: AtomicU64::new(0),
+            pins: AtomicUsize::new(0),
+        });
+        self.global.locals.lock().push(local.clone());
+        LocalHandle {
+            global: self.global.clone(),
+            local,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Try to advance the epoch and run the garbage that's safe to free. Returns how many deferred functions ran. Garbage
+    /// needs two advances to become safe, and an advance waits for every pinned thread to catch up, so call it again (with no
+    /// guard held) to drain everything
+    pub fn collect(&self) -> usize {
+        self.global.collect()
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.global.epoch.load(Ordering::Acquire)
+    }
+
+    /// Number of deferred functions that haven't run yet
+    pub fn pending(&self) -> usize {
+        self.global.garbage.lock().len()
+    }
+}
+
+/// A thread's registration with a collector. Dropping it unregisters the thread
+pub struct LocalHandle {
+    global: Arc<Global>,
+    local: Arc<Local>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl LocalHandle {
+    /// Pin the thread until the returned guard is dropped. Pins nest: the thread stays pinned in the epoch of its first guard
+    /// until the last one goes
+    pub fn pin(&self) -> Guard {
+        if self.local.pins.fetch_add(1, Ordering::Relaxed) == 0 {
+            let epoch = self.global.epoch.load(Ordering::Relaxed);
+            self.local.state.store(epoch << 1 | 1, Ordering::Relaxed);
+            // publish the pin before reading anything it protects (pairs with the fence in `try_advance`)
+            fence(Ordering::SeqCst);
+        }
+        Guard {
+            global: self.global.clone(),
+            local: self.local.clone(),
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.local.pins.load(Ordering::Relaxed) > 0
+    }
+}
+
+impl Drop for LocalHandle {
+    fn drop(&mut self) {
+        self.global
+            .locals
+            .lock()
+            .retain(|local| !Arc::ptr_eq(local, &self.local));
+    }
+}
+
+/// Proof that the thread is pinned: nothing retired while it's held is freed before it's dropped. Not `Send`, since the pin
+/// belongs to the thread that took it
+pub struct Guard {
+    global: Arc<Global>,
+    local: Arc<Local>,
+    _not_send: PhantomData<*const ()>,
+}
+
+fn default_collector() -> &'static Collector {
+    static COLLECTOR: OnceLock<Collector> = OnceLock::new();
+    COLLECTOR.get_or_init(Collector::new)
+}
+
+thread_local! {
+    static HANDLE: LocalHandle = default_collector().register();
+}
+
+impl Guard {
+    /// Pin the calling thread with the process-wide collector
+    pub fn pin() -> Guard {
+        HANDLE.with(|handle| handle.pin())
+    }
+
+    /// Run `f` once no thread that's pinned now (this one included) can still be using what it cleans up
+    pub fn defer<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.global.defer(Box::new(f));
+    }
+
+    /// Drop the box behind `ptr` once no pinned thread can still be reading it.
+    ///
+    /// # Safety
+    /// `ptr` must come from `Box::into_raw`, must already be unreachable for threads that pin from now on, and must not be
+    /// retired twice
+    pub unsafe fn defer_destroy<T: Send + 'static>(&self, ptr: *mut T) {
+        let ptr = SendPtr(ptr);
+        self.defer(move || {
+            let ptr = ptr;
+            drop(unsafe { Box::from_raw(ptr.0) })
+        });
+    }
+
+    /// Try to advance the epoch and free what's safe to free now, rather than waiting for the next collection
+    pub fn flush(&self) -> usize {
+        self.global.collect()
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if self.local.pins.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.local.state.store(0, Ordering::Release);
+        }
+    }
+}
+
+/// A pointer retired by `defer_destroy`, owned by the deferred function from then on
+struct SendPtr<T>(*mut T);
+
+unsafe impl<T: Send> Send for SendPtr<T> {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicPtr;
+
+    use super::*;
+
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_epoch_reclamation() {
+        let collector = Collector::new();
+        let reader = collector.register();
+        let writer = collector.register();
+        let dropped = Arc::new(AtomicUsize::new(0));
+
+        // a reader pinned before the node is retired keeps it alive, however often the writer collects
+        let pinned = reader.pin();
+        let nested = reader.pin();
+        let node = Box::into_raw(Box::new(Counted(dropped.clone())));
+        unsafe { writer.pin().defer_destroy(node) };
+        drop(nested);
+        for _ in 0..10 {
+            collector.collect();
+        }
+        assert!(dropped.load(Ordering::SeqCst) == 0);
+        assert!(collector.pending() == 1);
+
+        drop(pinned);
+        while collector.pending() > 0 {
+            collector.collect();
+        }
+        assert!(dropped.load(Ordering::SeqCst) == 1);
+
+        // garbage left when the collector goes is freed with it
+        let node = Box::into_raw(Box::new(Counted(dropped.clone())));
+        unsafe { writer.pin().defer_destroy(node) };
+        drop((reader, writer, collector));
+        assert!(dropped.load(Ordering::SeqCst) == 2);
+    }
+
+    #[test]
+    fn test_concurrent_swaps() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let shared = AtomicPtr::new(Box::into_raw(Box::new(Counted(dropped.clone()))));
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let (shared, dropped) = (&shared, dropped.clone());
+                s.spawn(move || {
+                    for i in 0..2000 {
+                        let guard = Guard::pin();
+                        if (t + i) % 4 == 0 {
+                            let new = Box::into_raw(Box::new(Counted(dropped.clone())));
+                            let old = shared.swap(new, Ordering::AcqRel);
+                            unsafe { guard.defer_destroy(old) };
+                        } else {
+                            // the node this thread can see hasn't been dropped
+                            let node = unsafe { &*shared.load(Ordering::Acquire) };
+                            assert!(Arc::strong_count(&node.0) > 1);
+                        }
+                    }
+                });
+            }
+        });
+        let guard = Guard::pin();
+        let last = shared.load(Ordering::Acquire);
+        unsafe { guard.defer_destroy(last) };
+        drop(guard);
+        let collector = default_collector();
+        while collector.pending() > 0 {
+            collector.collect();
+        }
+        assert!(dropped.load(Ordering::SeqCst) == 2001);
+    }
+}
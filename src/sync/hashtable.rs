@@ -1,6 +1,141 @@
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
 
-use crate::sync::Synchronized;
+use super::{Mutex, Synchronized};
 
 /// Thread-safe hash table type (protected by mutex)
 pub type HashTable<T, K> = Synchronized<HashMap<T, K>>;
+
+/// Default number of shards in a `ShardedHashTable`
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// Thread-safe hash table split into independently latched shards (striped locking). Each key lives in the shard picked by its
+/// hash, so operations on keys in different shards don't contend. Every operation latches exactly one shard, except the
+/// whole-table ones (`len`, `entries`), which latch the shards one at a time and so don't see a consistent snapshot if the
+/// table is being modified concurrently. Clones refer to the same table.
+#[derive(Clone)]
+pub struct ShardedHashTable<K, V> {
+    shards: Vec<HashTable<K, V>>,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq, V: Clone> ShardedHashTable<K, V> {
+    pub fn new() -> Self {
+        ShardedHashTable::with_shards(DEFAULT_SHARDS)
+    }
+
+    pub fn with_shards(shards: usize) -> Self {
+        assert!(shards > 0);
+        ShardedHashTable {
+            shards: (0..shards)
+                .map(|_| Arc::new(Mutex::new(HashMap::new())))
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &HashTable<K, V> {
+        &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()]
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.shard(key).lock().get(key).cloned()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.shard(key).lock().contains_key(key)
+    }
+
+    /// Insert `value` under `key`, returning the value it replaced
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).lock().insert(key, value)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).lock().remove(key)
+    }
+
+    /// The value under `key`, inserting the result of `f` first if there is none. `f` runs under the shard's latch, so it's
+    /// called at most once per key even when several threads race to insert it, but it must not touch the table itself
+    pub fn get_or_insert_with(&self, key: K, f: impl FnOnce() -> V) -> V {
+        self.shard(&key).lock().entry(key).or_insert_with(f).clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.lock().is_empty())
+    }
+
+    /// Copy out every entry, in no particular order
+    pub fn entries(&self) -> Vec<(K, V)>
+    where
+        K: Clone,
+    {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
+
+impl<K: Hash + Eq, V: Clone> Default for ShardedHashTable<K, V> {
+    fn default() -> Self {
+        ShardedHashTable::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use rayon::ThreadPoolBuilder;
+
+    use super::*;
+
+    #[test]
+    fn test_sharded_hash_table() {
+        let table: ShardedHashTable<isize, isize> = ShardedHashTable::with_shards(4);
+        assert!(table.is_empty());
+        assert!(table.insert(1, 10).is_none());
+        assert!(table.insert(1, 11) == Some(10));
+        assert!(table.get(&1) == Some(11) && table.contains_key(&1));
+        assert!(table.remove(&1) == Some(11) && table.get(&1).is_none());
+
+        // concurrent inserts from 8 threads, each key created exactly once
+        let created = AtomicUsize::new(0);
+        let pool = ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        pool.scope(|s| {
+            for t in 0..8 {
+                let (table, created) = (&table, &created);
+                s.spawn(move |_| {
+                    for key in 0..1000 {
+                        let value = table.get_or_insert_with(key, || {
+                            created.fetch_add(1, Ordering::SeqCst);
+                            key * 2
+                        });
+                        assert!(value == key * 2);
+                        if key % 8 == t {
+                            table.insert(key + 1000, key);
+                        }
+                    }
+                });
+            }
+        });
+        assert!(created.load(Ordering::SeqCst) == 1000);
+        assert!(table.len() == 2000);
+        let mut entries = table.entries();
+        entries.sort();
+        assert!(entries[0] == (0, 0) && entries[1999] == (1999, 999));
+    }
+}
@@ -0,0 +1,464 @@
+/// This file implements the primitives behind `Synchronized`, `RwSynchronized`, `BinarySemaphore`, `CountingSemaphore` and
+/// `CondVar` on top of loom, for model checking (it's compiled instead of parking_lot with the `loom` feature). Each lock is a
+/// small state machine (readers, upgradable holder, writer) kept in a `loom::sync::Mutex` and waited on with a
+/// `loom::sync::Condvar`, so every acquisition and release is a point where loom can switch threads, and latches can be taken
+/// and released in different scopes like the raw implementation allows. The types mirror the parking_lot API the crate uses.
+///
+/// Loom doesn't model time: timed waits and timed latch acquisitions block like their untimed versions and never time out.
+/// Loom primitives only work inside `loom::model`, so with the feature on, run only the loom tests:
+/// `cargo test --release --features loom loom_`.
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use loom::sync::{Condvar as LoomCondvar, Mutex as LoomMutex};
+
+use super::{Latch, RwLatch, RwSynchronized, Synchronized};
+use crate::telemetry::trace;
+
+#[derive(Default)]
+struct LockState {
+    readers: usize,
+    upgradable: bool,
+    writer: bool,
+}
+
+/// Lock without data. A mutex only ever takes it exclusively
+struct RawLock {
+    state: LoomMutex<LockState>,
+    released: LoomCondvar,
+}
+
+impl RawLock {
+    fn new() -> Self {
+        RawLock {
+            state: LoomMutex::new(LockState::default()),
+            released: LoomCondvar::new(),
+        }
+    }
+
+    /// Wait until `free` holds, then `take` the lock
+    fn acquire(&self, free: impl Fn(&LockState) -> bool, take: impl FnOnce(&mut LockState)) {
+        let mut state = self.state.lock().unwrap();
+        while !free(&state) {
+            state = self.released.wait(state).unwrap();
+        }
+        take(&mut state);
+    }
+
+    fn try_acquire(
+        &self,
+        free: impl Fn(&LockState) -> bool,
+        take: impl FnOnce(&mut LockState),
+    ) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !free(&state) {
+            return false;
+        }
+        take(&mut state);
+        true
+    }
+
+    fn release(&self, give: impl FnOnce(&mut LockState)) {
+        give(&mut self.state.lock().unwrap());
+        self.released.notify_all();
+    }
+
+    fn lock_exclusive(&self) {
+        self.acquire(exclusive_free, |state| state.writer = true);
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        self.try_acquire(exclusive_free, |state| state.writer = true)
+    }
+
+    fn unlock_exclusive(&self) {
+        self.release(|state| state.writer = false);
+    }
+
+    fn lock_shared(&self) {
+        self.acquire(|state| !state.writer, |state| state.readers += 1);
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        self.try_acquire(|state| !state.writer, |state| state.readers += 1)
+    }
+
+    fn unlock_shared(&self) {
+        self.release(|state| state.readers -= 1);
+    }
+
+    fn lock_upgradable(&self) {
+        self.acquire(upgradable_free, |state| state.upgradable = true);
+    }
+
+    fn try_lock_upgradable(&self) -> bool {
+        self.try_acquire(upgradable_free, |state| state.upgradable = true)
+    }
+
+    fn unlock_upgradable(&self) {
+        self.release(|state| state.upgradable = false);
+    }
+
+    /// Trade the upgradable lock for an exclusive one once the readers are gone
+    fn upgrade(&self) {
+        self.acquire(
+            |state| state.readers == 0,
+            |state| {
+                state.upgradable = false;
+                state.writer = true;
+            },
+        );
+    }
+
+    fn downgrade(&self) {
+        self.release(|state| {
+            state.writer = false;
+            state.readers += 1;
+        });
+    }
+}
+
+fn exclusive_free(state: &LockState) -> bool {
+    !state.writer && !state.upgradable && state.readers == 0
+}
+
+fn upgradable_free(state: &LockState) -> bool {
+    !state.writer && !state.upgradable
+}
+
+pub struct Mutex<T> {
+    raw: RawLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub fn new(data: T) -> Self {
+        Mutex {
+            raw: RawLock::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.raw.lock_exclusive();
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.raw
+            .try_lock_exclusive()
+            .then_some(MutexGuard { mutex: self })
+    }
+
+    pub fn data_ptr(&self) -> *mut T {
+        self.data.get()
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.raw.unlock_exclusive();
+    }
+}
+
+pub struct RwLock<T> {
+    raw: RawLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub fn new(data: T) -> Self {
+        RwLock {
+            raw: RawLock::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.raw.lock_shared();
+        RwLockReadGuard { lock: self }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.raw.lock_exclusive();
+        RwLockWriteGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.raw
+            .try_lock_shared()
+            .then_some(RwLockReadGuard { lock: self })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.raw
+            .try_lock_exclusive()
+            .then_some(RwLockWriteGuard { lock: self })
+    }
+
+    pub fn data_ptr(&self) -> *mut T {
+        self.data.get()
+    }
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.raw.unlock_shared();
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.raw.unlock_exclusive();
+    }
+}
+
+#[derive(Default)]
+struct CondvarState {
+    waiters: usize,
+    // notifications not yet picked up by a waiter
+    wakeups: usize,
+}
+
+/// Condition variable over a `Mutex`. A waiter registers itself before releasing the mutex, so a notification can't slip in
+/// between the release and the wait
+pub struct Condvar {
+    state: LoomMutex<CondvarState>,
+    notified: LoomCondvar,
+}
+
+pub struct WaitTimeoutResult;
+
+impl WaitTimeoutResult {
+    /// Always false: loom doesn't model time
+    pub fn timed_out(&self) -> bool {
+        false
+    }
+}
+
+impl Condvar {
+    pub fn new() -> Self {
+        Condvar {
+            state: LoomMutex::new(CondvarState::default()),
+            notified: LoomCondvar::new(),
+        }
+    }
+
+    pub fn wait<T>(&self, guard: &mut MutexGuard<'_, T>) {
+        let mut state = self.state.lock().unwrap();
+        state.waiters += 1;
+        guard.mutex.raw.unlock_exclusive();
+        while state.wakeups == 0 {
+            state = self.notified.wait(state).unwrap();
+        }
+        state.wakeups -= 1;
+        state.waiters -= 1;
+        drop(state);
+        guard.mutex.raw.lock_exclusive();
+    }
+
+    pub fn wait_while<T>(
+        &self,
+        guard: &mut MutexGuard<'_, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) {
+        while condition(guard) {
+            self.wait(guard);
+        }
+    }
+
+    pub fn wait_for<T>(
+        &self,
+        guard: &mut MutexGuard<'_, T>,
+        _timeout: Duration,
+    ) -> WaitTimeoutResult {
+        self.wait(guard);
+        WaitTimeoutResult
+    }
+
+    pub fn wait_until<T>(
+        &self,
+        guard: &mut MutexGuard<'_, T>,
+        _deadline: Instant,
+    ) -> WaitTimeoutResult {
+        self.wait(guard);
+        WaitTimeoutResult
+    }
+
+    pub fn notify_one(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.waiters == state.wakeups {
+            return false;
+        }
+        state.wakeups += 1;
+        self.notified.notify_all();
+        true
+    }
+
+    pub fn notify_all(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let woken = state.waiters - state.wakeups;
+        state.wakeups = state.waiters;
+        self.notified.notify_all();
+        woken
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Condvar::new()
+    }
+}
+
+impl<T> Latch<T> for Synchronized<T> {
+    fn init(item: T) -> Self {
+        Arc::new(Mutex::new(item))
+    }
+
+    fn latch(&self) {
+        trace::latch("mutex", || self.raw.lock_exclusive());
+    }
+
+    fn try_latch(&self) -> bool {
+        self.raw.try_lock_exclusive()
+    }
+
+    fn latch_timeout(&self, _timeout: Duration) -> bool {
+        self.latch();
+        true
+    }
+
+    fn unlatch(&self) {
+        self.raw.unlock_exclusive();
+    }
+}
+
+impl<T> RwLatch<T> for RwSynchronized<T> {
+    fn init(item: T) -> Self {
+        Arc::new(RwLock::new(item))
+    }
+
+    fn latch_shared(&self) {
+        trace::latch("shared", || self.raw.lock_shared());
+    }
+
+    fn latch_upgradable(&self) {
+        trace::latch("upgradable", || self.raw.lock_upgradable());
+    }
+
+    fn latch_excl(&self) {
+        trace::latch("exclusive", || self.raw.lock_exclusive());
+    }
+
+    fn try_latch_shared(&self) -> bool {
+        self.raw.try_lock_shared()
+    }
+
+    fn try_latch_upgradable(&self) -> bool {
+        self.raw.try_lock_upgradable()
+    }
+
+    fn try_latch_excl(&self) -> bool {
+        self.raw.try_lock_exclusive()
+    }
+
+    fn latch_shared_timeout(&self, _timeout: Duration) -> bool {
+        self.latch_shared();
+        true
+    }
+
+    fn latch_upgradable_timeout(&self, _timeout: Duration) -> bool {
+        self.latch_upgradable();
+        true
+    }
+
+    fn latch_excl_timeout(&self, _timeout: Duration) -> bool {
+        self.latch_excl();
+        true
+    }
+
+    fn unlatch_shared(&self) {
+        self.raw.unlock_shared();
+    }
+
+    fn unlatch_upgradable(&self) {
+        self.raw.unlock_upgradable();
+    }
+
+    fn unlatch_excl(&self) {
+        self.raw.unlock_exclusive();
+    }
+
+    fn latch_upgrade_shared(&self) {
+        trace::latch("upgrade", || self.raw.upgrade());
+    }
+
+    fn upgrade(&self) -> bool {
+        if !self.raw.try_lock_upgradable() {
+            return false;
+        }
+        self.raw.unlock_shared();
+        trace::latch("upgrade", || self.raw.upgrade());
+        true
+    }
+
+    fn downgrade(&self) {
+        self.raw.downgrade();
+    }
+}
@@ -6,19 +6,65 @@
 ///----------------------------------------------------------------------------------------------------
 /// This file implements synchronization primitives and methods for synchronization primitives used
 /// throughout the implementation of the tree. Specifically, this file contains a correct
-/// implementation of a binary semaphore as well as latch/unlatch methods for synchronized objects
-/// (both protected by mutexes and protected by rwlocks). The mutexes are `parking_lot::Mutex` and
-/// the rwlocks are `parking_lot::RwLock` (not std::sync::Mutex/std::sync::RwLock).
+/// implementation of a binary semaphore, a counting semaphore and a condition variable as well as latch/unlatch methods for synchronized objects
+/// (both protected by mutexes and protected by rwlocks), and `Access`, which wraps the latch in a
+/// guard or a closure so the data is only reachable while it's held. The mutexes are
+/// `parking_lot::Mutex` and the rwlocks are `parking_lot::RwLock` (not std::sync::Mutex/
+/// std::sync::RwLock), except with the `loom` feature, which swaps them for model-checked ones (see loom.rs), and with the
+/// `lock-order` feature, which wraps them to catch latch order inversions and self-deadlocks (see order.rs).
 ///----------------------------------------------------------------------------------------------------
-use parking_lot::lock_api::{RawMutex as _, RawRwLock as _, RawRwLockUpgrade as _};
-use parking_lot::{Condvar, Mutex, RwLock};
+#[cfg(not(any(miri, feature = "safe-sync", feature = "loom", feature = "lock-order")))]
+use parking_lot::lock_api::{
+    RawMutex as _, RawMutexTimed as _, RawRwLock as _, RawRwLockDowngrade as _,
+    RawRwLockTimed as _, RawRwLockUpgrade as _, RawRwLockUpgradeTimed as _,
+};
+#[cfg(not(any(feature = "loom", feature = "lock-order")))]
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+#[cfg(all(
+    not(feature = "loom"),
+    any(feature = "lock-order", not(any(miri, feature = "safe-sync")))
+))]
+use crate::telemetry::trace;
+
+#[cfg(feature = "loom")]
+use self::loom::{Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(all(feature = "lock-order", not(feature = "loom")))]
+use self::order::{Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+pub mod epoch;
 pub mod hashtable;
+#[cfg(feature = "loom")]
+mod loom;
+#[cfg(all(feature = "lock-order", not(feature = "loom")))]
+mod order;
+#[cfg(all(
+    any(miri, feature = "safe-sync"),
+    not(any(feature = "loom", feature = "lock-order"))
+))]
+mod safe;
 
 /// BinarySemaphore: Semaphore with two states. Useful for setup tasks or making the main thread wait. Prefer using condvars if you're
-/// trying to synchronize threads though.
-pub type BinarySemaphore = Arc<(Mutex<bool>, Condvar)>;
+/// trying to synchronize threads though. A semaphore can also be closed, which wakes every waiter for good, e.g. to shut down a
+/// background thread that sleeps on it.
+pub type BinarySemaphore = Arc<(Mutex<SemaphoreState>, Condvar)>;
+
+pub struct SemaphoreState {
+    value: bool,
+    closed: bool,
+}
+
+/// CountingSemaphore: Semaphore holding a count of available permits. `wait` takes a permit, blocking until one is available,
+/// and `post` returns one. Useful for waiting until n things have happened, or for capping how many threads do something at
+/// once.
+pub type CountingSemaphore = Arc<(Mutex<usize>, Condvar)>;
+
+/// Condition variable for waiting on the data behind a `Synchronized<T>`: wait with its guard, which is released while
+/// waiting and held again on wakeup. Can pass between threads (implements the clone trait)
+pub type CondVar = Arc<Condvar>;
 
 /// Protect anything with a Mutex. Can pass between threads (implements the clone trait)
 pub type Synchronized<T> = Arc<Mutex<T>>;
@@ -26,6 +72,12 @@ pub type Synchronized<T> = Arc<Mutex<T>>;
 /// Protect anything with a RwLock. Can pass between threads (implements the clone trait).
 pub type RwSynchronized<T> = Arc<RwLock<T>>;
 
+/// RAII guard for a `Synchronized<T>`: holds the latch and releases it when dropped
+pub type Guard<'a, T> = MutexGuard<'a, T>;
+/// RAII guards for a `RwSynchronized<T>`, holding a shared or an exclusive latch until dropped
+pub type SharedGuard<'a, T> = RwLockReadGuard<'a, T>;
+pub type ExclusiveGuard<'a, T> = RwLockWriteGuard<'a, T>;
+
 /// Use this to specify the latch type
 #[allow(unused)]
 #[derive(PartialEq, Eq)]
@@ -40,12 +92,35 @@ pub trait BinarySemaphoreMethods {
     fn init(state: bool) -> Self;
     fn post(&self);
     fn wait(&self) -> bool;
+    fn wait_timeout(&self, timeout: Duration) -> Option<bool>;
+    fn close(&self);
+    fn is_closed(&self) -> bool;
+}
+
+/// Additional methods for Counting Semaphores
+pub trait CountingSemaphoreMethods {
+    fn init(permits: usize) -> Self;
+    fn post(&self);
+    fn wait(&self);
+    fn try_wait(&self) -> bool;
+}
+
+/// Additional methods for CondVars
+pub trait CondVarMethods {
+    fn init() -> Self;
+    fn wait<T>(&self, guard: &mut Guard<'_, T>);
+    fn wait_while<T>(&self, guard: &mut Guard<'_, T>, condition: impl FnMut(&mut T) -> bool);
+    fn wait_timeout<T>(&self, guard: &mut Guard<'_, T>, timeout: Duration) -> bool;
+    fn notify_one(&self) -> bool;
+    fn notify_all(&self) -> usize;
 }
 
 /// Additional methods for Synchronized<T> objects
 pub trait Latch<T> {
     fn init(item: T) -> Self;
     fn latch(&self);
+    fn try_latch(&self) -> bool;
+    fn latch_timeout(&self, timeout: Duration) -> bool;
     fn unlatch(&self);
 }
 
@@ -55,32 +130,205 @@ pub trait RwLatch<T> {
     fn latch_shared(&self);
     fn latch_upgradable(&self);
     fn latch_excl(&self);
+    fn try_latch_shared(&self) -> bool;
+    fn try_latch_upgradable(&self) -> bool;
+    fn try_latch_excl(&self) -> bool;
+    fn latch_shared_timeout(&self, timeout: Duration) -> bool;
+    fn latch_upgradable_timeout(&self, timeout: Duration) -> bool;
+    fn latch_excl_timeout(&self, timeout: Duration) -> bool;
     fn unlatch_shared(&self);
     fn unlatch_upgradable(&self);
     fn unlatch_excl(&self);
     fn latch_upgrade_shared(&self);
+    fn upgrade(&self) -> bool;
+    fn downgrade(&self);
+}
+
+/// Safe access to a synchronized object. Each method latches the object, hands out a reference that can't outlive the latch,
+/// and unlatches when the guard (or the closure) is done, so the latch can't be forgotten and the data can't be reached
+/// without it. Prefer this over `Latch`/`RwLatch` and `data_ptr()` wherever the latch is taken and released in one scope.
+/// For a `Synchronized<T>` shared and exclusive access both take the mutex
+pub trait Access<T> {
+    type Shared<'a>: Deref<Target = T>
+    where
+        Self: 'a;
+    type Exclusive<'a>: DerefMut<Target = T>
+    where
+        Self: 'a;
+
+    fn shared(&self) -> Self::Shared<'_>;
+    fn exclusive(&self) -> Self::Exclusive<'_>;
+
+    /// Run `f` with a shared reference, under a shared latch
+    fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.shared())
+    }
+
+    /// Run `f` with a mutable reference, under an exclusive latch
+    fn with_mut<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.exclusive())
+    }
+}
+
+impl<T> Access<T> for Synchronized<T> {
+    type Shared<'a>
+        = Guard<'a, T>
+    where
+        T: 'a;
+    type Exclusive<'a>
+        = Guard<'a, T>
+    where
+        T: 'a;
+
+    fn shared(&self) -> Guard<'_, T> {
+        self.lock()
+    }
+
+    fn exclusive(&self) -> Guard<'_, T> {
+        self.lock()
+    }
+}
+
+impl<T> Access<T> for RwSynchronized<T> {
+    type Shared<'a>
+        = SharedGuard<'a, T>
+    where
+        T: 'a;
+    type Exclusive<'a>
+        = ExclusiveGuard<'a, T>
+    where
+        T: 'a;
+
+    fn shared(&self) -> SharedGuard<'_, T> {
+        self.read()
+    }
+
+    fn exclusive(&self) -> ExclusiveGuard<'_, T> {
+        self.write()
+    }
 }
 
 /// Implement most of the POSIX Semaphore API (init/post/wait) but not value
 impl BinarySemaphoreMethods for BinarySemaphore {
     fn init(state: bool) -> Self {
-        Arc::new((Mutex::new(state), Condvar::new()))
+        Arc::new((
+            Mutex::new(SemaphoreState {
+                value: state,
+                closed: false,
+            }),
+            Condvar::new(),
+        ))
     }
 
     fn post(&self) {
         let (mutex, condvar) = &**self;
         let mut state = mutex.lock();
-        *state = !*state;
+        state.value = !state.value;
         condvar.notify_one();
     }
 
+    /// Block until the semaphore is posted (returns true) or closed (returns false)
     fn wait(&self) -> bool {
         let (mutex, condvar) = &**self;
         let mut state = mutex.lock();
-        while !*state {
+        while !state.value && !state.closed {
             condvar.wait(&mut state);
         }
-        *state
+        !state.closed
+    }
+
+    /// Like `wait`, giving up after `timeout`: `Some(true)` if posted, `Some(false)` if closed, `None` if it timed out
+    fn wait_timeout(&self, timeout: Duration) -> Option<bool> {
+        let (mutex, condvar) = &**self;
+        let mut state = mutex.lock();
+        let deadline = Instant::now() + timeout;
+        while !state.value && !state.closed {
+            if condvar.wait_until(&mut state, deadline).timed_out() {
+                break;
+            }
+        }
+        match (state.closed, state.value) {
+            (true, _) => Some(false),
+            (false, true) => Some(true),
+            (false, false) => None,
+        }
+    }
+
+    /// Close the semaphore, waking every waiter. Closing is permanent: every later wait returns false right away
+    fn close(&self) {
+        let (mutex, condvar) = &**self;
+        mutex.lock().closed = true;
+        condvar.notify_all();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.0.lock().closed
+    }
+}
+
+impl CountingSemaphoreMethods for CountingSemaphore {
+    fn init(permits: usize) -> Self {
+        Arc::new((Mutex::new(permits), Condvar::new()))
+    }
+
+    /// Return a permit, waking one waiter
+    fn post(&self) {
+        let (mutex, condvar) = &**self;
+        *mutex.lock() += 1;
+        condvar.notify_one();
+    }
+
+    /// Take a permit, blocking until one is available
+    fn wait(&self) {
+        let (mutex, condvar) = &**self;
+        let mut permits = mutex.lock();
+        while *permits == 0 {
+            condvar.wait(&mut permits);
+        }
+        *permits -= 1;
+    }
+
+    /// Take a permit if one is available. Returns whether it took one
+    fn try_wait(&self) -> bool {
+        let mut permits = self.0.lock();
+        if *permits == 0 {
+            return false;
+        }
+        *permits -= 1;
+        true
+    }
+}
+
+/// Thin wrapper around `parking_lot::Condvar`. As with any condition variable, wakeups can be spurious: wait in a loop on the
+/// condition, or use `wait_while`
+impl CondVarMethods for CondVar {
+    fn init() -> Self {
+        Arc::new(Condvar::new())
+    }
+
+    /// Release the latch held by `guard`, block until notified, then latch again
+    fn wait<T>(&self, guard: &mut Guard<'_, T>) {
+        Condvar::wait(self, guard);
+    }
+
+    /// Block (releasing the latch while blocked) for as long as `condition` holds
+    fn wait_while<T>(&self, guard: &mut Guard<'_, T>, condition: impl FnMut(&mut T) -> bool) {
+        Condvar::wait_while(self, guard, condition);
+    }
+
+    /// Like `wait`, giving up after `timeout`. Returns false if it timed out
+    fn wait_timeout<T>(&self, guard: &mut Guard<'_, T>, timeout: Duration) -> bool {
+        !Condvar::wait_for(self, guard, timeout).timed_out()
+    }
+
+    /// Wake one waiter. Returns whether there was one
+    fn notify_one(&self) -> bool {
+        Condvar::notify_one(self)
+    }
+
+    /// Wake every waiter. Returns how many there were
+    fn notify_all(&self) -> usize {
+        Condvar::notify_all(self)
     }
 }
 
@@ -88,14 +336,26 @@ impl BinarySemaphoreMethods for BinarySemaphore {
 /// Examples of when you need to use these methods:
 /// - If you need to place a lock on an object in one function and unlock it in another function (i.e. when you can't do everything you)
 ///   want in one scope.
+#[cfg(all(
+    not(feature = "loom"),
+    any(feature = "lock-order", not(any(miri, feature = "safe-sync")))
+))]
 impl<T> Latch<T> for Synchronized<T> {
     fn init(item: T) -> Self {
         Arc::new(Mutex::new(item))
     }
     fn latch(&self) {
-        unsafe {
-            self.raw().lock();
-        }
+        trace::latch("mutex", || unsafe { self.raw().lock() });
+    }
+
+    /// Acquire the lock if nobody holds it. Returns whether it was acquired; if so, release it with `unlatch`
+    fn try_latch(&self) -> bool {
+        unsafe { self.raw().try_lock() }
+    }
+
+    /// Acquire the lock, giving up once `timeout` has passed. Returns whether it was acquired
+    fn latch_timeout(&self, timeout: Duration) -> bool {
+        trace::latch("mutex", || unsafe { self.raw().try_lock_for(timeout) })
     }
     fn unlatch(&self) {
         unsafe {
@@ -108,6 +368,10 @@ impl<T> Latch<T> for Synchronized<T> {
 /// Examples of when you need to use these methods:
 /// - If you need to place a lock on an object in one function and unlock it in another function (i.e. when you can't do everything you)
 ///   want in one scope.
+#[cfg(all(
+    not(feature = "loom"),
+    any(feature = "lock-order", not(any(miri, feature = "safe-sync")))
+))]
 impl<T> RwLatch<T> for RwSynchronized<T> {
     fn init(item: T) -> Self {
         Arc::new(RwLock::new(item))
@@ -115,23 +379,53 @@ impl<T> RwLatch<T> for RwSynchronized<T> {
 
     /// Acquire a shared lock. Must not hold a lock in the current context.
     fn latch_shared(&self) {
-        unsafe {
-            self.raw().lock_shared();
-        }
+        trace::latch("shared", || unsafe { self.raw().lock_shared() });
     }
 
     /// Acquire an upgradable lock. Must not hold a lock in the current context.
     fn latch_upgradable(&self) {
-        unsafe {
-            self.raw().lock_upgradable();
-        }
+        trace::latch("upgradable", || unsafe { self.raw().lock_upgradable() });
     }
 
     /// Acquire an exclusive lock. Must not hold a lock in the current context.
     fn latch_excl(&self) {
-        unsafe {
-            self.raw().lock_exclusive();
-        }
+        trace::latch("exclusive", || unsafe { self.raw().lock_exclusive() });
+    }
+
+    /// Acquire a shared lock if it can be had without waiting. Returns whether it was acquired
+    fn try_latch_shared(&self) -> bool {
+        unsafe { self.raw().try_lock_shared() }
+    }
+
+    /// Acquire an upgradable lock if it can be had without waiting. Returns whether it was acquired
+    fn try_latch_upgradable(&self) -> bool {
+        unsafe { self.raw().try_lock_upgradable() }
+    }
+
+    /// Acquire an exclusive lock if it can be had without waiting. Returns whether it was acquired
+    fn try_latch_excl(&self) -> bool {
+        unsafe { self.raw().try_lock_exclusive() }
+    }
+
+    /// Acquire a shared lock, giving up once `timeout` has passed. Returns whether it was acquired
+    fn latch_shared_timeout(&self, timeout: Duration) -> bool {
+        trace::latch("shared", || unsafe {
+            self.raw().try_lock_shared_for(timeout)
+        })
+    }
+
+    /// Acquire an upgradable lock, giving up once `timeout` has passed. Returns whether it was acquired
+    fn latch_upgradable_timeout(&self, timeout: Duration) -> bool {
+        trace::latch("upgradable", || unsafe {
+            self.raw().try_lock_upgradable_for(timeout)
+        })
+    }
+
+    /// Acquire an exclusive lock, giving up once `timeout` has passed. Returns whether it was acquired
+    fn latch_excl_timeout(&self, timeout: Duration) -> bool {
+        trace::latch("exclusive", || unsafe {
+            self.raw().try_lock_exclusive_for(timeout)
+        })
     }
 
     /// Release a shared lock. Must hold a shared lock in the current context.
@@ -158,18 +452,44 @@ impl<T> RwLatch<T> for RwSynchronized<T> {
     /// Upgrade an upgradable lock to an exclusive one. Must hold an upgradable lock in the current context that has not yet been
     /// upgraded
     fn latch_upgrade_shared(&self) {
+        trace::latch("upgrade", || unsafe { self.raw().upgrade() });
+    }
+
+    /// Upgrade a shared lock to an exclusive one without letting another writer in between. Must hold a shared lock in the
+    /// current context. Only one thread can be upgrading at a time: the upgrader first takes the upgradable lock (which
+    /// excludes writers and other upgraders but not readers), then trades its shared lock for it and waits for the remaining
+    /// readers to leave. Returns false without waiting if another upgrade is pending, in which case the shared lock is still
+    /// held. The caller must release it before retrying, since the pending upgrader is waiting for it to be released
+    fn upgrade(&self) -> bool {
+        unsafe {
+            if !self.raw().try_lock_upgradable() {
+                return false;
+            }
+            self.raw().unlock_shared();
+        }
+        trace::latch("upgrade", || unsafe { self.raw().upgrade() });
+        true
+    }
+
+    /// Downgrade an exclusive lock to a shared one without letting a writer in between, e.g. to keep reading a page after
+    /// modifying it. Must hold an exclusive lock in the current context
+    fn downgrade(&self) {
         unsafe {
-            self.raw().upgrade();
+            self.raw().downgrade();
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
     use rayon::ThreadPoolBuilder;
 
     use super::{
-        BinarySemaphore, BinarySemaphoreMethods as _, Latch as _, RwLatch as _, RwSynchronized,
+        Access as _, BinarySemaphore, BinarySemaphoreMethods as _, CondVar, CondVarMethods as _,
+        CountingSemaphore, CountingSemaphoreMethods as _, Latch as _, RwLatch as _, RwSynchronized,
         Synchronized,
     };
     struct TestStruct {
@@ -212,14 +532,14 @@ mod tests {
             }
         }
         let state = sem.wait();
-        assert!(state == true);
+        assert!(state);
         assert!(unsafe { (*sync_struct.data_ptr()).data } > 50);
     }
 
     fn rw_determine(rw_sync: &RwSynchronized<TestStruct>) -> bool {
         unsafe {
             rw_sync.latch_upgradable();
-            (*rw_sync.data_ptr()).data % 3 == 0
+            (*rw_sync.data_ptr()).data.is_multiple_of(3)
         }
     }
 
@@ -234,7 +554,7 @@ mod tests {
 
     fn rw_maybe_increment_thread(rw_sync: &RwSynchronized<TestStruct>) {
         loop {
-            if rw_determine(&rw_sync) {
+            if rw_determine(rw_sync) {
                 rw_sync.latch_upgrade_shared();
                 unsafe {
                     (*rw_sync.data_ptr()).data += 5;
@@ -270,7 +590,192 @@ mod tests {
             }
         }
         let state = sem.wait();
-        assert!(state == true);
+        assert!(state);
         assert!(unsafe { (*rw_sync_struct.data_ptr()).data } > 50);
     }
+
+    #[test]
+    fn test_semaphore_timeout_and_close() {
+        let sem = BinarySemaphore::init(false);
+        let started = Instant::now();
+        assert!(sem.wait_timeout(Duration::from_millis(20)).is_none());
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        sem.post();
+        assert!(sem.wait_timeout(Duration::from_millis(20)) == Some(true));
+        sem.post();
+
+        // closing wakes every waiter, and they can tell it apart from a post
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        pool.scope(|s| {
+            for i in 0..4 {
+                let sem = sem.clone();
+                s.spawn(move |_| {
+                    if i % 2 == 0 {
+                        assert!(!sem.wait());
+                    } else {
+                        assert!(sem.wait_timeout(Duration::from_secs(10)) == Some(false));
+                    }
+                });
+            }
+            std::thread::sleep(Duration::from_millis(10));
+            sem.close();
+        });
+        assert!(sem.is_closed());
+        sem.post();
+        assert!(!sem.wait());
+    }
+
+    #[test]
+    fn test_counting_semaphore() {
+        let sem = CountingSemaphore::init(2);
+        assert!(sem.try_wait());
+        sem.wait();
+        assert!(!sem.try_wait());
+
+        // wait for eight threads to be done
+        let pool = ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        let done = AtomicUsize::new(0);
+        pool.scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|_| {
+                    done.fetch_add(1, Ordering::SeqCst);
+                    sem.post();
+                });
+            }
+            for _ in 0..8 {
+                sem.wait();
+            }
+            assert!(done.load(Ordering::SeqCst) == 8);
+        });
+        assert!(!sem.try_wait());
+    }
+
+    #[test]
+    fn test_condvar() {
+        let sync_struct = Synchronized::init(TestStruct { data: 0 });
+        let changed = CondVar::init();
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        pool.scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|_| {
+                    for _ in 0..10 {
+                        sync_struct.lock().data += 1;
+                        changed.notify_all();
+                    }
+                });
+            }
+            let mut guard = sync_struct.lock();
+            changed.wait_while(&mut guard, |inner| inner.data < 40);
+            assert!(guard.data == 40);
+        });
+
+        let mut guard = sync_struct.lock();
+        assert!(!changed.wait_timeout(&mut guard, Duration::from_millis(10)));
+        assert!(!changed.notify_one());
+    }
+
+    #[test]
+    fn test_upgrade_downgrade() {
+        let rw_sync_struct = RwSynchronized::init(TestStruct { data: 0 });
+        let upgrades = AtomicUsize::new(0);
+        let pool = ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        pool.scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|_| {
+                    for _ in 0..500 {
+                        // read, then upgrade to write; a failed upgrade drops the read latch and starts over
+                        loop {
+                            rw_sync_struct.latch_shared();
+                            let seen = unsafe { (*rw_sync_struct.data_ptr()).data };
+                            if rw_sync_struct.upgrade() {
+                                // nobody wrote in between
+                                unsafe {
+                                    assert!((*rw_sync_struct.data_ptr()).data == seen);
+                                    (*rw_sync_struct.data_ptr()).data = seen + 1;
+                                }
+                                rw_sync_struct.downgrade();
+                                assert!(unsafe { (*rw_sync_struct.data_ptr()).data } == seen + 1);
+                                rw_sync_struct.unlatch_shared();
+                                upgrades.fetch_add(1, Ordering::Relaxed);
+                                break;
+                            }
+                            rw_sync_struct.unlatch_shared();
+                        }
+                    }
+                });
+            }
+        });
+        assert!(upgrades.load(Ordering::Relaxed) == 4000);
+        assert!(rw_sync_struct.with(|inner| inner.data) == 4000);
+
+        // a downgraded latch admits readers but not writers
+        rw_sync_struct.latch_excl();
+        rw_sync_struct.downgrade();
+        assert!(rw_sync_struct.try_read().is_some());
+        assert!(rw_sync_struct.try_write().is_none());
+        rw_sync_struct.unlatch_shared();
+    }
+
+    #[test]
+    fn test_try_latch() {
+        let sync_struct = Synchronized::init(TestStruct { data: 0 });
+        let rw_sync_struct = RwSynchronized::init(TestStruct { data: 0 });
+        assert!(sync_struct.try_latch());
+        assert!(!sync_struct.try_latch());
+        let started = Instant::now();
+        std::thread::scope(|s| {
+            // another thread can't get the latch while it's held
+            s.spawn(|| assert!(!sync_struct.latch_timeout(Duration::from_millis(20))));
+        });
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        sync_struct.unlatch();
+        assert!(sync_struct.latch_timeout(Duration::from_millis(20)));
+        sync_struct.unlatch();
+
+        assert!(rw_sync_struct.try_latch_shared());
+        assert!(rw_sync_struct.try_latch_upgradable());
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                assert!(!rw_sync_struct.try_latch_excl());
+                assert!(!rw_sync_struct.latch_upgradable_timeout(Duration::from_millis(10)));
+                assert!(rw_sync_struct.latch_shared_timeout(Duration::from_millis(10)));
+                rw_sync_struct.unlatch_shared();
+            });
+        });
+        rw_sync_struct.unlatch_upgradable();
+        rw_sync_struct.unlatch_shared();
+        assert!(rw_sync_struct.latch_excl_timeout(Duration::from_millis(10)));
+        assert!(!rw_sync_struct.try_latch_shared());
+        rw_sync_struct.unlatch_excl();
+    }
+
+    #[test]
+    fn test_access() {
+        let sync_struct = Synchronized::init(TestStruct { data: 0 });
+        let rw_sync_struct = RwSynchronized::init(TestStruct { data: 0 });
+        let pool = ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        pool.scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|_| {
+                    for _ in 0..1000 {
+                        sync_struct.with_mut(|inner| inner.data += 1);
+                        rw_sync_struct.exclusive().data += 1;
+                    }
+                });
+            }
+        });
+        assert!(sync_struct.with(|inner| inner.data) == 8000);
+        assert!(rw_sync_struct.with(|inner| inner.data) == 8000);
+
+        // the latch is released with the guard
+        let shared = rw_sync_struct.shared();
+        assert!(rw_sync_struct.try_write().is_none());
+        drop(shared);
+        assert!(rw_sync_struct.try_write().is_some());
+        let guard = sync_struct.exclusive();
+        assert!(sync_struct.try_lock().is_none());
+        drop(guard);
+        assert!(sync_struct.with_mut(|inner| std::mem::take(&mut inner.data)) == 8000);
+        assert!(sync_struct.shared().data == 0);
+    }
 }
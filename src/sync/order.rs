@@ -0,0 +1,641 @@
+/// This file implements the primitives behind `Synchronized`, `RwSynchronized` and `CondVar` with latch order checking, for
+/// tracking down deadlocks (it's compiled instead of plain parking_lot with the `lock-order` feature). The types wrap
+/// parking_lot's and mirror the API the crate uses, so every acquisition, through a guard or through `Latch`/`RwLatch`, goes
+/// through here:
+///
+/// - each thread keeps the latches it holds, in the order it took them. Blocking on a latch the thread already holds in a
+///   conflicting mode (anything but shared twice, or shared alongside upgradable) panics as a self-deadlock, and so does
+///   upgrading while the thread also holds a shared latch on the same lock
+/// - taking latch B while holding latch A records the edge A -> B in a process-wide order graph, with the stack trace of the
+///   first time it happened. If B can already reach A in the graph, some thread took them the other way round and the two
+///   can deadlock: that panics with both stack traces
+///
+/// Only blocking acquisitions are checked: `try_*` and timed acquisitions can't wait forever, but the latches they take count
+/// as held. Latches of the same type (two frames, two shards) aren't ordered against each other, because their owners order
+/// them by what they hold (B-link trees latch left to right and top down) rather than by lock, so frame reuse would make any
+/// fixed order look inverted. A lock's edges are dropped with it, so a new lock at the same address starts out unordered.
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use parking_lot::lock_api::{
+    RawMutex as _, RawMutexTimed as _, RawRwLock as _, RawRwLockDowngrade as _,
+    RawRwLockTimed as _, RawRwLockUpgrade as _, RawRwLockUpgradeTimed as _,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Shared,
+    Upgradable,
+    Exclusive,
+}
+
+impl Mode {
+    fn conflicts(self, other: Mode) -> bool {
+        !matches!(
+            (self, other),
+            (Mode::Shared, Mode::Shared)
+                | (Mode::Shared, Mode::Upgradable)
+                | (Mode::Upgradable, Mode::Shared)
+        )
+    }
+}
+
+struct Held {
+    addr: usize,
+    name: &'static str,
+    mode: Mode,
+}
+
+/// The order latches have been taken in: `edges[a][b]` is the stack trace of the first time `b` was taken while `a` was held
+#[derive(Default)]
+struct OrderGraph {
+    names: HashMap<usize, &'static str>,
+    edges: HashMap<usize, HashMap<usize, Arc<Backtrace>>>,
+    // the reverse of `edges`, so a lock's edges can be dropped with it
+    incoming: HashMap<usize, HashSet<usize>>,
+}
+
+impl OrderGraph {
+    /// A path of edges from `from` to `to`, if there is one
+    fn path(&self, from: usize, to: usize) -> Option<Vec<usize>> {
+        let mut stack = vec![vec![from]];
+        let mut seen = HashSet::from([from]);
+        while let Some(path) = stack.pop() {
+            let last = path[path.len() - 1];
+            if last == to {
+                return Some(path);
+            }
+            for next in self
+                .edges
+                .get(&last)
+                .into_iter()
+                .flat_map(|edges| edges.keys())
+            {
+                if seen.insert(*next) {
+                    let mut longer = path.clone();
+                    longer.push(*next);
+                    stack.push(longer);
+                }
+            }
+        }
+        None
+    }
+
+    fn describe(&self, addr: usize) -> String {
+        format!("{} at {:#x}", self.names.get(&addr).unwrap_or(&"?"), addr)
+    }
+}
+
+thread_local! {
+    static HELD: RefCell<Vec<Held>> = const { RefCell::new(Vec::new()) };
+}
+
+fn graph() -> &'static parking_lot::Mutex<OrderGraph> {
+    static GRAPH: OnceLock<parking_lot::Mutex<OrderGraph>> = OnceLock::new();
+    GRAPH.get_or_init(Default::default)
+}
+
+/// Check that blocking on `addr` in `mode` can't deadlock, recording its order against the latches this thread holds
+fn check(addr: usize, name: &'static str, mode: Mode) {
+    let held: Vec<(usize, &'static str, Mode)> = HELD.with(|held| {
+        held.borrow()
+            .iter()
+            .map(|held| (held.addr, held.name, held.mode))
+            .collect()
+    });
+    if let Some((_, _, held_mode)) = held
+        .iter()
+        .find(|(held, _, held_mode)| *held == addr && mode.conflicts(*held_mode))
+    {
+        panic!(
+            "self-deadlock: latching {} at {:#x} {:?} while this thread holds it {:?}\n{}",
+            name,
+            addr,
+            mode,
+            held_mode,
+            Backtrace::force_capture()
+        );
+    }
+
+    let mut graph = graph().lock();
+    graph.names.insert(addr, name);
+    for (before, before_name, _) in held {
+        if before == addr || before_name == name {
+            continue;
+        }
+        if graph
+            .edges
+            .get(&before)
+            .is_some_and(|edges| edges.contains_key(&addr))
+        {
+            continue;
+        }
+        if let Some(path) = graph.path(addr, before) {
+            let order: Vec<String> = path.iter().map(|addr| graph.describe(*addr)).collect();
+            let earlier = graph.edges[&path[0]][&path[1]].clone();
+            let message = format!(
+                "latch order inversion: latching {} while holding {}, but they were taken in the order {}\n\
+                 --- this acquisition ---\n{}\n--- earlier acquisition of {} while holding {} ---\n{}",
+                graph.describe(addr),
+                graph.describe(before),
+                order.join(" -> "),
+                Backtrace::force_capture(),
+                graph.describe(path[1]),
+                graph.describe(path[0]),
+                earlier
+            );
+            drop(graph);
+            panic!("{}", message);
+        }
+        let backtrace = Arc::new(Backtrace::force_capture());
+        graph
+            .edges
+            .entry(before)
+            .or_default()
+            .insert(addr, backtrace);
+        graph.incoming.entry(addr).or_default().insert(before);
+    }
+}
+
+fn acquired(addr: usize, name: &'static str, mode: Mode) {
+    HELD.with(|held| held.borrow_mut().push(Held { addr, name, mode }));
+}
+
+fn released(addr: usize, mode: Mode) {
+    HELD.with(|held| {
+        let mut held = held.borrow_mut();
+        if let Some(pos) = held
+            .iter()
+            .rposition(|held| held.addr == addr && held.mode == mode)
+        {
+            held.remove(pos);
+        }
+    });
+}
+
+/// Change the mode this thread holds `addr` in, keeping its place in the order
+fn converted(addr: usize, from: Mode, to: Mode) {
+    HELD.with(|held| {
+        if let Some(held) = held
+            .borrow_mut()
+            .iter_mut()
+            .rev()
+            .find(|held| held.addr == addr && held.mode == from)
+        {
+            held.mode = to;
+        }
+    });
+}
+
+/// Drop a lock's edges when it goes away
+fn forget(addr: usize) {
+    let mut graph = graph().lock();
+    if graph.names.remove(&addr).is_none() {
+        return;
+    }
+    for after in graph.edges.remove(&addr).unwrap_or_default().into_keys() {
+        if let Some(incoming) = graph.incoming.get_mut(&after) {
+            incoming.remove(&addr);
+        }
+    }
+    for before in graph.incoming.remove(&addr).unwrap_or_default() {
+        if let Some(edges) = graph.edges.get_mut(&before) {
+            edges.remove(&addr);
+        }
+    }
+}
+
+fn latch<R>(addr: usize, name: &'static str, mode: Mode, lock: impl FnOnce() -> R) -> R {
+    check(addr, name, mode);
+    let guard = lock();
+    acquired(addr, name, mode);
+    guard
+}
+
+fn try_latch(addr: usize, name: &'static str, mode: Mode, locked: bool) -> bool {
+    if locked {
+        acquired(addr, name, mode);
+    }
+    locked
+}
+
+pub struct Mutex<T> {
+    inner: parking_lot::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub fn new(data: T) -> Self {
+        Mutex {
+            inner: parking_lot::Mutex::new(data),
+        }
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let inner = latch(
+            self.addr(),
+            std::any::type_name::<T>(),
+            Mode::Exclusive,
+            || self.inner.lock(),
+        );
+        MutexGuard {
+            inner,
+            addr: self.addr(),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let inner = self.inner.try_lock()?;
+        acquired(self.addr(), std::any::type_name::<T>(), Mode::Exclusive);
+        Some(MutexGuard {
+            inner,
+            addr: self.addr(),
+        })
+    }
+
+    pub fn data_ptr(&self) -> *mut T {
+        self.inner.data_ptr()
+    }
+
+    /// The raw lock, checked like the guards are. For `Latch`
+    pub unsafe fn raw(&self) -> RawMutex<'_> {
+        RawMutex {
+            raw: self.inner.raw(),
+            addr: self.addr(),
+            name: std::any::type_name::<T>(),
+        }
+    }
+}
+
+impl<T> Drop for Mutex<T> {
+    fn drop(&mut self) {
+        forget(self.addr());
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    inner: parking_lot::MutexGuard<'a, T>,
+    addr: usize,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        released(self.addr, Mode::Exclusive);
+    }
+}
+
+pub struct RawMutex<'a> {
+    raw: &'a parking_lot::RawMutex,
+    addr: usize,
+    name: &'static str,
+}
+
+impl RawMutex<'_> {
+    pub unsafe fn lock(&self) {
+        latch(self.addr, self.name, Mode::Exclusive, || self.raw.lock());
+    }
+
+    pub unsafe fn try_lock(&self) -> bool {
+        try_latch(self.addr, self.name, Mode::Exclusive, self.raw.try_lock())
+    }
+
+    pub unsafe fn try_lock_for(&self, timeout: Duration) -> bool {
+        let locked = self.raw.try_lock_for(timeout);
+        try_latch(self.addr, self.name, Mode::Exclusive, locked)
+    }
+
+    pub unsafe fn unlock(&self) {
+        released(self.addr, Mode::Exclusive);
+        self.raw.unlock();
+    }
+}
+
+pub struct RwLock<T> {
+    inner: parking_lot::RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub fn new(data: T) -> Self {
+        RwLock {
+            inner: parking_lot::RwLock::new(data),
+        }
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let inner = latch(
+            self.addr(),
+            std::any::type_name::<T>(),
+            Mode::Shared,
+            || self.inner.read(),
+        );
+        RwLockReadGuard {
+            inner,
+            addr: self.addr(),
+        }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let inner = latch(
+            self.addr(),
+            std::any::type_name::<T>(),
+            Mode::Exclusive,
+            || self.inner.write(),
+        );
+        RwLockWriteGuard {
+            inner,
+            addr: self.addr(),
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let inner = self.inner.try_read()?;
+        acquired(self.addr(), std::any::type_name::<T>(), Mode::Shared);
+        Some(RwLockReadGuard {
+            inner,
+            addr: self.addr(),
+        })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let inner = self.inner.try_write()?;
+        acquired(self.addr(), std::any::type_name::<T>(), Mode::Exclusive);
+        Some(RwLockWriteGuard {
+            inner,
+            addr: self.addr(),
+        })
+    }
+
+    pub fn data_ptr(&self) -> *mut T {
+        self.inner.data_ptr()
+    }
+
+    /// The raw lock, checked like the guards are. For `RwLatch`
+    pub unsafe fn raw(&self) -> RawRwLock<'_> {
+        RawRwLock {
+            raw: self.inner.raw(),
+            addr: self.addr(),
+            name: std::any::type_name::<T>(),
+        }
+    }
+}
+
+impl<T> Drop for RwLock<T> {
+    fn drop(&mut self) {
+        forget(self.addr());
+    }
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    inner: parking_lot::RwLockReadGuard<'a, T>,
+    addr: usize,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    inner: parking_lot::RwLockWriteGuard<'a, T>,
+    addr: usize,
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        released(self.addr, Mode::Shared);
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        released(self.addr, Mode::Exclusive);
+    }
+}
+
+pub struct RawRwLock<'a> {
+    raw: &'a parking_lot::RawRwLock,
+    addr: usize,
+    name: &'static str,
+}
+
+impl RawRwLock<'_> {
+    pub unsafe fn lock_shared(&self) {
+        latch(self.addr, self.name, Mode::Shared, || {
+            self.raw.lock_shared()
+        });
+    }
+
+    pub unsafe fn lock_upgradable(&self) {
+        latch(self.addr, self.name, Mode::Upgradable, || {
+            self.raw.lock_upgradable()
+        });
+    }
+
+    pub unsafe fn lock_exclusive(&self) {
+        latch(self.addr, self.name, Mode::Exclusive, || {
+            self.raw.lock_exclusive()
+        });
+    }
+
+    pub unsafe fn try_lock_shared(&self) -> bool {
+        try_latch(
+            self.addr,
+            self.name,
+            Mode::Shared,
+            self.raw.try_lock_shared(),
+        )
+    }
+
+    pub unsafe fn try_lock_upgradable(&self) -> bool {
+        let locked = self.raw.try_lock_upgradable();
+        try_latch(self.addr, self.name, Mode::Upgradable, locked)
+    }
+
+    pub unsafe fn try_lock_exclusive(&self) -> bool {
+        let locked = self.raw.try_lock_exclusive();
+        try_latch(self.addr, self.name, Mode::Exclusive, locked)
+    }
+
+    pub unsafe fn try_lock_shared_for(&self, timeout: Duration) -> bool {
+        let locked = self.raw.try_lock_shared_for(timeout);
+        try_latch(self.addr, self.name, Mode::Shared, locked)
+    }
+
+    pub unsafe fn try_lock_upgradable_for(&self, timeout: Duration) -> bool {
+        let locked = self.raw.try_lock_upgradable_for(timeout);
+        try_latch(self.addr, self.name, Mode::Upgradable, locked)
+    }
+
+    pub unsafe fn try_lock_exclusive_for(&self, timeout: Duration) -> bool {
+        let locked = self.raw.try_lock_exclusive_for(timeout);
+        try_latch(self.addr, self.name, Mode::Exclusive, locked)
+    }
+
+    pub unsafe fn unlock_shared(&self) {
+        released(self.addr, Mode::Shared);
+        self.raw.unlock_shared();
+    }
+
+    pub unsafe fn unlock_upgradable(&self) {
+        released(self.addr, Mode::Upgradable);
+        self.raw.unlock_upgradable();
+    }
+
+    pub unsafe fn unlock_exclusive(&self) {
+        released(self.addr, Mode::Exclusive);
+        self.raw.unlock_exclusive();
+    }
+
+    /// Upgrading waits for every reader to leave, this thread included
+    pub unsafe fn upgrade(&self) {
+        let shared = HELD.with(|held| {
+            held.borrow()
+                .iter()
+                .any(|held| held.addr == self.addr && held.mode == Mode::Shared)
+        });
+        if shared {
+            panic!(
+                "self-deadlock: upgrading {} at {:#x} while this thread holds it shared too\n{}",
+                self.name,
+                self.addr,
+                Backtrace::force_capture()
+            );
+        }
+        self.raw.upgrade();
+        converted(self.addr, Mode::Upgradable, Mode::Exclusive);
+    }
+
+    pub unsafe fn downgrade(&self) {
+        self.raw.downgrade();
+        converted(self.addr, Mode::Exclusive, Mode::Shared);
+    }
+}
+
+/// Condition variable over a `Mutex`. The mutex counts as released while waiting
+#[derive(Default)]
+pub struct Condvar {
+    inner: parking_lot::Condvar,
+}
+
+impl Condvar {
+    pub fn new() -> Self {
+        Condvar::default()
+    }
+
+    /// Wait with `guard`'s latch released, then take it back without checking its order, which it already had
+    fn waiting<T, R>(
+        guard: &mut MutexGuard<'_, T>,
+        wait: impl FnOnce(&mut parking_lot::MutexGuard<'_, T>) -> R,
+    ) -> R {
+        released(guard.addr, Mode::Exclusive);
+        let result = wait(&mut guard.inner);
+        acquired(guard.addr, std::any::type_name::<T>(), Mode::Exclusive);
+        result
+    }
+
+    pub fn wait<T>(&self, guard: &mut MutexGuard<'_, T>) {
+        Condvar::waiting(guard, |inner| self.inner.wait(inner));
+    }
+
+    pub fn wait_while<T>(
+        &self,
+        guard: &mut MutexGuard<'_, T>,
+        condition: impl FnMut(&mut T) -> bool,
+    ) {
+        Condvar::waiting(guard, |inner| self.inner.wait_while(inner, condition));
+    }
+
+    pub fn wait_for<T>(
+        &self,
+        guard: &mut MutexGuard<'_, T>,
+        timeout: Duration,
+    ) -> parking_lot::WaitTimeoutResult {
+        Condvar::waiting(guard, |inner| self.inner.wait_for(inner, timeout))
+    }
+
+    pub fn wait_until<T>(
+        &self,
+        guard: &mut MutexGuard<'_, T>,
+        deadline: Instant,
+    ) -> parking_lot::WaitTimeoutResult {
+        Condvar::waiting(guard, |inner| self.inner.wait_until(inner, deadline))
+    }
+
+    pub fn notify_one(&self) -> bool {
+        self.inner.notify_one()
+    }
+
+    pub fn notify_all(&self) -> usize {
+        self.inner.notify_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "inversion")]
+    fn test_order_inversion() {
+        let a = Mutex::new(0usize);
+        let b = RwLock::new(String::new());
+        {
+            let _a = a.lock();
+            let _b = b.read();
+        }
+        // taking them the other way round, even on the same thread, could deadlock against the first order
+        let _b = b.write();
+        let _a = a.lock();
+    }
+
+    #[test]
+    #[should_panic(expected = "self-deadlock")]
+    fn test_self_deadlock() {
+        let lock = RwLock::new(0usize);
+        let _shared = lock.read();
+        let _shared_again = lock.read();
+        let _exclusive = lock.write();
+    }
+}
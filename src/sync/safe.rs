@@ -0,0 +1,219 @@
+/// This file implements `Latch`/`RwLatch` without raw lock calls, for running the crate under Miri (it's compiled instead of
+/// the raw implementation under `cfg(miri)` or with the `safe-sync` feature). `latch*` takes an ordinary owned guard and parks
+/// it in a thread-local table keyed by the lock's address; `unlatch*` takes the guard back out and drops it. Latches can only
+/// be released by the thread that took them, which the raw implementation also requires but can't check: here, releasing a
+/// latch the current thread doesn't hold panics.
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::{
+    ArcMutexGuard, ArcRwLockReadGuard, ArcRwLockUpgradableReadGuard, ArcRwLockWriteGuard, Mutex,
+    RawMutex, RawRwLock, RwLock,
+};
+
+use super::{Latch, RwLatch, RwSynchronized, Synchronized};
+use crate::telemetry::trace;
+
+thread_local! {
+    // guards held through latch calls, per lock address, most recent last
+    static HELD: RefCell<HashMap<usize, Vec<Box<dyn Any>>>> = RefCell::new(HashMap::new());
+}
+
+fn park<G: Any>(addr: usize, guard: G) {
+    HELD.with(|held| {
+        held.borrow_mut()
+            .entry(addr)
+            .or_default()
+            .push(Box::new(guard))
+    });
+}
+
+/// Take back the most recent guard of type `G` parked for `addr`
+fn unpark<G: Any>(addr: usize) -> G {
+    HELD.with(|held| {
+        let mut held = held.borrow_mut();
+        let guards = held
+            .get_mut(&addr)
+            .expect("unlatching a latch this thread doesn't hold");
+        let pos = guards
+            .iter()
+            .rposition(|guard| guard.is::<G>())
+            .expect("unlatching a latch this thread doesn't hold in that mode");
+        let guard = guards.remove(pos);
+        if guards.is_empty() {
+            held.remove(&addr);
+        }
+        *guard.downcast::<G>().unwrap()
+    })
+}
+
+impl<T: 'static> Latch<T> for Synchronized<T> {
+    fn init(item: T) -> Self {
+        Arc::new(Mutex::new(item))
+    }
+
+    fn latch(&self) {
+        park(
+            Arc::as_ptr(self) as usize,
+            trace::latch("mutex", || self.lock_arc()),
+        );
+    }
+
+    fn try_latch(&self) -> bool {
+        self.try_lock_arc()
+            .map(|guard| park(Arc::as_ptr(self) as usize, guard))
+            .is_some()
+    }
+
+    fn latch_timeout(&self, timeout: Duration) -> bool {
+        trace::latch("mutex", || self.try_lock_arc_for(timeout))
+            .map(|guard| park(Arc::as_ptr(self) as usize, guard))
+            .is_some()
+    }
+
+    fn unlatch(&self) {
+        drop(unpark::<ArcMutexGuard<RawMutex, T>>(
+            Arc::as_ptr(self) as usize
+        ));
+    }
+}
+
+impl<T: 'static> RwLatch<T> for RwSynchronized<T> {
+    fn init(item: T) -> Self {
+        Arc::new(RwLock::new(item))
+    }
+
+    fn latch_shared(&self) {
+        park(
+            Arc::as_ptr(self) as usize,
+            trace::latch("shared", || self.read_arc()),
+        );
+    }
+
+    fn latch_upgradable(&self) {
+        park(
+            Arc::as_ptr(self) as usize,
+            trace::latch("upgradable", || self.upgradable_read_arc()),
+        );
+    }
+
+    fn latch_excl(&self) {
+        park(
+            Arc::as_ptr(self) as usize,
+            trace::latch("exclusive", || self.write_arc()),
+        );
+    }
+
+    fn try_latch_shared(&self) -> bool {
+        self.try_read_arc()
+            .map(|guard| park(Arc::as_ptr(self) as usize, guard))
+            .is_some()
+    }
+
+    fn try_latch_upgradable(&self) -> bool {
+        self.try_upgradable_read_arc()
+            .map(|guard| park(Arc::as_ptr(self) as usize, guard))
+            .is_some()
+    }
+
+    fn try_latch_excl(&self) -> bool {
+        self.try_write_arc()
+            .map(|guard| park(Arc::as_ptr(self) as usize, guard))
+            .is_some()
+    }
+
+    fn latch_shared_timeout(&self, timeout: Duration) -> bool {
+        trace::latch("shared", || self.try_read_arc_for(timeout))
+            .map(|guard| park(Arc::as_ptr(self) as usize, guard))
+            .is_some()
+    }
+
+    fn latch_upgradable_timeout(&self, timeout: Duration) -> bool {
+        trace::latch("upgradable", || self.try_upgradable_read_arc_for(timeout))
+            .map(|guard| park(Arc::as_ptr(self) as usize, guard))
+            .is_some()
+    }
+
+    fn latch_excl_timeout(&self, timeout: Duration) -> bool {
+        trace::latch("exclusive", || self.try_write_arc_for(timeout))
+            .map(|guard| park(Arc::as_ptr(self) as usize, guard))
+            .is_some()
+    }
+
+    fn unlatch_shared(&self) {
+        drop(unpark::<ArcRwLockReadGuard<RawRwLock, T>>(
+            Arc::as_ptr(self) as usize,
+        ));
+    }
+
+    fn unlatch_upgradable(&self) {
+        drop(unpark::<ArcRwLockUpgradableReadGuard<RawRwLock, T>>(
+            Arc::as_ptr(self) as usize,
+        ));
+    }
+
+    fn unlatch_excl(&self) {
+        drop(unpark::<ArcRwLockWriteGuard<RawRwLock, T>>(
+            Arc::as_ptr(self) as usize,
+        ));
+    }
+
+    fn latch_upgrade_shared(&self) {
+        let addr = Arc::as_ptr(self) as usize;
+        let upgradable = unpark::<ArcRwLockUpgradableReadGuard<RawRwLock, T>>(addr);
+        park(
+            addr,
+            trace::latch("upgrade", || {
+                ArcRwLockUpgradableReadGuard::upgrade(upgradable)
+            }),
+        );
+    }
+
+    fn upgrade(&self) -> bool {
+        let addr = Arc::as_ptr(self) as usize;
+        let Some(upgradable) = self.try_upgradable_read_arc() else {
+            return false;
+        };
+        drop(unpark::<ArcRwLockReadGuard<RawRwLock, T>>(addr));
+        park(
+            addr,
+            trace::latch("upgrade", || {
+                ArcRwLockUpgradableReadGuard::upgrade(upgradable)
+            }),
+        );
+        true
+    }
+
+    fn downgrade(&self) {
+        let addr = Arc::as_ptr(self) as usize;
+        let exclusive = unpark::<ArcRwLockWriteGuard<RawRwLock, T>>(addr);
+        park(addr, ArcRwLockWriteGuard::downgrade(exclusive));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latch_across_calls() {
+        let sync = RwSynchronized::init(0usize);
+        sync.latch_upgradable();
+        sync.latch_shared();
+        sync.unlatch_shared();
+        sync.latch_upgrade_shared();
+        assert!(sync.try_read().is_none());
+        sync.unlatch_excl();
+        assert!(sync.try_write().is_some());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_unlatch_without_latch() {
+        let sync = Synchronized::init(0usize);
+        sync.unlatch();
+    }
+}
@@ -0,0 +1,180 @@
+/// This file implements the telemetry event bus. Subsystems publish lifecycle events (eviction pressure in the buffer pool,
+/// recovery starting and finishing, long-running transactions, lagging replicas) to a shared `EventBus` instead of calling
+/// into whatever wants to observe them, and consumers such as a metrics exporter or the audit log each hold their own
+/// subscription. Publishing never blocks: every subscriber has an unbounded queue, and subscribers whose receiving end has
+/// been dropped are forgotten on the next publish.
+use std::fmt::Display;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+use crate::shared::{Lsn, TxnId};
+use crate::sync::{Latch as _, Synchronized};
+
+pub(crate) mod trace;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryPhase {
+    Analysis,
+    Redo,
+    Undo,
+}
+
+impl Display for RecoveryPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecoveryPhase::Analysis => write!(f, "analysis"),
+            RecoveryPhase::Redo => write!(f, "redo"),
+            RecoveryPhase::Undo => write!(f, "undo"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageEvent {
+    /// The buffer pool needed a frame but every frame was pinned
+    EvictionPressure { frames: usize },
+    /// Crash recovery is about to replay the log up to `end_lsn`
+    RecoveryStarted { end_lsn: Lsn },
+    /// Periodic report from a recovery phase. `lsn` is the record being processed; redo moves it towards `end_lsn`, undo moves
+    /// it back towards the start of the log. `eta` is only known once some progress has been made
+    RecoveryProgress {
+        phase: RecoveryPhase,
+        processed: usize,
+        lsn: Lsn,
+        end_lsn: Lsn,
+        eta: Option<Duration>,
+    },
+    RecoveryFinished {
+        redone: usize,
+        undone: usize,
+        losers: usize,
+    },
+    /// A transaction has been open for longer than the long-transaction threshold, and was aborted if the policy says so
+    LongTransaction {
+        txn_id: TxnId,
+        age: Duration,
+        aborted: bool,
+    },
+    /// A replica's hold on the snapshot horizon was dropped because it hadn't advanced within the maximum standby lag
+    ReplicaHoldDropped { replica: String, lag: Duration },
+}
+
+impl Display for StorageEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageEvent::EvictionPressure { frames } => {
+                write!(f, "eviction pressure: all {} frames pinned", frames)
+            }
+            StorageEvent::RecoveryStarted { end_lsn } => {
+                write!(f, "recovery started [end lsn={}]", end_lsn)
+            }
+            StorageEvent::RecoveryProgress {
+                phase,
+                processed,
+                lsn,
+                end_lsn,
+                eta,
+            } => {
+                write!(
+                    f,
+                    "recovery {}: {} records processed [lsn={}/{}",
+                    phase, processed, lsn, end_lsn
+                )?;
+                match eta {
+                    Some(eta) => write!(f, ", eta={:.1}s]", eta.as_secs_f64()),
+                    None => write!(f, "]"),
+                }
+            }
+            StorageEvent::RecoveryFinished {
+                redone,
+                undone,
+                losers,
+            } => write!(
+                f,
+                "recovery finished [redone={}, undone={}, losers={}]",
+                redone, undone, losers
+            ),
+            StorageEvent::LongTransaction {
+                txn_id,
+                age,
+                aborted,
+            } => write!(
+                f,
+                "transaction {} open for {:.1}s{}",
+                txn_id,
+                age.as_secs_f64(),
+                if *aborted { ", aborted" } else { "" }
+            ),
+            StorageEvent::ReplicaHoldDropped { replica, lag } => write!(
+                f,
+                "replica {} dropped from the snapshot horizon after {:.1}s without progress",
+                replica,
+                lag.as_secs_f64()
+            ),
+        }
+    }
+}
+
+pub struct EventBusInternal {
+    subscribers: Vec<Sender<StorageEvent>>,
+}
+
+pub type EventBus = Synchronized<EventBusInternal>;
+
+pub trait EventBusApi {
+    fn create() -> Self;
+    fn subscribe(&self) -> Receiver<StorageEvent>;
+    fn publish(&self, event: StorageEvent);
+    fn subscribers(&self) -> usize;
+}
+
+impl EventBusApi for EventBus {
+    fn create() -> Self {
+        Synchronized::init(EventBusInternal {
+            subscribers: Vec::new(),
+        })
+    }
+
+    /// Receive every event published from now on. Drop the receiver to unsubscribe
+    fn subscribe(&self) -> Receiver<StorageEvent> {
+        let (sender, receiver) = channel();
+        self.lock().subscribers.push(sender);
+        receiver
+    }
+
+    /// Deliver `event` to every current subscriber
+    fn publish(&self, event: StorageEvent) {
+        self.lock()
+            .subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Number of live subscriptions, as of the last publish
+    fn subscribers(&self) -> usize {
+        self.lock().subscribers.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish_subscribe() {
+        let bus = EventBus::create();
+        // nobody listening is fine
+        bus.publish(StorageEvent::EvictionPressure { frames: 50 });
+
+        let metrics = bus.subscribe();
+        let audit = bus.subscribe();
+        bus.publish(StorageEvent::RecoveryStarted { end_lsn: 42 });
+        assert!(metrics.try_recv().unwrap() == StorageEvent::RecoveryStarted { end_lsn: 42 });
+        assert!(audit.try_recv().unwrap() == StorageEvent::RecoveryStarted { end_lsn: 42 });
+        assert!(metrics.try_recv().is_err());
+
+        drop(audit);
+        bus.publish(StorageEvent::EvictionPressure { frames: 50 });
+        assert!(bus.subscribers() == 1);
+        assert!(metrics.recv().unwrap().to_string() == "eviction pressure: all 50 frames pinned");
+    }
+}
@@ -0,0 +1,186 @@
+/// This file implements the crate's `tracing` instrumentation, compiled in with the `tracing` feature. Blocking latch
+/// acquisitions in `sync` run inside a `latch` span that records how long the thread waited, disk IO runs inside an `io` span
+/// that records how long it took, and the buffer pool emits an event for every fetch, eviction and flush. All of that is at
+/// trace or debug level, so a subscriber filtering at info sees none of it; what it does see is `trace_info!`, kept for rare
+/// events an operator wants to follow, like the progress of recovery.
+///
+/// Without the feature the helpers here just call through and `trace_event!` and `trace_info!` expand to nothing, so
+/// instrumented code pays nothing for them.
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+
+use crate::shared::PageId;
+
+/// Run `acquire`, which blocks until it holds a latch, inside a `latch` span recording the latch `mode` and the wait in
+/// microseconds
+#[cfg(feature = "tracing")]
+#[inline]
+pub(crate) fn latch<R>(mode: &'static str, acquire: impl FnOnce() -> R) -> R {
+    let span = tracing::trace_span!("latch", mode, wait_us = tracing::field::Empty);
+    let _entered = span.enter();
+    let started = Instant::now();
+    let acquired = acquire();
+    span.record("wait_us", started.elapsed().as_micros() as u64);
+    acquired
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn latch<R>(_mode: &'static str, acquire: impl FnOnce() -> R) -> R {
+    acquire()
+}
+
+/// Run `io`, a disk operation `op` on `page_id` (the first page, for a batch), inside an `io` span recording how long it
+/// took in microseconds and whether it failed
+#[cfg(feature = "tracing")]
+#[inline]
+pub(crate) fn io<T, E>(
+    op: &'static str,
+    page_id: PageId,
+    io: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E> {
+    let span = tracing::debug_span!(
+        "io",
+        op,
+        page_id,
+        elapsed_us = tracing::field::Empty,
+        failed = tracing::field::Empty
+    );
+    let _entered = span.enter();
+    let started = Instant::now();
+    let result = io();
+    span.record("elapsed_us", started.elapsed().as_micros() as u64);
+    span.record("failed", result.is_err());
+    result
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn io<T, E>(
+    _op: &'static str,
+    _page_id: PageId,
+    io: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E> {
+    io()
+}
+
+/// `tracing::debug!` with the `tracing` feature, nothing without it
+macro_rules! trace_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
+/// `tracing::info!` with the `tracing` feature, nothing without it
+macro_rules! trace_info {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::info!($($arg)*);
+    };
+}
+
+pub(crate) use {trace_event, trace_info};
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use parking_lot::Mutex;
+    use tracing::field::Field;
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    use crate::shared::{cwd, INVALID_LSN};
+    use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+    use crate::storage::buffer::diskmgr::{DiskApi as _, DiskMgr};
+    use crate::storage::log::logmgr::{LogApi as _, LogManager};
+    use crate::storage::log::record::LogBody;
+    use crate::storage::log::recovery::RecoveryManager;
+    use crate::sync::{RwLatch as _, RwSynchronized};
+
+    /// Counts spans by name, events by message, and info-level events as `info`
+    #[derive(Default)]
+    struct Counter {
+        next_id: AtomicU64,
+        seen: Mutex<HashMap<String, usize>>,
+    }
+
+    impl Counter {
+        fn count(&self, name: &str) -> usize {
+            self.seen.lock().get(name).copied().unwrap_or(0)
+        }
+    }
+
+    impl Subscriber for &'static Counter {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            *self
+                .seen
+                .lock()
+                .entry(span.metadata().name().to_string())
+                .or_default() += 1;
+            Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+        }
+
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut message = String::new();
+            event.record(&mut |field: &Field, value: &dyn std::fmt::Debug| {
+                if field.name() == "message" {
+                    message = format!("{:?}", value);
+                }
+            });
+            let mut seen = self.seen.lock();
+            *seen.entry(message).or_default() += 1;
+            if *event.metadata().level() == tracing::Level::INFO {
+                *seen.entry("info".to_string()).or_default() += 1;
+            }
+        }
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn test_instrumentation() {
+        let counter: &'static Counter = Box::leak(Box::default());
+        let dir = cwd() + "/tests/trace_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        tracing::subscriber::with_default(counter, || {
+            let latch = RwSynchronized::init(0);
+            latch.latch_excl();
+            latch.unlatch_excl();
+            assert!(counter.count("latch") == 1);
+
+            let pool = BufferPool::create(&(dir.clone() + "/data.bin")).unwrap();
+            let (page_id, _) = pool.new_page().unwrap();
+            assert!(pool.unpin_page(page_id, true));
+            assert!(pool.fetch_page(page_id).is_ok());
+            assert!(pool.unpin_page(page_id, false));
+            assert!(pool.flush_all().is_ok());
+            assert!(counter.count("fetch") == 1 && counter.count("flush") == 1);
+            // the page was written when it was allocated and again when it was flushed, then synced
+            assert!(counter.count("io") >= 3);
+            assert!(counter.count("info") == 0);
+
+            // recovery reports its phases at info level
+            let log = LogManager::create(&(dir.clone() + "/wal.log")).unwrap();
+            let begin = log.append(1, INVALID_LSN, LogBody::Begin).unwrap();
+            log.append(1, begin, LogBody::Commit).unwrap();
+            log.flush().unwrap();
+            let disk = DiskMgr::create(&(dir.clone() + "/recovered.bin")).unwrap();
+            RecoveryManager::new(log, disk).recover().unwrap();
+            assert!(counter.count("info") > 0);
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,223 @@
+/// This file implements golden-file tests for the on-disk formats. Each format has a version constant next to its definition;
+/// a sample value is serialized and compared byte for byte against the fixture checked in under `testdata/golden` for that
+/// version. Changing a layout without bumping its version fails the comparison. After a deliberate bump there's no fixture
+/// for the new version yet: run the tests with `UPDATE_GOLDEN=1` to record one, and check it in along with the change.
+///
+/// Fixtures are stored as hex, 32 bytes per line, so format changes show up readably in review.
+use std::fmt::Write as _;
+
+use crate::shared::cwd;
+
+/// Set this environment variable to record missing fixtures instead of failing
+pub const UPDATE_VAR: &str = "UPDATE_GOLDEN";
+
+fn fixture_path(name: &str, version: u32) -> String {
+    format!("{}/testdata/golden/{}.v{}.hex", cwd(), name, version)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2 + bytes.len() / 16);
+    for line in bytes.chunks(32) {
+        for byte in line {
+            write!(hex, "{:02x}", byte).unwrap();
+        }
+        hex.push('\n');
+    }
+    hex
+}
+
+/// How a serialized value differs from its fixture
+#[derive(Debug, PartialEq, Eq)]
+enum Mismatch {
+    /// Nothing has been recorded for this version yet
+    Missing(String),
+    /// The bytes differ, starting on the 32 byte line at this offset
+    Changed(usize),
+}
+
+/// Compare `bytes` with the fixture recorded for version `version` of the format called `name`. Panics, naming the first
+/// differing bytes, if they differ, or if there's no fixture and `UPDATE_GOLDEN` isn't set.
+pub fn check(name: &str, version: u32, bytes: &[u8]) {
+    match compare(name, version, bytes) {
+        Ok(()) => {}
+        Err(Mismatch::Missing(path)) if std::env::var_os(UPDATE_VAR).is_some() => {
+            std::fs::create_dir_all(format!("{}/testdata/golden", cwd())).unwrap();
+            std::fs::write(path, to_hex(bytes)).unwrap();
+        }
+        Err(Mismatch::Missing(path)) => panic!(
+            "no golden fixture for {} v{} at {}; run with {}=1 to record it",
+            name, version, path, UPDATE_VAR
+        ),
+        Err(Mismatch::Changed(offset)) => panic!(
+            "on-disk format of {} changed without a version bump (first difference in bytes {}..{}); if the change is \
+             intended, bump its version and record a new fixture",
+            name,
+            offset,
+            offset + 32
+        ),
+    }
+}
+
+fn compare(name: &str, version: u32, bytes: &[u8]) -> Result<(), Mismatch> {
+    let path = fixture_path(name, version);
+    let Ok(expected) = std::fs::read_to_string(&path) else {
+        return Err(Mismatch::Missing(path));
+    };
+    let actual = to_hex(bytes);
+    if expected == actual {
+        return Ok(());
+    }
+    let line = expected
+        .lines()
+        .zip(actual.lines())
+        .position(|(a, b)| a != b)
+        .unwrap_or(expected.lines().count().min(actual.lines().count()));
+    Err(Mismatch::Changed(line * 32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::{
+        RelationKind, StorageOptions, TableInfo, TableStats, CATALOG_FORMAT_VERSION,
+    };
+    use crate::storage::backup::manifest::{BackupManifest, ManifestEntry, MANIFEST_VERSION};
+    use crate::storage::buffer::io;
+    use crate::storage::buffer::page::{self, SlottedPage, PAGE_FORMAT_VERSION};
+    use crate::storage::log::record::{self, LogBody, LogRecord, LOG_FORMAT_VERSION};
+    use crate::storage::table::overflow::Stub;
+
+    #[test]
+    fn test_slotted_page_format() {
+        let mut page = page::empty();
+        page::set_lsn(&mut page, 0x0102030405060708);
+        page::set_page_id(&mut page, 7);
+        let mut slotted = SlottedPage::init(&mut page);
+        slotted.set_prev_page_id(3);
+        slotted.set_next_page_id(5);
+        slotted.insert(b"sweater weather").unwrap();
+        let softcore = slotted.insert(b"softcore").unwrap();
+        slotted.insert(b"daddy issues").unwrap();
+        slotted.delete(softcore);
+        let stub = Stub {
+            len: 10_000,
+            first_page_id: 11,
+        };
+        let slot = slotted.insert(&stub.encode()).unwrap();
+        slotted.set_overflow(slot, true);
+        page::set_checksum(&mut page);
+        check("slotted_page", PAGE_FORMAT_VERSION, &page);
+    }
+
+    #[test]
+    fn test_log_record_format() {
+        let records = [
+            LogRecord {
+                lsn: 8,
+                txn_id: 1,
+                prev_lsn: 0,
+                body: LogBody::Begin,
+            },
+            LogRecord {
+                lsn: 40,
+                txn_id: 1,
+                prev_lsn: 8,
+                body: LogBody::Update {
+                    page_id: 2,
+                    offset: 100,
+                    before: vec![0, 0, 0],
+                    after: vec![1, 2, 3],
+                },
+            },
+            LogRecord {
+                lsn: 90,
+                txn_id: 1,
+                prev_lsn: 40,
+                body: LogBody::Clr {
+                    page_id: 2,
+                    offset: 100,
+                    after: vec![0, 0, 0],
+                    undo_next_lsn: 8,
+                },
+            },
+            LogRecord {
+                lsn: 140,
+                txn_id: 1,
+                prev_lsn: 90,
+                body: LogBody::End,
+            },
+        ];
+        let bytes: Vec<u8> = records.iter().flat_map(record::encode).collect();
+        check("log_record", LOG_FORMAT_VERSION, &bytes);
+    }
+
+    #[test]
+    fn test_catalog_entry_format() {
+        let entries = [
+            TableInfo {
+                oid: 1,
+                name: "songs".to_string(),
+                kind: RelationKind::Table,
+                options: StorageOptions {
+                    compression: true,
+                    fill_factor: 70,
+                    ring_buffer_scans: false,
+                },
+                stats: TableStats { rows: 42, pages: 3 },
+            },
+            TableInfo {
+                oid: 2,
+                name: "songs_by_title".to_string(),
+                kind: RelationKind::Index(1),
+                options: StorageOptions::default(),
+                stats: TableStats::default(),
+            },
+        ];
+        check(
+            "catalog_entry",
+            CATALOG_FORMAT_VERSION,
+            &io::encode(&entries[..]).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_backup_manifest_format() {
+        let manifest = BackupManifest {
+            version: MANIFEST_VERSION,
+            checkpoint_lsn: 4096,
+            files: vec![
+                ManifestEntry {
+                    path: "data.bin".to_string(),
+                    size: 8192,
+                    checksum: 0xdeadbeef,
+                },
+                ManifestEntry {
+                    path: "log/wal.log".to_string(),
+                    size: 100,
+                    checksum: 0x12345678,
+                },
+            ],
+        };
+        check(
+            "backup_manifest",
+            MANIFEST_VERSION,
+            &io::encode(&manifest).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_changed_layout_is_caught() {
+        let mut page = page::empty();
+        SlottedPage::init(&mut page)
+            .insert(b"sweater weather")
+            .unwrap();
+        // same version, different bytes. While fixtures are being recorded this one may not exist yet
+        match compare("slotted_page", PAGE_FORMAT_VERSION, &page) {
+            Err(Mismatch::Changed(offset)) => assert!(offset == 0),
+            result => assert!(
+                matches!(result, Err(Mismatch::Missing(_)))
+                    && std::env::var_os(UPDATE_VAR).is_some()
+            ),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::fmt::Display;
+
+use derivative::Derivative;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+pub mod golden;
+
+/// Fixed-size record used by the storage tests. The music library example (`examples/music_library.rs`) shows the storage
+/// API end to end
+#[serde_as]
+#[derive(Serialize, Deserialize, Derivative, Clone, Copy)]
+#[derivative(Default)]
+pub struct Song {
+    // the default id value (-1) denotes that the struct is invalid. all valid songs must have a positive id
+    #[derivative(Default(value = "-1"))]
+    pub id: i32,
+    #[derivative(Default(value = "[0u8; 50]"))]
+    #[serde_as(as = "[_; 50]")]
+    pub title: [u8; 50],
+    #[derivative(Default(value = "[0u8; 50]"))]
+    #[serde_as(as = "[_; 50]")]
+    pub artist: [u8; 50],
+}
+
+impl Song {
+    pub fn new<'a>(id: i32, title: &'a str, artist: &'a str) -> Song {
+        let mut song_buf = [0u8; 50];
+        song_buf[..title.len()].copy_from_slice(title.as_bytes());
+        let mut artist_buf = [0u8; 50];
+        artist_buf[..artist.len()].copy_from_slice(artist.as_bytes());
+        Song {
+            id,
+            title: song_buf,
+            artist: artist_buf,
+        }
+    }
+}
+
+impl Display for Song {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Song [id={} title={} artist={}]",
+            self.id,
+            String::from_utf8_lossy(&self.title),
+            String::from_utf8_lossy(&self.artist)
+        )
+    }
+}
@@ -0,0 +1,350 @@
+/// This file implements write backpressure. When writers outpace the background work that keeps up with them (the buffer
+/// pool's writer, log flushes, index compaction), dirty pages, unflushed log and compaction backlog pile up until memory or
+/// the log runs out. The `WriteController` watches those three signals and slows writers down before that happens, in two
+/// stages per signal: above its slowdown threshold every write is delayed, for longer the closer the signal gets to its stop
+/// threshold, and at the stop threshold new writes are rejected with `Overloaded` until the backlog drains. Writers call
+/// `admit` before each write (or transaction); the current stall state and counters are in `stats`.
+///
+/// The signals are sampled at most once per `sample_interval`, so admitting a write is usually just a lock and a comparison.
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use crate::storage::buffer::bufmgr::{BufApi as _, BufferPool};
+use crate::storage::log::logmgr::{LogApi as _, LogManager};
+use crate::sync::{Latch as _, Synchronized};
+
+/// What's holding writes back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallReason {
+    DirtyPages,
+    /// Bytes of log appended but not yet durable
+    WalLag,
+    CompactionBacklog,
+}
+
+impl Display for StallReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StallReason::DirtyPages => write!(f, "too many dirty pages"),
+            StallReason::WalLag => write!(f, "log flushes falling behind"),
+            StallReason::CompactionBacklog => write!(f, "compaction falling behind"),
+        }
+    }
+}
+
+/// A write was rejected because a signal is at its stop threshold
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overloaded {
+    pub reason: StallReason,
+}
+
+impl Display for Overloaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "overloaded: {}", self.reason)
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StallState {
+    #[default]
+    Normal,
+    /// Every write is delayed by this much
+    Delayed(Duration, StallReason),
+    /// New writes are rejected
+    Stopped(StallReason),
+}
+
+/// Slowdown and stop thresholds for one signal. `stop` should be above `slowdown`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Threshold {
+    pub slowdown: usize,
+    pub stop: usize,
+}
+
+impl Threshold {
+    /// How far `value` is from the slowdown threshold towards the stop threshold: 0 at or below `slowdown`, 1 at or above
+    /// `stop`
+    fn level(&self, value: usize) -> f64 {
+        if value >= self.stop {
+            return 1.0;
+        }
+        if value <= self.slowdown {
+            return 0.0;
+        }
+        (value - self.slowdown) as f64 / (self.stop - self.slowdown) as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackpressureConfig {
+    pub dirty_pages: Threshold,
+    pub wal_lag: Threshold,
+    pub compaction_backlog: Threshold,
+    /// Delay just below a stop threshold. Delays grow linearly from zero at the slowdown threshold
+    pub max_delay: Duration,
+    pub sample_interval: Duration,
+}
+
+impl Default for BackpressureConfig {
+    fn default() -> Self {
+        BackpressureConfig {
+            dirty_pages: Threshold {
+                slowdown: 30,
+                stop: 45,
+            },
+            wal_lag: Threshold {
+                slowdown: 4 << 20,
+                stop: 16 << 20,
+            },
+            compaction_backlog: Threshold {
+                slowdown: 1000,
+                stop: 10000,
+            },
+            max_delay: Duration::from_millis(10),
+            sample_interval: Duration::from_millis(10),
+        }
+    }
+}
+
+/// The last sample of the signals, and what the controller has done so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BackpressureStats {
+    pub state: StallState,
+    pub dirty_pages: usize,
+    pub wal_lag: usize,
+    pub compaction_backlog: usize,
+    /// Writes admitted after a delay
+    pub delayed: usize,
+    pub rejected: usize,
+    pub total_delay: Duration,
+}
+
+pub struct WriteControllerInternal {
+    config: BackpressureConfig,
+    pool: Option<BufferPool>,
+    log: Option<LogManager>,
+    compaction_backlog: usize,
+    sampled: Option<Instant>,
+    stats: BackpressureStats,
+}
+
+impl WriteControllerInternal {
+    /// Resample the signals if the last sample is too old, and work out the stall state from them
+    fn refresh(&mut self, force: bool) {
+        if !force
+            && self
+                .sampled
+                .is_some_and(|sampled| sampled.elapsed() < self.config.sample_interval)
+        {
+            return;
+        }
+        self.sampled = Some(Instant::now());
+        self.stats.dirty_pages = self.pool.as_ref().map_or(0, |pool| pool.dirty_count());
+        self.stats.wal_lag = self.log.as_ref().map_or(0, |log| {
+            let last_lsn = log.last_lsn();
+            last_lsn.saturating_sub(log.persistent_lsn()) as usize
+        });
+        self.stats.compaction_backlog = self.compaction_backlog;
+
+        let (level, reason) = [
+            (
+                self.config.dirty_pages.level(self.stats.dirty_pages),
+                StallReason::DirtyPages,
+            ),
+            (
+                self.config.wal_lag.level(self.stats.wal_lag),
+                StallReason::WalLag,
+            ),
+            (
+                self.config
+                    .compaction_backlog
+                    .level(self.stats.compaction_backlog),
+                StallReason::CompactionBacklog,
+            ),
+        ]
+        .into_iter()
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .unwrap();
+        self.stats.state = if level >= 1.0 {
+            StallState::Stopped(reason)
+        } else if level > 0.0 {
+            StallState::Delayed(self.config.max_delay.mul_f64(level), reason)
+        } else {
+            StallState::Normal
+        };
+    }
+}
+
+pub type WriteController = Synchronized<WriteControllerInternal>;
+
+pub trait WriteControllerApi {
+    fn create(config: BackpressureConfig) -> Self;
+    fn admit(&self) -> Result<(), Overloaded>;
+    fn stats(&self) -> BackpressureStats;
+    fn set_buffer_pool(&self, pool: BufferPool);
+    fn set_log_manager(&self, log: LogManager);
+    fn set_compaction_backlog(&self, backlog: usize);
+}
+
+impl WriteControllerApi for WriteController {
+    fn create(config: BackpressureConfig) -> Self {
+        Synchronized::init(WriteControllerInternal {
+            config,
+            pool: None,
+            log: None,
+            compaction_backlog: 0,
+            sampled: None,
+            stats: BackpressureStats::default(),
+        })
+    }
+
+    /// Call before a write. Returns right away if nothing is backed up, sleeps first if something is over its slowdown
+    /// threshold, and fails if something is at its stop threshold
+    fn admit(&self) -> Result<(), Overloaded> {
+        let delay = {
+            let mut inner = self.lock();
+            inner.refresh(false);
+            match inner.stats.state {
+                StallState::Normal => return Ok(()),
+                StallState::Stopped(reason) => {
+                    inner.stats.rejected += 1;
+                    return Err(Overloaded { reason });
+                }
+                StallState::Delayed(delay, _) => {
+                    inner.stats.delayed += 1;
+                    inner.stats.total_delay += delay;
+                    delay
+                }
+            }
+        };
+        std::thread::sleep(delay);
+        Ok(())
+    }
+
+    /// The signals as of now, and the controller's counters
+    fn stats(&self) -> BackpressureStats {
+        let mut inner = self.lock();
+        inner.refresh(true);
+        inner.stats
+    }
+
+    /// Watch the number of dirty pages in `pool`
+    fn set_buffer_pool(&self, pool: BufferPool) {
+        let mut inner = self.lock();
+        inner.pool = Some(pool);
+        inner.sampled = None;
+    }
+
+    /// Watch how far flushes of `log` lag behind appends
+    fn set_log_manager(&self, log: LogManager) {
+        let mut inner = self.lock();
+        inner.log = Some(log);
+        inner.sampled = None;
+    }
+
+    /// Report the compaction backlog (e.g. underfull index nodes waiting to be merged). Takes effect immediately
+    fn set_compaction_backlog(&self, backlog: usize) {
+        let mut inner = self.lock();
+        inner.compaction_backlog = backlog;
+        inner.sampled = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::{cwd, PageId, INVALID_LSN};
+    use crate::storage::log::record::LogBody;
+
+    #[test]
+    fn test_graduated_backpressure() {
+        let controller = WriteController::create(BackpressureConfig {
+            compaction_backlog: Threshold {
+                slowdown: 100,
+                stop: 200,
+            },
+            max_delay: Duration::from_millis(20),
+            sample_interval: Duration::from_secs(60),
+            ..BackpressureConfig::default()
+        });
+        assert!(controller.admit().is_ok());
+        assert!(controller.stats().state == StallState::Normal);
+
+        controller.set_compaction_backlog(150);
+        let started = Instant::now();
+        assert!(controller.admit().is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(10));
+        let stats = controller.stats();
+        assert!(
+            stats.state
+                == StallState::Delayed(Duration::from_millis(10), StallReason::CompactionBacklog)
+        );
+        assert!(stats.delayed == 1 && stats.total_delay == Duration::from_millis(10));
+
+        controller.set_compaction_backlog(200);
+        let err = controller.admit().unwrap_err();
+        assert!(err.to_string() == "overloaded: compaction falling behind");
+        assert!(controller.stats().rejected == 1);
+
+        // writes go through again once the backlog drains
+        controller.set_compaction_backlog(0);
+        assert!(controller.admit().is_ok());
+    }
+
+    #[test]
+    fn test_dirty_pages_and_wal_lag() {
+        let dir = cwd() + "/tests/backpressure_tests";
+        std::fs::create_dir_all(&dir).unwrap();
+        let pool = BufferPool::create(&(dir.clone() + "/data.bin")).unwrap();
+        let log = LogManager::create(&(dir.clone() + "/wal.log")).unwrap();
+        let controller = WriteController::create(BackpressureConfig {
+            dirty_pages: Threshold {
+                slowdown: 2,
+                stop: 4,
+            },
+            wal_lag: Threshold {
+                slowdown: 1 << 20,
+                stop: 1 << 21,
+            },
+            ..BackpressureConfig::default()
+        });
+        controller.set_buffer_pool(pool.clone());
+        controller.set_log_manager(log.clone());
+
+        let page_ids: Vec<PageId> = (0..4)
+            .map(|_| {
+                let (page_id, _) = pool.new_page().unwrap();
+                pool.unpin_page(page_id, true);
+                page_id
+            })
+            .collect();
+        assert!(controller.stats().dirty_pages == 4);
+        assert!(
+            controller.admit()
+                == Err(Overloaded {
+                    reason: StallReason::DirtyPages
+                })
+        );
+        pool.flush_page(page_ids[0]).unwrap();
+        pool.flush_page(page_ids[1]).unwrap();
+        let stats = controller.stats();
+        assert!(stats.dirty_pages == 2 && stats.state == StallState::Normal);
+
+        // unflushed log counts towards the lag until it's flushed
+        let body = LogBody::Update {
+            page_id: 0,
+            offset: 0,
+            before: vec![0; 1 << 16],
+            after: vec![1; 1 << 16],
+        };
+        for _ in 0..20 {
+            log.append(1, INVALID_LSN, body.clone()).unwrap();
+        }
+        assert!(controller.stats().state == StallState::Stopped(StallReason::WalLag));
+        log.flush().unwrap();
+        assert!(controller.stats().wal_lag == 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+/// This file implements the snapshot horizon: the oldest LSN that anything may still read from. Open transactions, open
+/// cursors and connected replicas each hold the horizon back to the oldest point they can see (a transaction to its first log
+/// record, a cursor to the LSN its snapshot was taken at, a replica to the last LSN it has applied), and vacuum and WAL
+/// truncation may only discard what's older than the horizon. Local transactions are read from the transaction tracker;
+/// cursors and replicas register holds here and move them forward as they go.
+///
+/// A replica that disconnects or falls behind would otherwise pin the horizon forever. With `max_standby_lag` set, a replica
+/// hold that hasn't moved for that long is dropped (and `StorageEvent::ReplicaHoldDropped` published): the primary is then
+/// free to discard what the replica still needed, and the replica has to resynchronize from a backup.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::shared::Lsn;
+use crate::sync::{Latch as _, Synchronized};
+use crate::telemetry::{EventBus, EventBusApi as _, StorageEvent};
+use crate::txn::tracker::{TxnTracker, TxnTrackerApi as _};
+
+/// Something holding the horizon back, other than a local transaction
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Holder {
+    Cursor(u64),
+    Replica(String),
+}
+
+struct Hold {
+    lsn: Lsn,
+    // when the hold last moved forward
+    since: Instant,
+}
+
+pub struct SnapshotHorizonInternal {
+    holds: HashMap<Holder, Hold>,
+    tracker: Option<TxnTracker>,
+    max_standby_lag: Option<Duration>,
+    bus: Option<EventBus>,
+}
+
+impl SnapshotHorizonInternal {
+    /// Drop the holds of replicas that haven't made progress within `max_standby_lag`
+    fn drop_stale_replicas(&mut self) {
+        let Some(max_lag) = self.max_standby_lag else {
+            return;
+        };
+        let stale: Vec<(Holder, Duration)> = self
+            .holds
+            .iter()
+            .filter(|(holder, _)| matches!(holder, Holder::Replica(_)))
+            .map(|(holder, hold)| (holder.clone(), hold.since.elapsed()))
+            .filter(|(_, lag)| *lag > max_lag)
+            .collect();
+        for (holder, lag) in stale {
+            self.holds.remove(&holder);
+            if let (Some(bus), Holder::Replica(replica)) = (&self.bus, holder) {
+                bus.publish(StorageEvent::ReplicaHoldDropped { replica, lag });
+            }
+        }
+    }
+}
+
+pub type SnapshotHorizon = Synchronized<SnapshotHorizonInternal>;
+
+pub trait SnapshotHorizonApi {
+    fn create() -> Self;
+    fn hold(&self, holder: Holder, lsn: Lsn);
+    fn release(&self, holder: &Holder) -> bool;
+    fn holders(&self) -> Vec<(Holder, Lsn)>;
+    fn horizon(&self) -> Option<Lsn>;
+    fn permits(&self, lsn: Lsn) -> bool;
+    fn set_tracker(&self, tracker: TxnTracker);
+    fn set_max_standby_lag(&self, max_lag: Option<Duration>);
+    fn set_event_bus(&self, bus: EventBus);
+}
+
+impl SnapshotHorizonApi for SnapshotHorizon {
+    fn create() -> Self {
+        Synchronized::init(SnapshotHorizonInternal {
+            holds: HashMap::new(),
+            tracker: None,
+            max_standby_lag: None,
+            bus: None,
+        })
+    }
+
+    /// Hold the horizon at `lsn` on behalf of `holder`, registering it if it isn't registered yet. Holds normally only move
+    /// forward; moving one back is allowed, but only protects what hasn't been discarded yet
+    fn hold(&self, holder: Holder, lsn: Lsn) {
+        let mut inner = self.lock();
+        let now = Instant::now();
+        inner
+            .holds
+            .entry(holder)
+            .and_modify(|hold| {
+                if lsn > hold.lsn {
+                    hold.since = now;
+                }
+                hold.lsn = lsn;
+            })
+            .or_insert(Hold { lsn, since: now });
+    }
+
+    /// Remove `holder`'s hold, e.g. when a cursor is closed or a replica disconnects cleanly. Returns false if it had none
+    /// (for a replica, possibly because it was dropped for lagging)
+    fn release(&self, holder: &Holder) -> bool {
+        self.lock().holds.remove(holder).is_some()
+    }
+
+    /// Every registered hold, oldest first
+    fn holders(&self) -> Vec<(Holder, Lsn)> {
+        let mut inner = self.lock();
+        inner.drop_stale_replicas();
+        let mut holders: Vec<(Holder, Lsn)> = inner
+            .holds
+            .iter()
+            .map(|(holder, hold)| (holder.clone(), hold.lsn))
+            .collect();
+        holders.sort_by_key(|(_, lsn)| *lsn);
+        holders
+    }
+
+    /// The oldest LSN still visible to a transaction, cursor or replica, or `None` if nothing holds the horizon back
+    fn horizon(&self) -> Option<Lsn> {
+        let mut inner = self.lock();
+        inner.drop_stale_replicas();
+        let holds = inner.holds.values().map(|hold| hold.lsn).min();
+        let txns = inner
+            .tracker
+            .as_ref()
+            .and_then(|tracker| tracker.log_horizon());
+        holds.into_iter().chain(txns).min()
+    }
+
+    /// Whether log records and row versions older than `lsn` may be discarded, i.e. whether vacuum or WAL truncation may
+    /// proceed up to `lsn`
+    fn permits(&self, lsn: Lsn) -> bool {
+        self.horizon().is_none_or(|horizon| lsn <= horizon)
+    }
+
+    /// Take local transactions from `tracker`
+    fn set_tracker(&self, tracker: TxnTracker) {
+        self.lock().tracker = Some(tracker);
+    }
+
+    /// Drop replica holds that haven't moved for longer than `max_lag`. `None` (the default) lets replicas hold the horizon
+    /// back indefinitely
+    fn set_max_standby_lag(&self, max_lag: Option<Duration>) {
+        self.lock().max_standby_lag = max_lag;
+    }
+
+    /// Publish `StorageEvent::ReplicaHoldDropped` to `bus`
+    fn set_event_bus(&self, bus: EventBus) {
+        self.lock().bus = Some(bus);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_horizon() {
+        let horizon = SnapshotHorizon::create();
+        assert!(horizon.horizon().is_none() && horizon.permits(1000));
+        let tracker = TxnTracker::create();
+        horizon.set_tracker(tracker.clone());
+        tracker.begin(1);
+        tracker.logged(1, 300);
+        horizon.hold(Holder::Cursor(7), 200);
+        horizon.hold(Holder::Replica("standby".to_string()), 250);
+        assert!(horizon.horizon() == Some(200));
+        assert!(horizon.permits(200) && !horizon.permits(201));
+
+        // the cursor moves on, then closes
+        horizon.hold(Holder::Cursor(7), 400);
+        assert!(horizon.horizon() == Some(250));
+        assert!(horizon.release(&Holder::Cursor(7)));
+        assert!(!horizon.release(&Holder::Cursor(7)));
+        horizon.hold(Holder::Replica("standby".to_string()), 500);
+        assert!(horizon.horizon() == Some(300));
+        tracker.end(1);
+        assert!(horizon.holders() == vec![(Holder::Replica("standby".to_string()), 500)]);
+        assert!(horizon.horizon() == Some(500));
+    }
+
+    #[test]
+    fn test_stale_replica_is_dropped() {
+        let horizon = SnapshotHorizon::create();
+        let bus = EventBus::create();
+        let events = bus.subscribe();
+        horizon.set_event_bus(bus);
+        horizon.hold(Holder::Replica("stale".to_string()), 100);
+        horizon.hold(Holder::Replica("live".to_string()), 150);
+        std::thread::sleep(Duration::from_millis(30));
+        // re-reporting the same LSN isn't progress
+        horizon.hold(Holder::Replica("stale".to_string()), 100);
+        horizon.hold(Holder::Replica("live".to_string()), 200);
+        assert!(horizon.horizon() == Some(100));
+
+        horizon.set_max_standby_lag(Some(Duration::from_millis(20)));
+        assert!(horizon.horizon() == Some(200));
+        match events.try_recv().unwrap() {
+            StorageEvent::ReplicaHoldDropped { replica, lag } => {
+                assert!(replica == "stale" && lag >= Duration::from_millis(20))
+            }
+            event => panic!("unexpected event {}", event),
+        }
+        assert!(events.try_recv().is_err());
+    }
+}
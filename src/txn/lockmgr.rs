@@ -0,0 +1,796 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::shared::{PageId, TxnId};
+use crate::storage::table::heap::Rid;
+use crate::txn::session::{CancellationToken, Interrupted};
+
+/// How often a waiter with a cancellation token wakes up to check it. Cancelling doesn't signal the condvar, so waits are
+/// bounded by this (or the statement deadline, if sooner)
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What a lock protects: a whole page, or a single tuple in a table heap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LockTarget {
+    Page(PageId),
+    Row(Rid),
+}
+
+impl Display for LockTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockTarget::Page(page_id) => write!(f, "page {}", page_id),
+            LockTarget::Row(rid) => write!(f, "row {}:{}", rid.page_id, rid.slot),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+impl Display for LockMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockMode::Shared => write!(f, "shared"),
+            LockMode::Exclusive => write!(f, "exclusive"),
+        }
+    }
+}
+
+impl LockMode {
+    fn compatible(self, other: LockMode) -> bool {
+        self == LockMode::Shared && other == LockMode::Shared
+    }
+}
+
+/// How conflicting requests are ordered. `Fifo` grants requests in arrival order and leaves deadlocks to the caller. `WoundWait`
+/// prevents them: transaction ids double as timestamps (smaller is older), an older transaction that conflicts with a younger
+/// one wounds (aborts) it, and a younger transaction waits for an older one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LockPolicy {
+    #[default]
+    Fifo,
+    WoundWait,
+}
+
+/// Which transaction in a deadlock cycle gets aborted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VictimPolicy {
+    /// The transaction with the largest id
+    #[default]
+    Youngest,
+    /// The transaction holding or waiting for the fewest locks (the cheapest to roll back), youngest on ties
+    FewestLocks,
+}
+
+/// Configuration for the background deadlock detector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlockDetection {
+    pub interval: Duration,
+    pub victim: VictimPolicy,
+}
+
+impl Default for DeadlockDetection {
+    fn default() -> Self {
+        DeadlockDetection {
+            interval: Duration::from_millis(50),
+            victim: VictimPolicy::default(),
+        }
+    }
+}
+
+/// Why an interruptible lock request failed. `Aborted` means the transaction was wounded or chosen as a deadlock victim and
+/// must roll back; `Interrupted` means only the statement stopped, the transaction keeps the locks it already held and can
+/// either roll back or carry on with another statement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockError {
+    Aborted,
+    Interrupted(Interrupted),
+}
+
+impl From<Interrupted> for LockError {
+    fn from(interrupted: Interrupted) -> Self {
+        LockError::Interrupted(interrupted)
+    }
+}
+
+/// A transaction waiting for a lock, as reported by `LockApi::waits`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockWait {
+    pub txn_id: TxnId,
+    pub target: LockTarget,
+    /// The mode asked for. A shared lock waiting to be upgraded asks for `Exclusive`
+    pub mode: LockMode,
+    /// Transactions holding a lock on the target, and in which mode
+    pub holders: Vec<(TxnId, LockMode)>,
+    /// Transactions the waiter has to wait for: conflicting holders, and conflicting requests queued ahead of it
+    pub blocked_by: Vec<TxnId>,
+    pub waited: Duration,
+}
+
+impl Display for LockWait {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let holders: Vec<String> = self
+            .holders
+            .iter()
+            .map(|(txn_id, mode)| format!("{} ({})", txn_id, mode))
+            .collect();
+        let blocked_by: Vec<String> = self.blocked_by.iter().map(TxnId::to_string).collect();
+        write!(
+            f,
+            "txn {} waiting {:.3}s for {} lock on {} [held by: {}; blocked by: {}]",
+            self.txn_id,
+            self.waited.as_secs_f64(),
+            self.mode,
+            self.target,
+            holders.join(", "),
+            blocked_by.join(", ")
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LockRequest {
+    txn_id: TxnId,
+    mode: LockMode,
+    granted: bool,
+    // a granted shared lock waiting to become exclusive. it keeps its shared lock until the upgrade is granted
+    upgrading: bool,
+    // when the request (or the upgrade) started waiting
+    since: Instant,
+}
+
+pub struct LockManagerInternal {
+    policy: LockPolicy,
+    // requests per target in arrival order
+    queues: HashMap<LockTarget, Vec<LockRequest>>,
+    // targets each transaction holds or is waiting for
+    held: HashMap<TxnId, HashSet<LockTarget>>,
+    // transactions that have released a lock and so can't acquire new ones (the second phase of 2PL)
+    shrinking: HashSet<TxnId>,
+    aborted: HashSet<TxnId>,
+    detection: Option<DeadlockDetection>,
+}
+
+impl LockManagerInternal {
+    /// Whether `txn_id`'s request on `target` can be granted now, and whether anyone was newly wounded. Under `WoundWait`,
+    /// younger transactions standing in the way are wounded along the way.
+    fn grantable(&mut self, txn_id: TxnId, target: LockTarget) -> (bool, bool) {
+        let blockers = self.blockers(txn_id, target);
+        let mut wounded = false;
+        if self.policy == LockPolicy::WoundWait {
+            for blocker in blockers.iter().filter(|blocker| **blocker > txn_id) {
+                wounded |= self.aborted.insert(*blocker);
+            }
+        }
+        (blockers.is_empty(), wounded)
+    }
+
+    /// Transactions that `txn_id`'s request on `target` has to wait for
+    fn blockers(&self, txn_id: TxnId, target: LockTarget) -> Vec<TxnId> {
+        let queue = &self.queues[&target];
+        let pos = queue.iter().position(|req| req.txn_id == txn_id).unwrap();
+        let req = &queue[pos];
+        let mode = if req.upgrading {
+            LockMode::Exclusive
+        } else {
+            req.mode
+        };
+        let mut blockers = Vec::new();
+        for (i, other) in queue.iter().enumerate() {
+            if other.txn_id == txn_id {
+                continue;
+            }
+            let conflicts = if other.granted {
+                other.upgrading || !mode.compatible(other.mode)
+            } else {
+                // waiting requests ahead of us go first, except that an upgrade jumps the queue
+                i < pos && !req.upgrading
+            };
+            if conflicts {
+                blockers.push(other.txn_id);
+            }
+        }
+        blockers
+    }
+
+    /// The waits-for graph: every waiting transaction mapped to the transactions it waits for. Transactions that are already
+    /// aborted are left out since they're about to stop waiting.
+    fn waits_for(&self) -> BTreeMap<TxnId, Vec<TxnId>> {
+        let mut graph: BTreeMap<TxnId, Vec<TxnId>> = BTreeMap::new();
+        for (target, queue) in self.queues.iter() {
+            for req in queue.iter() {
+                if (req.granted && !req.upgrading) || self.aborted.contains(&req.txn_id) {
+                    continue;
+                }
+                let blockers = self
+                    .blockers(req.txn_id, *target)
+                    .into_iter()
+                    .filter(|blocker| !self.aborted.contains(blocker));
+                graph.entry(req.txn_id).or_default().extend(blockers);
+            }
+        }
+        for edges in graph.values_mut() {
+            edges.sort_unstable();
+            edges.dedup();
+        }
+        graph
+    }
+
+    /// Every waiting request, longest waiting first
+    fn waits(&self) -> Vec<LockWait> {
+        let mut waits = Vec::new();
+        for (target, queue) in self.queues.iter() {
+            let holders: Vec<(TxnId, LockMode)> = queue
+                .iter()
+                .filter(|req| req.granted)
+                .map(|req| (req.txn_id, req.mode))
+                .collect();
+            for req in queue.iter().filter(|req| !req.granted || req.upgrading) {
+                let mut blocked_by = self.blockers(req.txn_id, *target);
+                blocked_by.sort_unstable();
+                waits.push(LockWait {
+                    txn_id: req.txn_id,
+                    target: *target,
+                    mode: if req.upgrading {
+                        LockMode::Exclusive
+                    } else {
+                        req.mode
+                    },
+                    holders: holders.clone(),
+                    blocked_by,
+                    waited: req.since.elapsed(),
+                });
+            }
+        }
+        waits.sort_by(|a, b| b.waited.cmp(&a.waited).then(a.txn_id.cmp(&b.txn_id)));
+        waits
+    }
+
+    /// Abort one victim per cycle in the waits-for graph until it's acyclic. Returns the victims
+    fn break_deadlocks(&mut self, policy: VictimPolicy) -> Vec<TxnId> {
+        let mut graph = self.waits_for();
+        let mut victims = Vec::new();
+        while let Some(cycle) = find_cycle(&graph) {
+            let victim = match policy {
+                VictimPolicy::Youngest => *cycle.iter().max().unwrap(),
+                VictimPolicy::FewestLocks => *cycle
+                    .iter()
+                    .min_by_key(|txn_id| {
+                        let locks = self.held.get(txn_id).map_or(0, |targets| targets.len());
+                        (locks, std::cmp::Reverse(**txn_id))
+                    })
+                    .unwrap(),
+            };
+            graph.remove(&victim);
+            self.aborted.insert(victim);
+            victims.push(victim);
+        }
+        victims
+    }
+
+    fn remove_request(&mut self, txn_id: TxnId, target: LockTarget) -> Option<LockRequest> {
+        let queue = self.queues.get_mut(&target)?;
+        let pos = queue.iter().position(|req| req.txn_id == txn_id)?;
+        let req = queue.remove(pos);
+        if queue.is_empty() {
+            self.queues.remove(&target);
+        }
+        if let Some(targets) = self.held.get_mut(&txn_id) {
+            targets.remove(&target);
+        }
+        Some(req)
+    }
+}
+
+/// Two-phase lock manager. Transactions take shared or exclusive locks on targets, blocking while a conflicting lock is held.
+/// Once a transaction releases any lock it can't acquire new ones; `unlock_all` releases everything when it ends. A request
+/// returns false if the transaction has been aborted (wounded) while waiting, in which case it should roll back and call
+/// `unlock_all`.
+///
+/// These are logical locks held for the duration of a transaction, not the short-term latches in `sync`.
+pub type LockManager = Arc<(Mutex<LockManagerInternal>, Condvar)>;
+
+pub trait LockApi {
+    fn create(policy: LockPolicy) -> Self;
+    fn lock_shared(&self, txn_id: TxnId, target: LockTarget) -> bool;
+    fn lock_exclusive(&self, txn_id: TxnId, target: LockTarget) -> bool;
+    fn unlock(&self, txn_id: TxnId, target: LockTarget) -> bool;
+    fn unlock_all(&self, txn_id: TxnId);
+    fn is_aborted(&self, txn_id: TxnId) -> bool;
+    fn abort(&self, txn_id: TxnId);
+    fn lock_mode(&self, txn_id: TxnId, target: LockTarget) -> Option<LockMode>;
+    fn create_with_detection(policy: LockPolicy, detection: DeadlockDetection) -> Self;
+    fn detect_deadlocks(&self) -> Vec<TxnId>;
+    fn waits(&self) -> Vec<LockWait>;
+    fn lock_interruptible(
+        &self,
+        txn_id: TxnId,
+        target: LockTarget,
+        mode: LockMode,
+        cancel: &CancellationToken,
+    ) -> Result<(), LockError>;
+}
+
+impl LockApi for LockManager {
+    fn create(policy: LockPolicy) -> Self {
+        Arc::new((
+            Mutex::new(LockManagerInternal {
+                policy,
+                queues: HashMap::new(),
+                held: HashMap::new(),
+                shrinking: HashSet::new(),
+                aborted: HashSet::new(),
+                detection: None,
+            }),
+            Condvar::new(),
+        ))
+    }
+
+    fn lock_shared(&self, txn_id: TxnId, target: LockTarget) -> bool {
+        lock(self, txn_id, target, LockMode::Shared, None).is_ok()
+    }
+
+    /// Take an exclusive lock, upgrading a shared lock the transaction already holds
+    fn lock_exclusive(&self, txn_id: TxnId, target: LockTarget) -> bool {
+        lock(self, txn_id, target, LockMode::Exclusive, None).is_ok()
+    }
+
+    /// Take a lock like `lock_shared`/`lock_exclusive`, but give up waiting once the statement owning `cancel` is cancelled or
+    /// runs past its deadline. The pending request is withdrawn (a pending upgrade keeps its shared lock), so the transaction
+    /// is left exactly as it was before the call.
+    fn lock_interruptible(
+        &self,
+        txn_id: TxnId,
+        target: LockTarget,
+        mode: LockMode,
+        cancel: &CancellationToken,
+    ) -> Result<(), LockError> {
+        lock(self, txn_id, target, mode, Some(cancel))
+    }
+
+    /// Release one lock, moving the transaction into its shrinking phase. Returns false if it wasn't holding a lock on `target`
+    fn unlock(&self, txn_id: TxnId, target: LockTarget) -> bool {
+        let (mutex, condvar) = &**self;
+        let mut inner = mutex.lock();
+        match inner.remove_request(txn_id, target) {
+            Some(req) if req.granted => {
+                inner.shrinking.insert(txn_id);
+                condvar.notify_all();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Release every lock held by `txn_id` and forget about it. Call this when the transaction commits or aborts
+    fn unlock_all(&self, txn_id: TxnId) {
+        let (mutex, condvar) = &**self;
+        let mut inner = mutex.lock();
+        let targets = inner.held.remove(&txn_id).unwrap_or_default();
+        for target in targets {
+            inner.remove_request(txn_id, target);
+        }
+        inner.shrinking.remove(&txn_id);
+        inner.aborted.remove(&txn_id);
+        condvar.notify_all();
+    }
+
+    fn is_aborted(&self, txn_id: TxnId) -> bool {
+        self.0.lock().aborted.contains(&txn_id)
+    }
+
+    /// Abort `txn_id` from outside, e.g. because it has been running for too long. A lock request it's waiting on returns
+    /// false (`LockError::Aborted`), and so does every request it makes until `unlock_all`
+    fn abort(&self, txn_id: TxnId) {
+        let (mutex, condvar) = &**self;
+        mutex.lock().aborted.insert(txn_id);
+        condvar.notify_all();
+    }
+
+    /// The lock `txn_id` currently holds on `target`, if any
+    fn lock_mode(&self, txn_id: TxnId, target: LockTarget) -> Option<LockMode> {
+        let inner = self.0.lock();
+        inner
+            .queues
+            .get(&target)?
+            .iter()
+            .find(|req| req.txn_id == txn_id && req.granted)
+            .map(|req| req.mode)
+    }
+
+    /// Create a lock manager with a background thread that looks for deadlocks every `detection.interval` and aborts a victim
+    /// from each cycle it finds. The thread exits once the lock manager is dropped.
+    fn create_with_detection(policy: LockPolicy, detection: DeadlockDetection) -> Self {
+        let lock_mgr = LockManager::create(policy);
+        lock_mgr.0.lock().detection = Some(detection);
+        let weak: Weak<(Mutex<LockManagerInternal>, Condvar)> = Arc::downgrade(&lock_mgr);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(detection.interval);
+            let Some(lock_mgr) = weak.upgrade() else {
+                return;
+            };
+            lock_mgr.detect_deadlocks();
+        });
+        lock_mgr
+    }
+
+    /// A snapshot of every transaction currently waiting for a lock: what it waits for, who holds it, and for how long. Meant
+    /// for diagnosing blocked sessions, like `pg_locks`
+    fn waits(&self) -> Vec<LockWait> {
+        self.0.lock().waits()
+    }
+
+    /// Run one round of deadlock detection now and return the transactions that were aborted. Their pending lock requests
+    /// return false.
+    fn detect_deadlocks(&self) -> Vec<TxnId> {
+        let (mutex, condvar) = &**self;
+        let mut inner = mutex.lock();
+        let policy = inner.detection.unwrap_or_default().victim;
+        let victims = inner.break_deadlocks(policy);
+        if !victims.is_empty() {
+            condvar.notify_all();
+        }
+        victims
+    }
+}
+
+/// Find a cycle in a waits-for graph, visiting transactions in id order so the result is deterministic
+fn find_cycle(graph: &BTreeMap<TxnId, Vec<TxnId>>) -> Option<Vec<TxnId>> {
+    fn visit(
+        graph: &BTreeMap<TxnId, Vec<TxnId>>,
+        txn_id: TxnId,
+        path: &mut Vec<TxnId>,
+        done: &mut HashSet<TxnId>,
+    ) -> Option<Vec<TxnId>> {
+        if let Some(start) = path.iter().position(|id| *id == txn_id) {
+            return Some(path[start..].to_vec());
+        }
+        if done.contains(&txn_id) {
+            return None;
+        }
+        path.push(txn_id);
+        for next in graph.get(&txn_id).into_iter().flatten() {
+            if let Some(cycle) = visit(graph, *next, path, done) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        done.insert(txn_id);
+        None
+    }
+
+    let mut done = HashSet::new();
+    for txn_id in graph.keys() {
+        if let Some(cycle) = visit(graph, *txn_id, &mut Vec::new(), &mut done) {
+            return Some(cycle);
+        }
+    }
+    None
+}
+
+fn lock(
+    lock_mgr: &LockManager,
+    txn_id: TxnId,
+    target: LockTarget,
+    mode: LockMode,
+    cancel: Option<&CancellationToken>,
+) -> Result<(), LockError> {
+    let (mutex, condvar) = &**lock_mgr;
+    let mut inner = mutex.lock();
+    if inner.aborted.contains(&txn_id) || inner.shrinking.contains(&txn_id) {
+        return Err(LockError::Aborted);
+    }
+    if let Some(cancel) = cancel {
+        cancel.check()?;
+    }
+    let queue = inner.queues.entry(target).or_default();
+    let existing = queue
+        .iter()
+        .find(|req| req.txn_id == txn_id)
+        .map(|req| req.mode);
+    let upgrade_pending = queue.iter().any(|req| req.upgrading);
+    match existing {
+        Some(held) if held == LockMode::Exclusive || mode == LockMode::Shared => return Ok(()),
+        Some(_) => {
+            // only one upgrade can wait on a target at a time, otherwise the two upgraders wait on each other forever
+            if upgrade_pending {
+                inner.aborted.insert(txn_id);
+                return Err(LockError::Aborted);
+            }
+            let req = queue.iter_mut().find(|req| req.txn_id == txn_id).unwrap();
+            req.upgrading = true;
+            req.since = Instant::now();
+        }
+        None => {
+            queue.push(LockRequest {
+                txn_id,
+                mode,
+                granted: false,
+                upgrading: false,
+                since: Instant::now(),
+            });
+            inner.held.entry(txn_id).or_default().insert(target);
+        }
+    }
+
+    loop {
+        if inner.aborted.contains(&txn_id) {
+            abandon(&mut inner, txn_id, target);
+            condvar.notify_all();
+            return Err(LockError::Aborted);
+        }
+        let (grantable, wounded) = inner.grantable(txn_id, target);
+        // a wounded transaction may be waiting and has to notice it was aborted
+        if wounded {
+            condvar.notify_all();
+        }
+        if grantable {
+            break;
+        }
+        match cancel {
+            None => condvar.wait(&mut inner),
+            Some(cancel) => {
+                if let Err(interrupted) = cancel.check() {
+                    abandon(&mut inner, txn_id, target);
+                    condvar.notify_all();
+                    return Err(interrupted.into());
+                }
+                let mut wake = Instant::now() + CANCEL_POLL_INTERVAL;
+                if let Some(deadline) = cancel.deadline() {
+                    wake = wake.min(deadline);
+                }
+                condvar.wait_until(&mut inner, wake);
+            }
+        }
+    }
+    let queue = inner.queues.get_mut(&target).unwrap();
+    let req = queue.iter_mut().find(|req| req.txn_id == txn_id).unwrap();
+    req.granted = true;
+    req.mode = mode;
+    req.upgrading = false;
+    // requests queued behind this one were waiting for it to go first
+    if queue.iter().any(|req| !req.granted) {
+        condvar.notify_all();
+    }
+    Ok(())
+}
+
+/// Give up on a pending request. A failed upgrade leaves the shared lock in place
+fn abandon(inner: &mut LockManagerInternal, txn_id: TxnId, target: LockTarget) {
+    let queue = inner.queues.get_mut(&target).unwrap();
+    let req = queue.iter_mut().find(|req| req.txn_id == txn_id).unwrap();
+    if req.upgrading {
+        req.upgrading = false;
+    } else {
+        inner.remove_request(txn_id, target);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::txn::session::Session;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const PAGE: LockTarget = LockTarget::Page(1);
+
+    #[test]
+    fn test_shared_and_exclusive() {
+        let lock_mgr = LockManager::create(LockPolicy::Fifo);
+        assert!(lock_mgr.lock_shared(1, PAGE));
+        assert!(lock_mgr.lock_shared(2, PAGE));
+        assert!(lock_mgr.lock_mode(2, PAGE) == Some(LockMode::Shared));
+
+        let acquired = Arc::new(AtomicUsize::new(0));
+        let writer = {
+            let lock_mgr = lock_mgr.clone();
+            let acquired = acquired.clone();
+            std::thread::spawn(move || {
+                assert!(lock_mgr.lock_exclusive(3, PAGE));
+                acquired.store(1, Ordering::SeqCst);
+                lock_mgr.unlock_all(3);
+            })
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(acquired.load(Ordering::SeqCst) == 0);
+        assert!(lock_mgr.unlock(1, PAGE));
+        // two-phase: no new locks once a lock has been released
+        assert!(!lock_mgr.lock_shared(1, LockTarget::Page(2)));
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(acquired.load(Ordering::SeqCst) == 0);
+        lock_mgr.unlock_all(2);
+        writer.join().unwrap();
+        assert!(acquired.load(Ordering::SeqCst) == 1);
+        assert!(!lock_mgr.unlock(3, PAGE));
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let lock_mgr = LockManager::create(LockPolicy::Fifo);
+        assert!(lock_mgr.lock_shared(1, PAGE));
+        assert!(lock_mgr.lock_shared(2, PAGE));
+        let upgrader = {
+            let lock_mgr = lock_mgr.clone();
+            std::thread::spawn(move || lock_mgr.lock_exclusive(1, PAGE))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(lock_mgr.lock_mode(1, PAGE) == Some(LockMode::Shared));
+        // a second upgrade would deadlock with the first
+        assert!(!lock_mgr.lock_exclusive(2, PAGE));
+        assert!(lock_mgr.is_aborted(2));
+        lock_mgr.unlock_all(2);
+        assert!(upgrader.join().unwrap());
+        assert!(lock_mgr.lock_mode(1, PAGE) == Some(LockMode::Exclusive));
+    }
+
+    #[test]
+    fn test_waits() {
+        let lock_mgr = LockManager::create(LockPolicy::Fifo);
+        assert!(lock_mgr.lock_shared(1, PAGE));
+        assert!(lock_mgr.lock_shared(2, PAGE));
+        assert!(lock_mgr.waits().is_empty());
+        let waiters: Vec<_> = [1, 3]
+            .into_iter()
+            .map(|txn_id| {
+                let lock_mgr = lock_mgr.clone();
+                let handle = std::thread::spawn(move || lock_mgr.lock_exclusive(txn_id, PAGE));
+                std::thread::sleep(Duration::from_millis(20));
+                handle
+            })
+            .collect();
+
+        let waits = lock_mgr.waits();
+        assert!(waits.len() == 2);
+        // the upgrade has been waiting longest
+        assert!(waits[0].txn_id == 1 && waits[0].mode == LockMode::Exclusive);
+        assert!(waits[0].holders == vec![(1, LockMode::Shared), (2, LockMode::Shared)]);
+        assert!(waits[0].blocked_by == vec![2]);
+        assert!(waits[1].txn_id == 3 && waits[1].blocked_by == vec![1, 2]);
+        assert!(waits[0].waited > waits[1].waited);
+        assert!(waits[1].to_string().starts_with("txn 3 waiting"));
+        assert!(waits[1].to_string().ends_with(
+            "for exclusive lock on page 1 [held by: 1 (shared), 2 (shared); blocked by: 1, 2]"
+        ));
+
+        lock_mgr.unlock_all(2);
+        std::thread::sleep(Duration::from_millis(20));
+        let waits = lock_mgr.waits();
+        assert!(waits.len() == 1);
+        assert!(waits[0].holders == vec![(1, LockMode::Exclusive)]);
+        lock_mgr.unlock_all(1);
+        for waiter in waiters {
+            assert!(waiter.join().unwrap());
+        }
+        assert!(lock_mgr.waits().is_empty());
+    }
+
+    #[test]
+    fn test_deadlock_detection() {
+        let lock_mgr = LockManager::create_with_detection(
+            LockPolicy::Fifo,
+            DeadlockDetection {
+                interval: Duration::from_millis(10),
+                victim: VictimPolicy::Youngest,
+            },
+        );
+        let other = LockTarget::Page(2);
+        assert!(lock_mgr.lock_exclusive(1, PAGE));
+        assert!(lock_mgr.lock_exclusive(2, other));
+        let older = {
+            let lock_mgr = lock_mgr.clone();
+            std::thread::spawn(move || {
+                let acquired = lock_mgr.lock_exclusive(1, other);
+                lock_mgr.unlock_all(1);
+                acquired
+            })
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        // 1 waits for 2 and 2 waits for 1: the detector aborts the younger one
+        assert!(!lock_mgr.lock_shared(2, PAGE));
+        lock_mgr.unlock_all(2);
+        assert!(older.join().unwrap());
+    }
+
+    #[test]
+    fn test_fewest_locks_victim() {
+        let lock_mgr = LockManager::create(LockPolicy::Fifo);
+        lock_mgr.0.lock().detection = Some(DeadlockDetection {
+            interval: Duration::from_secs(60),
+            victim: VictimPolicy::FewestLocks,
+        });
+        for page_id in 2..5 {
+            assert!(lock_mgr.lock_shared(2, LockTarget::Page(page_id)));
+        }
+        assert!(lock_mgr.lock_exclusive(1, PAGE));
+        assert!(lock_mgr.lock_exclusive(2, LockTarget::Page(10)));
+        let waiters: Vec<_> = [(1, LockTarget::Page(10)), (2, PAGE)]
+            .into_iter()
+            .map(|(txn_id, target)| {
+                let lock_mgr = lock_mgr.clone();
+                std::thread::spawn(move || {
+                    let acquired = lock_mgr.lock_exclusive(txn_id, target);
+                    lock_mgr.unlock_all(txn_id);
+                    acquired
+                })
+            })
+            .collect();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(lock_mgr.detect_deadlocks() == vec![1]);
+        let results: Vec<bool> = waiters.into_iter().map(|w| w.join().unwrap()).collect();
+        assert!(results == vec![false, true]);
+    }
+
+    #[test]
+    fn test_wound_wait() {
+        let lock_mgr = LockManager::create(LockPolicy::WoundWait);
+        // the younger transaction holds the lock, the older one wounds it
+        assert!(lock_mgr.lock_exclusive(2, PAGE));
+        let older = {
+            let lock_mgr = lock_mgr.clone();
+            std::thread::spawn(move || lock_mgr.lock_exclusive(1, PAGE))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(lock_mgr.is_aborted(2));
+        assert!(!lock_mgr.lock_shared(2, LockTarget::Page(2)));
+        lock_mgr.unlock_all(2);
+        assert!(older.join().unwrap());
+
+        // a younger transaction waits for an older one instead
+        let younger = {
+            let lock_mgr = lock_mgr.clone();
+            std::thread::spawn(move || lock_mgr.lock_shared(3, PAGE))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!lock_mgr.is_aborted(1));
+        lock_mgr.unlock_all(1);
+        assert!(younger.join().unwrap());
+    }
+
+    #[test]
+    fn test_statement_timeout() {
+        let lock_mgr = LockManager::create(LockPolicy::Fifo);
+        assert!(lock_mgr.lock_exclusive(1, PAGE));
+        assert!(lock_mgr.lock_shared(2, LockTarget::Page(2)));
+
+        let mut session = Session::new().with_statement_timeout(Duration::from_millis(30));
+        session.begin(2);
+        session.begin_statement();
+        let token = session.cancellation_token();
+        let started = Instant::now();
+        let result = lock_mgr.lock_interruptible(2, PAGE, LockMode::Shared, &token);
+        assert!(result == Err(LockError::Interrupted(Interrupted::StatementTimeout)));
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        // the request is withdrawn and the transaction keeps what it held
+        assert!(!lock_mgr.is_aborted(2));
+        assert!(lock_mgr.lock_mode(2, PAGE).is_none());
+        assert!(lock_mgr.lock_mode(2, LockTarget::Page(2)) == Some(LockMode::Shared));
+
+        // a cancel from another thread wakes the waiter too. No timeout this time, so the cancel can't lose the race
+        session.set_statement_timeout(None);
+        session.begin_statement();
+        let canceller = {
+            let token = session.cancellation_token();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                token.cancel();
+            })
+        };
+        let token = session.cancellation_token();
+        let result = lock_mgr.lock_interruptible(2, PAGE, LockMode::Exclusive, &token);
+        assert!(result == Err(LockError::Interrupted(Interrupted::Cancelled)));
+        canceller.join().unwrap();
+        lock_mgr.unlock_all(1);
+        session.begin_statement();
+        assert!(lock_mgr
+            .lock_interruptible(2, PAGE, LockMode::Exclusive, &session.cancellation_token())
+            .is_ok());
+    }
+}
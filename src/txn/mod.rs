@@ -0,0 +1,7 @@
+pub mod backpressure;
+pub mod horizon;
+pub mod lockmgr;
+pub mod oracle;
+pub mod retry;
+pub mod session;
+pub mod tracker;
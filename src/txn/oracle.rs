@@ -0,0 +1,234 @@
+/// This file implements the timestamp oracle, which hands out commit timestamps from a hybrid logical clock (HLC). A timestamp
+/// is a wall-clock reading in milliseconds paired with a logical counter: timestamps follow the wall clock, so they can be
+/// compared against a point in time (e.g. a restore target), but they never go backwards when the clock does, and two
+/// timestamps issued within the same millisecond are still distinct and ordered. Unlike transaction ids, which only order
+/// transactions started on one node, timestamps stay comparable across nodes: a node that receives a timestamp from another
+/// (e.g. with a replicated commit) passes it to `observe`, and everything it issues afterwards is ordered after it.
+///
+/// A timestamp from a node whose clock is further ahead than `max_offset` is rejected rather than observed, since it would
+/// drag every later timestamp on this node ahead of the wall clock with it.
+use std::fmt::Display;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::sim::{SimApi as _, Simulation};
+use crate::sync::{Latch as _, Synchronized};
+
+/// Default bound on how far ahead of the local clock an observed timestamp may be
+pub const MAX_CLOCK_OFFSET: Duration = Duration::from_millis(500);
+
+/// A hybrid logical clock reading. Timestamps order by wall clock first and logical counter second
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Timestamp {
+    /// Milliseconds since the Unix epoch
+    pub physical: u64,
+    pub logical: u32,
+}
+
+impl Timestamp {
+    pub const ZERO: Timestamp = Timestamp {
+        physical: 0,
+        logical: 0,
+    };
+
+    /// The first timestamp at or after `physical` milliseconds since the Unix epoch, e.g. to turn a restore target into a
+    /// timestamp
+    pub fn at(physical: u64) -> Self {
+        Timestamp {
+            physical,
+            logical: 0,
+        }
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.physical, self.logical)
+    }
+}
+
+/// An observed timestamp was further ahead of the local clock than the oracle allows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSkew {
+    pub remote: Timestamp,
+    pub local: u64,
+}
+
+impl Display for ClockSkew {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "timestamp {} is {}ms ahead of the local clock",
+            self.remote,
+            self.remote.physical - self.local
+        )
+    }
+}
+
+impl std::error::Error for ClockSkew {}
+
+pub struct TimestampOracleInternal {
+    last: Timestamp,
+    max_offset: Duration,
+    sim: Option<Simulation>,
+}
+
+impl TimestampOracleInternal {
+    /// The physical clock: wall-clock milliseconds, or the simulation's logical time when one is attached
+    fn clock(&self) -> u64 {
+        match &self.sim {
+            Some(sim) => sim.now(),
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_millis() as u64),
+        }
+    }
+}
+
+pub type TimestampOracle = Synchronized<TimestampOracleInternal>;
+
+pub trait TimestampOracleApi {
+    fn create() -> Self;
+    fn now(&self) -> Timestamp;
+    fn last(&self) -> Timestamp;
+    fn observe(&self, remote: Timestamp) -> Result<Timestamp, ClockSkew>;
+    fn set_max_offset(&self, max_offset: Duration);
+    fn set_simulation(&self, sim: Simulation);
+}
+
+impl TimestampOracleApi for TimestampOracle {
+    fn create() -> Self {
+        Synchronized::init(TimestampOracleInternal {
+            last: Timestamp::ZERO,
+            max_offset: MAX_CLOCK_OFFSET,
+            sim: None,
+        })
+    }
+
+    /// Issue a timestamp later than every timestamp issued or observed so far
+    fn now(&self) -> Timestamp {
+        let mut inner = self.lock();
+        let physical = inner.clock();
+        inner.last = if physical > inner.last.physical {
+            Timestamp::at(physical)
+        } else {
+            Timestamp {
+                physical: inner.last.physical,
+                logical: inner.last.logical + 1,
+            }
+        };
+        inner.last
+    }
+
+    /// The latest timestamp issued or observed, without issuing a new one
+    fn last(&self) -> Timestamp {
+        self.lock().last
+    }
+
+    /// Merge in `remote`, a timestamp issued by another node, and issue a timestamp later than both it and everything this
+    /// oracle has issued. Fails, leaving the clock alone, if `remote` is more than the maximum offset ahead of the local clock
+    fn observe(&self, remote: Timestamp) -> Result<Timestamp, ClockSkew> {
+        let mut inner = self.lock();
+        let physical = inner.clock();
+        if remote.physical > physical + inner.max_offset.as_millis() as u64 {
+            return Err(ClockSkew {
+                remote,
+                local: physical,
+            });
+        }
+        let latest = inner.last.max(remote);
+        inner.last = if physical > latest.physical {
+            Timestamp::at(physical)
+        } else {
+            Timestamp {
+                physical: latest.physical,
+                logical: latest.logical + 1,
+            }
+        };
+        Ok(inner.last)
+    }
+
+    /// Reject observed timestamps more than `max_offset` ahead of the local clock (`MAX_CLOCK_OFFSET` by default)
+    fn set_max_offset(&self, max_offset: Duration) {
+        self.lock().max_offset = max_offset;
+    }
+
+    /// Read the physical clock from `sim` (one tick per millisecond) instead of the wall clock
+    fn set_simulation(&self, sim: Simulation) {
+        self.lock().sim = Some(sim);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_timestamps_are_unique_and_monotonic() {
+        let oracle = TimestampOracle::create();
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let oracle = oracle.clone();
+                std::thread::spawn(move || {
+                    let issued: Vec<Timestamp> = (0..1000).map(|_| oracle.now()).collect();
+                    assert!(issued.windows(2).all(|pair| pair[0] < pair[1]));
+                    issued
+                })
+            })
+            .collect();
+        let mut all = HashSet::new();
+        for handle in handles {
+            all.extend(handle.join().unwrap());
+        }
+        assert!(all.len() == 4000);
+        assert!(oracle.last() == all.into_iter().max().unwrap());
+    }
+
+    fn ts(physical: u64, logical: u32) -> Timestamp {
+        Timestamp { physical, logical }
+    }
+
+    #[test]
+    fn test_observe() {
+        let sim = Simulation::create(0);
+        let oracle = TimestampOracle::create();
+        oracle.set_simulation(sim.clone());
+        oracle.set_max_offset(Duration::from_millis(10));
+        for _ in 0..5 {
+            sim.tick();
+        }
+        assert!(oracle.now() == ts(5, 0));
+        // the clock standing still only advances the counter
+        assert!(oracle.now() == ts(5, 1));
+
+        // a node slightly ahead pulls the clock along with it
+        assert!(oracle.observe(ts(12, 3)) == Ok(ts(12, 4)));
+        assert!(oracle.now() == ts(12, 5));
+        // one too far ahead is rejected
+        let skewed = Timestamp::at(16);
+        assert!(
+            oracle.observe(skewed)
+                == Err(ClockSkew {
+                    remote: skewed,
+                    local: 5
+                })
+        );
+        assert!(oracle.last() == ts(12, 5));
+        assert!(
+            oracle.observe(skewed).unwrap_err().to_string()
+                == "timestamp 16.0 is 11ms ahead of the local clock"
+        );
+
+        // once the local clock passes the remote one, the counter starts over
+        for _ in 0..10 {
+            sim.tick();
+        }
+        assert!(oracle.now() == ts(15, 0));
+        assert!(oracle.observe(ts(3, 0)) == Ok(ts(15, 1)));
+    }
+}
@@ -0,0 +1,176 @@
+/// This file implements `run_txn`, which runs a transaction body and retries it when the lock manager aborts it (wounded
+/// under wound-wait, or chosen as a deadlock victim). Aborts like these are transient, and the fix is to roll back and
+/// try again, so callers shouldn't each write their own retry loop. Between attempts the helper sleeps for a capped
+/// exponential backoff with full jitter, which keeps two transactions that keep aborting each other from retrying in
+/// lockstep. A retried transaction keeps its id, so under `LockPolicy::WoundWait` it keeps its age and eventually wins.
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::shared::TxnId;
+use crate::txn::lockmgr::{LockApi as _, LockError, LockManager};
+
+/// Errors a transaction body can fail with. Retryable errors make `run_txn` roll the transaction back and run it again
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+
+impl Retryable for LockError {
+    /// Only aborts are worth retrying. An interrupted statement was cancelled or timed out on purpose
+    fn is_retryable(&self) -> bool {
+        matches!(self, LockError::Aborted)
+    }
+}
+
+/// How many times `run_txn` runs a transaction, and how long it waits between attempts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first. At least one attempt is always made
+    pub max_attempts: usize,
+    /// Upper bound of the wait after the first failed attempt. It doubles after each failure after that
+    pub base_delay: Duration,
+    /// Cap on the upper bound
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait after failed attempt number `attempt` (counting from zero): a uniformly random duration between zero
+    /// and `min(max_delay, base_delay * 2^attempt)`
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let ceiling = self
+            .base_delay
+            .checked_mul(1 << attempt.min(31))
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay));
+        ceiling.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+/// Run `body` as transaction `txn_id`, retrying it on retryable errors as `policy` allows. Every attempt ends with the
+/// transaction's locks released, whether it succeeded or not, so the body shouldn't release them itself. The body is also
+/// responsible for undoing whatever a failed attempt changed before it returns its error; nothing is rolled back here.
+/// Returns the first non-retryable error, or the last error once the attempts run out.
+pub fn run_txn<T, E: Retryable>(
+    lock_mgr: &LockManager,
+    txn_id: TxnId,
+    policy: &RetryPolicy,
+    mut body: impl FnMut(TxnId) -> Result<T, E>,
+) -> Result<T, E> {
+    let mut attempt = 0;
+    loop {
+        let result = body(txn_id);
+        lock_mgr.unlock_all(txn_id);
+        match result {
+            Err(err) if err.is_retryable() && attempt + 1 < policy.max_attempts => {
+                std::thread::sleep(policy.backoff(attempt));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier};
+
+    use super::*;
+    use crate::txn::lockmgr::{LockMode, LockPolicy, LockTarget};
+    use crate::txn::session::{CancellationToken, Interrupted};
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default();
+        for attempt in 0..64 {
+            let cap = policy
+                .max_delay
+                .min(policy.base_delay * 2u32.pow(attempt.min(20) as u32));
+            assert!(policy.backoff(attempt) <= cap);
+        }
+    }
+
+    #[test]
+    fn test_retries_until_success() {
+        let lock_mgr = LockManager::create(LockPolicy::WoundWait);
+        let attempts = AtomicUsize::new(0);
+        let result: Result<usize, LockError> =
+            run_txn(&lock_mgr, 1, &RetryPolicy::default(), |txn_id| {
+                assert!(lock_mgr.lock_exclusive(txn_id, LockTarget::Page(1)));
+                if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                    return Err(LockError::Aborted);
+                }
+                Ok(42)
+            });
+        assert!(result == Ok(42));
+        assert!(attempts.load(Ordering::SeqCst) == 3);
+        // committed, so its locks are gone
+        assert!(lock_mgr.lock_mode(1, LockTarget::Page(1)).is_none());
+
+        // interrupted statements aren't retried
+        attempts.store(0, Ordering::SeqCst);
+        let result: Result<(), LockError> = run_txn(&lock_mgr, 2, &RetryPolicy::default(), |_| {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(LockError::Interrupted(Interrupted::Cancelled))
+        });
+        assert!(result == Err(LockError::Interrupted(Interrupted::Cancelled)));
+        assert!(attempts.load(Ordering::SeqCst) == 1);
+
+        // and aborts are only retried so often
+        attempts.store(0, Ordering::SeqCst);
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            ..RetryPolicy::default()
+        };
+        let result: Result<(), LockError> = run_txn(&lock_mgr, 3, &policy, |_| {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(LockError::Aborted)
+        });
+        assert!(result == Err(LockError::Aborted));
+        assert!(attempts.load(Ordering::SeqCst) == 3);
+    }
+
+    #[test]
+    fn test_conflicting_transactions_both_commit() {
+        let lock_mgr = LockManager::create(LockPolicy::WoundWait);
+        let barrier = Arc::new(Barrier::new(2));
+        let handles: Vec<_> = [(1, [1, 2]), (2, [2, 1])]
+            .into_iter()
+            .map(|(txn_id, pages)| {
+                let lock_mgr = lock_mgr.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    let cancel = CancellationToken::default();
+                    let mut first = true;
+                    run_txn(&lock_mgr, txn_id, &RetryPolicy::default(), |txn_id| {
+                        // lock the two pages in opposite orders, both at once on the first attempt
+                        for page_id in pages {
+                            lock_mgr.lock_interruptible(
+                                txn_id,
+                                LockTarget::Page(page_id),
+                                LockMode::Exclusive,
+                                &cancel,
+                            )?;
+                            if std::mem::take(&mut first) {
+                                barrier.wait();
+                            }
+                        }
+                        Ok::<_, LockError>(txn_id)
+                    })
+                })
+            })
+            .collect();
+        for (txn_id, handle) in (1..).zip(handles) {
+            assert!(handle.join().unwrap() == Ok(txn_id));
+        }
+    }
+}
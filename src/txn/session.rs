@@ -0,0 +1,267 @@
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::shared::TxnId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    #[default]
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+/// Why a statement stopped before finishing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupted {
+    Cancelled,
+    StatementTimeout,
+}
+
+impl Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Interrupted::Cancelled => write!(f, "statement cancelled"),
+            Interrupted::StatementTimeout => write!(f, "statement timeout"),
+        }
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Shared state used to stop a running statement, either on request or once its deadline passes. Clones refer to the same
+/// state, so another thread (e.g. a connection handling a cancel request) can hold one while the statement polls it. Long
+/// running operations call `check` periodically and bail out with its error.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Arc<Mutex<Option<Instant>>>,
+}
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// When the running statement times out, if it has a timeout
+    pub fn deadline(&self) -> Option<Instant> {
+        *self.deadline.lock()
+    }
+
+    pub fn check(&self) -> Result<(), Interrupted> {
+        if self.is_cancelled() {
+            return Err(Interrupted::Cancelled);
+        }
+        match self.deadline() {
+            Some(deadline) if Instant::now() >= deadline => Err(Interrupted::StatementTimeout),
+            _ => Ok(()),
+        }
+    }
+
+    fn reset(&self, deadline: Option<Instant>) {
+        self.cancelled.store(false, Ordering::Release);
+        *self.deadline.lock() = deadline;
+    }
+}
+
+/// Upper bound on the memory a session's operations (sorts, hash tables, buffered results) may hold at once. Operators reserve
+/// before allocating and release when done; clones share the same accounting.
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    limit: usize,
+    used: Arc<AtomicUsize>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget {
+            limit,
+            used: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        MemoryBudget::new(usize::MAX)
+    }
+
+    /// Reserve `bytes`. Returns false, reserving nothing, if that would exceed the limit
+    pub fn try_reserve(&self, bytes: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|total| *total <= self.limit)
+            })
+            .is_ok()
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::AcqRel);
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+/// Per-connection context handed to every operation run on behalf of a client: the open transaction (if any), its isolation
+/// level, the cancellation token for the running statement, the session's memory budget, and its statement timeout. Limits
+/// are per session, so one client's large sort can't starve the others.
+#[derive(Debug, Clone)]
+pub struct Session {
+    txn: Option<TxnId>,
+    isolation: IsolationLevel,
+    cancel: CancellationToken,
+    memory: MemoryBudget,
+    statement_timeout: Option<Duration>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            txn: None,
+            isolation: IsolationLevel::default(),
+            cancel: CancellationToken::default(),
+            memory: MemoryBudget::unlimited(),
+            statement_timeout: None,
+        }
+    }
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session::default()
+    }
+
+    pub fn with_isolation(mut self, isolation: IsolationLevel) -> Self {
+        self.isolation = isolation;
+        self
+    }
+
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory = MemoryBudget::new(bytes);
+        self
+    }
+
+    pub fn with_statement_timeout(mut self, timeout: Duration) -> Self {
+        self.statement_timeout = Some(timeout);
+        self
+    }
+
+    /// Attach a transaction to the session. Returns false if one is already open
+    pub fn begin(&mut self, txn_id: TxnId) -> bool {
+        if self.txn.is_some() {
+            return false;
+        }
+        self.txn = Some(txn_id);
+        true
+    }
+
+    /// Detach the current transaction (after commit or abort), returning it
+    pub fn end(&mut self) -> Option<TxnId> {
+        self.txn.take()
+    }
+
+    pub fn txn(&self) -> Option<TxnId> {
+        self.txn
+    }
+
+    pub fn isolation(&self) -> IsolationLevel {
+        self.isolation
+    }
+
+    pub fn set_isolation(&mut self, isolation: IsolationLevel) {
+        self.isolation = isolation;
+    }
+
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    pub fn memory(&self) -> &MemoryBudget {
+        &self.memory
+    }
+
+    pub fn statement_timeout(&self) -> Option<Duration> {
+        self.statement_timeout
+    }
+
+    pub fn set_statement_timeout(&mut self, timeout: Option<Duration>) {
+        self.statement_timeout = timeout;
+    }
+
+    /// Start a new statement: arm the statement timeout, if any. A cancellation aimed at the previous statement doesn't carry
+    /// over
+    pub fn begin_statement(&mut self) {
+        let deadline = self
+            .statement_timeout
+            .map(|timeout| Instant::now() + timeout);
+        self.cancel.reset(deadline);
+    }
+
+    /// Whether the running statement should stop, and why
+    pub fn check(&self) -> Result<(), Interrupted> {
+        self.cancel.check()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session() {
+        let mut session = Session::new()
+            .with_isolation(IsolationLevel::Serializable)
+            .with_memory_limit(100)
+            .with_statement_timeout(Duration::from_secs(1));
+        assert!(session.begin(1));
+        assert!(!session.begin(2));
+        assert!(session.txn() == Some(1));
+        assert!(session.isolation() == IsolationLevel::Serializable);
+
+        let token = session.cancellation_token();
+        std::thread::spawn(move || token.cancel()).join().unwrap();
+        assert!(session.is_cancelled());
+        session.begin_statement();
+        assert!(!session.is_cancelled());
+
+        let memory = session.memory().clone();
+        assert!(memory.try_reserve(60));
+        assert!(!session.memory().try_reserve(60));
+        memory.release(60);
+        assert!(session.memory().try_reserve(100));
+        assert!(session.end() == Some(1));
+        assert!(session.txn().is_none());
+    }
+
+    #[test]
+    fn test_statement_timeout() {
+        let mut session = Session::new().with_statement_timeout(Duration::from_millis(20));
+        // the timeout only runs while a statement does
+        assert!(session.check().is_ok());
+        session.begin_statement();
+        assert!(session.check().is_ok());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(session.check() == Err(Interrupted::StatementTimeout));
+        session.begin_statement();
+        assert!(session.check().is_ok());
+        session.cancellation_token().cancel();
+        assert!(session.check() == Err(Interrupted::Cancelled));
+    }
+}
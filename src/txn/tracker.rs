@@ -0,0 +1,295 @@
+/// This file implements the transaction tracker, which keeps track of every open transaction's age and the first log record
+/// it wrote. The oldest first LSN is the log horizon: records from there on may still be needed to roll a transaction back,
+/// so the log can't be truncated past it, and one forgotten transaction is enough to hold it back indefinitely. The tracker
+/// reports the oldest transactions, and a `LongTxnPolicy` decides what happens to those open for longer than a threshold:
+/// either a warning on the event bus, or a forced abort through the lock manager (the transaction finds out the next time
+/// it waits for or asks for a lock).
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::shared::{Lsn, TxnId, INVALID_LSN};
+use crate::sync::{Latch as _, Synchronized};
+use crate::telemetry::{EventBus, EventBusApi as _, StorageEvent};
+use crate::txn::lockmgr::{LockApi as _, LockManager};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LongTxnAction {
+    /// Publish a `StorageEvent::LongTransaction` and let the transaction run
+    #[default]
+    Warn,
+    /// Publish the event and abort the transaction
+    Abort,
+}
+
+/// What to do about transactions that have been open for longer than `threshold`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LongTxnPolicy {
+    pub threshold: Duration,
+    pub action: LongTxnAction,
+}
+
+impl Default for LongTxnPolicy {
+    fn default() -> Self {
+        LongTxnPolicy {
+            threshold: Duration::from_secs(60),
+            action: LongTxnAction::default(),
+        }
+    }
+}
+
+/// An open transaction as seen by the tracker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxnInfo {
+    pub txn_id: TxnId,
+    pub started: Instant,
+    /// LSN of the transaction's first log record, or `INVALID_LSN` if it hasn't written one yet
+    pub first_lsn: Lsn,
+    /// LSN of the transaction's latest log record, where rolling it back starts
+    pub last_lsn: Lsn,
+}
+
+impl TxnInfo {
+    pub fn age(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+pub struct TxnTrackerInternal {
+    active: HashMap<TxnId, TxnInfo>,
+    // transactions already reported by `enforce`, so each is only reported once
+    reported: HashMap<TxnId, LongTxnAction>,
+    bus: Option<EventBus>,
+}
+
+pub type TxnTracker = Synchronized<TxnTrackerInternal>;
+
+pub trait TxnTrackerApi {
+    fn create() -> Self;
+    fn begin(&self, txn_id: TxnId);
+    fn logged(&self, txn_id: TxnId, lsn: Lsn);
+    fn end(&self, txn_id: TxnId);
+    fn active(&self) -> usize;
+    fn oldest(&self, n: usize) -> Vec<TxnInfo>;
+    fn log_horizon(&self) -> Option<Lsn>;
+    fn enforce(&self, policy: &LongTxnPolicy, lock_mgr: &LockManager) -> Vec<TxnId>;
+    fn start_monitor(
+        &self,
+        policy: LongTxnPolicy,
+        lock_mgr: LockManager,
+        interval: Duration,
+    ) -> TxnMonitor;
+    fn set_event_bus(&self, bus: EventBus);
+}
+
+impl TxnTrackerApi for TxnTracker {
+    fn create() -> Self {
+        Synchronized::init(TxnTrackerInternal {
+            active: HashMap::new(),
+            reported: HashMap::new(),
+            bus: None,
+        })
+    }
+
+    /// Start tracking `txn_id`, which just began. Beginning a transaction that's already tracked keeps its original start
+    fn begin(&self, txn_id: TxnId) {
+        self.lock().active.entry(txn_id).or_insert(TxnInfo {
+            txn_id,
+            started: Instant::now(),
+            first_lsn: INVALID_LSN,
+            last_lsn: INVALID_LSN,
+        });
+    }
+
+    /// Note that `txn_id` wrote the log record at `lsn`. Only the first one counts towards the log horizon
+    fn logged(&self, txn_id: TxnId, lsn: Lsn) {
+        if let Some(info) = self.lock().active.get_mut(&txn_id) {
+            if info.first_lsn == INVALID_LSN {
+                info.first_lsn = lsn;
+            }
+            info.last_lsn = lsn;
+        }
+    }
+
+    /// Stop tracking `txn_id`, which committed or rolled back
+    fn end(&self, txn_id: TxnId) {
+        let mut inner = self.lock();
+        inner.active.remove(&txn_id);
+        inner.reported.remove(&txn_id);
+    }
+
+    fn active(&self) -> usize {
+        self.lock().active.len()
+    }
+
+    /// The `n` oldest open transactions, oldest first
+    fn oldest(&self, n: usize) -> Vec<TxnInfo> {
+        let mut txns: Vec<TxnInfo> = self.lock().active.values().copied().collect();
+        txns.sort_by_key(|info| (info.started, info.txn_id));
+        txns.truncate(n);
+        txns
+    }
+
+    /// The oldest LSN an open transaction may still need to roll back, i.e. how far the log can be truncated. `None` if no
+    /// open transaction has written anything
+    fn log_horizon(&self) -> Option<Lsn> {
+        self.lock()
+            .active
+            .values()
+            .map(|info| info.first_lsn)
+            .filter(|lsn| *lsn != INVALID_LSN)
+            .min()
+    }
+
+    /// Apply `policy` to the open transactions once and return those that are over the threshold and haven't been dealt with
+    /// before. Aborted transactions stay tracked until the caller rolls them back and calls `end`.
+    fn enforce(&self, policy: &LongTxnPolicy, lock_mgr: &LockManager) -> Vec<TxnId> {
+        let mut inner = self.lock();
+        let mut over: Vec<TxnInfo> = inner
+            .active
+            .values()
+            .filter(|info| info.age() > policy.threshold)
+            .filter(|info| match inner.reported.get(&info.txn_id) {
+                None => true,
+                // a warning can still be escalated to an abort
+                Some(done) => *done == LongTxnAction::Warn && policy.action == LongTxnAction::Abort,
+            })
+            .copied()
+            .collect();
+        over.sort_by_key(|info| (info.started, info.txn_id));
+        for info in &over {
+            let aborted = policy.action == LongTxnAction::Abort;
+            if aborted {
+                lock_mgr.abort(info.txn_id);
+            }
+            inner.reported.insert(info.txn_id, policy.action);
+            if let Some(bus) = &inner.bus {
+                bus.publish(StorageEvent::LongTransaction {
+                    txn_id: info.txn_id,
+                    age: info.age(),
+                    aborted,
+                });
+            }
+        }
+        over.iter().map(|info| info.txn_id).collect()
+    }
+
+    /// Run `enforce` every `interval` on a background thread until the returned handle is dropped
+    fn start_monitor(
+        &self,
+        policy: LongTxnPolicy,
+        lock_mgr: LockManager,
+        interval: Duration,
+    ) -> TxnMonitor {
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let tracker = self.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Acquire) {
+                    tracker.enforce(&policy, &lock_mgr);
+                    std::thread::park_timeout(interval);
+                }
+            })
+        };
+        TxnMonitor {
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    /// Publish `StorageEvent::LongTransaction` to `bus`
+    fn set_event_bus(&self, bus: EventBus) {
+        self.lock().bus = Some(bus);
+    }
+}
+
+/// Handle to a background long-transaction monitor. Dropping it stops the thread
+pub struct TxnMonitor {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for TxnMonitor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::txn::lockmgr::{LockPolicy, LockTarget};
+
+    #[test]
+    fn test_oldest_and_horizon() {
+        let tracker = TxnTracker::create();
+        for txn_id in 1..=3 {
+            tracker.begin(txn_id);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(tracker.log_horizon().is_none());
+        tracker.logged(2, 40);
+        tracker.logged(3, 30);
+        tracker.logged(2, 50);
+        assert!(tracker.log_horizon() == Some(30));
+
+        let oldest = tracker.oldest(2);
+        assert!(oldest.iter().map(|info| info.txn_id).collect::<Vec<_>>() == vec![1, 2]);
+        assert!(oldest[0].age() >= oldest[1].age());
+        assert!(oldest[1].first_lsn == 40 && oldest[1].last_lsn == 50);
+
+        tracker.end(3);
+        assert!(tracker.active() == 2);
+        assert!(tracker.log_horizon() == Some(40));
+    }
+
+    #[test]
+    fn test_long_transactions() {
+        let tracker = TxnTracker::create();
+        let bus = EventBus::create();
+        let events = bus.subscribe();
+        tracker.set_event_bus(bus);
+        let lock_mgr = LockManager::create(LockPolicy::Fifo);
+        tracker.begin(1);
+        assert!(lock_mgr.lock_exclusive(1, LockTarget::Page(1)));
+        std::thread::sleep(Duration::from_millis(20));
+        tracker.begin(2);
+
+        let warn = LongTxnPolicy {
+            threshold: Duration::from_millis(10),
+            action: LongTxnAction::Warn,
+        };
+        assert!(tracker.enforce(&warn, &lock_mgr) == vec![1]);
+        // only reported once
+        assert!(tracker.enforce(&warn, &lock_mgr).is_empty());
+        assert!(!lock_mgr.is_aborted(1));
+        match events.try_recv().unwrap() {
+            StorageEvent::LongTransaction {
+                txn_id, aborted, ..
+            } => assert!(txn_id == 1 && !aborted),
+            event => panic!("unexpected event {}", event),
+        }
+
+        // escalating to an abort reports it again, and the transaction can't take any more locks
+        let abort = LongTxnPolicy {
+            action: LongTxnAction::Abort,
+            ..warn
+        };
+        let monitor = tracker.start_monitor(abort, lock_mgr.clone(), Duration::from_millis(5));
+        while !lock_mgr.is_aborted(1) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        drop(monitor);
+        assert!(!lock_mgr.lock_exclusive(1, LockTarget::Page(2)));
+        lock_mgr.unlock_all(1);
+        tracker.end(1);
+        assert!(tracker.oldest(1)[0].txn_id == 2);
+    }
+}